@@ -25,9 +25,10 @@ set -e
 PROJECT_ID=test-project
 INSTANCE_ID=test-instance
 DATABASE_ID=test-database
+SCHEMA=${SPANNER_SCHEMA:-schema.ddl}
 
 DDL_STATEMENTS=$(
-  grep -v ^-- schema.ddl \
+  grep -v ^-- "$SCHEMA" \
   | sed -n 's/ \+/ /gp' \
   | tr -d '\n' \
   | sed 's/\(.*\);/\1/' \
@@ -46,4 +47,8 @@ curl -sS --request POST \
   --header 'Content-Type: application/json' \
   --data "{\"createStatement\":\"CREATE DATABASE \`$DATABASE_ID\`\",\"extraStatements\":$DDL_STATEMENTS}"
 
-sleep infinity
+# Keep the container up, unless only preparing the emulator (e.g. for cargo
+# test)
+if [ -z "$SPANNER_PREPARE_ONLY" ]; then
+  sleep infinity
+fi
@@ -0,0 +1,291 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    sync::{
+        atomic::{AtomicU64, AtomicU8, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{Cache, CacheBackend};
+
+/// Number of bits of the hash used to select a register
+const PRECISION: u32 = 14;
+const REGISTERS: usize = 1 << PRECISION;
+
+const DAY: u64 = 24 * 60 * 60;
+const WEEK: u64 = 7 * DAY;
+
+/// A HyperLogLog cardinality estimator
+///
+/// Estimates the number of distinct values inserted within ~1% (standard
+/// error of 1.04/sqrt(2^14)) in a fixed 16KB, regardless of how many values
+/// are seen.
+///
+/// Registers only ever grow, so values are inserted without locking. Merging
+/// estimators (taking the maximum of each register) estimates the number of
+/// distinct values inserted into any of them.
+pub struct HyperLogLog {
+    registers: Box<[AtomicU8]>,
+}
+
+impl Default for HyperLogLog {
+    fn default() -> Self {
+        Self {
+            registers: (0..REGISTERS).map(|_| AtomicU8::new(0)).collect(),
+        }
+    }
+}
+
+impl HyperLogLog {
+    pub fn insert<T: Hash + ?Sized>(&self, value: &T) {
+        // DefaultHasher::new() is keyed identically across processes, so
+        // the same value always lands in the same register
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        let index = (hash >> (64 - PRECISION)) as usize;
+        // Guard bit caps the run of leading zeros for the remaining bits
+        let rest = (hash << PRECISION) | (1 << (PRECISION - 1));
+        let rank = rest.leading_zeros() as u8 + 1;
+        self.registers[index].fetch_max(rank, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        let m = REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let (sum, zeros) = self
+            .registers
+            .iter()
+            .fold((0.0, 0), |(sum, zeros), register| {
+                let register = register.load(Ordering::Relaxed);
+                (
+                    sum + 2f64.powi(-i32::from(register)),
+                    zeros + (register == 0) as u32,
+                )
+            });
+        let estimate = alpha * m * m / sum;
+        if estimate <= 2.5 * m && zeros > 0 {
+            // Small range correction (linear counting)
+            (m * (m / f64::from(zeros)).ln()).round() as u64
+        } else {
+            estimate.round() as u64
+        }
+    }
+
+    pub fn clear(&self) {
+        self.registers
+            .iter()
+            .for_each(|register| register.store(0, Ordering::Relaxed));
+    }
+
+    /// The registers, one byte each, as read by `merge`
+    pub fn registers(&self) -> Vec<u8> {
+        self.registers
+            .iter()
+            .map(|register| register.load(Ordering::Relaxed))
+            .collect()
+    }
+
+    /// Merge another estimator's `registers` into this one. Registers of
+    /// another precision are ignored
+    pub fn merge(&self, registers: &[u8]) {
+        if registers.len() != REGISTERS {
+            return;
+        }
+        for (register, other) in self.registers.iter().zip(registers) {
+            register.fetch_max(*other, Ordering::Relaxed);
+        }
+    }
+}
+
+/// An estimator bound to a fixed (UTC aligned) time window, reset when the
+/// window rolls over
+struct Window {
+    length: u64,
+    /// The window being counted, as a number of `length`s since the epoch
+    current: AtomicU64,
+    users: HyperLogLog,
+}
+
+impl Window {
+    fn new(length: u64, now: u64) -> Self {
+        Self {
+            length,
+            current: AtomicU64::new(now / length),
+            users: HyperLogLog::default(),
+        }
+    }
+
+    fn roll(&self, now: u64) {
+        let current = now / self.length;
+        let previous = self.current.load(Ordering::Relaxed);
+        // Users recorded while another thread resets the estimator may be
+        // counted in either window: negligible next to its error
+        if current != previous
+            && self
+                .current
+                .compare_exchange(previous, current, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            self.users.clear();
+        }
+    }
+
+    fn record<T: Hash + ?Sized>(&self, user: &T, now: u64) {
+        self.roll(now);
+        self.users.insert(user);
+    }
+
+    /// Merge the window's registers into those `shared` for it, returning
+    /// the merged estimate.
+    ///
+    /// Concurrent merges (by other processes) may overwrite one another:
+    /// as each ships all of its registers again at its next merge, the
+    /// shared registers catch up then
+    fn merge_shared(&self, shared: &dyn Cache, now: u64) -> u64 {
+        self.roll(now);
+        let current = self.current.load(Ordering::Relaxed);
+        let key = format!("{}:{}", self.length, current);
+        let merged = HyperLogLog::default();
+        if let Some(registers) = shared.get(&key) {
+            merged.merge(&registers);
+        }
+        merged.merge(&self.users.registers());
+        // Not needed once the window's over
+        let ttl = ((current + 1) * self.length).saturating_sub(now).max(1);
+        shared.set(&key, merged.registers(), Some(Duration::from_secs(ttl)));
+        merged.count()
+    }
+}
+
+/// Tracks the (approximate) number of distinct users seen per UTC day and
+/// week, without locking.
+///
+/// Each process records the users it serves in its own estimators, merged
+/// on demand into the registers held by its `Cache`: on a shared backend
+/// (Redis, memcached) those count the users of every process sharing it,
+/// i.e. the deployment's active users. In process, only this one's.
+pub struct ActiveUsers {
+    daily: Window,
+    weekly: Window,
+    shared: Arc<dyn Cache>,
+}
+
+impl Default for ActiveUsers {
+    fn default() -> Self {
+        Self::new(&CacheBackend::Memory)
+    }
+}
+
+impl ActiveUsers {
+    pub fn new(backend: &CacheBackend) -> Self {
+        // One entry per window
+        Self::with_cache(backend.cache("active_users", Some(2)))
+    }
+
+    fn with_cache(shared: Arc<dyn Cache>) -> Self {
+        let now = unix_now();
+        Self {
+            daily: Window::new(DAY, now),
+            weekly: Window::new(WEEK, now),
+            shared,
+        }
+    }
+
+    /// Record that a user was seen
+    pub fn record<T: Hash + ?Sized>(&self, user: &T) {
+        self.record_at(user, unix_now())
+    }
+
+    fn record_at<T: Hash + ?Sized>(&self, user: &T, now: u64) {
+        self.daily.record(user, now);
+        self.weekly.record(user, now);
+    }
+
+    /// Return the estimated (daily, weekly) active users so far, merging
+    /// this process' estimators into the shared ones first.
+    ///
+    /// Blocks on a remote `Cache` backend.
+    pub fn merge_counts(&self) -> (u64, u64) {
+        self.merge_counts_at(unix_now())
+    }
+
+    fn merge_counts_at(&self, now: u64) -> (u64, u64) {
+        (
+            self.daily.merge_shared(&*self.shared, now),
+            self.weekly.merge_shared(&*self.shared, now),
+        )
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemoryCache;
+
+    #[test]
+    fn estimates_within_tolerance() {
+        let hll = HyperLogLog::default();
+        assert_eq!(hll.count(), 0);
+        for _ in 0..3 {
+            for uid in 0..100_000u64 {
+                hll.insert(&uid);
+            }
+        }
+        let count = hll.count() as f64;
+        assert!((count - 100_000.0).abs() / 100_000.0 < 0.03, "{}", count);
+
+        let small = HyperLogLog::default();
+        for uid in 0..100u64 {
+            small.insert(&uid);
+        }
+        assert!((small.count() as i64 - 100).abs() <= 2);
+    }
+
+    #[test]
+    fn windows_roll_over() {
+        let start = 10 * WEEK;
+        let users = ActiveUsers::default();
+        users.record_at("a", start);
+        users.record_at("b", start + 60);
+        assert_eq!(users.merge_counts_at(start + 120), (2, 2));
+
+        // The next day resets the daily window only
+        users.record_at("c", start + DAY);
+        assert_eq!(users.merge_counts_at(start + DAY), (1, 3));
+
+        assert_eq!(users.merge_counts_at(start + WEEK), (0, 0));
+    }
+
+    #[test]
+    fn merged_across_processes() {
+        let now = 10 * WEEK;
+        let shared: Arc<dyn Cache> = Arc::new(MemoryCache::new(None));
+        let host1 = ActiveUsers::with_cache(Arc::clone(&shared));
+        let host2 = ActiveUsers::with_cache(Arc::clone(&shared));
+        host1.record_at("a", now);
+        host1.record_at("b", now);
+        host2.record_at("b", now);
+        host2.record_at("c", now);
+
+        assert_eq!(host1.merge_counts_at(now), (2, 2));
+        // Users seen by both hosts are counted once
+        assert_eq!(host2.merge_counts_at(now), (3, 3));
+        assert_eq!(host1.merge_counts_at(now), (3, 3));
+
+        let merged = HyperLogLog::default();
+        merged.merge(&host1.daily.users.registers());
+        merged.merge(&host2.daily.users.registers());
+        assert_eq!(merged.count(), 3);
+    }
+}
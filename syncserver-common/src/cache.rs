@@ -0,0 +1,585 @@
+//! Caches of the features needing one (collection names, token
+//! verifications), backed by the process' memory, Redis or memcached
+//! according to the `cache_url` setting.
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::{Duration, Instant},
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+/// A key/value cache. Failing to reach its backend is logged and treated as
+/// a miss: a cache is never required to serve a request
+pub trait Cache: fmt::Debug + Send + Sync {
+    fn get(&self, key: &str) -> Option<Vec<u8>>;
+
+    /// Cache a value, expiring after `ttl` when given
+    fn set(&self, key: &str, value: Vec<u8>, ttl: Option<Duration>);
+
+    fn delete(&self, key: &str);
+
+    /// Set `key` unless it holds a value, returning whether it was set.
+    /// Backends that can't do it atomically (memcached) get then set it, so
+    /// concurrent callers may both set it
+    fn set_if_absent(&self, key: &str, value: Vec<u8>, ttl: Option<Duration>) -> bool {
+        if self.get(key).is_some() {
+            return false;
+        }
+        self.set(key, value, ttl);
+        true
+    }
+
+    /// Drop every entry (of the cache's namespace where the backend allows
+    /// it: memcached is flushed entirely)
+    fn clear(&self);
+
+    /// Whether calls go over the network, blocking the calling thread
+    fn is_remote(&self) -> bool {
+        false
+    }
+
+    /// Only tracked by in process caches
+    fn stats(&self) -> CacheStats {
+        CacheStats::default()
+    }
+}
+
+impl dyn Cache {
+    /// Get a value cached by `set_value`
+    pub fn get_value<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        self.get(key)
+            .and_then(|value| serde_json::from_slice(&value).ok())
+    }
+
+    pub fn set_value<T: Serialize>(&self, key: &str, value: &T, ttl: Option<Duration>) {
+        if let Ok(value) = serde_json::to_vec(value) {
+            self.set(key, value, ttl);
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct CacheStats {
+    pub entries: u64,
+    pub evictions: u64,
+}
+
+/// The technology backing every cache, selected by the `cache_url` setting
+#[derive(Clone, Default)]
+pub enum CacheBackend {
+    #[default]
+    Memory,
+    #[cfg(feature = "redis")]
+    Redis(r2d2::Pool<redis::Client>),
+    #[cfg(feature = "memcached")]
+    Memcached(Arc<memcache::Client>),
+}
+
+impl CacheBackend {
+    /// In process without a url, otherwise Redis (`redis://`) or memcached
+    /// (`memcache://`) when built with their feature
+    pub fn from_url(url: Option<&str>) -> Result<Self, String> {
+        let url = match url {
+            Some(url) => url,
+            None => return Ok(Self::Memory),
+        };
+        let scheme = url.split("://").next().unwrap_or_default();
+        match scheme {
+            #[cfg(feature = "redis")]
+            "redis" | "rediss" => {
+                let client = redis::Client::open(url).map_err(|e| e.to_string())?;
+                // Don't fail startup while Redis is unreachable: lookups miss
+                // until it is
+                let pool = r2d2::Pool::builder().build_unchecked(client);
+                Ok(Self::Redis(pool))
+            }
+            #[cfg(feature = "memcached")]
+            "memcache" => memcache::Client::connect(url)
+                .map(|client| Self::Memcached(Arc::new(client)))
+                .map_err(|e| e.to_string()),
+            _ => Err(format!("Unsupported cache_url scheme: {}", scheme)),
+        }
+    }
+
+    /// A cache namespaced by `prefix` on shared backends. In process, it
+    /// holds up to `max_size` entries (unbounded when `None`)
+    pub fn cache(&self, prefix: &'static str, max_size: Option<usize>) -> Arc<dyn Cache> {
+        match self {
+            Self::Memory => Arc::new(MemoryCache::new(max_size)),
+            #[cfg(feature = "redis")]
+            Self::Redis(pool) => Arc::new(RedisCache {
+                pool: pool.clone(),
+                prefix,
+            }),
+            #[cfg(feature = "memcached")]
+            Self::Memcached(client) => Arc::new(MemcachedCache {
+                client: Arc::clone(client),
+                prefix,
+            }),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Memory => "memory",
+            #[cfg(feature = "redis")]
+            Self::Redis(_) => "redis",
+            #[cfg(feature = "memcached")]
+            Self::Memcached(_) => "memcached",
+        }
+    }
+}
+
+impl fmt::Debug for CacheBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("CacheBackend").field(&self.name()).finish()
+    }
+}
+
+/// A cache held in the process' memory. When full, expired entries are
+/// dropped, then the oldest entries evicted
+#[derive(Debug, Default)]
+pub struct MemoryCache {
+    /// Max number of entries held, unbounded when `None`
+    max_size: Option<usize>,
+    entries: Mutex<MemoryEntries>,
+    evictions: AtomicU64,
+}
+
+/// The entries, indexed by insertion and by expiry so neither dropping the
+/// expired ones nor evicting the oldest scans the whole cache
+#[derive(Debug, Default)]
+struct MemoryEntries {
+    values: HashMap<String, MemoryEntry>,
+    /// Keys by their entry's `seq`, oldest first
+    order: BTreeMap<u64, String>,
+    /// Keys of the entries with a ttl, soonest expiring first
+    expiries: BTreeMap<(Instant, u64), String>,
+    next_seq: u64,
+}
+
+#[derive(Debug)]
+struct MemoryEntry {
+    value: Vec<u8>,
+    expires_at: Option<Instant>,
+    /// When the key was inserted, relative to the others
+    seq: u64,
+}
+
+impl MemoryEntry {
+    fn is_expired(&self, now: Instant) -> bool {
+        matches!(self.expires_at, Some(expires_at) if expires_at <= now)
+    }
+}
+
+impl MemoryEntries {
+    fn insert(&mut self, key: &str, value: Vec<u8>, expires_at: Option<Instant>) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.order.insert(seq, key.to_owned());
+        if let Some(expires_at) = expires_at {
+            self.expiries.insert((expires_at, seq), key.to_owned());
+        }
+        self.values.insert(
+            key.to_owned(),
+            MemoryEntry {
+                value,
+                expires_at,
+                seq,
+            },
+        );
+    }
+
+    /// Replace the value of an existing entry, keeping its place in the
+    /// insertion order
+    fn replace(&mut self, key: &str, value: Vec<u8>, expires_at: Option<Instant>) {
+        let entry = match self.values.get_mut(key) {
+            Some(entry) => entry,
+            None => return,
+        };
+        if let Some(old) = entry.expires_at {
+            self.expiries.remove(&(old, entry.seq));
+        }
+        if let Some(expires_at) = expires_at {
+            self.expiries
+                .insert((expires_at, entry.seq), key.to_owned());
+        }
+        entry.value = value;
+        entry.expires_at = expires_at;
+    }
+
+    fn remove(&mut self, key: &str) -> Option<MemoryEntry> {
+        let entry = self.values.remove(key)?;
+        self.order.remove(&entry.seq);
+        if let Some(expires_at) = entry.expires_at {
+            self.expiries.remove(&(expires_at, entry.seq));
+        }
+        Some(entry)
+    }
+
+    fn remove_expired(&mut self, now: Instant) {
+        while let Some((&(expires_at, _), key)) = self.expiries.iter().next() {
+            if expires_at > now {
+                break;
+            }
+            let key = key.clone();
+            self.remove(&key);
+        }
+    }
+
+    fn remove_oldest(&mut self) -> bool {
+        let key = match self.order.values().next() {
+            Some(key) => key.clone(),
+            None => return false,
+        };
+        self.remove(&key).is_some()
+    }
+}
+
+impl MemoryCache {
+    pub fn new(max_size: Option<usize>) -> Self {
+        Self {
+            max_size,
+            ..Default::default()
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, MemoryEntries> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Insert a new `key`, making room for it when full
+    fn insert(
+        &self,
+        entries: &mut MemoryEntries,
+        key: &str,
+        value: Vec<u8>,
+        expires_at: Option<Instant>,
+    ) {
+        if let Some(max_size) = self.max_size {
+            if max_size == 0 {
+                return;
+            }
+            if entries.values.len() >= max_size {
+                entries.remove_expired(Instant::now());
+            }
+            while entries.values.len() >= max_size && entries.remove_oldest() {
+                self.evictions.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        entries.insert(key, value, expires_at);
+    }
+}
+
+impl Cache for MemoryCache {
+    fn get(&self, key: &str) -> Option<Vec<u8>> {
+        let mut entries = self.lock();
+        match entries.values.get(key) {
+            Some(entry) if entry.is_expired(Instant::now()) => {
+                entries.remove(key);
+                None
+            }
+            Some(entry) => Some(entry.value.clone()),
+            None => None,
+        }
+    }
+
+    fn set(&self, key: &str, value: Vec<u8>, ttl: Option<Duration>) {
+        let expires_at = ttl.map(|ttl| Instant::now() + ttl);
+        let mut entries = self.lock();
+        if entries.values.contains_key(key) {
+            entries.replace(key, value, expires_at);
+            return;
+        }
+        self.insert(&mut entries, key, value, expires_at);
+    }
+
+    fn delete(&self, key: &str) {
+        self.lock().remove(key);
+    }
+
+    fn set_if_absent(&self, key: &str, value: Vec<u8>, ttl: Option<Duration>) -> bool {
+        let now = Instant::now();
+        let mut entries = self.lock();
+        match entries.values.get(key) {
+            Some(entry) if entry.is_expired(now) => {
+                // Set anew: the expired entry's place in the order is stale
+                entries.remove(key);
+            }
+            Some(_) => return false,
+            None => (),
+        }
+        self.insert(&mut entries, key, value, ttl.map(|ttl| now + ttl));
+        true
+    }
+
+    fn clear(&self) {
+        let mut entries = self.lock();
+        entries.values.clear();
+        entries.order.clear();
+        entries.expiries.clear();
+    }
+
+    fn stats(&self) -> CacheStats {
+        CacheStats {
+            entries: self.lock().values.len() as u64,
+            evictions: self.evictions.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(feature = "redis")]
+struct RedisCache {
+    pool: r2d2::Pool<redis::Client>,
+    prefix: &'static str,
+}
+
+#[cfg(feature = "redis")]
+impl RedisCache {
+    fn call<T>(
+        &self,
+        f: impl FnOnce(&mut redis::Connection) -> redis::RedisResult<T>,
+    ) -> Option<T> {
+        let result = self
+            .pool
+            .get()
+            .map_err(|e| e.to_string())
+            .and_then(|mut conn| f(&mut conn).map_err(|e| e.to_string()));
+        match result {
+            Ok(value) => Some(value),
+            Err(e) => {
+                warn!("⚠️ Redis cache call failed: {}", e; "prefix" => self.prefix);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(feature = "redis")]
+impl Cache for RedisCache {
+    fn get(&self, key: &str) -> Option<Vec<u8>> {
+        let key = format!("{}:{}", self.prefix, key);
+        self.call(|conn| redis::cmd("GET").arg(key).query(conn))
+            .flatten()
+    }
+
+    fn set(&self, key: &str, value: Vec<u8>, ttl: Option<Duration>) {
+        let key = format!("{}:{}", self.prefix, key);
+        self.call(|conn| {
+            let mut cmd = redis::cmd("SET");
+            cmd.arg(key).arg(value);
+            if let Some(ttl) = ttl {
+                cmd.arg("PX").arg(ttl.as_millis().max(1) as u64);
+            }
+            cmd.query::<()>(conn)
+        });
+    }
+
+    fn delete(&self, key: &str) {
+        let key = format!("{}:{}", self.prefix, key);
+        self.call(|conn| redis::cmd("DEL").arg(key).query::<()>(conn));
+    }
+
+    fn set_if_absent(&self, key: &str, value: Vec<u8>, ttl: Option<Duration>) -> bool {
+        let key = format!("{}:{}", self.prefix, key);
+        self.call(|conn| {
+            let mut cmd = redis::cmd("SET");
+            cmd.arg(key).arg(value).arg("NX");
+            if let Some(ttl) = ttl {
+                cmd.arg("PX").arg(ttl.as_millis().max(1) as u64);
+            }
+            cmd.query::<Option<String>>(conn)
+        })
+        // Set unless the call failed: a replay's then let through rather
+        // than a first request rejected
+        .map_or(true, |reply| reply.is_some())
+    }
+
+    fn clear(&self) {
+        let pattern = format!("{}:*", self.prefix);
+        self.call(|conn| {
+            let keys: Vec<String> = redis::cmd("KEYS").arg(pattern).query(conn)?;
+            if keys.is_empty() {
+                return Ok(());
+            }
+            redis::cmd("DEL").arg(keys).query::<()>(conn)
+        });
+    }
+
+    fn is_remote(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(feature = "redis")]
+impl fmt::Debug for RedisCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RedisCache")
+            .field("prefix", &self.prefix)
+            .finish()
+    }
+}
+
+#[cfg(feature = "memcached")]
+struct MemcachedCache {
+    client: Arc<memcache::Client>,
+    prefix: &'static str,
+}
+
+#[cfg(feature = "memcached")]
+impl MemcachedCache {
+    fn call<T>(&self, result: Result<T, memcache::MemcacheError>) -> Option<T> {
+        result
+            .map_err(|e| warn!("⚠️ Memcached cache call failed: {}", e; "prefix" => self.prefix))
+            .ok()
+    }
+}
+
+#[cfg(feature = "memcached")]
+impl Cache for MemcachedCache {
+    fn get(&self, key: &str) -> Option<Vec<u8>> {
+        let key = format!("{}:{}", self.prefix, key);
+        self.call(self.client.get::<Vec<u8>>(&key)).flatten()
+    }
+
+    fn set(&self, key: &str, value: Vec<u8>, ttl: Option<Duration>) {
+        let key = format!("{}:{}", self.prefix, key);
+        // In seconds, 0 never expiring
+        let expiration = ttl.map_or(0, |ttl| ttl.as_secs().max(1) as u32);
+        self.call(self.client.set(&key, value.as_slice(), expiration));
+    }
+
+    fn delete(&self, key: &str) {
+        let key = format!("{}:{}", self.prefix, key);
+        self.call(self.client.delete(&key));
+    }
+
+    fn clear(&self) {
+        self.call(self.client.flush());
+    }
+
+    fn is_remote(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(feature = "memcached")]
+impl fmt::Debug for MemcachedCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MemcachedCache")
+            .field("prefix", &self.prefix)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn memory_cache_evicts_oldest() {
+        let cache = MemoryCache::new(Some(2));
+        cache.set("a", b"1".to_vec(), None);
+        cache.set("b", b"2".to_vec(), None);
+        // Replacing a value doesn't evict
+        cache.set("a", b"3".to_vec(), None);
+        assert_eq!(cache.get("a"), Some(b"3".to_vec()));
+
+        cache.set("c", b"4".to_vec(), None);
+        assert_eq!(cache.get("a"), None);
+        assert_eq!(cache.get("b"), Some(b"2".to_vec()));
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                entries: 2,
+                evictions: 1
+            }
+        );
+
+        cache.delete("b");
+        assert_eq!(cache.get("b"), None);
+        cache.clear();
+        assert_eq!(cache.get("c"), None);
+    }
+
+    #[test]
+    fn memory_cache_expires() {
+        let cache = MemoryCache::new(Some(2));
+        cache.set("a", b"1".to_vec(), Some(Duration::from_millis(10)));
+        cache.set("b", b"2".to_vec(), None);
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(cache.get("a"), None);
+
+        // Expired entries make room before live ones are evicted
+        cache.set("a", b"1".to_vec(), Some(Duration::from_millis(10)));
+        thread::sleep(Duration::from_millis(20));
+        cache.set("c", b"3".to_vec(), None);
+        assert_eq!(cache.get("b"), Some(b"2".to_vec()));
+        assert_eq!(cache.stats().evictions, 0);
+    }
+
+    #[test]
+    fn memory_cache_set_if_absent() {
+        let cache = MemoryCache::new(Some(2));
+        assert!(cache.set_if_absent("a", b"1".to_vec(), Some(Duration::from_millis(10))));
+        assert!(!cache.set_if_absent("a", b"2".to_vec(), None));
+        assert_eq!(cache.get("a"), Some(b"1".to_vec()));
+
+        // Unless it expired
+        thread::sleep(Duration::from_millis(20));
+        cache.set("b", b"4".to_vec(), None);
+        assert!(cache.set_if_absent("a", b"3".to_vec(), None));
+        assert_eq!(cache.get("a"), Some(b"3".to_vec()));
+
+        // Which makes it the newest entry
+        cache.set("c", b"5".to_vec(), None);
+        assert_eq!(cache.get("a"), Some(b"3".to_vec()));
+        assert_eq!(cache.get("b"), None);
+    }
+
+    #[test]
+    fn memory_cache_full() {
+        let cache = MemoryCache::new(Some(100));
+        for i in 0..100 {
+            cache.set(&i.to_string(), vec![], Some(Duration::from_secs(60)));
+        }
+        assert_eq!(cache.stats().entries, 100);
+
+        for i in 100..150 {
+            cache.set(&i.to_string(), vec![], None);
+        }
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                entries: 100,
+                evictions: 50
+            }
+        );
+        assert_eq!(cache.get("49"), None);
+        assert_eq!(cache.get("50"), Some(vec![]));
+
+        // Evicted and deleted entries leave nothing behind in the indexes
+        for i in 50..150 {
+            cache.delete(&i.to_string());
+        }
+        let entries = cache.lock();
+        assert!(entries.values.is_empty());
+        assert!(entries.order.is_empty());
+        assert!(entries.expiries.is_empty());
+    }
+
+    #[test]
+    fn values_roundtrip() {
+        let cache: Arc<dyn Cache> = CacheBackend::Memory.cache("test", None);
+        cache.set_value("key", &vec![1, 2, 3], None);
+        assert_eq!(cache.get_value::<Vec<u32>>("key"), Some(vec![1, 2, 3]));
+        assert_eq!(cache.get_value::<String>("key"), None);
+    }
+}
@@ -1,18 +1,28 @@
 #[macro_use]
 extern crate slog_scope;
 
+mod active_users;
+mod cache;
 mod metrics;
+mod safe_uid;
 
 use std::{
     fmt,
-    sync::atomic::{AtomicU64, Ordering},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
 };
 
 use actix_web::{error::BlockingError, web};
 use hkdf::Hkdf;
 use sha2::Sha256;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 
+pub use active_users::{ActiveUsers, HyperLogLog};
+pub use cache::{Cache, CacheBackend, CacheStats, MemoryCache};
 pub use metrics::{metrics_from_opts, MetricError, Metrics};
+pub use safe_uid::{set_safe_uid_key, SafeUid};
 
 // header statics must be lower case, numbers and symbols per the RFC spec. This reduces chance of error.
 pub static X_LAST_MODIFIED: &str = "x-last-modified";
@@ -23,6 +33,10 @@ pub static X_WEAVE_BYTES: &str = "x-weave-bytes";
 pub static X_WEAVE_TOTAL_RECORDS: &str = "x-weave-total-records";
 pub static X_WEAVE_TOTAL_BYTES: &str = "x-weave-total-bytes";
 pub static X_VERIFY_CODE: &str = "x-verify-code";
+pub static X_WEAVE_QUOTA_REMAINING: &str = "x-weave-quota-remaining";
+pub static X_WEAVE_ALERT: &str = "x-weave-alert";
+pub static X_WEAVE_BACKOFF: &str = "x-weave-backoff";
+pub static X_REQUEST_ID: &str = "x-request-id";
 
 // max load size in bytes
 pub const MAX_SPANNER_LOAD_SIZE: usize = 100_000_000;
@@ -70,6 +84,30 @@ pub trait InternalError {
     fn internal_error(message: String) -> Self;
 }
 
+/// The class of a task spawned on the [`BlockingThreadpool`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum TaskClass {
+    /// Short, latency sensitive work (e.g. metadata reads)
+    #[default]
+    Interactive,
+    /// Long running work (e.g. batch commits, purges) that may be throttled so it can't occupy
+    /// every thread in the pool
+    Bulk,
+}
+
+impl TaskClass {
+    pub fn tag(self) -> &'static str {
+        match self {
+            TaskClass::Interactive => "interactive",
+            TaskClass::Bulk => "bulk",
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
 /// A threadpool on which callers can spawn non-CPU-bound tasks that block their thread (this is
 /// mostly useful for running I/O tasks). `BlockingThreadpool` intentionally does not implement
 /// `Clone`: `Arc`s are not used internally, so a `BlockingThreadpool` should be instantiated once
@@ -77,21 +115,73 @@ pub trait InternalError {
 #[derive(Debug, Default)]
 pub struct BlockingThreadpool {
     spawned_tasks: AtomicU64,
+    /// Tasks running, per `TaskClass`
+    active_tasks: [AtomicU64; 2],
+    /// Tasks waiting on a `bulk_permits` permit, per `TaskClass`
+    queued_tasks: [AtomicU64; 2],
+    /// Limits the number of concurrently running `TaskClass::Bulk` tasks
+    bulk_permits: Option<Arc<Semaphore>>,
 }
 
+/// Permission to run `TaskClass::Bulk` work, returned to the `BlockingThreadpool` when dropped
+#[derive(Debug, Default)]
+pub struct BulkPermit(Option<OwnedSemaphorePermit>);
+
 impl BlockingThreadpool {
-    /// Runs a function as a task on the blocking threadpool.
+    /// Create a threadpool that runs at most `max_bulk_tasks` `TaskClass::Bulk` tasks at once,
+    /// leaving the remaining threads available to `TaskClass::Interactive` tasks.
+    pub fn with_bulk_limit(max_bulk_tasks: usize) -> Self {
+        debug_assert!(max_bulk_tasks > 0, "Bulk tasks would never run");
+        Self {
+            bulk_permits: Some(Arc::new(Semaphore::new(max_bulk_tasks))),
+            ..Default::default()
+        }
+    }
+
+    /// Runs a function as a `TaskClass::Interactive` task on the blocking threadpool.
     ///
-    /// WARNING: Spawning a blocking task through means other than calling this method will
-    /// result in inaccurate threadpool metrics being reported. If you want to spawn a task on
-    /// the blocking threadpool, you **must** use this function.
+    /// WARNING: Spawning a blocking task through means other than calling this method (or
+    /// `spawn_with_class`) will result in inaccurate threadpool metrics being reported. If you
+    /// want to spawn a task on the blocking threadpool, you **must** use these functions.
     pub async fn spawn<F, T, E>(&self, f: F) -> Result<T, E>
     where
         F: FnOnce() -> Result<T, E> + Send + 'static,
         T: Send + 'static,
         E: fmt::Debug + Send + InternalError + 'static,
     {
+        self.spawn_with_class(TaskClass::Interactive, f).await
+    }
+
+    /// Waits for permission to run `TaskClass::Bulk` work.
+    ///
+    /// The permit should be taken before acquiring the resources (database connections, locks)
+    /// the work needs, and held until they're released: waiting for it while holding them would
+    /// starve the interactive requests contending for the same resources.
+    pub async fn bulk_permit(&self) -> BulkPermit {
+        let permits = match &self.bulk_permits {
+            Some(permits) => Arc::clone(permits),
+            None => return BulkPermit::default(),
+        };
+        let index = TaskClass::Bulk.index();
+        self.queued_tasks[index].fetch_add(1, Ordering::Relaxed);
+        let permit = permits.acquire_owned().await;
+        self.queued_tasks[index].fetch_sub(1, Ordering::Relaxed);
+        BulkPermit(Some(permit))
+    }
+
+    /// Runs a function as a task of the given class on the blocking threadpool.
+    ///
+    /// The class only determines how the task is accounted for: `TaskClass::Bulk` tasks should
+    /// be run while holding a permit from `bulk_permit`.
+    pub async fn spawn_with_class<F, T, E>(&self, class: TaskClass, f: F) -> Result<T, E>
+    where
+        F: FnOnce() -> Result<T, E> + Send + 'static,
+        T: Send + 'static,
+        E: fmt::Debug + Send + InternalError + 'static,
+    {
+        let index = class.index();
         self.spawned_tasks.fetch_add(1, Ordering::Relaxed);
+        self.active_tasks[index].fetch_add(1, Ordering::Relaxed);
 
         let result = web::block(f).await.map_err(|e| match e {
             BlockingError::Error(e) => e,
@@ -100,6 +190,7 @@ impl BlockingThreadpool {
             }
         });
 
+        self.active_tasks[index].fetch_sub(1, Ordering::Relaxed);
         self.spawned_tasks.fetch_sub(1, Ordering::Relaxed);
 
         result
@@ -108,4 +199,14 @@ impl BlockingThreadpool {
     pub fn active_threads(&self) -> u64 {
         self.spawned_tasks.load(Ordering::Relaxed)
     }
+
+    /// The number of running tasks of the given class
+    pub fn active_tasks(&self, class: TaskClass) -> u64 {
+        self.active_tasks[class.index()].load(Ordering::Relaxed)
+    }
+
+    /// The number of tasks of the given class waiting for permission to run
+    pub fn queued_tasks(&self, class: TaskClass) -> u64 {
+        self.queued_tasks[class.index()].load(Ordering::Relaxed)
+    }
 }
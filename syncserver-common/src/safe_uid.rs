@@ -0,0 +1,51 @@
+use std::{fmt, sync::OnceLock};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// Key of the HMAC `SafeUid` displays, set once at startup
+static LOGGING_KEY: OnceLock<[u8; 32]> = OnceLock::new();
+
+/// Set the key of all `SafeUid`s. Only the first call has any effect; until
+/// then a fixed (zeroed) key is used.
+pub fn set_safe_uid_key(key: [u8; 32]) {
+    let _ = LOGGING_KEY.set(key);
+}
+
+/// Displays a user identifier as an HMAC of it, keyed with the logging key.
+///
+/// Wrap uids in this wherever they'd end up in logs, metric tags or error
+/// reports: the output is stable for a given uid (so a user's requests can
+/// still be correlated) but can't be mapped back to the uid without the key.
+#[derive(Clone, Copy, Debug)]
+pub struct SafeUid<T>(pub T);
+
+impl<T: fmt::Display> fmt::Display for SafeUid<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let key = LOGGING_KEY.get().copied().unwrap_or_default();
+        let mut mac = Hmac::<Sha256>::new_from_slice(&key).expect("HMAC has no key size limit");
+        mac.update(self.0.to_string().as_bytes());
+        let digest = mac.finalize().into_bytes();
+        for byte in &digest[..16] {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SafeUid;
+
+    #[test]
+    fn hides_the_uid() {
+        let safe = SafeUid("0123456789abcdef0123456789abcdef").to_string();
+        assert_eq!(safe.len(), 32);
+        assert!(!safe.contains("0123456789abcdef"));
+        assert_eq!(
+            safe,
+            SafeUid("0123456789abcdef0123456789abcdef").to_string()
+        );
+        assert_ne!(safe, SafeUid(42).to_string());
+    }
+}
@@ -0,0 +1,93 @@
+use std::cell::Cell;
+
+use diesel::{
+    backend::UsesAnsiSavepointSyntax,
+    connection::{AnsiTransactionManager, SimpleConnection},
+    deserialize::{Queryable, QueryableByName},
+    query_builder::{AsQuery, QueryFragment, QueryId},
+    result::{ConnectionResult, QueryResult},
+    sql_types::HasSqlType,
+    Connection,
+};
+
+/// A connection counting the statements sent through it (including those
+/// beginning and ending transactions), so the round trips made on behalf of
+/// a request can be checked against its budget
+pub struct CountingConnection<C> {
+    inner: C,
+    queries: Cell<u64>,
+}
+
+impl<C> CountingConnection<C> {
+    pub fn new(inner: C) -> Self {
+        Self {
+            inner,
+            queries: Cell::new(0),
+        }
+    }
+
+    pub fn query_count(&self) -> u64 {
+        self.queries.get()
+    }
+
+    fn count(&self) {
+        self.queries.set(self.queries.get() + 1);
+    }
+}
+
+impl<C: SimpleConnection> SimpleConnection for CountingConnection<C> {
+    fn batch_execute(&self, query: &str) -> QueryResult<()> {
+        self.count();
+        self.inner.batch_execute(query)
+    }
+}
+
+impl<C> Connection for CountingConnection<C>
+where
+    C: Connection<TransactionManager = AnsiTransactionManager> + Send + 'static,
+    C::Backend: UsesAnsiSavepointSyntax,
+{
+    type Backend = C::Backend;
+    type TransactionManager = C::TransactionManager;
+
+    fn establish(database_url: &str) -> ConnectionResult<Self> {
+        Ok(Self::new(C::establish(database_url)?))
+    }
+
+    fn execute(&self, query: &str) -> QueryResult<usize> {
+        self.count();
+        self.inner.execute(query)
+    }
+
+    fn query_by_index<T, U>(&self, source: T) -> QueryResult<Vec<U>>
+    where
+        T: AsQuery,
+        T::Query: QueryFragment<Self::Backend> + QueryId,
+        Self::Backend: HasSqlType<T::SqlType>,
+        U: Queryable<T::SqlType, Self::Backend>,
+    {
+        self.count();
+        self.inner.query_by_index(source)
+    }
+
+    fn query_by_name<T, U>(&self, source: &T) -> QueryResult<Vec<U>>
+    where
+        T: QueryFragment<Self::Backend> + QueryId,
+        U: QueryableByName<Self::Backend>,
+    {
+        self.count();
+        self.inner.query_by_name(source)
+    }
+
+    fn execute_returning_count<T>(&self, source: &T) -> QueryResult<usize>
+    where
+        T: QueryFragment<Self::Backend> + QueryId,
+    {
+        self.count();
+        self.inner.execute_returning_count(source)
+    }
+
+    fn transaction_manager(&self) -> &Self::TransactionManager {
+        self.inner.transaction_manager()
+    }
+}
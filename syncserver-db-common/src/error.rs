@@ -6,7 +6,8 @@ use syncserver_common::{from_error, impl_fmt_display};
 use thiserror::Error;
 
 /// Error specific to any MySQL database backend. These errors are not related to the syncstorage
-/// or tokenserver application logic; rather, they are lower-level errors arising from diesel.
+/// or tokenserver application logic; rather, they are lower-level errors arising from diesel
+/// (which the PostgreSQL backend also reports them as).
 #[derive(Debug)]
 pub struct MysqlError {
     kind: MysqlErrorKind,
@@ -27,6 +28,23 @@ enum MysqlErrorKind {
 
     #[error("Error migrating the database: {}", _0)]
     Migration(diesel_migrations::RunMigrationsError),
+
+    #[error(
+        "The database schema version ({found}) doesn't match the version this server expects \
+         ({expected}): apply the migrations matching this release or deploy the release matching \
+         the schema"
+    )]
+    SchemaVersion { expected: String, found: String },
+}
+
+impl MysqlError {
+    pub fn schema_version_mismatch(expected: &str, found: Option<String>) -> Self {
+        MysqlErrorKind::SchemaVersion {
+            expected: expected.to_owned(),
+            found: found.unwrap_or_else(|| "none".to_owned()),
+        }
+        .into()
+    }
 }
 
 impl From<MysqlErrorKind> for MysqlError {
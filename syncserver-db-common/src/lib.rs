@@ -1,23 +1,126 @@
+#[macro_use]
+extern crate slog_scope;
+
+pub mod counting;
 pub mod error;
+pub mod manager;
+pub mod migrations;
+pub mod singleflight;
 pub mod test;
 
-use std::fmt::Debug;
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
+use diesel::r2d2::{
+    event::{CheckoutEvent, TimeoutEvent},
+    HandleEvent,
+};
 use futures::future::LocalBoxFuture;
+use syncserver_common::Metrics;
 
 pub type DbFuture<'a, T, E> = LocalBoxFuture<'a, Result<T, E>>;
 
+/// Describes the params of a `Db` call in the call's metrics
+pub trait DbCallParams {
+    /// The (normalized) name of the collection the call operates on, if any
+    fn collection_tag(&self) -> Option<&'static str> {
+        None
+    }
+}
+
+/// Identifies the request a `Db` is pinned to in the log records of its calls
+/// (see `timed_request`)
+pub trait DbRequestId {
+    /// The request's `X-Request-Id`, once it's been set
+    fn request_id(&self) -> Option<&str> {
+        None
+    }
+}
+
+/// Runs a `Db` call, timing it as the `storage.db.call` metric tagged with
+/// its operation and collection (if any), and logging how long it took.
+///
+/// The duration is logged from the calling request's task, so it's recorded
+/// among the Sentry breadcrumbs of the request.
+pub async fn timed<T, E>(
+    metrics: &Metrics,
+    operation: &'static str,
+    collection: Option<&'static str>,
+    call: impl Future<Output = Result<T, E>>,
+) -> Result<T, E> {
+    timed_request(metrics, None, operation, collection, call).await
+}
+
+/// Like `timed`, also logging the id of the request making the call, so a
+/// slow call can be tied back to its request's other log records
+pub async fn timed_request<T, E>(
+    metrics: &Metrics,
+    request_id: Option<&str>,
+    operation: &'static str,
+    collection: Option<&'static str>,
+    call: impl Future<Output = Result<T, E>>,
+) -> Result<T, E> {
+    let mut tags = HashMap::new();
+    tags.insert("operation".to_owned(), operation.to_owned());
+    if let Some(collection) = collection {
+        tags.insert("collection".to_owned(), collection.to_owned());
+    }
+    let mut metrics = metrics.clone();
+    metrics.start_timer("storage.db.call", Some(tags));
+
+    let start = Instant::now();
+    let result = call.await;
+    debug!(
+        "Db call {} took {}ms", operation, start.elapsed().as_millis();
+        "db_call" => operation,
+        "collection" => collection,
+        "request_id" => request_id,
+        "ok" => result.is_ok(),
+    );
+    result
+}
+
+/// Starts timing a wait on a collection lock ("read" or "write" `mode`) as
+/// the `storage.lock.wait` metric, recorded once the returned `Metrics` is
+/// dropped.
+///
+/// Unlike the `storage.db.call` timing of `lock_for_read`/`lock_for_write`,
+/// this only covers the statement blocking on the lock, whether it's acquired
+/// or times out.
+pub fn lock_wait_timer(metrics: &Metrics, mode: &'static str) -> Metrics {
+    let mut tags = HashMap::new();
+    tags.insert("mode".to_owned(), mode.to_owned());
+    let mut metrics = metrics.clone();
+    metrics.start_timer("storage.lock.wait", Some(tags));
+    metrics
+}
+
 /// A trait to be implemented by database pool data structures. It provides an interface to
 /// derive the current state of the pool, as represented by the `PoolState` struct.
 pub trait GetPoolState {
     fn state(&self) -> PoolState;
 }
 
-#[derive(Debug, Default)]
-/// A mockable r2d2::State
+#[derive(Clone, Debug, Default)]
+/// A mockable r2d2::State, along with the pool's checkout stats (when it
+/// tracks them, see `CheckoutStats`)
 pub struct PoolState {
     pub connections: u32,
     pub idle_connections: u32,
+    /// Connections checked out of the pool so far
+    pub checkouts: u64,
+    /// The total time those checkouts spent waiting on a connection
+    pub checkout_wait: Duration,
+    /// Checkouts that timed out without getting a connection
+    pub checkout_failures: u64,
 }
 
 impl From<diesel::r2d2::State> for PoolState {
@@ -25,6 +128,7 @@ impl From<diesel::r2d2::State> for PoolState {
         PoolState {
             connections: state.connections,
             idle_connections: state.idle_connections,
+            ..Default::default()
         }
     }
 }
@@ -33,10 +137,47 @@ impl From<deadpool::Status> for PoolState {
         PoolState {
             connections: status.size as u32,
             idle_connections: status.available.max(0) as u32,
+            ..Default::default()
         }
     }
 }
 
+/// Counts a r2d2 pool's checkouts, how long they waited on a connection and
+/// those that timed out, as the pool's event handler.
+///
+/// Clones share their counts: the pool's handler is a clone of the one its
+/// `GetPoolState` reads.
+#[derive(Clone, Debug, Default)]
+pub struct CheckoutStats {
+    checkouts: Arc<AtomicU64>,
+    wait_micros: Arc<AtomicU64>,
+    failures: Arc<AtomicU64>,
+}
+
+impl CheckoutStats {
+    /// The pool's `state` along with its checkout stats
+    pub fn pool_state(&self, state: diesel::r2d2::State) -> PoolState {
+        PoolState {
+            checkouts: self.checkouts.load(Ordering::Relaxed),
+            checkout_wait: Duration::from_micros(self.wait_micros.load(Ordering::Relaxed)),
+            checkout_failures: self.failures.load(Ordering::Relaxed),
+            ..state.into()
+        }
+    }
+}
+
+impl HandleEvent for CheckoutStats {
+    fn handle_checkout(&self, event: CheckoutEvent) {
+        self.checkouts.fetch_add(1, Ordering::Relaxed);
+        self.wait_micros
+            .fetch_add(event.duration().as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn handle_timeout(&self, _event: TimeoutEvent) {
+        self.failures.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
 #[macro_export]
 macro_rules! sync_db_method {
     ($name:ident, $sync_name:ident, $type:ident) => {
@@ -45,10 +186,31 @@ macro_rules! sync_db_method {
     ($name:ident, $sync_name:ident, $type:ident, $result:ty) => {
         fn $name(&self, params: params::$type) -> DbFuture<'_, $result, DbError> {
             let db = self.clone();
-            Box::pin(
+            let collection = $crate::DbCallParams::collection_tag(&params);
+            Box::pin($crate::timed_request(
+                &self.metrics,
+                $crate::DbRequestId::request_id(self),
+                stringify!($name),
+                collection,
                 self.blocking_threadpool
                     .spawn(move || db.$sync_name(params)),
-            )
+            ))
+        }
+    };
+    (@bulk $name:ident, $sync_name:ident, $type:ident) => {
+        fn $name(&self, params: params::$type) -> DbFuture<'_, results::$type, DbError> {
+            let db = self.clone();
+            let collection = $crate::DbCallParams::collection_tag(&params);
+            Box::pin($crate::timed_request(
+                &self.metrics,
+                $crate::DbRequestId::request_id(self),
+                stringify!($name),
+                collection,
+                self.blocking_threadpool
+                    .spawn_with_class(syncserver_common::TaskClass::Bulk, move || {
+                        db.$sync_name(params)
+                    }),
+            ))
         }
     };
 }
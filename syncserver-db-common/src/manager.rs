@@ -0,0 +1,251 @@
+use std::time::Duration;
+
+#[cfg(feature = "postgres")]
+use diesel::pg::PgConnection;
+#[cfg(feature = "sqlite")]
+use diesel::sqlite::SqliteConnection;
+use diesel::{
+    connection::SimpleConnection,
+    mysql::MysqlConnection,
+    r2d2::{ConnectionManager, Error, ManageConnection},
+};
+use syncserver_common::Metrics;
+
+/// Session settings pinned on every connection, rather than inheriting the
+/// server's defaults (which differ between MySQL and MariaDB versions).
+/// Strict mode in particular makes oversized values fail their write
+/// instead of being silently truncated
+const SESSION_SETTINGS: &str = "SET SESSION \
+    sql_mode = 'STRICT_ALL_TABLES,NO_ENGINE_SUBSTITUTION', \
+    collation_connection = 'utf8mb4_bin'";
+
+/// An r2d2 `ConnectionManager` for MySQL that reports the connections the
+/// pool evicts.
+///
+/// r2d2 drops connections that fail validation on checkout (`is_valid`) and
+/// connections found broken when returned to the pool (`has_broken`). After a
+/// MySQL failover every pooled connection goes stale at once, so these
+/// evictions are counted under `storage.pool.connections.evicted`.
+///
+/// New connections have their session's `SESSION_SETTINGS` applied and
+/// optionally their `max_execution_time` set, so MySQL aborts any `SELECT`
+/// running longer than `statement_timeout`, and their
+/// `innodb_lock_wait_timeout`, bounding how long a statement waits on another
+/// transaction's row locks. (These are applied here rather
+/// than by a pool `CustomizeConnection`, as r2d2 only takes one customizer and
+/// tests use it for their test transactions.)
+#[derive(Debug)]
+pub struct MysqlConnectionManager {
+    inner: ConnectionManager<MysqlConnection>,
+    metrics: Metrics,
+    statement_timeout: Option<Duration>,
+    lock_wait_timeout: Option<Duration>,
+}
+
+impl MysqlConnectionManager {
+    pub fn new(
+        database_url: impl Into<String>,
+        metrics: &Metrics,
+        statement_timeout: Option<Duration>,
+        lock_wait_timeout: Option<Duration>,
+    ) -> Self {
+        Self {
+            inner: ConnectionManager::new(database_url),
+            metrics: metrics.clone(),
+            statement_timeout,
+            lock_wait_timeout,
+        }
+    }
+
+    fn record_eviction(&self, reason: &str) {
+        self.metrics
+            .incr_with_tag("storage.pool.connections.evicted", "reason", reason);
+    }
+}
+
+impl ManageConnection for MysqlConnectionManager {
+    type Connection = MysqlConnection;
+    type Error = Error;
+
+    fn connect(&self) -> Result<MysqlConnection, Error> {
+        let conn = self.inner.connect()?;
+        conn.batch_execute(SESSION_SETTINGS)
+            .map_err(Error::QueryError)?;
+        if let Some(timeout) = self.statement_timeout {
+            conn.batch_execute(&format!(
+                "SET SESSION max_execution_time = {}",
+                timeout.as_millis()
+            ))
+            .map_err(Error::QueryError)?;
+        }
+        if let Some(timeout) = self.lock_wait_timeout {
+            // In whole seconds, at least one
+            conn.batch_execute(&format!(
+                "SET SESSION innodb_lock_wait_timeout = {}",
+                timeout.as_secs().max(1)
+            ))
+            .map_err(Error::QueryError)?;
+        }
+        Ok(conn)
+    }
+
+    fn is_valid(&self, conn: &mut MysqlConnection) -> Result<(), Error> {
+        self.inner.is_valid(conn).map_err(|e| {
+            self.record_eviction("invalid");
+            e
+        })
+    }
+
+    fn has_broken(&self, conn: &mut MysqlConnection) -> bool {
+        let broken = self.inner.has_broken(conn);
+        if broken {
+            self.record_eviction("broken");
+        }
+        broken
+    }
+}
+
+/// An r2d2 `ConnectionManager` for PostgreSQL, the counterpart of
+/// `MysqlConnectionManager`: it reports evicted connections the same way and
+/// optionally sets new connections' `statement_timeout`, which (unlike
+/// MySQL's `max_execution_time`) aborts any statement running longer than it,
+/// and their `lock_timeout`, aborting any statement waiting on a lock longer
+/// than it.
+#[cfg(feature = "postgres")]
+#[derive(Debug)]
+pub struct PostgresConnectionManager {
+    inner: ConnectionManager<PgConnection>,
+    metrics: Metrics,
+    statement_timeout: Option<Duration>,
+    lock_wait_timeout: Option<Duration>,
+}
+
+#[cfg(feature = "postgres")]
+impl PostgresConnectionManager {
+    pub fn new(
+        database_url: impl Into<String>,
+        metrics: &Metrics,
+        statement_timeout: Option<Duration>,
+        lock_wait_timeout: Option<Duration>,
+    ) -> Self {
+        Self {
+            inner: ConnectionManager::new(database_url),
+            metrics: metrics.clone(),
+            statement_timeout,
+            lock_wait_timeout,
+        }
+    }
+
+    fn record_eviction(&self, reason: &str) {
+        self.metrics
+            .incr_with_tag("storage.pool.connections.evicted", "reason", reason);
+    }
+}
+
+#[cfg(feature = "postgres")]
+impl ManageConnection for PostgresConnectionManager {
+    type Connection = PgConnection;
+    type Error = Error;
+
+    fn connect(&self) -> Result<PgConnection, Error> {
+        let conn = self.inner.connect()?;
+        if let Some(timeout) = self.statement_timeout {
+            conn.batch_execute(&format!(
+                "SET SESSION statement_timeout = {}",
+                timeout.as_millis()
+            ))
+            .map_err(Error::QueryError)?;
+        }
+        if let Some(timeout) = self.lock_wait_timeout {
+            conn.batch_execute(&format!(
+                "SET SESSION lock_timeout = {}",
+                timeout.as_millis()
+            ))
+            .map_err(Error::QueryError)?;
+        }
+        Ok(conn)
+    }
+
+    fn is_valid(&self, conn: &mut PgConnection) -> Result<(), Error> {
+        self.inner.is_valid(conn).map_err(|e| {
+            self.record_eviction("invalid");
+            e
+        })
+    }
+
+    fn has_broken(&self, conn: &mut PgConnection) -> bool {
+        let broken = self.inner.has_broken(conn);
+        if broken {
+            self.record_eviction("broken");
+        }
+        broken
+    }
+}
+
+/// An r2d2 `ConnectionManager` for SQLite, reporting evicted connections like
+/// `MysqlConnectionManager`.
+///
+/// New connections switch the database to write-ahead logging, so readers
+/// aren't blocked by the (single) writer, and wait up to `busy_timeout` on
+/// another connection's write lock before failing with `SQLITE_BUSY`.
+#[cfg(feature = "sqlite")]
+#[derive(Debug)]
+pub struct SqliteConnectionManager {
+    inner: ConnectionManager<SqliteConnection>,
+    metrics: Metrics,
+    busy_timeout: Duration,
+}
+
+#[cfg(feature = "sqlite")]
+impl SqliteConnectionManager {
+    /// `database_path` is the database's filename, not a URL
+    pub fn new(
+        database_path: impl Into<String>,
+        metrics: &Metrics,
+        busy_timeout: Duration,
+    ) -> Self {
+        Self {
+            inner: ConnectionManager::new(database_path),
+            metrics: metrics.clone(),
+            busy_timeout,
+        }
+    }
+
+    fn record_eviction(&self, reason: &str) {
+        self.metrics
+            .incr_with_tag("storage.pool.connections.evicted", "reason", reason);
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl ManageConnection for SqliteConnectionManager {
+    type Connection = SqliteConnection;
+    type Error = Error;
+
+    fn connect(&self) -> Result<SqliteConnection, Error> {
+        let conn = self.inner.connect()?;
+        conn.batch_execute(&format!(
+            "PRAGMA busy_timeout = {}; \
+             PRAGMA journal_mode = WAL; \
+             PRAGMA synchronous = NORMAL;",
+            self.busy_timeout.as_millis()
+        ))
+        .map_err(Error::QueryError)?;
+        Ok(conn)
+    }
+
+    fn is_valid(&self, conn: &mut SqliteConnection) -> Result<(), Error> {
+        self.inner.is_valid(conn).map_err(|e| {
+            self.record_eviction("invalid");
+            e
+        })
+    }
+
+    fn has_broken(&self, conn: &mut SqliteConnection) -> bool {
+        let broken = self.inner.has_broken(conn);
+        if broken {
+            self.record_eviction("broken");
+        }
+        broken
+    }
+}
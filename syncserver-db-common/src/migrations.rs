@@ -0,0 +1,38 @@
+use std::{fs, path::Path};
+
+use diesel_migrations::MigrationConnection;
+
+use crate::error::MysqlError;
+
+/// Refuses a database whose latest applied migration isn't the latest one this
+/// build embeds: an older schema lacks what the build relies on, while a newer
+/// one may have changed it under the build's feet.
+pub fn check_schema_version(
+    conn: &impl MigrationConnection,
+    expected: &str,
+) -> Result<(), MysqlError> {
+    let found = conn.latest_run_migration_version()?;
+    if found.as_deref() == Some(expected) {
+        Ok(())
+    } else {
+        Err(MysqlError::schema_version_mismatch(expected, found))
+    }
+}
+
+/// The version diesel records for the latest migration in a migrations
+/// directory (the directory name up to the first `_`, without dashes). Used to
+/// check a crate's expected schema version is kept up to date.
+pub fn latest_migration_version(migrations_dir: &Path) -> Option<String> {
+    fs::read_dir(migrations_dir)
+        .ok()?
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            if !entry.file_type().ok()?.is_dir() {
+                return None;
+            }
+            let name = entry.file_name().into_string().ok()?;
+            let version = name.split('_').next()?.replace('-', "");
+            Some(version)
+        })
+        .max()
+}
@@ -0,0 +1,97 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+/// Runs work keyed by a name one caller at a time: concurrent callers for
+/// the same key queue behind the one in flight (and are expected to find its
+/// result cached), while callers for other keys proceed independently
+#[derive(Debug, Default)]
+pub struct SingleFlight {
+    flights: Mutex<HashMap<String, Arc<Mutex<()>>>>,
+}
+
+impl SingleFlight {
+    pub fn run<T>(&self, key: &str, f: impl FnOnce() -> T) -> T {
+        let flight = {
+            let mut flights = self.flights.lock().unwrap_or_else(PoisonError::into_inner);
+            Arc::clone(flights.entry(key.to_owned()).or_default())
+        };
+        let result = {
+            let _guard = flight.lock().unwrap_or_else(PoisonError::into_inner);
+            f()
+        };
+
+        let mut flights = self.flights.lock().unwrap_or_else(PoisonError::into_inner);
+        // Only the map and this caller still hold the flight: nobody is
+        // queued behind it
+        if Arc::strong_count(&flight) == 2 {
+            flights.remove(key);
+        }
+        result
+    }
+
+    /// The number of keys with work in flight
+    pub fn len(&self) -> usize {
+        self.flights
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Barrier,
+        },
+        thread,
+        time::Duration,
+    };
+
+    use super::*;
+
+    #[test]
+    fn one_flight_per_key() {
+        let flights = Arc::new(SingleFlight::default());
+        let cached = Arc::new(Mutex::new(None));
+        let created = Arc::new(AtomicUsize::new(0));
+        let barrier = Arc::new(Barrier::new(8));
+
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let (flights, cached, created, barrier) = (
+                    Arc::clone(&flights),
+                    Arc::clone(&cached),
+                    Arc::clone(&created),
+                    Arc::clone(&barrier),
+                );
+                thread::spawn(move || {
+                    barrier.wait();
+                    flights.run("xxx_col1", || {
+                        if let Some(id) = *cached.lock().unwrap() {
+                            return id;
+                        }
+                        // Widen the window for racing creations
+                        thread::sleep(Duration::from_millis(10));
+                        let id = created.fetch_add(1, Ordering::SeqCst) + 101;
+                        *cached.lock().unwrap() = Some(id);
+                        id
+                    })
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            assert_eq!(handle.join().unwrap(), 101);
+        }
+        assert_eq!(created.load(Ordering::SeqCst), 1);
+        assert!(flights.is_empty());
+    }
+}
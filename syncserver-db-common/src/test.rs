@@ -1,5 +1,4 @@
 use diesel::{
-    mysql::MysqlConnection,
     r2d2::{CustomizeConnection, Error as PoolError},
     Connection,
 };
@@ -7,8 +6,10 @@ use diesel::{
 #[derive(Debug)]
 pub struct TestTransactionCustomizer;
 
-impl CustomizeConnection<MysqlConnection, PoolError> for TestTransactionCustomizer {
-    fn on_acquire(&self, conn: &mut MysqlConnection) -> Result<(), PoolError> {
+impl<C: Connection + Send + 'static> CustomizeConnection<C, PoolError>
+    for TestTransactionCustomizer
+{
+    fn on_acquire(&self, conn: &mut C) -> Result<(), PoolError> {
         conn.begin_test_transaction().map_err(PoolError::QueryError)
     }
 }
@@ -21,7 +21,9 @@ use serde::{
     Serialize,
 };
 
-use syncserver_common::{from_error, impl_fmt_display, MetricError, ReportableError};
+use syncserver_common::{
+    from_error, impl_fmt_display, InternalError, MetricError, ReportableError, X_WEAVE_BACKOFF,
+};
 use syncstorage_db::{DbError, DbErrorIntrospect};
 
 use thiserror::Error;
@@ -51,7 +53,8 @@ pub enum WeaveError {
 /// Common `Result` type.
 pub type ApiResult<T> = Result<T, ApiError>;
 
-/// How long the client should wait before retrying a conflicting write.
+/// How long the client should wait before retrying a conflicting write (or
+/// one that timed out waiting on another's lock).
 pub const RETRY_AFTER: u8 = 10;
 
 /// Top-level error type.
@@ -83,6 +86,11 @@ pub enum ApiErrorKind {
 
     #[error("{}", _0)]
     Validation(ValidationError),
+
+    /// A write past its collection's rate limit, retryable after the given
+    /// number of seconds
+    #[error("Collection write rate limit exceeded")]
+    Throttled(u32),
 }
 
 impl ApiErrorKind {
@@ -91,6 +99,7 @@ impl ApiErrorKind {
             ApiErrorKind::Hawk(err) => err.metric_label(),
             ApiErrorKind::Db(err) => err.metric_label(),
             ApiErrorKind::Validation(err) => err.metric_label(),
+            ApiErrorKind::Throttled(_) => Some("storage.write_throttled".to_owned()),
             _ => None,
         }
     }
@@ -110,6 +119,8 @@ impl ApiError {
         match &self.kind {
             ApiErrorKind::Validation(ver) => ver.weave_error_code(),
             ApiErrorKind::Db(dber) if dber.is_quota() => WeaveError::OverQuota,
+            ApiErrorKind::Db(dber) if dber.is_invalid_value() => WeaveError::InvalidWbo,
+            ApiErrorKind::Db(dber) if dber.is_too_large() => WeaveError::SizeLimitExceeded,
             _ => WeaveError::UnknownError,
         }
     }
@@ -137,6 +148,10 @@ impl ApiError {
         matches!(&self.kind, ApiErrorKind::Db(dbe) if dbe.is_conflict())
     }
 
+    pub fn is_lock_timeout(&self) -> bool {
+        matches!(&self.kind, ApiErrorKind::Db(dbe) if dbe.is_lock_timeout())
+    }
+
     pub fn is_quota(&self) -> bool {
         matches!(&self.kind, ApiErrorKind::Db(dbe) if dbe.is_quota())
     }
@@ -170,6 +185,12 @@ impl From<std::io::Error> for ApiError {
     }
 }
 
+impl InternalError for ApiError {
+    fn internal_error(message: String) -> Self {
+        ApiErrorKind::Internal(message).into()
+    }
+}
+
 impl From<ApiErrorKind> for ApiError {
     fn from(kind: ApiErrorKind) -> Self {
         let status = match &kind {
@@ -179,6 +200,7 @@ impl From<ApiErrorKind> for ApiError {
                 StatusCode::INTERNAL_SERVER_ERROR
             }
             ApiErrorKind::Validation(error) => error.status,
+            ApiErrorKind::Throttled(_) => StatusCode::SERVICE_UNAVAILABLE,
         };
 
         Self {
@@ -198,9 +220,18 @@ impl ResponseError for ApiError {
         //
         // So instead we translate our error to a backwards compatible one
         let mut resp = HttpResponse::build(self.status);
-        if self.is_conflict() {
+        if self.is_conflict() || self.is_lock_timeout() {
             resp.header("Retry-After", RETRY_AFTER.to_string());
         };
+        if let ApiErrorKind::Throttled(seconds) = self.kind {
+            resp.header("Retry-After", seconds.to_string());
+            resp.header(X_WEAVE_BACKOFF, seconds.to_string());
+        }
+        if let ApiErrorKind::Hawk(_) = self.kind {
+            // Challenge the client to (re)authenticate with Hawk. Like the
+            // body, the challenge doesn't detail why the request was rejected
+            resp.header("WWW-Authenticate", "Hawk");
+        }
         resp.json(self.weave_error_code() as i32)
     }
 }
@@ -243,6 +274,7 @@ impl Serialize for ApiErrorKind {
             ApiErrorKind::NoServerState => {
                 Serialize::serialize("No State information found", serializer)
             }
+            ApiErrorKind::Throttled(_) => serialize_string_to_array(serializer, self),
         }
     }
 }
@@ -9,6 +9,8 @@ extern crate validator_derive;
 #[macro_use]
 pub mod error;
 pub mod logging;
+#[cfg(feature = "profiler")]
+pub mod profiler;
 pub mod server;
 pub mod tokenserver;
 pub mod web;
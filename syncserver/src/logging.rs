@@ -1,12 +1,102 @@
-use std::io;
+use std::{
+    collections::BTreeMap,
+    env, fmt, io,
+    str::FromStr,
+    sync::{Arc, RwLock},
+};
 
 use crate::error::ApiResult;
 
-use slog::{self, slog_o, Drain};
+use lazy_static::lazy_static;
+use sentry::{protocol::Breadcrumb, Level as SentryLevel};
+use serde_json::Value;
+use slog::{self, slog_o, Drain, FilterLevel, Key, Level, OwnedKVList, Record, KV};
+use slog_envlogger::{EnvLogger, LogBuilder};
 use slog_mozlog_json::MozLogJson;
 
+type AsyncDrain = slog::Fuse<slog_async::Async>;
+
+lazy_static! {
+    static ref LOG_FILTER: RwLock<Option<LogFilter>> = RwLock::new(None);
+}
+
+/// The global logger's `RUST_LOG` style filter, in front of its output
+struct LogFilter {
+    spec: String,
+    drain: EnvLogger<Arc<AsyncDrain>>,
+    output: Arc<AsyncDrain>,
+}
+
+impl LogFilter {
+    fn new(spec: String, output: Arc<AsyncDrain>) -> Self {
+        let drain = LogBuilder::new(Arc::clone(&output)).parse(&spec).build();
+        Self {
+            spec,
+            drain,
+            output,
+        }
+    }
+}
+
+/// Passes records through the current `LOG_FILTER`, so it can be replaced
+/// without rebuilding the global logger
+struct ReloadableFilter;
+
+impl Drain for ReloadableFilter {
+    type Ok = ();
+    type Err = slog::Never;
+
+    fn log(&self, record: &Record<'_>, values: &OwnedKVList) -> Result<(), slog::Never> {
+        if let Ok(filter) = LOG_FILTER.read() {
+            if let Some(filter) = filter.as_ref() {
+                filter.drain.log(record, values)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The current log filter, in `RUST_LOG` syntax
+pub fn log_filter() -> Option<String> {
+    LOG_FILTER
+        .read()
+        .ok()?
+        .as_ref()
+        .map(|filter| filter.spec.clone())
+}
+
+/// Replace the log filter at runtime (e.g. `info,syncstorage_mysql=debug`:
+/// a global level, then per module levels), as `RUST_LOG` sets it at startup.
+///
+/// Fails when logging isn't initialized or on a directive with an unknown
+/// level, which `slog_envlogger` would otherwise silently ignore
+pub fn set_log_filter(spec: &str) -> Result<(), String> {
+    let spec = spec.trim();
+    if spec.is_empty() {
+        return Err("Empty log filter".to_owned());
+    }
+    // Anything after a '/' is a regex over the messages
+    let directives = spec.split('/').next().unwrap_or_default();
+    for directive in directives.split(',').map(str::trim) {
+        if let Some((_, level)) = directive.split_once('=') {
+            FilterLevel::from_str(level)
+                .map_err(|_| format!("Invalid log level {:?} in {:?}", level, directive))?;
+        }
+    }
+
+    let mut filter = LOG_FILTER
+        .write()
+        .map_err(|_| "Log filter lock poisoned".to_owned())?;
+    let output = match filter.as_ref() {
+        Some(filter) => Arc::clone(&filter.output),
+        None => return Err("Logging isn't initialized".to_owned()),
+    };
+    *filter = Some(LogFilter::new(spec.to_owned(), output));
+    Ok(())
+}
+
 pub fn init_logging(json: bool) -> ApiResult<()> {
-    let logger = if json {
+    let output = if json {
         let hostname = hostname::get()
             .expect("Couldn't get hostname")
             .into_string()
@@ -22,16 +112,22 @@ pub fn init_logging(json: bool) -> ApiResult<()> {
             .hostname(hostname)
             .build()
             .fuse();
-        let drain = slog_envlogger::new(drain);
-        let drain = slog_async::Async::new(drain).build().fuse();
-        slog::Logger::root(drain, slog_o!())
+        slog_async::Async::new(drain).build().fuse()
     } else {
         let decorator = slog_term::TermDecorator::new().build();
         let drain = slog_term::FullFormat::new(decorator).build().fuse();
-        let drain = slog_envlogger::new(drain);
-        let drain = slog_async::Async::new(drain).build().fuse();
-        slog::Logger::root(drain, slog_o!())
+        slog_async::Async::new(drain).build().fuse()
     };
+    // Filtered ahead of the async drain, so dropped records aren't sent to
+    // its thread
+    let filter = LogFilter::new(env::var("RUST_LOG").unwrap_or_default(), Arc::new(output));
+    if let Ok(mut log_filter) = LOG_FILTER.write() {
+        *log_filter = Some(filter);
+    }
+    let logger = slog::Logger::root(
+        slog::Duplicate(SentryBreadcrumbs, ReloadableFilter).fuse(),
+        slog_o!(),
+    );
     // XXX: cancel slog_scope's NoGlobalLoggerSet for now, it's difficult to
     // prevent it from potentially panicing during tests. reset_logging resets
     // the global logger during shutdown anyway:
@@ -45,3 +141,106 @@ pub fn reset_logging() {
     let logger = slog::Logger::root(slog::Discard, slog_o!());
     slog_scope::set_global_logger(logger).cancel_reset();
 }
+
+/// `Settings::startup_report`'s pairs, logged as a record's fields so they're
+/// individually queryable in JSON logs
+pub struct StartupReport(pub Vec<(&'static str, String)>);
+
+impl KV for StartupReport {
+    fn serialize(
+        &self,
+        _record: &Record<'_>,
+        serializer: &mut dyn slog::Serializer,
+    ) -> slog::Result {
+        for (key, value) in &self.0 {
+            serializer.emit_str(Key::from(*key), value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Records every log record (regardless of the `RUST_LOG` filter) as a
+/// breadcrumb of the current Sentry `Hub`.
+///
+/// `middleware::sentry::report_error` binds a `Hub` to each request, so errors
+/// reported for a request carry what it logged beforehand (e.g. its db calls
+/// and their durations). This runs synchronously, ahead of `slog_async`,
+/// as the current `Hub` is that of the thread logging the record.
+struct SentryBreadcrumbs;
+
+impl Drain for SentryBreadcrumbs {
+    type Ok = ();
+    type Err = slog::Never;
+
+    fn log(&self, record: &Record<'_>, values: &OwnedKVList) -> Result<(), slog::Never> {
+        // The closure is only called when Sentry is enabled
+        sentry::add_breadcrumb(|| {
+            let mut data = BreadcrumbData::default();
+            // Ignore values failing to serialize: they're only missing from
+            // the breadcrumb
+            values.serialize(record, &mut data).ok();
+            record.kv().serialize(record, &mut data).ok();
+            Breadcrumb {
+                ty: "log".to_owned(),
+                category: Some(record.module().to_owned()),
+                level: sentry_level(record.level()),
+                message: Some(record.msg().to_string()),
+                data: data.0,
+                ..Default::default()
+            }
+        });
+        Ok(())
+    }
+}
+
+fn sentry_level(level: Level) -> SentryLevel {
+    match level {
+        Level::Critical | Level::Error => SentryLevel::Error,
+        Level::Warning => SentryLevel::Warning,
+        Level::Info => SentryLevel::Info,
+        Level::Debug | Level::Trace => SentryLevel::Debug,
+    }
+}
+
+#[derive(Default)]
+struct BreadcrumbData(BTreeMap<String, Value>);
+
+impl slog::Serializer for BreadcrumbData {
+    fn emit_arguments(&mut self, key: Key, val: &fmt::Arguments<'_>) -> slog::Result {
+        self.0.insert(key.to_string(), Value::from(val.to_string()));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use slog::{o, Logger};
+
+    use super::*;
+
+    #[test]
+    fn rejects_invalid_log_filters() {
+        assert!(set_log_filter("").is_err());
+        assert!(set_log_filter("info,syncstorage_mysql=loud").is_err());
+    }
+
+    #[test]
+    fn records_breadcrumbs() {
+        let logger = Logger::root(SentryBreadcrumbs.fuse(), o!("version" => "1.0"));
+        let events = sentry::test::with_captured_events(|| {
+            slog::debug!(logger, "Db call get_bsos took {}ms", 3; "db_call" => "get_bsos");
+            sentry::capture_message("oops", SentryLevel::Error);
+        });
+
+        let breadcrumbs = &events[0].breadcrumbs;
+        assert_eq!(breadcrumbs.len(), 1);
+        let breadcrumb = &breadcrumbs[0];
+        assert_eq!(breadcrumb.level, SentryLevel::Debug);
+        assert_eq!(
+            breadcrumb.message.as_deref(),
+            Some("Db call get_bsos took 3ms")
+        );
+        assert_eq!(breadcrumb.data["db_call"], "get_bsos");
+        assert_eq!(breadcrumb.data["version"], "1.0");
+    }
+}
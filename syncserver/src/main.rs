@@ -2,26 +2,212 @@
 #[macro_use]
 extern crate slog_scope;
 
-use std::{error::Error, sync::Arc};
+use std::{
+    error::Error,
+    fs::File,
+    io::{BufReader, BufWriter, Write},
+    sync::Arc,
+};
 
 use docopt::Docopt;
 use serde::Deserialize;
 
 use logging::init_logging;
 use syncserver::{logging, server};
+use syncserver_common::{BlockingThreadpool, CacheBackend, Metrics};
 use syncserver_settings::Settings;
+use syncstorage_db::{
+    backup::{self, UserSnapshot},
+    params, Db, DbPool, DbPoolImpl, SpannerDdl, UserIdentifier,
+};
 
 const USAGE: &str = "
-Usage: syncstorage [options]
+Usage:
+    syncstorage [options]
+    syncstorage migrate [options]
+    syncstorage backup <archive> [options]
+    syncstorage restore <archive> [options]
+    syncstorage resync [options]
+    syncstorage spanner-ddl (print | diff | apply) [options]
+
+Commands:
+    migrate                  Apply pending database migrations and exit.
+    backup                   Snapshot a user's storage into an archive.
+    restore                  Replace a user's storage with an archive's.
+    resync                   Force a user's clients to fully resync.
+    spanner-ddl              Print the expected Spanner schema, diff the
+                             database's against it or create what's missing.
 
 Options:
     -h, --help               Show this message.
     --config=CONFIGFILE      Syncstorage configuration file path.
+    --legacy-id=ID           The user's numeric id (MySQL).
+    --fxa-uid=UID            The user's FxA uid (Spanner).
+    --fxa-kid=KID            The user's FxA kid (Spanner).
+    --tenant=NAME            The user's tenant, when not the default one.
+    --ttl-policies           Expect Spanner's row deletion policies.
 ";
 
+/// Collections wiped by `resync`: without meta/global and crypto/keys,
+/// clients start over as after a node reassignment, generating new keys and
+/// re-uploading their data
+const RESYNC_COLLECTIONS: [&str; 2] = ["meta", "crypto"];
+
 #[derive(Debug, Deserialize)]
 struct Args {
+    cmd_migrate: bool,
+    cmd_backup: bool,
+    cmd_restore: bool,
+    cmd_resync: bool,
+    cmd_spanner_ddl: bool,
+    cmd_diff: bool,
+    cmd_apply: bool,
+    arg_archive: Option<String>,
     flag_config: Option<String>,
+    flag_legacy_id: Option<u64>,
+    flag_fxa_uid: Option<String>,
+    flag_fxa_kid: Option<String>,
+    flag_tenant: Option<String>,
+    flag_ttl_policies: bool,
+}
+
+impl Args {
+    fn user_id(&self, settings: &Settings) -> Result<UserIdentifier, Box<dyn Error>> {
+        if self.flag_legacy_id.is_none() && self.flag_fxa_uid.is_none() {
+            return Err("Either --legacy-id or --fxa-uid (with --fxa-kid) is required".into());
+        }
+        let tenant_id = match self.flag_tenant.as_deref() {
+            None => 0,
+            Some(tenant) => *settings
+                .syncstorage
+                .tenants
+                .get(tenant)
+                .ok_or_else(|| format!("Unknown tenant: {}", tenant))?,
+        };
+        Ok(UserIdentifier {
+            legacy_id: self.flag_legacy_id.unwrap_or_default(),
+            fxa_uid: self.flag_fxa_uid.clone().unwrap_or_default(),
+            fxa_kid: self.flag_fxa_kid.clone().unwrap_or_default(),
+        }
+        .scoped_to_tenant(tenant_id))
+    }
+}
+
+/// Applies the pending migrations of the enabled services' databases
+fn migrate(settings: &Settings) -> Result<(), Box<dyn Error>> {
+    if settings.syncstorage.enabled {
+        info!("Migrating the syncstorage database");
+        syncstorage_db::run_migrations(&settings.syncstorage)
+            .map_err(|e| format!("Failed to migrate the syncstorage database: {}", e))?;
+    }
+    if settings.tokenserver.enabled {
+        info!("Migrating the tokenserver database");
+        tokenserver_db::run_embedded_migrations(&settings.tokenserver.database_url)
+            .map_err(|e| format!("Failed to migrate the tokenserver database: {}", e))?;
+    }
+    Ok(())
+}
+
+fn db_pool(settings: &Settings) -> Result<DbPoolImpl, Box<dyn Error>> {
+    Ok(DbPoolImpl::new(
+        &settings.syncstorage,
+        &Metrics::noop(),
+        Arc::new(BlockingThreadpool::default()),
+        &CacheBackend::from_url(settings.cache_url.as_deref())?,
+    )?)
+}
+
+/// Writes a snapshot of a user's storage to the archive
+async fn backup(settings: &Settings, args: &Args, archive: &str) -> Result<(), Box<dyn Error>> {
+    let user_id = args.user_id(settings)?;
+    let db = db_pool(settings)?.get().await?;
+    let snapshot = backup::snapshot_user(&*db, &user_id).await?;
+    let mut writer = BufWriter::new(File::create(archive)?);
+    serde_json::to_writer(&mut writer, &snapshot)?;
+    writer.flush()?;
+    info!(
+        "Backed up {} BSOs in {} collections to {}",
+        snapshot
+            .collections
+            .iter()
+            .map(|c| c.bsos.len())
+            .sum::<usize>(),
+        snapshot.collections.len(),
+        archive
+    );
+    Ok(())
+}
+
+/// Replaces a user's storage with an archive's snapshot. Each collection's
+/// restored in its own transaction, keeping them within Spanner's mutation
+/// limits
+async fn restore(settings: &Settings, args: &Args, archive: &str) -> Result<(), Box<dyn Error>> {
+    let user_id = args.user_id(settings)?;
+    let snapshot: UserSnapshot = serde_json::from_reader(BufReader::new(File::open(archive)?))?;
+    backup::check_version(&snapshot)?;
+
+    let mut db = db_pool(settings)?.get().await?;
+    // Usage is still tracked, but the restore isn't limited by it
+    db.set_quota(
+        settings.syncstorage.enable_quota,
+        settings.syncstorage.limits.max_quota_limit as usize,
+        false,
+    );
+    db.begin(true).await?;
+    db.delete_storage(user_id.clone()).await?;
+    // Clients synced before the restore mustn't write their data back over it
+    db.bump_storage_epoch(user_id.clone()).await?;
+    db.commit().await?;
+    for collection in &snapshot.collections {
+        db.begin(true).await?;
+        backup::restore_collection(&*db, &user_id, collection).await?;
+        db.commit().await?;
+        info!(
+            "Restored {} BSOs to {}",
+            collection.bsos.len(),
+            collection.name
+        );
+    }
+    Ok(())
+}
+
+/// Forces a user's clients to fully resync (e.g. after corrupted data was
+/// detected server side)
+async fn resync(settings: &Settings, args: &Args) -> Result<(), Box<dyn Error>> {
+    let user_id = args.user_id(settings)?;
+    let db = db_pool(settings)?.get().await?;
+    db.begin(true).await?;
+    let result = db
+        .delete_collections(params::DeleteCollections {
+            user_id: user_id.clone(),
+            collections: RESYNC_COLLECTIONS.iter().map(|c| c.to_string()).collect(),
+        })
+        .await?;
+    db.bump_storage_epoch(user_id).await?;
+    db.commit().await?;
+    if result.collections.values().any(|deleted| *deleted) {
+        info!("Reset the user's storage, forcing a resync");
+    } else {
+        info!("The user had no meta or crypto collections: nothing to reset");
+    }
+    Ok(())
+}
+
+/// Prints the expected Spanner schema, diffs the database's against it or
+/// creates what's missing from it
+fn spanner_ddl(settings: &Settings, args: &Args) -> Result<(), Box<dyn Error>> {
+    let command = if args.cmd_apply {
+        SpannerDdl::Apply
+    } else if args.cmd_diff {
+        SpannerDdl::Diff
+    } else {
+        SpannerDdl::Print
+    };
+    print!(
+        "{}",
+        syncstorage_db::spanner_ddl(&settings.syncstorage, command, args.flag_ttl_policies)?
+    );
+    Ok(())
 }
 
 #[actix_web::main]
@@ -31,6 +217,30 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .unwrap_or_else(|e| e.exit());
     let settings = Settings::with_env_and_config_file(args.flag_config.as_deref())?;
     init_logging(!settings.human_logs).expect("Logging failed to initialize");
+    if args.cmd_migrate {
+        let result = migrate(&settings);
+        logging::reset_logging();
+        return result;
+    }
+    if let Some(archive) = args.arg_archive.as_deref() {
+        let result = if args.cmd_backup {
+            backup(&settings, &args, archive).await
+        } else {
+            restore(&settings, &args, archive).await
+        };
+        logging::reset_logging();
+        return result;
+    }
+    if args.cmd_resync {
+        let result = resync(&settings, &args).await;
+        logging::reset_logging();
+        return result;
+    }
+    if args.cmd_spanner_ddl {
+        let result = spanner_ddl(&settings, &args);
+        logging::reset_logging();
+        return result;
+    }
     debug!("Starting up...");
     // Set SENTRY_DSN environment variable to enable Sentry.
     // Avoid its default reqwest transport for now due to issues w/
@@ -45,6 +255,10 @@ async fn main() -> Result<(), Box<dyn Error>> {
         ..sentry::ClientOptions::default()
     });
 
+    info!("Startup report"; logging::StartupReport(settings.startup_report()));
+    #[cfg(feature = "profiler")]
+    syncserver::profiler::spawn(&settings)?;
+
     // Setup and run the server
     let banner = settings.banner();
     let server = if !settings.syncstorage.enabled {
@@ -0,0 +1,170 @@
+//! A Google Cloud Profiler agent, continuously profiling the process: the
+//! Profiler API is long polled for the profiles it wants of this deployment,
+//! which are then collected and uploaded.
+//!
+//! Only CPU profiles are offered: heap profiles would require an instrumented
+//! allocator. Runs on its own thread, authenticating as the instance's
+//! service account (via the GCE/GKE metadata server).
+use std::{error::Error, io::Write, thread, time::Duration};
+
+use base64::{engine, Engine};
+use flate2::{write::GzEncoder, Compression};
+use prost::Message;
+use reqwest::{blocking::Client, StatusCode};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use syncserver_settings::Settings;
+
+const API_URL: &str = "https://cloudprofiler.googleapis.com/v2";
+const METADATA_URL: &str = "http://metadata.google.internal/computeMetadata/v1";
+/// Sampling frequency of CPU profiles, in Hz
+const SAMPLE_FREQUENCY: i32 = 99;
+/// How long profiles are collected for when the API doesn't say
+const DEFAULT_DURATION: Duration = Duration::from_secs(10);
+/// How long to wait after failing to profile. The API also rejects requests
+/// (with a 409) while it doesn't want a profile
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+type ProfilerResult<T> = Result<T, Box<dyn Error>>;
+
+#[derive(Deserialize)]
+struct AccessToken {
+    access_token: String,
+}
+
+struct Agent {
+    client: Client,
+    service: String,
+    project_id: Option<String>,
+}
+
+/// Starts profiling in the background, when `profiler_service` is set
+pub fn spawn(settings: &Settings) -> ProfilerResult<()> {
+    let service = match &settings.profiler_service {
+        Some(service) => service.clone(),
+        None => return Ok(()),
+    };
+    let agent = Agent {
+        // Requests for profiles are long polled
+        client: Client::builder().timeout(None).build()?,
+        service,
+        project_id: settings.profiler_project_id.clone(),
+    };
+    thread::Builder::new()
+        .name("profiler".to_owned())
+        .spawn(move || loop {
+            if let Err(e) = agent.profile() {
+                let status = e
+                    .downcast_ref::<reqwest::Error>()
+                    .and_then(reqwest::Error::status);
+                if status != Some(StatusCode::CONFLICT) {
+                    warn!("⚠️ Profiling failed: {}", e);
+                }
+                thread::sleep(RETRY_INTERVAL);
+            }
+        })?;
+    Ok(())
+}
+
+impl Agent {
+    /// Waits for the API to want a profile, then collects and uploads it
+    fn profile(&self) -> ProfilerResult<()> {
+        let project_id = match &self.project_id {
+            Some(project_id) => project_id.clone(),
+            None => self.metadata("project/project-id")?,
+        };
+        let mut profile: Map<String, Value> = self
+            .client
+            .post(&format!("{}/projects/{}/profiles", API_URL, project_id))
+            .bearer_auth(self.access_token()?)
+            .json(&json!({
+                "deployment": {
+                    "projectId": project_id,
+                    "target": self.service,
+                    "labels": { "version": env!("CARGO_PKG_VERSION") },
+                },
+                "profileType": ["CPU"],
+            }))
+            .send()?
+            .error_for_status()?
+            .json()?;
+        let name = profile
+            .get("name")
+            .and_then(Value::as_str)
+            .ok_or("Profile without a name")?
+            .to_owned();
+        let duration = profile
+            .get("duration")
+            .and_then(Value::as_str)
+            .and_then(parse_duration)
+            .unwrap_or(DEFAULT_DURATION);
+
+        let profile_bytes = collect_cpu_profile(duration)?;
+        profile.insert(
+            "profileBytes".to_owned(),
+            Value::from(engine::general_purpose::STANDARD.encode(profile_bytes)),
+        );
+        // The access token may have expired while profiling
+        self.client
+            .patch(&format!("{}/{}", API_URL, name))
+            .bearer_auth(self.access_token()?)
+            .json(&profile)
+            .send()?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    fn access_token(&self) -> ProfilerResult<String> {
+        let token: AccessToken =
+            serde_json::from_str(&self.metadata("instance/service-accounts/default/token")?)?;
+        Ok(token.access_token)
+    }
+
+    fn metadata(&self, path: &str) -> ProfilerResult<String> {
+        Ok(self
+            .client
+            .get(&format!("{}/{}", METADATA_URL, path))
+            .header("Metadata-Flavor", "Google")
+            .timeout(Duration::from_secs(5))
+            .send()?
+            .error_for_status()?
+            .text()?)
+    }
+}
+
+/// A gzipped pprof CPU profile of the process over `duration`
+fn collect_cpu_profile(duration: Duration) -> ProfilerResult<Vec<u8>> {
+    let guard = pprof::ProfilerGuardBuilder::default()
+        .frequency(SAMPLE_FREQUENCY)
+        .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+        .build()?;
+    thread::sleep(duration);
+    let profile = guard.report().build()?.pprof()?;
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&profile.encode_to_vec())?;
+    Ok(encoder.finish()?)
+}
+
+/// Parses the API's durations (in seconds, e.g. `"10s"`)
+fn parse_duration(duration: &str) -> Option<Duration> {
+    duration
+        .strip_suffix('s')?
+        .parse::<f64>()
+        .ok()
+        .filter(|seconds| seconds.is_finite() && *seconds > 0.0)
+        .map(Duration::from_secs_f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("10s"), Some(Duration::from_secs(10)));
+        assert_eq!(parse_duration("0.5s"), Some(Duration::from_millis(500)));
+        assert_eq!(parse_duration("10"), None);
+        assert_eq!(parse_duration("-1s"), None);
+    }
+}
@@ -0,0 +1,183 @@
+//! Detects pathological clients (e.g. a buggy client looping on uploads) by
+//! their per-uid write rates, optionally asking only them to back off.
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use syncstorage_settings::Settings;
+
+/// Window over which writes are counted
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// Max number of users tracked at once
+const MAX_TRACKED_USERS: usize = 100_000;
+
+/// Why a user was flagged
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AbuseReason {
+    /// Too many BSOs written
+    Writes,
+    /// Too many payload bytes written
+    Bytes,
+}
+
+impl fmt::Display for AbuseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AbuseReason::Writes => "writes",
+            AbuseReason::Bytes => "bytes",
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct AbuseDetector {
+    max_writes: Option<u64>,
+    max_bytes: Option<u64>,
+    backoff_seconds: Option<u32>,
+    users: Mutex<HashMap<String, UserWrites>>,
+}
+
+#[derive(Debug)]
+struct UserWrites {
+    window_start: Instant,
+    writes: u64,
+    bytes: u64,
+    /// Until when the user's asked to back off
+    backoff_until: Option<Instant>,
+}
+
+impl AbuseDetector {
+    pub fn from_settings(settings: &Settings) -> Self {
+        Self {
+            max_writes: settings.abuse_max_writes_per_minute.map(u64::from),
+            max_bytes: settings.abuse_max_bytes_per_minute.map(u64::from),
+            backoff_seconds: settings.abuse_backoff_seconds,
+            ..Default::default()
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.max_writes.is_some() || self.max_bytes.is_some()
+    }
+
+    /// Record a user's write of `writes` BSOs totalling `bytes` of payloads,
+    /// returning why they're flagged when it pushes them past a threshold.
+    /// Users are only flagged once per window
+    pub fn record_writes(&self, uid: &str, writes: usize, bytes: usize) -> Option<AbuseReason> {
+        if !self.is_enabled() {
+            return None;
+        }
+        let now = Instant::now();
+        let mut users = self.lock();
+        if !users.contains_key(uid) && users.len() >= MAX_TRACKED_USERS {
+            users.retain(|_, user| user.is_active(now));
+            if users.len() >= MAX_TRACKED_USERS {
+                return None;
+            }
+        }
+        let user = users.entry(uid.to_owned()).or_insert(UserWrites {
+            window_start: now,
+            writes: 0,
+            bytes: 0,
+            backoff_until: None,
+        });
+        if now.duration_since(user.window_start) >= WINDOW {
+            user.window_start = now;
+            user.writes = 0;
+            user.bytes = 0;
+        }
+
+        let crossed = |max: Option<u64>, before: u64, after: u64| {
+            max.is_some_and(|max| before <= max && after > max)
+        };
+        let (writes_before, bytes_before) = (user.writes, user.bytes);
+        user.writes += writes as u64;
+        user.bytes += bytes as u64;
+        let reason = if crossed(self.max_writes, writes_before, user.writes) {
+            AbuseReason::Writes
+        } else if crossed(self.max_bytes, bytes_before, user.bytes) {
+            AbuseReason::Bytes
+        } else {
+            return None;
+        };
+        if let Some(seconds) = self.backoff_seconds {
+            user.backoff_until = Some(now + Duration::from_secs(seconds.into()));
+        }
+        Some(reason)
+    }
+
+    /// The `X-Weave-Backoff` (in seconds) to send a flagged user, if any
+    pub fn backoff(&self, uid: &str) -> Option<u32> {
+        let backoff_seconds = self.backoff_seconds?;
+        let users = self.lock();
+        let backoff_until = users.get(uid)?.backoff_until?;
+        (Instant::now() < backoff_until).then_some(backoff_seconds)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, UserWrites>> {
+        self.users.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl UserWrites {
+    fn is_active(&self, now: Instant) -> bool {
+        now.duration_since(self.window_start) < WINDOW
+            || matches!(self.backoff_until, Some(until) if now < until)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detector(backoff_seconds: Option<u32>) -> AbuseDetector {
+        AbuseDetector::from_settings(&Settings {
+            abuse_max_writes_per_minute: Some(100),
+            abuse_max_bytes_per_minute: Some(10_000),
+            abuse_backoff_seconds: backoff_seconds,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn flags_users_once_past_thresholds() {
+        let detector = detector(Some(3600));
+        assert_eq!(detector.record_writes("1", 100, 100), None);
+        assert_eq!(detector.backoff("1"), None);
+        assert_eq!(
+            detector.record_writes("1", 1, 100),
+            Some(AbuseReason::Writes)
+        );
+        assert_eq!(detector.record_writes("1", 100, 100), None);
+        assert_eq!(detector.backoff("1"), Some(3600));
+        // Other users are left alone
+        assert_eq!(detector.backoff("2"), None);
+
+        assert_eq!(
+            detector.record_writes("2", 1, 10_001),
+            Some(AbuseReason::Bytes)
+        );
+        assert_eq!(detector.backoff("2"), Some(3600));
+    }
+
+    #[test]
+    fn only_reports_without_backoff_seconds() {
+        let detector = detector(None);
+        assert_eq!(
+            detector.record_writes("1", 101, 0),
+            Some(AbuseReason::Writes)
+        );
+        assert_eq!(detector.backoff("1"), None);
+    }
+
+    #[test]
+    fn disabled_without_thresholds() {
+        let detector = AbuseDetector::from_settings(&Settings::default());
+        assert_eq!(detector.record_writes("1", 1_000_000, 1_000_000), None);
+        assert!(detector.lock().is_empty());
+    }
+}
@@ -1,50 +1,70 @@
 //! Main application server
 
-use std::{env, sync::Arc, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use actix_cors::Cors;
 use actix_web::{
-    dev::{self, Payload},
+    dev::{self, HttpServiceFactory, Payload},
     http::StatusCode,
     http::{header::LOCATION, Method},
     middleware::errhandlers::ErrorHandlers,
     web::{self, Data},
     App, FromRequest, HttpRequest, HttpResponse, HttpServer,
 };
-use cadence::{Gauged, StatsdClient};
+use cadence::{Counted, Gauged, StatsdClient};
 use futures::future::{self, Ready};
-use syncserver_common::{BlockingThreadpool, Metrics};
+use syncserver_common::{ActiveUsers, BlockingThreadpool, CacheBackend, Metrics, TaskClass};
 use syncserver_db_common::{GetPoolState, PoolState};
 use syncserver_settings::Settings;
-use syncstorage_db::{DbError, DbPool, DbPoolImpl};
-use syncstorage_settings::{Deadman, ServerLimits};
+use syncstorage_db::{purge, results, DbError, DbPool, DbPoolImpl};
+use syncstorage_settings::{Deadman, Quota, ServerLimits};
 use tokio::{sync::RwLock, time};
 
-use crate::error::ApiError;
-use crate::server::tags::Taggable;
+use crate::error::{ApiError, ApiErrorKind};
+use crate::server::{
+    abuse::AbuseDetector, overload::Overload, tags::Taggable, throttle::WriteThrottle,
+};
 use crate::tokenserver;
-use crate::web::{handlers, middleware};
+use crate::web::{
+    auth::{HawkKeyCache, HawkKeyCacheStats, HawkNonceCache, HawkNonceCacheStats},
+    handlers, middleware,
+};
 
 pub const BSO_ID_REGEX: &str = r"[ -~]{1,64}";
 pub const COLLECTION_ID_REGEX: &str = r"[a-zA-Z0-9._-]{1,32}";
 pub const SYNC_DOCS_URL: &str =
     "https://mozilla-services.readthedocs.io/en/latest/storage/apis-1.5.html";
-const MYSQL_UID_REGEX: &str = r"[0-9]{1,10}";
-const SYNC_VERSION_PATH: &str = "1.5";
+/// Either the legacy numeric uid or the (hex) FxA uid
+const UID_REGEX: &str = r"[0-9]{1,10}|[0-9a-fA-F]{32}";
+pub const SYNC_VERSION_PATH: &str = "1.5";
 
+pub mod abuse;
+pub mod overload;
 pub mod tags;
 #[cfg(test)]
 mod test;
+pub mod throttle;
 pub mod user_agent;
 
 /// This is the global HTTP state object that will be made available to all
 /// HTTP API calls.
+#[derive(Clone)]
 pub struct ServerState {
     pub db_pool: Box<dyn DbPool<Error = DbError>>,
 
     /// Server-enforced limits for request payloads.
     pub limits: Arc<ServerLimits>,
 
+    /// Caps on the BSOs returned by collection GETs (see
+    /// `syncstorage_settings::Settings::max_get_records`/`max_get_bytes`)
+    pub max_get_records: Option<u32>,
+    pub max_get_bytes: Option<u32>,
+
     /// limits rendered as JSON
     pub limits_json: String,
 
@@ -55,7 +75,53 @@ pub struct ServerState {
 
     pub quota_enabled: bool,
 
+    /// Usage past which writes carry quota warnings (see
+    /// `syncstorage_settings::Settings::quota_soft_limit`)
+    pub quota_soft_limit: Option<u32>,
+
+    /// The quota limits, resolving each user's hard limit (see
+    /// `Quota::limit_for`)
+    pub quota: Quota,
+
     pub deadman: Arc<RwLock<Deadman>>,
+
+    /// Distinct users seen per day/week, when reported (see
+    /// `active_users_report_interval`)
+    pub active_users: Option<Arc<ActiveUsers>>,
+
+    /// Whether the server's saturated and should ask clients to back off
+    pub overload: Arc<Overload>,
+
+    /// Per user write rates, flagging pathological clients
+    pub abuse: Arc<AbuseDetector>,
+
+    /// Per user write rate limits of high-churn collections
+    pub write_throttle: Arc<WriteThrottle>,
+
+    /// Ids of the tenants served besides the default one, by name (see
+    /// `syncstorage_settings::Settings::tenants`)
+    pub tenants: Arc<HashMap<String, u16>>,
+
+    /// Hawk keys of recently seen tokens
+    pub hawk_key_cache: Arc<HawkKeyCache>,
+
+    /// Nonces of recently accepted Hawk requests, rejecting their replays
+    pub hawk_nonce_cache: Arc<HawkNonceCache>,
+
+    /// Bearer token required by admin endpoints (disabled when unset)
+    pub admin_token: Option<String>,
+
+    /// Latency budget past which requests' phase timings are logged (see
+    /// `middleware::slow_requests`)
+    pub slow_request_threshold: Option<Duration>,
+
+    /// Statements a request may issue before it's flagged (see
+    /// `middleware::query_budget`)
+    pub query_budget: Option<u32>,
+
+    /// BSOs reads didn't find, to purge in case they expired (see
+    /// `syncstorage_settings::Settings::purge_on_read_queue_size`)
+    pub expired_bso_queue: Option<purge::ExpiredBsoQueue>,
 }
 
 pub fn cfg_path(path: &str) -> String {
@@ -65,7 +131,85 @@ pub fn cfg_path(path: &str) -> String {
             &format!("{{collection:{}}}", COLLECTION_ID_REGEX),
         )
         .replace("{bso}", &format!("{{bso:{}}}", BSO_ID_REGEX));
-    format!("/{}/{{uid:{}}}{}", SYNC_VERSION_PATH, MYSQL_UID_REGEX, path)
+    format!("/{}/{{uid:{}}}{}", SYNC_VERSION_PATH, UID_REGEX, path)
+}
+
+/// Register the storage API's routes, e.g. to mount them alongside other
+/// services: see `syncstorage_scope`
+pub fn configure_syncstorage(cfg: &mut web::ServiceConfig, limits: &ServerLimits) {
+    cfg.service(
+        web::resource(&cfg_path("/info/collections"))
+            .route(web::get().to(handlers::get_collections)),
+    )
+    .service(
+        web::resource(&cfg_path("/info/collection_counts"))
+            .route(web::get().to(handlers::get_collection_counts)),
+    )
+    .service(
+        web::resource(&cfg_path("/info/collection_usage"))
+            .route(web::get().to(handlers::get_collection_usage)),
+    )
+    .service(
+        web::resource(&cfg_path("/info/configuration"))
+            .route(web::get().to(handlers::get_configuration)),
+    )
+    .service(web::resource(&cfg_path("/info/quota")).route(web::get().to(handlers::get_quota)))
+    .service(web::resource(&cfg_path("")).route(web::delete().to(handlers::delete_all)))
+    .service(web::resource(&cfg_path("/storage")).route(web::delete().to(handlers::delete_all)))
+    .service(
+        web::resource(&cfg_path("/storage/{collection}"))
+            .app_data(
+                // Declare the payload limit for "normal" collections.
+                web::PayloadConfig::new(limits.max_request_bytes as usize),
+            )
+            .app_data(
+                // Declare the payload limits for "JSON" payloads
+                // (Specify "text/plain" for legacy client reasons)
+                web::JsonConfig::default()
+                    .limit(limits.max_request_bytes as usize)
+                    .content_type(|ct| ct == mime::TEXT_PLAIN),
+            )
+            .route(web::delete().to(handlers::delete_collection))
+            .route(web::head().to(handlers::head_collection))
+            .route(web::get().to(handlers::get_collection))
+            .route(web::post().to(handlers::post_collection)),
+    )
+    .service(
+        web::resource(&cfg_path("/storage/{collection}/{bso}"))
+            .app_data(web::PayloadConfig::new(limits.max_request_bytes as usize))
+            .app_data(
+                web::JsonConfig::default()
+                    .limit(limits.max_request_bytes as usize)
+                    .content_type(|ct| ct == mime::TEXT_PLAIN),
+            )
+            .route(web::delete().to(handlers::delete_bso))
+            .route(web::get().to(handlers::get_bso))
+            .route(web::put().to(handlers::put_bso)),
+    );
+}
+
+/// The storage API as a service mountable under `path` (e.g. "/sync",
+/// serving "/sync/1.5/{uid}/...") of an app embedding it, along with the
+/// storage middleware.
+///
+/// The embedding app provides the `ServerState` and `Arc<Secrets>` data
+/// the handlers and middleware expect. `path` mustn't include a "1.5"
+/// segment.
+pub fn syncstorage_scope(path: &str, limits: &ServerLimits) -> impl HttpServiceFactory {
+    // Middleware is applied LIFO, in build_app!'s order
+    web::scope(path)
+        .wrap(ErrorHandlers::new().handler(StatusCode::NOT_FOUND, ApiError::render_404))
+        .wrap_fn(middleware::body_limit::limit_request_body)
+        .wrap_fn(middleware::transaction::commit_successful)
+        .wrap_fn(middleware::query_budget::check_query_budget)
+        .wrap_fn(middleware::weave::set_weave_timestamp)
+        .wrap_fn(middleware::backoff::set_overload_backoff)
+        .wrap_fn(middleware::sentry::report_error)
+        .wrap_fn(middleware::rejectua::reject_user_agent)
+        .wrap_fn(middleware::emit_http_status_with_tokenserver_origin)
+        .wrap_fn(middleware::slow_requests::trace_slow_requests)
+        .wrap_fn(middleware::request_id::assign_request_id)
+        .configure(|cfg| configure_syncstorage(cfg, limits))
 }
 
 pub struct Server;
@@ -87,64 +231,19 @@ macro_rules! build_app {
             // These will wrap all outbound responses with matching status codes.
             .wrap(ErrorHandlers::new().handler(StatusCode::NOT_FOUND, ApiError::render_404))
             // These are our wrappers
+            .wrap_fn(middleware::body_limit::limit_request_body)
+            .wrap_fn(middleware::transaction::commit_successful)
+            .wrap_fn(middleware::query_budget::check_query_budget)
             .wrap_fn(middleware::weave::set_weave_timestamp)
+            .wrap_fn(middleware::backoff::set_overload_backoff)
             .wrap_fn(tokenserver::logging::handle_request_log_line)
             .wrap_fn(middleware::sentry::report_error)
             .wrap_fn(middleware::rejectua::reject_user_agent)
             .wrap($cors)
             .wrap_fn(middleware::emit_http_status_with_tokenserver_origin)
-            .service(
-                web::resource(&cfg_path("/info/collections"))
-                    .route(web::get().to(handlers::get_collections)),
-            )
-            .service(
-                web::resource(&cfg_path("/info/collection_counts"))
-                    .route(web::get().to(handlers::get_collection_counts)),
-            )
-            .service(
-                web::resource(&cfg_path("/info/collection_usage"))
-                    .route(web::get().to(handlers::get_collection_usage)),
-            )
-            .service(
-                web::resource(&cfg_path("/info/configuration"))
-                    .route(web::get().to(handlers::get_configuration)),
-            )
-            .service(
-                web::resource(&cfg_path("/info/quota")).route(web::get().to(handlers::get_quota)),
-            )
-            .service(web::resource(&cfg_path("")).route(web::delete().to(handlers::delete_all)))
-            .service(
-                web::resource(&cfg_path("/storage")).route(web::delete().to(handlers::delete_all)),
-            )
-            .service(
-                web::resource(&cfg_path("/storage/{collection}"))
-                    .app_data(
-                        // Declare the payload limit for "normal" collections.
-                        web::PayloadConfig::new($limits.max_request_bytes as usize),
-                    )
-                    .app_data(
-                        // Declare the payload limits for "JSON" payloads
-                        // (Specify "text/plain" for legacy client reasons)
-                        web::JsonConfig::default()
-                            .limit($limits.max_request_bytes as usize)
-                            .content_type(|ct| ct == mime::TEXT_PLAIN),
-                    )
-                    .route(web::delete().to(handlers::delete_collection))
-                    .route(web::get().to(handlers::get_collection))
-                    .route(web::post().to(handlers::post_collection)),
-            )
-            .service(
-                web::resource(&cfg_path("/storage/{collection}/{bso}"))
-                    .app_data(web::PayloadConfig::new($limits.max_request_bytes as usize))
-                    .app_data(
-                        web::JsonConfig::default()
-                            .limit($limits.max_request_bytes as usize)
-                            .content_type(|ct| ct == mime::TEXT_PLAIN),
-                    )
-                    .route(web::delete().to(handlers::delete_bso))
-                    .route(web::get().to(handlers::get_bso))
-                    .route(web::put().to(handlers::put_bso)),
-            )
+            .wrap_fn(middleware::slow_requests::trace_slow_requests)
+            .wrap_fn(middleware::request_id::assign_request_id)
+            .configure(|cfg| $crate::server::configure_syncstorage(cfg, &$limits))
             // Tokenserver
             .service(
                 web::resource("/1.0/{application}/{version}")
@@ -174,6 +273,24 @@ macro_rules! build_app {
                 })),
             )
             .service(web::resource("/__error__").route(web::get().to(handlers::test_error)))
+            // Admin
+            .service(
+                web::resource("/__backoff__")
+                    .route(web::get().to(handlers::get_backoff_broadcast))
+                    .route(web::put().to(handlers::put_backoff_broadcast)),
+            )
+            .service(
+                web::resource("/__delete_collections__")
+                    .route(web::post().to(handlers::post_delete_collections)),
+            )
+            .service(
+                web::resource("/__log_level__")
+                    .route(web::get().to(handlers::get_log_level))
+                    .route(web::put().to(handlers::put_log_level)),
+            )
+            .service(
+                web::resource("/__soft_reset__").route(web::post().to(handlers::post_soft_reset)),
+            )
             .service(web::resource("/").route(web::get().to(|_: HttpRequest| {
                 HttpResponse::Found()
                     .header(LOCATION, SYNC_DOCS_URL)
@@ -201,6 +318,7 @@ macro_rules! build_app_without_syncstorage {
             // For now, let's be permissive and use NGINX (the wrapping server)
             // for finer grained specification.
             .wrap($cors)
+            .wrap_fn(middleware::request_id::assign_request_id)
             .service(
                 web::resource("/1.0/{application}/{version}")
                     .route(web::get().to(tokenserver::handlers::get_tokenserver_result)),
@@ -240,28 +358,133 @@ macro_rules! build_app_without_syncstorage {
     };
 }
 
-impl Server {
-    pub async fn with_settings(settings: Settings) -> Result<dev::Server, ApiError> {
-        let settings_copy = settings.clone();
+impl ServerState {
+    /// Build the storage API's state from its settings, spawning its
+    /// background tasks
+    pub async fn from_settings(
+        settings: &Settings,
+        blocking_threadpool: Arc<BlockingThreadpool>,
+        cache_backend: &CacheBackend,
+    ) -> Result<Self, ApiError> {
         let metrics = syncserver_common::metrics_from_opts(
             &settings.syncstorage.statsd_label,
             settings.statsd_host.as_deref(),
             settings.statsd_port,
         )?;
-        let host = settings.host.clone();
-        let port = settings.port;
         let deadman = Arc::new(RwLock::new(Deadman::from(&settings.syncstorage)));
-        let blocking_threadpool = Arc::new(BlockingThreadpool::default());
+        let overload = Arc::new(Overload::from_settings(&settings.syncstorage));
+        let abuse = Arc::new(AbuseDetector::from_settings(&settings.syncstorage));
+        let write_throttle = Arc::new(WriteThrottle::from_settings(&settings.syncstorage));
+        let tenants = Arc::new(build_tenants(&settings.syncstorage.tenants)?);
+        let hawk_key_cache = Arc::new(HawkKeyCache::new(
+            settings.syncstorage.hawk_key_cache_max_size,
+        ));
+        let hawk_nonce_cache = Arc::new(HawkNonceCache::from_settings(
+            &settings.syncstorage,
+            cache_backend,
+            Arc::clone(&blocking_threadpool),
+        ));
         let db_pool = DbPoolImpl::new(
             &settings.syncstorage,
             &Metrics::from(&metrics),
-            blocking_threadpool.clone(),
+            Arc::clone(&blocking_threadpool),
+            cache_backend,
         )?;
-        let limits = Arc::new(settings.syncstorage.limits);
+        if settings.syncstorage.database_pool_warm_up {
+            warm_up_db_pool(&db_pool).await;
+        }
+        if let Some(interval) = settings.syncstorage.database_stats_interval {
+            spawn_database_stats_periodic_reporter(
+                Duration::from_secs(interval.into()),
+                metrics.clone(),
+                Box::new(db_pool.clone()),
+            );
+        }
+        if let Some(interval) = settings.syncstorage.purge_interval {
+            spawn_purge_periodic_task(
+                Duration::from_secs(interval.into()),
+                settings.syncstorage.purge_batch_size,
+                settings.syncstorage.purge_max_rows,
+                metrics.clone(),
+                Box::new(db_pool.clone()),
+            );
+        }
+        let expired_bso_queue = settings.syncstorage.purge_on_read_queue_size.map(|size| {
+            let (queue, receiver) = purge::ExpiredBsoQueue::new(size as usize);
+            spawn_purge_on_read_task(metrics.clone(), Box::new(db_pool.clone()), receiver);
+            queue
+        });
+        spawn_collection_cache_periodic_reporter(
+            Duration::from_secs(10),
+            metrics.clone(),
+            Box::new(db_pool.clone()),
+        );
+        spawn_hawk_caches_periodic_reporter(
+            Duration::from_secs(10),
+            metrics.clone(),
+            Arc::clone(&hawk_key_cache),
+            Arc::clone(&hawk_nonce_cache),
+        );
+        let active_users = settings
+            .syncstorage
+            .active_users_report_interval
+            .map(|interval| {
+                let active_users = Arc::new(ActiveUsers::new(cache_backend));
+                spawn_active_users_periodic_reporter(
+                    Duration::from_secs(interval.into()),
+                    metrics.clone(),
+                    Arc::clone(&active_users),
+                    blocking_threadpool,
+                );
+                active_users
+            });
+        let limits = Arc::new(settings.syncstorage.limits.clone());
         let limits_json =
             serde_json::to_string(&*limits).expect("ServerLimits failed to serialize");
+
+        Ok(Self {
+            db_pool: Box::new(db_pool),
+            limits,
+            limits_json,
+            max_get_records: settings.syncstorage.max_get_records,
+            max_get_bytes: settings.syncstorage.max_get_bytes,
+            metrics,
+            port: settings.port,
+            quota_enabled: settings.syncstorage.enable_quota,
+            quota_soft_limit: settings.syncstorage.quota_soft_limit,
+            quota: Quota::from(&settings.syncstorage),
+            deadman,
+            active_users,
+            overload,
+            abuse,
+            write_throttle,
+            tenants,
+            hawk_key_cache,
+            hawk_nonce_cache,
+            admin_token: settings.syncstorage.admin_token.clone(),
+            slow_request_threshold: settings
+                .syncstorage
+                .slow_request_threshold_ms
+                .map(|ms| Duration::from_millis(ms.into())),
+            query_budget: settings.syncstorage.database_query_budget,
+            expired_bso_queue,
+        })
+    }
+}
+
+impl Server {
+    pub async fn with_settings(settings: Settings) -> Result<dev::Server, ApiError> {
+        let settings_copy = settings.clone();
+        let host = settings.host.clone();
+        let port = settings.port;
+        syncserver_common::set_safe_uid_key(settings.master_secret.logging_secret);
+        let blocking_threadpool = Arc::new(build_blocking_threadpool(&settings));
+        let cache_backend = build_cache_backend(&settings)?;
+        let syncstorage_state =
+            ServerState::from_settings(&settings, blocking_threadpool.clone(), &cache_backend)
+                .await?;
+        let limits = Arc::clone(&syncstorage_state.limits);
         let secrets = Arc::new(settings.master_secret);
-        let quota_enabled = settings.syncstorage.enable_quota;
         let actix_keep_alive = settings.actix_keep_alive;
         let tokenserver_state = if settings.tokenserver.enabled {
             let state = tokenserver::ServerState::from_settings(
@@ -272,6 +495,7 @@ impl Server {
                     settings.statsd_port,
                 )?,
                 blocking_threadpool,
+                &cache_backend,
             )?;
 
             Some(state)
@@ -281,8 +505,8 @@ impl Server {
             // is only done for self-hosters.
             spawn_metric_periodic_reporter(
                 Duration::from_secs(10),
-                metrics.clone(),
-                db_pool.clone(),
+                syncstorage_state.metrics.clone(),
+                syncstorage_state.db_pool.clone(),
                 blocking_threadpool,
             )?;
 
@@ -290,18 +514,8 @@ impl Server {
         };
 
         let mut server = HttpServer::new(move || {
-            let syncstorage_state = ServerState {
-                db_pool: Box::new(db_pool.clone()),
-                limits: Arc::clone(&limits),
-                limits_json: limits_json.clone(),
-                metrics: metrics.clone(),
-                port,
-                quota_enabled,
-                deadman: Arc::clone(&deadman),
-            };
-
             build_app!(
-                syncstorage_state,
+                syncstorage_state.clone(),
                 tokenserver_state.clone(),
                 Arc::clone(&secrets),
                 limits,
@@ -326,8 +540,9 @@ impl Server {
         let settings_copy = settings.clone();
         let host = settings.host.clone();
         let port = settings.port;
+        syncserver_common::set_safe_uid_key(settings.master_secret.logging_secret);
         let secrets = Arc::new(settings.master_secret.clone());
-        let blocking_threadpool = Arc::new(BlockingThreadpool::default());
+        let blocking_threadpool = Arc::new(build_blocking_threadpool(&settings));
         let tokenserver_state = tokenserver::ServerState::from_settings(
             &settings.tokenserver,
             syncserver_common::metrics_from_opts(
@@ -336,6 +551,7 @@ impl Server {
                 settings.statsd_port,
             )?,
             blocking_threadpool.clone(),
+            &build_cache_backend(&settings)?,
         )?;
 
         spawn_metric_periodic_reporter(
@@ -429,6 +645,46 @@ impl FromRequest for MetricsWrapper {
     }
 }
 
+fn build_blocking_threadpool(settings: &Settings) -> BlockingThreadpool {
+    match settings.blocking_threadpool_bulk_limit {
+        Some(limit) => BlockingThreadpool::with_bulk_limit(limit),
+        None => BlockingThreadpool::default(),
+    }
+}
+
+/// Failures are only logged: the pool then warms up with its first requests
+async fn warm_up_db_pool(db_pool: &DbPoolImpl) {
+    let start = Instant::now();
+    match db_pool.warm_up().await {
+        Ok(collections) => info!(
+            "Warmed up the db pool in {}ms", start.elapsed().as_millis();
+            "collections" => collections
+        ),
+        Err(e) => warn!("⚠️ Failed to warm up the db pool: {}", e),
+    }
+}
+
+fn build_cache_backend(settings: &Settings) -> Result<CacheBackend, ApiError> {
+    CacheBackend::from_url(settings.cache_url.as_deref())
+        .map_err(|e| ApiErrorKind::Internal(format!("Invalid cache_url: {}", e)).into())
+}
+
+/// Validate the configured tenants: their ids scope their users' db keys, so
+/// reusing one (or the default tenant's 0) would mix up their storage
+fn build_tenants(tenants: &HashMap<String, u16>) -> Result<HashMap<String, u16>, ApiError> {
+    let mut ids = HashSet::new();
+    for (name, id) in tenants {
+        if *id == 0 || !ids.insert(*id) {
+            return Err(ApiErrorKind::Internal(format!(
+                "Invalid tenants: {}'s id ({}) is zero or already taken",
+                name, id
+            ))
+            .into());
+        }
+    }
+    Ok(tenants.clone())
+}
+
 /// Emit database pool and threadpool metrics periodically
 fn spawn_metric_periodic_reporter<T: GetPoolState + Send + 'static>(
     interval: Duration,
@@ -447,6 +703,7 @@ fn spawn_metric_periodic_reporter<T: GetPoolState + Send + 'static>(
             let PoolState {
                 connections,
                 idle_connections,
+                ..
             } = pool.state();
             metrics
                 .gauge_with_tags(
@@ -470,6 +727,24 @@ fn spawn_metric_periodic_reporter<T: GetPoolState + Send + 'static>(
                 .gauge_with_tags("blocking_threadpool.idle", idle_threads)
                 .with_tag("hostname", &hostname)
                 .send();
+            for class in [TaskClass::Interactive, TaskClass::Bulk] {
+                metrics
+                    .gauge_with_tags(
+                        "blocking_threadpool.tasks.active",
+                        blocking_threadpool.active_tasks(class),
+                    )
+                    .with_tag("hostname", &hostname)
+                    .with_tag("class", class.tag())
+                    .send();
+                metrics
+                    .gauge_with_tags(
+                        "blocking_threadpool.tasks.queued",
+                        blocking_threadpool.queued_tasks(class),
+                    )
+                    .with_tag("hostname", &hostname)
+                    .with_tag("class", class.tag())
+                    .send();
+            }
 
             time::delay_for(interval).await;
         }
@@ -477,3 +752,267 @@ fn spawn_metric_periodic_reporter<T: GetPoolState + Send + 'static>(
 
     Ok(())
 }
+
+/// Emit the collection id/name cache's size, hit ratio and insert/eviction
+/// counts periodically
+fn spawn_collection_cache_periodic_reporter(
+    interval: Duration,
+    metrics: Arc<StatsdClient>,
+    pool: Box<dyn DbPool<Error = DbError>>,
+) {
+    let hostname = hostname::get()
+        .expect("Couldn't get hostname")
+        .into_string()
+        .expect("Couldn't get hostname");
+    tokio::spawn(async move {
+        let mut previous = results::CollectionCacheStats::default();
+        loop {
+            let stats = pool.collection_cache_stats();
+            let hits = stats.hits - previous.hits;
+            let misses = stats.misses - previous.misses;
+            metrics
+                .gauge_with_tags("storage.collection_cache.entries", stats.entries)
+                .with_tag("hostname", &hostname)
+                .send();
+            if hits + misses > 0 {
+                metrics
+                    .gauge_with_tags(
+                        "storage.collection_cache.hit_ratio",
+                        hits * 100 / (hits + misses),
+                    )
+                    .with_tag("hostname", &hostname)
+                    .send();
+            }
+            for (label, count) in [
+                ("storage.collection_cache.hits", hits),
+                ("storage.collection_cache.misses", misses),
+                (
+                    "storage.collection_cache.inserts",
+                    stats.inserts - previous.inserts,
+                ),
+                (
+                    "storage.collection_cache.evictions",
+                    stats.evictions - previous.evictions,
+                ),
+                (
+                    "storage.collection_cache.creations",
+                    stats.creations - previous.creations,
+                ),
+            ] {
+                metrics
+                    .count_with_tags(label, count as i64)
+                    .with_tag("hostname", &hostname)
+                    .send();
+            }
+            previous = stats;
+            time::delay_for(interval).await;
+        }
+    });
+}
+
+/// Emit the Hawk key and nonce caches' sizes and hit/miss/replay/eviction
+/// counts periodically
+fn spawn_hawk_caches_periodic_reporter(
+    interval: Duration,
+    metrics: Arc<StatsdClient>,
+    key_cache: Arc<HawkKeyCache>,
+    nonce_cache: Arc<HawkNonceCache>,
+) {
+    let hostname = hostname::get()
+        .expect("Couldn't get hostname")
+        .into_string()
+        .expect("Couldn't get hostname");
+    tokio::spawn(async move {
+        let mut previous = HawkKeyCacheStats::default();
+        let mut previous_nonces = HawkNonceCacheStats::default();
+        loop {
+            let stats = key_cache.stats();
+            let nonce_stats = nonce_cache.stats();
+            for (label, entries) in [
+                ("storage.hawk_key_cache.entries", stats.entries),
+                ("storage.hawk_nonce_cache.entries", nonce_stats.entries),
+            ] {
+                metrics
+                    .gauge_with_tags(label, entries)
+                    .with_tag("hostname", &hostname)
+                    .send();
+            }
+            for (label, count) in [
+                ("storage.hawk_key_cache.hits", stats.hits - previous.hits),
+                (
+                    "storage.hawk_key_cache.misses",
+                    stats.misses - previous.misses,
+                ),
+                (
+                    "storage.hawk_key_cache.evictions",
+                    stats.evictions - previous.evictions,
+                ),
+                (
+                    "storage.hawk_nonce_cache.replays",
+                    nonce_stats.replays - previous_nonces.replays,
+                ),
+                (
+                    "storage.hawk_nonce_cache.evictions",
+                    nonce_stats.evictions - previous_nonces.evictions,
+                ),
+            ] {
+                metrics
+                    .count_with_tags(label, count as i64)
+                    .with_tag("hostname", &hostname)
+                    .send();
+            }
+            previous = stats;
+            previous_nonces = nonce_stats;
+            time::delay_for(interval).await;
+        }
+    });
+}
+
+/// Emit the daily/weekly active user estimates periodically, tagged with
+/// the reporting host.
+///
+/// Hosts sharing a `cache_url` each report the same deployment-wide
+/// estimates: they're to be aggregated by their maximum, not summed
+fn spawn_active_users_periodic_reporter(
+    interval: Duration,
+    metrics: Arc<StatsdClient>,
+    active_users: Arc<ActiveUsers>,
+    blocking_threadpool: Arc<BlockingThreadpool>,
+) {
+    let hostname = hostname::get()
+        .expect("Couldn't get hostname")
+        .into_string()
+        .expect("Couldn't get hostname");
+    tokio::spawn(async move {
+        loop {
+            let active_users = Arc::clone(&active_users);
+            match blocking_threadpool
+                .spawn(move || Ok::<_, ApiError>(active_users.merge_counts()))
+                .await
+            {
+                Ok((daily, weekly)) => {
+                    metrics
+                        .gauge_with_tags("users.active.daily", daily)
+                        .with_tag("hostname", &hostname)
+                        .send();
+                    metrics
+                        .gauge_with_tags("users.active.weekly", weekly)
+                        .with_tag("hostname", &hostname)
+                        .send();
+                }
+                Err(e) => warn!("⚠️ Couldn't count the active users: {:?}", e),
+            }
+            time::delay_for(interval).await;
+        }
+    });
+}
+
+/// Emit table-wide database statistics periodically
+///
+/// Runs on the local (actix) runtime as `Db` futures aren't `Send`.
+fn spawn_database_stats_periodic_reporter(
+    interval: Duration,
+    metrics: Arc<StatsdClient>,
+    pool: Box<dyn DbPool<Error = DbError>>,
+) {
+    let hostname = hostname::get()
+        .expect("Couldn't get hostname")
+        .into_string()
+        .expect("Couldn't get hostname");
+    actix_rt::spawn(async move {
+        loop {
+            match get_database_stats(&pool).await {
+                Ok(stats) => {
+                    for (label, value) in &[
+                        ("storage.db.bsos", stats.bsos),
+                        ("storage.db.bsos.expired", stats.expired_bsos),
+                        ("storage.db.user_collections", stats.user_collections),
+                        ("storage.db.batches", stats.batches),
+                    ] {
+                        metrics
+                            .gauge_with_tags(label, *value as u64)
+                            .with_tag("hostname", &hostname)
+                            .send();
+                    }
+                }
+                Err(e) => warn!("⚠️ Couldn't gather database stats: {:?}", e),
+            }
+            time::delay_for(interval).await;
+        }
+    });
+}
+
+async fn get_database_stats(
+    pool: &dyn DbPool<Error = DbError>,
+) -> Result<results::GetDatabaseStats, DbError> {
+    let db = pool.get().await?;
+    db.begin(false).await?;
+    let stats = db.get_database_stats().await;
+    db.commit().await?;
+    stats
+}
+
+/// Purge expired rows periodically, counting those deleted
+///
+/// Runs on the local (actix) runtime as `Db` futures aren't `Send`.
+fn spawn_purge_periodic_task(
+    interval: Duration,
+    batch_size: u32,
+    max_rows: u32,
+    metrics: Arc<StatsdClient>,
+    pool: Box<dyn DbPool<Error = DbError>>,
+) {
+    let hostname = hostname::get()
+        .expect("Couldn't get hostname")
+        .into_string()
+        .expect("Couldn't get hostname");
+    actix_rt::spawn(async move {
+        loop {
+            // Not right away: restarting a deployment's instances shouldn't
+            // start a purge each
+            time::delay_for(interval).await;
+            let start = Instant::now();
+            match purge::purge_expired(&*pool, batch_size, max_rows).await {
+                Ok(purged) => {
+                    for (label, rows) in [
+                        ("storage.purge.bsos", purged.bsos),
+                        ("storage.purge.user_collections", purged.user_collections),
+                        ("storage.purge.payloads", purged.payloads),
+                    ] {
+                        metrics
+                            .count_with_tags(label, rows as i64)
+                            .with_tag("hostname", &hostname)
+                            .send();
+                    }
+                    info!(
+                        "Purged expired rows in {}ms", start.elapsed().as_millis();
+                        "bsos" => purged.bsos,
+                        "user_collections" => purged.user_collections,
+                        "payloads" => purged.payloads
+                    );
+                }
+                Err(e) => warn!("⚠️ Couldn't purge expired rows: {:?}", e),
+            }
+        }
+    });
+}
+
+/// Purge the BSOs reads queued (see `ServerState::expired_bso_queue`) that
+/// expired, counting them
+///
+/// Runs on the local (actix) runtime as `Db` futures aren't `Send`.
+fn spawn_purge_on_read_task(
+    metrics: Arc<StatsdClient>,
+    pool: Box<dyn DbPool<Error = DbError>>,
+    mut receiver: purge::ExpiredBsoReceiver,
+) {
+    actix_rt::spawn(async move {
+        while let Some(bso) = receiver.next().await {
+            match purge::purge_expired_bso(&*pool, bso).await {
+                Ok(true) => metrics.incr_with_tags("storage.purge.on_read").send(),
+                Ok(false) => (),
+                Err(e) => warn!("⚠️ Couldn't purge an expired BSO: {:?}", e),
+            }
+        }
+    });
+}
@@ -0,0 +1,180 @@
+//! Detects when the server's saturated, so it can ask clients to back off
+//! before having to fail their requests.
+//!
+//! Operators can also broadcast a backoff to a percentage of users during an
+//! incident, growing it gradually rather than slowing every client at once.
+use std::{
+    sync::{
+        atomic::{AtomicU64, AtomicU8, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use sha2::{Digest, Sha256};
+use syncstorage_settings::Settings;
+
+/// Weight of the latest pool wait in its moving average (as a power of 2:
+/// 1/8th)
+const POOL_WAIT_WEIGHT_SHIFT: u32 = 3;
+
+#[derive(Debug, Default)]
+pub struct Overload {
+    max_in_flight: Option<u64>,
+    /// In microseconds
+    max_pool_wait: Option<u64>,
+    backoff_fraction: f64,
+    backoff_seconds: u32,
+    in_flight: AtomicU64,
+    /// Moving average of the time spent waiting on a db connection, in
+    /// microseconds
+    pool_wait: AtomicU64,
+    /// Percentage of users (by uid bucket) sent a backoff regardless of load
+    broadcast_percentage: AtomicU8,
+}
+
+impl Overload {
+    pub fn from_settings(settings: &Settings) -> Self {
+        Self {
+            max_in_flight: settings.overload_max_in_flight_requests.map(u64::from),
+            max_pool_wait: settings
+                .overload_max_pool_wait_ms
+                .map(|ms| u64::from(ms) * 1_000),
+            backoff_fraction: settings.overload_backoff_fraction.clamp(0.0, 1.0),
+            backoff_seconds: settings.overload_backoff_seconds,
+            broadcast_percentage: AtomicU8::new(settings.backoff_broadcast_percentage.min(100)),
+            ..Default::default()
+        }
+    }
+
+    /// Count a request as in flight until the returned guard's dropped
+    pub fn start_request(self: &Arc<Self>) -> InFlight {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlight(Arc::clone(self))
+    }
+
+    /// Record the time a request waited on a db connection
+    pub fn record_pool_wait(&self, wait: Duration) {
+        let wait = wait.as_micros() as u64;
+        // Races between concurrent updates lose a sample at worst
+        let average = self.pool_wait.load(Ordering::Relaxed);
+        let average =
+            average - (average >> POOL_WAIT_WEIGHT_SHIFT) + (wait >> POOL_WAIT_WEIGHT_SHIFT);
+        self.pool_wait.store(average, Ordering::Relaxed);
+    }
+
+    pub fn is_saturated(&self) -> bool {
+        matches!(self.max_in_flight, Some(max) if self.in_flight.load(Ordering::Relaxed) > max)
+            || matches!(self.max_pool_wait, Some(max) if self.pool_wait.load(Ordering::Relaxed) > max)
+    }
+
+    pub fn broadcast_percentage(&self) -> u8 {
+        self.broadcast_percentage.load(Ordering::Relaxed)
+    }
+
+    /// Change the percentage of users sent a backoff (capped at 100)
+    pub fn set_broadcast_percentage(&self, percentage: u8) {
+        self.broadcast_percentage
+            .store(percentage.min(100), Ordering::Relaxed);
+    }
+
+    /// The `X-Weave-Backoff` (in seconds) to attach to a response for the
+    /// given user, if any: users in the broadcast percentage always get one,
+    /// otherwise only a fraction of responses do while the server's saturated
+    pub fn backoff(&self, uid: Option<&str>) -> Option<u32> {
+        let broadcast = matches!(uid, Some(uid) if uid_bucket(uid) < self.broadcast_percentage());
+        (broadcast || (self.is_saturated() && rand::random::<f64>() < self.backoff_fraction))
+            .then_some(self.backoff_seconds)
+    }
+}
+
+/// The user's bucket (0 - 99). Stable across requests and nodes, so growing
+/// the broadcast percentage only adds users to the ones already backing off
+fn uid_bucket(uid: &str) -> u8 {
+    let digest = Sha256::digest(uid.as_bytes());
+    (u16::from_be_bytes([digest[0], digest[1]]) % 100) as u8
+}
+
+/// A request in flight (see `Overload::start_request`)
+pub struct InFlight(Arc<Overload>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn overload(max_in_flight: Option<u32>, max_pool_wait_ms: Option<u32>) -> Arc<Overload> {
+        Arc::new(Overload::from_settings(&Settings {
+            overload_max_in_flight_requests: max_in_flight,
+            overload_max_pool_wait_ms: max_pool_wait_ms,
+            overload_backoff_fraction: 1.0,
+            ..Default::default()
+        }))
+    }
+
+    #[test]
+    fn backs_off_past_max_in_flight() {
+        let overload = overload(Some(1), None);
+        let first = overload.start_request();
+        assert_eq!(overload.backoff(None), None);
+        let second = overload.start_request();
+        assert_eq!(overload.backoff(None), Some(300));
+        drop((first, second));
+        assert!(!overload.is_saturated());
+    }
+
+    #[test]
+    fn backs_off_past_max_pool_wait() {
+        let overload = overload(None, Some(10));
+        overload.record_pool_wait(Duration::from_millis(50));
+        assert!(!overload.is_saturated());
+        for _ in 0..20 {
+            overload.record_pool_wait(Duration::from_millis(50));
+        }
+        assert!(overload.is_saturated());
+        for _ in 0..40 {
+            overload.record_pool_wait(Duration::from_millis(1));
+        }
+        assert!(!overload.is_saturated());
+    }
+
+    #[test]
+    fn never_backs_off_unconfigured() {
+        let overload = overload(None, None);
+        let _request = overload.start_request();
+        overload.record_pool_wait(Duration::from_secs(10));
+        assert_eq!(overload.backoff(None), None);
+    }
+
+    #[test]
+    fn broadcasts_to_a_percentage_of_users() {
+        let overload = overload(None, None);
+        let uids: Vec<String> = (0..1_000).map(|uid| uid.to_string()).collect();
+        let backed_off = |overload: &Overload| {
+            uids.iter()
+                .filter(|uid| overload.backoff(Some(uid)).is_some())
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        assert!(backed_off(&overload).is_empty());
+
+        overload.set_broadcast_percentage(10);
+        let some = backed_off(&overload);
+        assert!((50..150).contains(&some.len()));
+        // Requests without a uid aren't affected
+        assert_eq!(overload.backoff(None), None);
+
+        overload.set_broadcast_percentage(50);
+        let more = backed_off(&overload);
+        assert!(some.iter().all(|uid| more.contains(uid)));
+
+        overload.set_broadcast_percentage(200);
+        assert_eq!(overload.broadcast_percentage(), 100);
+        assert_eq!(backed_off(&overload).len(), uids.len());
+    }
+}
@@ -16,11 +16,14 @@ use rand::{thread_rng, Rng};
 use serde::de::DeserializeOwned;
 use serde_json::json;
 use sha2::Sha256;
-use syncserver_common::{self, X_LAST_MODIFIED};
+use syncserver_common::{
+    self, CacheBackend, X_LAST_MODIFIED, X_REQUEST_ID, X_WEAVE_ALERT, X_WEAVE_BACKOFF,
+    X_WEAVE_NEXT_OFFSET, X_WEAVE_QUOTA_REMAINING, X_WEAVE_RECORDS, X_WEAVE_TIMESTAMP,
+};
 use syncserver_settings::{Secrets, Settings};
 use syncstorage_db::{
     params,
-    results::{DeleteBso, GetBso, PostBsos, PutBso},
+    results::{DeleteBso, PostBsos, PutBso},
     DbPoolImpl, SyncTimestamp,
 };
 use syncstorage_settings::ServerLimits;
@@ -28,7 +31,12 @@ use syncstorage_settings::ServerLimits;
 use super::*;
 use crate::build_app;
 use crate::tokenserver;
-use crate::web::{auth::HawkPayload, extractors::BsoBody};
+use crate::web::{
+    auth::{HawkKeyCache, HawkNonceCache, HawkPayload},
+    extractors::{BsoBody, HawkIdentifier},
+    transaction::DbTransactionPool,
+    wire::Bso,
+};
 
 lazy_static! {
     static ref SERVER_LIMITS: Arc<ServerLimits> = Arc::new(ServerLimits::default());
@@ -61,6 +69,7 @@ fn get_test_settings() -> Settings {
     settings.port = port;
     settings.host = host;
     settings.syncstorage.database_pool_max_size = pool_size + 1;
+    syncstorage_db::assert_test_database_url(&settings.syncstorage.database_url);
     settings
 }
 
@@ -73,16 +82,38 @@ async fn get_test_state(settings: &Settings) -> ServerState {
             DbPoolImpl::new(
                 &settings.syncstorage,
                 &Metrics::from(&metrics),
-                blocking_threadpool,
+                Arc::clone(&blocking_threadpool),
+                &CacheBackend::Memory,
             )
             .expect("Could not get db_pool in get_test_state"),
         ),
         limits: Arc::clone(&SERVER_LIMITS),
         limits_json: serde_json::to_string(&**SERVER_LIMITS).unwrap(),
+        max_get_records: settings.syncstorage.max_get_records,
+        max_get_bytes: settings.syncstorage.max_get_bytes,
         metrics,
         port: settings.port,
         quota_enabled: settings.syncstorage.enable_quota,
+        quota_soft_limit: settings.syncstorage.quota_soft_limit,
+        quota: Quota::from(&settings.syncstorage),
         deadman: Arc::new(RwLock::new(Deadman::from(&settings.syncstorage))),
+        active_users: Some(Arc::new(ActiveUsers::default())),
+        overload: Arc::new(Overload::from_settings(&settings.syncstorage)),
+        abuse: Arc::new(AbuseDetector::from_settings(&settings.syncstorage)),
+        write_throttle: Arc::new(WriteThrottle::from_settings(&settings.syncstorage)),
+        tenants: Arc::new(settings.syncstorage.tenants.clone()),
+        hawk_key_cache: Arc::new(HawkKeyCache::new(
+            settings.syncstorage.hawk_key_cache_max_size,
+        )),
+        hawk_nonce_cache: Arc::new(HawkNonceCache::from_settings(
+            &settings.syncstorage,
+            &CacheBackend::Memory,
+            Arc::clone(&blocking_threadpool),
+        )),
+        admin_token: settings.syncstorage.admin_token.clone(),
+        slow_request_threshold: None,
+        query_budget: None,
+        expired_bso_queue: None,
     }
 }
 
@@ -144,6 +175,10 @@ fn create_request(
 }
 
 fn create_hawk_header(method: &str, port: u16, path: &str) -> String {
+    create_tenant_hawk_header(method, port, path, None)
+}
+
+fn create_tenant_hawk_header(method: &str, port: u16, path: &str, tenant: Option<&str>) -> String {
     // TestServer hardcodes its hostname to localhost and binds to a random
     // port
     let host = TEST_HOST;
@@ -156,6 +191,8 @@ fn create_hawk_header(method: &str, port: u16, path: &str) -> String {
         fxa_kid: format!("xxx_test_kid_{}", *RAND_UID),
         device_id: "xxx_test".to_owned(),
         tokenserver_origin: Default::default(),
+        issued_at: None,
+        tenant: tenant.map(str::to_owned),
     };
     let payload =
         serde_json::to_string(&payload).expect("Could not get payload in create_hawk_header");
@@ -335,8 +372,13 @@ async fn quota() {
 
 #[actix_rt::test]
 async fn delete_all() {
-    test_endpoint(http::Method::DELETE, "/1.5/42", None, Some("null")).await;
-    test_endpoint(http::Method::DELETE, "/1.5/42/storage", None, Some("null")).await;
+    let start = SyncTimestamp::default();
+    for path in ["/1.5/42", "/1.5/42/storage"] {
+        test_endpoint_with_response(http::Method::DELETE, path, &move |result: SyncTimestamp| {
+            assert!(result > start, "Bad timestamp {:?} <= {:?}", result, start);
+        })
+        .await;
+    }
 }
 
 #[actix_rt::test]
@@ -387,7 +429,7 @@ async fn get_collection() {
     test_endpoint_with_response(
         http::Method::GET,
         "/1.5/42/storage/bookmarks",
-        &move |collection: Vec<GetBso>| {
+        &move |collection: Vec<Bso>| {
             assert_eq!(collection.len(), 0);
         },
     )
@@ -395,7 +437,7 @@ async fn get_collection() {
     test_endpoint_with_response(
         http::Method::GET,
         "/1.5/42/storage/nonexistent",
-        &move |collection: Vec<GetBso>| {
+        &move |collection: Vec<Bso>| {
             assert_eq!(collection.len(), 0);
         },
     )
@@ -420,6 +462,154 @@ async fn post_collection() {
     assert_eq!(result.failed.len(), 0);
 }
 
+#[actix_rt::test]
+async fn weave_records_without_bodies() {
+    let mut settings = get_test_settings();
+    // persist the db across requests
+    settings.syncstorage.database_use_test_transactions = false;
+    let mut app = init_app!(settings).await;
+    let path = "/1.5/42/storage/xxx_records";
+
+    let req = create_request(http::Method::DELETE, path, None, None).to_request();
+    assert!(app.call(req).await.unwrap().status().is_success());
+    actix_rt::time::delay_for(Duration::from_millis(10)).await;
+
+    let bsos = json!([
+        {"id": "a", "payload": "x"},
+        {"id": "b", "payload": "x"},
+        {"id": "c", "payload": "x"},
+    ]);
+    let req = create_request(http::Method::POST, path, None, Some(bsos)).to_request();
+    assert!(app.call(req).await.unwrap().status().is_success());
+    actix_rt::time::delay_for(Duration::from_millis(10)).await;
+
+    for (query, expected) in [("", "3"), ("?limit=2", "2"), ("?ids=a,b,zzz", "2")] {
+        let req = create_request(
+            http::Method::HEAD,
+            &format!("{}{}", path, query),
+            None,
+            None,
+        )
+        .to_request();
+        let resp = app.call(req).await.unwrap();
+        assert!(resp.status().is_success());
+        assert_eq!(resp.headers().get(X_WEAVE_RECORDS).unwrap(), expected);
+        assert!(test::read_body(resp).await.is_empty());
+    }
+
+    let req = create_request(
+        http::Method::DELETE,
+        &format!("{}?ids=a,zzz", path),
+        None,
+        None,
+    )
+    .to_request();
+    let resp = app.call(req).await.unwrap();
+    assert!(resp.status().is_success());
+    assert_eq!(resp.headers().get(X_WEAVE_RECORDS).unwrap(), "1");
+    actix_rt::time::delay_for(Duration::from_millis(10)).await;
+
+    let req = create_request(http::Method::DELETE, path, None, None).to_request();
+    assert!(app.call(req).await.unwrap().status().is_success());
+}
+
+#[actix_rt::test]
+async fn get_collection_caps() {
+    let mut settings = get_test_settings();
+    // persist the db across requests
+    settings.syncstorage.database_use_test_transactions = false;
+    settings.syncstorage.max_get_records = Some(2);
+    settings.syncstorage.max_get_bytes = Some(6);
+    let mut app = init_app!(settings).await;
+    let path = "/1.5/42/storage/xxx_capped";
+
+    let req = create_request(http::Method::DELETE, path, None, None).to_request();
+    assert!(app.call(req).await.unwrap().status().is_success());
+    actix_rt::time::delay_for(Duration::from_millis(10)).await;
+
+    let bsos = json!([
+        {"id": "a", "payload": "xxxx"},
+        {"id": "b", "payload": "xxxx"},
+        {"id": "c", "payload": "xxxx"},
+    ]);
+    let req = create_request(http::Method::POST, path, None, Some(bsos)).to_request();
+    assert!(app.call(req).await.unwrap().status().is_success());
+    actix_rt::time::delay_for(Duration::from_millis(10)).await;
+
+    let get = |query: &str| {
+        create_request(http::Method::GET, &format!("{}{}", path, query), None, None).to_request()
+    };
+    // Whatever the requested limit
+    for query in ["", "?limit=100"] {
+        let resp = app.call(get(query)).await.unwrap();
+        assert_eq!(resp.headers().get(X_WEAVE_RECORDS).unwrap(), "2");
+        assert_eq!(resp.headers().get(X_WEAVE_NEXT_OFFSET).unwrap(), "2");
+    }
+    let resp = app.call(get("?limit=1")).await.unwrap();
+    assert_eq!(resp.headers().get(X_WEAVE_RECORDS).unwrap(), "1");
+
+    // Full bodies are also paginated by size, a page holding at least one
+    let resp = app.call(get("?full=1")).await.unwrap();
+    assert_eq!(resp.headers().get(X_WEAVE_RECORDS).unwrap(), "1");
+    assert_eq!(resp.headers().get(X_WEAVE_NEXT_OFFSET).unwrap(), "1");
+    let resp = app.call(get("?full=1&offset=2")).await.unwrap();
+    assert_eq!(resp.headers().get(X_WEAVE_RECORDS).unwrap(), "1");
+    assert!(resp.headers().get(X_WEAVE_NEXT_OFFSET).is_none());
+
+    let req = create_request(http::Method::DELETE, path, None, None).to_request();
+    assert!(app.call(req).await.unwrap().status().is_success());
+}
+
+#[actix_rt::test]
+async fn get_collection_newlines_streamed() {
+    let mut settings = get_test_settings();
+    // Streamed pages are read on connections of their own
+    settings.syncstorage.database_use_test_transactions = false;
+    let mut app = init_app!(settings).await;
+
+    let path = "/1.5/42/storage/xxx_streamed";
+    for chunk in 0..11 {
+        let bsos: Vec<_> = (0..100)
+            .map(|i| json!({"id": format!("b{}", chunk * 100 + i), "payload": "x\ny"}))
+            .collect();
+        let req = create_request(http::Method::POST, path, None, Some(json!(bsos))).to_request();
+        let sresp = app.call(req).await.unwrap();
+        assert!(sresp.status().is_success());
+    }
+
+    let get = |query: &str| {
+        let uri = format!("{}{}", path, query);
+        test::TestRequest::with_uri(&uri)
+            .header(
+                "Authorization",
+                create_hawk_header("GET", settings.port, &uri),
+            )
+            .header("Accept", "application/newlines")
+            .to_request()
+    };
+    let sresp = app.call(get("?full=1")).await.unwrap();
+    assert!(sresp.status().is_success());
+    assert_eq!(sresp.headers().get(X_WEAVE_RECORDS).unwrap(), "1100");
+    // Not buffered
+    assert!(sresp.headers().get("Content-Length").is_none());
+    let body = test::read_body(sresp).await;
+    let lines: Vec<_> = std::str::from_utf8(&body).unwrap().lines().collect();
+    assert_eq!(lines.len(), 1100);
+    let ids: HashSet<String> = lines
+        .iter()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["id"].to_string())
+        .collect();
+    assert_eq!(ids.len(), 1100);
+
+    let sresp = app.call(get("?offset=50")).await.unwrap();
+    assert_eq!(sresp.headers().get(X_WEAVE_RECORDS).unwrap(), "1050");
+    let body = test::read_body(sresp).await;
+    assert_eq!(body.iter().filter(|b| **b == b'\n').count(), 1050);
+
+    let req = create_request(http::Method::DELETE, path, None, None).to_request();
+    assert!(app.call(req).await.unwrap().status().is_success());
+}
+
 #[actix_rt::test]
 async fn delete_bso() {
     test_endpoint(
@@ -442,6 +632,77 @@ async fn get_bso() {
     .await;
 }
 
+#[actix_rt::test]
+async fn responses_carry_request_ids() {
+    let mut app = init_app!().await;
+    let mut ids = Vec::new();
+    for _ in 0..2 {
+        let req =
+            create_request(http::Method::GET, "/1.5/42/info/collections", None, None).to_request();
+        let resp = app.call(req).await.unwrap();
+        assert!(resp.status().is_success());
+        let id = resp.headers().get(X_REQUEST_ID).unwrap().to_str().unwrap();
+        assert_eq!(id.len(), 36);
+        ids.push(id.to_owned());
+    }
+    assert_ne!(ids[0], ids[1]);
+}
+
+#[actix_rt::test]
+async fn rolls_back_unsuccessful_responses() {
+    async fn write_then_fail(
+        user_id: HawkIdentifier,
+        db_pool: DbTransactionPool,
+        request: HttpRequest,
+    ) -> Result<HttpResponse, ApiError> {
+        db_pool
+            .transaction_http(request, |db| async move {
+                db.put_bso(params::PutBso {
+                    user_id: user_id.into(),
+                    collection: "xxx_rollback".to_owned(),
+                    id: "b0".to_owned(),
+                    sortindex: None,
+                    payload: Some("payload".to_owned()),
+                    ttl: None,
+                })
+                .await?;
+                Ok(HttpResponse::ServiceUnavailable().finish())
+            })
+            .await
+    }
+
+    let mut settings = get_test_settings();
+    // persist the db across requests
+    settings.syncstorage.database_use_test_transactions = false;
+    let limits = Arc::new(settings.syncstorage.limits.clone());
+    let state = get_test_state(&settings).await;
+    let mut app = test::init_service(
+        build_app!(
+            state,
+            None::<tokenserver::ServerState>,
+            Arc::clone(&SECRETS),
+            limits,
+            build_cors(&settings)
+        )
+        .service(web::resource("/1.5/{uid}/rollback").route(web::put().to(write_then_fail))),
+    )
+    .await;
+
+    let req = create_request(http::Method::PUT, "/1.5/42/rollback", None, None).to_request();
+    let resp = app.call(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+    let req = create_request(
+        http::Method::GET,
+        "/1.5/42/storage/xxx_rollback/b0",
+        None,
+        None,
+    )
+    .to_request();
+    let resp = app.call(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
+
 #[actix_rt::test]
 async fn put_bso() {
     let start = SyncTimestamp::default();
@@ -555,6 +816,89 @@ async fn invalid_batch_post() {
     assert_eq!(body, "0");
 }
 
+#[actix_rt::test]
+async fn request_body_too_large() {
+    let mut app = init_app!().await;
+
+    // Individually valid records, together exceeding max_request_bytes
+    let payload = "x".repeat(SERVER_LIMITS.max_record_payload_bytes as usize / 2);
+    let bsos = (0..5)
+        .map(|i| json!({"id": i.to_string(), "payload": payload}))
+        .collect::<Vec<_>>();
+    let req = create_request(
+        http::Method::POST,
+        "/1.5/42/storage/tabs",
+        None,
+        Some(json!(bsos)),
+    )
+    .to_request();
+    let response = app
+        .call(req)
+        .await
+        .expect("Could not get response in request_body_too_large");
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    let body = String::from_utf8(test::read_body(response).await.to_vec())
+        .expect("Could not get body in request_body_too_large");
+    // WeaveError::SizeLimitExceeded
+    assert_eq!(body, "17");
+}
+
+#[actix_rt::test]
+async fn chunked_request_body_too_large() {
+    let settings = get_test_settings();
+    let mut app = init_app!(settings).await;
+
+    let path = "/1.5/42/storage/tabs";
+    let payload = "x".repeat(SERVER_LIMITS.max_record_payload_bytes as usize / 2);
+    let bsos = (0..5)
+        .map(|i| json!({"id": i.to_string(), "payload": payload}))
+        .collect::<Vec<_>>();
+    let mut req = test::TestRequest::with_uri(path)
+        .method(http::Method::POST)
+        .header(
+            "Authorization",
+            create_hawk_header("POST", settings.port, path),
+        )
+        .header("Content-Type", "application/json")
+        .header("Transfer-Encoding", "chunked")
+        .set_payload(json!(bsos).to_string())
+        .to_request();
+    // Its size is only found out as it's read
+    req.headers_mut().remove(http::header::CONTENT_LENGTH);
+
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+}
+
+#[actix_rt::test]
+async fn post_collection_gzipped() {
+    use std::io::Write;
+
+    let settings = get_test_settings();
+    let mut app = init_app!(settings).await;
+
+    let path = "/1.5/42/storage/tabs";
+    let bsos = json!([{"id": "gz", "payload": "zipped"}]).to_string();
+    let mut body = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+    body.write_all(bsos.as_bytes()).unwrap();
+    let req = test::TestRequest::with_uri(path)
+        .method(http::Method::POST)
+        .header(
+            "Authorization",
+            create_hawk_header("POST", settings.port, path),
+        )
+        .header("Content-Type", "application/json")
+        .header("Content-Encoding", "gzip")
+        .set_payload(body.finish().unwrap())
+        .to_request();
+
+    let response = app.call(req).await.unwrap();
+    assert!(response.status().is_success());
+    let result: PostBsos = serde_json::from_slice(&test::read_body(response).await).unwrap();
+    assert_eq!(result.success, vec!["gz"]);
+    assert!(result.failed.is_empty());
+}
+
 #[actix_rt::test]
 async fn accept_new_or_dev_ios() {
     let mut app = init_app!().await;
@@ -725,6 +1069,106 @@ async fn overquota() {
     assert!(resp.response().status().is_success());
 }
 
+#[actix_rt::test]
+async fn quota_soft_limit() {
+    let mut settings = get_test_settings();
+    settings.syncstorage.enable_quota = true;
+    settings.syncstorage.enforce_quota = true;
+    settings.syncstorage.quota_soft_limit = Some(5);
+    let mut app = init_app!(settings).await;
+
+    // Writes past the soft limit succeed but carry warnings
+    let req = create_request(
+        http::Method::PUT,
+        "/1.5/42/storage/xxx_col2/12345",
+        None,
+        Some(json!(
+            {"payload": "*".repeat(500)}
+        )),
+    )
+    .to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let remaining = response
+        .headers()
+        .get(X_WEAVE_QUOTA_REMAINING)
+        .expect("No X-Weave-Quota-Remaining header")
+        .to_str()
+        .unwrap()
+        .parse::<f64>()
+        .unwrap();
+    assert!(remaining < f64::from(SERVER_LIMITS.max_quota_limit) / 1024.0);
+    let alert: serde_json::Value = serde_json::from_str(
+        response
+            .headers()
+            .get(X_WEAVE_ALERT)
+            .unwrap()
+            .to_str()
+            .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(alert["code"], "quota-warning");
+}
+
+#[actix_rt::test]
+async fn quota_soft_limit_exempt_user() {
+    let mut settings = get_test_settings();
+    settings.syncstorage.enable_quota = true;
+    settings.syncstorage.enforce_quota = true;
+    settings.syncstorage.quota_soft_limit = Some(5);
+    settings
+        .syncstorage
+        .quota_overrides
+        .insert("42".to_owned(), 0);
+    let mut app = init_app!(settings).await;
+
+    let req = create_request(
+        http::Method::PUT,
+        "/1.5/42/storage/xxx_col2/12345",
+        None,
+        Some(json!({"payload": "*".repeat(500)})),
+    )
+    .to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers().get(X_WEAVE_QUOTA_REMAINING).is_none());
+    assert!(response.headers().get(X_WEAVE_ALERT).is_none());
+}
+
+#[actix_rt::test]
+async fn quota_soft_limit_overridden_user() {
+    let mut settings = get_test_settings();
+    settings.syncstorage.enable_quota = true;
+    settings.syncstorage.enforce_quota = true;
+    settings.syncstorage.quota_soft_limit = Some(5);
+    settings
+        .syncstorage
+        .quota_overrides
+        .insert("42".to_owned(), 10_240);
+    let mut app = init_app!(settings).await;
+
+    let req = create_request(
+        http::Method::PUT,
+        "/1.5/42/storage/xxx_col2/12345",
+        None,
+        Some(json!({"payload": "*".repeat(500)})),
+    )
+    .to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    // Remaining against the user's 10KB limit, not the global one
+    let remaining = response
+        .headers()
+        .get(X_WEAVE_QUOTA_REMAINING)
+        .expect("No X-Weave-Quota-Remaining header")
+        .to_str()
+        .unwrap()
+        .parse::<f64>()
+        .unwrap();
+    assert!(remaining > 9.0 && remaining < 10.0);
+    assert!(response.headers().get(X_WEAVE_ALERT).is_some());
+}
+
 #[actix_rt::test]
 async fn lbheartbeat_max_pool_size_check() {
     use actix_web::web::Buf;
@@ -800,3 +1244,390 @@ async fn lbheartbeat_ttl_check() {
     let sresp = app.call(lb_req).await.unwrap();
     assert_eq!(sresp.status(), StatusCode::INTERNAL_SERVER_ERROR);
 }
+
+#[actix_rt::test]
+async fn heartbeat_pool_stats() {
+    let mut app = init_app!().await;
+
+    let req = create_request(http::Method::GET, "/__heartbeat__", None, None).to_request();
+    let sresp = app.call(req).await.unwrap();
+    assert!(sresp.status().is_success());
+    let body: serde_json::Value = test::read_body_json(sresp).await;
+    assert_eq!(body["database"], "Ok");
+    let pool = &body["pools"]["syncstorage"];
+    for stat in &[
+        "active_connections",
+        "idle_connections",
+        "checkouts",
+        "average_checkout_wait_ms",
+    ] {
+        assert!(pool[stat].is_number(), "missing {}", stat);
+    }
+    assert_eq!(pool["checkout_failures"], 0);
+}
+
+#[actix_rt::test]
+async fn backoff_broadcast() {
+    let mut settings = get_test_settings();
+    settings.syncstorage.admin_token = Some("s3cr3t".to_owned());
+    let mut app = init_app!(settings).await;
+
+    let admin_request = |method: http::Method, token: &str| {
+        test::TestRequest::with_uri("/__backoff__")
+            .method(method)
+            .header("Authorization", format!("Bearer {}", token))
+    };
+    let req = admin_request(http::Method::PUT, "wrong")
+        .set_json(&json!({"percentage": 100}))
+        .to_request();
+    let sresp = app.call(req).await.unwrap();
+    assert_eq!(sresp.status(), StatusCode::UNAUTHORIZED);
+
+    let req = admin_request(http::Method::PUT, "s3cr3t")
+        .set_json(&json!({"percentage": 100}))
+        .to_request();
+    let sresp = app.call(req).await.unwrap();
+    assert!(sresp.status().is_success());
+
+    let req = admin_request(http::Method::GET, "s3cr3t").to_request();
+    let sresp = app.call(req).await.unwrap();
+    let body: serde_json::Value = test::read_body_json(sresp).await;
+    assert_eq!(body, json!({"percentage": 100}));
+
+    let req =
+        create_request(http::Method::GET, "/1.5/42/info/collections", None, None).to_request();
+    let sresp = app.call(req).await.unwrap();
+    assert!(sresp.status().is_success());
+    assert_eq!(sresp.headers().get(X_WEAVE_BACKOFF).unwrap(), "300");
+}
+
+#[actix_rt::test]
+async fn admin_delete_collections() {
+    let mut settings = get_test_settings();
+    settings.syncstorage.admin_token = Some("s3cr3t".to_owned());
+    let mut app = init_app!(settings).await;
+
+    let admin_request = |token: &str| {
+        test::TestRequest::with_uri("/__delete_collections__")
+            .method(http::Method::POST)
+            .header("Authorization", format!("Bearer {}", token))
+            .set_json(&json!({"legacy_id": 42, "collections": ["xxx_admin_delete"]}))
+    };
+    let sresp = app.call(admin_request("wrong").to_request()).await.unwrap();
+    assert_eq!(sresp.status(), StatusCode::UNAUTHORIZED);
+
+    let sresp = app
+        .call(admin_request("s3cr3t").to_request())
+        .await
+        .unwrap();
+    assert!(sresp.status().is_success());
+    let body: serde_json::Value = test::read_body_json(sresp).await;
+    assert_eq!(body["collections"], json!({"xxx_admin_delete": false}));
+}
+
+#[actix_rt::test]
+async fn admin_soft_reset() {
+    let mut settings = get_test_settings();
+    settings.syncstorage.admin_token = Some("s3cr3t".to_owned());
+    let mut app = init_app!(settings).await;
+
+    for collection in ["meta", "xxx_soft_reset"] {
+        let req = create_request(
+            http::Method::PUT,
+            &format!("/1.5/42/storage/{}/wibble", collection),
+            None,
+            Some(json!({"payload": "wobble"})),
+        )
+        .to_request();
+        let sresp = app.call(req).await.unwrap();
+        assert!(sresp.status().is_success());
+    }
+
+    let req = test::TestRequest::with_uri("/__soft_reset__")
+        .method(http::Method::POST)
+        .header("Authorization", "Bearer s3cr3t")
+        .set_json(&json!({"legacy_id": 42}))
+        .to_request();
+    let sresp = app.call(req).await.unwrap();
+    assert!(sresp.status().is_success());
+    let body: serde_json::Value = test::read_body_json(sresp).await;
+    assert_eq!(body["collections"]["xxx_soft_reset"], json!(true));
+    assert!(body["collections"].get("meta").is_none());
+
+    let req =
+        create_request(http::Method::GET, "/1.5/42/storage/meta/wibble", None, None).to_request();
+    let sresp = app.call(req).await.unwrap();
+    assert!(sresp.status().is_success());
+    let req = create_request(
+        http::Method::GET,
+        "/1.5/42/storage/xxx_soft_reset/wibble",
+        None,
+        None,
+    )
+    .to_request();
+    let sresp = app.call(req).await.unwrap();
+    assert_eq!(sresp.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_rt::test]
+async fn admin_log_level() {
+    let mut settings = get_test_settings();
+    settings.syncstorage.admin_token = Some("s3cr3t".to_owned());
+    let mut app = init_app!(settings).await;
+
+    let put_log_level = |filter: &str| {
+        test::TestRequest::with_uri("/__log_level__")
+            .method(http::Method::PUT)
+            .header("Authorization", "Bearer s3cr3t")
+            .set_json(&json!({ "filter": filter }))
+            .to_request()
+    };
+    let sresp = app
+        .call(put_log_level("warn,syncstorage_mysql=loud"))
+        .await
+        .unwrap();
+    assert_eq!(sresp.status(), StatusCode::BAD_REQUEST);
+
+    let sresp = app
+        .call(put_log_level(" warn,syncstorage_mysql=debug "))
+        .await
+        .unwrap();
+    assert!(sresp.status().is_success());
+    let body: serde_json::Value = test::read_body_json(sresp).await;
+    assert_eq!(body["filter"], "warn,syncstorage_mysql=debug");
+
+    let req = test::TestRequest::with_uri("/__log_level__")
+        .header("Authorization", "Bearer s3cr3t")
+        .to_request();
+    let sresp = app.call(req).await.unwrap();
+    assert!(sresp.status().is_success());
+}
+
+#[actix_rt::test]
+async fn backoff_broadcast_disabled_without_admin_token() {
+    let mut app = init_app!().await;
+    let req = test::TestRequest::with_uri("/__backoff__").to_request();
+    let sresp = app.call(req).await.unwrap();
+    assert_eq!(sresp.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_rt::test]
+async fn abuse_backoff() {
+    let mut settings = get_test_settings();
+    settings.syncstorage.abuse_max_writes_per_minute = Some(1);
+    settings.syncstorage.abuse_backoff_seconds = Some(600);
+    let mut app = init_app!(settings).await;
+
+    let put_bso = || {
+        create_request(
+            http::Method::PUT,
+            "/1.5/42/storage/bookmarks/wibble",
+            None,
+            Some(json!({"payload": "wobble"})),
+        )
+        .to_request()
+    };
+    let sresp = app.call(put_bso()).await.unwrap();
+    assert!(sresp.status().is_success());
+    assert!(sresp.headers().get(X_WEAVE_BACKOFF).is_none());
+
+    let sresp = app.call(put_bso()).await.unwrap();
+    assert!(sresp.status().is_success());
+    assert_eq!(sresp.headers().get(X_WEAVE_BACKOFF).unwrap(), "600");
+
+    // The flagged user's reads back off as well
+    let req =
+        create_request(http::Method::GET, "/1.5/42/info/collections", None, None).to_request();
+    let sresp = app.call(req).await.unwrap();
+    assert_eq!(sresp.headers().get(X_WEAVE_BACKOFF).unwrap(), "600");
+}
+
+#[actix_rt::test]
+async fn tenants_are_isolated() {
+    let mut settings = get_test_settings();
+    settings
+        .syncstorage
+        .tenants
+        .insert("staging".to_owned(), 1);
+    let port = settings.port;
+    let mut app = init_app!(settings).await;
+
+    let path = "/1.5/42/storage/bookmarks/wibble";
+    let request = |method: http::Method, tenant: Option<&str>, payload: Option<&str>| {
+        let mut req = test::TestRequest::with_uri(path)
+            .method(method.clone())
+            .header(
+                "Authorization",
+                create_tenant_hawk_header(method.as_str(), port, path, tenant),
+            )
+            .header("Accept", "application/json");
+        if let Some(payload) = payload {
+            req = req.set_json(&json!({ "payload": payload }));
+        }
+        req.to_request()
+    };
+
+    let sresp = app
+        .call(request(http::Method::PUT, None, Some("prod")))
+        .await
+        .unwrap();
+    assert!(sresp.status().is_success());
+    // The same uid of another tenant is another user
+    let sresp = app
+        .call(request(http::Method::GET, Some("staging"), None))
+        .await
+        .unwrap();
+    assert_eq!(sresp.status(), StatusCode::NOT_FOUND);
+
+    let sresp = app
+        .call(request(http::Method::PUT, Some("staging"), Some("staging")))
+        .await
+        .unwrap();
+    assert!(sresp.status().is_success());
+    let sresp = app
+        .call(request(http::Method::GET, Some("staging"), None))
+        .await
+        .unwrap();
+    let bso: Bso = test::read_body_json(sresp).await;
+    assert_eq!(bso.payload.as_deref(), Some("staging"));
+    let sresp = app
+        .call(request(http::Method::GET, None, None))
+        .await
+        .unwrap();
+    let bso: Bso = test::read_body_json(sresp).await;
+    assert_eq!(bso.payload.as_deref(), Some("prod"));
+
+    let sresp = app
+        .call(request(http::Method::GET, Some("unknown"), None))
+        .await
+        .unwrap();
+    assert_eq!(sresp.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(sresp.headers().get("WWW-Authenticate").unwrap(), "Hawk");
+}
+
+#[actix_rt::test]
+async fn replayed_requests_are_rejected() {
+    let settings = get_test_settings();
+    let mut app = init_app!(settings).await;
+
+    let path = "/1.5/42/info/collections";
+    let header = create_hawk_header("GET", settings.port, path);
+    let request = |path: &str| {
+        test::TestRequest::with_uri(path)
+            .header("Authorization", header.clone())
+            .header("Accept", "application/json")
+            .to_request()
+    };
+
+    // Requests failing validation don't use up their nonce
+    let sresp = app.call(request("/1.5/42/info/quota")).await.unwrap();
+    assert_eq!(sresp.status(), StatusCode::UNAUTHORIZED);
+    let sresp = app.call(request(path)).await.unwrap();
+    assert!(sresp.status().is_success());
+    let sresp = app.call(request(path)).await.unwrap();
+    assert_eq!(sresp.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(sresp.headers().get("WWW-Authenticate").unwrap(), "Hawk");
+}
+
+#[test]
+fn tenant_ids_must_be_unique() {
+    let tenants = |ids: &[(&str, u16)]| {
+        ids.iter()
+            .map(|(name, id)| (name.to_string(), *id))
+            .collect::<HashMap<_, _>>()
+    };
+    assert!(build_tenants(&tenants(&[("stage", 1), ("dev", 2)])).is_ok());
+    assert!(build_tenants(&tenants(&[("stage", 1), ("dev", 1)])).is_err());
+    assert!(build_tenants(&tenants(&[("stage", 0)])).is_err());
+}
+
+#[actix_rt::test]
+async fn collection_write_throttle() {
+    let mut settings = get_test_settings();
+    settings
+        .syncstorage
+        .collection_write_rate_limits
+        .insert("tabs".to_owned(), 2);
+    let mut app = init_app!(settings).await;
+
+    let put_bso = |collection: &str| {
+        create_request(
+            http::Method::PUT,
+            &format!("/1.5/42/storage/{}/wibble", collection),
+            None,
+            Some(json!({"payload": "wobble"})),
+        )
+        .to_request()
+    };
+    for _ in 0..2 {
+        let sresp = app.call(put_bso("tabs")).await.unwrap();
+        assert!(sresp.status().is_success());
+    }
+    let sresp = app.call(put_bso("tabs")).await.unwrap();
+    assert_eq!(sresp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(sresp.headers().get(X_WEAVE_BACKOFF).unwrap(), "30");
+    assert_eq!(sresp.headers().get("Retry-After").unwrap(), "30");
+
+    // Nor are POSTs allowed
+    let req = create_request(
+        http::Method::POST,
+        "/1.5/42/storage/tabs",
+        None,
+        Some(json!([{"id": "wibble", "payload": "wobble"}])),
+    )
+    .to_request();
+    let sresp = app.call(req).await.unwrap();
+    assert_eq!(sresp.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+    // Other collections are unaffected
+    let sresp = app.call(put_bso("bookmarks")).await.unwrap();
+    assert!(sresp.status().is_success());
+}
+
+#[actix_rt::test]
+async fn mounted_under_a_custom_path() {
+    let settings = get_test_settings();
+    let state = get_test_state(&settings).await;
+    let mut app = test::init_service(
+        App::new()
+            .data(state)
+            .data(Arc::clone(&SECRETS))
+            .service(syncstorage_scope("/sync", &SERVER_LIMITS))
+            .service(
+                web::resource("/login")
+                    .route(web::get().to(|_: HttpRequest| HttpResponse::Ok().finish())),
+            ),
+    )
+    .await;
+
+    let req = create_request(
+        http::Method::GET,
+        "/sync/1.5/42/info/collections",
+        None,
+        None,
+    )
+    .to_request();
+    let sresp = app.call(req).await.unwrap();
+    assert!(sresp.status().is_success());
+    assert!(sresp.headers().contains_key(X_WEAVE_TIMESTAMP));
+    assert_eq!(test::read_body(sresp).await, "{}".as_bytes());
+
+    let req = create_request(
+        http::Method::PUT,
+        "/sync/1.5/42/storage/bookmarks/wibble",
+        None,
+        Some(json!({"payload": "wobble"})),
+    )
+    .to_request();
+    let sresp = app.call(req).await.unwrap();
+    assert!(sresp.status().is_success());
+
+    // The embedder's own routes are left alone
+    let req = test::TestRequest::with_uri("/login").to_request();
+    let sresp = app.call(req).await.unwrap();
+    assert_eq!(sresp.status(), StatusCode::OK);
+    let req =
+        create_request(http::Method::GET, "/1.5/42/info/collections", None, None).to_request();
+    let sresp = app.call(req).await.unwrap();
+    assert_eq!(sresp.status(), StatusCode::NOT_FOUND);
+}
@@ -0,0 +1,145 @@
+//! Per user write rate limits of specific (high-churn) collections, so a
+//! client rapidly updating e.g. its tabs can't monopolize the server's
+//! resources. Each user/collection pair gets its own token bucket.
+use std::{
+    collections::HashMap,
+    sync::{Mutex, MutexGuard},
+    time::Instant,
+};
+
+use syncstorage_settings::Settings;
+
+/// Max number of user/collection buckets tracked at once
+const MAX_TRACKED_BUCKETS: usize = 100_000;
+
+#[derive(Debug, Default)]
+pub struct WriteThrottle {
+    /// Writes per minute, by collection
+    limits: HashMap<String, u32>,
+    buckets: Mutex<HashMap<(String, String), Bucket>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl WriteThrottle {
+    pub fn from_settings(settings: &Settings) -> Self {
+        Self {
+            limits: settings
+                .collection_write_rate_limits
+                .iter()
+                .filter(|(_, limit)| **limit > 0)
+                .map(|(collection, limit)| (collection.clone(), *limit))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Take a write of the user's to the collection from its bucket, or
+    /// return how long (in seconds) until the next one's allowed
+    pub fn acquire(&self, uid: &str, collection: &str) -> Result<(), u32> {
+        let Some(&limit) = self.limits.get(collection) else {
+            return Ok(());
+        };
+        let capacity = f64::from(limit);
+        let now = Instant::now();
+        let mut buckets = self.lock();
+        let key = (uid.to_owned(), collection.to_owned());
+        if !buckets.contains_key(&key) && buckets.len() >= MAX_TRACKED_BUCKETS {
+            // Refilled buckets are no different from new ones
+            buckets.retain(|(_, collection), bucket| {
+                let capacity = self.limits.get(collection).map_or(0.0, |l| f64::from(*l));
+                bucket.refilled(capacity, now) < capacity
+            });
+            if buckets.len() >= MAX_TRACKED_BUCKETS {
+                return Ok(());
+            }
+        }
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        bucket.tokens = bucket.refilled(capacity, now);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            let wait = (1.0 - bucket.tokens) * 60.0 / capacity;
+            Err((wait.ceil() as u32).max(1))
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<(String, String), Bucket>> {
+        self.buckets.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Bucket {
+    /// The bucket's tokens as of `now`, refilled at `capacity` per minute
+    fn refilled(&self, capacity: f64, now: Instant) -> f64 {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        (self.tokens + elapsed * capacity / 60.0).min(capacity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn throttle(limits: &[(&str, u32)]) -> WriteThrottle {
+        WriteThrottle::from_settings(&Settings {
+            collection_write_rate_limits: limits
+                .iter()
+                .map(|(collection, limit)| (collection.to_string(), *limit))
+                .collect(),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn throttles_past_the_burst() {
+        let throttle = throttle(&[("tabs", 3)]);
+        for _ in 0..3 {
+            assert_eq!(throttle.acquire("1", "tabs"), Ok(()));
+        }
+        // One write every 20 seconds from then on
+        assert_eq!(throttle.acquire("1", "tabs"), Err(20));
+        // Other users and collections are left alone
+        assert_eq!(throttle.acquire("2", "tabs"), Ok(()));
+        for _ in 0..100 {
+            assert_eq!(throttle.acquire("1", "bookmarks"), Ok(()));
+        }
+    }
+
+    #[test]
+    fn refills_over_time() {
+        let throttle = throttle(&[("tabs", 60)]);
+        for _ in 0..60 {
+            assert_eq!(throttle.acquire("1", "tabs"), Ok(()));
+        }
+        assert_eq!(throttle.acquire("1", "tabs"), Err(1));
+
+        // Rewind the bucket rather than sleeping
+        for bucket in throttle.lock().values_mut() {
+            bucket.updated -= Duration::from_secs(2);
+        }
+        assert_eq!(throttle.acquire("1", "tabs"), Ok(()));
+        assert_eq!(throttle.acquire("1", "tabs"), Ok(()));
+        assert!(throttle.acquire("1", "tabs").is_err());
+    }
+
+    #[test]
+    fn ignores_zero_limits() {
+        let throttle = throttle(&[("tabs", 0)]);
+        for _ in 0..100 {
+            assert_eq!(throttle.acquire("1", "tabs"), Ok(()));
+        }
+        assert!(throttle.lock().is_empty());
+    }
+}
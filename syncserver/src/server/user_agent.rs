@@ -1,3 +1,5 @@
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
 use woothee::parser::{Parser, WootheeResult};
 
 // List of valid user-agent attributes to keep, anything not in this
@@ -11,6 +13,72 @@ const VALID_UA_BROWSER: &[&str] = &["Chrome", "Firefox", "Safari", "Opera"];
 // field). Windows has many values and we only care that its Windows
 const VALID_UA_OS: &[&str] = &["Firefox OS", "Linux", "Mac OSX"];
 
+lazy_static! {
+    // e.g. "Firefox/110.0 (Windows NT 10.0; Win64; x64) FxSync/1.110.0.20230213.desktop"
+    static ref DESKTOP_UA_REGEX: Regex =
+        Regex::new(r"^Firefox/(?P<major>[0-9]+)[^ ]* \((?P<os>[^)]*)\) FxSync/").unwrap();
+    // e.g. "Firefox-iOS-Sync/108.1b24234 (iPad; iPhone OS 16.0) (Firefox)"
+    static ref IOS_UA_REGEX: Regex =
+        Regex::new(r"^Firefox-iOS-Sync/(?P<major>[0-9]+|dev)").unwrap();
+    // e.g. "Mobile-Android-Sync/(Mobile; Android 12) (Firefox 110.0.1)"
+    static ref ANDROID_UA_REGEX: Regex =
+        Regex::new(r"^Mobile-Android-Sync/.*\((?:Firefox|Fenix|Fennec)[^0-9)]*(?P<major>[0-9]+)?")
+            .unwrap();
+}
+
+/// A sync client as identified by its User-Agent, reduced to values suited
+/// for metric tags (e.g. only the major version)
+#[derive(Debug, Eq, PartialEq)]
+pub struct SyncClient<'a> {
+    pub product: &'static str,
+    pub version: &'a str,
+    pub platform: &'static str,
+}
+
+pub fn parse_sync_client(agent: &str) -> SyncClient<'_> {
+    fn major<'a>(captures: &Captures<'a>) -> &'a str {
+        captures
+            .name("major")
+            .map_or("unknown", |major| major.as_str())
+    }
+
+    if let Some(captures) = DESKTOP_UA_REGEX.captures(agent) {
+        let os = &captures["os"];
+        let platform = if os.contains("Windows") {
+            "windows"
+        } else if os.contains("Mac") {
+            "macos"
+        } else if os.contains("Linux") || os.contains("BSD") || os.contains("X11") {
+            "linux"
+        } else {
+            "other"
+        };
+        SyncClient {
+            product: "firefox-desktop",
+            version: major(&captures),
+            platform,
+        }
+    } else if let Some(captures) = IOS_UA_REGEX.captures(agent) {
+        SyncClient {
+            product: "firefox-ios",
+            version: major(&captures),
+            platform: "ios",
+        }
+    } else if let Some(captures) = ANDROID_UA_REGEX.captures(agent) {
+        SyncClient {
+            product: "firefox-android",
+            version: major(&captures),
+            platform: "android",
+        }
+    } else {
+        SyncClient {
+            product: "other",
+            version: "unknown",
+            platform: "other",
+        }
+    }
+}
+
 pub fn parse_user_agent(agent: &str) -> (WootheeResult<'_>, &str, &str) {
     let parser = Parser::new();
     let wresult = parser.parse(agent).unwrap_or_else(|| WootheeResult {
@@ -41,7 +109,7 @@ pub fn parse_user_agent(agent: &str) -> (WootheeResult<'_>, &str, &str) {
 
 #[cfg(test)]
 mod tests {
-    use super::parse_user_agent;
+    use super::{parse_sync_client, parse_user_agent, SyncClient};
 
     #[test]
     fn test_linux() {
@@ -81,4 +149,41 @@ mod tests {
         assert_eq!(metrics_browser, "Other");
         assert_eq!(ua_result.name, "UNKNOWN");
     }
+
+    #[test]
+    fn test_sync_clients() {
+        let client = |product, version, platform| SyncClient {
+            product,
+            version,
+            platform,
+        };
+        assert_eq!(
+            parse_sync_client(
+                "Firefox/110.0 (Windows NT 10.0; Win64; x64) FxSync/1.110.0.20230213.desktop"
+            ),
+            client("firefox-desktop", "110", "windows")
+        );
+        assert_eq!(
+            parse_sync_client(
+                "Firefox/109.0.1 (Macintosh; Intel Mac OS X 10.15) FxSync/1.109.0.20230130.desktop"
+            ),
+            client("firefox-desktop", "109", "macos")
+        );
+        assert_eq!(
+            parse_sync_client("Firefox-iOS-Sync/108.1b24234 (iPad; iPhone OS 16.0) (Firefox)"),
+            client("firefox-ios", "108", "ios")
+        );
+        assert_eq!(
+            parse_sync_client("Firefox-iOS-Sync/dev (iPhone; iPhone OS 16.0) (Firefox)"),
+            client("firefox-ios", "dev", "ios")
+        );
+        assert_eq!(
+            parse_sync_client("Mobile-Android-Sync/(Mobile; Android 12) (Firefox 110.0.1)"),
+            client("firefox-android", "110", "android")
+        );
+        assert_eq!(
+            parse_sync_client("curl/7.87.0"),
+            client("other", "unknown", "other")
+        );
+    }
 }
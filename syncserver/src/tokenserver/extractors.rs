@@ -21,6 +21,7 @@ use lazy_static::lazy_static;
 use regex::Regex;
 use serde::Deserialize;
 use sha2::Sha256;
+use syncserver_common::Metrics;
 use syncserver_settings::Secrets;
 use tokenserver_common::{ErrorLocation, NodeType, TokenserverError};
 use tokenserver_db::{params, results, Db, DbPool};
@@ -32,7 +33,7 @@ lazy_static! {
     static ref CLIENT_STATE_REGEX: Regex = Regex::new("^[a-zA-Z0-9._-]{1,32}$").unwrap();
 }
 
-const SYNC_SERVICE_NAME: &str = "sync-1.5";
+pub(crate) const SYNC_SERVICE_NAME: &str = "sync-1.5";
 
 /// Information from the request needed to process a Tokenserver request.
 #[derive(Debug, Default, Eq, PartialEq)]
@@ -45,6 +46,10 @@ pub struct TokenserverRequest {
     pub service_id: i32,
     pub duration: u64,
     pub node_type: NodeType,
+    /// The URL advertised for the user's node (see `NodeRegistry`)
+    pub node_url: String,
+    /// The tenant stamped into the token (see `ServerState::tenant`)
+    pub tenant: Option<String>,
 }
 
 impl TokenserverRequest {
@@ -284,6 +289,7 @@ impl FromRequest for TokenserverRequest {
                 })
             };
 
+            let node_url = state.node_registry.resolve(&user.node);
             let tokenserver_request = TokenserverRequest {
                 user,
                 auth_data,
@@ -293,6 +299,8 @@ impl FromRequest for TokenserverRequest {
                 service_id,
                 duration: duration.unwrap_or(state.token_duration),
                 node_type: state.node_type,
+                node_url,
+                tenant: state.tenant.clone(),
             };
 
             tokenserver_request.validate()?;
@@ -462,7 +470,11 @@ impl FromRequest for AuthData {
                     let mut tags = HashMap::default();
                     tags.insert("token_type".to_owned(), "BrowserID".to_owned());
                     metrics.start_timer("token_verification", Some(tags));
-                    let verify_output = state.browserid_verifier.verify(assertion).await?;
+                    let verify_output = state
+                        .browserid_verifier
+                        .verify(assertion)
+                        .await
+                        .map_err(|e| record_verification_failure(&metrics, "BrowserID", e))?;
 
                     // For requests using BrowserID, the client state is embedded in the
                     // X-Client-State header, and the generation and keys_changed_at are extracted
@@ -492,7 +504,11 @@ impl FromRequest for AuthData {
                     let mut tags = HashMap::default();
                     tags.insert("token_type".to_owned(), "OAuth".to_owned());
                     metrics.start_timer("token_verification", Some(tags));
-                    let verify_output = state.oauth_verifier.verify(token).await?;
+                    let verify_output = state
+                        .oauth_verifier
+                        .verify(token)
+                        .await
+                        .map_err(|e| record_verification_failure(&metrics, "OAuth", e))?;
 
                     // For requests using OAuth, the keys_changed_at and client state are embedded
                     // in the X-KeyID header.
@@ -514,6 +530,26 @@ impl FromRequest for AuthData {
     }
 }
 
+/// Counts a failed token verification, tagged with the token type and the cause of the failure.
+/// Failures to reach the verifier are reported separately from tokens it rejected.
+fn record_verification_failure(
+    metrics: &Metrics,
+    token_type: &str,
+    error: TokenserverError,
+) -> TokenserverError {
+    let cause = if error.http_status.is_server_error() {
+        "verifier_unavailable"
+    } else {
+        error.status
+    };
+    let mut tags = HashMap::default();
+    tags.insert("token_type".to_owned(), token_type.to_owned());
+    tags.insert("cause".to_owned(), cause.to_owned());
+    metrics.incr_with_tags("token_verification.failure", tags);
+
+    error
+}
+
 /// The value extracted from the X-Client-State header if it was present. The value in this header
 /// consists of the raw client state bytes encoded as a hexadecimal string.
 struct XClientStateHeader(Option<String>);
@@ -716,7 +752,7 @@ mod tests {
     use tokenserver_db::mock::MockDbPool as MockTokenserverPool;
     use tokenserver_settings::Settings as TokenserverSettings;
 
-    use crate::tokenserver::ServerState;
+    use crate::tokenserver::{nodes::NodeRegistry, ServerState};
 
     use std::sync::Arc;
     use std::time::{SystemTime, UNIX_EPOCH};
@@ -777,6 +813,8 @@ mod tests {
             service_id: i32::default(),
             duration: 100,
             node_type: NodeType::default(),
+            node_url: String::new(),
+            tenant: None,
         };
 
         assert_eq!(result, expected_tokenserver_request);
@@ -1111,6 +1149,8 @@ mod tests {
             service_id: 1,
             duration: TOKEN_DURATION,
             node_type: NodeType::default(),
+            node_url: "node".to_owned(),
+            tenant: None,
         };
 
         let error = tokenserver_request.validate().unwrap_err();
@@ -1154,6 +1194,8 @@ mod tests {
             service_id: 1,
             duration: TOKEN_DURATION,
             node_type: NodeType::default(),
+            node_url: "node".to_owned(),
+            tenant: None,
         };
 
         let error = tokenserver_request.validate().unwrap_err();
@@ -1196,6 +1238,8 @@ mod tests {
             service_id: 1,
             duration: TOKEN_DURATION,
             node_type: NodeType::default(),
+            node_url: "node".to_owned(),
+            tenant: None,
         };
 
         let error = tokenserver_request.validate().unwrap_err();
@@ -1239,6 +1283,8 @@ mod tests {
             service_id: 1,
             duration: TOKEN_DURATION,
             node_type: NodeType::default(),
+            node_url: "node".to_owned(),
+            tenant: None,
         };
 
         let error = tokenserver_request.validate().unwrap_err();
@@ -1276,6 +1322,8 @@ mod tests {
             service_id: 1,
             duration: TOKEN_DURATION,
             node_type: NodeType::default(),
+            node_url: "node".to_owned(),
+            tenant: None,
         };
 
         let error = tokenserver_request.validate().unwrap_err();
@@ -1314,6 +1362,8 @@ mod tests {
             service_id: 1,
             duration: TOKEN_DURATION,
             node_type: NodeType::default(),
+            node_url: "node".to_owned(),
+            tenant: None,
         };
 
         let error = tokenserver_request.validate().unwrap_err();
@@ -1341,6 +1391,7 @@ mod tests {
             db_pool: Box::new(MockTokenserverPool::new()),
             node_capacity_release_rate: None,
             node_type: NodeType::default(),
+            node_registry: NodeRegistry::default(),
             metrics: syncserver_common::metrics_from_opts(
                 &tokenserver_settings.statsd_label,
                 syncserver_settings.statsd_host.as_deref(),
@@ -1348,6 +1399,9 @@ mod tests {
             )
             .unwrap(),
             token_duration: TOKEN_DURATION,
+            tenant: None,
+            service_id: None,
+            storage_token_checks: None,
         }
     }
 }
@@ -7,6 +7,7 @@ use actix_web::{http::StatusCode, Error, HttpResponse};
 use base64::{engine, Engine};
 use serde::Serialize;
 use serde_json::Value;
+use syncserver_common::Metrics;
 use tokenserver_auth::{MakeTokenPlaintext, Tokenlib, TokenserverOrigin};
 use tokenserver_common::{NodeType, TokenserverError};
 use tokenserver_db::{
@@ -36,7 +37,7 @@ pub async fn get_tokenserver_result(
     DbWrapper(db): DbWrapper,
     TokenserverMetrics(mut metrics): TokenserverMetrics,
 ) -> Result<HttpResponse, TokenserverError> {
-    let updates = update_user(&req, db).await?;
+    let updates = update_user(&req, db, &metrics).await?;
 
     let (token, derived_secret) = {
         let token_plaintext = get_token_plaintext(&req, &updates)?;
@@ -50,13 +51,22 @@ pub async fn get_tokenserver_result(
         id: token,
         key: derived_secret,
         uid: updates.uid,
-        api_endpoint: format!("{:}/1.5/{:}", req.user.node, updates.uid),
+        api_endpoint: format!("{:}/1.5/{:}", req.node_url, updates.uid),
         duration: req.duration,
         hashed_fxa_uid: req.hashed_fxa_uid,
         hashalg: "sha256",
         node_type: req.node_type,
     };
 
+    metrics.incr("token.issued");
+    info!(
+        "Token issued";
+        "uid" => &result.hashed_fxa_uid,
+        "generation" => updates.generation,
+        "keys_changed_at" => updates.keys_changed_at,
+        "node" => &req.node_url,
+    );
+
     let timestamp = {
         let start = SystemTime::now();
         start.duration_since(UNIX_EPOCH).unwrap().as_secs()
@@ -94,23 +104,20 @@ fn get_token_plaintext(
         )
     };
 
-    let expires = {
-        let start = SystemTime::now();
-        let current_time = start.duration_since(UNIX_EPOCH).unwrap();
-        let expires = current_time + Duration::from_secs(req.duration);
-
-        expires.as_secs()
-    };
+    let current_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let expires = (current_time + Duration::from_secs(req.duration)).as_secs();
 
     Ok(MakeTokenPlaintext {
-        node: req.user.node.to_owned(),
+        node: req.node_url.to_owned(),
         fxa_kid,
         fxa_uid: req.auth_data.fxa_uid.clone(),
         hashed_device_id: req.hashed_device_id.clone(),
         hashed_fxa_uid: req.hashed_fxa_uid.clone(),
         expires,
+        issued_at: current_time.as_secs(),
         uid: updates.uid.to_owned(),
         tokenserver_origin: TokenserverOrigin::Rust,
+        tenant: req.tenant.clone(),
     })
 }
 
@@ -123,6 +130,7 @@ struct UserUpdates {
 async fn update_user(
     req: &TokenserverRequest,
     db: Box<dyn Db>,
+    metrics: &Metrics,
 ) -> Result<UserUpdates, TokenserverError> {
     let keys_changed_at = match (req.auth_data.keys_changed_at, req.user.keys_changed_at) {
         // If the keys_changed_at in the request is larger than that stored on the user record,
@@ -217,6 +225,12 @@ async fn update_user(
             replaced_at: timestamp,
         })
         .await?;
+        metrics.incr_with_tag("user.created", "reason", "client_state_change");
+        info!(
+            "Replaced user after a client state change";
+            "uid" => &req.hashed_fxa_uid,
+            "generation" => generation,
+        );
 
         Ok(UserUpdates {
             keys_changed_at,
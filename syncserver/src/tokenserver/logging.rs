@@ -5,6 +5,7 @@ use actix_web::{
 use futures::future::Future;
 
 use super::LogItems;
+use crate::web::middleware::request_id::RequestId;
 
 pub fn handle_request_log_line(
     request: ServiceRequest,
@@ -14,7 +15,10 @@ pub fn handle_request_log_line(
         Error = actix_web::Error,
     >,
 ) -> impl Future<Output = Result<ServiceResponse, actix_web::Error>> {
-    let items = LogItems::from(request.head());
+    let mut items = LogItems::from(request.head());
+    if let Some(request_id) = request.extensions().get::<RequestId>() {
+        items.insert("request_id".to_owned(), request_id.0.clone());
+    }
     request.extensions_mut().insert(items);
     let fut = service.call(request);
 
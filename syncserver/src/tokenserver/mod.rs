@@ -1,25 +1,29 @@
 pub mod extractors;
 pub mod handlers;
 pub mod logging;
+pub mod nodes;
 
 use actix_web::{dev::RequestHead, http::header::USER_AGENT, HttpRequest};
 use cadence::StatsdClient;
 use serde::{
     ser::{SerializeMap, Serializer},
-    Serialize,
+    Deserialize, Serialize,
 };
-use syncserver_common::{BlockingThreadpool, Metrics};
-use tokenserver_auth::{browserid, oauth, VerifyToken};
+use syncserver_common::{BlockingThreadpool, Cache, CacheBackend, Metrics};
+use tokenserver_auth::{browserid, oauth, TokenserverOrigin, VerifyToken};
 use tokenserver_common::NodeType;
-use tokenserver_db::{params, DbPool, TokenserverPool};
+use tokenserver_db::{params, results, DbPool, TokenserverPool};
 use tokenserver_settings::Settings;
 
 use crate::{
     error::{ApiError, ApiErrorKind},
     server::user_agent,
+    web::{error::HawkErrorKind, extractors::HawkIdentifier},
 };
+use extractors::SYNC_SERVICE_NAME;
+use nodes::NodeRegistry;
 
-use std::{collections::HashMap, convert::TryFrom, fmt, sync::Arc};
+use std::{collections::HashMap, convert::TryFrom, fmt, sync::Arc, time::Duration};
 
 #[derive(Clone)]
 pub struct ServerState {
@@ -30,8 +34,16 @@ pub struct ServerState {
     pub browserid_verifier: Box<dyn VerifyToken<Output = browserid::VerifyOutput>>,
     pub node_capacity_release_rate: Option<f32>,
     pub node_type: NodeType,
+    pub node_registry: NodeRegistry,
     pub metrics: Arc<StatsdClient>,
     pub token_duration: u64,
+    /// Stamped into the tokens issued (see `Settings::tenant`)
+    pub tenant: Option<String>,
+    /// The sync service's id, looked up at startup
+    pub service_id: Option<i32>,
+    /// `None` unless storage tokens are checked (see
+    /// `Settings::check_storage_tokens`)
+    pub storage_token_checks: Option<Arc<StorageTokenChecks>>,
 }
 
 impl ServerState {
@@ -39,15 +51,22 @@ impl ServerState {
         settings: &Settings,
         metrics: Arc<StatsdClient>,
         blocking_threadpool: Arc<BlockingThreadpool>,
+        cache_backend: &CacheBackend,
     ) -> Result<Self, ApiError> {
-        let oauth_verifier = Box::new(
-            oauth::Verifier::new(settings, blocking_threadpool.clone())
-                .expect("failed to create Tokenserver OAuth verifier"),
-        );
+        let oauth_verifier = {
+            let verifier =
+                oauth::Verifier::new(settings, blocking_threadpool.clone(), cache_backend)
+                    .expect("failed to create Tokenserver OAuth verifier");
+            verifier.spawn_jwks_refresh_task();
+
+            Box::new(verifier)
+        };
         let browserid_verifier = Box::new(
             browserid::Verifier::try_from(settings)
                 .expect("failed to create Tokenserver BrowserID verifier"),
         );
+        let node_registry = NodeRegistry::from_settings(settings)?;
+        node_registry.spawn_refresh_task();
         let use_test_transactions = false;
 
         TokenserverPool::new(
@@ -69,6 +88,7 @@ impl ServerState {
                 })
                 .ok()
                 .map(|result| result.id);
+            let service_id = db_pool.service_id;
 
             ServerState {
                 fxa_email_domain: settings.fxa_email_domain.clone(),
@@ -78,12 +98,147 @@ impl ServerState {
                 db_pool: Box::new(db_pool),
                 node_capacity_release_rate: settings.node_capacity_release_rate,
                 node_type: settings.node_type,
+                node_registry,
                 metrics,
                 token_duration: settings.token_duration,
+                tenant: settings.tenant.clone(),
+                service_id,
+                storage_token_checks: StorageTokenChecks::from_settings(settings).map(Arc::new),
             }
         })
         .map_err(|_| ApiErrorKind::Internal("Failed to create Tokenserver pool".to_owned()).into())
     }
+
+    /// Check a storage request's token, when this Tokenserver minted it and
+    /// `Settings::check_storage_tokens` is set, against the user's records:
+    /// like requests for new tokens, those of a record since replaced (e.g.
+    /// when the user was reassigned to another node) are rejected. Returns
+    /// when the keys of the user's current record last changed, for the
+    /// storage keys check to reject older keys (see
+    /// `DbTransactionPool::check_keys_changed_at`).
+    ///
+    /// Requests aren't failed when the records can't be read (e.g. the
+    /// Tokenserver database is down): their tokens are left unchecked
+    pub async fn check_storage_token(
+        &self,
+        user: &HawkIdentifier,
+    ) -> Result<Option<i64>, ApiError> {
+        let checks = match &self.storage_token_checks {
+            Some(checks) => checks,
+            None => return Ok(None),
+        };
+        if user.tokenserver_origin != TokenserverOrigin::Rust || user.tenant_id != 0 {
+            return Ok(None);
+        }
+        let cached = checks
+            .cache
+            .as_ref()
+            .and_then(|cache| cache.get_value::<StorageTokenRecords>(&user.fxa_uid));
+        let records = match cached {
+            Some(records) => records,
+            None => match self.get_storage_token_records(user).await {
+                Ok(records) => {
+                    if let Some(cache) = &checks.cache {
+                        cache.set_value(&user.fxa_uid, &records, Some(checks.cache_ttl));
+                    }
+                    records
+                }
+                Err(e) => {
+                    warn!("⚠️ Couldn't check a storage token: {}", e);
+                    return Ok(None);
+                }
+            },
+        };
+
+        if records.replaced_uids.contains(&(user.legacy_id as i64)) {
+            return Err(HawkErrorKind::StaleToken.into());
+        }
+        Ok(records.keys_changed_at)
+    }
+
+    async fn get_storage_token_records(
+        &self,
+        user: &HawkIdentifier,
+    ) -> Result<StorageTokenRecords, String> {
+        let db = self.db_pool.get().await.map_err(|e| e.to_string())?;
+        let service_id = match self.service_id {
+            Some(service_id) => service_id,
+            None => {
+                db.get_service_id(params::GetServiceId {
+                    service: SYNC_SERVICE_NAME.to_owned(),
+                })
+                .await
+                .map_err(|e| e.to_string())?
+                .id
+            }
+        };
+        let users = db
+            .get_users(params::GetUsers {
+                service_id,
+                email: format!("{}@{}", user.fxa_uid, self.fxa_email_domain),
+            })
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(StorageTokenRecords::new(&users))
+    }
+}
+
+/// The users' records cached for storage token checks (see
+/// `ServerState::check_storage_token`)
+pub struct StorageTokenChecks {
+    /// Users' records by FxA uid, `None` when not cached
+    cache: Option<Arc<dyn Cache>>,
+    cache_ttl: Duration,
+}
+
+impl StorageTokenChecks {
+    /// Checks per `settings`, `None` when disabled
+    pub fn from_settings(settings: &Settings) -> Option<Self> {
+        if !settings.check_storage_tokens {
+            return None;
+        }
+        let cache_ttl = settings.storage_token_check_cache_ttl;
+        Some(Self {
+            cache: (cache_ttl > 0).then(|| {
+                CacheBackend::Memory.cache(
+                    "storage_token_records",
+                    Some(STORAGE_TOKEN_CACHE_MAX_SIZE),
+                )
+            }),
+            cache_ttl: Duration::from_secs(cache_ttl.into()),
+        })
+    }
+}
+
+/// Max number of users whose records are cached for storage token checks
+const STORAGE_TOKEN_CACHE_MAX_SIZE: usize = 100_000;
+
+/// A user's records, as far as storage token checks go
+#[derive(Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+struct StorageTokenRecords {
+    /// The uids of the records since replaced
+    replaced_uids: Vec<i64>,
+    /// When the keys of the current record last changed, if there's one
+    keys_changed_at: Option<i64>,
+}
+
+impl StorageTokenRecords {
+    /// From the user's records, most recent first
+    fn new(records: &[results::GetRawUser]) -> Self {
+        Self {
+            replaced_uids: records
+                .iter()
+                .filter(|record| record.replaced_at.is_some())
+                .map(|record| record.uid)
+                .collect(),
+            // The keys are identified the way the Tokenserver mints tokens
+            // (see `handlers::get_token_plaintext`)
+            keys_changed_at: records
+                .iter()
+                .find(|record| record.replaced_at.is_none())
+                .map(|record| record.keys_changed_at.unwrap_or(record.generation)),
+        }
+    }
 }
 
 pub struct TokenserverMetrics(Metrics);
@@ -183,3 +338,42 @@ impl<'a> From<&'a HttpRequest> for LogItemsMutator<'a> {
         Self(req)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(
+        uid: i64,
+        keys_changed_at: Option<i64>,
+        replaced_at: Option<i64>,
+    ) -> results::GetRawUser {
+        results::GetRawUser {
+            uid,
+            generation: 1_400_000_000_000,
+            keys_changed_at,
+            replaced_at,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn storage_token_records() {
+        let records = StorageTokenRecords::new(&[
+            record(2, Some(1_600_000_000_000), None),
+            record(1, Some(1_500_000_000_000), Some(1_600_000_000_000)),
+        ]);
+        assert_eq!(
+            records,
+            StorageTokenRecords {
+                replaced_uids: vec![1],
+                keys_changed_at: Some(1_600_000_000_000),
+            }
+        );
+        // Without keys_changed_at, the generation identifies the keys
+        let records = StorageTokenRecords::new(&[record(2, None, None)]);
+        assert_eq!(records.keys_changed_at, Some(1_400_000_000_000));
+        // Users without records are left alone
+        assert_eq!(StorageTokenRecords::new(&[]), StorageTokenRecords::default());
+    }
+}
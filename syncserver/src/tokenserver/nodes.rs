@@ -0,0 +1,130 @@
+//! Maps storage nodes, as recorded in the `nodes` table, to the URLs advertised
+//! to clients (in tokens and their `api_endpoint`).
+use std::{
+    collections::HashMap,
+    fs,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use tokenserver_settings::Settings;
+use tokio::time;
+
+use crate::error::{ApiError, ApiErrorKind};
+
+/// Without a registry file, the `nodes` table is the registry: nodes are
+/// advertised as recorded there (and can be updated there at runtime).
+/// The file is a JSON object of `{"<node>": "<advertised url>"}`, reread
+/// periodically so nodes can be moved to new URLs without a restart
+#[derive(Clone, Debug, Default)]
+pub struct NodeRegistry {
+    path: Option<String>,
+    refresh_interval: Duration,
+    urls: Arc<RwLock<HashMap<String, String>>>,
+}
+
+impl NodeRegistry {
+    /// Fails when the registry file can't be loaded, so a misconfigured node
+    /// doesn't start advertising the wrong URLs
+    pub fn from_settings(settings: &Settings) -> Result<Self, ApiError> {
+        let registry = Self {
+            path: settings.node_registry_path.clone(),
+            refresh_interval: Duration::from_secs(settings.node_registry_refresh_interval),
+            ..Default::default()
+        };
+        registry.reload()?;
+        Ok(registry)
+    }
+
+    /// The URL to advertise for a node
+    pub fn resolve(&self, node: &str) -> String {
+        self.urls
+            .read()
+            .unwrap()
+            .get(node)
+            .cloned()
+            .unwrap_or_else(|| node.to_owned())
+    }
+
+    /// Spawns a task periodically rereading the registry file. Does nothing
+    /// without one
+    pub fn spawn_refresh_task(&self) {
+        if self.path.is_none() {
+            return;
+        }
+        let registry = self.clone();
+
+        tokio::spawn(async move {
+            loop {
+                time::delay_for(registry.refresh_interval).await;
+                // The previous mapping's kept until the file's fixed
+                if let Err(e) = registry.reload() {
+                    warn!("⚠️ Failed to reload the node registry: {}", e);
+                }
+            }
+        });
+    }
+
+    fn reload(&self) -> Result<(), ApiError> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let urls: HashMap<String, String> = fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|contents| serde_json::from_str(&contents).map_err(|e| e.to_string()))
+            .map_err(|e| {
+                ApiErrorKind::Internal(format!("Invalid node registry {}: {}", path, e))
+            })?;
+        let mut current = self.urls.write().unwrap();
+        if *current != urls {
+            info!("Node registry updated"; "nodes" => urls.len());
+            *current = urls;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    #[test]
+    fn resolves_from_registry_file() {
+        let path = env::temp_dir().join(format!("node_registry_{}.json", std::process::id()));
+        fs::write(
+            &path,
+            r#"{"https://old.example.com": "https://new.example.com"}"#,
+        )
+        .unwrap();
+        let settings = Settings {
+            node_registry_path: Some(path.to_string_lossy().into_owned()),
+            ..Default::default()
+        };
+
+        let registry = NodeRegistry::from_settings(&settings).unwrap();
+        assert_eq!(
+            registry.resolve("https://old.example.com"),
+            "https://new.example.com"
+        );
+        // Unlisted nodes are advertised as recorded
+        assert_eq!(
+            registry.resolve("https://other.example.com"),
+            "https://other.example.com"
+        );
+
+        fs::write(&path, "{}").unwrap();
+        registry.reload().unwrap();
+        assert_eq!(
+            registry.resolve("https://old.example.com"),
+            "https://old.example.com"
+        );
+
+        // Invalid files are rejected, keeping the previous mapping
+        fs::write(&path, "[").unwrap();
+        assert!(registry.reload().is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -6,16 +6,24 @@
     allow(dead_code, unused_imports, unused_variables)
 )]
 
-use std::convert::TryInto;
+use std::{
+    convert::TryInto,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::SystemTime,
+};
 
 use base64::{engine, Engine};
 use chrono::offset::Utc;
-use hawk::{self, Header as HawkHeader, Key, RequestBuilder};
+use hawk::{self, DigestAlgorithm, Header as HawkHeader, Key, PayloadHasher, RequestBuilder};
 use hmac::{Hmac, Mac};
 use serde::{Deserialize, Serialize};
-use sha2::Sha256;
-use syncserver_common;
+use sha2::{Digest, Sha256};
+use syncserver_common::{self, BlockingThreadpool, Cache, CacheBackend, MemoryCache};
 use syncserver_settings::Secrets;
+use syncstorage_settings::Settings as SyncstorageSettings;
 use time::Duration;
 use tokenserver_auth::TokenserverOrigin;
 
@@ -26,7 +34,7 @@ use super::{
     error::{HawkErrorKind, ValidationErrorKind},
     extractors::RequestErrorLocation,
 };
-use crate::error::{ApiErrorKind, ApiResult};
+use crate::error::{ApiError, ApiErrorKind, ApiResult};
 use crate::label;
 
 /// A parsed and authenticated JSON payload
@@ -59,6 +67,16 @@ pub struct HawkPayload {
     /// The Tokenserver that created this token.
     #[serde(default)]
     pub tokenserver_origin: TokenserverOrigin,
+
+    /// When the token was minted, in seconds. Absent from tokens of older
+    /// Tokenservers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub issued_at: Option<u64>,
+
+    /// The tenant of the user's storage, set by Tokenservers of other
+    /// tenants than the default one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
 }
 
 impl HawkPayload {
@@ -67,6 +85,7 @@ impl HawkPayload {
     ///
     /// Assumes that the header string
     /// includes the `Hawk ` prefix.
+    #[allow(clippy::too_many_arguments)]
     fn new(
         header: &str,
         method: &str,
@@ -74,6 +93,7 @@ impl HawkPayload {
         host: &str,
         port: u16,
         secrets: &Secrets,
+        key_cache: Option<&HawkKeyCache>,
         expiry: u64,
     ) -> ApiResult<HawkPayload> {
         if header.len() < 5 || &header[0..5] != "Hawk " {
@@ -85,13 +105,10 @@ impl HawkPayload {
 
         let payload = HawkPayload::extract_and_validate(id, secrets, expiry)?;
 
-        let token_secret = syncserver_common::hkdf_expand_32(
-            format!("services.mozilla.com/tokenlib/v1/derive/{}", id).as_bytes(),
-            Some(payload.salt.as_bytes()),
-            &secrets.master_secret,
-        )
-        .map_err(|e| ApiErrorKind::Internal(format!("HKDF Error: {:?}", e)))?;
-        let token_secret = engine::general_purpose::URL_SAFE.encode(token_secret);
+        let token_secret = match key_cache {
+            Some(key_cache) => key_cache.token_secret(id, &payload, secrets)?,
+            None => derive_token_secret(id, &payload.salt, secrets)?,
+        };
 
         let request = RequestBuilder::new(method, host, port, path).request();
 
@@ -158,6 +175,8 @@ impl HawkPayload {
             fxa_kid: "xxx_test".to_owned(),
             device_id: "xxx_test".to_owned(),
             tokenserver_origin: Default::default(),
+            issued_at: None,
+            tenant: None,
         }
     }
 }
@@ -167,6 +186,7 @@ impl HawkPayload {
         header: &str,
         method: &str,
         secrets: &Secrets,
+        key_cache: Option<&HawkKeyCache>,
         ci: &ConnectionInfo,
         uri: &Uri,
     ) -> ApiResult<Self> {
@@ -193,10 +213,302 @@ impl HawkPayload {
             Utc::now().timestamp() as u64
         };
 
-        HawkPayload::new(header, method, path.as_str(), host, port, secrets, expiry)
+        HawkPayload::new(
+            header,
+            method,
+            path.as_str(),
+            host,
+            port,
+            secrets,
+            key_cache,
+            expiry,
+        )
+    }
+
+    /// Verify the `hash` attribute of a Hawk header, when it has one,
+    /// against the request's body and `Content-Type` (without parameters).
+    ///
+    /// The attribute's covered by the header's MAC, so a mismatch means the
+    /// body was altered or truncated after it was signed.
+    pub fn verify_payload_hash(header: &str, content_type: &str, body: &[u8]) -> ApiResult<()> {
+        match PayloadHashVerifier::new(header, content_type)? {
+            Some(mut verifier) => {
+                verifier.update(body)?;
+                verifier.verify()
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// Verifies the `hash` attribute of a Hawk header against a body read a
+/// chunk at a time (see `HawkPayload::verify_payload_hash`)
+pub struct PayloadHashVerifier {
+    hasher: PayloadHasher,
+    expected: Vec<u8>,
+}
+
+impl PayloadHashVerifier {
+    /// A verifier of the header's `hash` attribute, if it has one
+    pub fn new(header: &str, content_type: &str) -> ApiResult<Option<Self>> {
+        if header.len() < 5 || &header[0..5] != "Hawk " {
+            Err(HawkErrorKind::MissingPrefix)?;
+        }
+
+        let header: HawkHeader = header[5..].parse()?;
+        let expected = match header.hash {
+            Some(hash) => hash,
+            None => return Ok(None),
+        };
+        let hasher = PayloadHasher::new(
+            content_type.trim().to_ascii_lowercase().as_bytes(),
+            DigestAlgorithm::Sha256,
+        )?;
+        Ok(Some(Self { hasher, expected }))
+    }
+
+    /// Hash the next chunk of the body
+    pub fn update(&mut self, chunk: &[u8]) -> ApiResult<()> {
+        self.hasher.update(chunk)?;
+        Ok(())
+    }
+
+    /// Check the body read in full against the header's hash
+    pub fn verify(self) -> ApiResult<()> {
+        if self.hasher.finish()? == self.expected {
+            Ok(())
+        } else {
+            Err(HawkErrorKind::PayloadHashMismatch)?
+        }
+    }
+}
+
+/// The Hawk keys of recently seen tokens, by token id, so the requests of a
+/// syncing client don't each repeat their token's key derivation. Keys are
+/// held until their token expires (tokens accepted past their expiry, by
+/// info/collections, are never cached) or evicted to make room, oldest
+/// first.
+#[derive(Debug)]
+pub struct HawkKeyCache {
+    /// `None` when disabled
+    keys: Option<MemoryCache>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct HawkKeyCacheStats {
+    pub entries: u64,
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+}
+
+impl HawkKeyCache {
+    /// A cache of up to `max_size` keys, disabled when 0
+    pub fn new(max_size: u32) -> Self {
+        Self {
+            keys: (max_size > 0).then(|| MemoryCache::new(Some(max_size as usize))),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// The Hawk key of a token, whose `id` was verified to be signed with
+    /// `secrets`
+    fn token_secret(
+        &self,
+        id: &str,
+        payload: &HawkPayload,
+        secrets: &Secrets,
+    ) -> ApiResult<String> {
+        let keys = match &self.keys {
+            Some(keys) => keys,
+            None => return derive_token_secret(id, &payload.salt, secrets),
+        };
+        // Keys derived from a master secret since rotated out are never
+        // looked up again, and left to expire
+        let cache_key = format!(
+            "{}:{}",
+            engine::general_purpose::URL_SAFE.encode(&secrets.signing_secret[..8]),
+            id
+        );
+        if let Some(token_secret) = keys
+            .get(&cache_key)
+            .and_then(|token_secret| String::from_utf8(token_secret).ok())
+        {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(token_secret);
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+
+        let token_secret = derive_token_secret(id, &payload.salt, secrets)?;
+        let expires_in = payload.expires - Utc::now().timestamp() as f64;
+        if expires_in > 0.0 {
+            keys.set(
+                &cache_key,
+                token_secret.clone().into_bytes(),
+                Some(std::time::Duration::from_secs_f64(expires_in)),
+            );
+        }
+        Ok(token_secret)
+    }
+
+    pub fn stats(&self) -> HawkKeyCacheStats {
+        let keys = self
+            .keys
+            .as_ref()
+            .map(|keys| keys.stats())
+            .unwrap_or_default();
+        HawkKeyCacheStats {
+            entries: keys.entries,
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: keys.evictions,
+        }
     }
 }
 
+/// The nonces of recently accepted Hawk requests, by token, so a captured
+/// request can't be replayed. While enabled, requests are only accepted
+/// within `window` of their timestamp (rather than the clock skew leeway),
+/// so nonces are held until their request's past it, or evicted to make
+/// room. Held in process, unless shared through the `cache_url` backend to
+/// also catch replays to other processes.
+#[derive(Debug)]
+pub struct HawkNonceCache {
+    /// `None` when disabled
+    nonces: Option<Arc<dyn Cache>>,
+    window: std::time::Duration,
+    /// Runs the calls to a remote cache
+    blocking_threadpool: Arc<BlockingThreadpool>,
+    replays: AtomicU64,
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct HawkNonceCacheStats {
+    pub entries: u64,
+    pub replays: u64,
+    pub evictions: u64,
+}
+
+impl HawkNonceCache {
+    /// A cache holding nonces in `nonces`, disabled when `None`
+    pub fn new(
+        nonces: Option<Arc<dyn Cache>>,
+        window: std::time::Duration,
+        blocking_threadpool: Arc<BlockingThreadpool>,
+    ) -> Self {
+        Self {
+            nonces,
+            window,
+            blocking_threadpool,
+            replays: AtomicU64::new(0),
+        }
+    }
+
+    /// Held in the `cache_url` backend when `hawk_nonce_cache_shared`
+    pub fn from_settings(
+        settings: &SyncstorageSettings,
+        cache_backend: &CacheBackend,
+        blocking_threadpool: Arc<BlockingThreadpool>,
+    ) -> Self {
+        let memory = CacheBackend::Memory;
+        let cache_backend = if settings.hawk_nonce_cache_shared {
+            cache_backend
+        } else {
+            &memory
+        };
+        let nonces = (settings.hawk_nonce_cache_max_size > 0).then(|| {
+            cache_backend.cache(
+                "hawk_nonce",
+                Some(settings.hawk_nonce_cache_max_size as usize),
+            )
+        });
+        Self::new(
+            nonces,
+            std::time::Duration::from_secs(settings.hawk_nonce_window.into()),
+            blocking_threadpool,
+        )
+    }
+
+    /// Record the nonce of an authenticated request's `Authorization`
+    /// header, failing when its token already used it or its timestamp's
+    /// outside the window
+    pub async fn check(&self, header: &str) -> ApiResult<()> {
+        let nonces = match &self.nonces {
+            Some(nonces) if !cfg!(feature = "no_auth") => nonces,
+            _ => return Ok(()),
+        };
+        let header: HawkHeader = header
+            .strip_prefix("Hawk ")
+            .ok_or(HawkErrorKind::MissingPrefix)?
+            .parse()?;
+        let (id, nonce, ts) = match (&header.id, &header.nonce, header.ts) {
+            (Some(id), Some(nonce), Some(ts)) => (id, nonce, ts),
+            _ => Err(HawkErrorKind::InvalidHeader)?,
+        };
+
+        let now = SystemTime::now();
+        let skew = match ts.duration_since(now) {
+            Ok(ahead) => ahead,
+            Err(e) => e.duration(),
+        };
+        if skew > self.window {
+            Err(HawkErrorKind::StaleTimestamp)?;
+        }
+        // Past it, a replay's rejected regardless
+        let ttl = (ts + self.window).duration_since(now).unwrap_or_default();
+        // Token ids are long: key the nonces by their digest
+        let key = format!(
+            "{}:{}",
+            engine::general_purpose::URL_SAFE.encode(Sha256::digest(id.as_bytes())),
+            nonce
+        );
+
+        let is_new = if nonces.is_remote() {
+            let nonces = Arc::clone(nonces);
+            self.blocking_threadpool
+                .spawn(move || Ok::<_, ApiError>(nonces.set_if_absent(&key, vec![], Some(ttl))))
+                .await?
+        } else {
+            nonces.set_if_absent(&key, vec![], Some(ttl))
+        };
+        if is_new {
+            Ok(())
+        } else {
+            self.replays.fetch_add(1, Ordering::Relaxed);
+            Err(HawkErrorKind::ReplayedNonce)?
+        }
+    }
+
+    /// Cache sizes are only tracked in process
+    pub fn stats(&self) -> HawkNonceCacheStats {
+        let nonces = self
+            .nonces
+            .as_ref()
+            .map(|nonces| nonces.stats())
+            .unwrap_or_default();
+        HawkNonceCacheStats {
+            entries: nonces.entries,
+            replays: self.replays.load(Ordering::Relaxed),
+            evictions: nonces.evictions,
+        }
+    }
+}
+
+/// Derive the Hawk key of a token from the master secret, like
+/// tokenlib: an HKDF expansion salted with the token's `salt`
+fn derive_token_secret(id: &str, salt: &str, secrets: &Secrets) -> ApiResult<String> {
+    let token_secret = syncserver_common::hkdf_expand_32(
+        format!("services.mozilla.com/tokenlib/v1/derive/{}", id).as_bytes(),
+        Some(salt.as_bytes()),
+        &secrets.master_secret,
+    )
+    .map_err(|e| ApiErrorKind::Internal(format!("HKDF Error: {:?}", e)))?;
+    Ok(engine::general_purpose::URL_SAFE.encode(token_secret))
+}
+
 /// Helper function for [HMAC](https://tools.ietf.org/html/rfc2104) verification.
 fn verify_hmac(info: &[u8], key: &[u8], expected: &[u8]) -> ApiResult<()> {
     let mut hmac = Hmac::<Sha256>::new_from_slice(key)?;
@@ -206,7 +518,15 @@ fn verify_hmac(info: &[u8], key: &[u8], expected: &[u8]) -> ApiResult<()> {
 
 #[cfg(test)]
 mod tests {
-    use super::{HawkPayload, Secrets};
+    use std::sync::Arc;
+
+    use base64::{engine, Engine};
+    use chrono::offset::Utc;
+    use futures::executor::block_on;
+    use hawk::{DigestAlgorithm, PayloadHasher};
+    use syncserver_common::{BlockingThreadpool, MemoryCache};
+
+    use super::{HawkKeyCache, HawkNonceCache, HawkPayload, Secrets};
 
     #[test]
     fn valid_header() {
@@ -219,6 +539,7 @@ mod tests {
             &fixture.request.host,
             fixture.request.port,
             &fixture.master_secret,
+            None,
             fixture.expected.expires.round() as u64 - 1,
         );
 
@@ -247,6 +568,7 @@ mod tests {
             &fixture.request.host,
             fixture.request.port,
             &fixture.master_secret,
+            None,
             fixture.expected.expires.round() as u64 - 1,
         );
 
@@ -256,6 +578,67 @@ mod tests {
             .unwrap();
     }
 
+    #[test]
+    fn cached_key() {
+        let fixture = TestFixture::new();
+        let key_cache = HawkKeyCache::new(10);
+        let payload = |secrets: &Secrets| {
+            HawkPayload::new(
+                &fixture.header.to_string(),
+                &fixture.request.method,
+                &fixture.request.path,
+                &fixture.request.host,
+                fixture.request.port,
+                secrets,
+                Some(&key_cache),
+                fixture.expected.expires.round() as u64 - 1,
+            )
+        };
+
+        assert_eq!(payload(&fixture.master_secret).unwrap(), fixture.expected);
+        assert_eq!(payload(&fixture.master_secret).unwrap(), fixture.expected);
+        let stats = key_cache.stats();
+        assert_eq!((stats.entries, stats.hits, stats.misses), (1, 1, 1));
+
+        // Not once the secret's rotated
+        assert!(payload(&Secrets::new("wibble").unwrap()).is_err());
+        assert_eq!(key_cache.stats().hits, 1);
+    }
+
+    #[test]
+    fn replayed_nonce() {
+        let nonce_cache = HawkNonceCache::new(
+            Some(Arc::new(MemoryCache::new(Some(10)))),
+            std::time::Duration::from_secs(60),
+            Arc::new(BlockingThreadpool::default()),
+        );
+        let fixture = TestFixture::new();
+        let check = |nonce: &str, ts: u64| {
+            let mut header = fixture.header.clone();
+            header.nonce = nonce.to_owned();
+            header.ts = ts;
+            block_on(nonce_cache.check(&header.to_string()))
+        };
+        let now = Utc::now().timestamp() as u64;
+
+        assert!(check("h1Ch4vo=", now).is_ok());
+        assert_eq!(
+            check("h1Ch4vo=", now).unwrap_err().to_string(),
+            "HAWK authentication error: nonce already used"
+        );
+        assert!(check("4Rj7c+0=", now).is_ok());
+
+        // Requests outside the window are rejected outright
+        for ts in [now - 61, now + 61] {
+            assert_eq!(
+                check("1d4mRs0=", ts).unwrap_err().to_string(),
+                "HAWK authentication error: timestamp outside the accepted window"
+            );
+        }
+        let stats = nonce_cache.stats();
+        assert_eq!((stats.entries, stats.replays), (2, 1));
+    }
+
     #[test]
     fn missing_hawk_prefix() {
         let fixture = TestFixture::new();
@@ -267,6 +650,7 @@ mod tests {
             &fixture.request.host,
             fixture.request.port,
             &fixture.master_secret,
+            None,
             fixture.expected.expires.round() as u64 - 1,
         );
 
@@ -284,6 +668,7 @@ mod tests {
             &fixture.request.host,
             fixture.request.port,
             &fixture.master_secret,
+            None,
             fixture.expected.expires.round() as u64 - 1,
         );
 
@@ -301,6 +686,7 @@ mod tests {
             &fixture.request.host,
             fixture.request.port,
             &Secrets::new("wibble").unwrap(),
+            None,
             fixture.expected.expires.round() as u64 - 1,
         );
 
@@ -323,6 +709,7 @@ mod tests {
             &fixture.request.host,
             fixture.request.port,
             &fixture.master_secret,
+            None,
             fixture.expected.expires.round() as u64 - 1,
         );
 
@@ -340,6 +727,7 @@ mod tests {
             &fixture.request.host,
             fixture.request.port,
             &fixture.master_secret,
+            None,
             fixture.expected.expires.round() as u64,
         );
 
@@ -358,6 +746,7 @@ mod tests {
             &fixture.request.host,
             fixture.request.port,
             &fixture.master_secret,
+            None,
             fixture.expected.expires.round() as u64 - 1,
         );
 
@@ -376,6 +765,7 @@ mod tests {
             &fixture.request.host,
             fixture.request.port,
             &fixture.master_secret,
+            None,
             fixture.expected.expires.round() as u64 - 1,
         );
 
@@ -394,6 +784,7 @@ mod tests {
             &fixture.request.host,
             fixture.request.port,
             &fixture.master_secret,
+            None,
             fixture.expected.expires.round() as u64 - 1,
         );
 
@@ -412,6 +803,7 @@ mod tests {
             &fixture.request.host,
             fixture.request.port,
             &fixture.master_secret,
+            None,
             fixture.expected.expires.round() as u64 - 1,
         );
 
@@ -433,6 +825,7 @@ mod tests {
             &fixture.request.host,
             fixture.request.port,
             &fixture.master_secret,
+            None,
             fixture.expected.expires.round() as u64 - 1,
         );
 
@@ -451,6 +844,7 @@ mod tests {
             &fixture.request.host,
             fixture.request.port,
             &fixture.master_secret,
+            None,
             fixture.expected.expires.round() as u64 - 1,
         );
 
@@ -469,12 +863,66 @@ mod tests {
             &fixture.request.host,
             fixture.request.port,
             &fixture.master_secret,
+            None,
             fixture.expected.expires.round() as u64 - 1,
         );
 
         assert!(result.is_err());
     }
 
+    #[test]
+    fn valid_payload_hash() {
+        let fixture = TestFixture::new();
+        let body = br#"{"id": "wibble", "payload": "wobble"}"#;
+        let hash =
+            PayloadHasher::hash(&b"application/json"[..], DigestAlgorithm::Sha256, &body[..])
+                .unwrap();
+        let header = format!(
+            "{}, hash=\"{}\"",
+            fixture.header.to_string(),
+            engine::general_purpose::STANDARD.encode(hash)
+        );
+
+        assert!(HawkPayload::verify_payload_hash(&header, "application/json", body).is_ok());
+        assert!(HawkPayload::verify_payload_hash(&header, "Application/JSON", body).is_ok());
+    }
+
+    #[test]
+    fn bad_payload_hash() {
+        let fixture = TestFixture::new();
+        let body = br#"{"id": "wibble", "payload": "wobble"}"#;
+        let hash =
+            PayloadHasher::hash(&b"application/json"[..], DigestAlgorithm::Sha256, &body[..])
+                .unwrap();
+        let header = format!(
+            "{}, hash=\"{}\"",
+            fixture.header.to_string(),
+            engine::general_purpose::STANDARD.encode(hash)
+        );
+
+        // Truncated
+        let result =
+            HawkPayload::verify_payload_hash(&header, "application/json", &body[..body.len() - 1]);
+        assert!(result.is_err());
+
+        // Hashed with another Content-Type
+        let result = HawkPayload::verify_payload_hash(&header, "text/plain", body);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn missing_payload_hash() {
+        let fixture = TestFixture::new();
+
+        let result = HawkPayload::verify_payload_hash(
+            &fixture.header.to_string(),
+            "application/json",
+            b"anything goes",
+        );
+
+        assert!(result.is_ok());
+    }
+
     #[derive(Debug)]
     struct TestFixture {
         pub header: HawkHeader,
@@ -508,12 +956,14 @@ mod tests {
                     fxa_kid: "de697ad66d845b2873c9d7e13b8971af".to_owned(),
                     device_id: "2bcb92f4d4698c3d7b083a3c698a16ccd78bc2a8d20a96e4bb128ddceaf4e0b6".to_owned(),
                     tokenserver_origin: Default::default(),
+                    issued_at: None,
+                    tenant: None,
                 },
             }
         }
     }
 
-    #[derive(Debug)]
+    #[derive(Clone, Debug)]
     struct HawkHeader {
         pub id: String,
         pub mac: String,
@@ -44,7 +44,16 @@ impl HawkError {
             HawkErrorKind::MissingId => Some("request.error.hawk.missing_id".to_owned()),
             HawkErrorKind::MissingPrefix => Some("request.error.hawk.missing_prefix".to_owned()),
             HawkErrorKind::Parse(_) => Some("request.error.hawk.parse_error".to_owned()),
+            HawkErrorKind::PayloadHashMismatch => {
+                Some("request.error.hawk.payload_hash_mismatch".to_owned())
+            }
+            HawkErrorKind::ReplayedNonce => Some("request.error.hawk.replayed_nonce".to_owned()),
+            HawkErrorKind::StaleKeys => Some("request.error.hawk.stale_keys".to_owned()),
+            HawkErrorKind::StaleTimestamp => Some("request.error.hawk.stale_timestamp".to_owned()),
+            HawkErrorKind::StaleToken => Some("request.error.hawk.stale_token".to_owned()),
             HawkErrorKind::TruncatedId => Some("request.error.hawk.id_too_short".to_owned()),
+            HawkErrorKind::UidMismatch => Some("request.error.hawk.uid_mismatch".to_owned()),
+            HawkErrorKind::UnknownTenant => Some("request.error.hawk.unknown_tenant".to_owned()),
             _ => None,
         }
     }
@@ -89,8 +98,29 @@ pub enum HawkErrorKind {
     #[error("{}", _0)]
     Parse(ParseError),
 
+    #[error("payload hash does not match the request body")]
+    PayloadHashMismatch,
+
+    #[error("nonce already used")]
+    ReplayedNonce,
+
+    #[error("token's keys predate the user's latest ones")]
+    StaleKeys,
+
+    #[error("timestamp outside the accepted window")]
+    StaleTimestamp,
+
+    #[error("token predates the user's storage reset")]
+    StaleToken,
+
     #[error("id property is too short")]
     TruncatedId,
+
+    #[error("uid in path does not match the token")]
+    UidMismatch,
+
+    #[error("token of an unknown tenant")]
+    UnknownTenant,
 }
 
 /// An error occurred in an Actix extractor.
@@ -187,6 +217,7 @@ impl From<ValidationErrorKind> for ValidationError {
                 match name.to_ascii_lowercase().as_str() {
                     "accept" => StatusCode::NOT_ACCEPTABLE,
                     "content-type" => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                    "content-length" => StatusCode::PAYLOAD_TOO_LARGE,
                     _ => StatusCode::BAD_REQUEST,
                 }
             }
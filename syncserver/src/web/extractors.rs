@@ -3,19 +3,23 @@
 //! Handles ensuring the header's, body, and query parameters are correct, extraction to
 //! relevant types, and failing correctly with the appropriate errors if issues arise.
 use std::{
-    self, collections::HashMap, collections::HashSet, num::ParseIntError, str::FromStr, sync::Arc,
+    self, collections::HashMap, collections::HashSet, str::FromStr, sync::Arc, time::Instant,
 };
 
 use actix_web::{
-    dev::{ConnectionInfo, Extensions, Payload, RequestHead},
+    dev::{ConnectionInfo, Decompress, Extensions, Payload, RequestHead},
+    error::PayloadError,
     http::{
-        header::{qitem, Accept, ContentType, Header, HeaderMap},
+        header::{qitem, Accept, ContentType, Header, HeaderMap, USER_AGENT},
         Uri,
     },
-    web::{Data, Json, Query},
+    web::{Bytes, BytesMut, Data, Json, Query},
     Error, FromRequest, HttpMessage, HttpRequest,
 };
-use futures::future::{self, FutureExt, LocalBoxFuture, Ready, TryFutureExt};
+use futures::{
+    future::{self, FutureExt, LocalBoxFuture, Ready, TryFutureExt},
+    stream, StreamExt,
+};
 use syncserver_settings::Secrets;
 
 use lazy_static::lazy_static;
@@ -26,10 +30,10 @@ use serde::{
     Deserialize, Serialize,
 };
 use serde_json::Value;
-use syncserver_common::{Metrics, X_WEAVE_RECORDS};
+use syncserver_common::{Metrics, SafeUid, X_WEAVE_RECORDS};
 use syncstorage_db::{
     params::{self, PostCollectionBso},
-    DbError, DbPool, Sorting, SyncTimestamp, UserIdentifier,
+    DbError, DbPool, PoolState, Projection, Sorting, SyncTimestamp, UserIdentifier,
 };
 use tokenserver_auth::TokenserverOrigin;
 use validator::{Validate, ValidationError};
@@ -39,9 +43,15 @@ use crate::label;
 use crate::server::{
     tags::Taggable, MetricsWrapper, ServerState, BSO_ID_REGEX, COLLECTION_ID_REGEX,
 };
+use crate::tokenserver;
 use crate::web::{
-    auth::HawkPayload,
+    auth::{HawkKeyCache, HawkPayload, PayloadHashVerifier},
     error::{HawkErrorKind, ValidationErrorKind},
+    middleware::{
+        body_limit::{body_too_large, is_body_too_large},
+        slow_requests::RequestTrace,
+    },
+    path_uid, safe_path, sync_path,
     transaction::DbTransactionPool,
     DOCKER_FLOW_ENDPOINTS,
 };
@@ -158,6 +168,29 @@ fn get_accepted(req: &HttpRequest, accepted: &[&str], default: &'static str) ->
     "invalid".to_string()
 }
 
+/// Check a request's body against the payload hash of its Hawk header, if
+/// the client sent one
+fn verify_payload_hash(req: &HttpRequest, body: &[u8]) -> Result<(), Error> {
+    if let Some(mut hash_verifier) = payload_hash_verifier(req)? {
+        hash_verifier.update(body)?;
+        hash_verifier.verify()?;
+    }
+    Ok(())
+}
+
+/// A verifier of a request's body, read a chunk at a time, against the
+/// payload hash of its Hawk header, if the client sent one
+fn payload_hash_verifier(req: &HttpRequest) -> Result<Option<PayloadHashVerifier>, Error> {
+    let auth_header = match req.headers().get("authorization") {
+        Some(header) => header
+            .to_str()
+            .map_err(|e| -> ApiError { HawkErrorKind::Header(e).into() })?,
+        // Left to the HawkIdentifier
+        None => return Ok(None),
+    };
+    Ok(PayloadHashVerifier::new(auth_header, req.content_type())?)
+}
+
 #[derive(Default, Deserialize)]
 pub struct BsoBodies {
     pub valid: Vec<BatchBsoBody>,
@@ -177,178 +210,234 @@ impl FromRequest for BsoBodies {
     ///   - Request content-type is a valid value
     ///   - Valid BSO's include a BSO id
     ///
+    /// Newline-delimited bodies are parsed a line at a time as they're read,
+    /// so only the BSOs parsed (not the body they came in) are held whole.
+    /// They're handed to the db layer together, rather than as they're
+    /// parsed: none may be written before the body's payload hash is
+    /// verified, and the valid ones are bounded by `max_post_bytes` anyway.
+    ///
+    /// Bodies sent with a `Content-Encoding` are decoded as they're read,
+    /// `max_request_bytes` bounding their decoded size.
+    ///
     /// No collection id is used, so payload checks are not done here.
     fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
-        // Only try and parse the body if its a valid content-type
-        let ctype = match ContentType::parse(req) {
-            Ok(v) => v,
-            Err(e) => {
-                return Box::pin(future::err(
-                    ValidationErrorKind::FromDetails(
+        let req = req.clone();
+        let mut payload = Decompress::from_headers(payload.take(), req.headers());
+
+        Box::pin(async move {
+            // Only try and parse the body if its a valid content-type
+            let ctype = match ContentType::parse(&req) {
+                Ok(v) => v,
+                Err(e) => {
+                    return Err(ValidationErrorKind::FromDetails(
                         format!("Unreadable Content-Type: {:?}", e),
                         RequestErrorLocation::Header,
                         Some("Content-Type".to_owned()),
                         label!("request.error.invalid_content_type"),
                     )
-                    .into(),
-                ))
-            }
-        };
-        let content_type = format!("{}/{}", ctype.type_(), ctype.subtype());
-        trace!("BSO Body content_type: {:?}", &content_type);
+                    .into())
+                }
+            };
+            let content_type = format!("{}/{}", ctype.type_(), ctype.subtype());
+            trace!("BSO Body content_type: {:?}", &content_type);
 
-        if !ACCEPTED_CONTENT_TYPES.contains(&content_type.as_ref()) {
-            return Box::pin(future::err(
-                ValidationErrorKind::FromDetails(
+            if !ACCEPTED_CONTENT_TYPES.contains(&content_type.as_ref()) {
+                return Err(ValidationErrorKind::FromDetails(
                     format!("Invalid Content-Type {:?}", content_type),
                     RequestErrorLocation::Header,
                     Some("Content-Type".to_owned()),
                     label!("request.error.invalid_content_type"),
                 )
-                .into(),
-            ));
-        }
-
-        // Load the entire request into a String
-        let fut = <String>::from_request(req, payload).map_err(|e| {
-            warn!("⚠️ Payload read error: {:?}", e);
-            ValidationErrorKind::FromDetails(
-                "Mimetype/encoding/content-length error".to_owned(),
-                RequestErrorLocation::Header,
-                None,
-                None,
-            )
-            .into()
-        });
-
-        // Avoid duplicating by defining our error func now, doesn't need the box wrapper
-        fn make_error() -> Error {
-            ValidationErrorKind::FromDetails(
-                "Invalid JSON in request body".to_owned(),
-                RequestErrorLocation::Body,
-                Some("bsos".to_owned()),
-                label!("request.validate.invalid_body_json"),
-            )
-            .into()
-        }
-
-        // Define a new bool to check from a static closure to release the reference on the
-        // content_type header
-        let newlines: bool = content_type == "application/newlines";
+                .into());
+            }
+            let newlines = content_type == "application/newlines";
 
-        // Grab the max sizes
-        let state = match req.app_data::<Data<ServerState>>() {
-            Some(s) => s,
-            None => {
-                error!("⚠️ Could not load the app state");
-                return Box::pin(future::err(
-                    ValidationErrorKind::FromDetails(
+            // Grab the max sizes
+            let state = match req.app_data::<Data<ServerState>>() {
+                Some(s) => s,
+                None => {
+                    error!("⚠️ Could not load the app state");
+                    return Err(ValidationErrorKind::FromDetails(
                         "Internal error".to_owned(),
                         RequestErrorLocation::Unknown,
                         Some("app_data".to_owned()),
                         None,
                     )
-                    .into(),
-                ));
-            }
-        };
+                    .into());
+                }
+            };
+            let max_request_bytes = state.limits.max_request_bytes as usize;
+            let mut bodies = BsoBodiesParser::new(
+                state.limits.max_record_payload_bytes as usize,
+                state.limits.max_post_bytes as usize,
+            );
 
-        let max_payload_size = state.limits.max_record_payload_bytes as usize;
-        let max_post_bytes = state.limits.max_post_bytes as usize;
-
-        let fut = fut.and_then(move |body| {
-            // Get all the raw / values
-            let bsos: Vec<Value> = if newlines {
-                let mut bsos = Vec::new();
-                for item in body.lines() {
-                    // Check that its a valid JSON map like we expect
-                    if let Ok(raw_json) = serde_json::from_str::<Value>(item) {
-                        bsos.push(raw_json);
-                    } else {
-                        // Per Python version, BSO's must json deserialize
-                        return future::err(make_error());
+            let mut hash_verifier = payload_hash_verifier(&req)?;
+            let mut body = BytesMut::new();
+            let mut read = 0;
+            // The bytes at the start of the body known to hold no newline
+            let mut scanned = 0;
+            // An invalid BSO's error is only returned once the body's been
+            // read whole and its payload hash verified
+            let mut parsed = Ok(());
+            while let Some(chunk) = payload.next().await {
+                let chunk = chunk.map_err(|e| {
+                    if let PayloadError::Overflow = e {
+                        // Cut off by `limit_request_body`
+                        return Error::from(body_too_large());
                     }
+                    warn!("⚠️ Payload read error: {:?}", e);
+                    Error::from(ValidationErrorKind::FromDetails(
+                        "Mimetype/encoding/content-length error".to_owned(),
+                        RequestErrorLocation::Header,
+                        None,
+                        None,
+                    ))
+                })?;
+                read += chunk.len();
+                if read > max_request_bytes {
+                    return Err(body_too_large().into());
+                }
+                if let Some(hash_verifier) = hash_verifier.as_mut() {
+                    hash_verifier.update(&chunk)?;
+                }
+                if parsed.is_err() {
+                    continue;
+                }
+
+                body.extend_from_slice(&chunk);
+                if newlines {
+                    while let Some(end) = body[scanned..].iter().position(|b| *b == b'\n') {
+                        let line = body.split_to(scanned + end + 1);
+                        scanned = 0;
+                        parsed = bodies.push_line(&line[..line.len() - 1]);
+                        if parsed.is_err() {
+                            body.clear();
+                            break;
+                        }
+                    }
+                    scanned = body.len();
+                }
+            }
+            if let Some(hash_verifier) = hash_verifier {
+                hash_verifier.verify()?;
+            }
+            parsed?;
+
+            if newlines {
+                if !body.is_empty() {
+                    bodies.push_line(&body)?;
                 }
-                bsos
-            } else if let Ok(json_vals) = serde_json::from_str::<Vec<Value>>(&body) {
-                json_vals
             } else {
                 // Per Python version, BSO's must json deserialize
-                return future::err(make_error());
-            };
+                let bsos: Vec<Value> = serde_json::from_slice(&body).map_err(|_| invalid_json())?;
+                for bso in bsos {
+                    bodies.push(bso)?;
+                }
+            }
+            Ok(bodies.finish())
+        })
+    }
+}
 
-            // Validate all the BSO's, move invalid to our other list. Assume they'll all make
-            // it with our pre-allocation
-            let mut valid: Vec<BatchBsoBody> = Vec::with_capacity(bsos.len());
+fn invalid_json() -> Error {
+    ValidationErrorKind::FromDetails(
+        "Invalid JSON in request body".to_owned(),
+        RequestErrorLocation::Body,
+        Some("bsos".to_owned()),
+        label!("request.validate.invalid_body_json"),
+    )
+    .into()
+}
 
-            // Invalid BSO's are any BSO that can deserialize despite how wrong the contents are
-            // per the way the Python version works.
-            let mut invalid: HashMap<String, String> = HashMap::new();
+/// Validates the BSOs of a POST as they're parsed, sorting them into the
+/// valid and invalid ones
+struct BsoBodiesParser {
+    bodies: BsoBodies,
+    /// For dupe detection
+    ids: HashSet<String>,
+    max_payload_size: usize,
+    max_post_bytes: usize,
+    /// Keep track of our total payload size
+    total_payload_size: usize,
+}
 
-            // Keep track of our total payload size
-            let mut total_payload_size = 0;
+impl BsoBodiesParser {
+    fn new(max_payload_size: usize, max_post_bytes: usize) -> Self {
+        Self {
+            bodies: BsoBodies::default(),
+            ids: HashSet::new(),
+            max_payload_size,
+            max_post_bytes,
+            total_payload_size: 0,
+        }
+    }
 
-            // Temporarily track the bso id's for dupe detection
-            let mut bso_ids: HashSet<String> = HashSet::with_capacity(bsos.len());
+    /// Parse a line of a newline-delimited body
+    fn push_line(&mut self, line: &[u8]) -> Result<(), Error> {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        // Per Python version, BSO's must json deserialize
+        let bso = serde_json::from_slice::<Value>(line).map_err(|_| invalid_json())?;
+        self.push(bso)
+    }
 
-            for bso in bsos {
-                // Error out if its not a JSON mapping type
-                if !bso.is_object() {
-                    return future::err(make_error());
-                }
-                // Save all id's we get, check for missing id, or duplicate.
-                let bso_id = if let Some(id) = bso.get("id").and_then(serde_json::Value::as_str) {
-                    let id = id.to_string();
-                    if bso_ids.contains(&id) {
-                        return future::err(
-                            ValidationErrorKind::FromDetails(
-                                "Input BSO has duplicate ID".to_owned(),
-                                RequestErrorLocation::Body,
-                                Some("bsos".to_owned()),
-                                label!("request.store.duplicate_bso_id"),
-                            )
-                            .into(),
-                        );
-                    } else {
-                        bso_ids.insert(id.clone());
-                        id
-                    }
+    fn push(&mut self, bso: Value) -> Result<(), Error> {
+        // Error out if its not a JSON mapping type
+        if !bso.is_object() {
+            return Err(invalid_json());
+        }
+        // Save all id's we get, check for missing id, or duplicate.
+        let bso_id = if let Some(id) = bso.get("id").and_then(serde_json::Value::as_str) {
+            let id = id.to_string();
+            if self.ids.contains(&id) {
+                return Err(ValidationErrorKind::FromDetails(
+                    "Input BSO has duplicate ID".to_owned(),
+                    RequestErrorLocation::Body,
+                    Some("bsos".to_owned()),
+                    label!("request.store.duplicate_bso_id"),
+                )
+                .into());
+            } else {
+                self.ids.insert(id.clone());
+                id
+            }
+        } else {
+            return Err(ValidationErrorKind::FromDetails(
+                "Input BSO has no ID".to_owned(),
+                RequestErrorLocation::Body,
+                Some("bsos".to_owned()),
+                label!("request.store.missing_bso_id"),
+            )
+            .into());
+        };
+        // Invalid BSO's are any BSO that can deserialize despite how wrong the contents are
+        // per the way the Python version works.
+        match BatchBsoBody::from_raw_bso(bso) {
+            Ok(b) => {
+                // Is this record too large? Deny if it is.
+                let payload_size = b
+                    .payload
+                    .as_ref()
+                    .map(std::string::String::len)
+                    .unwrap_or_default();
+                self.total_payload_size += payload_size;
+                if payload_size <= self.max_payload_size
+                    && self.total_payload_size <= self.max_post_bytes
+                {
+                    self.bodies.valid.push(b);
                 } else {
-                    return future::err(
-                        ValidationErrorKind::FromDetails(
-                            "Input BSO has no ID".to_owned(),
-                            RequestErrorLocation::Body,
-                            Some("bsos".to_owned()),
-                            label!("request.store.missing_bso_id"),
-                        )
-                        .into(),
-                    );
-                };
-                match BatchBsoBody::from_raw_bso(bso) {
-                    Ok(b) => {
-                        // Is this record too large? Deny if it is.
-                        let payload_size = b
-                            .payload
-                            .as_ref()
-                            .map(std::string::String::len)
-                            .unwrap_or_default();
-                        total_payload_size += payload_size;
-                        if payload_size <= max_payload_size && total_payload_size <= max_post_bytes
-                        {
-                            valid.push(b);
-                        } else {
-                            invalid.insert(b.id, "retry bytes".to_string());
-                        }
-                    }
-                    Err(e) => {
-                        invalid.insert(bso_id, e);
-                    }
+                    self.bodies.invalid.insert(b.id, "retry bytes".to_string());
                 }
             }
-            future::ok(BsoBodies { valid, invalid })
-        });
+            Err(e) => {
+                self.bodies.invalid.insert(bso_id, e);
+            }
+        }
+        Ok(())
+    }
 
-        Box::pin(fut)
+    fn finish(self) -> BsoBodies {
+        self.bodies
     }
 }
 
@@ -423,19 +512,33 @@ impl FromRequest for BsoBody {
 
             let max_payload_size = state.limits.max_record_payload_bytes as usize;
 
+            let read_error = |e: Error| {
+                if is_body_too_large(&e) {
+                    return ApiError::from(body_too_large());
+                }
+                warn!("⚠️ Could not parse BSO Body: {:?}", e);
+                let err: ApiError = ValidationErrorKind::FromDetails(
+                    e.to_string(),
+                    RequestErrorLocation::Body,
+                    Some("bso".to_owned()),
+                    label!("request.validate.bad_bso_body"),
+                )
+                .into();
+                err
+            };
+
+            // The body's read whole to check its payload hash, then replayed
+            // to the JSON extractor
+            let body = Bytes::from_request(&req, &mut payload)
+                .await
+                .map_err(read_error)?;
+            verify_payload_hash(&req, &body)?;
+            let mut payload =
+                Payload::Stream(Box::pin(stream::once(future::ok::<_, PayloadError>(body))));
+
             let bso = <Json<BsoBody>>::from_request(&req, &mut payload)
                 .await
-                .map_err(|e| {
-                    warn!("⚠️ Could not parse BSO Body: {:?}", e);
-                    let err: ApiError = ValidationErrorKind::FromDetails(
-                        e.to_string(),
-                        RequestErrorLocation::Body,
-                        Some("bso".to_owned()),
-                        label!("request.validate.bad_bso_body"),
-                    )
-                    .into();
-                    err
-                })?;
+                .map_err(read_error)?;
 
             // Check the max payload size manually with our desired limit
             if bso
@@ -477,7 +580,10 @@ impl BsoParam {
     fn bsoparam_from_path(uri: &Uri) -> Result<Self, Error> {
         // TODO: replace with proper path parser
         // path: "/1.5/{uid}/storage/{collection}/{bso}"
-        let elements: Vec<&str> = uri.path().split('/').collect();
+        let elements: Vec<&str> = sync_path(uri.path())
+            .unwrap_or_default()
+            .split('/')
+            .collect();
         let elem = elements.get(3);
         if elem.is_none() || elem != Some(&"storage") || elements.len() != 6 {
             return Err(ValidationErrorKind::FromDetails(
@@ -508,7 +614,7 @@ impl BsoParam {
             })?;
             Ok(Self { bso: sv })
         } else {
-            warn!("⚠️ Missing BSO: {:?}", uri.path());
+            warn!("⚠️ Missing BSO: {:?}", safe_path(uri.path()));
             Err(ValidationErrorKind::FromDetails(
                 "Missing BSO".to_owned(),
                 RequestErrorLocation::Path,
@@ -553,7 +659,10 @@ impl CollectionParam {
     fn col_from_path(uri: &Uri) -> Result<Option<CollectionParam>, Error> {
         // TODO: replace with proper path parser.
         // path: "/1.5/{uid}/storage/{collection}"
-        let elements: Vec<&str> = uri.path().split('/').collect();
+        let elements: Vec<&str> = sync_path(uri.path())
+            .unwrap_or_default()
+            .split('/')
+            .collect();
         let elem = elements.get(3);
         if elem.is_none() || elem != Some(&"storage") || !(5..=6).contains(&elements.len()) {
             return Ok(None);
@@ -674,7 +783,11 @@ pub struct CollectionRequest {
     pub collection: String,
     pub user_id: UserIdentifier,
     pub tokenserver_origin: TokenserverOrigin,
+    /// With `limit` capped to the server's `max_get_records`
     pub query: BsoQueryParams,
+    /// Max combined size of the payloads returned, past which they're
+    /// paginated
+    pub max_payload_bytes: Option<u64>,
     pub reply: ReplyFormat,
     pub metrics: Metrics,
 }
@@ -688,13 +801,35 @@ impl FromRequest for CollectionRequest {
         let req = req.clone();
         let mut payload = Payload::None;
         async move {
-            let (user_id, query, collection) =
-                <(HawkIdentifier, BsoQueryParams, CollectionParam)>::from_request(
-                    &req,
-                    &mut payload,
-                )
-                .await?;
+            let state = match req.app_data::<Data<ServerState>>() {
+                Some(s) => s,
+                None => {
+                    error!("⚠️ Could not load the app state");
+                    return Err(ValidationErrorKind::FromDetails(
+                        "Internal error".to_owned(),
+                        RequestErrorLocation::Unknown,
+                        Some("app_data".to_owned()),
+                        None,
+                    )
+                    .into());
+                }
+            };
+
+            let (user_id, mut query, collection) = <(
+                HawkIdentifier,
+                BsoQueryParams,
+                CollectionParam,
+            )>::from_request(&req, &mut payload)
+            .await?;
             let collection = collection.collection;
+            // Whatever the client asked for: larger results are paginated
+            if let Some(max_records) = state.max_get_records {
+                query.limit = Some(
+                    query
+                        .limit
+                        .map_or(max_records, |limit| limit.min(max_records)),
+                );
+            }
 
             let accept = get_accepted(&req, &ACCEPTED_CONTENT_TYPES, "application/json");
             let reply = match accept.as_str() {
@@ -716,6 +851,7 @@ impl FromRequest for CollectionRequest {
                 tokenserver_origin: user_id.tokenserver_origin,
                 user_id: user_id.into(),
                 query,
+                max_payload_bytes: state.max_get_bytes.map(u64::from),
                 reply,
                 metrics: MetricsWrapper::extract(&req).await?.0,
             })
@@ -738,6 +874,7 @@ pub struct CollectionPostRequest {
     pub batch: Option<BatchRequest>,
     pub metrics: Metrics,
     pub quota_enabled: bool,
+    pub quota_warning: Option<QuotaWarning>,
 }
 
 impl FromRequest for CollectionPostRequest {
@@ -779,6 +916,7 @@ impl FromRequest for CollectionPostRequest {
                 .await?;
 
             let collection = collection.collection;
+            throttle_write(&req, state, &collection)?;
             if collection == "crypto" {
                 // Verify the client didn't mess up the crypto if we have a payload
                 for bso in &bsos.valid {
@@ -808,6 +946,14 @@ impl FromRequest for CollectionPostRequest {
 
             // XXX: let's not use extract here (maybe convert to extrude?)
             let batch = BatchRequestOpt::extract(&req).await?;
+            let metrics = MetricsWrapper::extract(&req).await?.0;
+            record_writes(
+                &req,
+                state,
+                &metrics,
+                bsos.valid.iter().map(|bso| bso.payload.as_deref()),
+            );
+            let quota_warning = QuotaWarning::from_state(state, &user_id);
             Ok(CollectionPostRequest {
                 collection,
                 tokenserver_origin: user_id.tokenserver_origin,
@@ -815,13 +961,76 @@ impl FromRequest for CollectionPostRequest {
                 query,
                 bsos,
                 batch: batch.opt,
-                metrics: MetricsWrapper::extract(&req).await?.0,
+                metrics,
                 quota_enabled: state.quota_enabled,
+                quota_warning,
             })
         })
     }
 }
 
+/// Reject a write past its collection's rate limit (see
+/// `server::throttle`)
+fn throttle_write(req: &HttpRequest, state: &ServerState, collection: &str) -> Result<(), Error> {
+    let Some(uid) = path_uid(req.path()) else {
+        return Ok(());
+    };
+    state
+        .write_throttle
+        .acquire(uid, collection)
+        .map_err(|retry_after| ApiError::from(ApiErrorKind::Throttled(retry_after)).into())
+}
+
+/// Count a request's BSO writes towards its user's write rates, reporting
+/// the user as a pathological client when they're past the thresholds
+fn record_writes<'a>(
+    req: &HttpRequest,
+    state: &ServerState,
+    metrics: &Metrics,
+    payloads: impl ExactSizeIterator<Item = Option<&'a str>>,
+) {
+    let Some(uid) = path_uid(req.path()) else {
+        return;
+    };
+    let writes = payloads.len();
+    let bytes = payloads.map(|payload| payload.map_or(0, str::len)).sum();
+    if let Some(reason) = state.abuse.record_writes(uid, writes, bytes) {
+        warn!(
+            "⚠️ Pathological client: {} past the {} threshold",
+            SafeUid(uid),
+            reason;
+            "ua" => req
+                .headers()
+                .get(USER_AGENT)
+                .and_then(|ua| ua.to_str().ok())
+                .unwrap_or_default()
+        );
+        metrics.incr_with_tag("storage.abuse.detected", "reason", &reason.to_string());
+    }
+}
+
+/// Thresholds for warning clients nearing their quota on writes
+#[derive(Clone, Copy, Debug)]
+pub struct QuotaWarning {
+    /// Collection usage (in bytes) past which responses carry warnings
+    pub soft_limit: usize,
+    /// Collection usage (in bytes) past which writes are rejected
+    pub hard_limit: usize,
+}
+
+impl QuotaWarning {
+    /// The thresholds applying to `user`: none when they're exempt from
+    /// quota, their overridden limit (if any) as the hard limit otherwise
+    fn from_state(state: &ServerState, user: &HawkIdentifier) -> Option<Self> {
+        let soft_limit = state.quota_soft_limit? as usize;
+        let hard_limit = state.quota.limit_for(user.legacy_id, &user.fxa_uid)?;
+        Some(QuotaWarning {
+            soft_limit: soft_limit.min(hard_limit),
+            hard_limit,
+        })
+    }
+}
+
 /// BSO Request Delete/Get extractor
 ///
 /// Extracts/validates information needed for BSO delete/get requests.
@@ -875,6 +1084,7 @@ pub struct BsoPutRequest {
     pub bso: String,
     pub body: BsoBody,
     pub metrics: Metrics,
+    pub quota_warning: Option<QuotaWarning>,
 }
 
 impl FromRequest for BsoPutRequest {
@@ -887,6 +1097,19 @@ impl FromRequest for BsoPutRequest {
         let mut payload = payload.take();
 
         async move {
+            let state = match req.app_data::<Data<ServerState>>() {
+                Some(s) => s,
+                None => {
+                    error!("⚠️ Could not load the app state");
+                    return Err(ValidationErrorKind::FromDetails(
+                        "Internal error".to_owned(),
+                        RequestErrorLocation::Unknown,
+                        Some("app_data".to_owned()),
+                        None,
+                    )
+                    .into());
+                }
+            };
             let metrics = MetricsWrapper::extract(&req).await?.0;
             let (user_id, collection, query, bso, body) =
                 <(
@@ -897,6 +1120,13 @@ impl FromRequest for BsoPutRequest {
                     BsoBody,
                 )>::from_request(&req, &mut payload)
                 .await?;
+            throttle_write(&req, state, &collection.collection)?;
+            record_writes(
+                &req,
+                state,
+                &metrics,
+                std::iter::once(body.payload.as_deref()),
+            );
 
             let collection = collection.collection;
             if collection == "crypto" {
@@ -913,6 +1143,7 @@ impl FromRequest for BsoPutRequest {
                     }
                 }
             }
+            let quota_warning = QuotaWarning::from_state(state, &user_id);
             Ok(BsoPutRequest {
                 collection,
                 tokenserver_origin: user_id.tokenserver_origin,
@@ -921,6 +1152,7 @@ impl FromRequest for BsoPutRequest {
                 bso: bso.bso,
                 body,
                 metrics,
+                quota_warning,
             })
         }
         .boxed_local()
@@ -937,6 +1169,8 @@ pub struct QuotaInfo {
 pub struct HeartbeatRequest {
     pub headers: HeaderMap,
     pub db_pool: Box<dyn DbPool<Error = DbError>>,
+    /// The state of the tokenserver's pool, when it's enabled
+    pub tokenserver_pool: Option<PoolState>,
     pub quota: QuotaInfo,
 }
 
@@ -964,6 +1198,9 @@ impl FromRequest for HeartbeatRequest {
                 }
             };
             let db_pool = state.db_pool.clone();
+            let tokenserver_pool = req
+                .app_data::<Data<tokenserver::ServerState>>()
+                .map(|state| state.db_pool.state());
             let quota = QuotaInfo {
                 enabled: state.quota_enabled,
                 size: state.limits.max_quota_limit,
@@ -972,6 +1209,7 @@ impl FromRequest for HeartbeatRequest {
             Ok(HeartbeatRequest {
                 headers,
                 db_pool,
+                tokenserver_pool,
                 quota,
             })
         }
@@ -1008,6 +1246,19 @@ pub struct HawkIdentifier {
     pub fxa_uid: String,
     pub fxa_kid: String,
     pub tokenserver_origin: TokenserverOrigin,
+    /// When the user's token was minted, in seconds (when known)
+    pub issued_at: Option<u64>,
+    /// The id of the user's tenant (see `syncstorage_settings::Settings::tenants`),
+    /// 0 for the default one
+    pub tenant_id: u16,
+}
+
+/// The uid in a request's path: the legacy numeric uid issued by older
+/// tokenservers or the FxA uid used by durable sync deployments
+#[derive(Debug, PartialEq)]
+enum PathUid {
+    Legacy(u64),
+    FxaUid(String),
 }
 
 impl HawkIdentifier {
@@ -1018,17 +1269,30 @@ impl HawkIdentifier {
             fxa_uid: "cmd".to_owned(),
             fxa_kid: "cmd".to_owned(),
             tokenserver_origin: TokenserverOrigin::default(),
+            issued_at: None,
+            tenant_id: 0,
         }
     }
 
-    fn uid_from_path(uri: &Uri) -> Result<u64, Error> {
+    /// When the keys the token was issued for last changed, in milliseconds:
+    /// the leading part of its `fxa_kid`, which the Tokenserver took from
+    /// the client's `X-KeyID`
+    pub fn keys_changed_at(&self) -> Option<i64> {
+        let (keys_changed_at, _) = self.fxa_kid.split_once('-')?;
+        keys_changed_at.parse().ok()
+    }
+
+    fn uid_from_path(uri: &Uri) -> Result<PathUid, Error> {
         // TODO: replace with proper path parser.
         // path: "/1.5/{uid}"
-        let elements: Vec<&str> = uri.path().split('/').collect();
+        let elements: Vec<&str> = sync_path(uri.path())
+            .unwrap_or_default()
+            .split('/')
+            .collect();
         if let Some(v) = elements.get(2) {
             let clean = match urldecode(v) {
                 Err(e) => {
-                    warn!("⚠️ HawkIdentifier Error invalid UID {:?} {:?}", v, e);
+                    warn!("⚠️ HawkIdentifier Error invalid UID {} {:?}", SafeUid(v), e);
                     return Err(ValidationErrorKind::FromDetails(
                         "Invalid UID".to_owned(),
                         RequestErrorLocation::Path,
@@ -1039,8 +1303,11 @@ impl HawkIdentifier {
                 }
                 Ok(v) => v,
             };
-            u64::from_str(&clean).map_err(|e| {
-                warn!("⚠️ HawkIdentifier Error invalid UID {:?} {:?}", v, e);
+            if clean.len() == 32 && clean.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Ok(PathUid::FxaUid(clean.to_ascii_lowercase()));
+            }
+            u64::from_str(&clean).map(PathUid::Legacy).map_err(|e| {
+                warn!("⚠️ HawkIdentifier Error invalid UID {} {:?}", SafeUid(v), e);
                 ValidationErrorKind::FromDetails(
                     "Invalid UID".to_owned(),
                     RequestErrorLocation::Path,
@@ -1050,7 +1317,10 @@ impl HawkIdentifier {
                 .into()
             })
         } else {
-            warn!("⚠️ HawkIdentifier Error missing UID {:?}", uri);
+            warn!(
+                "⚠️ HawkIdentifier Error missing UID {:?}",
+                safe_path(uri.path())
+            );
             Err(ValidationErrorKind::FromDetails(
                 "Missing UID".to_owned(),
                 RequestErrorLocation::Path,
@@ -1060,58 +1330,73 @@ impl HawkIdentifier {
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn extrude<T>(
         msg: &T,
         method: &str,
         uri: &Uri,
         ci: &ConnectionInfo,
         secrets: &Secrets,
+        key_cache: Option<&HawkKeyCache>,
+        tenants: &HashMap<String, u16>,
     ) -> Result<Self, Error>
     where
         T: HttpMessage,
     {
-        if let Some(user_id) = msg.extensions().get::<HawkIdentifier>() {
-            return Ok(user_id.clone());
-        }
-
         let auth_header = msg
             .headers()
             .get("authorization")
             .ok_or_else(|| -> ApiError { HawkErrorKind::MissingHeader.into() })?
             .to_str()
             .map_err(|e| -> ApiError { HawkErrorKind::Header(e).into() })?;
-        let identifier = Self::generate(
+        Self::generate(
             secrets,
+            key_cache,
+            tenants,
             method,
             auth_header,
             ci,
             uri,
             &mut msg.extensions_mut(),
-        )?;
-        msg.extensions_mut().insert(identifier.clone());
-        Ok(identifier)
+        )
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn generate(
         secrets: &Secrets,
+        key_cache: Option<&HawkKeyCache>,
+        tenants: &HashMap<String, u16>,
         method: &str,
         header: &str,
         connection_info: &ConnectionInfo,
         uri: &Uri,
         exts: &mut Extensions,
     ) -> Result<Self, Error> {
-        let payload = HawkPayload::extrude(header, method, secrets, connection_info, uri)?;
-        let puid = Self::uid_from_path(uri)?;
-        if payload.user_id != puid {
-            warn!("⚠️ Hawk UID not in URI: {:?} {:?}", payload.user_id, uri);
-            Err(ValidationErrorKind::FromDetails(
-                "conflicts with payload".to_owned(),
-                RequestErrorLocation::Path,
-                Some("uid".to_owned()),
-                label!("request.validate.hawk.uri_missing_uid"),
-            ))?;
+        let payload =
+            HawkPayload::extrude(header, method, secrets, key_cache, connection_info, uri)?;
+        let uid_matches = match Self::uid_from_path(uri)? {
+            PathUid::Legacy(uid) => uid == payload.user_id,
+            PathUid::FxaUid(fxa_uid) => fxa_uid.eq_ignore_ascii_case(&payload.fxa_uid),
+        };
+        if !uid_matches {
+            // A valid token for one user must never grant access to another
+            // user's storage
+            warn!(
+                "⚠️ Hawk UID not in URI: {} {:?}",
+                SafeUid(payload.user_id),
+                safe_path(uri.path())
+            );
+            return Err(ApiError::from(HawkErrorKind::UidMismatch).into());
         }
 
+        let tenant_id = match &payload.tenant {
+            None => 0,
+            Some(tenant) => *tenants.get(tenant).ok_or_else(|| {
+                warn!("⚠️ Hawk token of an unknown tenant: {:?}", tenant);
+                ApiError::from(HawkErrorKind::UnknownTenant)
+            })?,
+        };
+
         // Store the origin of the token so we can later use it as a tag when emitting metrics
         exts.insert(payload.tokenserver_origin);
 
@@ -1120,6 +1405,8 @@ impl HawkIdentifier {
             fxa_uid: payload.fxa_uid,
             fxa_kid: payload.fxa_kid,
             tokenserver_origin: payload.tokenserver_origin,
+            issued_at: payload.issued_at,
+            tenant_id,
         };
         Ok(user_id)
     }
@@ -1132,19 +1419,24 @@ impl From<HawkIdentifier> for UserIdentifier {
             fxa_uid: hawk_id.fxa_uid,
             fxa_kid: hawk_id.fxa_kid,
         }
+        .scoped_to_tenant(hawk_id.tenant_id)
     }
 }
 
 impl FromRequest for HawkIdentifier {
     type Config = ();
     type Error = Error;
-    type Future = Ready<Result<Self, Self::Error>>;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
 
     /// Use HawkPayload extraction and format as HawkIdentifier.
     fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
         // Dummy token if a Docker Flow request is detected.
         if DOCKER_FLOW_ENDPOINTS.contains(&req.uri().path().to_lowercase().as_str()) {
-            return future::ready(Ok(HawkIdentifier::cmd_dummy()));
+            return Box::pin(future::ok(HawkIdentifier::cmd_dummy()));
+        }
+        // Authenticated once per request: its nonce is used up by then
+        if let Some(user_id) = req.extensions().get::<HawkIdentifier>() {
+            return Box::pin(future::ok(user_id.clone()));
         }
         let req = req.clone();
         let uri = req.uri();
@@ -1157,68 +1449,67 @@ impl FromRequest for HawkIdentifier {
             Some(v) => v,
             None => {
                 let err: ApiError = ApiErrorKind::Internal("No app_data Secrets".to_owned()).into();
-                return future::ready(Err(err.into()));
+                return Box::pin(future::err(err.into()));
             }
         };
 
-        let result = Self::extrude(&req, method.as_str(), uri, &connection_info, secrets);
+        let tenants = req
+            .app_data::<Data<ServerState>>()
+            .map(|state| Arc::clone(&state.tenants))
+            .unwrap_or_default();
+        let key_cache = req
+            .app_data::<Data<ServerState>>()
+            .map(|state| Arc::clone(&state.hawk_key_cache));
+        let nonce_cache = req
+            .app_data::<Data<ServerState>>()
+            .map(|state| Arc::clone(&state.hawk_nonce_cache));
+
+        let start = Instant::now();
+        let result = Self::extrude(
+            &req,
+            method.as_str(),
+            uri,
+            &connection_info,
+            secrets,
+            key_cache.as_deref(),
+            &tenants,
+        );
+
+        Box::pin(async move {
+            let result = match (result, nonce_cache) {
+                (Ok(hawk_id), Some(nonce_cache)) => {
+                    // Only once the request's authenticated, so forged ones
+                    // can't use up nonces
+                    let auth_header = req
+                        .headers()
+                        .get("authorization")
+                        .and_then(|header| header.to_str().ok())
+                        .unwrap_or_default();
+                    nonce_cache
+                        .check(auth_header)
+                        .await
+                        .map(|_| hawk_id)
+                        .map_err(Into::into)
+                }
+                (result, _) => result,
+            };
+            RequestTrace::record(&req, "auth", start.elapsed());
 
-        if let Ok(ref hawk_id) = result {
+            let hawk_id = result?;
+            req.extensions_mut().insert(hawk_id.clone());
             // Store the origin of the token as an extra to be included when emitting a Sentry error
             req.add_extra(
                 "tokenserver_origin".to_owned(),
                 hawk_id.tokenserver_origin.to_string(),
             );
-        }
-
-        future::ready(result)
-    }
-}
-
-#[derive(Debug, Default, Clone, Copy, Deserialize, Eq, PartialEq, Validate)]
-#[serde(default)]
-pub struct Offset {
-    pub timestamp: Option<SyncTimestamp>,
-    pub offset: u64,
-}
-
-impl From<Offset> for params::Offset {
-    fn from(offset: Offset) -> Self {
-        Self {
-            timestamp: offset.timestamp,
-            offset: offset.offset,
-        }
-    }
-}
-
-impl FromStr for Offset {
-    type Err = ParseIntError;
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        // issue559: Disable ':' support for now: simply parse as i64 as
-        // previously (it was u64 previously but i64's close enough)
-        let result = Offset {
-            timestamp: None,
-            offset: s.parse::<u64>()?,
-        };
-        /*
-        let result = match s.chars().position(|c| c == ':') {
-            None => Offset {
-                timestamp: None,
-                offset: s.parse::<u64>()?,
-            },
-            Some(_colon_position) => {
-                let mut parts = s.split(':');
-                let timestamp_string = parts.next().unwrap_or("0");
-                let timestamp = SyncTimestamp::from_milliseconds(timestamp_string.parse::<u64>()?);
-                let offset = parts.next().unwrap_or("0").parse::<u64>()?;
-                Offset {
-                    timestamp: Some(timestamp),
-                    offset,
-                }
+            if let Some(active_users) = req
+                .app_data::<Data<ServerState>>()
+                .and_then(|state| state.active_users.as_ref())
+            {
+                active_users.record(&hawk_id.fxa_uid);
             }
-        };
-        */
-        Ok(result)
+            Ok(hawk_id)
+        })
     }
 }
 
@@ -1246,7 +1537,7 @@ pub struct BsoQueryParams {
 
     /// position at which to restart search (string)
     #[serde(deserialize_with = "deserialize_offset")]
-    pub offset: Option<Offset>,
+    pub offset: Option<params::Offset>,
 
     /// a comma-separated list of BSO ids (list of strings)
     #[serde(deserialize_with = "deserialize_comma_sep_string", default)]
@@ -1256,6 +1547,10 @@ pub struct BsoQueryParams {
     // flag, whether to include full bodies (bool)
     #[serde(deserialize_with = "deserialize_present_value")]
     pub full: bool,
+
+    /// the fields of full bodies: `full` (the default), `nosortindex` or
+    /// `meta` (only `id` and `modified`)
+    pub projection: Projection,
 }
 
 impl FromRequest for BsoQueryParams {
@@ -1693,13 +1988,13 @@ where
     }
 }
 
-fn deserialize_offset<'de, D>(deserializer: D) -> Result<Option<Offset>, D::Error>
+fn deserialize_offset<'de, D>(deserializer: D) -> Result<Option<params::Offset>, D::Error>
 where
     D: Deserializer<'de>,
 {
     let maybe_str: Option<String> = Deserialize::deserialize(deserializer)?;
     if let Some(val) = maybe_str {
-        return Ok(Some(Offset::from_str(&val).map_err(SerdeError::custom)?));
+        return Ok(Some(val.parse().map_err(SerdeError::custom)?));
     }
     Ok(None)
 }
@@ -1753,15 +2048,17 @@ mod tests {
     use rand::{thread_rng, Rng};
     use serde_json::{self, json};
     use sha2::Sha256;
-    use syncserver_common;
+    use syncserver_common::{self, ActiveUsers, BlockingThreadpool};
     use syncserver_settings::Settings as GlobalSettings;
-    use syncstorage_settings::{Deadman, ServerLimits, Settings as SyncstorageSettings};
+    use syncstorage_settings::{Deadman, Quota, ServerLimits, Settings as SyncstorageSettings};
     use tokio::sync::RwLock;
 
-    use crate::server::ServerState;
+    use crate::server::{
+        abuse::AbuseDetector, overload::Overload, throttle::WriteThrottle, ServerState,
+    };
     use syncstorage_db::mock::{MockDb, MockDbPool};
 
-    use crate::web::auth::HawkPayload;
+    use crate::web::auth::{HawkKeyCache, HawkNonceCache, HawkPayload};
 
     lazy_static! {
         static ref SERVER_LIMITS: Arc<ServerLimits> = Arc::new(ServerLimits::default());
@@ -1788,6 +2085,8 @@ mod tests {
             db_pool: Box::new(MockDbPool::new()),
             limits: Arc::clone(&SERVER_LIMITS),
             limits_json: serde_json::to_string(&**SERVER_LIMITS).unwrap(),
+            max_get_records: syncstorage_settings.max_get_records,
+            max_get_bytes: syncstorage_settings.max_get_bytes,
             port: 8000,
             metrics: syncserver_common::metrics_from_opts(
                 &syncstorage_settings.statsd_label,
@@ -1796,7 +2095,24 @@ mod tests {
             )
             .unwrap(),
             quota_enabled: syncstorage_settings.enable_quota,
+            quota_soft_limit: syncstorage_settings.quota_soft_limit,
+            quota: Quota::from(&syncstorage_settings),
             deadman: Arc::new(RwLock::new(Deadman::default())),
+            active_users: Some(Arc::new(ActiveUsers::default())),
+            overload: Arc::new(Overload::default()),
+            abuse: Arc::new(AbuseDetector::default()),
+            write_throttle: Arc::new(WriteThrottle::default()),
+            tenants: Arc::new(HashMap::new()),
+            hawk_key_cache: Arc::new(HawkKeyCache::new(0)),
+            hawk_nonce_cache: Arc::new(HawkNonceCache::new(
+                None,
+                std::time::Duration::default(),
+                Arc::new(BlockingThreadpool::default()),
+            )),
+            admin_token: None,
+            slow_request_threshold: None,
+            query_budget: None,
+            expired_bso_queue: None,
         }
     }
 
@@ -1840,6 +2156,15 @@ mod tests {
     async fn post_collection(
         qs: &str,
         body: &serde_json::Value,
+    ) -> Result<CollectionPostRequest, Error> {
+        post_collection_chunks(qs, "application/json; charset=UTF-8", &[&body.to_string()]).await
+    }
+
+    /// POST a body sent in `chunks`
+    async fn post_collection_chunks(
+        qs: &str,
+        content_type: &str,
+        chunks: &[&str],
     ) -> Result<CollectionPostRequest, Error> {
         let payload = HawkPayload::test_default(*USER_ID);
         let state = make_state();
@@ -1850,7 +2175,6 @@ mod tests {
             if !qs.is_empty() { "?" } else { "" },
             qs
         );
-        let bod_str = body.to_string();
         let header =
             create_valid_hawk_header(&payload, &secrets, "POST", &path, TEST_HOST, TEST_PORT);
         let req = TestRequest::with_uri(&format!("http://{}:{}{}", TEST_HOST, TEST_PORT, path))
@@ -1858,9 +2182,9 @@ mod tests {
             .data(secrets)
             .method(Method::POST)
             .header("authorization", header)
-            .header("content-type", "application/json; charset=UTF-8")
+            .header("content-type", content_type)
             .header("accept", "application/json;q=0.9,/;q=0.2")
-            .set_payload(bod_str.to_owned())
+            .set_payload(chunks.concat())
             .param("uid", &USER_ID_STR)
             .param("collection", "tabs")
             .to_http_request();
@@ -1868,8 +2192,11 @@ mod tests {
 
         // Not sure why but sending req through *::extract loses the body.
         // Compose a payload here and call the *::from_request
-        let (_sender, mut payload) = h1::Payload::create(true);
-        payload.unread_data(Bytes::from(bod_str.to_owned()));
+        let (mut sender, payload) = h1::Payload::create(false);
+        for chunk in chunks {
+            sender.feed_data(Bytes::from(chunk.to_string()));
+        }
+        sender.feed_eof();
         CollectionPostRequest::from_request(&req, &mut payload.into()).await
     }
 
@@ -1947,6 +2274,21 @@ mod tests {
         assert_eq!(result.sort, Sorting::Index);
         assert_eq!(result.older.unwrap(), SyncTimestamp::from_seconds(2.43));
         assert!(result.full);
+        assert_eq!(result.projection, Projection::Full);
+    }
+
+    #[test]
+    fn test_projection_query_arg() {
+        let req = TestRequest::with_uri("/?full=1&projection=meta")
+            .data(make_state())
+            .to_http_request();
+        let result = block_on(BsoQueryParams::extract(&req)).unwrap();
+        assert_eq!(result.projection, Projection::Meta);
+
+        let req = TestRequest::with_uri("/?full=1&projection=payload")
+            .data(make_state())
+            .to_http_request();
+        assert!(block_on(BsoQueryParams::extract(&req)).is_err());
     }
 
     #[test]
@@ -2190,6 +2532,33 @@ mod tests {
         assert!(result.batch.is_none());
     }
 
+    #[actix_rt::test]
+    async fn test_newlines_collection_post_request_in_chunks() {
+        // Lines split across chunks, and chunks holding several lines
+        let chunks = [
+            "{\"id\": \"123\", \"payl",
+            "oad\": \"xxx\"}\r\n{\"id\": \"456\", \"payload\": \"y\"}\n{\"id\": \"789\",",
+            " \"sortindex\": \"bad\"}",
+        ];
+        let result = post_collection_chunks("", "application/newlines", &chunks)
+            .await
+            .expect("Could not get result in test_newlines_collection_post_request_in_chunks");
+        let ids: Vec<_> = result
+            .bsos
+            .valid
+            .iter()
+            .map(|bso| bso.id.as_str())
+            .collect();
+        assert_eq!(ids, ["123", "456"]);
+        assert_eq!(result.bsos.valid[0].payload.as_deref(), Some("xxx"));
+        assert!(result.bsos.invalid.contains_key("789"));
+
+        let chunks = ["{\"id\": \"123\"}\n", "not json\n", "{\"id\": \"456\"}"];
+        let result = post_collection_chunks("", "application/newlines", &chunks).await;
+        let response: HttpResponse = result.err().unwrap().into();
+        assert_eq!(response.status(), 400);
+    }
+
     #[actix_rt::test]
     async fn test_invalid_collection_post_request() {
         // Add extra fields, these will be invalid
@@ -2348,6 +2717,43 @@ mod tests {
         assert_eq!(result.legacy_id, *USER_ID);
     }
 
+    #[test]
+    fn valid_header_with_fxa_uid_path() {
+        let fxa_uid = "319b98f9961ff1dbdd07313cd6ba925a";
+        let mut hawk_payload = HawkPayload::test_default(*USER_ID);
+        hawk_payload.fxa_uid = fxa_uid.to_owned();
+        let state = make_state();
+        let secrets = Arc::clone(&SECRETS);
+        let uri = format!("/1.5/{}/storage/col2", fxa_uid.to_uppercase());
+        let header =
+            create_valid_hawk_header(&hawk_payload, &secrets, "GET", &uri, TEST_HOST, TEST_PORT);
+        let req = TestRequest::with_uri(&uri)
+            .header("authorization", header)
+            .method(Method::GET)
+            .data(state)
+            .data(secrets)
+            .to_http_request();
+        let mut payload = Payload::None;
+        let result = block_on(HawkIdentifier::from_request(&req, &mut payload))
+            .expect("Could not get result in valid_header_with_fxa_uid_path");
+        assert_eq!(result.legacy_id, *USER_ID);
+        assert_eq!(result.fxa_uid, fxa_uid);
+
+        // Another user's FxA uid is rejected
+        let uri = "/1.5/00000000000000000000000000000000/storage/col2";
+        let header =
+            create_valid_hawk_header(&hawk_payload, &SECRETS, "GET", uri, TEST_HOST, TEST_PORT);
+        let req = TestRequest::with_uri(uri)
+            .header("authorization", header)
+            .method(Method::GET)
+            .data(make_state())
+            .data(Arc::clone(&SECRETS))
+            .to_http_request();
+        let result = block_on(HawkIdentifier::extract(&req));
+        let response: HttpResponse = result.err().unwrap().into();
+        assert_eq!(response.status(), 401);
+    }
+
     #[test]
     fn valid_header_with_invalid_uid_in_path() {
         // the uid in the hawk payload should match the UID in the path.
@@ -2368,19 +2774,84 @@ mod tests {
         let result = block_on(HawkIdentifier::extract(&req));
         assert!(result.is_err());
         let response: HttpResponse = result.err().unwrap().into();
-        assert_eq!(response.status(), 400);
+        assert_eq!(response.status(), 401);
         let body = extract_body_as_str(ServiceResponse::new(req, response));
         assert_eq!(body, "0");
+    }
 
-        /* New tests for when we can use descriptive errors
+    #[test]
+    fn valid_header_replayed_against_other_uid() {
+        // A token signed for USER_ID's path must not authorize another user's
+        // path, even when signed with a matching path
+        let hawk_payload = HawkPayload::test_default(*USER_ID);
+        let other_uid = *USER_ID + 1;
+        let state = make_state();
+        let secrets = Arc::clone(&SECRETS);
+        let uri = format!("/1.5/{}/info/collections", other_uid);
+        let header =
+            create_valid_hawk_header(&hawk_payload, &secrets, "GET", &uri, TEST_HOST, TEST_PORT);
+        let req = TestRequest::with_uri(&uri)
+            .data(state)
+            .data(secrets)
+            .header("authorization", header)
+            .method(Method::GET)
+            .param("uid", &other_uid.to_string())
+            .to_http_request();
+        let mut payload = Payload::None;
+        let result = block_on(HawkIdentifier::from_request(&req, &mut payload));
+        let response: HttpResponse = result.err().unwrap().into();
+        assert_eq!(response.status(), 401);
+    }
 
-        let err: serde_json::Value = serde_json::from_str(&body).unwrap();
-        assert_eq!(err["status"], 400);
+    #[test]
+    fn valid_header_of_a_tenant() {
+        let mut hawk_payload = HawkPayload::test_default(*USER_ID);
+        hawk_payload.tenant = Some("staging".to_owned());
+        let uri = format!("/1.5/{}/storage/col2", *USER_ID);
+        let request = |state: ServerState| {
+            let header = create_valid_hawk_header(
+                &hawk_payload,
+                &SECRETS,
+                "GET",
+                &uri,
+                TEST_HOST,
+                TEST_PORT,
+            );
+            TestRequest::with_uri(&uri)
+                .header("authorization", header)
+                .method(Method::GET)
+                .data(state)
+                .data(Arc::clone(&SECRETS))
+                .to_http_request()
+        };
 
-        assert_eq!(err["errors"][0]["description"], "conflicts with payload");
-        assert_eq!(err["errors"][0]["location"], "path");
-        assert_eq!(err["errors"][0]["name"], "uid");
-        */
+        let mut state = make_state();
+        state.tenants = Arc::new(HashMap::from([("staging".to_owned(), 3)]));
+        let result = block_on(HawkIdentifier::extract(&request(state)))
+            .expect("Could not get result in valid_header_of_a_tenant");
+        assert_eq!(result.tenant_id, 3);
+        let user_id = UserIdentifier::from(result);
+        assert_ne!(user_id.legacy_id, *USER_ID);
+        assert_eq!(user_id.fxa_uid, "xxx_test@3");
+
+        // Tokens of tenants the server doesn't know are rejected
+        let result = block_on(HawkIdentifier::extract(&request(make_state())));
+        let response: HttpResponse = result.err().unwrap().into();
+        assert_eq!(response.status(), 401);
+    }
+
+    #[test]
+    fn keys_changed_at_of_fxa_kid() {
+        let user_id = |fxa_kid: &str| HawkIdentifier {
+            fxa_kid: fxa_kid.to_owned(),
+            ..Default::default()
+        };
+        assert_eq!(
+            user_id("1500000000000-P_Jt-8o0Lg").keys_changed_at(),
+            Some(1_500_000_000_000)
+        );
+        assert_eq!(user_id("xxx_test").keys_changed_at(), None);
+        assert_eq!(user_id("").keys_changed_at(), None);
     }
 
     #[actix_rt::test]
@@ -2405,14 +2876,42 @@ mod tests {
         let sample_offset = params::Offset {
             timestamp: Some(SyncTimestamp::default()),
             offset: 1234,
+            after: None,
         };
 
-        let test_offset = Offset {
+        let test_offset = params::Offset {
             timestamp: None,
             offset: sample_offset.offset,
+            after: None,
         };
 
         let offset_str = sample_offset.to_string();
-        assert!(test_offset == Offset::from_str(&offset_str).unwrap())
+        assert!(test_offset == offset_str.parse().unwrap())
+    }
+
+    #[actix_rt::test]
+    async fn test_keyset_offset() {
+        let offset = params::Offset {
+            timestamp: None,
+            offset: 20,
+            after: Some(params::BsoPosition {
+                modified: SyncTimestamp::from_milliseconds(1_234_567_890),
+                id: "{a:b}".to_owned(),
+            }),
+        };
+        let offset_str = offset.to_string();
+        assert_eq!(offset_str, "20:1234567890:7b613a627d");
+        assert_eq!(offset, offset_str.parse().unwrap());
+
+        for invalid in [
+            "",
+            "x",
+            "20:1234567890",
+            "20:x:7b",
+            "20:1234567890:7",
+            "20:1:zz",
+        ] {
+            assert!(invalid.parse::<params::Offset>().is_err(), "{}", invalid);
+        }
     }
 }
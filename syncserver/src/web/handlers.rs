@@ -1,31 +1,48 @@
 //! API Handlers
 use std::collections::HashMap;
 use std::convert::Into;
+use std::time::Instant;
 
-use actix_web::{dev::HttpResponseBuilder, http::StatusCode, web::Data, HttpRequest, HttpResponse};
-use serde::Serialize;
+use actix_web::{
+    dev::HttpResponseBuilder,
+    http::{header::AUTHORIZATION, StatusCode},
+    web::{Bytes, Data, Json},
+    HttpRequest, HttpResponse,
+};
+use futures::stream;
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use syncserver_common::{X_LAST_MODIFIED, X_WEAVE_NEXT_OFFSET, X_WEAVE_RECORDS};
+use sha2::{Digest, Sha256};
+use syncserver_common::{
+    Metrics, SafeUid, X_LAST_MODIFIED, X_WEAVE_ALERT, X_WEAVE_NEXT_OFFSET, X_WEAVE_QUOTA_REMAINING,
+    X_WEAVE_RECORDS, X_WEAVE_TOTAL_BYTES, X_WEAVE_TOTAL_RECORDS,
+};
 use syncstorage_db::{
-    params,
-    results::{CreateBatch, Paginated},
-    Db, DbError, DbErrorIntrospect,
+    collection_tag, params,
+    results::{self, CreateBatch, Paginated},
+    Db, DbError, DbErrorIntrospect, DbPool, PoolState, Sorting, SyncTimestamp, UserIdentifier,
 };
 use time;
 
 use crate::{
     error::{ApiError, ApiErrorKind},
+    logging,
     server::ServerState,
     web::{
         extractors::{
             BsoPutRequest, BsoRequest, CollectionPostRequest, CollectionRequest, EmitApiMetric,
-            HeartbeatRequest, MetaRequest, ReplyFormat, TestErrorRequest,
+            HeartbeatRequest, MetaRequest, QuotaWarning, ReplyFormat, TestErrorRequest,
         },
+        middleware::slow_requests::RequestTrace,
         transaction::DbTransactionPool,
+        wire::Bso,
     },
 };
 
 pub const ONE_KB: f64 = 1024.0;
+/// The BSOs read at a time by streamed collection GETs, which are only
+/// streamed past this many
+const NEWLINES_PAGE_SIZE: u64 = 1000;
 
 pub async fn get_collections(
     meta: MetaRequest,
@@ -105,11 +122,30 @@ pub async fn delete_all(
     db_pool
         .transaction_http(request, |db| async move {
             meta.emit_api_metric("request.delete_all");
-            Ok(HttpResponse::Ok().json(db.delete_storage(meta.user_id).await?))
+            db.delete_storage(meta.user_id.clone()).await?;
+
+            // Users are only identified in the logs: a metric tag per user
+            // would be unbounded
+            info!("Deleted all storage"; "uid" => hash_user_id(&meta.user_id));
+            meta.metrics.incr("storage.delete_all");
+
+            let timestamp = db.timestamp();
+            Ok(HttpResponse::Ok()
+                .header(X_LAST_MODIFIED, timestamp.as_header())
+                .json(timestamp))
         })
         .await
 }
 
+/// A stable, non-reversible identifier for a user suitable for logs
+fn hash_user_id(user_id: &UserIdentifier) -> String {
+    if user_id.fxa_uid.is_empty() {
+        SafeUid(user_id.legacy_id).to_string()
+    } else {
+        SafeUid(&user_id.fxa_uid).to_string()
+    }
+}
+
 pub async fn delete_collection(
     coll: CollectionRequest,
     db_pool: DbTransactionPool,
@@ -118,13 +154,27 @@ pub async fn delete_collection(
     db_pool
         .transaction_http(request, |db| async move {
             let delete_bsos = !coll.query.ids.is_empty();
+            let mut deleted = 0;
             let timestamp = if delete_bsos {
                 coll.emit_api_metric("request.delete_bsos");
-                db.delete_bsos(params::DeleteBsos {
-                    user_id: coll.user_id.clone(),
-                    collection: coll.collection.clone(),
-                    ids: coll.query.ids.clone(),
-                })
+                async {
+                    // Counted beforehand as deletes don't report their rows
+                    deleted = db
+                        .get_bsos_count(params::GetBsosCount {
+                            user_id: coll.user_id.clone(),
+                            collection: coll.collection.clone(),
+                            newer: None,
+                            older: None,
+                            ids: coll.query.ids.clone(),
+                        })
+                        .await?;
+                    db.delete_bsos(params::DeleteBsos {
+                        user_id: coll.user_id.clone(),
+                        collection: coll.collection.clone(),
+                        ids: coll.query.ids.clone(),
+                    })
+                    .await
+                }
                 .await
             } else {
                 coll.emit_api_metric("request.delete_collection");
@@ -148,7 +198,8 @@ pub async fn delete_collection(
 
             let mut resp = HttpResponse::Ok();
             if delete_bsos {
-                resp.header(X_LAST_MODIFIED, timestamp.as_header());
+                resp.header(X_LAST_MODIFIED, timestamp.as_header())
+                    .header(X_WEAVE_RECORDS, deleted.to_string());
             }
             Ok(resp.json(timestamp))
         })
@@ -161,6 +212,10 @@ pub async fn get_collection(
     db_pool: DbTransactionPool,
     request: HttpRequest,
 ) -> Result<HttpResponse, ApiError> {
+    let req = request.clone();
+    let pool = request
+        .app_data::<Data<ServerState>>()
+        .map(|state| state.db_pool.clone());
     db_pool
         .transaction_http(request, |db| async move {
             coll.emit_api_metric("request.get_collection");
@@ -170,26 +225,231 @@ pub async fn get_collection(
                 older: coll.query.older,
                 sort: coll.query.sort,
                 limit: coll.query.limit,
-                offset: coll.query.offset.map(Into::into),
+                offset: coll.query.offset.clone(),
                 ids: coll.query.ids.clone(),
                 full: coll.query.full,
+                projection: coll.query.projection,
                 collection: coll.collection.clone(),
+                max_payload_bytes: coll.max_payload_bytes,
             };
+            if let (ReplyFormat::Newlines, Some(pool)) = (coll.reply, pool) {
+                if let Some(records) = streamed_records(&coll, &*db).await? {
+                    return Ok(stream_get_collection(&coll, &*db, pool, params, records).await?);
+                }
+            }
             let response = if coll.query.full {
-                let result = db.get_bsos(params).await;
-                finish_get_collection(&coll, db, result).await?
+                let projection = coll.query.projection;
+                let result = db.get_bsos(params).await.map(|bsos| Paginated {
+                    items: bsos
+                        .items
+                        .into_iter()
+                        .map(|bso| Bso::projected(bso, projection))
+                        .collect(),
+                    offset: bsos.offset,
+                });
+                finish_get_collection(&coll, &req, db, result).await?
             } else {
                 // Changed to be a Paginated list of BSOs, need to extract IDs from them.
                 let result = db.get_bso_ids(params).await;
-                finish_get_collection(&coll, db, result).await?
+                finish_get_collection(&coll, &req, db, result).await?
             };
             Ok(response)
         })
         .await
 }
 
+/// Answers a GET's `X-Last-Modified` and `X-Weave-Records` headers by
+/// counting the matching BSOs rather than loading them
+pub async fn head_collection(
+    coll: CollectionRequest,
+    db_pool: DbTransactionPool,
+    request: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    db_pool
+        .transaction_http(request, |db| async move {
+            coll.emit_api_metric("request.head_collection");
+            // The number of records the equivalent GET would return
+            let mut records = count_records(&coll, &*db).await?;
+            if let Some(limit) = coll.query.limit {
+                records = records.min(limit.into());
+            }
+
+            let ts = db
+                .extract_resource(coll.user_id.clone(), Some(coll.collection.clone()), None)
+                .await?;
+            Ok(HttpResponse::Ok()
+                .header(X_LAST_MODIFIED, ts.as_header())
+                .header(X_WEAVE_RECORDS, records.to_string())
+                .finish())
+        })
+        .await
+}
+
+/// The number of BSOs matching a collection GET past its offset,
+/// regardless of its limit
+async fn count_records(
+    coll: &CollectionRequest,
+    db: &dyn Db<Error = DbError>,
+) -> Result<u64, DbError> {
+    let count = db
+        .get_bsos_count(params::GetBsosCount {
+            user_id: coll.user_id.clone(),
+            collection: coll.collection.clone(),
+            newer: coll.query.newer,
+            older: coll.query.older,
+            ids: coll.query.ids.clone(),
+        })
+        .await
+        .or_else(|e| {
+            if e.is_collection_not_found() {
+                Ok(0)
+            } else {
+                Err(e)
+            }
+        })?;
+    let offset = coll.query.offset.as_ref().map_or(0, |offset| offset.offset);
+    Ok(count.saturating_sub(offset))
+}
+
+/// The number of BSOs a newline-delimited collection GET returns, when
+/// there are enough of them for it to be streamed (see
+/// `stream_get_collection`)
+async fn streamed_records(
+    coll: &CollectionRequest,
+    db: &dyn Db<Error = DbError>,
+) -> Result<Option<u64>, DbError> {
+    // Limited to a page anyway
+    if matches!(coll.query.limit, Some(limit) if u64::from(limit) <= NEWLINES_PAGE_SIZE) {
+        return Ok(None);
+    }
+    // A streamed response can't be paginated: by its payloads' size, or by
+    // a limit cutting it short
+    if coll.max_payload_bytes.is_some() {
+        return Ok(None);
+    }
+    let records = count_records(coll, db).await?;
+    if records <= NEWLINES_PAGE_SIZE
+        || matches!(coll.query.limit, Some(limit) if records > u64::from(limit))
+    {
+        return Ok(None);
+    }
+    Ok(Some(records))
+}
+
+/// Reply to a newline-delimited collection GET with a body streamed a page
+/// of BSOs at a time, rather than buffering them all.
+///
+/// Pages are read once the request's transaction has ended, each in a
+/// transaction (and on a connection) of its own, so BSOs written meanwhile
+/// are left out.
+async fn stream_get_collection(
+    coll: &CollectionRequest,
+    db: &dyn Db<Error = DbError>,
+    pool: Box<dyn DbPool<Error = DbError>>,
+    mut params: params::GetBsos,
+    records: u64,
+) -> Result<HttpResponse, DbError> {
+    let ts = db
+        .extract_resource(coll.user_id.clone(), Some(coll.collection.clone()), None)
+        .await?;
+    // Pages only line up given a total order
+    if params.sort == Sorting::None {
+        params.sort = Sorting::Newest;
+    }
+    // Timestamps have a 10ms resolution
+    let written_since = SyncTimestamp::from_milliseconds(u64::from(ts) + 10);
+    params.older = Some(match params.older {
+        Some(older) if older < written_since => older,
+        _ => written_since,
+    });
+    let lock = params::LockCollection {
+        user_id: coll.user_id.clone(),
+        collection: coll.collection.clone(),
+    };
+    let offset = params.offset.take();
+
+    // Each page starts at the offset the previous one returned, the last
+    // one returning none
+    let pages = stream::unfold(Some((0, offset)), move |state| {
+        let (pool, lock, mut params) = (pool.clone(), lock.clone(), params.clone());
+        async move {
+            let (streamed, offset) = state?;
+            if streamed >= records {
+                return None;
+            }
+            params.limit = Some((records - streamed).min(NEWLINES_PAGE_SIZE) as u32);
+            params.offset = offset;
+            match read_newlines_page(&*pool, lock, params).await {
+                // The BSOs left were deleted since they were counted
+                Ok((0, _, _)) => None,
+                Ok((len, page, next)) => {
+                    let next = next.and_then(|next| next.parse().ok());
+                    let state = next.map(|next| (streamed + len as u64, Some(next)));
+                    Some((Ok(Bytes::from(page)), state))
+                }
+                Err(e) => {
+                    warn!("⚠️ Couldn't stream a collection's BSOs: {:?}", e);
+                    Some((Err(ApiError::from(e)), None))
+                }
+            }
+        }
+    });
+    Ok(HttpResponse::build(StatusCode::OK)
+        .header(X_LAST_MODIFIED, ts.as_header())
+        .header(X_WEAVE_RECORDS, records.to_string())
+        .header("Content-Type", "application/newlines")
+        .streaming(Box::pin(pages)))
+}
+
+/// Read a page of a collection GET's BSOs in a transaction of its own,
+/// returning their number, their newline-delimited JSON and the next page's
+/// offset
+async fn read_newlines_page(
+    pool: &dyn DbPool<Error = DbError>,
+    lock: params::LockCollection,
+    params: params::GetBsos,
+) -> Result<(usize, String, Option<String>), DbError> {
+    let db = pool.get().await?;
+    db.lock_for_read(lock).await?;
+    let result = if params.full {
+        let projection = params.projection;
+        db.get_bsos(params).await.map(|page| {
+            let items = page
+                .items
+                .into_iter()
+                .map(|bso| Bso::projected(bso, projection));
+            (items.len(), to_newlines(items), page.offset)
+        })
+    } else {
+        db.get_bso_ids(params)
+            .await
+            .map(|page| (page.items.len(), to_newlines(page.items), page.offset))
+    };
+    match result {
+        Ok(page) => {
+            db.commit().await?;
+            Ok(page)
+        }
+        Err(e) => {
+            db.rollback().await?;
+            Err(e)
+        }
+    }
+}
+
+/// Serialize items as newline-delimited JSON, skipping those that can't be
+fn to_newlines<T: Serialize>(items: impl IntoIterator<Item = T>) -> String {
+    items
+        .into_iter()
+        .map(|v| serde_json::to_string(&v).unwrap_or_else(|_| "".to_string()))
+        .filter(|v| !v.is_empty())
+        .map(|v| v.replace('\n', "\\u000a") + "\n")
+        .collect()
+}
+
 async fn finish_get_collection<T>(
     coll: &CollectionRequest,
+    request: &HttpRequest,
     db: Box<dyn Db<Error = DbError>>,
     result: Result<Paginated<T>, DbError>,
 ) -> Result<HttpResponse, DbError>
@@ -219,23 +479,19 @@ where
         resp.header(X_WEAVE_NEXT_OFFSET, offset);
     }
 
-    match coll.reply {
-        ReplyFormat::Json => Ok(resp.json(result.items)),
+    let start = Instant::now();
+    let response = match coll.reply {
+        ReplyFormat::Json => resp.json(result.items),
         ReplyFormat::Newlines => {
-            let items: String = result
-                .items
-                .into_iter()
-                .map(|v| serde_json::to_string(&v).unwrap_or_else(|_| "".to_string()))
-                .filter(|v| !v.is_empty())
-                .map(|v| v.replace('\n', "\\u000a") + "\n")
-                .collect();
+            let items = to_newlines(result.items);
 
-            Ok(resp
-                .header("Content-Type", "application/newlines")
+            resp.header("Content-Type", "application/newlines")
                 .header("Content-Length", format!("{}", items.len()))
-                .body(items))
+                .body(items)
         }
-    }
+    };
+    RequestTrace::record(request, "serialization", start.elapsed());
+    Ok(response)
 }
 
 pub async fn post_collection(
@@ -260,17 +516,43 @@ pub async fn post_collection(
                 }
             }
 
+            let payload_sizes: HashMap<_, _> = coll
+                .bsos
+                .valid
+                .iter()
+                .map(|bso| (bso.id.clone(), payload_size(&bso.payload)))
+                .collect();
             let result = db
                 .post_bsos(params::PostBsos {
-                    user_id: coll.user_id,
-                    collection: coll.collection,
+                    user_id: coll.user_id.clone(),
+                    collection: coll.collection.clone(),
                     bsos: coll.bsos.valid.into_iter().map(From::from).collect(),
                     for_batch: false,
                     failed: coll.bsos.invalid,
                 })
                 .await?;
+            record_collection_writes(
+                &coll.metrics,
+                &coll.collection,
+                "post",
+                result.success.len(),
+                result
+                    .success
+                    .iter()
+                    .filter_map(|id| payload_sizes.get(id))
+                    .sum(),
+            );
 
-            Ok(HttpResponse::build(StatusCode::OK)
+            let mut builder = HttpResponse::build(StatusCode::OK);
+            add_quota_warning(
+                &mut builder,
+                db.as_ref(),
+                coll.quota_warning,
+                &coll.user_id,
+                &coll.collection,
+            )
+            .await?;
+            Ok(builder
                 .header(X_LAST_MODIFIED, result.modified.as_header())
                 .json(result))
         })
@@ -339,15 +621,32 @@ pub async fn post_collection_batch(
     let mut success = vec![];
     let mut failed = coll.bsos.invalid;
     let bso_ids: Vec<_> = coll.bsos.valid.iter().map(|bso| bso.id.clone()).collect();
+    let bso_bytes: usize = coll
+        .bsos
+        .valid
+        .iter()
+        .map(|bso| payload_size(&bso.payload))
+        .sum();
 
     let mut resp: Value = json!({});
 
     macro_rules! handle_result {
         // collect up the successful and failed bso_ids into a response.
-        ( $r: expr) => {
+        ( $r: expr, $op: expr) => {
             match $r {
-                Ok(_) => success.extend(bso_ids.clone()),
-                Err(e) if e.is_conflict() || e.is_quota() => return Err(e.into()),
+                Ok(_) => {
+                    record_collection_writes(
+                        &coll.metrics,
+                        &collection,
+                        $op,
+                        bso_ids.len(),
+                        bso_bytes,
+                    );
+                    success.extend(bso_ids.clone())
+                }
+                Err(e) if e.is_conflict() || e.is_lock_timeout() || e.is_quota() => {
+                    return Err(e.into())
+                }
                 _ => failed.extend(
                     bso_ids
                         .clone()
@@ -360,6 +659,7 @@ pub async fn post_collection_batch(
 
     // If we're not committing the current set of records yet.
     if !breq.commit {
+        let mut totals = None;
         // and there are bsos included in this message.
         if !coll.bsos.valid.is_empty() {
             // Append the data to the requested batch.
@@ -373,8 +673,21 @@ pub async fn post_collection_batch(
                 })
                 .await
             };
-            handle_result!(result);
+            totals = result.as_ref().ok().copied();
+            handle_result!(result, "batch_append");
         }
+        let totals = match totals {
+            Some(totals) => totals,
+            None => db
+                .get_batch(params::GetBatch {
+                    user_id: user_id.clone(),
+                    collection: collection.clone(),
+                    id: new_batch.id.clone(),
+                })
+                .await?
+                .map(|batch| batch.totals)
+                .unwrap_or_default(),
+        };
 
         // Return the batch append response without committing the current
         // batch to the BSO table.
@@ -382,7 +695,10 @@ pub async fn post_collection_batch(
         resp["failed"] = json!(failed);
 
         resp["batch"] = json!(&new_batch.id);
-        return Ok(HttpResponse::Accepted().json(resp));
+        return Ok(HttpResponse::Accepted()
+            .header(X_WEAVE_TOTAL_RECORDS, totals.records.to_string())
+            .header(X_WEAVE_TOTAL_BYTES, totals.bytes.to_string())
+            .json(resp));
     }
 
     // We've been asked to commit the accumulated data, so get to it!
@@ -398,7 +714,9 @@ pub async fn post_collection_batch(
     // (max_total_records, max_total_bytes)
     //
     // First, write the pending batch BSO data into the BSO table.
+    let mut totals = results::BatchTotals::default();
     let modified = if let Some(batch) = batch {
+        totals = batch.totals;
         db.commit_batch(params::CommitBatch {
             user_id: user_id.clone(),
             collection: collection.clone(),
@@ -438,7 +756,11 @@ pub async fn post_collection_batch(
             .await
             .map(|_| ());
 
-        handle_result!(result);
+        if result.is_ok() {
+            totals.records += bso_ids.len() as u64;
+            totals.bytes += bso_bytes as u64;
+        }
+        handle_result!(result, "batch_commit");
     }
 
     // Always return success, failed, & modified
@@ -446,8 +768,19 @@ pub async fn post_collection_batch(
     resp["failed"] = json!(failed);
     resp["modified"] = json!(modified);
     trace!("Batch: Returning result: {}", &resp);
-    Ok(HttpResponse::build(StatusCode::OK)
+    let mut builder = HttpResponse::build(StatusCode::OK);
+    add_quota_warning(
+        &mut builder,
+        db.as_ref(),
+        coll.quota_warning,
+        &user_id,
+        &collection,
+    )
+    .await?;
+    Ok(builder
         .header(X_LAST_MODIFIED, modified.as_header())
+        .header(X_WEAVE_TOTAL_RECORDS, totals.records.to_string())
+        .header(X_WEAVE_TOTAL_BYTES, totals.bytes.to_string())
         .json(resp))
 }
 
@@ -476,21 +809,34 @@ pub async fn get_bso(
     db_pool: DbTransactionPool,
     request: HttpRequest,
 ) -> Result<HttpResponse, ApiError> {
+    let expired_bso_queue = request
+        .app_data::<Data<ServerState>>()
+        .and_then(|state| state.expired_bso_queue.clone());
     db_pool
         .transaction_http(request, |db| async move {
             bso_req.emit_api_metric("request.get_bso");
             let result = db
                 .get_bso(params::GetBso {
-                    user_id: bso_req.user_id,
-                    collection: bso_req.collection,
-                    id: bso_req.bso,
+                    user_id: bso_req.user_id.clone(),
+                    collection: bso_req.collection.clone(),
+                    id: bso_req.bso.clone(),
                 })
                 .await?;
 
-            Ok(result.map_or_else(
-                || HttpResponse::NotFound().finish(),
-                |bso| HttpResponse::Ok().json(bso),
-            ))
+            Ok(match result {
+                Some(bso) => HttpResponse::Ok().json(Bso::from(bso)),
+                None => {
+                    // It may have expired: purge it if so
+                    if let Some(queue) = expired_bso_queue {
+                        queue.push(params::PurgeExpiredBso {
+                            user_id: bso_req.user_id,
+                            collection: bso_req.collection,
+                            id: bso_req.bso,
+                        });
+                    }
+                    HttpResponse::NotFound().finish()
+                }
+            })
         })
         .await
 }
@@ -503,24 +849,106 @@ pub async fn put_bso(
     db_pool
         .transaction_http(request, |db| async move {
             bso_req.emit_api_metric("request.put_bso");
+            let bytes = payload_size(&bso_req.body.payload);
             let result = db
                 .put_bso(params::PutBso {
-                    user_id: bso_req.user_id,
-                    collection: bso_req.collection,
+                    user_id: bso_req.user_id.clone(),
+                    collection: bso_req.collection.clone(),
                     id: bso_req.bso,
                     sortindex: bso_req.body.sortindex,
                     payload: bso_req.body.payload,
                     ttl: bso_req.body.ttl,
                 })
                 .await?;
+            record_collection_writes(&bso_req.metrics, &bso_req.collection, "put", 1, bytes);
 
-            Ok(HttpResponse::build(StatusCode::OK)
+            let mut builder = HttpResponse::build(StatusCode::OK);
+            add_quota_warning(
+                &mut builder,
+                db.as_ref(),
+                bso_req.quota_warning,
+                &bso_req.user_id,
+                &bso_req.collection,
+            )
+            .await?;
+            Ok(builder
                 .header(X_LAST_MODIFIED, result.as_header())
                 .json(result))
         })
         .await
 }
 
+fn payload_size(payload: &Option<String>) -> usize {
+    payload.as_ref().map_or(0, String::len)
+}
+
+/// Count BSOs written to a collection (and their payloads' size), so the
+/// collections driving the database's write load stand out. `op` is the kind
+/// of write (`put`, `post`, `batch_append` or `batch_commit`)
+fn record_collection_writes(
+    metrics: &Metrics,
+    collection: &str,
+    op: &str,
+    rows: usize,
+    bytes: usize,
+) {
+    let mut tags = HashMap::default();
+    tags.insert(
+        "collection".to_owned(),
+        collection_tag(collection).to_owned(),
+    );
+    tags.insert("op".to_owned(), op.to_owned());
+    metrics.count_with_tags("storage.collection.rows_written", rows as i64, tags.clone());
+    metrics.count_with_tags("storage.collection.bytes_written", bytes as i64, tags);
+}
+
+/// Warn the client via response headers once a write leaves the collection's
+/// usage past the soft quota limit
+async fn add_quota_warning(
+    builder: &mut HttpResponseBuilder,
+    db: &dyn Db<Error = DbError>,
+    quota_warning: Option<QuotaWarning>,
+    user_id: &UserIdentifier,
+    collection: &str,
+) -> Result<(), DbError> {
+    let quota_warning = match quota_warning {
+        Some(quota_warning) => quota_warning,
+        None => return Ok(()),
+    };
+    // Usually computed by the write already
+    let usage = match db.written_quota_usage(user_id, collection) {
+        Some(usage) => usage,
+        None => {
+            let collection_id = db.get_collection_id(collection.to_owned()).await?;
+            db.get_quota_usage(params::GetQuotaUsage {
+                user_id: user_id.clone(),
+                collection: collection.to_owned(),
+                collection_id,
+            })
+            .await?
+        }
+    };
+    if usage.total_bytes < quota_warning.soft_limit {
+        return Ok(());
+    }
+
+    let remaining = quota_warning.hard_limit.saturating_sub(usage.total_bytes);
+    builder
+        .header(
+            X_WEAVE_QUOTA_REMAINING,
+            format!("{:.2}", remaining as f64 / ONE_KB),
+        )
+        .header(
+            X_WEAVE_ALERT,
+            json!({
+                "code": "quota-warning",
+                "message": format!("Collection {} is nearing its storage quota", collection),
+            })
+            .to_string(),
+        );
+    Ok(())
+}
+
 pub fn get_configuration(state: Data<ServerState>) -> HttpResponse {
     // With no DbConnection (via a `transaction_http` call) needed here, we
     // miss out on a couple things it does:
@@ -536,6 +964,8 @@ pub fn get_configuration(state: Data<ServerState>) -> HttpResponse {
 
 /** Returns a status message indicating the state of the current server
  *
+ * The database is checked with a trivial query on a pooled connection, and
+ * each pool's connections and checkout stats are reported under `pools`.
  */
 pub async fn heartbeat(hb: HeartbeatRequest) -> Result<HttpResponse, ApiError> {
     let mut checklist = HashMap::new();
@@ -543,10 +973,22 @@ pub async fn heartbeat(hb: HeartbeatRequest) -> Result<HttpResponse, ApiError> {
         "version".to_owned(),
         Value::String(env!("CARGO_PKG_VERSION").to_owned()),
     );
-    let db = hb.db_pool.get().await?;
-
     checklist.insert("quota".to_owned(), serde_json::to_value(hb.quota)?);
 
+    let mut pools = serde_json::Map::new();
+    pools.insert("syncstorage".to_owned(), pool_stats(hb.db_pool.state()));
+    if let Some(state) = hb.tokenserver_pool {
+        pools.insert("tokenserver".to_owned(), pool_stats(state));
+    }
+    checklist.insert("pools".to_owned(), Value::Object(pools));
+
+    if !hb.db_pool.is_initialized() {
+        checklist.insert("status".to_owned(), Value::from("Err"));
+        checklist.insert("database".to_owned(), Value::from("Initializing"));
+        return Ok(HttpResponse::ServiceUnavailable().json(checklist));
+    }
+    let db = hb.db_pool.get().await?;
+
     match db.check().await {
         Ok(result) => {
             if result {
@@ -572,6 +1014,22 @@ pub async fn heartbeat(hb: HeartbeatRequest) -> Result<HttpResponse, ApiError> {
     }
 }
 
+/// A pool's connections and checkouts as reported by the heartbeat
+fn pool_stats(state: PoolState) -> Value {
+    let average_checkout_wait_ms = if state.checkouts == 0 {
+        0.0
+    } else {
+        state.checkout_wait.as_secs_f64() * 1000.0 / state.checkouts as f64
+    };
+    json!({
+        "active_connections": state.connections.saturating_sub(state.idle_connections),
+        "idle_connections": state.idle_connections,
+        "checkouts": state.checkouts,
+        "checkout_failures": state.checkout_failures,
+        "average_checkout_wait_ms": average_checkout_wait_ms,
+    })
+}
+
 pub async fn lbheartbeat(req: HttpRequest) -> Result<HttpResponse, ApiError> {
     let mut resp: HashMap<String, Value> = HashMap::new();
 
@@ -591,10 +1049,15 @@ pub async fn lbheartbeat(req: HttpRequest) -> Result<HttpResponse, ApiError> {
         return Ok(HttpResponseBuilder::new(StatusCode::INTERNAL_SERVER_ERROR).json(resp));
     }
 
+    if !state.db_pool.is_initialized() {
+        // Keep traffic away until the (lazily initialized) db is reachable
+        resp.insert("database".to_owned(), Value::from("Initializing"));
+        return Ok(HttpResponseBuilder::new(StatusCode::SERVICE_UNAVAILABLE).json(resp));
+    }
+
     let db_state = if cfg!(test) {
         use actix_web::http::header::HeaderValue;
         use std::str::FromStr;
-        use syncstorage_db::PoolState;
 
         let test_pool = PoolState {
             connections: u32::from_str(
@@ -613,6 +1076,7 @@ pub async fn lbheartbeat(req: HttpRequest) -> Result<HttpResponse, ApiError> {
                     .unwrap_or("0"),
             )
             .unwrap_or_default(),
+            ..Default::default()
         };
         // dbg!(&test_pool, deadman.max_size);
         test_pool
@@ -651,6 +1115,260 @@ pub async fn lbheartbeat(req: HttpRequest) -> Result<HttpResponse, ApiError> {
     Ok(HttpResponseBuilder::new(status_code).json(json!(resp)))
 }
 
+#[derive(Debug, Deserialize, Serialize)]
+pub struct BackoffBroadcast {
+    /// Percentage (0 - 100) of users sent an `X-Weave-Backoff`
+    percentage: u8,
+}
+
+pub async fn get_backoff_broadcast(req: HttpRequest) -> Result<HttpResponse, ApiError> {
+    let state = match admin_state(&req) {
+        Ok(state) => state,
+        Err(resp) => return Ok(resp),
+    };
+    Ok(HttpResponse::Ok().json(BackoffBroadcast {
+        percentage: state.overload.broadcast_percentage(),
+    }))
+}
+
+pub async fn put_backoff_broadcast(
+    req: HttpRequest,
+    body: Json<BackoffBroadcast>,
+) -> Result<HttpResponse, ApiError> {
+    let state = match admin_state(&req) {
+        Ok(state) => state,
+        Err(resp) => return Ok(resp),
+    };
+    if body.percentage > 100 {
+        return Ok(HttpResponse::BadRequest().json(json!({
+            "error": "percentage must be between 0 and 100"
+        })));
+    }
+    let previous = state.overload.broadcast_percentage();
+    state.overload.set_broadcast_percentage(body.percentage);
+    warn!(
+        "Backoff broadcast changed from {}% to {}% of users",
+        previous, body.percentage
+    );
+    Ok(HttpResponse::Ok().json(BackoffBroadcast {
+        percentage: body.percentage,
+    }))
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct LogLevel {
+    /// The log filter, in `RUST_LOG` syntax (e.g.
+    /// `warn,syncserver::web=debug`)
+    filter: String,
+}
+
+pub async fn get_log_level(req: HttpRequest) -> Result<HttpResponse, ApiError> {
+    if let Err(resp) = admin_state(&req) {
+        return Ok(resp);
+    }
+    Ok(HttpResponse::Ok().json(LogLevel {
+        filter: logging::log_filter().unwrap_or_default(),
+    }))
+}
+
+/// Change the log level (globally and per module) without a restart, e.g.
+/// to debug an issue while its state lasts
+pub async fn put_log_level(
+    req: HttpRequest,
+    body: Json<LogLevel>,
+) -> Result<HttpResponse, ApiError> {
+    if let Err(resp) = admin_state(&req) {
+        return Ok(resp);
+    }
+    let previous = logging::log_filter().unwrap_or_default();
+    if let Err(e) = logging::set_log_filter(&body.filter) {
+        return Ok(HttpResponse::BadRequest().json(json!({ "error": e })));
+    }
+    let filter = body.into_inner().filter.trim().to_owned();
+    warn!("Log filter changed from {:?} to {:?}", previous, filter);
+    Ok(HttpResponse::Ok().json(LogLevel { filter }))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteCollectionsBody {
+    #[serde(default)]
+    legacy_id: u64,
+    #[serde(default)]
+    fxa_uid: String,
+    #[serde(default)]
+    fxa_kid: String,
+    /// The user's tenant, the default one when unset
+    #[serde(default)]
+    tenant: Option<String>,
+    collections: Vec<String>,
+}
+
+/// Delete several of a user's collections in a single transaction (e.g.
+/// clients, crypto and meta to force a resync)
+pub async fn post_delete_collections(
+    req: HttpRequest,
+    body: Json<DeleteCollectionsBody>,
+) -> Result<HttpResponse, ApiError> {
+    let state = match admin_state(&req) {
+        Ok(state) => state,
+        Err(resp) => return Ok(resp),
+    };
+    let body = body.into_inner();
+    if body.collections.is_empty() {
+        return Ok(HttpResponse::BadRequest().json(json!({
+            "error": "collections must not be empty"
+        })));
+    }
+    let user_id = UserIdentifier {
+        legacy_id: body.legacy_id,
+        fxa_uid: body.fxa_uid,
+        fxa_kid: body.fxa_kid,
+    };
+    let user_id = match scope_to_tenant(state, user_id, body.tenant.as_deref()) {
+        Ok(user_id) => user_id,
+        Err(resp) => return Ok(resp),
+    };
+
+    let db = state.db_pool.get().await?;
+    db.begin(true).await?;
+    let result = match db
+        .delete_collections(params::DeleteCollections {
+            user_id: user_id.clone(),
+            collections: body.collections,
+        })
+        .await
+    {
+        Ok(result) => result,
+        Err(e) => {
+            db.rollback().await?;
+            return Err(e.into());
+        }
+    };
+    db.commit().await?;
+
+    let deleted: Vec<_> = result
+        .collections
+        .iter()
+        .filter(|(_, deleted)| **deleted)
+        .map(|(collection, _)| collection.as_str())
+        .collect();
+    warn!(
+        "Deleted collections";
+        "uid" => hash_user_id(&user_id),
+        "collections" => deleted.join(",")
+    );
+    Ok(HttpResponse::Ok().json(result))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SoftResetBody {
+    #[serde(default)]
+    legacy_id: u64,
+    #[serde(default)]
+    fxa_uid: String,
+    #[serde(default)]
+    fxa_kid: String,
+    /// The user's tenant, the default one when unset
+    #[serde(default)]
+    tenant: Option<String>,
+    /// The collections to keep, meta and crypto by default
+    #[serde(default = "soft_reset_keep")]
+    keep: Vec<String>,
+}
+
+fn soft_reset_keep() -> Vec<String> {
+    vec!["meta".to_owned(), "crypto".to_owned()]
+}
+
+/// Delete all of a user's collections except a protected set (a "soft
+/// reset"): keeping meta and crypto, clients re-upload their data under the
+/// same keys rather than starting over
+pub async fn post_soft_reset(
+    req: HttpRequest,
+    body: Json<SoftResetBody>,
+) -> Result<HttpResponse, ApiError> {
+    let state = match admin_state(&req) {
+        Ok(state) => state,
+        Err(resp) => return Ok(resp),
+    };
+    let body = body.into_inner();
+    let user_id = UserIdentifier {
+        legacy_id: body.legacy_id,
+        fxa_uid: body.fxa_uid,
+        fxa_kid: body.fxa_kid,
+    };
+    let user_id = match scope_to_tenant(state, user_id, body.tenant.as_deref()) {
+        Ok(user_id) => user_id,
+        Err(resp) => return Ok(resp),
+    };
+
+    let db = state.db_pool.get().await?;
+    db.begin(true).await?;
+    let result = match db
+        .delete_collections_except(params::DeleteCollectionsExcept {
+            user_id: user_id.clone(),
+            keep: body.keep.clone(),
+        })
+        .await
+    {
+        Ok(result) => result,
+        Err(e) => {
+            db.rollback().await?;
+            return Err(e.into());
+        }
+    };
+    db.commit().await?;
+
+    let deleted: Vec<_> = result.collections.keys().map(String::as_str).collect();
+    warn!(
+        "Soft reset the user's storage";
+        "uid" => hash_user_id(&user_id),
+        "kept" => body.keep.join(","),
+        "collections" => deleted.join(",")
+    );
+    Ok(HttpResponse::Ok().json(result))
+}
+
+/// The server state of an admin request bearing the admin token, or the
+/// response rejecting it. Admin endpoints don't exist without a token
+/// configured
+fn admin_state(req: &HttpRequest) -> Result<&Data<ServerState>, HttpResponse> {
+    let state = req
+        .app_data::<Data<ServerState>>()
+        .ok_or_else(|| HttpResponse::InternalServerError().finish())?;
+    let admin_token = state
+        .admin_token
+        .as_deref()
+        .ok_or_else(|| HttpResponse::NotFound().finish())?;
+    let token = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    // Compare digests so the comparison's timing doesn't leak the token
+    if Sha256::digest(token.as_bytes()) != Sha256::digest(admin_token.as_bytes()) {
+        return Err(HttpResponse::Unauthorized().finish());
+    }
+    Ok(state)
+}
+
+/// Scope the user targeted by an admin endpoint to their tenant (see
+/// `ServerState::tenants`)
+fn scope_to_tenant(
+    state: &ServerState,
+    user_id: UserIdentifier,
+    tenant: Option<&str>,
+) -> Result<UserIdentifier, HttpResponse> {
+    let tenant_id = match tenant {
+        None => 0,
+        Some(tenant) => *state.tenants.get(tenant).ok_or_else(|| {
+            HttpResponse::BadRequest().json(json!({ "error": "unknown tenant" }))
+        })?,
+    };
+    Ok(user_id.scoped_to_tenant(tenant_id))
+}
+
 // try returning an API error
 pub async fn test_error(
     _req: HttpRequest,
@@ -0,0 +1,72 @@
+use std::future::Future;
+use std::sync::Arc;
+
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse},
+    http::header::{HeaderName, HeaderValue},
+    web::Data,
+};
+use syncserver_common::{Metrics, X_WEAVE_BACKOFF};
+
+use crate::{
+    server::ServerState,
+    web::{path_uid, DOCKER_FLOW_ENDPOINTS},
+};
+
+/// Middleware asking (some) clients to back off while the server's
+/// saturated, a backoff's being broadcast or they've been flagged as
+/// pathological clients, via an `X-Weave-Backoff` header on otherwise normal
+/// responses.
+pub fn set_overload_backoff(
+    request: ServiceRequest,
+    service: &mut impl Service<
+        Request = ServiceRequest,
+        Response = ServiceResponse,
+        Error = actix_web::Error,
+    >,
+) -> impl Future<Output = Result<ServiceResponse, actix_web::Error>> {
+    // Health checks are left alone
+    let state = if DOCKER_FLOW_ENDPOINTS.contains(&request.uri().path().to_lowercase().as_str()) {
+        None
+    } else {
+        request.app_data::<Data<ServerState>>().map(|state| {
+            (
+                Arc::clone(&state.overload),
+                Arc::clone(&state.abuse),
+                Arc::clone(&state.metrics),
+            )
+        })
+    };
+    let uid = path_uid(request.path()).map(str::to_owned);
+    let in_flight = state
+        .as_ref()
+        .map(|(overload, ..)| overload.start_request());
+    let fut = service.call(request);
+
+    Box::pin(async move {
+        let mut resp = fut.await?;
+        drop(in_flight);
+
+        let Some((overload, abuse, metrics)) = state else {
+            return Ok(resp);
+        };
+        // Don't override a backoff already set for the request
+        if resp.headers().contains_key(X_WEAVE_BACKOFF) {
+            return Ok(resp);
+        }
+        let backoff = match uid.as_deref().and_then(|uid| abuse.backoff(uid)) {
+            Some(seconds) => Some((seconds, "storage.abuse.backoff")),
+            None => overload
+                .backoff(uid.as_deref())
+                .map(|seconds| (seconds, "storage.overload.backoff")),
+        };
+        if let Some((seconds, label)) = backoff {
+            resp.headers_mut().insert(
+                HeaderName::from_static(X_WEAVE_BACKOFF),
+                HeaderValue::from(seconds),
+            );
+            Metrics::from(&metrics).incr(label);
+        }
+        Ok(resp)
+    })
+}
@@ -0,0 +1,82 @@
+use std::future::Future;
+
+use actix_web::{
+    dev::{Payload, Service, ServiceRequest, ServiceResponse},
+    error::{JsonPayloadError, PayloadError},
+    http::header::CONTENT_LENGTH,
+    web::Data,
+};
+use futures::{
+    future::{self, Either},
+    StreamExt,
+};
+
+use crate::label;
+use crate::server::ServerState;
+use crate::web::error::{RequestErrorLocation, ValidationErrorKind};
+
+/// The error of requests whose body exceeds `limits.max_request_bytes`
+pub fn body_too_large() -> ValidationErrorKind {
+    ValidationErrorKind::FromDetails(
+        "size-limit-exceeded".to_owned(),
+        RequestErrorLocation::Header,
+        Some("Content-Length".to_owned()),
+        label!("request.error.body_too_large"),
+    )
+}
+
+/// Whether extracting a request's body failed for exceeding
+/// `limits.max_request_bytes`
+pub fn is_body_too_large(error: &actix_web::Error) -> bool {
+    match error.as_error::<JsonPayloadError>() {
+        Some(JsonPayloadError::Overflow)
+        | Some(JsonPayloadError::Payload(PayloadError::Overflow)) => true,
+        _ => matches!(
+            error.as_error::<PayloadError>(),
+            Some(PayloadError::Overflow)
+        ),
+    }
+}
+
+/// Middleware rejecting request bodies larger than `limits.max_request_bytes`
+/// with a 413, regardless of the per record payload limits checked by the
+/// extractors.
+///
+/// Requests declaring a larger `Content-Length` are rejected before their
+/// body's read. Others have their body cut off as soon as it exceeds the
+/// limit, failing its extraction (see `is_body_too_large`), so an unbounded
+/// body is never buffered
+pub fn limit_request_body(
+    mut request: ServiceRequest,
+    service: &mut impl Service<
+        Request = ServiceRequest,
+        Response = ServiceResponse,
+        Error = actix_web::Error,
+    >,
+) -> impl Future<Output = Result<ServiceResponse, actix_web::Error>> {
+    let limit = match request.app_data::<Data<ServerState>>() {
+        Some(state) => state.limits.max_request_bytes as usize,
+        // Tokenserver only
+        None => return Either::Left(service.call(request)),
+    };
+    let content_length = request
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+    if content_length.map_or(false, |length| length > limit) {
+        return Either::Right(future::ok(request.error_response(body_too_large())));
+    }
+
+    let mut read = 0;
+    let payload = request.take_payload().map(move |chunk| {
+        let chunk = chunk?;
+        read += chunk.len();
+        if read > limit {
+            return Err(PayloadError::Overflow);
+        }
+        Ok(chunk)
+    });
+    request.set_payload(Payload::Stream(Box::pin(payload)));
+    Either::Left(service.call(request))
+}
@@ -1,5 +1,11 @@
+pub mod backoff;
+pub mod body_limit;
+pub mod query_budget;
 pub mod rejectua;
+pub mod request_id;
 pub mod sentry;
+pub mod slow_requests;
+pub mod transaction;
 pub mod weave;
 
 // # Web Middleware
@@ -11,13 +17,15 @@ use std::future::Future;
 
 use actix_web::{
     dev::{Service, ServiceRequest, ServiceResponse},
+    http::{header::USER_AGENT, StatusCode},
     web::Data,
 };
 use syncserver_common::Metrics;
+use syncstorage_db::collection_tag;
 use tokenserver_auth::TokenserverOrigin;
 
 use crate::error::{ApiError, ApiErrorKind};
-use crate::server::ServerState;
+use crate::server::{user_agent::parse_sync_client, ServerState};
 
 pub fn emit_http_status_with_tokenserver_origin(
     req: ServiceRequest,
@@ -58,6 +66,56 @@ pub fn emit_http_status_with_tokenserver_origin(
             metrics.incr_with_tags("http_5XX", tags);
         }
 
+        // Per client release counts, so changes in protocol behavior (e.g.
+        // a rise in 412s) can be traced back to the clients causing them
+        let user_agent = req
+            .headers()
+            .get(USER_AGENT)
+            .and_then(|ua| ua.to_str().ok())
+            .unwrap_or_default();
+        let client = parse_sync_client(user_agent);
+        let mut client_tags = HashMap::default();
+        client_tags.insert("ua.product".to_owned(), client.product.to_owned());
+        client_tags.insert("ua.version".to_owned(), client.version.to_owned());
+        client_tags.insert("ua.platform".to_owned(), client.platform.to_owned());
+        client_tags.insert("status".to_owned(), res.status().as_u16().to_string());
+        metrics.incr_with_tags("request.client", client_tags);
+
+        emit_precondition_failures(&metrics, &res);
+
         Ok(res)
     }
 }
+
+/// Count failed preconditions (412s) and conflicts (409s, and the 503s
+/// reporting a conflicting write) per route and collection. A rise in these
+/// is the earliest sign of a timestamp bug or of clients with broken clocks
+fn emit_precondition_failures(metrics: &Metrics, res: &ServiceResponse) {
+    let label = match res.status() {
+        StatusCode::PRECONDITION_FAILED => "request.precondition_failed",
+        StatusCode::CONFLICT => "request.conflict",
+        StatusCode::SERVICE_UNAVAILABLE
+            if res
+                .response()
+                .error()
+                .and_then(|e| e.as_error::<ApiError>())
+                .map_or(false, ApiError::is_conflict) =>
+        {
+            "request.conflict"
+        }
+        _ => return,
+    };
+    let req = res.request();
+    let mut tags = HashMap::default();
+    // The route's pattern rather than its path, which carries the uid
+    tags.insert("route".to_owned(), req.match_pattern().unwrap_or_default());
+    tags.insert("method".to_owned(), req.method().to_string());
+    tags.insert("status".to_owned(), res.status().as_u16().to_string());
+    if let Some(collection) = req.match_info().get("collection") {
+        tags.insert(
+            "collection".to_owned(),
+            collection_tag(collection).to_owned(),
+        );
+    }
+    metrics.incr_with_tags(label, tags);
+}
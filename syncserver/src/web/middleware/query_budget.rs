@@ -0,0 +1,60 @@
+use std::collections::HashMap;
+use std::future::Future;
+
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse},
+    web::Data,
+};
+use syncserver_common::Metrics;
+
+use crate::server::ServerState;
+use crate::web::transaction::query_count;
+
+/// Middleware counting the database statements issued by each request (as
+/// `storage.db.queries`, per route) and flagging those exceeding
+/// `database_query_budget`: a handler whose statements grow with its input
+/// (e.g. one write per BSO) shows up as a regression well before it's slow.
+///
+/// Wraps the `transaction` middleware, so the statements finishing the
+/// request's transaction are counted
+pub fn check_query_budget(
+    request: ServiceRequest,
+    service: &mut impl Service<
+        Request = ServiceRequest,
+        Response = ServiceResponse,
+        Error = actix_web::Error,
+    >,
+) -> impl Future<Output = Result<ServiceResponse, actix_web::Error>> {
+    let fut = service.call(request);
+
+    async move {
+        let resp = fut.await?;
+        let req = resp.request();
+        let (queries, state) = match (query_count(req), req.app_data::<Data<ServerState>>()) {
+            (Some(queries), Some(state)) => (queries, state),
+            // Requests without db access, or tokenserver's
+            _ => return Ok(resp),
+        };
+        let metrics = Metrics::from(&state.metrics);
+        // The route's pattern rather than its path, which carries the uid
+        let route = req.match_pattern().unwrap_or_default();
+        let mut tags = HashMap::default();
+        tags.insert("route".to_owned(), route.clone());
+        tags.insert("method".to_owned(), req.method().to_string());
+        metrics.count_with_tags("storage.db.queries", queries as i64, tags.clone());
+
+        if let Some(budget) = state.query_budget {
+            if queries > u64::from(budget) {
+                warn!(
+                    "🔁 Request exceeded its query budget: {} {} issued {} statements",
+                    req.method(), route, queries;
+                    "status" => resp.status().as_u16(),
+                    "queries" => queries,
+                    "budget" => budget,
+                );
+                metrics.incr_with_tags("storage.db.query_budget_exceeded", tags);
+            }
+        }
+        Ok(resp)
+    }
+}
@@ -0,0 +1,48 @@
+use std::future::Future;
+
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse},
+    http::header::{HeaderName, HeaderValue},
+    HttpMessage, HttpRequest,
+};
+use syncserver_common::X_REQUEST_ID;
+use uuid::Uuid;
+
+use crate::server::tags::Taggable;
+
+/// A random id correlating a request's log records (including those of its
+/// db calls) and Sentry events, returned to the client as `X-Request-Id`
+#[derive(Clone, Debug)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    /// The id assigned to the request, if `assign_request_id` wraps it
+    pub fn of(req: &HttpRequest) -> Option<String> {
+        req.extensions().get::<Self>().map(|id| id.0.clone())
+    }
+}
+
+/// Middleware assigning each request a `RequestId`. It should wrap all the
+/// others, so their log records can include it
+pub fn assign_request_id(
+    request: ServiceRequest,
+    service: &mut impl Service<
+        Request = ServiceRequest,
+        Response = ServiceResponse,
+        Error = actix_web::Error,
+    >,
+) -> impl Future<Output = Result<ServiceResponse, actix_web::Error>> {
+    let id = Uuid::new_v4().to_string();
+    request.add_extra("request_id".to_owned(), id.clone());
+    request.extensions_mut().insert(RequestId(id.clone()));
+    let fut = service.call(request);
+
+    async move {
+        let mut resp = fut.await?;
+        if let Ok(value) = HeaderValue::from_str(&id) {
+            resp.headers_mut()
+                .insert(HeaderName::from_static(X_REQUEST_ID), value);
+        }
+        Ok(resp)
+    }
+}
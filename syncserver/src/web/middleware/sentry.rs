@@ -1,6 +1,7 @@
 use std::collections::HashMap;
 use std::error::Error as StdError;
 use std::future::Future;
+use std::sync::Arc;
 
 use actix_http::HttpMessage;
 use actix_web::{
@@ -8,7 +9,7 @@ use actix_web::{
     http::header::USER_AGENT,
     FromRequest,
 };
-use sentry::protocol::Event;
+use sentry::{protocol::Event, Hub, SentryFutureExt};
 use sentry_backtrace::parse_stacktrace;
 use serde_json::value::Value;
 use syncserver_common::{Metrics, ReportableError};
@@ -16,6 +17,7 @@ use tokenserver_common::TokenserverError;
 
 use crate::error::ApiError;
 use crate::server::{tags::Taggable, user_agent, MetricsWrapper};
+use crate::web::safe_path;
 
 pub fn report(
     tags: HashMap<String, String>,
@@ -41,9 +43,12 @@ pub fn report_error(
     add_initial_tags(&request, request.head().method.to_string());
     add_initial_extras(&request, request.head().uri.to_string());
 
-    let fut = service.call(request);
+    // Each request gets its own `Hub`, so the breadcrumbs it records (see
+    // `logging::SentryBreadcrumbs`) are only attached to its own events
+    let hub = Arc::new(Hub::new_from_top(Hub::main()));
+    let fut = Hub::run(hub.clone(), || service.call(request));
 
-    Box::pin(async move {
+    let reported = async move {
         let mut sresp = fut.await?;
         let tags = sresp.request().get_tags();
         let extras = sresp.request().get_extras();
@@ -84,7 +89,8 @@ pub fn report_error(
             }
         }
         Ok(sresp)
-    })
+    };
+    Box::pin(reported.bind_hub(hub))
 }
 
 fn process_error<E>(
@@ -183,7 +189,7 @@ where
         }
     }
 
-    msg.add_extra("uri.path".to_owned(), uri);
+    msg.add_extra("uri.path".to_owned(), safe_path(&uri));
 }
 
 #[cfg(test)]
@@ -219,7 +225,7 @@ mod tests {
         extras.insert("ua.name".to_owned(), "Firefox".to_owned());
         extras.insert("ua.browser.family".to_owned(), "Firefox".to_owned());
         extras.insert("ua".to_owned(), ua.to_owned());
-        extras.insert("uri.path".to_owned(), uri);
+        extras.insert("uri.path".to_owned(), safe_path(&uri));
 
         for extra in extras.clone() {
             req.add_extra(extra.0.clone(), extra.1.clone())
@@ -0,0 +1,91 @@
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse},
+    web::Data,
+    HttpMessage, HttpRequest,
+};
+use slog::{Key, Record, Serializer, KV};
+
+use super::request_id::RequestId;
+use crate::server::ServerState;
+
+/// Time spent by a request in each of its phases (e.g. `auth`, `lock`,
+/// `queries`), recorded while slow request tracing's enabled
+#[derive(Debug, Default)]
+pub struct RequestTrace {
+    phases: Vec<(&'static str, Duration)>,
+}
+
+impl RequestTrace {
+    /// Add to the time the request spent in a phase. A noop unless slow
+    /// request tracing's enabled
+    pub fn record(req: &HttpRequest, phase: &'static str, duration: Duration) {
+        if let Some(trace) = req.extensions_mut().get_mut::<Self>() {
+            match trace.phases.iter_mut().find(|(name, _)| *name == phase) {
+                Some((_, total)) => *total += duration,
+                None => trace.phases.push((phase, duration)),
+            }
+        }
+    }
+}
+
+impl KV for RequestTrace {
+    fn serialize(&self, _record: &Record<'_>, serializer: &mut dyn Serializer) -> slog::Result {
+        for (phase, duration) in &self.phases {
+            serializer.emit_u64(
+                Key::from(format!("{}_ms", phase)),
+                duration.as_millis() as u64,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Middleware logging the phase timings of requests exceeding the latency
+/// budget (`slow_request_threshold_ms`) at WARN, so tail latency can be
+/// diagnosed without tracing every request.
+///
+/// Phases are timed where they happen (see `RequestTrace::record`), so
+/// nested phases overlap: `serialization` is part of `queries`, the time
+/// spent by the handler within its transaction
+pub fn trace_slow_requests(
+    request: ServiceRequest,
+    service: &mut impl Service<
+        Request = ServiceRequest,
+        Response = ServiceResponse,
+        Error = actix_web::Error,
+    >,
+) -> impl Future<Output = Result<ServiceResponse, actix_web::Error>> {
+    let threshold = request
+        .app_data::<Data<ServerState>>()
+        .and_then(|state| state.slow_request_threshold);
+    if threshold.is_some() {
+        request.extensions_mut().insert(RequestTrace::default());
+    }
+    let start = Instant::now();
+    let fut = service.call(request);
+
+    async move {
+        let resp = fut.await?;
+        let elapsed = start.elapsed();
+        if matches!(threshold, Some(threshold) if elapsed >= threshold) {
+            let req = resp.request();
+            let trace = req
+                .extensions_mut()
+                .remove::<RequestTrace>()
+                .unwrap_or_default();
+            // The route's pattern rather than its path, which carries the uid
+            let route = req.match_pattern().unwrap_or_default();
+            warn!(
+                "🐢 Slow request: {} {} took {}ms", req.method(), route, elapsed.as_millis();
+                "status" => resp.status().as_u16(),
+                "request_id" => RequestId::of(req),
+                "total_ms" => elapsed.as_millis() as u64,
+                trace
+            );
+        }
+        Ok(resp)
+    }
+}
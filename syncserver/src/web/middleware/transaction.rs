@@ -0,0 +1,36 @@
+use std::future::Future;
+use std::time::Instant;
+
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+
+use super::slow_requests::RequestTrace;
+use crate::web::transaction::finish_transaction;
+
+/// Middleware finishing the request's db transaction: committed only for
+/// successful (2xx) responses, so handlers failing partway through can't
+/// leave partial writes (e.g. bumped collection timestamps) behind.
+/// Transactions of requests dropped before their response are rolled back
+/// instead (see `PinnedDb`).
+pub fn commit_successful(
+    request: ServiceRequest,
+    service: &mut impl Service<
+        Request = ServiceRequest,
+        Response = ServiceResponse,
+        Error = actix_web::Error,
+    >,
+) -> impl Future<Output = Result<ServiceResponse, actix_web::Error>> {
+    let http_request = request.request().clone();
+    let fut = service.call(request);
+
+    async move {
+        let result = fut.await;
+        let commit = matches!(
+            &result,
+            Ok(resp) if resp.status().is_success() && resp.response().error().is_none()
+        );
+        let start = Instant::now();
+        finish_transaction(&http_request, commit).await?;
+        RequestTrace::record(&http_request, "commit", start.elapsed());
+        result
+    }
+}
@@ -4,7 +4,12 @@ pub mod error;
 pub mod extractors;
 pub mod handlers;
 pub mod middleware;
-mod transaction;
+pub mod transaction;
+pub mod wire;
+
+use syncserver_common::SafeUid;
+
+use crate::server::SYNC_VERSION_PATH;
 
 // Known DockerFlow commands for Ops callbacks
 pub const DOCKER_FLOW_ENDPOINTS: [&str; 4] = [
@@ -14,9 +19,64 @@ pub const DOCKER_FLOW_ENDPOINTS: [&str; 4] = [
     "/__error__",
 ];
 
+/// A storage request path from its "/1.5/" on, past the path the storage
+/// API's mounted under, if any (see `server::syncstorage_scope`)
+pub fn sync_path(path: &str) -> Option<&str> {
+    let start = path.find(&format!("/{}/", SYNC_VERSION_PATH))?;
+    Some(&path[start..])
+}
+
+/// The uid of a storage request path ("/1.5/{uid}/...")
+pub fn path_uid(path: &str) -> Option<&str> {
+    sync_path(path)?
+        .split('/')
+        .nth(2)
+        .filter(|uid| !uid.is_empty())
+}
+
+/// A request path with its uid ("/1.5/{uid}/...") replaced by its `SafeUid`,
+/// for logs and error reports
+pub fn safe_path(path: &str) -> String {
+    let Some(sync_path) = sync_path(path) else {
+        return path.to_owned();
+    };
+    let mut elements: Vec<String> = sync_path.split('/').map(str::to_owned).collect();
+    if let Some(uid) = elements.get_mut(2).filter(|uid| !uid.is_empty()) {
+        *uid = SafeUid(uid.as_str()).to_string();
+    }
+    let prefix = &path[..path.len() - sync_path.len()];
+    format!("{}{}", prefix, elements.join("/"))
+}
+
 #[macro_export]
 macro_rules! label {
     ($string:expr) => {
         Some($string.to_string())
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_safe_path() {
+        let uid = "0123456789abcdef0123456789abcdef";
+        let path = safe_path(&format!("/1.5/{}/storage/bookmarks", uid));
+        assert_eq!(path, format!("/1.5/{}/storage/bookmarks", SafeUid(uid)));
+        assert_eq!(safe_path("/__heartbeat__"), "/__heartbeat__");
+        assert_eq!(safe_path("/1.0/sync/1.5"), "/1.0/sync/1.5");
+        let path = safe_path(&format!("/sync/1.5/{}/info/collections", uid));
+        assert_eq!(path, format!("/sync/1.5/{}/info/collections", SafeUid(uid)));
+    }
+
+    #[test]
+    fn test_path_uid() {
+        assert_eq!(path_uid("/1.5/123/info/collections"), Some("123"));
+        assert_eq!(path_uid("/1.5/123"), Some("123"));
+        assert_eq!(path_uid("/1.5/"), None);
+        assert_eq!(path_uid("/1.0/sync/1.5"), None);
+        assert_eq!(path_uid("/sync/1.5/123/storage/1.5"), Some("123"));
+        assert_eq!(path_uid("/__heartbeat__"), None);
+    }
+}
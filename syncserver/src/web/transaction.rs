@@ -1,33 +1,112 @@
+use std::cell::Cell;
 use std::future::Future;
+use std::sync::Arc;
+use std::time::Instant;
 
 use actix_http::http::{HeaderValue, Method, StatusCode};
 use actix_http::Error;
 use actix_web::dev::{Payload, PayloadStream};
 use actix_web::http::header;
-use actix_web::web::Data;
+use actix_web::web::{Data, Query};
 use actix_web::{FromRequest, HttpRequest, HttpResponse};
 use futures::future::LocalBoxFuture;
 use futures::FutureExt;
-use syncserver_common::X_LAST_MODIFIED;
-use syncstorage_db::{params, results::ConnectionInfo, Db, DbError, DbPool, UserIdentifier};
+use syncserver_common::{BulkPermit, Metrics, X_LAST_MODIFIED};
+use syncstorage_db::{
+    collection_tag, params,
+    results::{ConnectionInfo, LockedUser},
+    Db, DbError, DbPool, UserIdentifier,
+};
 
 use crate::error::{ApiError, ApiErrorKind};
 use crate::server::tags::Taggable;
-use crate::server::{MetricsWrapper, ServerState};
-use crate::web::extractors::{
-    BsoParam, CollectionParam, HawkIdentifier, PreConditionHeader, PreConditionHeaderOpt,
+use crate::server::{overload::Overload, MetricsWrapper, ServerState};
+use crate::tokenserver;
+use crate::web::{
+    error::HawkErrorKind,
+    extractors::{
+        BatchParams, BsoParam, CollectionParam, HawkIdentifier, PreConditionHeader,
+        PreConditionHeaderOpt,
+    },
+    middleware::{request_id::RequestId, slow_requests::RequestTrace},
 };
 
 #[derive(Clone)]
 pub struct DbTransactionPool {
     pool: Box<dyn DbPool<Error = DbError>>,
+    overload: Arc<Overload>,
+    metrics: Metrics,
     is_read: bool,
+    /// Whether the request makes bulk calls (batch uploads), only run under a
+    /// `DbPool::bulk_permit`
+    is_bulk: bool,
     user_id: UserIdentifier,
+    /// When the request's token was minted, in seconds
+    issued_at: Option<u64>,
+    /// When the keys of the request's token last changed, in milliseconds
+    keys_changed_at: Option<i64>,
+    /// When the keys of the user's current Tokenserver record last changed,
+    /// if the token was checked against it (see
+    /// `tokenserver::ServerState::check_storage_token`)
+    current_keys_changed_at: Option<i64>,
     collection: Option<String>,
     bso_opt: Option<String>,
     precondition: PreConditionHeaderOpt,
 }
 
+/// The `Db` checked out for a request, stored in its extensions
+struct PinnedDb {
+    db: Box<dyn Db<Error = DbError>>,
+    /// Whether its transaction awaits the response to be committed or rolled
+    /// back (see `finish_transaction`)
+    pending: Cell<bool>,
+}
+
+impl Drop for PinnedDb {
+    fn drop(&mut self) {
+        // The request was dropped before its response (e.g. it timed out or
+        // its handler panicked)
+        if self.pending.get() {
+            let db = self.db.clone();
+            actix_rt::spawn(async move {
+                if let Err(e) = db.rollback().await {
+                    warn!("⚠️ Couldn't roll back an abandoned transaction: {:?}", e);
+                }
+            });
+        }
+    }
+}
+
+/// The number of statements the request's issued to the database, if it's
+/// used one
+pub fn query_count(request: &HttpRequest) -> Option<u64> {
+    request
+        .extensions()
+        .get::<PinnedDb>()
+        .map(|pinned| pinned.db.query_count())
+}
+
+fn set_pending(request: &HttpRequest, pending: bool) {
+    if let Some(pinned) = request.extensions().get::<PinnedDb>() {
+        pinned.pending.set(pending);
+    }
+}
+
+/// Commit or roll back the request's pending transaction, if any, once its
+/// response is known
+pub async fn finish_transaction(request: &HttpRequest, commit: bool) -> Result<(), ApiError> {
+    let db = match request.extensions().get::<PinnedDb>() {
+        Some(pinned) if pinned.pending.replace(false) => pinned.db.clone(),
+        _ => return Ok(()),
+    };
+    if commit {
+        db.commit().await?;
+    } else {
+        db.rollback().await?;
+    }
+    Ok(())
+}
+
 fn set_extra(req: &HttpRequest, connection_info: ConnectionInfo) {
     req.add_extra("connection_age".to_owned(), connection_info.age.to_string());
     req.add_extra(
@@ -43,8 +122,8 @@ fn set_extra(req: &HttpRequest, connection_info: ConnectionInfo) {
 impl DbTransactionPool {
     /// Perform an action inside of a DB transaction. If the action fails, the
     /// transaction is rolled back. If the action succeeds, the transaction is
-    /// NOT committed. Further processing is required before we are sure the
-    /// action has succeeded (ex. check HTTP response for internal error).
+    /// NOT committed: it's left pending until we're sure the action has
+    /// succeeded (ex. check HTTP response for internal error).
     async fn transaction_internal<'a, A: 'a, R, F>(
         &'a self,
         request: HttpRequest,
@@ -54,11 +133,20 @@ impl DbTransactionPool {
         A: FnOnce(Box<dyn Db<Error = DbError>>) -> F,
         F: Future<Output = Result<R, ApiError>>,
     {
-        // Get connection from pool
-        let db = self.pool.get().await?;
+        // Taken before the connection and the collection lock: waiting for it
+        // while holding them would hold up the user's other requests. It's
+        // kept until the request is done with them
+        if self.is_bulk && request.extensions().get::<BulkPermit>().is_none() {
+            let start = Instant::now();
+            let permit = self.pool.bulk_permit().await;
+            RequestTrace::record(&request, "bulk_permit_wait", start.elapsed());
+            request.extensions_mut().insert(permit);
+        }
+        let db = self.get_db(&request).await?;
         let db2 = db.clone();
 
         // Lock for transaction
+        let start = Instant::now();
         let result = match (self.get_lock_collection(), self.is_read) {
             (Some(lc), true) => db.lock_for_read(lc).await,
             (Some(lc), false) => db.lock_for_write(lc).await,
@@ -70,22 +158,143 @@ impl DbTransactionPool {
             // Update the extra info fields.
             set_extra(&request, db.get_connection_info());
             db.rollback().await?;
-            return Err(e.into());
+            let e: ApiError = e.into();
+            self.record_conflict(&e);
+            return Err(e);
         }
+        // Usually read along with the write lock
+        let locked_user = db.locked_user(&self.user_id);
+        if let Err(e) = self.check_storage_epoch(&*db, locked_user.as_ref()).await {
+            db.rollback().await?;
+            return Err(e);
+        }
+        if let Err(e) = self.check_keys_changed_at(&*db, locked_user.as_ref()).await {
+            db.rollback().await?;
+            return Err(e);
+        }
+        RequestTrace::record(&request, "lock", start.elapsed());
+        set_pending(&request, true);
 
         // XXX: lock_for_x usually begins transactions but Dbs may also
         // implicitly create them, so commit/rollback are always called to
         // finish them. They noop when no implicit transaction was created
         // (maybe rename them to maybe_commit/rollback?)
-        match action(db).await {
+        let start = Instant::now();
+        let result = action(db).await;
+        RequestTrace::record(&request, "queries", start.elapsed());
+        match result {
             Ok(resp) => Ok((resp, db2)),
             Err(e) => {
+                set_pending(&request, false);
                 db2.rollback().await?;
+                self.record_conflict(&e);
                 Err(e)
             }
         }
     }
 
+    /// Count writes conflicting with another of the user's (e.g. failing to
+    /// lock the collection), per collection
+    fn record_conflict(&self, error: &ApiError) {
+        if let (Some(collection), false) = (&self.collection, self.is_read) {
+            if error.is_conflict() {
+                self.metrics.incr_with_tag(
+                    "storage.collection.conflicts",
+                    "collection",
+                    collection_tag(collection),
+                );
+            }
+        }
+    }
+
+    /// Reject writes from tokens minted before the user's storage was last
+    /// reset: their clients were offline across it and would otherwise
+    /// resurrect its data. The 401 has them fetch a new token and notice the
+    /// reset
+    async fn check_storage_epoch(
+        &self,
+        db: &dyn Db<Error = DbError>,
+        locked_user: Option<&LockedUser>,
+    ) -> Result<(), ApiError> {
+        let issued_at = match self.issued_at {
+            Some(issued_at) if !self.is_read => issued_at,
+            _ => return Ok(()),
+        };
+        let epoch = match locked_user {
+            Some(locked_user) => locked_user.storage_epoch,
+            None => db.get_storage_epoch(self.user_id.clone()).await?,
+        };
+        // Tokens only have second precision: those minted within the
+        // epoch's second are let through
+        if matches!(epoch, Some(epoch) if (issued_at as i64) < epoch.as_i64() / 1000) {
+            return Err(HawkErrorKind::StaleToken.into());
+        }
+        Ok(())
+    }
+
+    /// Reject requests with keys older than the user's current ones: the
+    /// client missed a change of keys (e.g. a password reset), and its data
+    /// would be unreadable to the user's other clients. The current keys are
+    /// those of the user's Tokenserver record, when the token was checked
+    /// against it, and for writes those the user's clients already wrote
+    /// with. Newer keys are recorded as they're first written with
+    async fn check_keys_changed_at(
+        &self,
+        db: &dyn Db<Error = DbError>,
+        locked_user: Option<&LockedUser>,
+    ) -> Result<(), ApiError> {
+        let keys_changed_at = match self.keys_changed_at {
+            Some(keys_changed_at) => keys_changed_at,
+            None => return Ok(()),
+        };
+        if matches!(self.current_keys_changed_at, Some(current) if keys_changed_at < current) {
+            return Err(HawkErrorKind::StaleKeys.into());
+        }
+        if self.is_read {
+            return Ok(());
+        }
+        let latest = match locked_user {
+            Some(locked_user) => locked_user.keys_changed_at,
+            None => db.get_keys_changed_at(self.user_id.clone()).await?,
+        };
+        match latest {
+            Some(latest) if keys_changed_at < latest => Err(HawkErrorKind::StaleKeys.into()),
+            Some(latest) if keys_changed_at == latest => Ok(()),
+            _ => {
+                db.record_keys_changed_at(params::RecordKeysChangedAt {
+                    user_id: self.user_id.clone(),
+                    keys_changed_at,
+                })
+                .await?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Get a connection from the pool, pinned to the request: every
+    /// transaction within the same request shares its connection/session
+    /// (and with it the session's timestamp and collection locks)
+    async fn get_db(
+        &self,
+        request: &HttpRequest,
+    ) -> Result<Box<dyn Db<Error = DbError>>, ApiError> {
+        if let Some(pinned) = request.extensions().get::<PinnedDb>() {
+            return Ok(pinned.db.clone());
+        }
+        let start = Instant::now();
+        let mut db = self.pool.get().await?;
+        self.overload.record_pool_wait(start.elapsed());
+        if let Some(request_id) = RequestId::of(request) {
+            db.set_request_id(request_id);
+        }
+        RequestTrace::record(request, "pool_wait", start.elapsed());
+        request.extensions_mut().insert(PinnedDb {
+            db: db.clone(),
+            pending: Cell::new(false),
+        });
+        Ok(db)
+    }
+
     pub fn get_pool(&self) -> Result<Box<dyn DbPool<Error = DbError>>, Error> {
         Ok(self.pool.clone())
     }
@@ -100,15 +309,18 @@ impl DbTransactionPool {
         A: FnOnce(Box<dyn Db<Error = DbError>>) -> F,
         F: Future<Output = Result<R, ApiError>> + 'a,
     {
-        let (resp, db) = self.transaction_internal(request, action).await?;
+        let (resp, db) = self.transaction_internal(request.clone(), action).await?;
 
         // No further processing before commit is possible
+        set_pending(&request, false);
+        let start = Instant::now();
         db.commit().await?;
+        RequestTrace::record(&request, "commit", start.elapsed());
         Ok(resp)
     }
 
-    /// Perform an action inside of a DB transaction. This method will rollback
-    /// if the HTTP response is an error.
+    /// Perform an action inside of a DB transaction, committed by the
+    /// `transaction` middleware only if the HTTP response is successful.
     pub async fn transaction_http<'a, A: 'a, F>(
         &'a self,
         request: HttpRequest,
@@ -172,16 +384,9 @@ impl DbTransactionPool {
             }
         };
 
-        let (resp, db) = self
+        let (resp, _) = self
             .transaction_internal(request.clone(), check_precondition)
             .await?;
-        // match on error and return a composed HttpResponse (so we can use the tags?)
-
-        // HttpResponse can contain an internal error
-        match resp.error() {
-            None => db.commit().await?,
-            Some(_) => db.rollback().await?,
-        };
         Ok(resp)
     }
 
@@ -240,19 +445,36 @@ impl FromRequest for DbTransactionPool {
                     return Err(e);
                 }
             };
+            // `Result::unwrap` is safe to use here, since Metrics::extract can never fail
+            let metrics = MetricsWrapper::extract(&req).await.unwrap().0;
             let method = req.method().clone();
             let user_id = HawkIdentifier::extract(&req).await.map_err(|e| {
                 warn!("⚠️ Bad Hawk Id: {:?}", e; "user_agent"=> useragent);
                 e
             })?;
+            let current_keys_changed_at = match req.app_data::<Data<tokenserver::ServerState>>() {
+                Some(tokenserver_state) => tokenserver_state.check_storage_token(&user_id).await?,
+                None => None,
+            };
             let bso = BsoParam::extrude(req.head(), &mut req.extensions_mut()).ok();
             let bso_opt = bso.map(|b| b.bso);
 
             let is_read = matches!(method, Method::GET | Method::HEAD);
+            let is_bulk = method == Method::POST
+                && collection.is_some()
+                && Query::<BatchParams>::from_query(req.query_string())
+                    .map(|params| params.batch.is_some())
+                    .unwrap_or(false);
             let precondition = PreConditionHeaderOpt::extrude(req.headers())?;
             let pool = Self {
                 pool: state.db_pool.clone(),
+                overload: Arc::clone(&state.overload),
+                metrics,
                 is_read,
+                is_bulk,
+                issued_at: user_id.issued_at,
+                keys_changed_at: user_id.keys_changed_at(),
+                current_keys_changed_at,
                 user_id: user_id.into(),
                 collection,
                 bso_opt,
@@ -0,0 +1,112 @@
+//! The wire format of the BSOs responses carry, shared by every handler
+//! rendering them (item and collection GETs, in either reply format) rather
+//! than left to the database's row types
+use serde::{Deserialize, Serialize};
+use syncstorage_db::{results, Projection, SyncTimestamp};
+
+/// A BSO as rendered to clients: `modified` in decimal seconds (see
+/// `SyncTimestamp`), `payload` passed through as stored and `sortindex` only
+/// when it's set.
+///
+/// BSOs projected to their `id` and `modified` (`Projection::Meta`) have
+/// neither a `payload` nor a `sortindex`.
+#[derive(Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Bso {
+    pub id: String,
+    pub modified: SyncTimestamp,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sortindex: Option<i32>,
+}
+
+impl Bso {
+    /// A BSO rendered with the fields of `projection`
+    pub fn projected(bso: results::GetBso, projection: Projection) -> Self {
+        match projection {
+            Projection::Full => bso.into(),
+            Projection::NoSortindex => Self {
+                sortindex: None,
+                ..bso.into()
+            },
+            Projection::Meta => Self {
+                id: bso.id,
+                modified: bso.modified,
+                payload: None,
+                sortindex: None,
+            },
+        }
+    }
+}
+
+impl From<results::GetBso> for Bso {
+    fn from(bso: results::GetBso) -> Self {
+        Self {
+            id: bso.id,
+            modified: bso.modified,
+            payload: Some(bso.payload),
+            sortindex: bso.sortindex,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(sortindex: Option<i32>) -> results::GetBso {
+        results::GetBso {
+            id: "b0".to_owned(),
+            modified: SyncTimestamp::from_milliseconds(1_500_000_000_120),
+            payload: "{\"ciphertext\":\"x\\ny\"}".to_owned(),
+            sortindex,
+            expiry: 1_600_000_000_000,
+        }
+    }
+
+    fn render(bso: Bso) -> String {
+        serde_json::to_string(&bso).unwrap()
+    }
+
+    #[test]
+    fn full() {
+        assert_eq!(
+            render(row(Some(5)).into()),
+            r#"{"id":"b0","modified":1500000000.12,"payload":"{\"ciphertext\":\"x\\ny\"}","sortindex":5}"#
+        );
+        assert_eq!(
+            render(row(None).into()),
+            r#"{"id":"b0","modified":1500000000.12,"payload":"{\"ciphertext\":\"x\\ny\"}"}"#
+        );
+    }
+
+    #[test]
+    fn projections() {
+        assert_eq!(
+            render(Bso::projected(row(Some(5)), Projection::NoSortindex)),
+            r#"{"id":"b0","modified":1500000000.12,"payload":"{\"ciphertext\":\"x\\ny\"}"}"#
+        );
+        assert_eq!(
+            render(Bso::projected(row(Some(5)), Projection::Meta)),
+            r#"{"id":"b0","modified":1500000000.12}"#
+        );
+    }
+
+    #[test]
+    fn whole_seconds_keep_their_decimals() {
+        let bso = results::GetBso {
+            modified: SyncTimestamp::from_milliseconds(1_500_000_000_000),
+            ..row(None)
+        };
+        assert!(
+            render(Bso::projected(bso, Projection::Meta)).contains(r#""modified":1500000000.00"#)
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        let bso = Bso::from(row(Some(5)));
+        let json = render(Bso::from(row(Some(5))));
+        assert_eq!(serde_json::from_str::<Bso>(&json).unwrap(), bso);
+    }
+}
@@ -29,11 +29,20 @@ enum SyncstorageDbErrorKind {
     #[error("An attempt at a conflicting write")]
     Conflict,
 
+    #[error("Timed out waiting for a lock held by another transaction")]
+    LockTimeout,
+
     #[error("Unexpected error: {}", _0)]
     Internal(String),
 
     #[error("User over quota")]
     Quota,
+
+    #[error("Invalid value: {}", _0)]
+    InvalidValue(String),
+
+    #[error("Value too large: {}", _0)]
+    TooLarge(String),
 }
 
 impl SyncstorageDbError {
@@ -53,6 +62,12 @@ impl SyncstorageDbError {
         SyncstorageDbErrorKind::Conflict.into()
     }
 
+    /// Waiting for a lock exceeded the database's lock wait timeout (e.g.
+    /// another of the user's writes holding their collection's lock)
+    pub fn lock_timeout() -> Self {
+        SyncstorageDbErrorKind::LockTimeout.into()
+    }
+
     pub fn internal(msg: String) -> Self {
         SyncstorageDbErrorKind::Internal(msg).into()
     }
@@ -60,14 +75,29 @@ impl SyncstorageDbError {
     pub fn quota() -> Self {
         SyncstorageDbErrorKind::Quota.into()
     }
+
+    /// A value the database can't store (e.g. a bso id longer than its
+    /// column)
+    pub fn invalid_value(msg: String) -> Self {
+        SyncstorageDbErrorKind::InvalidValue(msg).into()
+    }
+
+    /// A value exceeding the size the database can store (e.g. a payload
+    /// larger than its column)
+    pub fn too_large(msg: String) -> Self {
+        SyncstorageDbErrorKind::TooLarge(msg).into()
+    }
 }
 
 pub trait DbErrorIntrospect {
     fn is_collection_not_found(&self) -> bool;
     fn is_conflict(&self) -> bool;
+    fn is_lock_timeout(&self) -> bool;
     fn is_quota(&self) -> bool;
     fn is_bso_not_found(&self) -> bool;
     fn is_batch_not_found(&self) -> bool;
+    fn is_invalid_value(&self) -> bool;
+    fn is_too_large(&self) -> bool;
 }
 
 impl DbErrorIntrospect for SyncstorageDbError {
@@ -79,6 +109,10 @@ impl DbErrorIntrospect for SyncstorageDbError {
         matches!(self.kind, SyncstorageDbErrorKind::Conflict)
     }
 
+    fn is_lock_timeout(&self) -> bool {
+        matches!(self.kind, SyncstorageDbErrorKind::LockTimeout)
+    }
+
     fn is_quota(&self) -> bool {
         matches!(self.kind, SyncstorageDbErrorKind::Quota)
     }
@@ -90,16 +124,28 @@ impl DbErrorIntrospect for SyncstorageDbError {
     fn is_batch_not_found(&self) -> bool {
         matches!(self.kind, SyncstorageDbErrorKind::BatchNotFound)
     }
+
+    fn is_invalid_value(&self) -> bool {
+        matches!(self.kind, SyncstorageDbErrorKind::InvalidValue(_))
+    }
+
+    fn is_too_large(&self) -> bool {
+        matches!(self.kind, SyncstorageDbErrorKind::TooLarge(_))
+    }
 }
 
 impl ReportableError for SyncstorageDbError {
     fn is_sentry_event(&self) -> bool {
-        !matches!(&self.kind, SyncstorageDbErrorKind::Conflict)
+        !matches!(
+            &self.kind,
+            SyncstorageDbErrorKind::Conflict | SyncstorageDbErrorKind::LockTimeout
+        )
     }
 
     fn metric_label(&self) -> Option<String> {
         match &self.kind {
             SyncstorageDbErrorKind::Conflict => Some("storage.conflict".to_owned()),
+            SyncstorageDbErrorKind::LockTimeout => Some("storage.lock_timeout".to_owned()),
             _ => None,
         }
     }
@@ -122,8 +168,12 @@ impl From<SyncstorageDbErrorKind> for SyncstorageDbError {
             // handle these respones very well:
             //  * desktop bug: https://bugzilla.mozilla.org/show_bug.cgi?id=959034
             //  * android bug: https://bugzilla.mozilla.org/show_bug.cgi?id=959032
-            SyncstorageDbErrorKind::Conflict => StatusCode::SERVICE_UNAVAILABLE,
+            SyncstorageDbErrorKind::Conflict | SyncstorageDbErrorKind::LockTimeout => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
             SyncstorageDbErrorKind::Quota => StatusCode::FORBIDDEN,
+            SyncstorageDbErrorKind::InvalidValue(_) => StatusCode::BAD_REQUEST,
+            SyncstorageDbErrorKind::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         };
 
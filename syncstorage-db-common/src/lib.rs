@@ -9,7 +9,8 @@ use async_trait::async_trait;
 use futures::{future, TryFutureExt};
 use lazy_static::lazy_static;
 use serde::Deserialize;
-use syncserver_db_common::{DbFuture, GetPoolState};
+use syncserver_common::BulkPermit;
+use syncserver_db_common::{DbCallParams, DbFuture, GetPoolState, PoolState};
 
 use error::DbErrorIntrospect;
 use util::SyncTimestamp;
@@ -38,6 +39,38 @@ lazy_static! {
     };
 }
 
+/// The name of a collection as tagged on metrics: standard collections
+/// verbatim and custom ones bucketed together as "other"
+pub fn collection_tag(collection: &str) -> &'static str {
+    STD_COLLS
+        .iter()
+        .find(|(_, name)| *name == collection)
+        .map(|(_, name)| *name)
+        .unwrap_or("other")
+}
+
+/// How many of `bsos` fit within `max_bytes` of payloads, when they don't
+/// all fit. A page always holds at least one BSO, so paginating clients keep
+/// making progress
+pub fn payload_page_len(bsos: &[results::GetBso], max_bytes: u64) -> Option<usize> {
+    let mut total = 0;
+    for (i, bso) in bsos.iter().enumerate() {
+        total += bso.payload.len() as u64;
+        if total > max_bytes {
+            let len = i.max(1);
+            return (len < bsos.len()).then_some(len);
+        }
+    }
+    None
+}
+
+/// Whether a BSO with the given `expiry` has expired as of `now` (both in
+/// milliseconds). A BSO is gone from its expiry on: every read, count and
+/// usage total skips it from then, and purges may delete it
+pub fn is_expired(expiry: i64, now: i64) -> bool {
+    expiry <= now
+}
+
 /// Rough guesstimate of the maximum reasonable life span of a batch
 pub const BATCH_LIFETIME: i64 = 2 * 60 * 60 * 1000; // 2 hours, in milliseconds
 
@@ -55,6 +88,25 @@ pub trait DbPool: Sync + Send + Debug + GetPoolState {
 
     fn validate_batch_id(&self, params: params::ValidateBatchId) -> Result<(), Self::Error>;
 
+    fn collection_cache_stats(&self) -> results::CollectionCacheStats;
+
+    /// Whether the database is ready to serve requests. Only false for a
+    /// lazily initialized pool that hasn't reached the database yet
+    fn is_initialized(&self) -> bool {
+        true
+    }
+
+    /// Prepares the pool for its first requests (see
+    /// `syncstorage_settings::Settings::database_pool_warm_up`), returning
+    /// the number of custom collections cached
+    async fn warm_up(&self) -> Result<usize, Self::Error>;
+
+    /// Waits for permission to run bulk calls (batch appends and commits,
+    /// purges), to be taken before checking out the connection they run on
+    async fn bulk_permit(&self) -> BulkPermit {
+        BulkPermit::default()
+    }
+
     fn box_clone(&self) -> Box<dyn DbPool<Error = Self::Error>>;
 }
 
@@ -64,6 +116,22 @@ impl<E> Clone for Box<dyn DbPool<Error = E>> {
     }
 }
 
+impl<E> GetPoolState for Box<dyn DbPool<Error = E>> {
+    fn state(&self) -> PoolState {
+        (**self).state()
+    }
+}
+
+/// A database session, bound to a single connection for its lifetime.
+///
+/// Its methods are awaited from the actix workers serving requests, so
+/// their futures must never block: backends with blocking drivers run each
+/// call (transaction control included) on the `BlockingThreadpool`.
+///
+/// `DbFuture` is what its methods would desugar to as `#[async_trait(?Send)]`
+/// `async fn`s: they aren't `Send`, a session being pinned to the worker
+/// serving its request. The backends' `*_sync` methods are their internals,
+/// only ever called from within the threadpool's tasks (or from tests).
 pub trait Db: Debug {
     type Error: DbErrorIntrospect + 'static;
 
@@ -117,11 +185,50 @@ pub trait Db: Debug {
         params: params::DeleteStorage,
     ) -> DbFuture<'_, results::DeleteStorage, Self::Error>;
 
+    fn get_storage_epoch(
+        &self,
+        params: params::GetStorageEpoch,
+    ) -> DbFuture<'_, results::GetStorageEpoch, Self::Error>;
+
+    /// Move the user's storage epoch to the current timestamp (e.g. when
+    /// migrating or resetting their storage), invalidating their clients'
+    /// tokens minted before it
+    fn bump_storage_epoch(
+        &self,
+        params: params::BumpStorageEpoch,
+    ) -> DbFuture<'_, results::BumpStorageEpoch, Self::Error>;
+
+    fn get_keys_changed_at(
+        &self,
+        params: params::GetKeysChangedAt,
+    ) -> DbFuture<'_, results::GetKeysChangedAt, Self::Error>;
+
+    /// Record that the user's clients write with keys changed at
+    /// `keys_changed_at`, unless a later change is already recorded
+    fn record_keys_changed_at(
+        &self,
+        params: params::RecordKeysChangedAt,
+    ) -> DbFuture<'_, results::RecordKeysChangedAt, Self::Error>;
+
     fn delete_collection(
         &self,
         params: params::DeleteCollection,
     ) -> DbFuture<'_, results::DeleteCollection, Self::Error>;
 
+    /// Delete several of a user's collections under a single timestamp
+    /// (atomically when called within a transaction)
+    fn delete_collections(
+        &self,
+        params: params::DeleteCollections,
+    ) -> DbFuture<'_, results::DeleteCollections, Self::Error>;
+
+    /// Delete all of a user's collections but those to `keep` (e.g. a "soft
+    /// reset" keeping meta and crypto), under a single timestamp
+    fn delete_collections_except(
+        &self,
+        params: params::DeleteCollectionsExcept,
+    ) -> DbFuture<'_, results::DeleteCollectionsExcept, Self::Error>;
+
     fn delete_bsos(
         &self,
         params: params::DeleteBsos,
@@ -132,6 +239,12 @@ pub trait Db: Debug {
     fn get_bso_ids(&self, params: params::GetBsos)
         -> DbFuture<'_, results::GetBsoIds, Self::Error>;
 
+    /// Count the (unexpired) BSOs matching a query, without loading them
+    fn get_bsos_count(
+        &self,
+        params: params::GetBsosCount,
+    ) -> DbFuture<'_, results::GetBsosCount, Self::Error>;
+
     fn post_bsos(&self, params: params::PostBsos) -> DbFuture<'_, results::PostBsos, Self::Error>;
 
     fn delete_bso(
@@ -159,6 +272,9 @@ pub trait Db: Debug {
         params: params::ValidateBatch,
     ) -> DbFuture<'_, results::ValidateBatch, Self::Error>;
 
+    /// Append bsos to a pending batch, without touching its collection: the
+    /// collection's timestamp only advances once the batch is committed, so
+    /// clients never see it change before the batch's bsos land
     fn append_to_batch(
         &self,
         params: params::AppendToBatch,
@@ -178,8 +294,53 @@ pub trait Db: Debug {
 
     fn check(&self) -> DbFuture<'_, results::Check, Self::Error>;
 
+    /// Count rows across all users (expensive: only for periodic reporting)
+    fn get_database_stats(&self) -> DbFuture<'_, results::GetDatabaseStats, Self::Error>;
+
+    fn get_native_ttl(&self) -> DbFuture<'_, results::GetNativeTtl, Self::Error>;
+
+    /// Delete up to `limit` expired bsos across all users, then up to
+    /// `limit` of the collections left without any (their timestamps kept
+    /// in the users' storage timestamps). Meant to be called outside of a
+    /// transaction, so each delete only holds its locks briefly (see
+    /// `syncstorage_db::purge`)
+    fn purge_expired(
+        &self,
+        params: params::PurgeExpired,
+    ) -> DbFuture<'_, results::PurgeExpired, Self::Error>;
+
+    /// Delete a single BSO if it has expired (e.g. one a read didn't find),
+    /// leaving its collection as is: reads already skip expired BSOs
+    fn purge_expired_bso(
+        &self,
+        params: params::PurgeExpiredBso,
+    ) -> DbFuture<'_, results::PurgeExpiredBso, Self::Error>;
+
     fn get_connection_info(&self) -> results::ConnectionInfo;
 
+    /// The number of statements sent to the database by this `Db` so far
+    /// (i.e. by the request it's pinned to)
+    fn query_count(&self) -> u64;
+
+    /// The usage of a user's collection as computed by this `Db`'s last write
+    /// to it (when updating its `user_collections` row), sparing a
+    /// `get_quota_usage` call. `None` if no write computed it
+    fn written_quota_usage(
+        &self,
+        _user_id: &UserIdentifier,
+        _collection: &str,
+    ) -> Option<results::GetQuotaUsage> {
+        None
+    }
+
+    /// The user's storage epoch and keys as read by this `Db`'s write lock on
+    /// one of their collections, sparing `get_storage_epoch` and
+    /// `get_keys_changed_at` calls. `None` if the lock didn't read them, or
+    /// they've changed since
+    fn locked_user(&self, _user_id: &UserIdentifier) -> Option<results::LockedUser> {
+        None
+    }
+
     /// Retrieve the timestamp for an item/collection
     ///
     /// Modeled on the Python `get_resource_timestamp` function.
@@ -249,6 +410,10 @@ pub trait Db: Debug {
     fn clear_coll_cache(&self) -> DbFuture<'_, (), Self::Error>;
 
     fn set_quota(&mut self, enabled: bool, limit: usize, enforce: bool);
+
+    /// Tag the log records of this `Db`'s calls with the id of the request
+    /// it's pinned to. Ignored by backends not logging it
+    fn set_request_id(&mut self, _request_id: String) {}
 }
 
 impl<E> Clone for Box<dyn Db<Error = E>>
@@ -270,6 +435,19 @@ pub enum Sorting {
     Index,
 }
 
+/// The fields of the BSOs returned by `get_bsos` (i.e. requested with
+/// `?full=1`)
+#[derive(Debug, Default, Deserialize, Clone, PartialEq, Eq, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Projection {
+    #[default]
+    Full,
+    /// All but their `sortindex`
+    NoSortindex,
+    /// Only their `id` and `modified`, for clients only detecting changes
+    Meta,
+}
+
 #[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
 pub struct UserIdentifier {
     /// For MySQL database backends as the primary key
@@ -278,3 +456,24 @@ pub struct UserIdentifier {
     pub fxa_uid: String,
     pub fxa_kid: String,
 }
+
+/// Bit offset of the tenant id in the `legacy_id`s of tenants' users: past
+/// the 10 digits of the uids the server accepts
+const TENANT_ID_SHIFT: u32 = 40;
+
+impl UserIdentifier {
+    /// Scope the user's db keys to a tenant, isolating the sync populations
+    /// sharing the database: the tenant's id is set in the high bits of the
+    /// `legacy_id` and appended to the `fxa_uid`. The default tenant (0)'s
+    /// keys are left unchanged
+    pub fn scoped_to_tenant(mut self, tenant_id: u16) -> Self {
+        if tenant_id != 0 {
+            self.legacy_id |= u64::from(tenant_id) << TENANT_ID_SHIFT;
+            self.fxa_uid = format!("{}@{}", self.fxa_uid, tenant_id);
+        }
+        self
+    }
+}
+
+/// Calls given a user (e.g. info/collections) span all of their collections
+impl DbCallParams for UserIdentifier {}
@@ -1,10 +1,12 @@
 //! Parameter types for database methods.
 use std::{collections::HashMap, num::ParseIntError, str::FromStr};
 
-use diesel::Queryable;
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
 
-use crate::{results, util::SyncTimestamp, Sorting, UserIdentifier};
+use syncserver_db_common::DbCallParams;
+
+use crate::{collection_tag, results, util::SyncTimestamp, Projection, Sorting, UserIdentifier};
 
 macro_rules! data {
     ($name:ident {$($property:ident: $type:ty,)*}) => {
@@ -21,6 +23,16 @@ macro_rules! uid_data {
     )+)
 }
 
+macro_rules! collection_call_params {
+    ($($name:ident),+) => ($(
+        impl DbCallParams for $name {
+            fn collection_tag(&self) -> Option<&'static str> {
+                Some(collection_tag(&self.collection))
+            }
+        }
+    )+)
+}
+
 macro_rules! collection_data {
     ($($name:ident {$($property:ident: $type:ty,)*},)+) => ($(
         data! {
@@ -30,6 +42,7 @@ macro_rules! collection_data {
                 $($property: $type,)*
             }
         }
+        collection_call_params!($name);
     )+)
 }
 
@@ -43,6 +56,7 @@ macro_rules! bso_data {
                 $($property: $type,)*
             }
         }
+        collection_call_params!($name);
     )+)
 }
 
@@ -53,55 +67,119 @@ uid_data! {
     GetStorageTimestamp,
     GetStorageUsage,
     DeleteStorage,
+    GetStorageEpoch,
+    BumpStorageEpoch,
+    GetKeysChangedAt,
 }
 
-#[derive(Debug, Default, Clone)]
+/// Where a page of `get_bsos`/`get_bso_ids` results starts: past the first
+/// `offset` BSOs, or, for results sorted by `modified`, past the previous
+/// page's last BSO (`after`) when it's known.
+///
+/// Seeking past `after` makes for a cheap `WHERE` clause, whereas skipping
+/// `offset` rows has the database read all of them: backends that can't seek
+/// (or results sorted otherwise) fall back to the latter.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
 pub struct Offset {
     pub timestamp: Option<SyncTimestamp>,
     pub offset: u64,
+    pub after: Option<BsoPosition>,
+}
+
+/// A BSO's position in results sorted by `modified`, ties broken by `id`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BsoPosition {
+    pub modified: SyncTimestamp,
+    pub id: String,
+}
+
+impl Offset {
+    /// The BSO to seek past for results sorted by `sort`, if any
+    pub fn after(&self, sort: Sorting) -> Option<&BsoPosition> {
+        match sort {
+            Sorting::Newest | Sorting::Oldest => self.after.as_ref(),
+            Sorting::None | Sorting::Index => None,
+        }
+    }
+
+    /// The offset of the page following a page of `len` BSOs starting at
+    /// this one, sorted by `sort`, with `last` its last BSO
+    pub fn next(&self, len: usize, sort: Sorting, last: Option<BsoPosition>) -> Self {
+        Self {
+            timestamp: None,
+            offset: self.offset + len as u64,
+            after: match sort {
+                Sorting::Newest | Sorting::Oldest => last,
+                Sorting::None | Sorting::Index => None,
+            },
+        }
+    }
+}
+
+impl From<&results::GetBso> for BsoPosition {
+    fn from(bso: &results::GetBso) -> Self {
+        Self {
+            modified: bso.modified,
+            id: bso.id.clone(),
+        }
+    }
+}
+
+/// An `offset` token that isn't either of the formats `Offset`s are written
+/// in
+#[derive(Debug, Error)]
+#[error("Invalid offset")]
+pub struct InvalidOffset;
+
+impl From<ParseIntError> for InvalidOffset {
+    fn from(_: ParseIntError) -> Self {
+        InvalidOffset
+    }
 }
 
 impl ToString for Offset {
+    /// "{offset}", or "{offset}:{modified}:{id}" when seeking past a BSO, its
+    /// id hex encoded (ids may hold any printable ASCII, ':' included)
     fn to_string(&self) -> String {
-        // issue559: Disable ':' support for now.
-        self.offset.to_string()
-        /*
-        match self.timestamp {
+        match &self.after {
             None => self.offset.to_string(),
-            Some(ts) => format!("{}:{}", ts.as_i64(), self.offset),
+            Some(after) => {
+                let id: String = after.id.bytes().map(|b| format!("{:02x}", b)).collect();
+                format!("{}:{}:{}", self.offset, after.modified.as_i64(), id)
+            }
         }
-        */
     }
 }
 
 impl FromStr for Offset {
-    type Err = ParseIntError;
+    type Err = InvalidOffset;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        // issue559: Disable ':' support for now: simply parse as i64 as
-        // previously (it was u64 previously but i64's close enough)
-        let result = Offset {
-            timestamp: None,
-            offset: s.parse::<u64>()?,
-        };
-        /*
-        let result = match s.chars().position(|c| c == ':') {
-            None => Offset {
-                timestamp: None,
-                offset: s.parse::<u64>()?,
-            },
-            Some(_colon_position) => {
-                let mut parts = s.split(':');
-                let timestamp_string = parts.next().unwrap_or("0");
-                let timestamp = SyncTimestamp::from_milliseconds(timestamp_string.parse::<u64>()?);
-                let offset = parts.next().unwrap_or("0").parse::<u64>()?;
-                Offset {
-                    timestamp: Some(timestamp),
-                    offset,
-                }
+        let mut parts = s.splitn(3, ':');
+        let offset = parts.next().unwrap_or_default().parse::<u64>()?;
+        let after = match (parts.next(), parts.next()) {
+            (None, _) => None,
+            (Some(modified), Some(id)) if id.len() % 2 == 0 => {
+                let id = (0..id.len())
+                    .step_by(2)
+                    .map(|i| {
+                        Ok(u8::from_str_radix(
+                            id.get(i..i + 2).ok_or(InvalidOffset)?,
+                            16,
+                        )?)
+                    })
+                    .collect::<Result<Vec<_>, InvalidOffset>>()?;
+                Some(BsoPosition {
+                    modified: SyncTimestamp::from_milliseconds(modified.parse()?),
+                    id: String::from_utf8(id).map_err(|_| InvalidOffset)?,
+                })
             }
+            _ => return Err(InvalidOffset),
         };
-        */
-        Ok(result)
+        Ok(Offset {
+            timestamp: None,
+            offset,
+            after,
+        })
     }
 }
 
@@ -120,6 +198,14 @@ collection_data! {
         offset: Option<Offset>,
         ids: Vec<String>,
         full: bool,
+        projection: Projection,
+        // Max combined size of the returned payloads (see `payload_page_len`)
+        max_payload_bytes: Option<u64>,
+    },
+    GetBsosCount {
+        newer: Option<SyncTimestamp>,
+        older: Option<SyncTimestamp>,
+        ids: Vec<String>,
     },
     PostBsos {
         bsos: Vec<PostCollectionBso>,
@@ -168,11 +254,14 @@ bso_data! {
     DeleteBso {},
     GetBso {},
     GetBsoTimestamp {},
+    PurgeExpiredBso {},
 }
 
-#[derive(Clone, Debug, Default, Queryable)]
+#[derive(Clone, Debug, Default)]
 pub struct Batch {
     pub id: String,
+    /// What's been appended to the batch so far
+    pub totals: results::BatchTotals,
 }
 
 pub struct PutBso {
@@ -185,6 +274,8 @@ pub struct PutBso {
     pub ttl: Option<u32>,
 }
 
+collection_call_params!(PutBso);
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct PostCollectionBso {
     pub id: String,
@@ -194,6 +285,43 @@ pub struct PostCollectionBso {
     pub ttl: Option<u32>,
 }
 
+data! {
+    DeleteCollections {
+        user_id: UserIdentifier,
+        collections: Vec<String>,
+    }
+}
+
+impl DbCallParams for DeleteCollections {}
+
+data! {
+    DeleteCollectionsExcept {
+        user_id: UserIdentifier,
+        keep: Vec<String>,
+    }
+}
+
+impl DbCallParams for DeleteCollectionsExcept {}
+
+data! {
+    PurgeExpired {
+        // Max number of rows deleted from each table
+        limit: u32,
+    }
+}
+
+impl DbCallParams for PurgeExpired {}
+
+data! {
+    RecordKeysChangedAt {
+        user_id: UserIdentifier,
+        // In milliseconds since epoch, as in the token's `fxa_kid`
+        keys_changed_at: i64,
+    }
+}
+
+impl DbCallParams for RecordKeysChangedAt {}
+
 pub type GetCollectionId = String;
 
 pub type CreateCollection = String;
@@ -205,3 +333,5 @@ data! {
         collection: String,
     }
 }
+
+collection_call_params!(UpdateCollection);
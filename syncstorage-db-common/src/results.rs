@@ -18,11 +18,30 @@ pub type GetCollectionCounts = HashMap<String, i64>;
 pub type GetCollectionUsage = HashMap<String, i64>;
 pub type GetStorageTimestamp = SyncTimestamp;
 pub type GetStorageUsage = u64;
+pub type GetBsosCount = u64;
 pub type DeleteStorage = ();
+/// When the user's storage was last reset, if ever
+pub type GetStorageEpoch = Option<SyncTimestamp>;
+pub type BumpStorageEpoch = SyncTimestamp;
+/// When the keys the user's clients write with last changed, if recorded
+pub type GetKeysChangedAt = Option<i64>;
+pub type RecordKeysChangedAt = ();
 pub type DeleteCollection = SyncTimestamp;
 pub type DeleteBsos = SyncTimestamp;
 pub type DeleteBso = SyncTimestamp;
 pub type PutBso = SyncTimestamp;
+/// Whether the BSO had expired, and was deleted
+pub type PurgeExpiredBso = bool;
+
+#[derive(Debug, Default, Serialize)]
+pub struct DeleteCollections {
+    /// The storage timestamp once they're deleted
+    pub modified: SyncTimestamp,
+    /// Per collection: whether it existed (and so was deleted)
+    pub collections: HashMap<String, bool>,
+}
+
+pub type DeleteCollectionsExcept = DeleteCollections;
 
 #[derive(Debug, Default, Clone)]
 pub struct CreateBatch {
@@ -30,21 +49,88 @@ pub struct CreateBatch {
     pub size: Option<usize>,
 }
 
+/// Running totals of what was appended to a batch, reported to clients so
+/// they can track its progress against the batch limits
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BatchTotals {
+    pub records: u64,
+    pub bytes: u64,
+}
+
 pub type ValidateBatch = bool;
-pub type AppendToBatch = ();
+pub type AppendToBatch = BatchTotals;
 pub type GetBatch = params::Batch;
 pub type DeleteBatch = ();
 pub type CommitBatch = SyncTimestamp;
 pub type ValidateBatchId = ();
 pub type Check = bool;
 
+/// Table-wide row counts, periodically reported as metrics
 #[derive(Debug, Default)]
+pub struct GetDatabaseStats {
+    pub bsos: i64,
+    /// Expired bsos not yet removed by the purge job
+    pub expired_bsos: i64,
+    pub user_collections: i64,
+    pub batches: i64,
+}
+
+/// Rows deleted by a purge of expired data, from across all users
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PurgeExpired {
+    pub bsos: u64,
+    /// Collections left without any bsos
+    pub user_collections: u64,
+    /// Deduplicated payloads no longer referenced by any bso (MySQL's
+    /// `bso_payloads`)
+    pub payloads: u64,
+}
+
+impl PurgeExpired {
+    /// The number of rows deleted from all tables
+    pub fn rows(&self) -> u64 {
+        self.bsos + self.user_collections + self.payloads
+    }
+}
+
+/// Which tables the database itself deletes expired rows from (e.g. via
+/// Spanner's row deletion policies): purges can skip them
+#[derive(Debug, Default)]
+pub struct GetNativeTtl {
+    pub bsos: bool,
+    pub batches: bool,
+}
+
+/// Cumulative collection id/name cache counters, periodically reported as
+/// metrics
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CollectionCacheStats {
+    pub entries: u64,
+    pub hits: u64,
+    pub misses: u64,
+    pub inserts: u64,
+    pub evictions: u64,
+    /// Collections created after missing the cache: once per new collection
+    /// however many of its first writes race (MySQL and PostgreSQL only)
+    pub creations: u64,
+}
+
+/// A user's storage epoch and keys, as read along with a write lock on one of
+/// their collections (see `Db::locked_user`)
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LockedUser {
+    pub storage_epoch: GetStorageEpoch,
+    pub keys_changed_at: GetKeysChangedAt,
+}
+
+#[derive(Clone, Debug, Default)]
 pub struct GetQuotaUsage {
     pub total_bytes: usize,
     pub count: i32,
 }
 
-#[derive(Debug, Default, Deserialize, Queryable, QueryableByName, Serialize)]
+/// A BSO row: the web layer renders it in its own wire format
+#[derive(Debug, Default, Queryable, QueryableByName)]
 pub struct GetBso {
     #[sql_type = "Text"]
     pub id: String,
@@ -52,23 +138,17 @@ pub struct GetBso {
     pub modified: SyncTimestamp,
     #[sql_type = "Text"]
     pub payload: String,
-    #[serde(skip_serializing_if = "Option::is_none")]
     #[sql_type = "Nullable<Integer>"]
     pub sortindex: Option<i32>,
     // NOTE: expiry (ttl) is never rendered to clients and only loaded for
     // tests: this and its associated queries/loading could be wrapped in
     // #[cfg(test)]
-    #[serde(skip_serializing)]
-    #[serde(skip_deserializing)]
     #[sql_type = "BigInt"]
     pub expiry: i64,
 }
 
 #[derive(Debug, Default)]
-pub struct Paginated<T>
-where
-    T: Serialize,
-{
+pub struct Paginated<T> {
     pub items: Vec<T>,
     pub offset: Option<String>,
 }
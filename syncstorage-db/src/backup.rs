@@ -0,0 +1,173 @@
+//! Snapshots of a user's full storage, restorable later with their
+//! timestamps preserved (e.g. for support escalations or as a safety copy
+//! before migrating users).
+use serde::{Deserialize, Serialize};
+use syncstorage_db_common::{
+    error::DbErrorIntrospect, is_expired, params, util::SyncTimestamp, Db, Projection, Sorting,
+    UserIdentifier,
+};
+
+use crate::DbError;
+
+/// Version of the snapshot format
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// Number of BSOs read per query while snapshotting a collection
+const SNAPSHOT_PAGE_SIZE: u32 = 1_000;
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct UserSnapshot {
+    pub version: u32,
+    pub collections: Vec<CollectionSnapshot>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct CollectionSnapshot {
+    pub name: String,
+    /// In milliseconds
+    pub modified: i64,
+    pub bsos: Vec<BsoSnapshot>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct BsoSnapshot {
+    pub id: String,
+    pub sortindex: Option<i32>,
+    pub payload: String,
+    /// In milliseconds
+    pub modified: i64,
+    /// In milliseconds
+    pub expiry: i64,
+}
+
+/// Read all of a user's (unexpired) BSOs
+pub async fn snapshot_user(
+    db: &dyn Db<Error = DbError>,
+    user_id: &UserIdentifier,
+) -> Result<UserSnapshot, DbError> {
+    let mut timestamps: Vec<_> = db
+        .get_collection_timestamps(user_id.clone())
+        .await?
+        .into_iter()
+        .collect();
+    timestamps.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let mut collections = Vec::with_capacity(timestamps.len());
+    for (name, modified) in timestamps {
+        let mut bsos = vec![];
+        let mut offset = None;
+        loop {
+            let page = db
+                .get_bsos(params::GetBsos {
+                    user_id: user_id.clone(),
+                    collection: name.clone(),
+                    newer: None,
+                    older: None,
+                    sort: Sorting::Oldest,
+                    limit: Some(SNAPSHOT_PAGE_SIZE),
+                    offset,
+                    ids: vec![],
+                    full: true,
+                    projection: Projection::Full,
+                    max_payload_bytes: None,
+                })
+                .await?;
+            bsos.extend(page.items.into_iter().map(|bso| BsoSnapshot {
+                id: bso.id,
+                sortindex: bso.sortindex,
+                payload: bso.payload,
+                modified: bso.modified.as_i64(),
+                expiry: bso.expiry,
+            }));
+            offset = match page.offset {
+                Some(offset) => Some(offset.parse().map_err(|_| {
+                    DbError::internal(format!("Invalid pagination offset: {}", offset))
+                })?),
+                None => break,
+            };
+        }
+        collections.push(CollectionSnapshot {
+            name,
+            modified: modified.as_i64(),
+            bsos,
+        });
+    }
+    Ok(UserSnapshot {
+        version: SNAPSHOT_VERSION,
+        collections,
+    })
+}
+
+/// Write a collection's snapshot back, with the BSOs' and collection's
+/// original timestamps. BSOs that expired since the snapshot are skipped.
+///
+/// Callers manage the transaction and should clear the user's storage
+/// beforehand: BSOs missing from the snapshot are otherwise left as is.
+pub async fn restore_collection(
+    db: &dyn Db<Error = DbError>,
+    user_id: &UserIdentifier,
+    collection: &CollectionSnapshot,
+) -> Result<(), DbError> {
+    let now = SyncTimestamp::default().as_i64();
+    let mut bsos: Vec<_> = collection
+        .bsos
+        .iter()
+        .filter(|bso| !is_expired(bso.expiry, now))
+        .collect();
+    // Written in order, so the collection's timestamp only ever moves
+    // forward
+    bsos.sort_by_key(|bso| bso.modified);
+    for bso in bsos {
+        db.set_timestamp(SyncTimestamp::from_i64(bso.modified)?);
+        db.put_bso(params::PutBso {
+            user_id: user_id.clone(),
+            collection: collection.name.clone(),
+            id: bso.id.clone(),
+            sortindex: bso.sortindex,
+            payload: Some(bso.payload.clone()),
+            // Rounded up to the second
+            ttl: Some(((bso.expiry - bso.modified + 999) / 1_000) as u32),
+        })
+        .await?;
+    }
+
+    // Collections may have been modified after their last remaining BSO
+    // (e.g. by deletes)
+    let collection_id = match db.get_collection_id(collection.name.clone()).await {
+        Err(e) if e.is_collection_not_found() => {
+            db.create_collection(collection.name.clone()).await?
+        }
+        result => result?,
+    };
+    db.set_timestamp(SyncTimestamp::from_i64(collection.modified)?);
+    db.update_collection(params::UpdateCollection {
+        user_id: user_id.clone(),
+        collection_id,
+        collection: collection.name.clone(),
+    })
+    .await?;
+    Ok(())
+}
+
+/// Write a whole snapshot back (see `restore_collection`)
+pub async fn restore_user(
+    db: &dyn Db<Error = DbError>,
+    user_id: &UserIdentifier,
+    snapshot: &UserSnapshot,
+) -> Result<(), DbError> {
+    check_version(snapshot)?;
+    for collection in &snapshot.collections {
+        restore_collection(db, user_id, collection).await?;
+    }
+    Ok(())
+}
+
+pub fn check_version(snapshot: &UserSnapshot) -> Result<(), DbError> {
+    if snapshot.version != SNAPSHOT_VERSION {
+        return Err(DbError::internal(format!(
+            "Unsupported snapshot version: {}",
+            snapshot.version
+        )));
+    }
+    Ok(())
+}
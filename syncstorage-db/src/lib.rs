@@ -4,7 +4,9 @@
 #[macro_use]
 extern crate slog_scope;
 
+pub mod backup;
 pub mod mock;
+pub mod purge;
 #[cfg(test)]
 mod tests;
 
@@ -13,12 +15,54 @@ use std::time::Duration;
 use cadence::{Gauged, StatsdClient};
 use tokio::{self, time};
 
+#[cfg(feature = "memory")]
+pub type DbPoolImpl = syncstorage_memory::MemoryDbPool;
+#[cfg(feature = "memory")]
+pub use syncstorage_memory::DbError;
+#[cfg(feature = "memory")]
+pub type DbImpl = syncstorage_memory::MemoryDb;
+/// The scheme of the compiled in backend's `database_url`
+#[cfg(feature = "memory")]
+pub const DATABASE_SCHEME: &str = "memory";
+
+/// Does nothing: the in memory database is created empty, with nothing to
+/// migrate
+#[cfg(feature = "memory")]
+pub fn run_migrations(_settings: &syncstorage_settings::Settings) -> Result<(), DbError> {
+    Ok(())
+}
+
 #[cfg(feature = "mysql")]
 pub type DbPoolImpl = syncstorage_mysql::MysqlDbPool;
 #[cfg(feature = "mysql")]
 pub use syncstorage_mysql::DbError;
 #[cfg(feature = "mysql")]
 pub type DbImpl = syncstorage_mysql::MysqlDb;
+/// The scheme of the compiled in backend's `database_url`
+#[cfg(feature = "mysql")]
+pub const DATABASE_SCHEME: &str = "mysql";
+
+/// Applies any pending migrations to the database
+#[cfg(feature = "mysql")]
+pub fn run_migrations(settings: &syncstorage_settings::Settings) -> Result<(), DbError> {
+    syncstorage_mysql::run_embedded_migrations(&settings.database_url)
+}
+
+#[cfg(feature = "postgres")]
+pub type DbPoolImpl = syncstorage_postgres::PostgresDbPool;
+#[cfg(feature = "postgres")]
+pub use syncstorage_postgres::DbError;
+#[cfg(feature = "postgres")]
+pub type DbImpl = syncstorage_postgres::PostgresDb;
+/// The scheme of the compiled in backend's `database_url`
+#[cfg(feature = "postgres")]
+pub const DATABASE_SCHEME: &str = "postgres";
+
+/// Applies any pending migrations to the database
+#[cfg(feature = "postgres")]
+pub fn run_migrations(settings: &syncstorage_settings::Settings) -> Result<(), DbError> {
+    syncstorage_postgres::run_embedded_migrations(&settings.database_url)
+}
 
 #[cfg(feature = "spanner")]
 pub type DbPoolImpl = syncstorage_spanner::SpannerDbPool;
@@ -26,21 +70,149 @@ pub type DbPoolImpl = syncstorage_spanner::SpannerDbPool;
 pub use syncstorage_spanner::DbError;
 #[cfg(feature = "spanner")]
 pub type DbImpl = syncstorage_spanner::SpannerDb;
+/// The scheme of the compiled in backend's `database_url`
+#[cfg(feature = "spanner")]
+pub const DATABASE_SCHEME: &str = "spanner";
+
+/// Does nothing: Spanner's schema isn't migrated by the server (see
+/// `syncstorage-spanner/src/schema.ddl`)
+#[cfg(feature = "spanner")]
+pub fn run_migrations(_settings: &syncstorage_settings::Settings) -> Result<(), DbError> {
+    Ok(())
+}
+
+/// What the `spanner-ddl` subcommand does with the expected Spanner schema
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SpannerDdl {
+    /// Print it
+    Print,
+    /// Print how the database's differs from it
+    Diff,
+    /// Create what's missing from the database. Objects defined differently
+    /// are left for the operator to migrate
+    Apply,
+}
+
+/// Manages the Spanner schema (see `syncstorage_spanner::ddl`), optionally
+/// with its row deletion policies. Returns the report to print
+#[cfg(feature = "spanner")]
+pub fn spanner_ddl(
+    settings: &syncstorage_settings::Settings,
+    command: SpannerDdl,
+    ttl_policies: bool,
+) -> Result<String, DbError> {
+    use syncstorage_spanner::ddl;
+
+    let expected = ddl::expected_schema(ttl_policies);
+    if command == SpannerDdl::Print {
+        return Ok(ddl::to_ddl(&expected));
+    }
+    let admin = ddl::DatabaseAdmin::connect(settings)?;
+    let diff = ddl::diff(&expected, &admin.get_ddl()?);
+    if command == SpannerDdl::Diff || diff.missing.is_empty() {
+        return Ok(diff.to_string());
+    }
+    admin.update_ddl(&diff.missing)?;
+    let mut report = format!("{}\nApplied the missing statements\n", diff);
+    if diff.creates_table("collections") {
+        report.push_str(
+            "Populate the new collections table with \
+             syncstorage-spanner/src/insert_standard_collections.sql\n",
+        );
+    }
+    Ok(report)
+}
+
+/// Fails: the Spanner schema's only managed by Spanner builds
+#[cfg(not(feature = "spanner"))]
+pub fn spanner_ddl(
+    _settings: &syncstorage_settings::Settings,
+    _command: SpannerDdl,
+    _ttl_policies: bool,
+) -> Result<String, DbError> {
+    Err(DbError::internal(format!(
+        "spanner-ddl requires a Spanner build, not {}",
+        DATABASE_SCHEME
+    )))
+}
+
+#[cfg(feature = "sqlite")]
+pub type DbPoolImpl = syncstorage_sqlite::SqliteDbPool;
+#[cfg(feature = "sqlite")]
+pub use syncstorage_sqlite::DbError;
+#[cfg(feature = "sqlite")]
+pub type DbImpl = syncstorage_sqlite::SqliteDb;
+/// The scheme of the compiled in backend's `database_url`
+#[cfg(feature = "sqlite")]
+pub const DATABASE_SCHEME: &str = "sqlite";
+
+/// Applies any pending migrations to the database
+#[cfg(feature = "sqlite")]
+pub fn run_migrations(settings: &syncstorage_settings::Settings) -> Result<(), DbError> {
+    syncstorage_sqlite::run_embedded_migrations(&settings.database_url)
+}
 
 pub use syncserver_db_common::{GetPoolState, PoolState};
 pub use syncstorage_db_common::error::DbErrorIntrospect;
 
 pub use syncstorage_db_common::{
-    params, results,
+    collection_tag, params, results,
     util::{to_rfc3339, SyncTimestamp},
-    Db, DbPool, Sorting, UserIdentifier,
+    Db, DbPool, Projection, Sorting, UserIdentifier,
 };
 
-#[cfg(all(feature = "mysql", feature = "spanner"))]
-compile_error!("only one of the \"mysql\" and \"spanner\" features can be enabled at a time");
+#[cfg(any(
+    all(feature = "memory", feature = "mysql"),
+    all(feature = "memory", feature = "postgres"),
+    all(feature = "memory", feature = "spanner"),
+    all(feature = "memory", feature = "sqlite"),
+    all(feature = "mysql", feature = "postgres"),
+    all(feature = "mysql", feature = "spanner"),
+    all(feature = "mysql", feature = "sqlite"),
+    all(feature = "postgres", feature = "spanner"),
+    all(feature = "postgres", feature = "sqlite"),
+    all(feature = "spanner", feature = "sqlite"),
+))]
+compile_error!(
+    "only one of the \"memory\", \"mysql\", \"postgres\", \"spanner\" and \"sqlite\" \
+     features can be enabled at a time"
+);
+
+#[cfg(not(any(
+    feature = "memory",
+    feature = "mysql",
+    feature = "postgres",
+    feature = "spanner",
+    feature = "sqlite"
+)))]
+compile_error!(
+    "exactly one of the \"memory\", \"mysql\", \"postgres\", \"spanner\" and \"sqlite\" \
+     features must be enabled"
+);
 
-#[cfg(not(any(feature = "mysql", feature = "spanner")))]
-compile_error!("exactly one of the \"mysql\" and \"spanner\" features must be enabled");
+/// Panics unless `database_url` points at the compiled in backend.
+///
+/// The test suites are shared by the backends: they run against the one
+/// selected by `--features` (`syncstorage-db/memory`, `syncstorage-db/mysql`,
+/// `syncstorage-db/postgres`, `syncstorage-db/spanner` or
+/// `syncstorage-db/sqlite`), on the database of
+/// `SYNC_SYNCSTORAGE__DATABASE_URL`. Mixing them up would otherwise fail every
+/// test with a connection error
+pub fn assert_test_database_url(database_url: &str) {
+    let scheme = database_url
+        .split_once("://")
+        .map(|(scheme, _)| scheme)
+        .unwrap_or_default();
+    // PostgreSQL's URLs may be spelled either way
+    let is_backend_url =
+        scheme == DATABASE_SCHEME || (cfg!(feature = "postgres") && scheme == "postgresql");
+    assert!(
+        is_backend_url,
+        "The tests were built for {} but SYNC_SYNCSTORAGE__DATABASE_URL is {:?}: set it to a {} \
+         database or rebuild with --no-default-features --features=syncstorage-db/<backend>",
+        DATABASE_SCHEME, database_url, DATABASE_SCHEME,
+    );
+}
 
 /// Emit DbPool metrics periodically
 pub fn spawn_pool_periodic_reporter<T: GetPoolState + Send + 'static>(
@@ -57,6 +229,7 @@ pub fn spawn_pool_periodic_reporter<T: GetPoolState + Send + 'static>(
             let PoolState {
                 connections,
                 idle_connections,
+                ..
             } = pool.state();
             metrics
                 .gauge_with_tags(
@@ -30,6 +30,14 @@ impl DbPool for MockDbPool {
         Ok(())
     }
 
+    fn collection_cache_stats(&self) -> results::CollectionCacheStats {
+        results::CollectionCacheStats::default()
+    }
+
+    async fn warm_up(&self) -> Result<usize, DbError> {
+        Ok(0)
+    }
+
     fn box_clone(&self) -> Box<dyn DbPool<Error = DbError>> {
         Box::new(self.clone())
     }
@@ -85,6 +93,14 @@ impl Db for MockDb {
         Box::pin(future::ok(true))
     }
 
+    fn get_database_stats(&self) -> DbFuture<'_, results::GetDatabaseStats> {
+        Box::pin(future::ok(results::GetDatabaseStats::default()))
+    }
+
+    fn get_native_ttl(&self) -> DbFuture<'_, results::GetNativeTtl> {
+        Box::pin(future::ok(results::GetNativeTtl::default()))
+    }
+
     mock_db_method!(lock_for_read, LockCollection);
     mock_db_method!(lock_for_write, LockCollection);
     mock_db_method!(get_collection_timestamps, GetCollectionTimestamps);
@@ -95,10 +111,17 @@ impl Db for MockDb {
     mock_db_method!(get_storage_usage, GetStorageUsage);
     mock_db_method!(get_quota_usage, GetQuotaUsage);
     mock_db_method!(delete_storage, DeleteStorage);
+    mock_db_method!(get_storage_epoch, GetStorageEpoch);
+    mock_db_method!(bump_storage_epoch, BumpStorageEpoch);
+    mock_db_method!(get_keys_changed_at, GetKeysChangedAt);
+    mock_db_method!(record_keys_changed_at, RecordKeysChangedAt);
     mock_db_method!(delete_collection, DeleteCollection);
+    mock_db_method!(delete_collections, DeleteCollections);
+    mock_db_method!(delete_collections_except, DeleteCollectionsExcept);
     mock_db_method!(delete_bsos, DeleteBsos);
     mock_db_method!(get_bsos, GetBsos);
     mock_db_method!(get_bso_ids, GetBsoIds);
+    mock_db_method!(get_bsos_count, GetBsosCount);
     mock_db_method!(post_bsos, PostBsos);
     mock_db_method!(delete_bso, DeleteBso);
     mock_db_method!(get_bso, GetBso, Option<results::GetBso>);
@@ -109,11 +132,17 @@ impl Db for MockDb {
     mock_db_method!(append_to_batch, AppendToBatch);
     mock_db_method!(get_batch, GetBatch, Option<results::GetBatch>);
     mock_db_method!(commit_batch, CommitBatch);
+    mock_db_method!(purge_expired, PurgeExpired);
+    mock_db_method!(purge_expired_bso, PurgeExpiredBso);
 
     fn get_connection_info(&self) -> results::ConnectionInfo {
         results::ConnectionInfo::default()
     }
 
+    fn query_count(&self) -> u64 {
+        0
+    }
+
     mock_db_method!(get_collection_id, GetCollectionId);
     mock_db_method!(create_collection, CreateCollection);
     mock_db_method!(update_collection, UpdateCollection);
@@ -0,0 +1,157 @@
+//! Purges of expired BSOs, which are otherwise only ever filtered out of
+//! reads, and of the collections they leave empty.
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use futures::{channel::mpsc, StreamExt};
+use syncstorage_db_common::{params, results, DbPool};
+
+use crate::DbError;
+
+/// Purge expired rows until none are left or `max_rows` of them were
+/// deleted, deleting up to `batch_size` rows of each table at a time.
+///
+/// Nothing's purged from databases expiring BSOs themselves (see
+/// `Db::get_native_ttl`).
+pub async fn purge_expired(
+    pool: &dyn DbPool<Error = DbError>,
+    batch_size: u32,
+    max_rows: u32,
+) -> Result<results::PurgeExpired, DbError> {
+    let _permit = pool.bulk_permit().await;
+    let db = pool.get().await?;
+    let mut purged = results::PurgeExpired::default();
+    if db.get_native_ttl().await?.bsos {
+        return Ok(purged);
+    }
+
+    let max_rows = u64::from(max_rows);
+    while purged.rows() < max_rows {
+        let limit = u64::from(batch_size.max(1)).min(max_rows - purged.rows()) as u32;
+        let batch = db.purge_expired(params::PurgeExpired { limit }).await?;
+        purged.bsos += batch.bsos;
+        purged.user_collections += batch.user_collections;
+        purged.payloads += batch.payloads;
+        // A table with rows left to purge would have filled its batch
+        if [batch.bsos, batch.user_collections, batch.payloads]
+            .iter()
+            .all(|rows| *rows < u64::from(limit))
+        {
+            break;
+        }
+    }
+    Ok(purged)
+}
+
+/// A write-behind queue of BSOs reads didn't find, likely because they
+/// expired: a background task purges those that did (see
+/// `purge_expired_bso`), keeping hot collections tidy between purges.
+///
+/// It's bounded: BSOs queued while it's full are dropped, left to the
+/// periodic purges.
+#[derive(Clone, Debug)]
+pub struct ExpiredBsoQueue {
+    sender: mpsc::UnboundedSender<params::PurgeExpiredBso>,
+    /// The number of BSOs waiting in the queue
+    queued: Arc<AtomicUsize>,
+    capacity: usize,
+}
+
+/// The receiving end of an `ExpiredBsoQueue`
+#[derive(Debug)]
+pub struct ExpiredBsoReceiver {
+    receiver: mpsc::UnboundedReceiver<params::PurgeExpiredBso>,
+    queued: Arc<AtomicUsize>,
+}
+
+impl ExpiredBsoQueue {
+    /// A queue holding up to `capacity` BSOs at a time
+    pub fn new(capacity: usize) -> (Self, ExpiredBsoReceiver) {
+        let (sender, receiver) = mpsc::unbounded();
+        let queued = Arc::new(AtomicUsize::new(0));
+        let queue = Self {
+            sender,
+            queued: Arc::clone(&queued),
+            capacity,
+        };
+        (queue, ExpiredBsoReceiver { receiver, queued })
+    }
+
+    /// Queue a BSO for purging, returning whether it was queued
+    pub fn push(&self, bso: params::PurgeExpiredBso) -> bool {
+        if self.queued.fetch_add(1, Ordering::Relaxed) >= self.capacity
+            || self.sender.unbounded_send(bso).is_err()
+        {
+            self.queued.fetch_sub(1, Ordering::Relaxed);
+            return false;
+        }
+        true
+    }
+}
+
+impl ExpiredBsoReceiver {
+    /// The next BSO queued, or `None` once all of the queue's senders are
+    /// gone
+    pub async fn next(&mut self) -> Option<params::PurgeExpiredBso> {
+        let bso = self.receiver.next().await;
+        if bso.is_some() {
+            self.queued.fetch_sub(1, Ordering::Relaxed);
+        }
+        bso
+    }
+}
+
+/// Delete a BSO if it has expired, in a transaction of its own, returning
+/// whether it was
+pub async fn purge_expired_bso(
+    pool: &dyn DbPool<Error = DbError>,
+    bso: params::PurgeExpiredBso,
+) -> Result<results::PurgeExpiredBso, DbError> {
+    let _permit = pool.bulk_permit().await;
+    let db = pool.get().await?;
+    db.begin(true).await?;
+    let purged = db.purge_expired_bso(bso).await?;
+    db.commit().await?;
+    Ok(purged)
+}
+
+#[cfg(test)]
+mod tests {
+    use syncstorage_db_common::UserIdentifier;
+
+    use super::*;
+
+    fn bso(id: &str) -> params::PurgeExpiredBso {
+        params::PurgeExpiredBso {
+            user_id: UserIdentifier::default(),
+            collection: "bookmarks".to_owned(),
+            id: id.to_owned(),
+        }
+    }
+
+    #[tokio::test]
+    async fn expired_bso_queue_is_bounded() {
+        let (queue, mut receiver) = ExpiredBsoQueue::new(2);
+        assert!(queue.push(bso("b0")));
+        assert!(queue.push(bso("b1")));
+        assert!(!queue.push(bso("b2")));
+
+        assert_eq!(
+            receiver.next().await.map(|bso| bso.id).as_deref(),
+            Some("b0")
+        );
+        assert!(queue.push(bso("b3")));
+        drop(queue);
+        assert_eq!(
+            receiver.next().await.map(|bso| bso.id).as_deref(),
+            Some("b1")
+        );
+        assert_eq!(
+            receiver.next().await.map(|bso| bso.id).as_deref(),
+            Some("b3")
+        );
+        assert!(receiver.next().await.is_none());
+    }
+}
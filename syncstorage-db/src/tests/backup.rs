@@ -0,0 +1,58 @@
+use lazy_static::lazy_static;
+use rand::{thread_rng, Rng};
+use syncstorage_db_common::util::SyncTimestamp;
+
+use super::support::{db_pool, dbso, hid, pbso, test_db};
+use crate::{
+    backup::{restore_user, snapshot_user},
+    DbError,
+};
+
+lazy_static! {
+    static ref UID: u32 = thread_rng().gen_range(0..10000);
+}
+
+#[tokio::test]
+async fn snapshot_and_restore_user() -> Result<(), DbError> {
+    let pool = db_pool(None).await?;
+    let db = test_db(pool).await?;
+
+    let uid = *UID;
+    with_delta!(db, -3000, {
+        db.put_bso(pbso(uid, "bookmarks", "b0", Some("p0"), Some(1), None))
+            .await?;
+        db.put_bso(pbso(uid, "tabs", "t0", Some("tabs"), None, Some(3600)))
+            .await
+    })?;
+    with_delta!(db, -2000, {
+        db.put_bso(pbso(uid, "bookmarks", "b1", Some("p1"), None, None))
+            .await
+    })?;
+    with_delta!(db, -1000, {
+        db.put_bso(pbso(uid, "bookmarks", "b2", Some("p2"), None, None))
+            .await?;
+        db.delete_bso(dbso(uid, "bookmarks", "b2")).await
+    })?;
+
+    let snapshot = snapshot_user(&*db, &hid(uid)).await?;
+    let names: Vec<_> = snapshot.collections.iter().map(|c| &c.name).collect();
+    assert_eq!(names, ["bookmarks", "tabs"]);
+    let bookmarks = &snapshot.collections[0];
+    let ids: Vec<_> = bookmarks.bsos.iter().map(|bso| &bso.id).collect();
+    assert_eq!(ids, ["b0", "b1"]);
+    // The delete moved the collection past its BSOs
+    assert!(bookmarks.modified > bookmarks.bsos[1].modified);
+    let timestamps = db.get_collection_timestamps(hid(uid)).await?;
+
+    db.delete_storage(hid(uid)).await?;
+    restore_user(&*db, &hid(uid), &snapshot).await?;
+
+    let restored = snapshot_user(&*db, &hid(uid)).await?;
+    for (collection, restored) in snapshot.collections.iter().zip(&restored.collections) {
+        assert_eq!(collection.name, restored.name);
+        assert_eq!(collection.modified, restored.modified);
+        assert_eq!(collection.bsos, restored.bsos);
+    }
+    assert_eq!(db.get_collection_timestamps(hid(uid)).await?, timestamps);
+    Ok(())
+}
@@ -130,10 +130,17 @@ async fn append_commit() -> Result<(), DbError> {
     let new_batch = db.create_batch(cb(uid, coll, bsos1)).await?;
 
     let bsos2 = vec![postbso("b2", Some("payload 2"), None, Some(1000))];
-    db.append_to_batch(ab(uid, coll, new_batch.clone(), bsos2))
+    let totals = db
+        .append_to_batch(ab(uid, coll, new_batch.clone(), bsos2))
         .await?;
+    let expected = results::BatchTotals {
+        records: 3,
+        bytes: 27,
+    };
+    assert_eq!(totals, expected);
 
     let batch = db.get_batch(gb(uid, coll, new_batch.id)).await?.unwrap();
+    assert_eq!(batch.totals, expected);
     let modified = db
         .commit_batch(params::CommitBatch {
             user_id: hid(uid),
@@ -159,6 +166,51 @@ async fn append_commit() -> Result<(), DbError> {
     Ok(())
 }
 
+#[tokio::test]
+async fn append_leaves_collections_untouched() -> Result<(), DbError> {
+    let pool = db_pool(None).await?;
+    let db = test_db(pool).await?;
+
+    let uid = 1;
+    let coll = "clients";
+    with_delta!(db, -1000, {
+        db.put_bso(pbso(uid, coll, "b0", Some("payload 0"), None, None))
+            .await
+    })?;
+    let timestamps = db.get_collection_timestamps(hid(uid)).await?;
+    let storage_timestamp = db.get_storage_timestamp(hid(uid)).await?;
+
+    // Batches of an existing collection and of one without any bsos yet
+    let mut batches = vec![];
+    for coll in &[coll, "tabs"] {
+        let bsos = vec![postbso("b1", Some("payload 1"), None, None)];
+        let batch = db.create_batch(cb(uid, coll, bsos)).await?;
+        for bid in &["b2", "b3"] {
+            let bsos = vec![postbso(bid, Some("payload"), None, None)];
+            db.append_to_batch(ab(uid, coll, batch.clone(), bsos))
+                .await?;
+        }
+        batches.push(batch);
+    }
+    assert_eq!(db.get_collection_timestamps(hid(uid)).await?, timestamps);
+    assert_eq!(db.get_storage_timestamp(hid(uid)).await?, storage_timestamp);
+
+    // Only the commit touches the collection
+    let batch = db.get_batch(gb(uid, coll, batches[0].id.clone())).await?;
+    let modified = db
+        .commit_batch(params::CommitBatch {
+            user_id: hid(uid),
+            collection: coll.to_owned(),
+            batch: batch.unwrap(),
+        })
+        .await?;
+    let timestamps = db.get_collection_timestamps(hid(uid)).await?;
+    assert_eq!(timestamps.get(coll), Some(&modified));
+    assert!(!timestamps.contains_key("tabs"));
+    assert_eq!(db.get_storage_timestamp(hid(uid)).await?, modified);
+    Ok(())
+}
+
 #[tokio::test]
 async fn quota_test_create_batch() -> Result<(), DbError> {
     let mut settings = Settings::test_settings().syncstorage;
@@ -1,15 +1,17 @@
 #![allow(clippy::cognitive_complexity)]
-use std::collections::HashMap;
+use std::{collections::HashMap, sync::Arc};
 
 use lazy_static::lazy_static;
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use syncserver_common::{BlockingThreadpool, CacheBackend, Metrics};
 use syncserver_settings::Settings;
 use syncstorage_db_common::{
-    error::DbErrorIntrospect, params, util::SyncTimestamp, Sorting, DEFAULT_BSO_TTL,
+    error::DbErrorIntrospect, params, util::SyncTimestamp, Db, DbPool, Projection, Sorting,
+    DEFAULT_BSO_TTL,
 };
 
 use super::support::{db_pool, dbso, dbsos, gbso, gbsos, hid, pbso, postbso, test_db};
-use crate::DbError;
+use crate::{DbError, DbPoolImpl};
 
 // distant future (year 2099) timestamp for tests
 const MAX_TIMESTAMP: u64 = 4_070_937_600_000;
@@ -226,6 +228,85 @@ async fn get_bsos_limit_offset() -> Result<(), DbError> {
     Ok(())
 }
 
+#[tokio::test]
+async fn get_bsos_offset_past_timestamp_ties() -> Result<(), DbError> {
+    let pool = db_pool(None).await?;
+    let db = test_db(pool).await?;
+
+    let uid = *UID;
+    let coll = "clients";
+    // Two BSOs to a timestamp, so pages end between BSOs modified together
+    for i in 0..6 {
+        let bso = pbso(
+            uid,
+            coll,
+            &format!("b{}", i),
+            Some("payload"),
+            None,
+            Some(DEFAULT_BSO_TTL),
+        );
+        with_delta!(&db, i64::from(i / 2) * 10, { db.put_bso(bso).await })?;
+    }
+
+    for (sort, expected) in [
+        (Sorting::Newest, ["b5", "b4", "b3", "b2", "b1", "b0"]),
+        (Sorting::Oldest, ["b0", "b1", "b2", "b3", "b4", "b5"]),
+    ] {
+        let mut ids = vec![];
+        let mut offset = "0".to_owned();
+        loop {
+            let bsos = db
+                .get_bsos(gbsos(uid, coll, &[], MAX_TIMESTAMP, 0, sort, 3, &offset))
+                .await?;
+            ids.extend(bsos.items.into_iter().map(|bso| bso.id));
+            match bsos.offset {
+                Some(next) => offset = next,
+                None => break,
+            }
+        }
+        assert_eq!(ids, expected);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn get_bsos_projections() -> Result<(), DbError> {
+    let pool = db_pool(None).await?;
+    let db = test_db(pool).await?;
+
+    let uid = *UID;
+    let coll = "clients";
+    db.put_bso(pbso(
+        uid,
+        coll,
+        "b0",
+        Some("a"),
+        Some(1),
+        Some(DEFAULT_BSO_TTL),
+    ))
+    .await?;
+
+    let get_bsos = |projection| {
+        let mut params = gbsos(uid, coll, &[], MAX_TIMESTAMP, 0, Sorting::Newest, 10, "0");
+        params.projection = projection;
+        db.get_bsos(params)
+    };
+
+    let bso = &get_bsos(Projection::Full).await?.items[0];
+    assert_eq!(bso.payload, "a");
+    assert_eq!(bso.sortindex, Some(1));
+
+    let bso = &get_bsos(Projection::NoSortindex).await?.items[0];
+    assert_eq!(bso.payload, "a");
+    assert_eq!(bso.sortindex, None);
+
+    let bsos = get_bsos(Projection::Meta).await?;
+    assert_eq!(bsos.items[0].id, "b0");
+    assert_eq!(bsos.items[0].payload, "");
+    assert_eq!(bsos.items[0].sortindex, None);
+    Ok(())
+}
+
 #[tokio::test]
 async fn get_bsos_newer() -> Result<(), DbError> {
     let pool = db_pool(None).await?;
@@ -384,6 +465,39 @@ async fn get_bsos_sort() -> Result<(), DbError> {
     Ok(())
 }
 
+#[tokio::test]
+async fn get_bsos_count() -> Result<(), DbError> {
+    let pool = db_pool(None).await?;
+    let db = test_db(pool).await?;
+
+    let uid = *UID;
+    let coll = "clients";
+    let count = |newer: Option<i64>, older: Option<i64>, ids: &[&str]| {
+        db.get_bsos_count(params::GetBsosCount {
+            user_id: hid(uid),
+            collection: coll.to_owned(),
+            newer: newer.map(|ts| SyncTimestamp::from_i64(ts).unwrap()),
+            older: older.map(|ts| SyncTimestamp::from_i64(ts).unwrap()),
+            ids: ids.iter().map(|id| id.to_string()).collect(),
+        })
+    };
+    assert_eq!(count(None, None, &[]).await?, 0);
+
+    let timestamp = db.timestamp().as_i64();
+    for i in 0..5 {
+        let bso = pbso(uid, coll, &format!("b{}", i), Some("payload"), None, None);
+        with_delta!(&db, i * 10, { db.put_bso(bso).await })?;
+    }
+    db.put_bso(pbso(uid, "crypto", "b0", Some("payload"), None, None))
+        .await?;
+
+    assert_eq!(count(None, None, &[]).await?, 5);
+    assert_eq!(count(Some(timestamp + 10), None, &[]).await?, 3);
+    assert_eq!(count(None, Some(timestamp + 10), &[]).await?, 1);
+    assert_eq!(count(None, None, &["b0", "b3", "missing"]).await?, 2);
+    Ok(())
+}
+
 #[tokio::test]
 async fn delete_bsos_in_correct_collection() -> Result<(), DbError> {
     let pool = db_pool(None).await?;
@@ -442,6 +556,52 @@ async fn create_collection() -> Result<(), DbError> {
     assert_ne!(cid, 0);
     let cid2 = db.get_collection_id(name.to_owned()).await?;
     assert_eq!(cid2, cid);
+
+    // Found in the db too, not only in the cache
+    db.clear_coll_cache().await?;
+    let cid3 = db.get_collection_id(name.to_owned()).await?;
+    assert_eq!(cid3, cid);
+    Ok(())
+}
+
+#[tokio::test]
+async fn create_collection_concurrently() -> Result<(), DbError> {
+    // Committed for real: racing test transactions would wait on each
+    // other's locks until they time out
+    let mut settings = Settings::test_settings().syncstorage;
+    settings.database_use_test_transactions = false;
+    settings.database_pool_max_size = 2;
+    let pool = DbPoolImpl::new(
+        &settings,
+        &Metrics::noop(),
+        Arc::new(BlockingThreadpool::default()),
+        &CacheBackend::Memory,
+    )?;
+
+    let name = format!("xxx_race_{}", thread_rng().gen_range(0..1_000_000));
+    let create = |db: Box<dyn Db<Error = DbError>>| {
+        let name = name.clone();
+        async move {
+            loop {
+                let result = async {
+                    db.begin(true).await?;
+                    let id = db.create_collection(name.clone()).await?;
+                    db.commit().await?;
+                    Ok(id)
+                }
+                .await;
+                match result {
+                    // Spanner may abort one of the racing transactions
+                    Err(e) if e.is_conflict() => {
+                        let _ = db.rollback().await;
+                    }
+                    result => return result,
+                }
+            }
+        }
+    };
+    let (id1, id2) = futures::join!(create(pool.get().await?), create(pool.get().await?));
+    assert_eq!(id1?, id2?);
     Ok(())
 }
 
@@ -497,6 +657,89 @@ async fn delete_collection() -> Result<(), DbError> {
     Ok(())
 }
 
+#[tokio::test]
+async fn delete_collections() -> Result<(), DbError> {
+    let pool = db_pool(None).await?;
+    let db = test_db(pool).await?;
+
+    let uid = *UID;
+    db.put_bso(pbso(uid, "clients", "b0", Some("test"), None, None))
+        .await?;
+    db.put_bso(pbso(uid, "crypto", "keys", Some("test"), None, None))
+        .await?;
+    db.put_bso(pbso(uid, "bookmarks", "b0", Some("test"), None, None))
+        .await?;
+
+    let result = db
+        .delete_collections(params::DeleteCollections {
+            user_id: hid(uid),
+            collections: ["clients", "crypto", "meta", "xxx_unknown"]
+                .iter()
+                .map(|coll| coll.to_string())
+                .collect(),
+        })
+        .await?;
+    let expected: HashMap<_, _> = [
+        ("clients", true),
+        ("crypto", true),
+        ("meta", false),
+        ("xxx_unknown", false),
+    ]
+    .iter()
+    .map(|(coll, deleted)| (coll.to_string(), *deleted))
+    .collect();
+    assert_eq!(result.collections, expected);
+    assert_eq!(result.modified, db.get_storage_timestamp(hid(uid)).await?);
+
+    let cols = db.get_collection_timestamps(hid(uid)).await?;
+    assert_eq!(cols.keys().collect::<Vec<_>>(), vec!["bookmarks"]);
+    assert!(db.get_bso(gbso(uid, "crypto", "keys")).await?.is_none());
+    Ok(())
+}
+
+#[tokio::test]
+async fn delete_collections_except() -> Result<(), DbError> {
+    let pool = db_pool(None).await?;
+    let db = test_db(pool).await?;
+
+    let uid = *UID;
+    db.put_bso(pbso(uid, "clients", "b0", Some("test"), None, None))
+        .await?;
+    db.put_bso(pbso(uid, "crypto", "keys", Some("test"), None, None))
+        .await?;
+    db.put_bso(pbso(uid, "meta", "global", Some("test"), None, None))
+        .await?;
+    db.put_bso(pbso(uid, "bookmarks", "b0", Some("test"), None, None))
+        .await?;
+
+    let result = db
+        .delete_collections_except(params::DeleteCollectionsExcept {
+            user_id: hid(uid),
+            keep: ["crypto", "meta", "xxx_unknown"]
+                .iter()
+                .map(|coll| coll.to_string())
+                .collect(),
+        })
+        .await?;
+    let expected: HashMap<_, _> = [("bookmarks", true), ("clients", true)]
+        .iter()
+        .map(|(coll, deleted)| (coll.to_string(), *deleted))
+        .collect();
+    assert_eq!(result.collections, expected);
+    assert_eq!(result.modified, db.get_storage_timestamp(hid(uid)).await?);
+
+    let mut cols: Vec<_> = db
+        .get_collection_timestamps(hid(uid))
+        .await?
+        .into_keys()
+        .collect();
+    cols.sort();
+    assert_eq!(cols, vec!["crypto", "meta"]);
+    assert!(db.get_bso(gbso(uid, "crypto", "keys")).await?.is_some());
+    assert!(db.get_bso(gbso(uid, "bookmarks", "b0")).await?.is_none());
+    Ok(())
+}
+
 #[tokio::test]
 async fn delete_collection_tombstone() -> Result<(), DbError> {
     let pool = db_pool(None).await?;
@@ -610,6 +853,117 @@ async fn get_collection_timestamps_tombstone() -> Result<(), DbError> {
     Ok(())
 }
 
+#[tokio::test]
+async fn get_usage_empty() -> Result<(), DbError> {
+    let pool = db_pool(None).await?;
+    let db = test_db(pool).await?;
+
+    let uid = *UID;
+    let coll = "bookmarks";
+    let collection_id = db.get_collection_id(coll.to_owned()).await?;
+    let check_empty = || async {
+        assert_eq!(db.get_storage_usage(hid(uid)).await?, 0);
+        assert!(db.get_collection_usage(hid(uid)).await?.is_empty());
+        let quota = db
+            .get_quota_usage(params::GetQuotaUsage {
+                user_id: hid(uid),
+                collection: coll.to_owned(),
+                collection_id,
+            })
+            .await?;
+        assert_eq!(quota.total_bytes, 0);
+        assert_eq!(quota.count, 0);
+        Ok::<_, DbError>(())
+    };
+
+    // A brand new user
+    check_empty().await?;
+
+    // A user whose storage was since emptied
+    db.put_bso(pbso(uid, coll, "b0", Some("payload"), None, None))
+        .await?;
+    db.delete_bso(dbso(uid, coll, "b0")).await?;
+    check_empty().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn storage_epoch() -> Result<(), DbError> {
+    let pool = db_pool(None).await?;
+    let db = test_db(pool).await?;
+
+    let uid = *UID;
+    assert_eq!(db.get_storage_epoch(hid(uid)).await?, None);
+    let epoch = db.bump_storage_epoch(hid(uid)).await?;
+    assert_eq!(epoch, db.timestamp());
+    assert_eq!(db.get_storage_epoch(hid(uid)).await?, Some(epoch));
+
+    let epoch = with_delta!(db, 1_000, { db.bump_storage_epoch(hid(uid)).await })?;
+    // The epoch outlives the storage it invalidates
+    db.delete_storage(hid(uid)).await?;
+    assert_eq!(db.get_storage_epoch(hid(uid)).await?, Some(epoch));
+    Ok(())
+}
+
+#[tokio::test]
+async fn keys_changed_at() -> Result<(), DbError> {
+    let pool = db_pool(None).await?;
+    let db = test_db(pool).await?;
+    if crate::DATABASE_SCHEME == "spanner" {
+        // Storage is keyed by the keys instead
+        return Ok(());
+    }
+
+    let uid = *UID;
+    let record = |keys_changed_at| params::RecordKeysChangedAt {
+        user_id: hid(uid),
+        keys_changed_at,
+    };
+    assert_eq!(db.get_keys_changed_at(hid(uid)).await?, None);
+    db.record_keys_changed_at(record(1_500_000_000_000)).await?;
+    assert_eq!(
+        db.get_keys_changed_at(hid(uid)).await?,
+        Some(1_500_000_000_000)
+    );
+    db.record_keys_changed_at(record(1_600_000_000_000)).await?;
+    // Older keys never replace newer ones
+    db.record_keys_changed_at(record(1_500_000_000_000)).await?;
+    db.delete_storage(hid(uid)).await?;
+    assert_eq!(
+        db.get_keys_changed_at(hid(uid)).await?,
+        Some(1_600_000_000_000)
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn locked_user() -> Result<(), DbError> {
+    let pool = db_pool(None).await?;
+    let db = test_db(pool).await?;
+
+    let uid = *UID;
+    let coll = "clients";
+    with_delta!(db, -1000, {
+        db.put_bso(pbso(uid, coll, "b0", Some("payload"), None, None))
+            .await
+    })?;
+    let epoch = db.bump_storage_epoch(hid(uid)).await?;
+    assert_eq!(db.locked_user(&hid(uid)), None);
+
+    // Read along with the write lock
+    db.lock_for_write(params::LockCollection {
+        user_id: hid(uid),
+        collection: coll.to_owned(),
+    })
+    .await?;
+    let locked = db.locked_user(&hid(uid)).expect("read with the write lock");
+    assert_eq!(locked.storage_epoch, Some(epoch));
+    // Forgotten once changed
+    db.bump_storage_epoch(hid(uid)).await?;
+    assert_eq!(db.locked_user(&hid(uid)), None);
+    Ok(())
+}
+
 #[tokio::test]
 async fn get_collection_usage() -> Result<(), DbError> {
     let pool = db_pool(None).await?;
@@ -704,6 +1058,65 @@ async fn test_quota() -> Result<(), DbError> {
     Ok(())
 }
 
+#[tokio::test]
+async fn post_bsos_quota_usage() -> Result<(), DbError> {
+    let settings = Settings::test_settings();
+
+    if !settings.syncstorage.enable_quota {
+        debug!("[test] Skipping test");
+        return Ok(());
+    }
+
+    let pool = db_pool(None).await?;
+    let mut db = test_db(pool).await?;
+
+    let uid = *UID;
+    let coll = "bookmarks";
+    db.set_quota(true, 1_000_000, true);
+
+    // The collection's touched once for the whole post: its usage must
+    // still account for every BSO
+    let result = db
+        .post_bsos(params::PostBsos {
+            user_id: hid(uid),
+            collection: coll.to_owned(),
+            bsos: vec![
+                postbso("b0", Some("payload 0"), None, None),
+                postbso("b1", Some("payload 1"), Some(1), None),
+                postbso("b1", Some("payload 1!"), None, None),
+                postbso("b2", Some("payload 2"), None, None),
+            ],
+            for_batch: false,
+            failed: Default::default(),
+        })
+        .await?;
+    assert_eq!(result.failed.len(), 0);
+
+    let collection_id = db.get_collection_id(coll.to_owned()).await?;
+    let quota = db
+        .get_quota_usage(params::GetQuotaUsage {
+            user_id: hid(uid),
+            collection: coll.to_owned(),
+            collection_id,
+        })
+        .await?;
+    assert_eq!(quota.count, 3);
+    assert_eq!(quota.total_bytes, 28);
+    // As reused by the web layer's quota warnings (when the backend keeps it)
+    if let Some(written) = db.written_quota_usage(&hid(uid), coll) {
+        assert_eq!(written.count, quota.count);
+        assert_eq!(written.total_bytes, quota.total_bytes);
+    }
+    let ts = db
+        .get_collection_timestamp(params::GetCollectionTimestamp {
+            user_id: hid(uid),
+            collection: coll.to_owned(),
+        })
+        .await?;
+    assert_eq!(result.modified, ts);
+    Ok(())
+}
+
 #[tokio::test]
 async fn get_collection_counts() -> Result<(), DbError> {
     let pool = db_pool(None).await?;
@@ -727,6 +1140,56 @@ async fn get_collection_counts() -> Result<(), DbError> {
     Ok(())
 }
 
+#[tokio::test]
+async fn expiry_boundary() -> Result<(), DbError> {
+    let pool = db_pool(None).await?;
+    let db = test_db(pool).await?;
+
+    let uid = *UID;
+    let coll = "clients";
+    // Expires exactly at the current timestamp
+    with_delta!(db, -1000, {
+        db.put_bso(pbso(uid, coll, "expired", Some("expired"), None, Some(1)))
+            .await
+    })?;
+    db.put_bso(pbso(uid, coll, "live", Some("live"), None, None))
+        .await?;
+
+    // Gone from every read path alike
+    assert!(db.get_bso(gbso(uid, coll, "expired")).await?.is_none());
+    let bsos = db
+        .get_bsos(gbsos(
+            uid,
+            coll,
+            &[],
+            MAX_TIMESTAMP,
+            0,
+            Sorting::Index,
+            10,
+            "0",
+        ))
+        .await?;
+    let ids: Vec<_> = bsos.items.iter().map(|bso| bso.id.as_str()).collect();
+    assert_eq!(ids, ["live"]);
+    let count = db
+        .get_bsos_count(params::GetBsosCount {
+            user_id: hid(uid),
+            collection: coll.to_owned(),
+            newer: None,
+            older: None,
+            ids: vec![],
+        })
+        .await?;
+    assert_eq!(count, 1);
+    let counts = db.get_collection_counts(hid(uid)).await?;
+    assert_eq!(counts.get(coll), Some(&1));
+    let usage = db.get_collection_usage(hid(uid)).await?;
+    assert_eq!(usage.get(coll), Some(&("live".len() as i64)));
+    let result = db.delete_bso(dbso(uid, coll, "expired")).await;
+    assert!(result.unwrap_err().is_bso_not_found());
+    Ok(())
+}
+
 #[tokio::test]
 async fn put_bso() -> Result<(), DbError> {
     let pool = db_pool(None).await?;
@@ -1009,13 +1472,6 @@ async fn usage_stats() -> Result<(), DbError> {
     Ok(())
 }
 
-#[tokio::test]
-async fn purge_expired() -> Result<(), DbError> {
-    let pool = db_pool(None).await?;
-    let db = test_db(pool).await?;
-    Ok(())
-}
-
 #[tokio::test]
 async fn optimize() -> Result<(), DbError> {
     let pool = db_pool(None).await?;
@@ -1083,7 +1539,9 @@ async fn lock_for_read() -> Result<(), DbError> {
         collection: coll.to_owned(),
     })
     .await?;
-    let result = db.get_collection_id("NewCollection".to_owned()).await;
+    // Other tests' collections are committed: pick one none of them create
+    let missing = format!("xxx_missing_{}", thread_rng().gen_range(0..1_000_000));
+    let result = db.get_collection_id(missing).await;
     assert!(result.unwrap_err().is_collection_not_found());
     db.commit().await?;
     Ok(())
@@ -1115,3 +1573,177 @@ async fn heartbeat() -> Result<(), DbError> {
     assert!(db.check().await?);
     Ok(())
 }
+
+#[tokio::test]
+async fn database_stats() -> Result<(), DbError> {
+    let pool = db_pool(None).await?;
+    let db = test_db(pool).await?;
+
+    let uid = *UID;
+    let coll = "clients";
+    let before = db.get_database_stats().await?;
+    db.put_bso(pbso(uid, coll, "stats0", Some("live"), None, None))
+        .await?;
+    with_delta!(db, -(3600 * 1000), {
+        db.put_bso(pbso(uid, coll, "stats1", Some("expired"), None, Some(1)))
+            .await
+    })?;
+
+    let stats = db.get_database_stats().await?;
+    assert_eq!(stats.bsos, before.bsos + 2);
+    assert_eq!(stats.expired_bsos, before.expired_bsos + 1);
+    assert!(stats.user_collections > 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn purge_expired() -> Result<(), DbError> {
+    let pool = db_pool(None).await?;
+    let db = test_db(pool).await?;
+    if crate::DATABASE_SCHEME == "spanner" {
+        // Purged by purge_ttl instead
+        return Ok(());
+    }
+
+    let uid = *UID;
+    with_delta!(db, -(7200 * 1000), {
+        db.put_bso(pbso(uid, "kept", "live", Some("live"), None, None))
+            .await?;
+        db.put_bso(pbso(uid, "kept", "expired", Some("expired"), None, Some(1)))
+            .await
+    })?;
+    with_delta!(db, -(3600 * 1000), {
+        db.put_bso(pbso(
+            uid,
+            "purged",
+            "expired",
+            Some("expired"),
+            None,
+            Some(1),
+        ))
+        .await
+    })?;
+    let modified = db.get_storage_timestamp(hid(uid)).await?;
+
+    // Purges across all users: other rows of the test database may go too
+    let limit = 1_000;
+    loop {
+        let purged = db.purge_expired(params::PurgeExpired { limit }).await?;
+        if purged.bsos < u64::from(limit) && purged.user_collections < u64::from(limit) {
+            break;
+        }
+    }
+
+    let collections = db.get_collection_timestamps(hid(uid)).await?;
+    assert!(collections.contains_key("kept"));
+    assert!(!collections.contains_key("purged"));
+    // Nothing the user can read changed
+    assert_eq!(db.get_storage_timestamp(hid(uid)).await?, modified);
+    let counts = db.get_collection_counts(hid(uid)).await?;
+    assert_eq!(counts.get("kept"), Some(&1));
+    Ok(())
+}
+
+#[tokio::test]
+async fn purge_expired_bso() -> Result<(), DbError> {
+    let pool = db_pool(None).await?;
+    let db = test_db(pool).await?;
+
+    let uid = *UID;
+    let coll = "bookmarks";
+    with_delta!(db, -(3600 * 1000), {
+        db.put_bso(pbso(uid, coll, "expired", Some("expired"), None, Some(1)))
+            .await
+    })?;
+    db.put_bso(pbso(uid, coll, "live", Some("live"), None, None))
+        .await?;
+    let collection_timestamp = params::GetCollectionTimestamp {
+        user_id: hid(uid),
+        collection: coll.to_owned(),
+    };
+    let modified = db
+        .get_collection_timestamp(collection_timestamp.clone())
+        .await?;
+
+    let purge = |coll: &str, id: &str| params::PurgeExpiredBso {
+        user_id: hid(uid),
+        collection: coll.to_owned(),
+        id: id.to_owned(),
+    };
+    assert!(db.purge_expired_bso(purge(coll, "expired")).await?);
+    assert!(!db.purge_expired_bso(purge(coll, "expired")).await?);
+    assert!(!db.purge_expired_bso(purge(coll, "live")).await?);
+    assert!(!db.purge_expired_bso(purge(coll, "missing")).await?);
+    assert!(
+        !db.purge_expired_bso(purge("nonexistent", "expired"))
+            .await?
+    );
+
+    assert!(db.get_bso(gbso(uid, coll, "live")).await?.is_some());
+    // Nothing the user can read changed
+    assert_eq!(
+        db.get_collection_timestamp(collection_timestamp).await?,
+        modified
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn native_ttl() -> Result<(), DbError> {
+    let pool = db_pool(None).await?;
+    let db = test_db(pool).await?;
+
+    // Neither backend's test schema delegates expiry to the database
+    let native_ttl = db.get_native_ttl().await?;
+    assert!(!native_ttl.bsos);
+    assert!(!native_ttl.batches);
+    Ok(())
+}
+
+#[tokio::test]
+async fn deduplicated_payloads() -> Result<(), DbError> {
+    let mut settings = Settings::test_settings().syncstorage;
+    settings.payload_dedup_min_size = Some(8);
+    let pool = db_pool(Some(settings)).await?;
+    let db = test_db(pool).await?;
+
+    let uid = *UID;
+    let coll = "bookmarks";
+    let shared = "a payload shared by many users";
+    db.put_bso(pbso(uid, coll, "b0", Some(shared), None, None))
+        .await?;
+    db.put_bso(pbso(uid + 1, coll, "b0", Some(shared), None, None))
+        .await?;
+    db.put_bso(pbso(uid, coll, "b1", Some("small"), None, None))
+        .await?;
+
+    for user_id in [uid, uid + 1] {
+        let bso = db.get_bso(gbso(user_id, coll, "b0")).await?.unwrap();
+        assert_eq!(bso.payload, shared);
+    }
+    let bsos = db
+        .get_bsos(gbsos(
+            uid,
+            coll,
+            &[],
+            MAX_TIMESTAMP,
+            0,
+            Sorting::Index,
+            10,
+            "0",
+        ))
+        .await?;
+    let mut payloads: Vec<_> = bsos.items.into_iter().map(|bso| bso.payload).collect();
+    payloads.sort();
+    assert_eq!(payloads, vec![shared.to_owned(), "small".to_owned()]);
+
+    let sizes = db.get_collection_usage(hid(uid)).await?;
+    assert_eq!(sizes[coll], (shared.len() + "small".len()) as i64);
+
+    // Overwriting a deduplicated payload with an inline one
+    db.put_bso(pbso(uid, coll, "b0", Some("small"), None, None))
+        .await?;
+    let bso = db.get_bso(gbso(uid, coll, "b0")).await?.unwrap();
+    assert_eq!(bso.payload, "small");
+    Ok(())
+}
@@ -2,6 +2,8 @@
 #[macro_use]
 pub mod support;
 
+#[cfg(test)]
+mod backup;
 #[cfg(test)]
 pub mod batch;
 #[cfg(test)]
@@ -1,8 +1,10 @@
 use std::{str::FromStr, sync::Arc};
 
-use syncserver_common::{BlockingThreadpool, Metrics};
+use syncserver_common::{BlockingThreadpool, CacheBackend, Metrics};
 use syncserver_settings::Settings as SyncserverSettings;
-use syncstorage_db_common::{params, util::SyncTimestamp, Db, DbPool, Sorting, UserIdentifier};
+use syncstorage_db_common::{
+    params, util::SyncTimestamp, Db, DbPool, Projection, Sorting, UserIdentifier,
+};
 use syncstorage_settings::Settings as SyncstorageSettings;
 
 use crate::{DbError, DbPoolImpl};
@@ -21,9 +23,15 @@ pub async fn db_pool(settings: Option<SyncstorageSettings>) -> Result<DbPoolImpl
     // inherit SYNC_SYNCSTORAGE__DATABASE_URL from the env
     let mut settings = settings.unwrap_or_else(|| SyncserverSettings::test_settings().syncstorage);
     settings.database_use_test_transactions = use_test_transactions;
+    crate::assert_test_database_url(&settings.database_url);
 
     let metrics = Metrics::noop();
-    let pool = DbPoolImpl::new(&settings, &metrics, Arc::new(BlockingThreadpool::default()))?;
+    let pool = DbPoolImpl::new(
+        &settings,
+        &metrics,
+        Arc::new(BlockingThreadpool::default()),
+        &CacheBackend::Memory,
+    )?;
     Ok(pool)
 }
 
@@ -106,6 +114,8 @@ pub fn gbsos(
         limit: Some(limit as u32),
         offset: Some(params::Offset::from_str(offset).unwrap_or_default()),
         full: true,
+        projection: Projection::Full,
+        max_payload_bytes: None,
     }
 }
 
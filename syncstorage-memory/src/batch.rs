@@ -0,0 +1,205 @@
+use std::collections::HashMap;
+
+use base64::Engine;
+
+use syncstorage_db_common::{params, results, BATCH_LIFETIME};
+
+use super::{
+    error::DbError,
+    models::{check_bso_size, MemoryDb},
+    store::{BatchItem, BatchUpload, UserData},
+    DbResult,
+};
+
+pub fn create(db: &MemoryDb, params: params::CreateBatch) -> DbResult<results::CreateBatch> {
+    let user_id = params.user_id.legacy_id;
+    let collection_id = db.get_collection_id(&params.collection)?;
+    for bso in &params.bsos {
+        check_bso_size(&bso.id)?;
+    }
+    // Like the other backends' batch ids: a millisecond timestamp with the
+    // uid's lowest digit mixed in (see the MySQL backend for why)
+    let batch_id = db.timestamp().as_i64() + (user_id % 10) as i64;
+    db.write(user_id, |data| {
+        if data.batches.contains_key(&batch_id) {
+            // The user tried to create two batches with the same timestamp
+            return Err(DbError::conflict());
+        }
+        let mut batch = BatchUpload {
+            collection_id,
+            items: HashMap::new(),
+            totals: results::BatchTotals::default(),
+        };
+        do_append(&mut batch, params.bsos);
+        data.batches.insert(batch_id, batch);
+        Ok(())
+    })?;
+    Ok(results::CreateBatch {
+        id: encode_id(batch_id),
+        size: None,
+    })
+}
+
+pub fn validate(db: &MemoryDb, params: params::ValidateBatch) -> DbResult<bool> {
+    let batch_id = decode_id(&params.id)?;
+    // Batches that are obviously too old don't count even before they're
+    // purged. Recall that the batchid is a millisecond timestamp.
+    if (batch_id + BATCH_LIFETIME) < db.timestamp().as_i64() {
+        return Ok(false);
+    }
+
+    let collection_id = db.get_collection_id(&params.collection)?;
+    Ok(db.read(params.user_id.legacy_id, |data| {
+        find(data, batch_id, collection_id).is_some()
+    }))
+}
+
+pub fn append(db: &MemoryDb, params: params::AppendToBatch) -> DbResult<results::AppendToBatch> {
+    let exists = validate(
+        db,
+        params::ValidateBatch {
+            user_id: params.user_id.clone(),
+            collection: params.collection.clone(),
+            id: params.batch.id.clone(),
+        },
+    )?;
+
+    if !exists {
+        return Err(DbError::batch_not_found());
+    }
+    for bso in &params.bsos {
+        check_bso_size(&bso.id)?;
+    }
+
+    let batch_id = decode_id(&params.batch.id)?;
+    db.write(params.user_id.legacy_id, |data| {
+        let batch = data
+            .batches
+            .get_mut(&batch_id)
+            .ok_or_else(DbError::batch_not_found)?;
+        do_append(batch, params.bsos);
+        Ok(batch.totals)
+    })
+}
+
+pub fn get(db: &MemoryDb, params: params::GetBatch) -> DbResult<Option<results::GetBatch>> {
+    let batch_id = decode_id(&params.id)?;
+    // See validate
+    if (batch_id + BATCH_LIFETIME) < db.timestamp().as_i64() {
+        return Ok(None);
+    }
+
+    let collection_id = db.get_collection_id(&params.collection)?;
+    let totals = db.read(params.user_id.legacy_id, |data| {
+        find(data, batch_id, collection_id).map(|batch| batch.totals)
+    });
+    Ok(totals.map(|totals| results::GetBatch {
+        id: params.id,
+        totals,
+    }))
+}
+
+pub fn delete(db: &MemoryDb, params: params::DeleteBatch) -> DbResult<()> {
+    let batch_id = decode_id(&params.id)?;
+    let collection_id = db.get_collection_id(&params.collection)?;
+    db.write(params.user_id.legacy_id, |data| {
+        if find(data, batch_id, collection_id).is_some() {
+            data.batches.remove(&batch_id);
+        }
+        Ok(())
+    })
+}
+
+/// Commits a batch to the user's bsos, deleting the batch when succesful.
+///
+/// Like a POST, the fields the batch didn't set are left as is on existing
+/// bsos
+pub fn commit(db: &MemoryDb, params: params::CommitBatch) -> DbResult<results::CommitBatch> {
+    let batch_id = decode_id(&params.batch.id)?;
+    let collection_id = db.get_collection_id(&params.collection)?;
+    let timestamp = db.timestamp();
+    db.write(params.user_id.legacy_id, |data| {
+        let batch = if find(data, batch_id, collection_id).is_some() {
+            data.batches.remove(&batch_id)
+        } else {
+            None
+        };
+        for (id, item) in batch.into_iter().flat_map(|batch| batch.items) {
+            data.write_bso(
+                collection_id,
+                params::PostCollectionBso {
+                    id: id.clone(),
+                    sortindex: item.sortindex,
+                    payload: item.payload,
+                    ttl: item.ttl_offset,
+                },
+                timestamp,
+            );
+            // Even those of its bsos only setting a ttl are modified by the
+            // commit
+            if let Some(bso) = data
+                .bsos
+                .get_mut(&collection_id)
+                .and_then(|bsos| bsos.get_mut(&id))
+            {
+                bso.modified = timestamp;
+            }
+        }
+        Ok(())
+    })?;
+
+    db.update_collection(params.user_id.legacy_id, collection_id)?;
+    Ok(timestamp)
+}
+
+/// Appends bsos to a batch, merging the fields they set into those of the
+/// same ids appended before
+fn do_append(batch: &mut BatchUpload, bsos: Vec<params::PostCollectionBso>) {
+    batch.totals.records += bsos.len() as u64;
+    for bso in bsos {
+        batch.totals.bytes += bso.payload.as_ref().map_or(0, |p| p.len() as u64);
+        let item = batch.items.entry(bso.id).or_insert_with(BatchItem::default);
+        if bso.sortindex.is_some() {
+            item.sortindex = bso.sortindex;
+        }
+        if bso.payload.is_some() {
+            item.payload = bso.payload;
+        }
+        if bso.ttl.is_some() {
+            item.ttl_offset = bso.ttl;
+        }
+    }
+}
+
+/// The user's batch of the collection
+fn find(data: &UserData, batch_id: i64, collection_id: i32) -> Option<&BatchUpload> {
+    data.batches
+        .get(&batch_id)
+        .filter(|batch| batch.collection_id == collection_id)
+}
+
+pub fn validate_batch_id(id: &str) -> DbResult<()> {
+    decode_id(id).map(|_| ())
+}
+
+fn encode_id(id: i64) -> String {
+    base64::engine::general_purpose::STANDARD.encode(id.to_string())
+}
+
+fn decode_id(id: &str) -> DbResult<i64> {
+    let bytes = base64::engine::general_purpose::STANDARD
+        .decode(id)
+        .unwrap_or_else(|_| id.as_bytes().to_vec());
+    let decoded = std::str::from_utf8(&bytes).unwrap_or(id);
+    decoded
+        .parse::<i64>()
+        .map_err(|e| DbError::internal(format!("Invalid batch_id: {}", e)))
+}
+
+macro_rules! batch_db_method {
+    ($name:ident, $batch_name:ident, $type:ident) => {
+        pub fn $name(&self, params: params::$type) -> DbResult<results::$type> {
+            batch::$batch_name(self, params)
+        }
+    };
+}
@@ -0,0 +1,123 @@
+use std::fmt;
+
+use backtrace::Backtrace;
+use http::StatusCode;
+use syncserver_common::{from_error, impl_fmt_display, InternalError, ReportableError};
+use syncstorage_db_common::error::{DbErrorIntrospect, SyncstorageDbError};
+use thiserror::Error;
+
+/// An error type that represents any errors that may occur while processing a syncstorage
+/// request against the in memory database. Having no database server to fail, they're all
+/// application-specific.
+#[derive(Debug)]
+pub struct DbError {
+    kind: DbErrorKind,
+    pub status: StatusCode,
+    pub backtrace: Box<Backtrace>,
+}
+
+impl DbError {
+    pub fn batch_not_found() -> Self {
+        DbErrorKind::Common(SyncstorageDbError::batch_not_found()).into()
+    }
+
+    pub fn bso_not_found() -> Self {
+        DbErrorKind::Common(SyncstorageDbError::bso_not_found()).into()
+    }
+
+    pub fn collection_not_found() -> Self {
+        DbErrorKind::Common(SyncstorageDbError::collection_not_found()).into()
+    }
+
+    pub fn conflict() -> Self {
+        DbErrorKind::Common(SyncstorageDbError::conflict()).into()
+    }
+
+    pub fn internal(msg: String) -> Self {
+        DbErrorKind::Common(SyncstorageDbError::internal(msg)).into()
+    }
+
+    pub fn quota() -> Self {
+        DbErrorKind::Common(SyncstorageDbError::quota()).into()
+    }
+
+    pub fn invalid_value(msg: String) -> Self {
+        DbErrorKind::Common(SyncstorageDbError::invalid_value(msg)).into()
+    }
+}
+
+#[derive(Debug, Error)]
+enum DbErrorKind {
+    #[error("{}", _0)]
+    Common(SyncstorageDbError),
+}
+
+impl From<DbErrorKind> for DbError {
+    fn from(kind: DbErrorKind) -> Self {
+        let DbErrorKind::Common(dbe) = &kind;
+        Self {
+            status: dbe.status,
+            backtrace: Box::new(dbe.backtrace.clone()),
+            kind,
+        }
+    }
+}
+
+impl DbErrorIntrospect for DbError {
+    fn is_batch_not_found(&self) -> bool {
+        matches!(&self.kind, DbErrorKind::Common(e) if e.is_batch_not_found())
+    }
+
+    fn is_bso_not_found(&self) -> bool {
+        matches!(&self.kind, DbErrorKind::Common(e) if e.is_bso_not_found())
+    }
+
+    fn is_collection_not_found(&self) -> bool {
+        matches!(&self.kind, DbErrorKind::Common(e) if e.is_collection_not_found())
+    }
+
+    fn is_conflict(&self) -> bool {
+        matches!(&self.kind, DbErrorKind::Common(e) if e.is_conflict())
+    }
+
+    fn is_lock_timeout(&self) -> bool {
+        matches!(&self.kind, DbErrorKind::Common(e) if e.is_lock_timeout())
+    }
+
+    fn is_quota(&self) -> bool {
+        matches!(&self.kind, DbErrorKind::Common(e) if e.is_quota())
+    }
+
+    fn is_invalid_value(&self) -> bool {
+        matches!(&self.kind, DbErrorKind::Common(e) if e.is_invalid_value())
+    }
+
+    fn is_too_large(&self) -> bool {
+        matches!(&self.kind, DbErrorKind::Common(e) if e.is_too_large())
+    }
+}
+
+impl ReportableError for DbError {
+    fn is_sentry_event(&self) -> bool {
+        matches!(&self.kind, DbErrorKind::Common(e) if e.is_sentry_event())
+    }
+
+    fn metric_label(&self) -> Option<String> {
+        let DbErrorKind::Common(e) = &self.kind;
+        e.metric_label()
+    }
+
+    fn error_backtrace(&self) -> String {
+        format!("{:#?}", self.backtrace)
+    }
+}
+
+impl InternalError for DbError {
+    fn internal_error(message: String) -> Self {
+        DbErrorKind::Common(SyncstorageDbError::internal(message)).into()
+    }
+}
+
+impl_fmt_display!(DbError, DbErrorKind);
+
+from_error!(SyncstorageDbError, DbError, DbErrorKind::Common);
@@ -0,0 +1,17 @@
+#[macro_use]
+extern crate slog_scope;
+
+#[macro_use]
+mod batch;
+mod error;
+mod models;
+mod pool;
+mod store;
+#[cfg(test)]
+mod test;
+
+pub use error::DbError;
+pub use models::MemoryDb;
+pub use pool::MemoryDbPool;
+
+pub(crate) type DbResult<T> = Result<T, error::DbError>;
@@ -0,0 +1,128 @@
+use async_trait::async_trait;
+
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+};
+
+use syncserver_common::{BlockingThreadpool, CacheBackend, Metrics};
+use syncserver_db_common::{GetPoolState, PoolState};
+use syncstorage_db_common::{results, Db, DbPool};
+use syncstorage_settings::{Quota, Settings};
+
+use super::{error::DbError, models::MemoryDb, store::Store, DbResult};
+
+/// Whether `database_url` selects the in memory database: `memory://`
+fn is_memory_url(database_url: &str) -> bool {
+    database_url == "memory://"
+}
+
+#[derive(Clone)]
+pub struct MemoryDbPool {
+    /// The database: shared by the pool's clones, gone along with the last
+    /// of them
+    store: Arc<RwLock<Store>>,
+    /// The id of the next Db handed out
+    next_id: Arc<AtomicU64>,
+
+    metrics: Metrics,
+    quota: Quota,
+}
+
+impl MemoryDbPool {
+    /// Creates a new, empty, in memory database.
+    ///
+    /// Each pool has its own database, which doesn't outlive it: it's meant
+    /// for tests and ephemeral (e.g. local development) servers. There being
+    /// no connections to a database server, the pool settings are ignored,
+    /// as are migrations, the collection cache and the timeouts.
+    pub fn new(
+        settings: &Settings,
+        metrics: &Metrics,
+        _blocking_threadpool: Arc<BlockingThreadpool>,
+        _cache_backend: &CacheBackend,
+    ) -> DbResult<Self> {
+        if !is_memory_url(&settings.database_url) {
+            return Err(DbError::internal(format!(
+                "Invalid in memory database_url: {}",
+                settings.database_url
+            )));
+        }
+        Ok(Self {
+            store: Arc::new(RwLock::new(Store::default())),
+            next_id: Arc::new(AtomicU64::new(1)),
+            metrics: metrics.clone(),
+            quota: Quota::from(settings),
+        })
+    }
+
+    pub fn get_sync(&self) -> MemoryDb {
+        MemoryDb::new(
+            self.next_id.fetch_add(1, Ordering::Relaxed),
+            Arc::clone(&self.store),
+            &self.metrics,
+            &self.quota,
+        )
+    }
+}
+
+#[async_trait]
+impl DbPool for MemoryDbPool {
+    type Error = DbError;
+
+    async fn get<'a>(&'a self) -> DbResult<Box<dyn Db<Error = Self::Error>>> {
+        Ok(Box::new(self.get_sync()) as Box<dyn Db<Error = Self::Error>>)
+    }
+
+    fn validate_batch_id(&self, id: String) -> DbResult<()> {
+        super::batch::validate_batch_id(&id)
+    }
+
+    /// Collections are only ever looked up in the store, so there are no
+    /// cache hits or misses to report: just its count of collections
+    fn collection_cache_stats(&self) -> results::CollectionCacheStats {
+        let store = self.store.read().unwrap_or_else(|e| e.into_inner());
+        results::CollectionCacheStats {
+            entries: store.collection_count() as u64,
+            ..Default::default()
+        }
+    }
+
+    async fn warm_up(&self) -> DbResult<usize> {
+        // Nothing to connect to
+        Ok(0)
+    }
+
+    fn box_clone(&self) -> Box<dyn DbPool<Error = Self::Error>> {
+        Box::new(self.clone())
+    }
+}
+
+impl fmt::Debug for MemoryDbPool {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("MemoryDbPool").finish()
+    }
+}
+
+impl GetPoolState for MemoryDbPool {
+    /// There are no connections to report
+    fn state(&self) -> PoolState {
+        PoolState::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_urls() {
+        assert!(is_memory_url("memory://"));
+        assert!(!is_memory_url("memory://syncstorage"));
+        assert!(!is_memory_url("sqlite://:memory:"));
+        assert!(!is_memory_url("mysql://root@127.0.0.1/syncstorage"));
+    }
+}
@@ -0,0 +1,277 @@
+//! The data of an in memory database, shared by the `MemoryDb`s of a
+//! `MemoryDbPool`. Where the other backends have tables, it has maps: users'
+//! data is keyed by their `legacy_id`, then by collection id.
+use std::collections::HashMap;
+
+use syncstorage_db_common::{
+    is_expired, params, results, util::SyncTimestamp, DEFAULT_BSO_TTL, FIRST_CUSTOM_COLLECTION_ID,
+    STD_COLLS,
+};
+
+/// The id of the pseudo collection whose `modified` is when the user's
+/// collections were last deleted, moving their storage timestamp forward
+pub const TOMBSTONE: i32 = 0;
+
+#[derive(Debug)]
+pub struct Store {
+    /// Collection ids by name. Like the other backends' `collections` table,
+    /// they're shared by all users and created outside of transactions
+    collection_ids: HashMap<String, i32>,
+    collection_names: HashMap<i32, String>,
+    next_collection_id: i32,
+    pub users: HashMap<u64, UserData>,
+    /// The id of the `MemoryDb` whose transaction holds each user's write
+    /// lock
+    pub writers: HashMap<u64, u64>,
+}
+
+impl Default for Store {
+    fn default() -> Self {
+        Self {
+            collection_ids: STD_COLLS
+                .iter()
+                .map(|(id, name)| ((*name).to_owned(), *id))
+                .collect(),
+            collection_names: STD_COLLS
+                .iter()
+                .map(|(id, name)| (*id, (*name).to_owned()))
+                .collect(),
+            next_collection_id: FIRST_CUSTOM_COLLECTION_ID,
+            users: HashMap::new(),
+            writers: HashMap::new(),
+        }
+    }
+}
+
+impl Store {
+    pub fn collection_id(&self, name: &str) -> Option<i32> {
+        self.collection_ids.get(name).copied()
+    }
+
+    pub fn collection_name(&self, id: i32) -> Option<&str> {
+        self.collection_names.get(&id).map(String::as_str)
+    }
+
+    pub fn get_or_create_collection_id(&mut self, name: &str) -> i32 {
+        if let Some(id) = self.collection_id(name) {
+            return id;
+        }
+        let id = self.next_collection_id;
+        self.next_collection_id += 1;
+        self.collection_ids.insert(name.to_owned(), id);
+        self.collection_names.insert(id, name.to_owned());
+        id
+    }
+
+    pub fn collection_count(&self) -> usize {
+        self.collection_ids.len()
+    }
+}
+
+/// Everything stored of a user
+#[derive(Clone, Debug, Default)]
+pub struct UserData {
+    /// The user's collections (the `TOMBSTONE` included)
+    pub collections: HashMap<i32, UserCollection>,
+    /// The user's BSOs, by collection then BSO id
+    pub bsos: HashMap<i32, HashMap<String, Bso>>,
+    /// The user's uncommitted batches, by batch id
+    pub batches: HashMap<i64, BatchUpload>,
+    /// When the user's storage was last reset, if ever (kept by
+    /// `delete_storage`)
+    pub epoch: Option<SyncTimestamp>,
+    /// When the keys the user's clients write with last changed, if recorded
+    /// (also kept by `delete_storage`)
+    pub keys_changed_at: Option<i64>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct UserCollection {
+    pub modified: SyncTimestamp,
+    /// Usage counters, only maintained when quota or
+    /// `collection_usage_counters` are enabled
+    pub count: i32,
+    pub total_bytes: i64,
+}
+
+#[derive(Clone, Debug)]
+pub struct Bso {
+    pub modified: SyncTimestamp,
+    pub payload: String,
+    pub sortindex: Option<i32>,
+    /// In milliseconds, like `modified`
+    pub expiry: i64,
+}
+
+#[derive(Clone, Debug)]
+pub struct BatchUpload {
+    pub collection_id: i32,
+    /// The BSO fields appended so far, merged per BSO id
+    pub items: HashMap<String, BatchItem>,
+    pub totals: results::BatchTotals,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct BatchItem {
+    pub sortindex: Option<i32>,
+    pub payload: Option<String>,
+    /// The ttl in seconds, added to the batch's commit timestamp
+    pub ttl_offset: Option<u32>,
+}
+
+impl UserData {
+    /// The latest `modified` of the user's collections (0 when they have
+    /// none)
+    pub fn storage_timestamp(&self) -> SyncTimestamp {
+        self.collections
+            .values()
+            .map(|collection| collection.modified)
+            .max_by_key(|modified| modified.as_i64())
+            .unwrap_or_else(|| SyncTimestamp::from_milliseconds(0))
+    }
+
+    /// The BSOs of a collection that haven't expired as of `now`
+    pub fn live_bsos(&self, collection_id: i32, now: i64) -> impl Iterator<Item = (&String, &Bso)> {
+        self.bsos
+            .get(&collection_id)
+            .into_iter()
+            .flatten()
+            .filter(move |(_, bso)| !is_expired(bso.expiry, now))
+    }
+
+    /// Upsert a BSO, only updating the fields it sets (and `modified`
+    /// along with its payload or sortindex) of an existing one
+    pub fn write_bso(
+        &mut self,
+        collection_id: i32,
+        bso: params::PostCollectionBso,
+        timestamp: SyncTimestamp,
+    ) {
+        // remember: this is in millis
+        let expiry = |ttl: u32| timestamp.as_i64() + i64::from(ttl) * 1000;
+        let bsos = self.bsos.entry(collection_id).or_default();
+        if let Some(existing) = bsos.get_mut(&bso.id) {
+            if bso.payload.is_some() || bso.sortindex.is_some() {
+                existing.modified = timestamp;
+            }
+            if let Some(payload) = bso.payload {
+                existing.payload = payload;
+            }
+            if bso.sortindex.is_some() {
+                existing.sortindex = bso.sortindex;
+            }
+            if let Some(ttl) = bso.ttl {
+                existing.expiry = expiry(ttl);
+            }
+        } else {
+            bsos.insert(
+                bso.id,
+                Bso {
+                    modified: timestamp,
+                    payload: bso.payload.unwrap_or_default(),
+                    sortindex: bso.sortindex,
+                    expiry: expiry(bso.ttl.unwrap_or(DEFAULT_BSO_TTL)),
+                },
+            );
+        }
+    }
+
+    /// Delete a collection's BSOs and the collection itself, returning
+    /// whether there was anything to delete
+    pub fn delete_collection(&mut self, collection_id: i32) -> bool {
+        let bsos = self
+            .bsos
+            .remove(&collection_id)
+            .map_or(false, |bsos| !bsos.is_empty());
+        let collection = self.collections.remove(&collection_id).is_some();
+        bsos || collection
+    }
+
+    pub fn erect_tombstone(&mut self, timestamp: SyncTimestamp) {
+        self.collections.insert(
+            TOMBSTONE,
+            UserCollection {
+                modified: timestamp,
+                count: 0,
+                total_bytes: 0,
+            },
+        );
+    }
+
+    /// Delete up to `limit` of the user's BSOs that expired as of `now`,
+    /// returning the number deleted
+    pub fn purge_expired_bsos(&mut self, now: i64, limit: u64) -> u64 {
+        let mut purged = 0;
+        for bsos in self.bsos.values_mut() {
+            bsos.retain(|_, bso| {
+                let purge = purged < limit && is_expired(bso.expiry, now);
+                purged += u64::from(purge);
+                !purge
+            });
+        }
+        self.bsos.retain(|_, bsos| !bsos.is_empty());
+        purged
+    }
+
+    /// Delete up to `limit` of the user's collections without any BSOs,
+    /// returning the number deleted. The tombstone's raised to the latest of
+    /// their timestamps (unless it's past it already), so the storage
+    /// timestamp doesn't go back
+    pub fn delete_empty_collections(&mut self, limit: u64) -> u64 {
+        let empty: Vec<_> = self
+            .collections
+            .keys()
+            .filter(|id| **id != TOMBSTONE && !self.bsos.contains_key(*id))
+            .take(limit as usize)
+            .copied()
+            .collect();
+        let latest = empty
+            .iter()
+            .filter_map(|id| self.collections.remove(id))
+            .map(|collection| collection.modified)
+            .max_by_key(|modified| modified.as_i64());
+        if let Some(latest) = latest {
+            let tombstone = self.collections.get(&TOMBSTONE).map(|c| c.modified);
+            if tombstone.map_or(true, |tombstone| tombstone.as_i64() < latest.as_i64()) {
+                self.erect_tombstone(latest);
+            }
+        }
+        empty.len() as u64
+    }
+
+    /// Mark a collection as modified at `timestamp`, recounting its usage
+    /// when `counters` are maintained
+    pub fn update_collection(
+        &mut self,
+        collection_id: i32,
+        timestamp: SyncTimestamp,
+        counters: bool,
+    ) -> SyncTimestamp {
+        let usage = if counters {
+            self.usage(collection_id, timestamp.as_i64())
+        } else {
+            results::GetQuotaUsage::default()
+        };
+        self.collections.insert(
+            collection_id,
+            UserCollection {
+                modified: timestamp,
+                count: usage.count,
+                total_bytes: usage.total_bytes as i64,
+            },
+        );
+        timestamp
+    }
+
+    /// The count and combined payload size of a collection's live BSOs
+    pub fn usage(&self, collection_id: i32, now: i64) -> results::GetQuotaUsage {
+        self.live_bsos(collection_id, now).fold(
+            results::GetQuotaUsage::default(),
+            |mut usage, (_, bso)| {
+                usage.count += 1;
+                usage.total_bytes += bso.payload.len();
+                usage
+            },
+        )
+    }
+}
@@ -0,0 +1,117 @@
+use std::sync::Arc;
+
+use syncserver_common::{BlockingThreadpool, CacheBackend, Metrics};
+use syncstorage_db_common::{error::DbErrorIntrospect, params, UserIdentifier, STD_COLLS};
+use syncstorage_settings::Settings;
+
+use crate::{models::MemoryDb, pool::MemoryDbPool, DbResult};
+
+fn pool() -> DbResult<MemoryDbPool> {
+    let settings = Settings {
+        database_url: "memory://".to_owned(),
+        ..Default::default()
+    };
+    MemoryDbPool::new(
+        &settings,
+        &Metrics::noop(),
+        Arc::new(BlockingThreadpool::default()),
+        &CacheBackend::Memory,
+    )
+}
+
+fn user_id() -> UserIdentifier {
+    UserIdentifier {
+        legacy_id: 1,
+        ..Default::default()
+    }
+}
+
+fn put_bso(db: &MemoryDb, id: &str, payload: &str) -> DbResult<()> {
+    db.put_bso_sync(params::PutBso {
+        user_id: user_id(),
+        collection: "bookmarks".to_owned(),
+        id: id.to_owned(),
+        payload: Some(payload.to_owned()),
+        sortindex: None,
+        ttl: None,
+    })?;
+    Ok(())
+}
+
+fn get_payload(db: &MemoryDb, id: &str) -> DbResult<Option<String>> {
+    Ok(db
+        .get_bso_sync(params::GetBso {
+            user_id: user_id(),
+            collection: "bookmarks".to_owned(),
+            id: id.to_owned(),
+        })?
+        .map(|bso| bso.payload))
+}
+
+fn lock_for_write(db: &MemoryDb) -> DbResult<()> {
+    db.lock_for_write_sync(params::LockCollection {
+        user_id: user_id(),
+        collection: "bookmarks".to_owned(),
+    })
+}
+
+#[test]
+fn static_collection_id() -> DbResult<()> {
+    let db = pool()?.get_sync();
+
+    for (id, name) in STD_COLLS {
+        assert_eq!(db.get_collection_id(name)?, *id);
+    }
+
+    // Custom collections are numbered past the reserved ids
+    let cid = db.get_or_create_collection_id("col1")?;
+    assert!(cid > 100);
+    assert_eq!(db.get_or_create_collection_id("col1")?, cid);
+    Ok(())
+}
+
+#[test]
+fn pools_are_isolated() -> DbResult<()> {
+    let db = pool()?.get_sync();
+    put_bso(&db, "b0", "payload")?;
+    assert_eq!(get_payload(&pool()?.get_sync(), "b0")?, None);
+    Ok(())
+}
+
+#[test]
+fn transactions_commit_or_roll_back() -> DbResult<()> {
+    let pool = pool()?;
+    let (db, other) = (pool.get_sync(), pool.get_sync());
+
+    lock_for_write(&db)?;
+    put_bso(&db, "b0", "rolled back")?;
+    db.rollback_sync()?;
+    assert_eq!(get_payload(&db, "b0")?, None);
+
+    lock_for_write(&db)?;
+    put_bso(&db, "b0", "payload")?;
+    // Only visible to the transaction until it's committed
+    assert_eq!(get_payload(&db, "b0")?.as_deref(), Some("payload"));
+    assert_eq!(get_payload(&other, "b0")?, None);
+    db.commit_sync()?;
+    assert_eq!(get_payload(&other, "b0")?.as_deref(), Some("payload"));
+    Ok(())
+}
+
+#[test]
+fn concurrent_writers_conflict() -> DbResult<()> {
+    let pool = pool()?;
+    let (db, other) = (pool.get_sync(), pool.get_sync());
+
+    lock_for_write(&db)?;
+    let result = put_bso(&other, "b0", "payload");
+    assert!(result.unwrap_err().is_conflict());
+    assert!(lock_for_write(&other).unwrap_err().is_conflict());
+
+    // Dropping a Db ends its transaction
+    drop(db);
+    other.rollback_sync()?;
+    lock_for_write(&other)?;
+    put_bso(&other, "b0", "payload")?;
+    other.commit_sync()
+}
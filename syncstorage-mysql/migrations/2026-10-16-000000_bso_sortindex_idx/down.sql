@@ -0,0 +1 @@
+DROP INDEX bso_usr_col_sortindex_idx ON bso;
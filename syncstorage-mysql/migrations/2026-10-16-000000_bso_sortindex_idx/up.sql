@@ -0,0 +1,4 @@
+-- Lets `sort=index` reads be served in index order, as `bso_usr_col_mod_idx`
+-- does for `sort=newest`/`sort=oldest`: InnoDB appends the primary key to
+-- secondary indexes, so both also cover the `id` tiebreaker.
+CREATE INDEX bso_usr_col_sortindex_idx ON bso (userid, collection, sortindex);
@@ -0,0 +1,10 @@
+-- Inline the deduplicated payloads again before dropping them
+UPDATE `bso`
+  JOIN `bso_payloads` ON `bso_payloads`.`hash` = `bso`.`payload_hash`
+   SET `bso`.`payload` = `bso_payloads`.`payload`;
+
+ALTER TABLE `bso`
+    DROP INDEX `bso_payload_hash_idx`,
+    DROP COLUMN `payload_hash`;
+
+DROP TABLE `bso_payloads`;
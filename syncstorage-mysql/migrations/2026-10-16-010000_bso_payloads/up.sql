@@ -0,0 +1,13 @@
+-- Deduplicated payloads, referenced from bso rows by their SHA-256 hash
+CREATE TABLE `bso_payloads` (
+    `hash` BINARY(32) NOT NULL,
+    `payload` MEDIUMTEXT NOT NULL,
+    -- when a bso last started referencing this payload, in milliseconds
+    -- since epoch (refreshed lazily)
+    `last_referenced` BIGINT NOT NULL,
+    PRIMARY KEY (`hash`)
+);
+
+ALTER TABLE `bso`
+    ADD COLUMN `payload_hash` BINARY(32) NULL,
+    ADD INDEX `bso_payload_hash_idx` (`payload_hash`);
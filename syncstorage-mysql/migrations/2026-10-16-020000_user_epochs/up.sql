@@ -0,0 +1,7 @@
+-- When each user's storage was last reset (e.g. migrated), in milliseconds
+-- since epoch. Writes from tokens issued before it are rejected
+CREATE TABLE `user_epochs` (
+    `userid` BIGINT NOT NULL,
+    `epoch` BIGINT NOT NULL,
+    PRIMARY KEY (`userid`)
+);
@@ -0,0 +1,4 @@
+-- Running totals of the records and payload bytes appended to each batch
+ALTER TABLE `batch_uploads`
+    ADD COLUMN `total_records` BIGINT NOT NULL DEFAULT 0,
+    ADD COLUMN `total_bytes` BIGINT NOT NULL DEFAULT 0;
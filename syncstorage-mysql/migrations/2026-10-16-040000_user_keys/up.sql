@@ -0,0 +1,8 @@
+-- When the keys each user's clients write with last changed (the
+-- keys_changed_at of their tokens' fxa_kid), in milliseconds since epoch.
+-- Writes with keys changed before it are rejected
+CREATE TABLE `user_keys` (
+    `userid` BIGINT NOT NULL,
+    `keys_changed_at` BIGINT NOT NULL,
+    PRIMARY KEY (`userid`)
+);
@@ -0,0 +1,6 @@
+-- Optional: partitions `bso` by a hash of the user id. Not run by the
+-- embedded migrations; see README.md before applying.
+--
+-- The partition count can't be changed without rebuilding the table, so
+-- size it for the deployment's expected growth.
+ALTER TABLE bso PARTITION BY HASH (userid) PARTITIONS 32;
@@ -0,0 +1,3 @@
+-- Reverts `partition_bso_by_userid.sql`, rebuilding `bso` as a single
+-- unpartitioned table.
+ALTER TABLE bso REMOVE PARTITIONING;
@@ -1,4 +1,3 @@
-use base64::Engine;
 use std::collections::HashSet;
 
 use diesel::{
@@ -11,16 +10,14 @@ use diesel::{
     ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl,
 };
 use syncstorage_db_common::{params, results, UserIdentifier, BATCH_LIFETIME};
+use syncstorage_sql_db_common::batch::{decode_id, encode_id, MAXTTL};
 
 use super::{
-    error::DbError,
-    models::MysqlDb,
+    models::{check_bso_size, MysqlDb},
     schema::{batch_upload_items, batch_uploads},
-    DbResult,
+    DbError, DbResult,
 };
 
-const MAXTTL: i32 = 2_100_000_000;
-
 pub fn create(db: &MysqlDb, params: params::CreateBatch) -> DbResult<results::CreateBatch> {
     let user_id = params.user_id.legacy_id as i64;
     let collection_id = db.get_collection_id(&params.collection)?;
@@ -79,7 +76,7 @@ pub fn validate(db: &MysqlDb, params: params::ValidateBatch) -> DbResult<bool> {
     Ok(exists.is_some())
 }
 
-pub fn append(db: &MysqlDb, params: params::AppendToBatch) -> DbResult<()> {
+pub fn append(db: &MysqlDb, params: params::AppendToBatch) -> DbResult<results::AppendToBatch> {
     let exists = validate(
         db,
         params::ValidateBatch {
@@ -95,25 +92,32 @@ pub fn append(db: &MysqlDb, params: params::AppendToBatch) -> DbResult<()> {
 
     let batch_id = decode_id(&params.batch.id)?;
     let collection_id = db.get_collection_id(&params.collection)?;
-    do_append(db, batch_id, params.user_id, collection_id, params.bsos)?;
-    Ok(())
+    do_append(db, batch_id, params.user_id, collection_id, params.bsos)
 }
 
 pub fn get(db: &MysqlDb, params: params::GetBatch) -> DbResult<Option<results::GetBatch>> {
-    let is_valid = validate(
-        db,
-        params::ValidateBatch {
-            user_id: params.user_id,
-            collection: params.collection,
-            id: params.id.clone(),
+    let batch_id = decode_id(&params.id)?;
+    // See validate
+    if (batch_id + BATCH_LIFETIME) < db.timestamp().as_i64() {
+        return Ok(None);
+    }
+
+    let user_id = params.user_id.legacy_id as i64;
+    let collection_id = db.get_collection_id(&params.collection)?;
+    let totals = batch_uploads::table
+        .select((batch_uploads::total_records, batch_uploads::total_bytes))
+        .filter(batch_uploads::batch_id.eq(&batch_id))
+        .filter(batch_uploads::user_id.eq(&user_id))
+        .filter(batch_uploads::collection_id.eq(&collection_id))
+        .get_result::<(i64, i64)>(&db.conn)
+        .optional()?;
+    Ok(totals.map(|(records, bytes)| results::GetBatch {
+        id: params.id,
+        totals: results::BatchTotals {
+            records: records as u64,
+            bytes: bytes as u64,
         },
-    )?;
-    let batch = if is_valid {
-        Some(results::GetBatch { id: params.id })
-    } else {
-        None
-    };
-    Ok(batch)
+    }))
 }
 
 pub fn delete(db: &MysqlDb, params: params::DeleteBatch) -> DbResult<()> {
@@ -150,7 +154,7 @@ pub fn commit(db: &MysqlDb, params: params::CommitBatch) -> DbResult<results::Co
         .bind::<BigInt, _>(&db.timestamp().as_i64())
         .execute(&db.conn)?;
 
-    db.update_collection(user_id as u32, collection_id)?;
+    db.update_collection(user_id as u64, collection_id)?;
 
     delete(
         db,
@@ -163,13 +167,14 @@ pub fn commit(db: &MysqlDb, params: params::CommitBatch) -> DbResult<results::Co
     Ok(timestamp)
 }
 
+/// Appends bsos to a batch, returning the batch's updated totals
 pub fn do_append(
     db: &MysqlDb,
     batch_id: i64,
     user_id: UserIdentifier,
     _collection_id: i32,
     bsos: Vec<params::PostCollectionBso>,
-) -> DbResult<()> {
+) -> DbResult<results::BatchTotals> {
     fn exist_idx(user_id: u64, batch_id: i64, bso_id: &str) -> String {
         // Construct something that matches the key for batch_upload_items
         format!(
@@ -200,6 +205,15 @@ pub fn do_append(
         ttl_offset: Option<i32>,
     }
 
+    for bso in &bsos {
+        check_bso_size(&bso.id, bso.payload.as_deref())?;
+    }
+
+    let records = bsos.len() as i64;
+    let bytes: i64 = bsos
+        .iter()
+        .map(|bso| bso.payload.as_ref().map_or(0, |p| p.len() as i64))
+        .sum();
     let mut existing = HashSet::new();
 
     // pre-load the "existing" hashset with any batched uploads that are already in the table.
@@ -250,31 +264,24 @@ pub fn do_append(
         }
     }
 
-    Ok(())
-}
-
-pub fn validate_batch_id(id: &str) -> DbResult<()> {
-    decode_id(id).map(|_| ())
-}
-
-fn encode_id(id: i64) -> String {
-    base64::engine::general_purpose::STANDARD.encode(id.to_string())
-}
-
-fn decode_id(id: &str) -> DbResult<i64> {
-    let bytes = base64::engine::general_purpose::STANDARD
-        .decode(id)
-        .unwrap_or_else(|_| id.as_bytes().to_vec());
-    let decoded = std::str::from_utf8(&bytes).unwrap_or(id);
-    decoded
-        .parse::<i64>()
-        .map_err(|e| DbError::internal(format!("Invalid batch_id: {}", e)))
-}
-
-macro_rules! batch_db_method {
-    ($name:ident, $batch_name:ident, $type:ident) => {
-        pub fn $name(&self, params: params::$type) -> DbResult<results::$type> {
-            batch::$batch_name(self, params)
-        }
-    };
+    let user_id = user_id.legacy_id as i64;
+    diesel::update(
+        batch_uploads::table
+            .filter(batch_uploads::batch_id.eq(batch_id))
+            .filter(batch_uploads::user_id.eq(user_id)),
+    )
+    .set((
+        batch_uploads::total_records.eq(batch_uploads::total_records + records),
+        batch_uploads::total_bytes.eq(batch_uploads::total_bytes + bytes),
+    ))
+    .execute(&db.conn)?;
+    let (records, bytes) = batch_uploads::table
+        .select((batch_uploads::total_records, batch_uploads::total_bytes))
+        .filter(batch_uploads::batch_id.eq(batch_id))
+        .filter(batch_uploads::user_id.eq(user_id))
+        .get_result::<(i64, i64)>(&db.conn)?;
+    Ok(results::BatchTotals {
+        records: records as u64,
+        bytes: bytes as u64,
+    })
 }
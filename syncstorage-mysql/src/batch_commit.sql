@@ -16,4 +16,5 @@ SELECT
        sortindex = COALESCE(batch_upload_items.sortindex, bso.sortindex),
        ttl = COALESCE((batch_upload_items.ttl_offset * 1000) + ?, bso.ttl),
        payload = COALESCE(batch_upload_items.payload, bso.payload),
+       payload_hash = IF(batch_upload_items.payload IS NULL, bso.payload_hash, NULL),
        payload_size = COALESCE(batch_upload_items.payload_size, bso.payload_size)
@@ -5,18 +5,17 @@ extern crate diesel_migrations;
 #[macro_use]
 extern crate slog_scope;
 
-#[macro_use]
 mod batch;
 mod diesel_ext;
-mod error;
 mod models;
 mod pool;
 mod schema;
 #[cfg(test)]
 mod test;
+mod timestamp_cache;
 
-pub use error::DbError;
 pub use models::MysqlDb;
-pub use pool::MysqlDbPool;
+pub use pool::{run_embedded_migrations, MysqlDbPool};
+pub use syncstorage_sql_db_common::DbError;
 
-pub(crate) type DbResult<T> = Result<T, error::DbError>;
+pub(crate) use syncstorage_sql_db_common::DbResult;
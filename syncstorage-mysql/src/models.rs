@@ -1,43 +1,60 @@
-use futures::future::TryFutureExt;
-
-use std::{self, cell::RefCell, collections::HashMap, fmt, ops::Deref, sync::Arc};
+use std::{
+    self,
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    fmt,
+    ops::Deref,
+    sync::Arc,
+};
 
 use diesel::{
     connection::TransactionManager,
     delete,
-    dsl::max,
+    dsl::{count_star, max, Gt, LtEq},
     expression::sql_literal::sql,
-    mysql::MysqlConnection,
-    r2d2::{ConnectionManager, PooledConnection},
+    mysql::Mysql,
+    r2d2::PooledConnection,
     sql_query,
-    sql_types::{BigInt, Integer, Nullable, Text},
-    Connection, ExpressionMethods, GroupByDsl, OptionalExtension, QueryDsl, RunQueryDsl,
+    sql_types::{BigInt, Binary, Bool, Integer, Nullable, Text},
+    update, BoolExpressionMethods, BoxableExpression, Connection, ExpressionMethods, GroupByDsl,
+    OptionalExtension, QueryDsl, RunQueryDsl,
 };
 #[cfg(debug_assertions)]
 use diesel_logger::LoggingConnection;
+use sha2::{Digest, Sha256};
 use syncserver_common::{BlockingThreadpool, Metrics};
-use syncserver_db_common::{sync_db_method, DbFuture};
+use syncserver_db_common::{
+    counting::CountingConnection, lock_wait_timer, manager::MysqlConnectionManager, sync_db_method,
+    DbFuture,
+};
 use syncstorage_db_common::{
-    error::DbErrorIntrospect, params, results, util::SyncTimestamp, Db, Sorting, UserIdentifier,
-    DEFAULT_BSO_TTL,
+    error::DbErrorIntrospect, params, payload_page_len, results, util::SyncTimestamp, Projection,
+    Sorting, UserIdentifier, DEFAULT_BSO_TTL, FIRST_CUSTOM_COLLECTION_ID, STD_COLLS,
+};
+use syncstorage_settings::Quota;
+use syncstorage_sql_db_common::{
+    batch_db_method, check_bso_id, check_quota, impl_sql_db, map_collection_names, CollectionLock,
+    DbSession, Page, USER_EPOCH_COLUMN, USER_KEYS_CHANGED_AT_COLUMN,
 };
-use syncstorage_settings::{Quota, DEFAULT_MAX_TOTAL_RECORDS};
 
 use super::{
     batch,
     diesel_ext::LockInShareModeDsl,
-    error::DbError,
     pool::CollectionCache,
-    schema::{bso, collections, user_collections},
-    DbResult,
+    schema::{
+        batch_uploads, bso, bso_payloads, collections, user_collections, user_epochs, user_keys,
+    },
+    timestamp_cache::{ReadToken, TimestampCache},
+    DbError, DbResult,
 };
 
-type Conn = PooledConnection<ConnectionManager<MysqlConnection>>;
-
-// this is the max number of records we will return.
-static DEFAULT_LIMIT: u32 = DEFAULT_MAX_TOTAL_RECORDS;
+type Conn = PooledConnection<MysqlConnectionManager>;
 
 const TOMBSTONE: i32 = 0;
+/// The `last_modified` of the user_collections rows of standard collections
+/// created ahead of their first write (see `create_std_collections`), which
+/// don't exist as far as readers are concerned
+const UNWRITTEN: i64 = 0;
 /// SQL Variable remapping
 /// These names are the legacy values mapped to the new names.
 const COLLECTION_ID: &str = "collection";
@@ -48,25 +65,21 @@ const LAST_MODIFIED: &str = "last_modified";
 const COUNT: &str = "count";
 const TOTAL_BYTES: &str = "total_bytes";
 
-#[derive(Debug)]
-enum CollectionLock {
-    Read,
-    Write,
-}
-
-/// Per session Db metadata
-#[derive(Debug, Default)]
-struct MysqlDbSession {
-    /// The "current time" on the server used for this session's operations
-    timestamp: SyncTimestamp,
-    /// Cache of collection modified timestamps per (user_id, collection_id)
-    coll_modified_cache: HashMap<(u32, i32), SyncTimestamp>,
-    /// Currently locked collections
-    coll_locks: HashMap<(u32, i32), CollectionLock>,
-    /// Whether a transaction was started (begin() called)
-    in_transaction: bool,
-    in_write_transaction: bool,
-}
+/// A bso's payload, whether stored inline or deduplicated in `bso_payloads`
+const BSO_PAYLOAD: &str = "IF(bso.payload_hash IS NULL, bso.payload, COALESCE(
+    (SELECT bso_payloads.payload FROM bso_payloads WHERE bso_payloads.hash = bso.payload_hash),
+    ''))";
+/// The length of a bso's payload, whether stored inline or deduplicated
+const BSO_PAYLOAD_LENGTH: &str = "IF(payload_hash IS NULL, LENGTH(payload), payload_size)";
+/// Max number of BSOs written by a single multi-row insert
+const POST_BSOS_CHUNK_SIZE: usize = 100;
+/// How long a deduplicated payload's kept after the last bso referencing it
+/// was written, in milliseconds. Covers writes racing the payload's cleanup
+const PAYLOAD_GRACE_PERIOD: i64 = 24 * 60 * 60 * 1000;
+/// The size of the column payloads are written to (see the migrations),
+/// `MEDIUMTEXT`'s, in bytes. Payloads exceeding it are rejected before
+/// they're bound, rather than failing in MySQL as 500s
+pub(super) const MAX_PAYLOAD_SIZE: usize = (1 << 24) - 1;
 
 #[derive(Clone, Debug)]
 pub struct MysqlDb {
@@ -81,10 +94,16 @@ pub struct MysqlDb {
 
     /// Pool level cache of collection_ids and their names
     coll_cache: Arc<CollectionCache>,
+    /// Pool level cache of recent collection timestamps
+    timestamp_cache: Option<Arc<TimestampCache>>,
+    /// Size from which payloads are deduplicated, when enabled
+    payload_dedup_min_size: Option<usize>,
 
     pub metrics: Metrics,
     pub quota: Quota,
     blocking_threadpool: Arc<BlockingThreadpool>,
+    /// The id of the request this is pinned to, logged with its calls
+    request_id: Option<String>,
 }
 
 /// Despite the db conn structs being !Sync (see Arc<MysqlDbInner> above) we
@@ -94,11 +113,11 @@ unsafe impl Send for MysqlDb {}
 
 pub struct MysqlDbInner {
     #[cfg(not(debug_assertions))]
-    pub(super) conn: Conn,
+    pub(super) conn: CountingConnection<Conn>,
     #[cfg(debug_assertions)]
-    pub(super) conn: LoggingConnection<Conn>, // display SQL when RUST_LOG="diesel_logger=trace"
+    pub(super) conn: CountingConnection<LoggingConnection<Conn>>, // display SQL when RUST_LOG="diesel_logger=trace"
 
-    session: RefCell<MysqlDbSession>,
+    session: RefCell<DbSession>,
 }
 
 impl fmt::Debug for MysqlDbInner {
@@ -119,15 +138,17 @@ impl MysqlDb {
     pub(super) fn new(
         conn: Conn,
         coll_cache: Arc<CollectionCache>,
+        timestamp_cache: Option<Arc<TimestampCache>>,
+        payload_dedup_min_size: Option<usize>,
         metrics: &Metrics,
         quota: &Quota,
         blocking_threadpool: Arc<BlockingThreadpool>,
     ) -> Self {
         let inner = MysqlDbInner {
             #[cfg(not(debug_assertions))]
-            conn,
+            conn: CountingConnection::new(conn),
             #[cfg(debug_assertions)]
-            conn: LoggingConnection::new(conn),
+            conn: CountingConnection::new(LoggingConnection::new(conn)),
             session: RefCell::new(Default::default()),
         };
         // https://github.com/mozilla-services/syncstorage-rs/issues/1480
@@ -135,9 +156,12 @@ impl MysqlDb {
         MysqlDb {
             inner: Arc::new(inner),
             coll_cache,
+            timestamp_cache,
+            payload_dedup_min_size,
             metrics: metrics.clone(),
-            quota: *quota,
+            quota: quota.clone(),
             blocking_threadpool,
+            request_id: None,
         }
     }
 
@@ -161,117 +185,180 @@ impl MysqlDb {
                 Err(e)
             }
         })?;
-        // If we already have a read or write lock then it's safe to
-        // use it as-is.
         if self
             .session
             .borrow()
-            .coll_locks
-            .get(&(user_id as u32, collection_id))
-            .is_some()
+            .is_locked(user_id as u64, collection_id)
         {
             return Ok(());
         }
 
         // Lock the db
         self.begin(false)?;
-        let modified = user_collections::table
-            .select(user_collections::modified)
-            .filter(user_collections::user_id.eq(user_id))
-            .filter(user_collections::collection_id.eq(collection_id))
-            .lock_in_share_mode()
-            .first(&self.conn)
-            .optional()?;
-        if let Some(modified) = modified {
-            let modified = SyncTimestamp::from_i64(modified)?;
-            self.session
-                .borrow_mut()
-                .coll_modified_cache
-                .insert((user_id as u32, collection_id), modified); // why does it still expect a u32 int?
-        }
-        // XXX: who's responsible for unlocking (removing the entry)
-        self.session
-            .borrow_mut()
-            .coll_locks
-            .insert((user_id as u32, collection_id), CollectionLock::Read);
-        Ok(())
+        let cached = self
+            .readable_timestamp_cache()
+            .and_then(|cache| cache.get(user_id as u64, collection_id));
+        let modified = match cached {
+            Some(modified) => Some(modified),
+            None => {
+                let token = self.timestamp_read_token(user_id as u64);
+                let modified = {
+                    let _timer = lock_wait_timer(&self.metrics, "read");
+                    user_collections::table
+                        .select(user_collections::modified)
+                        .filter(user_collections::user_id.eq(user_id))
+                        .filter(user_collections::collection_id.eq(collection_id))
+                        .lock_in_share_mode()
+                        .first(&self.conn)
+                        .optional()?
+                }
+                .filter(|modified| *modified != UNWRITTEN)
+                .map(SyncTimestamp::from_i64)
+                .transpose()?;
+                if let (Some(cache), Some(token), Some(modified)) =
+                    (self.readable_timestamp_cache(), token, modified)
+                {
+                    cache.put(token, user_id as u64, collection_id, modified);
+                }
+                modified
+            }
+        };
+        self.session.borrow_mut().lock(
+            user_id as u64,
+            collection_id,
+            CollectionLock::Read,
+            modified,
+        )
     }
 
-    fn lock_for_write_sync(&self, params: params::LockCollection) -> DbResult<()> {
+    pub(super) fn lock_for_write_sync(&self, params: params::LockCollection) -> DbResult<()> {
         let user_id = params.user_id.legacy_id as i64;
         let collection_id = self.get_or_create_collection_id(&params.collection)?;
-        if let Some(CollectionLock::Read) = self
-            .session
+        self.session
             .borrow()
-            .coll_locks
-            .get(&(user_id as u32, collection_id))
-        {
-            return Err(DbError::internal(
-                "Can't escalate read-lock to write-lock".to_owned(),
-            ));
-        }
+            .check_write_lockable(user_id as u64, collection_id)?;
 
         // Lock the db
         self.begin(true)?;
-        let modified = user_collections::table
-            .select(user_collections::modified)
-            .filter(user_collections::user_id.eq(user_id))
-            .filter(user_collections::collection_id.eq(collection_id))
-            .for_update()
-            .first(&self.conn)
-            .optional()?;
-        if let Some(modified) = modified {
-            let modified = SyncTimestamp::from_i64(modified)?;
-            // Forbid the write if it would not properly incr the timestamp
-            if modified >= self.timestamp() {
-                return Err(DbError::conflict());
+        let locked = {
+            let _timer = lock_wait_timer(&self.metrics, "write");
+            user_collections::table
+                .select((
+                    user_collections::modified,
+                    sql::<Nullable<BigInt>>(USER_EPOCH_COLUMN),
+                    sql::<Nullable<BigInt>>(USER_KEYS_CHANGED_AT_COLUMN),
+                ))
+                .filter(user_collections::user_id.eq(user_id))
+                .filter(user_collections::collection_id.eq(collection_id))
+                .for_update()
+                .first::<(i64, Option<i64>, Option<i64>)>(&self.conn)
+                .optional()?
+        };
+        // The user's storage epoch and keys are read along with the lock,
+        // unless it's the collection's first write (see `Db::locked_user`)
+        let modified = match locked {
+            Some((modified, storage_epoch, keys_changed_at)) => {
+                self.session.borrow_mut().lock_user(
+                    user_id as u64,
+                    storage_epoch,
+                    keys_changed_at,
+                )?;
+                Some(modified)
             }
-            self.session
-                .borrow_mut()
-                .coll_modified_cache
-                .insert((user_id as u32, collection_id), modified);
+            None => None,
+        };
+        if modified.is_none() && collection_id < FIRST_CUSTOM_COLLECTION_ID {
+            // Likely the user's first write: the rows created are locked
+            // by this transaction until it ends
+            self.create_std_collections(user_id)?;
         }
-        self.session
-            .borrow_mut()
-            .coll_locks
-            .insert((user_id as u32, collection_id), CollectionLock::Write);
-        Ok(())
+        let modified = modified
+            .filter(|modified| *modified != UNWRITTEN)
+            .map(SyncTimestamp::from_i64)
+            .transpose()?;
+        self.session.borrow_mut().lock(
+            user_id as u64,
+            collection_id,
+            CollectionLock::Write,
+            modified,
+        )
     }
 
     pub(super) fn begin(&self, for_write: bool) -> DbResult<()> {
         self.conn
             .transaction_manager()
             .begin_transaction(&self.conn)?;
-        self.session.borrow_mut().in_transaction = true;
-        if for_write {
-            self.session.borrow_mut().in_write_transaction = true;
-        }
+        self.session.borrow_mut().begin(for_write);
         Ok(())
     }
 
-    async fn begin_async(&self, for_write: bool) -> DbResult<()> {
-        self.begin(for_write)
-    }
-
     fn commit_sync(&self) -> DbResult<()> {
         if self.session.borrow().in_transaction {
             self.conn
                 .transaction_manager()
                 .commit_transaction(&self.conn)?;
         }
+        self.apply_timestamp_cache_updates();
         Ok(())
     }
 
-    fn rollback_sync(&self) -> DbResult<()> {
+    pub(super) fn rollback_sync(&self) -> DbResult<()> {
         if self.session.borrow().in_transaction {
             self.conn
                 .transaction_manager()
                 .rollback_transaction(&self.conn)?;
         }
+        let mut session = self.session.borrow_mut();
+        session.written_timestamps.clear();
+        session.invalidated_users.clear();
         Ok(())
     }
 
-    fn erect_tombstone(&self, user_id: i32) -> DbResult<()> {
+    /// The pool's timestamp cache, when enabled and readable: write
+    /// transactions bypass it as they may read their own uncommitted writes
+    fn readable_timestamp_cache(&self) -> Option<&TimestampCache> {
+        if self.session.borrow().in_write_transaction {
+            return None;
+        }
+        self.timestamp_cache.as_deref()
+    }
+
+    /// Taken before reading collection timestamps that are then cached in
+    /// the pool's timestamp cache
+    fn timestamp_read_token(&self, user_id: u64) -> Option<ReadToken> {
+        self.readable_timestamp_cache()
+            .map(|cache| cache.read_token(user_id))
+    }
+
+    /// Apply this session's committed writes to the pool's timestamp cache
+    fn apply_timestamp_cache_updates(&self) {
+        let mut session = self.session.borrow_mut();
+        let invalidated_users = std::mem::take(&mut session.invalidated_users);
+        let written_timestamps = std::mem::take(&mut session.written_timestamps);
+        if let Some(cache) = &self.timestamp_cache {
+            for user_id in invalidated_users {
+                cache.invalidate(user_id);
+            }
+            for (user_id, modified) in &written_timestamps {
+                cache.update(*user_id, modified);
+            }
+        }
+    }
+
+    /// Drop the user from the pool's timestamp cache, now (so this
+    /// transaction doesn't read stale timestamps) and once committed (so
+    /// concurrent reads can't have cached stale ones)
+    fn invalidate_cached_timestamps(&self, user_id: u64) {
+        if let Some(cache) = &self.timestamp_cache {
+            cache.invalidate(user_id);
+            self.session.borrow_mut().invalidated_users.insert(user_id);
+            if !self.session.borrow().in_transaction {
+                self.apply_timestamp_cache_updates();
+            }
+        }
+    }
+
+    fn erect_tombstone(&self, user_id: i64) -> DbResult<()> {
         sql_query(format!(
             r#"INSERT INTO user_collections ({user_id}, {collection_id}, {modified})
                VALUES (?, ?, ?)
@@ -281,15 +368,44 @@ impl MysqlDb {
             collection_id = COLLECTION_ID,
             modified = LAST_MODIFIED
         ))
-        .bind::<BigInt, _>(user_id as i64)
+        .bind::<BigInt, _>(user_id)
         .bind::<Integer, _>(TOMBSTONE)
         .bind::<BigInt, _>(self.timestamp().as_i64())
         .execute(&self.conn)?;
         Ok(())
     }
 
-    fn delete_storage_sync(&self, user_id: UserIdentifier) -> DbResult<()> {
+    /// Create the user_collections rows of every standard collection the user
+    /// lacks in a single statement, rather than one per collection as each
+    /// is first written (typically in a burst, while a new device sets up).
+    /// They're left `UNWRITTEN` until then
+    fn create_std_collections(&self, user_id: i64) -> DbResult<()> {
+        let rows = vec!["(?, ?, ?, 0, 0)"; STD_COLLS.len()].join(", ");
+        let mut query = sql_query(format!(
+            "INSERT IGNORE INTO user_collections
+                ({user_id}, {collection_id}, {modified}, {total_bytes}, {count})
+             VALUES {rows}",
+            user_id = USER_ID,
+            collection_id = COLLECTION_ID,
+            modified = LAST_MODIFIED,
+            total_bytes = TOTAL_BYTES,
+            count = COUNT,
+            rows = rows,
+        ))
+        .into_boxed::<Mysql>();
+        for (collection_id, _) in STD_COLLS {
+            query = query
+                .bind::<BigInt, _>(user_id)
+                .bind::<Integer, _>(*collection_id)
+                .bind::<BigInt, _>(UNWRITTEN);
+        }
+        query.execute(&self.conn)?;
+        Ok(())
+    }
+
+    pub(super) fn delete_storage_sync(&self, user_id: UserIdentifier) -> DbResult<()> {
         let user_id = user_id.legacy_id as i64;
+        self.invalidate_cached_timestamps(user_id as u64);
         // Delete user data.
         delete(bso::table)
             .filter(bso::user_id.eq(user_id))
@@ -301,49 +417,169 @@ impl MysqlDb {
         Ok(())
     }
 
+    fn get_storage_epoch_sync(
+        &self,
+        user_id: UserIdentifier,
+    ) -> DbResult<results::GetStorageEpoch> {
+        user_epochs::table
+            .select(user_epochs::epoch)
+            .filter(user_epochs::user_id.eq(user_id.legacy_id as i64))
+            .first::<i64>(&self.conn)
+            .optional()?
+            .map(SyncTimestamp::from_i64)
+            .transpose()
+            .map_err(Into::into)
+    }
+
+    fn bump_storage_epoch_sync(
+        &self,
+        user_id: UserIdentifier,
+    ) -> DbResult<results::BumpStorageEpoch> {
+        self.session
+            .borrow_mut()
+            .forget_locked_user(user_id.legacy_id);
+        let timestamp = self.timestamp();
+        sql_query(
+            r#"INSERT INTO user_epochs (userid, epoch)
+               VALUES (?, ?)
+                   ON DUPLICATE KEY UPDATE
+                      epoch = VALUES(epoch)"#,
+        )
+        .bind::<BigInt, _>(user_id.legacy_id as i64)
+        .bind::<BigInt, _>(timestamp.as_i64())
+        .execute(&self.conn)?;
+        Ok(timestamp)
+    }
+
+    fn get_keys_changed_at_sync(
+        &self,
+        user_id: UserIdentifier,
+    ) -> DbResult<results::GetKeysChangedAt> {
+        Ok(user_keys::table
+            .select(user_keys::keys_changed_at)
+            .filter(user_keys::user_id.eq(user_id.legacy_id as i64))
+            .first::<i64>(&self.conn)
+            .optional()?)
+    }
+
+    fn record_keys_changed_at_sync(
+        &self,
+        params: params::RecordKeysChangedAt,
+    ) -> DbResult<results::RecordKeysChangedAt> {
+        self.session
+            .borrow_mut()
+            .forget_locked_user(params.user_id.legacy_id);
+        sql_query(
+            r#"INSERT INTO user_keys (userid, keys_changed_at)
+               VALUES (?, ?)
+                   ON DUPLICATE KEY UPDATE
+                      keys_changed_at = GREATEST(keys_changed_at, VALUES(keys_changed_at))"#,
+        )
+        .bind::<BigInt, _>(params.user_id.legacy_id as i64)
+        .bind::<BigInt, _>(params.keys_changed_at)
+        .execute(&self.conn)?;
+        Ok(())
+    }
+
     // Deleting the collection should result in:
     //  - collection does not appear in /info/collections
     //  - X-Last-Modified timestamp at the storage level changing
     fn delete_collection_sync(&self, params: params::DeleteCollection) -> DbResult<SyncTimestamp> {
         let user_id = params.user_id.legacy_id as i64;
         let collection_id = self.get_collection_id(&params.collection)?;
-        let mut count = delete(bso::table)
-            .filter(bso::user_id.eq(user_id))
-            .filter(bso::collection_id.eq(&collection_id))
-            .execute(&self.conn)?;
-        count += delete(user_collections::table)
-            .filter(user_collections::user_id.eq(user_id))
-            .filter(user_collections::collection_id.eq(&collection_id))
-            .execute(&self.conn)?;
-        if count == 0 {
+        self.invalidate_cached_timestamps(user_id as u64);
+        if !self.delete_collection_rows(user_id, collection_id)? {
             return Err(DbError::collection_not_found());
         } else {
-            self.erect_tombstone(user_id as i32)?;
+            self.erect_tombstone(user_id)?;
         }
         self.get_storage_timestamp_sync(params.user_id)
     }
 
-    pub(super) fn get_or_create_collection_id(&self, name: &str) -> DbResult<i32> {
-        if let Some(id) = self.coll_cache.get_id(name)? {
-            return Ok(id);
+    fn delete_collections_sync(
+        &self,
+        params: params::DeleteCollections,
+    ) -> DbResult<results::DeleteCollections> {
+        let user_id = params.user_id.legacy_id as i64;
+        self.invalidate_cached_timestamps(user_id as u64);
+        let mut collections = HashMap::new();
+        for collection in params.collections {
+            let deleted = match self.get_collection_id(&collection) {
+                Ok(collection_id) => self.delete_collection_rows(user_id, collection_id)?,
+                Err(e) if e.is_collection_not_found() => false,
+                Err(e) => return Err(e),
+            };
+            collections.insert(collection, deleted);
+        }
+        if collections.values().any(|deleted| *deleted) {
+            self.erect_tombstone(user_id)?;
         }
+        Ok(results::DeleteCollections {
+            modified: self.get_storage_timestamp_sync(params.user_id)?,
+            collections,
+        })
+    }
 
-        let id = self.conn.transaction(|| {
-            diesel::insert_or_ignore_into(collections::table)
-                .values(collections::name.eq(name))
-                .execute(&self.conn)?;
+    pub(super) fn delete_collections_except_sync(
+        &self,
+        params: params::DeleteCollectionsExcept,
+    ) -> DbResult<results::DeleteCollectionsExcept> {
+        let user_id = params.user_id.legacy_id as i64;
+        self.invalidate_cached_timestamps(user_id as u64);
+        // Collections that were never created have nothing to keep
+        let mut keep = vec![TOMBSTONE];
+        for collection in &params.keep {
+            match self.get_collection_id(collection) {
+                Ok(collection_id) => keep.push(collection_id),
+                Err(e) if e.is_collection_not_found() => (),
+                Err(e) => return Err(e),
+            }
+        }
 
-            collections::table
-                .select(collections::id)
-                .filter(collections::name.eq(name))
-                .first(&self.conn)
-        })?;
+        let deleted = user_collections::table
+            .select(user_collections::collection_id)
+            .filter(user_collections::user_id.eq(user_id))
+            .filter(user_collections::collection_id.ne_all(keep.clone()))
+            .filter(user_collections::modified.ne(UNWRITTEN))
+            .load::<i32>(&self.conn)?;
+        delete(bso::table)
+            .filter(bso::user_id.eq(user_id))
+            .filter(bso::collection_id.ne_all(keep.clone()))
+            .execute(&self.conn)?;
+        delete(user_collections::table)
+            .filter(user_collections::user_id.eq(user_id))
+            .filter(user_collections::collection_id.ne_all(keep))
+            .filter(user_collections::modified.ne(UNWRITTEN))
+            .execute(&self.conn)?;
 
-        if !self.session.borrow().in_write_transaction {
-            self.coll_cache.put(id, name.to_owned())?;
+        let collections =
+            self.map_collection_names(deleted.into_iter().map(|id| (id, true)).collect())?;
+        if !collections.is_empty() {
+            self.erect_tombstone(user_id)?;
         }
+        Ok(results::DeleteCollections {
+            modified: self.get_storage_timestamp_sync(params.user_id)?,
+            collections,
+        })
+    }
 
-        Ok(id)
+    /// Delete a collection's bsos and its user_collections row, returning
+    /// whether there was anything to delete
+    fn delete_collection_rows(&self, user_id: i64, collection_id: i32) -> DbResult<bool> {
+        let mut count = delete(bso::table)
+            .filter(bso::user_id.eq(user_id))
+            .filter(bso::collection_id.eq(&collection_id))
+            .execute(&self.conn)?;
+        count += delete(user_collections::table)
+            .filter(user_collections::user_id.eq(user_id))
+            .filter(user_collections::collection_id.eq(&collection_id))
+            .filter(user_collections::modified.ne(UNWRITTEN))
+            .execute(&self.conn)?;
+        Ok(count > 0)
+    }
+
+    pub(super) fn get_or_create_collection_id(&self, name: &str) -> DbResult<i32> {
+        self.coll_cache.get_or_create_id(name)
     }
 
     pub(super) fn get_collection_id(&self, name: &str) -> DbResult<i32> {
@@ -385,7 +621,7 @@ impl MysqlDb {
         Ok(name)
     }
 
-    fn put_bso_sync(&self, bso: params::PutBso) -> DbResult<results::PutBso> {
+    pub(super) fn put_bso_sync(&self, bso: params::PutBso) -> DbResult<results::PutBso> {
         /*
         if bso.payload.is_none() && bso.sortindex.is_none() && bso.ttl.is_none() {
             // XXX: go returns an error here (ErrNothingToDo), and is treated
@@ -395,33 +631,47 @@ impl MysqlDb {
         */
 
         let collection_id = self.get_or_create_collection_id(&bso.collection)?;
-        let user_id: u64 = bso.user_id.legacy_id;
-        let timestamp = self.timestamp().as_i64();
-        if self.quota.enabled {
-            let usage = self.get_quota_usage_sync(params::GetQuotaUsage {
-                user_id: bso.user_id.clone(),
-                collection: bso.collection.clone(),
+        self.check_quota(&bso.user_id, &bso.collection, collection_id)?;
+        self.conn.transaction(|| {
+            self.write_bso(&bso, collection_id)?;
+            self.update_collection(bso.user_id.legacy_id, collection_id)
+        })
+    }
+
+    fn check_quota(
+        &self,
+        user_id: &UserIdentifier,
+        collection: &str,
+        collection_id: i32,
+    ) -> DbResult<()> {
+        check_quota(&self.quota, &self.metrics, user_id, collection, || {
+            self.get_quota_usage_sync(params::GetQuotaUsage {
+                user_id: user_id.clone(),
+                collection: collection.to_owned(),
                 collection_id,
-            })?;
-            if usage.total_bytes >= self.quota.size {
-                let mut tags = HashMap::default();
-                tags.insert("collection".to_owned(), bso.collection.clone());
-                self.metrics.incr_with_tags("storage.quota.at_limit", tags);
-                if self.quota.enforced {
-                    return Err(DbError::quota());
-                } else {
-                    warn!("Quota at limit for user's collection ({} bytes)", usage.total_bytes; "collection"=>bso.collection.clone());
-                }
-            }
-        }
+            })
+        })
+    }
 
+    /// Write a BSO, leaving its collection's `user_collections` row to the
+    /// caller
+    fn write_bso(&self, bso: &params::PutBso, collection_id: i32) -> DbResult<()> {
+        check_bso_size(&bso.id, bso.payload.as_deref())?;
+        let user_id: u64 = bso.user_id.legacy_id;
+        let timestamp = self.timestamp().as_i64();
         self.conn.transaction(|| {
             let payload = bso.payload.as_deref().unwrap_or_default();
+            let payload_hash = match self.payload_dedup_min_size {
+                Some(min_size) if bso.payload.is_some() && payload.len() >= min_size => {
+                    Some(self.store_payload(payload, timestamp)?)
+                }
+                _ => None,
+            };
             let sortindex = bso.sortindex;
             let ttl = bso.ttl.map_or(DEFAULT_BSO_TTL, |ttl| ttl);
             let q = format!(r#"
-            INSERT INTO bso ({user_id}, {collection_id}, id, sortindex, payload, {modified}, {expiry})
-            VALUES (?, ?, ?, ?, ?, ?, ?)
+            INSERT INTO bso ({user_id}, {collection_id}, id, sortindex, payload, payload_hash, payload_size, {modified}, {expiry})
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
                 ON DUPLICATE KEY UPDATE
                    {user_id} = VALUES({user_id}),
                    {collection_id} = VALUES({collection_id}),
@@ -440,7 +690,7 @@ impl MysqlDb {
                 "{}{}",
                 q,
                 if bso.payload.is_some() {
-                    ", payload = VALUES(payload)"
+                    ", payload = VALUES(payload), payload_hash = VALUES(payload_hash), payload_size = VALUES(payload_size)"
                 } else {
                     ""
                 },
@@ -468,11 +718,14 @@ impl MysqlDb {
                 .bind::<Integer, _>(&collection_id)
                 .bind::<Text, _>(&bso.id)
                 .bind::<Nullable<Integer>, _>(sortindex)
-                .bind::<Text, _>(payload)
+                // Deduplicated payloads are only stored in bso_payloads
+                .bind::<Text, _>(if payload_hash.is_some() { "" } else { payload })
+                .bind::<Nullable<Binary>, _>(payload_hash)
+                .bind::<BigInt, _>(payload.len() as i64)
                 .bind::<BigInt, _>(timestamp)
                 .bind::<BigInt, _>(timestamp + (i64::from(ttl) * 1000)) // remember: this is in millis
                 .execute(&self.conn)?;
-            self.update_collection(user_id as u32, collection_id)
+            Ok(())
         })
     }
 
@@ -480,17 +733,24 @@ impl MysqlDb {
         let user_id = params.user_id.legacy_id as i64;
         let collection_id = self.get_collection_id(&params.collection)?;
         let now = self.timestamp().as_i64();
+        // Columns outside the projection aren't read (particularly payloads,
+        // which may be deduplicated into another table)
+        let (payload, sortindex) = match params.projection {
+            Projection::Full => (BSO_PAYLOAD, "bso.sortindex"),
+            Projection::NoSortindex => (BSO_PAYLOAD, "NULL"),
+            Projection::Meta => ("''", "NULL"),
+        };
         let mut query = bso::table
             .select((
                 bso::id,
                 bso::modified,
-                bso::payload,
-                bso::sortindex,
+                sql::<Text>(payload),
+                sql::<Nullable<Integer>>(sortindex),
                 bso::expiry,
             ))
             .filter(bso::user_id.eq(user_id))
             .filter(bso::collection_id.eq(collection_id))
-            .filter(bso::expiry.gt(now))
+            .filter(unexpired(now))
             .into_boxed();
 
         if let Some(older) = params.older {
@@ -517,27 +777,25 @@ impl MysqlDb {
             }
             Sorting::Oldest => query.order(bso::id.asc()).order(bso::modified.asc()),
             */
-            Sorting::Index => query.order(bso::sortindex.desc()),
+            // Each order is served by an index on (userid, collection, <sort
+            // column>), with `id` implicitly appended by InnoDB, avoiding a
+            // filesort
+            Sorting::Index => query.order((bso::sortindex.desc(), bso::id.desc())),
             Sorting::Newest => query.order((bso::modified.desc(), bso::id.desc())),
             Sorting::Oldest => query.order((bso::modified.asc(), bso::id.asc())),
             _ => query,
         };
 
-        let limit = params
-            .limit
-            .map(i64::from)
-            .unwrap_or(DEFAULT_LIMIT as i64)
-            .max(0);
-        // fetch an extra row to detect if there are more rows that
-        // match the query conditions
-        query = query.limit(if limit > 0 { limit + 1 } else { limit });
-
-        let numeric_offset = params.offset.map_or(0, |offset| offset.offset as i64);
-
-        if numeric_offset > 0 {
-            // XXX: copy over this optimization:
-            // https://github.com/mozilla-services/server-syncstorage/blob/a0f8117/syncstorage/storage/sql/__init__.py#L404
-            query = query.offset(numeric_offset);
+        let page = Page::new(params.limit);
+        query = query.limit(page.fetch_limit());
+
+        let offset = params.offset.unwrap_or_default();
+        // Seeking past the previous page's last BSO spares reading every
+        // BSO before it, as skipping over them does
+        if let Some(after) = offset.after(params.sort) {
+            query = query.filter(past(after, params.sort));
+        } else if offset.offset > 0 {
+            query = query.offset(offset.offset as i64);
         }
         let mut bsos = query.load::<results::GetBso>(&self.conn)?;
 
@@ -546,19 +804,22 @@ impl MysqlDb {
         //if bsos.len() == 0 {
         //}
 
-        let next_offset = if limit >= 0 && bsos.len() > limit as usize {
-            bsos.pop();
-            Some((limit + numeric_offset).to_string())
-        } else {
-            // if an explicit "limit=0" is sent, return the offset of "0"
-            // Otherwise, this would break at least the db::tests::db::get_bsos_limit_offset
-            // unit test.
-            if limit == 0 {
-                Some(0.to_string())
-            } else {
-                None
-            }
-        };
+        let mut next_offset = page
+            .next_offset(&mut bsos, &offset, params.sort, Into::into)
+            .or_else(|| {
+                // if an explicit "limit=0" is sent, return the offset of "0"
+                // Otherwise, this would break at least the db::tests::db::get_bsos_limit_offset
+                // unit test.
+                page.is_empty().then(|| 0.to_string())
+            });
+        if let Some(len) = params
+            .max_payload_bytes
+            .and_then(|max_bytes| payload_page_len(&bsos, max_bytes))
+        {
+            bsos.truncate(len);
+            let last = bsos.last().map(Into::into);
+            next_offset = Some(offset.next(len, params.sort, last).to_string());
+        }
 
         Ok(results::GetBsos {
             items: bsos,
@@ -570,10 +831,10 @@ impl MysqlDb {
         let user_id = params.user_id.legacy_id as i64;
         let collection_id = self.get_collection_id(&params.collection)?;
         let mut query = bso::table
-            .select(bso::id)
+            .select((bso::id, bso::modified))
             .filter(bso::user_id.eq(user_id))
             .filter(bso::collection_id.eq(collection_id))
-            .filter(bso::expiry.gt(self.timestamp().as_i64()))
+            .filter(unexpired(self.timestamp().as_i64()))
             .into_boxed();
 
         if let Some(older) = params.older {
@@ -588,62 +849,77 @@ impl MysqlDb {
         }
 
         query = match params.sort {
-            Sorting::Index => query.order(bso::sortindex.desc()),
-            Sorting::Newest => query.order(bso::modified.desc()),
-            Sorting::Oldest => query.order(bso::modified.asc()),
+            Sorting::Index => query.order((bso::sortindex.desc(), bso::id.desc())),
+            Sorting::Newest => query.order((bso::modified.desc(), bso::id.desc())),
+            Sorting::Oldest => query.order((bso::modified.asc(), bso::id.asc())),
             _ => query,
         };
 
-        // negative limits are no longer allowed by mysql.
-        let limit = params
-            .limit
-            .map(i64::from)
-            .unwrap_or(DEFAULT_LIMIT as i64)
-            .max(0);
-        // fetch an extra row to detect if there are more rows that
-        // match the query conditions. Negative limits will cause an error.
-        query = query.limit(if limit == 0 { limit } else { limit + 1 });
-        let numeric_offset = params.offset.map_or(0, |offset| offset.offset as i64);
-        if numeric_offset != 0 {
-            // XXX: copy over this optimization:
-            // https://github.com/mozilla-services/server-syncstorage/blob/a0f8117/syncstorage/storage/sql/__init__.py#L404
-            query = query.offset(numeric_offset);
+        let page = Page::new(params.limit);
+        query = query.limit(page.fetch_limit());
+        let offset = params.offset.unwrap_or_default();
+        if let Some(after) = offset.after(params.sort) {
+            query = query.filter(past(after, params.sort));
+        } else if offset.offset > 0 {
+            query = query.offset(offset.offset as i64);
         }
-        let mut ids = query.load::<String>(&self.conn)?;
+        let mut rows = query.load::<(String, SyncTimestamp)>(&self.conn)?;
 
         // XXX: an additional get_collection_timestamp is done here in
         // python to trigger potential CollectionNotFoundErrors
         //if bsos.len() == 0 {
         //}
 
-        let next_offset = if limit >= 0 && ids.len() > limit as usize {
-            ids.pop();
-            Some((limit + numeric_offset).to_string())
-        } else {
-            None
-        };
+        let next_offset = page.next_offset(&mut rows, &offset, params.sort, |(id, modified)| {
+            params::BsoPosition {
+                modified: *modified,
+                id: id.clone(),
+            }
+        });
 
         Ok(results::GetBsoIds {
-            items: ids,
+            items: rows.into_iter().map(|(id, _)| id).collect(),
             offset: next_offset,
         })
     }
 
-    fn get_bso_sync(&self, params: params::GetBso) -> DbResult<Option<results::GetBso>> {
+    fn get_bsos_count_sync(&self, params: params::GetBsosCount) -> DbResult<results::GetBsosCount> {
+        let user_id = params.user_id.legacy_id as i64;
+        let collection_id = self.get_collection_id(&params.collection)?;
+        let mut query = bso::table
+            .select(count_star())
+            .filter(bso::user_id.eq(user_id))
+            .filter(bso::collection_id.eq(collection_id))
+            .filter(unexpired(self.timestamp().as_i64()))
+            .into_boxed();
+
+        if let Some(older) = params.older {
+            query = query.filter(bso::modified.lt(older.as_i64()));
+        }
+        if let Some(newer) = params.newer {
+            query = query.filter(bso::modified.gt(newer.as_i64()));
+        }
+        if !params.ids.is_empty() {
+            query = query.filter(bso::id.eq_any(params.ids));
+        }
+        Ok(query.get_result::<i64>(&self.conn)? as u64)
+    }
+
+    pub(super) fn get_bso_sync(&self, params: params::GetBso) -> DbResult<Option<results::GetBso>> {
         let user_id = params.user_id.legacy_id as i64;
         let collection_id = self.get_collection_id(&params.collection)?;
         Ok(bso::table
             .select((
                 bso::id,
                 bso::modified,
-                bso::payload,
+                sql::<Text>(BSO_PAYLOAD),
                 bso::sortindex,
                 bso::expiry,
             ))
             .filter(bso::user_id.eq(user_id))
             .filter(bso::collection_id.eq(&collection_id))
             .filter(bso::id.eq(&params.id))
-            .filter(bso::expiry.ge(self.timestamp().as_i64()))
+            .filter(unexpired(self.timestamp().as_i64()))
             .get_result::<results::GetBso>(&self.conn)
             .optional()?)
     }
@@ -655,12 +931,32 @@ impl MysqlDb {
             .filter(bso::user_id.eq(user_id as i64))
             .filter(bso::collection_id.eq(&collection_id))
             .filter(bso::id.eq(params.id))
-            .filter(bso::expiry.gt(&self.timestamp().as_i64()))
+            .filter(unexpired(self.timestamp().as_i64()))
             .execute(&self.conn)?;
         if affected_rows == 0 {
             return Err(DbError::bso_not_found());
         }
-        self.update_collection(user_id as u32, collection_id)
+        self.update_collection(user_id, collection_id)
+    }
+
+    /// Delete the bso if it has expired. Its collection's left as is: reads
+    /// already skipped the bso
+    fn purge_expired_bso_sync(
+        &self,
+        params: params::PurgeExpiredBso,
+    ) -> DbResult<results::PurgeExpiredBso> {
+        let collection_id = match self.get_collection_id(&params.collection) {
+            Ok(collection_id) => collection_id,
+            Err(e) if e.is_collection_not_found() => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        let affected_rows = delete(bso::table)
+            .filter(bso::user_id.eq(params.user_id.legacy_id as i64))
+            .filter(bso::collection_id.eq(&collection_id))
+            .filter(bso::id.eq(params.id))
+            .filter(expired(SyncTimestamp::default().as_i64()))
+            .execute(&self.conn)?;
+        Ok(affected_rows > 0)
     }
 
     fn delete_bsos_sync(&self, params: params::DeleteBsos) -> DbResult<results::DeleteBsos> {
@@ -671,40 +967,185 @@ impl MysqlDb {
             .filter(bso::collection_id.eq(&collection_id))
             .filter(bso::id.eq_any(params.ids))
             .execute(&self.conn)?;
-        self.update_collection(user_id as u32, collection_id)
+        self.update_collection(user_id as u64, collection_id)
     }
 
-    fn post_bsos_sync(&self, input: params::PostBsos) -> DbResult<results::PostBsos> {
+    pub(super) fn post_bsos_sync(&self, input: params::PostBsos) -> DbResult<results::PostBsos> {
         let collection_id = self.get_or_create_collection_id(&input.collection)?;
         let mut result = results::PostBsos {
             modified: self.timestamp(),
             success: Default::default(),
             failed: input.failed,
         };
-
+        // BSOs the columns can't hold fail individually, like those failing
+        // to write
+        let mut bsos = Vec::with_capacity(input.bsos.len());
         for pbso in input.bsos {
+            match check_bso_size(&pbso.id, pbso.payload.as_deref()) {
+                Ok(()) => bsos.push(pbso),
+                Err(e) => {
+                    result.failed.insert(pbso.id, e.to_string());
+                }
+            }
+        }
+        let ids: Vec<String> = bsos.iter().map(|pbso| pbso.id.clone()).collect();
+        let mut succeeded = HashSet::new();
+
+        // Quota's checked once for the whole post, against the usage
+        // recorded before it (the collection's only touched once, after all
+        // its BSOs are written)
+        self.check_quota(&input.user_id, &input.collection, collection_id)?;
+
+        // BSOs without a payload only update some of their columns and
+        // deduplicated payloads are stored separately: those are written one
+        // at a time, as are posts repeating BSO ids (whose last write must
+        // win)
+        let bulk_writable = ids.iter().collect::<HashSet<_>>().len() == ids.len();
+        let (bulk, single): (Vec<_>, Vec<_>) = bsos.into_iter().partition(|pbso| {
+            bulk_writable
+                && pbso.payload.as_ref().is_some_and(|payload| {
+                    !self
+                        .payload_dedup_min_size
+                        .is_some_and(|min_size| payload.len() >= min_size)
+                })
+        });
+
+        // Rows of a multi-row insert must update the same columns
+        let mut shapes: HashMap<(bool, bool), Vec<params::PostCollectionBso>> = HashMap::new();
+        for pbso in bulk {
+            shapes
+                .entry((pbso.sortindex.is_some(), pbso.ttl.is_some()))
+                .or_default()
+                .push(pbso);
+        }
+        for bsos in shapes.into_values() {
+            let mut bsos = bsos.into_iter().peekable();
+            while bsos.peek().is_some() {
+                let chunk: Vec<_> = bsos.by_ref().take(POST_BSOS_CHUNK_SIZE).collect();
+                let inserted = self.conn.transaction(|| {
+                    self.insert_bsos_chunk(input.user_id.legacy_id, collection_id, &chunk)
+                });
+                match inserted {
+                    Ok(()) => succeeded.extend(chunk.into_iter().map(|pbso| pbso.id)),
+                    Err(e) => {
+                        // Isolate the failing BSO(s) rather than failing the
+                        // whole chunk
+                        warn!("Bulk BSO insert failed, retrying one at a time: {}", e);
+                        self.metrics.incr("storage.post_bsos.chunk_fallback");
+                        self.put_bsos_singly(
+                            &input.user_id,
+                            &input.collection,
+                            collection_id,
+                            chunk,
+                            &mut succeeded,
+                            &mut result.failed,
+                        );
+                    }
+                }
+            }
+        }
+        self.put_bsos_singly(
+            &input.user_id,
+            &input.collection,
+            collection_id,
+            single,
+            &mut succeeded,
+            &mut result.failed,
+        );
+
+        result.success = ids
+            .into_iter()
+            .filter(|id| succeeded.contains(id))
+            .collect();
+        self.update_collection(input.user_id.legacy_id, collection_id)?;
+        Ok(result)
+    }
+
+    /// Write BSOs one at a time, reporting their individual failures
+    fn put_bsos_singly(
+        &self,
+        user_id: &UserIdentifier,
+        collection: &str,
+        collection_id: i32,
+        bsos: Vec<params::PostCollectionBso>,
+        succeeded: &mut HashSet<String>,
+        failed: &mut HashMap<String, String>,
+    ) {
+        for pbso in bsos {
             let id = pbso.id;
-            let put_result = self.put_bso_sync(params::PutBso {
-                user_id: input.user_id.clone(),
-                collection: input.collection.clone(),
-                id: id.clone(),
-                payload: pbso.payload,
-                sortindex: pbso.sortindex,
-                ttl: pbso.ttl,
-            });
+            let put_result = self.write_bso(
+                &params::PutBso {
+                    user_id: user_id.clone(),
+                    collection: collection.to_owned(),
+                    id: id.clone(),
+                    payload: pbso.payload,
+                    sortindex: pbso.sortindex,
+                    ttl: pbso.ttl,
+                },
+                collection_id,
+            );
             // XXX: python version doesn't report failures from db
             // layer.. (wouldn't db failures abort the entire transaction
             // anyway?)
             // XXX: sanitize to.to_string()?
             match put_result {
-                Ok(_) => result.success.push(id),
+                Ok(_) => {
+                    succeeded.insert(id);
+                }
                 Err(e) => {
-                    result.failed.insert(id, e.to_string());
+                    failed.insert(id, e.to_string());
                 }
             }
         }
-        self.update_collection(input.user_id.legacy_id as u32, collection_id)?;
-        Ok(result)
+    }
+
+    /// Write BSOs (all with payloads and the same optional fields set) with
+    /// a single multi-row insert
+    fn insert_bsos_chunk(
+        &self,
+        user_id: u64,
+        collection_id: i32,
+        bsos: &[params::PostCollectionBso],
+    ) -> DbResult<()> {
+        let Some(first) = bsos.first() else {
+            return Ok(());
+        };
+        let timestamp = self.timestamp().as_i64();
+        let mut q = format!(
+            "INSERT INTO bso ({user_id}, {collection_id}, id, sortindex, payload, payload_hash, payload_size, {modified}, {expiry}) VALUES {values}
+                ON DUPLICATE KEY UPDATE
+                   payload = VALUES(payload),
+                   payload_hash = VALUES(payload_hash),
+                   payload_size = VALUES(payload_size),
+                   {modified} = VALUES({modified})",
+            user_id = USER_ID,
+            collection_id = COLLECTION_ID,
+            modified = MODIFIED,
+            expiry = EXPIRY,
+            values = vec!["(?, ?, ?, ?, ?, NULL, ?, ?, ?)"; bsos.len()].join(", "),
+        );
+        if first.sortindex.is_some() {
+            q.push_str(", sortindex = VALUES(sortindex)");
+        }
+        if first.ttl.is_some() {
+            q.push_str(&format!(", {expiry} = VALUES({expiry})", expiry = EXPIRY));
+        }
+        let mut query = sql_query(q).into_boxed::<Mysql>();
+        for bso in bsos {
+            let payload = bso.payload.as_deref().unwrap_or_default();
+            let ttl = bso.ttl.unwrap_or(DEFAULT_BSO_TTL);
+            query = query
+                .bind::<BigInt, _>(user_id as i64)
+                .bind::<Integer, _>(collection_id)
+                .bind::<Text, _>(bso.id.clone())
+                .bind::<Nullable<Integer>, _>(bso.sortindex)
+                .bind::<Text, _>(payload.to_owned())
+                .bind::<BigInt, _>(payload.len() as i64)
+                .bind::<BigInt, _>(timestamp)
+                .bind::<BigInt, _>(timestamp + (i64::from(ttl) * 1000));
+        }
+        query.execute(&self.conn)?;
+        Ok(())
     }
 
     fn get_storage_timestamp_sync(&self, user_id: UserIdentifier) -> DbResult<SyncTimestamp> {
@@ -721,23 +1162,33 @@ impl MysqlDb {
         &self,
         params: params::GetCollectionTimestamp,
     ) -> DbResult<SyncTimestamp> {
-        let user_id = params.user_id.legacy_id as u32;
+        let user_id = params.user_id.legacy_id;
         let collection_id = self.get_collection_id(&params.collection)?;
         if let Some(modified) = self
             .session
             .borrow()
-            .coll_modified_cache
-            .get(&(user_id, collection_id))
+            .locked_modified(user_id, collection_id)
         {
-            return Ok(*modified);
+            return Ok(modified);
+        }
+        if let Some(cache) = self.readable_timestamp_cache() {
+            if let Some(modified) = cache.get(user_id, collection_id) {
+                return Ok(modified);
+            }
         }
-        user_collections::table
+        let token = self.timestamp_read_token(user_id);
+        let modified = user_collections::table
             .select(user_collections::modified)
             .filter(user_collections::user_id.eq(user_id as i64))
             .filter(user_collections::collection_id.eq(collection_id))
+            .filter(user_collections::modified.ne(UNWRITTEN))
             .first(&self.conn)
             .optional()?
-            .ok_or_else(DbError::collection_not_found)
+            .ok_or_else(DbError::collection_not_found)?;
+        if let (Some(cache), Some(token)) = (self.readable_timestamp_cache(), token) {
+            cache.put(token, user_id, collection_id, modified);
+        }
+        Ok(modified)
     }
 
     fn get_bso_timestamp_sync(&self, params: params::GetBsoTimestamp) -> DbResult<SyncTimestamp> {
@@ -754,21 +1205,31 @@ impl MysqlDb {
         SyncTimestamp::from_i64(modified).map_err(Into::into)
     }
 
-    fn get_collection_timestamps_sync(
+    pub(super) fn get_collection_timestamps_sync(
         &self,
         user_id: UserIdentifier,
     ) -> DbResult<results::GetCollectionTimestamps> {
+        let legacy_id = user_id.legacy_id;
+        if let Some(modifieds) = self
+            .readable_timestamp_cache()
+            .and_then(|cache| cache.get_all(legacy_id))
+        {
+            return self.map_collection_names(modifieds);
+        }
+        let token = self.timestamp_read_token(legacy_id);
         let modifieds = sql_query(format!(
             "SELECT {collection_id}, {modified}
                FROM user_collections
               WHERE {user_id} = ?
-               AND {collection_id} != ?",
+               AND {collection_id} != ?
+               AND {modified} != ?",
             collection_id = COLLECTION_ID,
             user_id = USER_ID,
             modified = LAST_MODIFIED
         ))
         .bind::<BigInt, _>(user_id.legacy_id as i64)
         .bind::<Integer, _>(TOMBSTONE)
+        .bind::<BigInt, _>(UNWRITTEN)
         .load::<UserCollectionsResult>(&self.conn)?
         .into_iter()
         .map(|cr| {
@@ -777,25 +1238,262 @@ impl MysqlDb {
                 .map_err(Into::into)
         })
         .collect::<DbResult<HashMap<_, _>>>()?;
+        if let (Some(cache), Some(token)) = (self.readable_timestamp_cache(), token) {
+            cache.put_all(token, legacy_id, modifieds.clone());
+        }
         self.map_collection_names(modifieds)
     }
 
     fn check_sync(&self) -> DbResult<results::Check> {
-        // has the database been up for more than 0 seconds?
-        let result = sql_query("SHOW STATUS LIKE \"Uptime\"").execute(&self.conn)?;
-        Ok(result as u64 > 0)
+        // does the database answer a query on this pooled connection?
+        let result = diesel::select(sql::<Integer>("1")).get_result::<i32>(&self.conn)?;
+        Ok(result == 1)
+    }
+
+    fn get_database_stats_sync(&self) -> DbResult<results::GetDatabaseStats> {
+        let now = SyncTimestamp::default().as_i64();
+        let bsos = bso::table.count().get_result::<i64>(&self.conn)?;
+        let expired_bsos = bso::table
+            .filter(expired(now))
+            .count()
+            .get_result::<i64>(&self.conn)?;
+        let user_collections = user_collections::table
+            .filter(user_collections::modified.ne(UNWRITTEN))
+            .count()
+            .get_result::<i64>(&self.conn)?;
+        let batches = batch_uploads::table.count().get_result::<i64>(&self.conn)?;
+        Ok(results::GetDatabaseStats {
+            bsos,
+            expired_bsos,
+            user_collections,
+            batches,
+        })
+    }
+
+    /// The partitions of the bso table when it's been partitioned (see
+    /// `partitioning/README.md`), empty otherwise
+    pub fn get_bso_partitions_sync(&self) -> DbResult<Vec<String>> {
+        Ok(sql_query(
+            "SELECT PARTITION_NAME AS name
+               FROM information_schema.PARTITIONS
+              WHERE TABLE_SCHEMA = DATABASE()
+                AND TABLE_NAME = 'bso'
+                AND PARTITION_NAME IS NOT NULL
+              ORDER BY PARTITION_ORDINAL_POSITION",
+        )
+        .load::<NameResult>(&self.conn)?
+        .into_iter()
+        .map(|result| result.name)
+        .collect())
+    }
+
+    /// Deletes up to `limit` expired bsos, returning how many were deleted.
+    /// Restricting the delete to one of the table's partitions confines its
+    /// scan and locks to that partition
+    pub fn delete_expired_bsos_sync(&self, partition: Option<&str>, limit: u32) -> DbResult<usize> {
+        let partition = match partition {
+            Some(name) if name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') => {
+                format!("PARTITION (`{}`)", name)
+            }
+            Some(name) => {
+                return Err(DbError::internal(format!(
+                    "Invalid bso partition name: {}",
+                    name
+                )))
+            }
+            None => "".to_owned(),
+        };
+        Ok(sql_query(format!(
+            "DELETE FROM bso {partition} WHERE {expiry} <= ? LIMIT ?",
+            partition = partition,
+            expiry = EXPIRY
+        ))
+        .bind::<BigInt, _>(SyncTimestamp::default().as_i64())
+        .bind::<BigInt, _>(i64::from(limit))
+        .execute(&self.conn)?)
+    }
+
+    /// Store a payload once per distinct content in `bso_payloads`,
+    /// returning its hash to reference it by
+    fn store_payload(&self, payload: &str, timestamp: i64) -> DbResult<Vec<u8>> {
+        let hash = Sha256::digest(payload.as_bytes()).to_vec();
+        let inserted = sql_query(
+            "INSERT IGNORE INTO bso_payloads (hash, payload, last_referenced)
+             VALUES (?, ?, ?)",
+        )
+        .bind::<Binary, _>(&hash)
+        .bind::<Text, _>(payload)
+        .bind::<BigInt, _>(timestamp)
+        .execute(&self.conn)?;
+        if inserted == 0 {
+            // Widely shared payloads are hot rows: only take the exclusive
+            // lock refreshing last_referenced once per half grace period
+            let last_referenced = bso_payloads::table
+                .select(bso_payloads::last_referenced)
+                .filter(bso_payloads::hash.eq(hash.as_slice()))
+                .first::<i64>(&self.conn)?;
+            if last_referenced < timestamp - PAYLOAD_GRACE_PERIOD / 2 {
+                update(bso_payloads::table.filter(bso_payloads::hash.eq(hash.as_slice())))
+                    .set(bso_payloads::last_referenced.eq(timestamp))
+                    .execute(&self.conn)?;
+            }
+        }
+        Ok(hash)
+    }
+
+    /// Delete up to `limit` deduplicated payloads no longer referenced by any
+    /// bso, returning the number deleted.
+    ///
+    /// Payloads aren't reference counted (every bso overwrite, delete and
+    /// expiry would have to maintain the count): instead this checks for
+    /// references, sparing payloads referenced by a write within
+    /// `PAYLOAD_GRACE_PERIOD` as that write may not have committed yet.
+    pub fn delete_unreferenced_payloads_sync(&self, limit: u32) -> DbResult<usize> {
+        Ok(sql_query(
+            "DELETE FROM bso_payloads
+              WHERE last_referenced < ?
+                AND NOT EXISTS (SELECT 1 FROM bso WHERE bso.payload_hash = bso_payloads.hash)
+              LIMIT ?",
+        )
+        .bind::<BigInt, _>(SyncTimestamp::default().as_i64() - PAYLOAD_GRACE_PERIOD)
+        .bind::<BigInt, _>(i64::from(limit))
+        .execute(&self.conn)?)
+    }
+
+    /// Purge expired bsos (a partition at a time when `bso`'s partitioned),
+    /// then the deduplicated payloads and collections left unused
+    fn purge_expired_sync(&self, params: params::PurgeExpired) -> DbResult<results::PurgeExpired> {
+        let limit = params.limit;
+        let partitions = self.get_bso_partitions_sync()?;
+        let mut bsos = 0;
+        if partitions.is_empty() {
+            bsos = self.delete_expired_bsos_sync(None, limit)?;
+        }
+        for partition in &partitions {
+            // The partitions share the limit
+            let remaining = limit.saturating_sub(bsos as u32);
+            if remaining == 0 {
+                break;
+            }
+            bsos += self.delete_expired_bsos_sync(Some(partition), remaining)?;
+        }
+        let payloads = self.delete_unreferenced_payloads_sync(limit)?;
+        let user_collections = self.delete_empty_collections_sync(limit)?;
+        Ok(results::PurgeExpired {
+            bsos: bsos as u64,
+            user_collections: user_collections as u64,
+            payloads: payloads as u64,
+        })
+    }
+
+    /// Delete up to `limit` collections without any bsos (e.g. once theirs
+    /// expired and were purged), returning the number deleted.
+    ///
+    /// Like deleting a collection, this raises the user's tombstone so their
+    /// storage timestamp doesn't go back, though only up to the collection's
+    /// timestamp: nothing they can read changed.
+    fn delete_empty_collections_sync(&self, limit: u32) -> DbResult<usize> {
+        let no_bsos = format!(
+            "NOT EXISTS (SELECT 1 FROM bso
+                          WHERE bso.{user_id} = user_collections.{user_id}
+                            AND bso.{collection_id} = user_collections.{collection_id})",
+            user_id = USER_ID,
+            collection_id = COLLECTION_ID,
+        );
+        let empty = sql_query(format!(
+            "SELECT {user_id}, {collection_id}, {modified}
+               FROM user_collections
+              WHERE {collection_id} != ?
+                AND {modified} != ?
+                AND {no_bsos}
+              LIMIT ?",
+            user_id = USER_ID,
+            collection_id = COLLECTION_ID,
+            modified = LAST_MODIFIED,
+            no_bsos = no_bsos,
+        ))
+        .bind::<Integer, _>(TOMBSTONE)
+        .bind::<BigInt, _>(UNWRITTEN)
+        .bind::<BigInt, _>(i64::from(limit))
+        .load::<EmptyCollectionResult>(&self.conn)?;
+
+        let mut deleted = 0;
+        for collection in empty {
+            self.raise_tombstone(collection.userid, collection.last_modified)?;
+            // Spared when written to since
+            deleted += sql_query(format!(
+                "DELETE FROM user_collections
+                  WHERE {user_id} = ?
+                    AND {collection_id} = ?
+                    AND {modified} = ?
+                    AND {no_bsos}",
+                user_id = USER_ID,
+                collection_id = COLLECTION_ID,
+                modified = LAST_MODIFIED,
+                no_bsos = no_bsos,
+            ))
+            .bind::<BigInt, _>(collection.userid)
+            .bind::<Integer, _>(collection.collection)
+            .bind::<BigInt, _>(collection.last_modified)
+            .execute(&self.conn)?;
+            self.invalidate_cached_timestamps(collection.userid as u64);
+        }
+        Ok(deleted)
+    }
+
+    /// Raise the user's tombstone to `modified`, unless it's already past it
+    fn raise_tombstone(&self, user_id: i64, modified: i64) -> DbResult<()> {
+        sql_query(format!(
+            r#"INSERT INTO user_collections ({user_id}, {collection_id}, {modified})
+               VALUES (?, ?, ?)
+                   ON DUPLICATE KEY UPDATE
+                      {modified} = GREATEST({modified}, VALUES({modified}))"#,
+            user_id = USER_ID,
+            collection_id = COLLECTION_ID,
+            modified = LAST_MODIFIED
+        ))
+        .bind::<BigInt, _>(user_id)
+        .bind::<Integer, _>(TOMBSTONE)
+        .bind::<BigInt, _>(modified)
+        .execute(&self.conn)?;
+        Ok(())
     }
 
     fn map_collection_names<T>(&self, by_id: HashMap<i32, T>) -> DbResult<HashMap<String, T>> {
-        let mut names = self.load_collection_names(by_id.keys())?;
-        by_id
-            .into_iter()
-            .map(|(id, value)| {
-                names.remove(&id).map(|name| (name, value)).ok_or_else(|| {
-                    DbError::internal("load_collection_names unknown collection id".to_owned())
-                })
-            })
-            .collect()
+        let names = self.load_collection_names(by_id.keys())?;
+        map_collection_names(by_id, names)
+    }
+
+    /// Runs the common lookups (for a user without any data) so diesel
+    /// prepares and caches their statements on this connection
+    pub(super) fn prime_statements(&self) -> DbResult<()> {
+        let user_id = UserIdentifier::default();
+        self.get_storage_timestamp_sync(user_id.clone())?;
+        let collection = STD_COLLS[0].1.to_owned();
+        self.get_bso_timestamp_sync(params::GetBsoTimestamp {
+            user_id: user_id.clone(),
+            collection: collection.clone(),
+            id: String::new(),
+        })?;
+        self.get_bso_sync(params::GetBso {
+            user_id,
+            collection,
+            id: String::new(),
+        })?;
+        Ok(())
+    }
+
+    /// Caches the names of every custom collection, returning their count
+    pub(super) fn cache_custom_collections(&self) -> DbResult<usize> {
+        let collections = collections::table
+            .select((collections::id, collections::name))
+            .filter(collections::id.ge(FIRST_CUSTOM_COLLECTION_ID))
+            .load::<(i32, String)>(&self.conn)?;
+        let count = collections.len();
+        for (id, name) in collections {
+            self.coll_cache.put(id, name)?;
+        }
+        Ok(count)
     }
 
     fn load_collection_names<'a>(
@@ -831,11 +1529,16 @@ impl MysqlDb {
 
     pub(super) fn update_collection(
         &self,
-        user_id: u32,
+        user_id: u64,
         collection_id: i32,
     ) -> DbResult<SyncTimestamp> {
-        let quota = if self.quota.enabled {
-            self.calc_quota_usage_sync(user_id, collection_id)?
+        let quota = if self.quota.enabled || self.quota.usage_counters {
+            let quota = self.calc_quota_usage_sync(user_id, collection_id)?;
+            self.session
+                .borrow_mut()
+                .written_usage
+                .insert((user_id, collection_id), quota.clone());
+            quota
         } else {
             results::GetQuotaUsage {
                 count: 0,
@@ -868,6 +1571,18 @@ impl MysqlDb {
             .bind::<BigInt, _>(&total_bytes)
             .bind::<Integer, _>(&quota.count)
             .execute(&self.conn)?;
+        if self.timestamp_cache.is_some() {
+            let timestamp = self.timestamp();
+            self.session
+                .borrow_mut()
+                .written_timestamps
+                .entry(user_id)
+                .or_default()
+                .insert(collection_id, timestamp);
+            if !self.session.borrow().in_transaction {
+                self.apply_timestamp_cache_updates();
+            }
+        }
         Ok(self.timestamp())
     }
 
@@ -877,12 +1592,16 @@ impl MysqlDb {
         user_id: UserIdentifier,
     ) -> DbResult<results::GetStorageUsage> {
         let uid = user_id.legacy_id as i64;
+        // SUM is NULL for users without any (unexpired) BSOs
         let total_bytes = bso::table
-            .select(sql::<Nullable<BigInt>>("SUM(LENGTH(payload))"))
+            .select(sql::<BigInt>(&format!(
+                "COALESCE(SUM({}), 0)",
+                BSO_PAYLOAD_LENGTH
+            )))
             .filter(bso::user_id.eq(uid))
-            .filter(bso::expiry.gt(&self.timestamp().as_i64()))
-            .get_result::<Option<i64>>(&self.conn)?;
-        Ok(total_bytes.unwrap_or_default() as u64)
+            .filter(unexpired(self.timestamp().as_i64()))
+            .get_result::<i64>(&self.conn)?;
+        Ok(total_bytes as u64)
     }
 
     // Perform a lighter weight "read only" quota storage check
@@ -910,16 +1629,16 @@ impl MysqlDb {
     // perform a heavier weight quota calculation
     fn calc_quota_usage_sync(
         &self,
-        user_id: u32,
+        user_id: u64,
         collection_id: i32,
     ) -> DbResult<results::GetQuotaUsage> {
         let (total_bytes, count): (i64, i32) = bso::table
             .select((
-                sql::<BigInt>(r#"COALESCE(SUM(LENGTH(COALESCE(payload, ""))),0)"#),
+                sql::<BigInt>(&format!("COALESCE(SUM({}), 0)", BSO_PAYLOAD_LENGTH)),
                 sql::<Integer>("COALESCE(COUNT(*),0)"),
             ))
             .filter(bso::user_id.eq(user_id as i64))
-            .filter(bso::expiry.gt(self.timestamp().as_i64()))
+            .filter(unexpired(self.timestamp().as_i64()))
             .filter(bso::collection_id.eq(collection_id))
             .get_result(&self.conn)
             .optional()?
@@ -930,14 +1649,20 @@ impl MysqlDb {
         })
     }
 
-    fn get_collection_usage_sync(
+    pub(super) fn get_collection_usage_sync(
         &self,
         user_id: UserIdentifier,
     ) -> DbResult<results::GetCollectionUsage> {
+        if self.quota.usage_counters {
+            return self.get_collection_counters_sync(&user_id, TOTAL_BYTES);
+        }
         let counts = bso::table
-            .select((bso::collection_id, sql::<BigInt>("SUM(LENGTH(payload))")))
+            .select((
+                bso::collection_id,
+                sql::<BigInt>(&format!("COALESCE(SUM({}), 0)", BSO_PAYLOAD_LENGTH)),
+            ))
             .filter(bso::user_id.eq(user_id.legacy_id as i64))
-            .filter(bso::expiry.gt(&self.timestamp().as_i64()))
+            .filter(unexpired(self.timestamp().as_i64()))
             .group_by(bso::collection_id)
             .load(&self.conn)?
             .into_iter()
@@ -945,10 +1670,13 @@ impl MysqlDb {
         self.map_collection_names(counts)
     }
 
-    fn get_collection_counts_sync(
+    pub(super) fn get_collection_counts_sync(
         &self,
         user_id: UserIdentifier,
     ) -> DbResult<results::GetCollectionCounts> {
+        if self.quota.usage_counters {
+            return self.get_collection_counters_sync(&user_id, COUNT);
+        }
         let counts = bso::table
             .select((
                 bso::collection_id,
@@ -958,7 +1686,7 @@ impl MysqlDb {
                 )),
             ))
             .filter(bso::user_id.eq(user_id.legacy_id as i64))
-            .filter(bso::expiry.gt(&self.timestamp().as_i64()))
+            .filter(unexpired(self.timestamp().as_i64()))
             .group_by(bso::collection_id)
             .load(&self.conn)?
             .into_iter()
@@ -966,6 +1694,26 @@ impl MysqlDb {
         self.map_collection_names(counts)
     }
 
+    /// A usage counter (`count` or `total_bytes`) of each of the user's
+    /// non-empty collections, as last maintained by `update_collection`
+    fn get_collection_counters_sync(
+        &self,
+        user_id: &UserIdentifier,
+        counter: &str,
+    ) -> DbResult<HashMap<String, i64>> {
+        let counters = user_collections::table
+            .select((
+                user_collections::collection_id,
+                sql::<BigInt>(&format!("COALESCE({}, 0)", counter)),
+            ))
+            .filter(user_collections::user_id.eq(user_id.legacy_id as i64))
+            .filter(user_collections::count.gt(0))
+            .load(&self.conn)?
+            .into_iter()
+            .collect();
+        self.map_collection_names(counters)
+    }
+
     batch_db_method!(create_batch_sync, create, CreateBatch);
     batch_db_method!(validate_batch_sync, validate, ValidateBatch);
     batch_db_method!(append_to_batch_sync, append, AppendToBatch);
@@ -981,143 +1729,52 @@ impl MysqlDb {
     }
 }
 
-impl Db for MysqlDb {
-    type Error = DbError;
-
-    fn commit(&self) -> DbFuture<'_, (), Self::Error> {
-        let db = self.clone();
-        Box::pin(self.blocking_threadpool.spawn(move || db.commit_sync()))
-    }
-
-    fn rollback(&self) -> DbFuture<'_, (), Self::Error> {
-        let db = self.clone();
-        Box::pin(self.blocking_threadpool.spawn(move || db.rollback_sync()))
-    }
-
-    fn begin(&self, for_write: bool) -> DbFuture<'_, (), Self::Error> {
-        let db = self.clone();
-        Box::pin(async move { db.begin_async(for_write).map_err(Into::into).await })
-    }
-
-    fn check(&self) -> DbFuture<'_, results::Check, Self::Error> {
-        let db = self.clone();
-        Box::pin(self.blocking_threadpool.spawn(move || db.check_sync()))
-    }
-
-    sync_db_method!(lock_for_read, lock_for_read_sync, LockCollection);
-    sync_db_method!(lock_for_write, lock_for_write_sync, LockCollection);
-    sync_db_method!(
-        get_collection_timestamps,
-        get_collection_timestamps_sync,
-        GetCollectionTimestamps
-    );
-    sync_db_method!(
-        get_collection_timestamp,
-        get_collection_timestamp_sync,
-        GetCollectionTimestamp
-    );
-    sync_db_method!(
-        get_collection_counts,
-        get_collection_counts_sync,
-        GetCollectionCounts
-    );
-    sync_db_method!(
-        get_collection_usage,
-        get_collection_usage_sync,
-        GetCollectionUsage
-    );
-    sync_db_method!(
-        get_storage_timestamp,
-        get_storage_timestamp_sync,
-        GetStorageTimestamp
-    );
-    sync_db_method!(get_storage_usage, get_storage_usage_sync, GetStorageUsage);
-    sync_db_method!(get_quota_usage, get_quota_usage_sync, GetQuotaUsage);
-    sync_db_method!(delete_storage, delete_storage_sync, DeleteStorage);
-    sync_db_method!(delete_collection, delete_collection_sync, DeleteCollection);
-    sync_db_method!(delete_bsos, delete_bsos_sync, DeleteBsos);
-    sync_db_method!(get_bsos, get_bsos_sync, GetBsos);
-    sync_db_method!(get_bso_ids, get_bso_ids_sync, GetBsoIds);
-    sync_db_method!(post_bsos, post_bsos_sync, PostBsos);
-    sync_db_method!(delete_bso, delete_bso_sync, DeleteBso);
-    sync_db_method!(get_bso, get_bso_sync, GetBso, Option<results::GetBso>);
-    sync_db_method!(
-        get_bso_timestamp,
-        get_bso_timestamp_sync,
-        GetBsoTimestamp,
-        results::GetBsoTimestamp
-    );
-    sync_db_method!(put_bso, put_bso_sync, PutBso);
-    sync_db_method!(create_batch, create_batch_sync, CreateBatch);
-    sync_db_method!(validate_batch, validate_batch_sync, ValidateBatch);
-    sync_db_method!(append_to_batch, append_to_batch_sync, AppendToBatch);
-    sync_db_method!(
-        get_batch,
-        get_batch_sync,
-        GetBatch,
-        Option<results::GetBatch>
-    );
-    sync_db_method!(commit_batch, commit_batch_sync, CommitBatch);
-
-    fn get_collection_id(&self, name: String) -> DbFuture<'_, i32, Self::Error> {
-        let db = self.clone();
-        Box::pin(
-            self.blocking_threadpool
-                .spawn(move || db.get_collection_id(&name)),
-        )
-    }
+impl_sql_db!(MysqlDb);
 
-    fn get_connection_info(&self) -> results::ConnectionInfo {
-        results::ConnectionInfo::default()
-    }
+/// Filters the BSOs that haven't expired as of `now` (see
+/// `syncstorage_db_common::is_expired`)
+fn unexpired(now: i64) -> Gt<bso::expiry, i64> {
+    bso::expiry.gt(now)
+}
 
-    fn create_collection(&self, name: String) -> DbFuture<'_, i32, Self::Error> {
-        let db = self.clone();
-        Box::pin(
-            self.blocking_threadpool
-                .spawn(move || db.get_or_create_collection_id(&name)),
+/// Filters the BSOs past `after` in results sorted by `sort` (by `modified`,
+/// then `id`)
+fn past(
+    after: &params::BsoPosition,
+    sort: Sorting,
+) -> Box<dyn BoxableExpression<bso::table, Mysql, SqlType = Bool>> {
+    let (modified, id) = (after.modified.as_i64(), after.id.clone());
+    if sort == Sorting::Oldest {
+        Box::new(
+            bso::modified
+                .gt(modified)
+                .or(bso::modified.eq(modified).and(bso::id.gt(id))),
+        )
+    } else {
+        Box::new(
+            bso::modified
+                .lt(modified)
+                .or(bso::modified.eq(modified).and(bso::id.lt(id))),
         )
     }
+}
 
-    fn update_collection(
-        &self,
-        param: params::UpdateCollection,
-    ) -> DbFuture<'_, SyncTimestamp, Self::Error> {
-        let db = self.clone();
-        Box::pin(self.blocking_threadpool.spawn(move || {
-            db.update_collection(param.user_id.legacy_id as u32, param.collection_id)
-        }))
-    }
-
-    fn timestamp(&self) -> SyncTimestamp {
-        self.timestamp()
-    }
-
-    fn set_timestamp(&self, timestamp: SyncTimestamp) {
-        self.session.borrow_mut().timestamp = timestamp;
-    }
-
-    sync_db_method!(delete_batch, delete_batch_sync, DeleteBatch);
-
-    fn clear_coll_cache(&self) -> DbFuture<'_, (), Self::Error> {
-        let db = self.clone();
-        Box::pin(self.blocking_threadpool.spawn(move || {
-            db.coll_cache.clear();
-            Ok(())
-        }))
-    }
-
-    fn set_quota(&mut self, enabled: bool, limit: usize, enforced: bool) {
-        self.quota = Quota {
-            size: limit,
-            enabled,
-            enforced,
-        }
-    }
+/// Filters the BSOs that have expired as of `now`
+fn expired(now: i64) -> LtEq<bso::expiry, i64> {
+    bso::expiry.le(now)
+}
 
-    fn box_clone(&self) -> Box<dyn Db<Error = Self::Error>> {
-        Box::new(self.clone())
+/// Reject a BSO whose id or payload its columns can't hold: ids with a 400
+/// and payloads with a 413
+pub(super) fn check_bso_size(id: &str, payload: Option<&str>) -> DbResult<()> {
+    check_bso_id(id)?;
+    if payload.is_some_and(|payload| payload.len() > MAX_PAYLOAD_SIZE) {
+        return Err(DbError::too_large(format!(
+            "BSO payload larger than {} bytes",
+            MAX_PAYLOAD_SIZE
+        )));
     }
+    Ok(())
 }
 
 #[derive(Debug, QueryableByName)]
@@ -1133,6 +1790,16 @@ struct NameResult {
     name: String,
 }
 
+#[derive(Debug, QueryableByName)]
+struct EmptyCollectionResult {
+    #[sql_type = "BigInt"]
+    userid: i64, // USER_ID
+    #[sql_type = "Integer"]
+    collection: i32, // COLLECTION_ID
+    #[sql_type = "BigInt"]
+    last_modified: i64, // LAST_MODIFIED
+}
+
 #[derive(Debug, QueryableByName)]
 struct UserCollectionsResult {
     // Can't substitute column names here.
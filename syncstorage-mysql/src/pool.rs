@@ -1,35 +1,57 @@
 use async_trait::async_trait;
 
 use std::{
-    collections::HashMap,
     fmt,
-    sync::{Arc, RwLock},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
     time::Duration,
 };
 
 use diesel::{
-    mysql::MysqlConnection,
-    r2d2::{ConnectionManager, Pool},
-    Connection,
+    mysql::MysqlConnection, r2d2::Pool, result::QueryResult, Connection, ExpressionMethods,
+    QueryDsl, RunQueryDsl,
 };
 #[cfg(debug_assertions)]
 use diesel_logger::LoggingConnection;
-use syncserver_common::{BlockingThreadpool, Metrics};
-#[cfg(debug_assertions)]
-use syncserver_db_common::test::TestTransactionCustomizer;
-use syncserver_db_common::{GetPoolState, PoolState};
-use syncstorage_db_common::{Db, DbPool, STD_COLLS};
+use syncserver_common::{BlockingThreadpool, BulkPermit, CacheBackend, Metrics};
+use syncserver_db_common::{
+    manager::MysqlConnectionManager, migrations::check_schema_version, CheckoutStats, GetPoolState,
+    PoolState,
+};
+use syncstorage_db_common::{results, Db, DbPool};
 use syncstorage_settings::{Quota, Settings};
+use syncstorage_sql_db_common::{batch::validate_batch_id, pool_builder, CollectionCreator};
 
-use super::{error::DbError, models::MysqlDb, DbResult};
+use super::{
+    models::MysqlDb, schema::collections, timestamp_cache::TimestampCache, DbError, DbResult,
+};
 
 embed_migrations!();
 
+/// The version of the latest migration in `migrations/`: the schema version
+/// this build requires the database to be at
+const SCHEMA_VERSION: &str = "20261016040000";
+
+/// How long a lazily initialized pool waits between attempts to initialize
+/// the database
+const LAZY_INIT_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long a statement waits on another transaction's row locks (e.g. those
+/// taken by `MysqlDb::lock_for_write_sync`) before giving up, unless
+/// `database_lock_wait_timeout` says otherwise. Much shorter than InnoDB's
+/// 50 second default, so contended writes are promptly answered with a 503
+/// the client retries after backing off rather than tying up a blocking
+/// thread and a connection
+const LOCK_WAIT_TIMEOUT: Duration = Duration::from_secs(5);
+
 /// Run the diesel embedded migrations
 ///
 /// Mysql DDL statements implicitly commit which could disrupt MysqlPool's
 /// begin_test_transaction during tests. So this runs on its own separate conn.
-fn run_embedded_migrations(database_url: &str) -> DbResult<()> {
+pub fn run_embedded_migrations(database_url: &str) -> DbResult<()> {
     let conn = MysqlConnection::establish(database_url)?;
     #[cfg(debug_assertions)]
     // XXX: this doesn't show the DDL statements
@@ -40,74 +62,195 @@ fn run_embedded_migrations(database_url: &str) -> DbResult<()> {
     Ok(())
 }
 
+pub(super) type CollectionCache =
+    syncstorage_sql_db_common::CollectionCache<MysqlConnectionManager>;
+
+/// Applies pending migrations (when `run_migrations`) and checks the
+/// database is at the schema version this build requires
+fn init_database(database_url: &str, run_migrations: bool) -> DbResult<()> {
+    if run_migrations {
+        run_embedded_migrations(database_url)?;
+    }
+    check_schema_version(&MysqlConnection::establish(database_url)?, SCHEMA_VERSION)?;
+    Ok(())
+}
+
 #[derive(Clone)]
 pub struct MysqlDbPool {
     /// Pool of db connections
-    pool: Pool<ConnectionManager<MysqlConnection>>,
-    /// Thread Pool for running synchronous db calls
-    /// In-memory cache of collection_ids and their names
+    pool: Pool<MysqlConnectionManager>,
+    /// The pool's checkouts, counted by its event handler
+    checkout_stats: CheckoutStats,
+    /// Cache of collection_ids and their names
     coll_cache: Arc<CollectionCache>,
+    /// In-memory cache of recent collection timestamps, when enabled
+    timestamp_cache: Option<Arc<TimestampCache>>,
+    /// Size from which payloads are deduplicated, when enabled
+    payload_dedup_min_size: Option<usize>,
 
     metrics: Metrics,
     quota: Quota,
     blocking_threadpool: Arc<BlockingThreadpool>,
+    /// Whether the database has been migrated and its schema version checked
+    initialized: Arc<AtomicBool>,
 }
 
 impl MysqlDbPool {
     /// Creates a new pool of Mysql db connections.
     ///
-    /// Also initializes the Mysql db, ensuring all migrations are ran.
+    /// Also initializes the Mysql db, ensuring all migrations are ran, unless
+    /// `run_migrations` is disabled.
+    ///
+    /// With `database_pool_lazy_init` enabled this doesn't fail when the db
+    /// is unreachable: the pool is created without connecting and the db is
+    /// initialized by a background thread, retrying until it succeeds. Until
+    /// then the pool reports itself as uninitialized and hands out no
+    /// connections.
     pub fn new(
         settings: &Settings,
         metrics: &Metrics,
         blocking_threadpool: Arc<BlockingThreadpool>,
+        cache_backend: &CacheBackend,
     ) -> DbResult<Self> {
-        run_embedded_migrations(&settings.database_url)?;
-        Self::new_without_migrations(settings, metrics, blocking_threadpool)
+        if !settings.database_pool_lazy_init {
+            if settings.run_migrations {
+                run_embedded_migrations(&settings.database_url)?;
+            }
+            return Self::new_without_migrations(
+                settings,
+                metrics,
+                blocking_threadpool,
+                cache_backend,
+            );
+        }
+
+        let pool = Self::build(settings, metrics, blocking_threadpool, cache_backend, false)?;
+        let initialized = Arc::clone(&pool.initialized);
+        let metrics = metrics.clone();
+        let database_url = settings.database_url.clone();
+        let run_migrations = settings.run_migrations;
+        thread::Builder::new()
+            .name("mysql-db-init".to_owned())
+            .spawn(move || loop {
+                match init_database(&database_url, run_migrations) {
+                    Ok(()) => {
+                        info!("Database initialized");
+                        initialized.store(true, Ordering::Relaxed);
+                        break;
+                    }
+                    Err(e) => {
+                        warn!("Database initialization failed, retrying"; "error" => e.to_string());
+                        metrics.incr("storage.pool.init.failure");
+                        thread::sleep(LAZY_INIT_RETRY_INTERVAL);
+                    }
+                }
+            })
+            .map_err(|e| DbError::internal(format!("Couldn't spawn db init thread: {}", e)))?;
+        Ok(pool)
     }
 
     pub fn new_without_migrations(
         settings: &Settings,
         metrics: &Metrics,
         blocking_threadpool: Arc<BlockingThreadpool>,
+        cache_backend: &CacheBackend,
     ) -> DbResult<Self> {
-        let manager = ConnectionManager::<MysqlConnection>::new(settings.database_url.clone());
-        let builder = Pool::builder()
-            .max_size(settings.database_pool_max_size)
-            .connection_timeout(Duration::from_secs(
-                settings.database_pool_connection_timeout.unwrap_or(30) as u64,
-            ))
-            .min_idle(settings.database_pool_min_idle);
-
-        #[cfg(debug_assertions)]
-        let builder = if settings.database_use_test_transactions {
-            builder.connection_customizer(Box::new(TestTransactionCustomizer))
+        init_database(&settings.database_url, false)?;
+        Self::build(settings, metrics, blocking_threadpool, cache_backend, true)
+    }
+
+    fn build(
+        settings: &Settings,
+        metrics: &Metrics,
+        blocking_threadpool: Arc<BlockingThreadpool>,
+        cache_backend: &CacheBackend,
+        initialized: bool,
+    ) -> DbResult<Self> {
+        let new_manager = || {
+            MysqlConnectionManager::new(
+                settings.database_url.clone(),
+                metrics,
+                settings
+                    .database_statement_timeout
+                    .map(|seconds| Duration::from_secs(seconds.into())),
+                Some(
+                    settings
+                        .database_lock_wait_timeout
+                        .map_or(LOCK_WAIT_TIMEOUT, |seconds| {
+                            Duration::from_secs(seconds.into())
+                        }),
+                ),
+            )
+        };
+        let manager = new_manager();
+        let checkout_stats = CheckoutStats::default();
+        let builder = pool_builder(settings, &checkout_stats);
+        let creator = CollectionCreator::new(new_manager(), settings, insert_collection);
+
+        // An uninitialized pool is likely unable to connect yet: don't wait on
+        // establishing its `min_idle` connections
+        let pool = if initialized {
+            builder.build(manager)?
         } else {
-            builder
+            builder.build_unchecked(manager)
         };
 
         Ok(Self {
-            pool: builder.build(manager)?,
-            coll_cache: Default::default(),
+            pool,
+            checkout_stats,
+            coll_cache: Arc::new(CollectionCache::new(
+                cache_backend,
+                settings
+                    .collection_cache_max_size
+                    .map(|max_size| max_size as usize),
+                Some(creator),
+            )),
+            timestamp_cache: settings
+                .collection_timestamp_cache_max_size
+                .map(|max_size| Arc::new(TimestampCache::new(max_size as usize))),
+            payload_dedup_min_size: settings
+                .payload_dedup_min_size
+                .map(|min_size| min_size as usize),
             metrics: metrics.clone(),
-            quota: Quota {
-                size: settings.limits.max_quota_limit as usize,
-                enabled: settings.enable_quota,
-                enforced: settings.enforce_quota,
-            },
+            quota: Quota::from(settings),
             blocking_threadpool,
+            initialized: Arc::new(AtomicBool::new(initialized)),
         })
     }
 
     pub fn get_sync(&self) -> DbResult<MysqlDb> {
+        if !self.is_initialized() {
+            return Err(DbError::internal(
+                "The database is not initialized yet".to_owned(),
+            ));
+        }
         Ok(MysqlDb::new(
             self.pool.get()?,
             Arc::clone(&self.coll_cache),
+            self.timestamp_cache.clone(),
+            self.payload_dedup_min_size,
             &self.metrics,
             &self.quota,
             self.blocking_threadpool.clone(),
         ))
     }
+
+    /// Checks out `min_idle` connections (at least one) at once, so each is
+    /// established and primed
+    fn warm_up_sync(&self) -> DbResult<usize> {
+        let connections = self
+            .pool
+            .min_idle()
+            .unwrap_or(1)
+            .clamp(1, self.pool.max_size());
+        let dbs = (0..connections)
+            .map(|_| self.get_sync())
+            .collect::<DbResult<Vec<_>>>()?;
+        for db in &dbs {
+            db.prime_statements()?;
+        }
+        dbs[0].cache_custom_collections()
+    }
 }
 
 #[async_trait]
@@ -123,7 +266,29 @@ impl DbPool for MysqlDbPool {
     }
 
     fn validate_batch_id(&self, id: String) -> DbResult<()> {
-        super::batch::validate_batch_id(&id)
+        validate_batch_id(&id)
+    }
+
+    fn collection_cache_stats(&self) -> results::CollectionCacheStats {
+        self.coll_cache.stats()
+    }
+
+    fn is_initialized(&self) -> bool {
+        self.initialized.load(Ordering::Relaxed)
+    }
+
+    async fn warm_up(&self) -> DbResult<usize> {
+        if !self.is_initialized() {
+            return Ok(0);
+        }
+        let pool = self.clone();
+        self.blocking_threadpool
+            .spawn(move || pool.warm_up_sync())
+            .await
+    }
+
+    async fn bulk_permit(&self) -> BulkPermit {
+        self.blocking_threadpool.bulk_permit().await
     }
 
     fn box_clone(&self) -> Box<dyn DbPool<Error = Self::Error>> {
@@ -141,71 +306,36 @@ impl fmt::Debug for MysqlDbPool {
 
 impl GetPoolState for MysqlDbPool {
     fn state(&self) -> PoolState {
-        self.pool.state().into()
+        self.checkout_stats.pool_state(self.pool.state())
     }
 }
 
-#[derive(Debug)]
-pub(super) struct CollectionCache {
-    pub by_name: RwLock<HashMap<String, i32>>,
-    pub by_id: RwLock<HashMap<i32, String>>,
+/// Inserts the collection `name` unless it exists, returning its id (see
+/// `CollectionCreator`)
+fn insert_collection(conn: &MysqlConnection, name: &str) -> QueryResult<i32> {
+    diesel::insert_or_ignore_into(collections::table)
+        .values(collections::name.eq(name))
+        .execute(conn)?;
+    collections::table
+        .select(collections::id)
+        .filter(collections::name.eq(name))
+        .first(conn)
 }
 
-impl CollectionCache {
-    pub fn put(&self, id: i32, name: String) -> DbResult<()> {
-        // XXX: should this emit a metric?
-        // XXX: should probably either lock both simultaneously during
-        // writes or use an RwLock alternative
-        self.by_name
-            .write()
-            .map_err(|_| DbError::internal("by_name write".to_owned()))?
-            .insert(name.clone(), id);
-        self.by_id
-            .write()
-            .map_err(|_| DbError::internal("by_id write".to_owned()))?
-            .insert(id, name);
-        Ok(())
-    }
-
-    pub fn get_id(&self, name: &str) -> DbResult<Option<i32>> {
-        Ok(self
-            .by_name
-            .read()
-            .map_err(|_| DbError::internal("by_name read".to_owned()))?
-            .get(name)
-            .cloned())
-    }
-
-    pub fn get_name(&self, id: i32) -> DbResult<Option<String>> {
-        Ok(self
-            .by_id
-            .read()
-            .map_err(|_| DbError::internal("by_id read".to_owned()))?
-            .get(&id)
-            .cloned())
-    }
-
-    pub fn clear(&self) {
-        self.by_name.write().expect("by_name write").clear();
-        self.by_id.write().expect("by_id write").clear();
-    }
-}
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
 
-impl Default for CollectionCache {
-    fn default() -> Self {
-        Self {
-            by_name: RwLock::new(
-                STD_COLLS
-                    .iter()
-                    .map(|(k, v)| ((*v).to_owned(), *k))
-                    .collect(),
-            ),
-            by_id: RwLock::new(
-                STD_COLLS
-                    .iter()
-                    .map(|(k, v)| (*k, (*v).to_owned()))
-                    .collect(),
-            ),
-        }
+    use syncserver_db_common::migrations::latest_migration_version;
+
+    use super::*;
+
+    #[test]
+    fn schema_version_is_latest_migration() {
+        let migrations_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("migrations");
+        assert_eq!(
+            latest_migration_version(&migrations_dir).as_deref(),
+            Some(SCHEMA_VERSION)
+        );
     }
 }
@@ -6,6 +6,8 @@ table! {
         user_id -> Bigint,
         #[sql_name="collection"]
         collection_id -> Integer,
+        total_records -> Bigint,
+        total_bytes -> Bigint,
     }
 }
 
@@ -37,6 +39,15 @@ table! {
         modified -> Bigint,
         #[sql_name="ttl"]
         expiry -> Bigint,
+        payload_hash -> Nullable<Binary>,
+    }
+}
+
+table! {
+    bso_payloads (hash) {
+        hash -> Binary,
+        payload -> Mediumtext,
+        last_referenced -> Bigint,
     }
 }
 
@@ -62,10 +73,29 @@ table! {
     }
 }
 
+table! {
+    user_epochs (user_id) {
+        #[sql_name="userid"]
+        user_id -> BigInt,
+        epoch -> Bigint,
+    }
+}
+
+table! {
+    user_keys (user_id) {
+        #[sql_name="userid"]
+        user_id -> BigInt,
+        keys_changed_at -> BigInt,
+    }
+}
+
 allow_tables_to_appear_in_same_query!(
     batch_uploads,
     batch_upload_items,
     bso,
+    bso_payloads,
     collections,
     user_collections,
+    user_epochs,
+    user_keys,
 );
@@ -1,17 +1,32 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Barrier},
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use diesel::{
     // expression_methods::TextExpressionMethods, // See note below about `not_like` becoming swedish
+    sql_query,
+    sql_types::{BigInt, Integer, Nullable, Text},
     ExpressionMethods,
     QueryDsl,
     RunQueryDsl,
 };
-use syncserver_common::{BlockingThreadpool, Metrics};
+use http::StatusCode;
+use syncserver_common::{BlockingThreadpool, CacheBackend, Metrics};
 use syncserver_settings::Settings as SyncserverSettings;
+use syncstorage_db_common::{error::DbErrorIntrospect, params, DbPool, UserIdentifier, STD_COLLS};
 use syncstorage_settings::Settings as SyncstorageSettings;
+use syncstorage_sql_db_common::{MAX_BSO_ID_LENGTH, MAX_COLLECTION_NAME_LENGTH};
 use url::Url;
 
-use crate::{models::MysqlDb, pool::MysqlDbPool, schema::collections, DbResult};
+use crate::{
+    models::{check_bso_size, MysqlDb, MAX_PAYLOAD_SIZE},
+    pool::MysqlDbPool,
+    schema::{bso, bso_payloads, collections, user_collections},
+    DbResult,
+};
 
 pub fn db(settings: &SyncstorageSettings) -> DbResult<MysqlDb> {
     let _ = env_logger::try_init();
@@ -21,6 +36,7 @@ pub fn db(settings: &SyncstorageSettings) -> DbResult<MysqlDb> {
         settings,
         &Metrics::noop(),
         Arc::new(BlockingThreadpool::default()),
+        &CacheBackend::Memory,
     )?;
     pool.get_sync()
 }
@@ -78,3 +94,501 @@ fn static_collection_id() -> DbResult<()> {
     assert!(cid >= 100);
     Ok(())
 }
+
+#[test]
+fn create_collection_once_under_write_locks() -> DbResult<()> {
+    let mut settings = SyncserverSettings::test_settings().syncstorage;
+    if Url::parse(&settings.database_url).unwrap().scheme() != "mysql" {
+        // Skip this test if we're not using mysql
+        return Ok(());
+    }
+    // Test transactions create collections within the caller's, uncached
+    settings.database_use_test_transactions = false;
+    settings.database_pool_max_size = 4;
+    let pool = MysqlDbPool::new(
+        &settings,
+        &Metrics::noop(),
+        Arc::new(BlockingThreadpool::default()),
+        &CacheBackend::Memory,
+    )?;
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .subsec_nanos();
+    let name = format!("xxx_herd_{}", nanos);
+
+    // A burst of first writes to the collection, each from within its
+    // write transaction
+    let barrier = Arc::new(Barrier::new(4));
+    let handles = (0..4)
+        .map(|_| {
+            let db = pool.get_sync()?;
+            let (name, barrier) = (name.clone(), Arc::clone(&barrier));
+            Ok(thread::spawn(move || -> DbResult<i32> {
+                db.begin(true)?;
+                barrier.wait();
+                let id = db.get_or_create_collection_id(&name);
+                db.rollback_sync()?;
+                id
+            }))
+        })
+        .collect::<DbResult<Vec<_>>>()?;
+    let ids = handles
+        .into_iter()
+        .map(|handle| handle.join().unwrap())
+        .collect::<DbResult<Vec<_>>>();
+
+    let db = pool.get_sync()?;
+    diesel::delete(collections::table.filter(collections::name.eq(&name)))
+        .execute(&db.inner.conn)?;
+
+    let ids = ids?;
+    assert!(ids.iter().all(|id| *id == ids[0]));
+    // Inserted once, the other writers found it cached
+    assert_eq!(pool.collection_cache_stats().creations, 1);
+    Ok(())
+}
+
+#[derive(Debug, QueryableByName)]
+struct ExplainRow {
+    #[sql_type = "Nullable<Text>"]
+    key: Option<String>,
+    #[sql_type = "Nullable<Text>"]
+    #[column_name = "Extra"]
+    extra: Option<String>,
+}
+
+#[test]
+fn get_bsos_sort_orders_avoid_filesort() -> DbResult<()> {
+    let settings = SyncserverSettings::test_settings().syncstorage;
+    if Url::parse(&settings.database_url).unwrap().scheme() != "mysql" {
+        // Skip this test if we're not using mysql
+        return Ok(());
+    }
+    let db = db(&settings)?;
+
+    // The planner's choice of index on a near empty test table is arbitrary,
+    // so force the index expected to serve each of `get_bsos_sync`'s sort
+    // orders and check it does so without sorting
+    for (index, order) in [
+        ("bso_usr_col_sortindex_idx", "sortindex DESC, id DESC"),
+        ("bso_usr_col_mod_idx", "modified DESC, id DESC"),
+        ("bso_usr_col_mod_idx", "modified ASC, id ASC"),
+    ] {
+        let plan = sql_query(format!(
+            "EXPLAIN SELECT id, modified, payload, sortindex, ttl
+               FROM bso FORCE INDEX ({})
+              WHERE userid = ?
+                AND collection = ?
+                AND ttl > ?
+              ORDER BY {}
+              LIMIT 11",
+            index, order
+        ))
+        .bind::<BigInt, _>(1)
+        .bind::<Integer, _>(1)
+        .bind::<BigInt, _>(0)
+        .load::<ExplainRow>(&db.inner.conn)?;
+
+        assert_eq!(plan.len(), 1);
+        assert_eq!(plan[0].key.as_deref(), Some(index), "{}", order);
+        let extra = plan[0].extra.as_deref().unwrap_or_default();
+        assert!(!extra.contains("filesort"), "{}: {}", order, extra);
+    }
+    Ok(())
+}
+
+#[test]
+fn delete_expired_bsos() -> DbResult<()> {
+    let settings = SyncserverSettings::test_settings().syncstorage;
+    if Url::parse(&settings.database_url).unwrap().scheme() != "mysql" {
+        // Skip this test if we're not using mysql
+        return Ok(());
+    }
+    let db = db(&settings)?;
+
+    let expired_bso = bso::table
+        .filter(bso::user_id.eq(1))
+        .filter(bso::collection_id.eq(1))
+        .filter(bso::id.eq("expired"));
+    diesel::insert_into(bso::table)
+        .values((
+            bso::user_id.eq(1),
+            bso::collection_id.eq(1),
+            bso::id.eq("expired"),
+            bso::payload.eq(""),
+            bso::payload_size.eq(0),
+            bso::modified.eq(0),
+            bso::expiry.eq(1),
+        ))
+        .execute(&db.inner.conn)?;
+    assert_eq!(expired_bso.count().get_result::<i64>(&db.inner.conn)?, 1);
+
+    // Works the same whether or not the test database is partitioned
+    let partitions = db.get_bso_partitions_sync()?;
+    if partitions.is_empty() {
+        db.delete_expired_bsos_sync(None, 1_000)?;
+    }
+    for partition in &partitions {
+        db.delete_expired_bsos_sync(Some(partition), 1_000)?;
+    }
+    assert_eq!(expired_bso.count().get_result::<i64>(&db.inner.conn)?, 0);
+
+    assert!(db
+        .delete_expired_bsos_sync(Some("p0; DROP TABLE bso"), 1)
+        .is_err());
+    Ok(())
+}
+
+#[derive(Debug, QueryableByName)]
+struct MaxExecutionTime {
+    #[sql_type = "BigInt"]
+    max_execution_time: i64,
+}
+
+#[test]
+fn statement_timeout_sets_max_execution_time() -> DbResult<()> {
+    let mut settings = SyncserverSettings::test_settings().syncstorage;
+    if Url::parse(&settings.database_url).unwrap().scheme() != "mysql" {
+        // Skip this test if we're not using mysql
+        return Ok(());
+    }
+    settings.database_statement_timeout = Some(3);
+    let db = db(&settings)?;
+
+    let result = sql_query("SELECT @@SESSION.max_execution_time AS max_execution_time")
+        .get_result::<MaxExecutionTime>(&db.inner.conn)?;
+    assert_eq!(result.max_execution_time, 3_000);
+    Ok(())
+}
+
+#[derive(Debug, QueryableByName)]
+struct LockWaitTimeout {
+    #[sql_type = "BigInt"]
+    innodb_lock_wait_timeout: i64,
+}
+
+#[test]
+fn lock_wait_timeout_defaults_short() -> DbResult<()> {
+    let mut settings = SyncserverSettings::test_settings().syncstorage;
+    if Url::parse(&settings.database_url).unwrap().scheme() != "mysql" {
+        // Skip this test if we're not using mysql
+        return Ok(());
+    }
+    let lock_wait_timeout = |db: &MysqlDb| {
+        sql_query("SELECT @@SESSION.innodb_lock_wait_timeout AS innodb_lock_wait_timeout")
+            .get_result::<LockWaitTimeout>(&db.inner.conn)
+            .map(|result| result.innodb_lock_wait_timeout)
+    };
+
+    settings.database_lock_wait_timeout = None;
+    assert_eq!(lock_wait_timeout(&db(&settings)?)?, 5);
+    settings.database_lock_wait_timeout = Some(2);
+    assert_eq!(lock_wait_timeout(&db(&settings)?)?, 2);
+    Ok(())
+}
+
+#[test]
+fn contended_write_lock_times_out() -> DbResult<()> {
+    let mut settings = SyncserverSettings::test_settings().syncstorage;
+    if Url::parse(&settings.database_url).unwrap().scheme() != "mysql" {
+        // Skip this test if we're not using mysql
+        return Ok(());
+    }
+    settings.database_lock_wait_timeout = Some(1);
+    let pool = MysqlDbPool::new(
+        &settings,
+        &Metrics::noop(),
+        Arc::new(BlockingThreadpool::default()),
+        &CacheBackend::Memory,
+    )?;
+    let (db, db2) = (pool.get_sync()?, pool.get_sync()?);
+
+    let lock = || params::LockCollection {
+        user_id: UserIdentifier {
+            legacy_id: 1_253,
+            ..Default::default()
+        },
+        collection: "bookmarks".to_owned(),
+    };
+    db.lock_for_write_sync(lock())?;
+    // Blocked by the first write's (uncommitted) rows until it gives up
+    let err = db2.lock_for_write_sync(lock()).unwrap_err();
+    assert!(err.is_lock_timeout());
+    assert_eq!(err.status, StatusCode::SERVICE_UNAVAILABLE);
+    db2.rollback_sync()?;
+    db.rollback_sync()?;
+    Ok(())
+}
+
+#[test]
+fn delete_unreferenced_payloads() -> DbResult<()> {
+    let settings = SyncserverSettings::test_settings().syncstorage;
+    if Url::parse(&settings.database_url).unwrap().scheme() != "mysql" {
+        // Skip this test if we're not using mysql
+        return Ok(());
+    }
+    let db = db(&settings)?;
+
+    let referenced = vec![1; 32];
+    let unreferenced = vec![2; 32];
+    let recent = vec![3; 32];
+    for (hash, last_referenced) in [(&referenced, 0), (&unreferenced, 0), (&recent, i64::MAX)] {
+        diesel::insert_into(bso_payloads::table)
+            .values((
+                bso_payloads::hash.eq(hash),
+                bso_payloads::payload.eq("payload"),
+                bso_payloads::last_referenced.eq(last_referenced),
+            ))
+            .execute(&db.inner.conn)?;
+    }
+    diesel::insert_into(bso::table)
+        .values((
+            bso::user_id.eq(1),
+            bso::collection_id.eq(1),
+            bso::id.eq("deduplicated"),
+            bso::payload.eq(""),
+            bso::payload_size.eq(7),
+            bso::modified.eq(0),
+            bso::expiry.eq(i64::MAX),
+            bso::payload_hash.eq(&referenced),
+        ))
+        .execute(&db.inner.conn)?;
+
+    db.delete_unreferenced_payloads_sync(1_000)?;
+    let mut remaining = bso_payloads::table
+        .select(bso_payloads::hash)
+        .filter(bso_payloads::hash.eq_any(vec![&referenced, &unreferenced, &recent]))
+        .load::<Vec<u8>>(&db.inner.conn)?;
+    remaining.sort();
+    assert_eq!(remaining, vec![referenced, recent]);
+    Ok(())
+}
+
+#[test]
+fn post_bsos_isolates_failing_bsos() -> DbResult<()> {
+    let settings = SyncserverSettings::test_settings().syncstorage;
+    if Url::parse(&settings.database_url).unwrap().scheme() != "mysql" {
+        // Skip this test if we're not using mysql
+        return Ok(());
+    }
+    let db = db(&settings)?;
+
+    let user_id = UserIdentifier {
+        legacy_id: 1,
+        ..Default::default()
+    };
+    let postbso = |id: &str| params::PostCollectionBso {
+        id: id.to_owned(),
+        payload: Some(format!("payload {}", id)),
+        sortindex: None,
+        ttl: None,
+    };
+    // Too long for the id column: rejected rather than failing the
+    // multi-row insert it'd be part of
+    let too_long = "x".repeat(65);
+    let result = db.post_bsos_sync(params::PostBsos {
+        user_id: user_id.clone(),
+        collection: "bookmarks".to_owned(),
+        bsos: vec![postbso("b0"), postbso(&too_long), postbso("b2")],
+        for_batch: false,
+        failed: HashMap::new(),
+    })?;
+    assert_eq!(result.success, vec!["b0", "b2"]);
+    assert!(result.failed.contains_key(&too_long));
+
+    let bso = db.get_bso_sync(params::GetBso {
+        user_id,
+        collection: "bookmarks".to_owned(),
+        id: "b2".to_owned(),
+    })?;
+    assert_eq!(bso.unwrap().payload, "payload b2");
+    Ok(())
+}
+
+#[test]
+fn session_settings_pinned() -> DbResult<()> {
+    #[derive(QueryableByName)]
+    struct SessionSettings {
+        #[sql_type = "Text"]
+        sql_mode: String,
+        #[sql_type = "Text"]
+        collation: String,
+    }
+
+    let settings = SyncserverSettings::test_settings().syncstorage;
+    if Url::parse(&settings.database_url).unwrap().scheme() != "mysql" {
+        // Skip this test if we're not using mysql
+        return Ok(());
+    }
+    let db = db(&settings)?;
+
+    let session = sql_query(
+        "SELECT @@SESSION.sql_mode AS sql_mode, @@SESSION.collation_connection AS collation",
+    )
+    .get_result::<SessionSettings>(&db.inner.conn)?;
+    assert_eq!(session.sql_mode, "STRICT_ALL_TABLES,NO_ENGINE_SUBSTITUTION");
+    assert_eq!(session.collation, "utf8mb4_bin");
+    Ok(())
+}
+
+#[test]
+fn oversized_values_rejected() -> DbResult<()> {
+    let settings = SyncserverSettings::test_settings().syncstorage;
+    if Url::parse(&settings.database_url).unwrap().scheme() != "mysql" {
+        // Skip this test if we're not using mysql
+        return Ok(());
+    }
+    let db = db(&settings)?;
+
+    let user_id = UserIdentifier {
+        legacy_id: 1,
+        ..Default::default()
+    };
+    let put_bso = |collection: &str, id: &str| {
+        db.put_bso_sync(params::PutBso {
+            user_id: user_id.clone(),
+            collection: collection.to_owned(),
+            id: id.to_owned(),
+            payload: Some("payload".to_owned()),
+            sortindex: None,
+            ttl: None,
+        })
+    };
+
+    put_bso("bookmarks", &"x".repeat(MAX_BSO_ID_LENGTH))?;
+    let err = put_bso("bookmarks", &"x".repeat(MAX_BSO_ID_LENGTH + 1)).unwrap_err();
+    assert_eq!(err.status, StatusCode::BAD_REQUEST);
+
+    put_bso(&"c".repeat(MAX_COLLECTION_NAME_LENGTH), "b0")?;
+    let err = put_bso(&"c".repeat(MAX_COLLECTION_NAME_LENGTH + 1), "b0").unwrap_err();
+    assert_eq!(err.status, StatusCode::BAD_REQUEST);
+
+    // Larger than MySQL's default max_allowed_packet: rejected without
+    // reaching MySQL
+    let err = db
+        .put_bso_sync(params::PutBso {
+            user_id,
+            collection: "bookmarks".to_owned(),
+            id: "b0".to_owned(),
+            payload: Some("x".repeat(MAX_PAYLOAD_SIZE + 1)),
+            sortindex: None,
+            ttl: None,
+        })
+        .unwrap_err();
+    assert_eq!(err.status, StatusCode::PAYLOAD_TOO_LARGE);
+    Ok(())
+}
+
+#[test]
+fn bso_size_limits() {
+    let payload = "x".repeat(MAX_PAYLOAD_SIZE);
+    assert!(check_bso_size(&"x".repeat(MAX_BSO_ID_LENGTH), Some(&payload)).is_ok());
+    assert!(check_bso_size("b0", None).is_ok());
+
+    let err = check_bso_size(&"x".repeat(MAX_BSO_ID_LENGTH + 1), None).unwrap_err();
+    assert_eq!(err.status, StatusCode::BAD_REQUEST);
+    let err = check_bso_size("b0", Some(&(payload + "x"))).unwrap_err();
+    assert_eq!(err.status, StatusCode::PAYLOAD_TOO_LARGE);
+}
+
+#[test]
+fn warm_up_queries() -> DbResult<()> {
+    let settings = SyncserverSettings::test_settings().syncstorage;
+    if Url::parse(&settings.database_url).unwrap().scheme() != "mysql" {
+        // Skip this test if we're not using mysql
+        return Ok(());
+    }
+    let db = db(&settings)?;
+
+    db.prime_statements()?;
+    db.get_or_create_collection_id("xxx_warm_up")?;
+    assert!(db.cache_custom_collections()? >= 1);
+    Ok(())
+}
+
+#[test]
+fn collection_usage_counters() -> DbResult<()> {
+    let mut settings = SyncserverSettings::test_settings().syncstorage;
+    if Url::parse(&settings.database_url).unwrap().scheme() != "mysql" {
+        // Skip this test if we're not using mysql
+        return Ok(());
+    }
+    settings.collection_usage_counters = true;
+    let db = db(&settings)?;
+
+    let user_id = UserIdentifier {
+        legacy_id: 1_243,
+        ..Default::default()
+    };
+    db.delete_storage_sync(user_id.clone())?;
+    let postbso = |id: &str, ttl| params::PostCollectionBso {
+        id: id.to_owned(),
+        payload: Some("payload".to_owned()),
+        sortindex: None,
+        ttl,
+    };
+    db.post_bsos_sync(params::PostBsos {
+        user_id: user_id.clone(),
+        collection: "history".to_owned(),
+        bsos: vec![
+            postbso("h0", None),
+            postbso("h1", None),
+            postbso("h2", Some(0)),
+        ],
+        for_batch: false,
+        failed: HashMap::new(),
+    })?;
+
+    // Maintained by the write, excluding what had already expired
+    let counts = db.get_collection_counts_sync(user_id.clone())?;
+    assert_eq!(counts, HashMap::from([("history".to_owned(), 2)]));
+    let usage = db.get_collection_usage_sync(user_id)?;
+    assert_eq!(usage, HashMap::from([("history".to_owned(), 14)]));
+    Ok(())
+}
+
+#[test]
+fn statements_counted() -> DbResult<()> {
+    let settings = SyncserverSettings::test_settings().syncstorage;
+    if Url::parse(&settings.database_url).unwrap().scheme() != "mysql" {
+        // Skip this test if we're not using mysql
+        return Ok(());
+    }
+    let db = db(&settings)?;
+
+    let before = db.conn.query_count();
+    sql_query("SELECT 1").execute(&db.inner.conn)?;
+    db.get_or_create_collection_id("xxx_statements_counted")?;
+    assert!(db.conn.query_count() >= before + 2);
+    Ok(())
+}
+
+#[test]
+fn std_collections_created_on_first_write() -> DbResult<()> {
+    let settings = SyncserverSettings::test_settings().syncstorage;
+    if Url::parse(&settings.database_url).unwrap().scheme() != "mysql" {
+        // Skip this test if we're not using mysql
+        return Ok(());
+    }
+    let db = db(&settings)?;
+
+    let user_id = UserIdentifier {
+        legacy_id: 1_245,
+        ..Default::default()
+    };
+    db.delete_storage_sync(user_id.clone())?;
+    db.lock_for_write_sync(params::LockCollection {
+        user_id: user_id.clone(),
+        collection: "bookmarks".to_owned(),
+    })?;
+    let rows = user_collections::table
+        .filter(user_collections::user_id.eq(1_245))
+        .count()
+        .get_result::<i64>(&db.inner.conn)?;
+    assert_eq!(rows as usize, STD_COLLS.len());
+    // Unwritten until they're written to
+    assert!(db.get_collection_timestamps_sync(user_id)?.is_empty());
+    db.rollback_sync()?;
+    Ok(())
+}
@@ -0,0 +1,189 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, MutexGuard,
+    },
+};
+
+use syncstorage_db_common::util::SyncTimestamp;
+
+/// Number of write counters users are spread across
+const WRITE_COUNTER_BUCKETS: usize = 64;
+
+/// Pool level cache of recently seen collection modified timestamps per
+/// (user_id, collection_id), letting precondition checks and
+/// info/collections of actively syncing users skip the db.
+///
+/// Committed writes update the cached timestamps of users already cached and
+/// deletes drop users altogether. Since only this process's writes are seen,
+/// the cache is only correct when every write for a user goes through it.
+///
+/// Reads populating the cache race with concurrent writes: a read may
+/// return a timestamp that a write commits over before the read's result is
+/// cached. So writes bump a counter (shared by the users in a bucket) once
+/// committed and reads only populate the cache when their bucket's counter
+/// didn't change since before the read.
+#[derive(Debug)]
+pub(super) struct TimestampCache {
+    /// Max number of users cached
+    max_size: usize,
+    by_user: Mutex<HashMap<u64, UserTimestamps>>,
+    write_counters: [AtomicU64; WRITE_COUNTER_BUCKETS],
+}
+
+#[derive(Debug, Default)]
+struct UserTimestamps {
+    /// Whether `modified` holds all of the user's collections
+    complete: bool,
+    modified: HashMap<i32, SyncTimestamp>,
+}
+
+/// A user's write counter as of before a read (see `TimestampCache`)
+#[derive(Clone, Copy, Debug)]
+pub(super) struct ReadToken(u64);
+
+impl TimestampCache {
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            max_size,
+            by_user: Mutex::default(),
+            write_counters: Default::default(),
+        }
+    }
+
+    /// Must be taken before reading timestamps from the db that are then
+    /// cached
+    pub fn read_token(&self, user_id: u64) -> ReadToken {
+        ReadToken(self.write_counter(user_id).load(Ordering::SeqCst))
+    }
+
+    pub fn get(&self, user_id: u64, collection_id: i32) -> Option<SyncTimestamp> {
+        self.lock()
+            .get(&user_id)
+            .and_then(|user| user.modified.get(&collection_id))
+            .copied()
+    }
+
+    /// All of the user's collection timestamps, when known
+    pub fn get_all(&self, user_id: u64) -> Option<HashMap<i32, SyncTimestamp>> {
+        self.lock()
+            .get(&user_id)
+            .filter(|user| user.complete)
+            .map(|user| user.modified.clone())
+    }
+
+    /// Cache a collection timestamp read from the db
+    pub fn put(&self, token: ReadToken, user_id: u64, collection_id: i32, modified: SyncTimestamp) {
+        self.populate(token, user_id, |user| {
+            set_max(
+                user.modified.entry(collection_id).or_insert(modified),
+                modified,
+            );
+        });
+    }
+
+    /// Cache all of a user's collection timestamps read from the db
+    pub fn put_all(&self, token: ReadToken, user_id: u64, modified: HashMap<i32, SyncTimestamp>) {
+        self.populate(token, user_id, |user| {
+            user.complete = true;
+            user.modified = modified;
+        });
+    }
+
+    /// Record committed writes to a user's collections
+    pub fn update(&self, user_id: u64, modified: &HashMap<i32, SyncTimestamp>) {
+        self.write_counter(user_id).fetch_add(1, Ordering::SeqCst);
+        if let Some(user) = self.lock().get_mut(&user_id) {
+            for (&collection_id, &modified) in modified {
+                set_max(
+                    user.modified.entry(collection_id).or_insert(modified),
+                    modified,
+                );
+            }
+        }
+    }
+
+    /// Drop a user's cached timestamps, e.g. after deleting collections
+    pub fn invalidate(&self, user_id: u64) {
+        self.write_counter(user_id).fetch_add(1, Ordering::SeqCst);
+        self.lock().remove(&user_id);
+    }
+
+    fn populate(&self, token: ReadToken, user_id: u64, f: impl FnOnce(&mut UserTimestamps)) {
+        let mut by_user = self.lock();
+        // Checked while holding the lock, which writes also take after
+        // bumping their counter
+        if self.read_token(user_id).0 != token.0 {
+            return;
+        }
+        if !by_user.contains_key(&user_id) && by_user.len() >= self.max_size {
+            let evicted = by_user.keys().next().copied();
+            if let Some(evicted) = evicted {
+                by_user.remove(&evicted);
+            }
+        }
+        f(by_user.entry(user_id).or_default());
+    }
+
+    fn write_counter(&self, user_id: u64) -> &AtomicU64 {
+        &self.write_counters[user_id as usize % WRITE_COUNTER_BUCKETS]
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<u64, UserTimestamps>> {
+        self.by_user.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn set_max(cached: &mut SyncTimestamp, modified: SyncTimestamp) {
+    if modified > *cached {
+        *cached = modified;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ts(millis: i64) -> SyncTimestamp {
+        SyncTimestamp::from_i64(millis).unwrap()
+    }
+
+    #[test]
+    fn writes_update_cached_users() {
+        let cache = TimestampCache::new(10);
+        cache.update(1, &HashMap::from([(7, ts(1_000))]));
+        assert_eq!(cache.get(1, 7), None);
+
+        cache.put(cache.read_token(1), 1, 7, ts(1_000));
+        cache.update(1, &HashMap::from([(7, ts(2_000)), (8, ts(2_000))]));
+        assert_eq!(cache.get(1, 7), Some(ts(2_000)));
+        assert_eq!(cache.get(1, 8), Some(ts(2_000)));
+        // Only info/collections fills in all of a user's collections
+        assert_eq!(cache.get_all(1), None);
+
+        cache.invalidate(1);
+        assert_eq!(cache.get(1, 7), None);
+    }
+
+    #[test]
+    fn reads_racing_writes_arent_cached() {
+        let cache = TimestampCache::new(10);
+        let token = cache.read_token(1);
+        cache.update(1, &HashMap::from([(7, ts(2_000))]));
+        cache.put_all(token, 1, HashMap::from([(7, ts(1_000))]));
+        assert_eq!(cache.get_all(1), None);
+
+        cache.put_all(cache.read_token(1), 1, HashMap::from([(7, ts(2_000))]));
+        assert_eq!(cache.get_all(1), Some(HashMap::from([(7, ts(2_000))])));
+    }
+
+    #[test]
+    fn evicts_users_when_full() {
+        let cache = TimestampCache::new(1);
+        cache.put(cache.read_token(1), 1, 7, ts(1_000));
+        cache.put(cache.read_token(2), 2, 7, ts(1_000));
+        assert_eq!(cache.get(1, 7), None);
+        assert_eq!(cache.get(2, 7), Some(ts(1_000)));
+    }
+}
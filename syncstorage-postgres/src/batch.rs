@@ -0,0 +1,231 @@
+use diesel::{
+    self,
+    dsl::sql,
+    insert_into,
+    result::{DatabaseErrorKind::UniqueViolation, Error as DieselError},
+    sql_query,
+    sql_types::{BigInt, Integer, Nullable, Text},
+    ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl,
+};
+use syncstorage_db_common::{params, results, UserIdentifier, BATCH_LIFETIME};
+use syncstorage_sql_db_common::batch::{decode_id, encode_id, MAXTTL};
+
+use super::{
+    models::{check_bso_size, PostgresDb},
+    schema::{batch_upload_items, batch_uploads},
+    DbError, DbResult,
+};
+
+pub fn create(db: &PostgresDb, params: params::CreateBatch) -> DbResult<results::CreateBatch> {
+    let user_id = params.user_id.legacy_id as i64;
+    let collection_id = db.get_collection_id(&params.collection)?;
+    // Careful, there's some weirdness here!
+    //
+    // Sync timestamps are in seconds and quantized to two decimal places, so
+    // when we convert one to a bigint in milliseconds, the final digit is
+    // always zero. But we want to use the lower digits of the batchid for
+    // sharding writes via (batchid % num_tables), and leaving it as zero would
+    // skew the sharding distribution.
+    //
+    // So we mix in the lowest digit of the uid to improve the distribution
+    // while still letting us treat these ids as millisecond timestamps.  It's
+    // yuck, but it works and it keeps the weirdness contained to this single
+    // line of code.
+    let batch_id = db.timestamp().as_i64() + (user_id % 10);
+    insert_into(batch_uploads::table)
+        .values((
+            batch_uploads::batch_id.eq(&batch_id),
+            batch_uploads::user_id.eq(&user_id),
+            batch_uploads::collection_id.eq(&collection_id),
+        ))
+        .execute(&db.conn)
+        .map_err(|e| -> DbError {
+            match e {
+                // The user tried to create two batches with the same timestamp
+                DieselError::DatabaseError(UniqueViolation, _) => DbError::conflict(),
+                _ => e.into(),
+            }
+        })?;
+
+    do_append(db, batch_id, params.user_id, collection_id, params.bsos)?;
+    Ok(results::CreateBatch {
+        id: encode_id(batch_id),
+        size: None,
+    })
+}
+
+pub fn validate(db: &PostgresDb, params: params::ValidateBatch) -> DbResult<bool> {
+    let batch_id = decode_id(&params.id)?;
+    // Avoid hitting the db for batches that are obviously too old.  Recall
+    // that the batchid is a millisecond timestamp.
+    if (batch_id + BATCH_LIFETIME) < db.timestamp().as_i64() {
+        return Ok(false);
+    }
+
+    let user_id = params.user_id.legacy_id as i64;
+    let collection_id = db.get_collection_id(&params.collection)?;
+    let exists = batch_uploads::table
+        .select(sql::<Integer>("1"))
+        .filter(batch_uploads::batch_id.eq(&batch_id))
+        .filter(batch_uploads::user_id.eq(&user_id))
+        .filter(batch_uploads::collection_id.eq(&collection_id))
+        .get_result::<i32>(&db.conn)
+        .optional()?;
+    Ok(exists.is_some())
+}
+
+pub fn append(db: &PostgresDb, params: params::AppendToBatch) -> DbResult<results::AppendToBatch> {
+    let exists = validate(
+        db,
+        params::ValidateBatch {
+            user_id: params.user_id.clone(),
+            collection: params.collection.clone(),
+            id: params.batch.id.clone(),
+        },
+    )?;
+
+    if !exists {
+        return Err(DbError::batch_not_found());
+    }
+
+    let batch_id = decode_id(&params.batch.id)?;
+    let collection_id = db.get_collection_id(&params.collection)?;
+    do_append(db, batch_id, params.user_id, collection_id, params.bsos)
+}
+
+pub fn get(db: &PostgresDb, params: params::GetBatch) -> DbResult<Option<results::GetBatch>> {
+    let batch_id = decode_id(&params.id)?;
+    // See validate
+    if (batch_id + BATCH_LIFETIME) < db.timestamp().as_i64() {
+        return Ok(None);
+    }
+
+    let user_id = params.user_id.legacy_id as i64;
+    let collection_id = db.get_collection_id(&params.collection)?;
+    let totals = batch_uploads::table
+        .select((batch_uploads::total_records, batch_uploads::total_bytes))
+        .filter(batch_uploads::batch_id.eq(&batch_id))
+        .filter(batch_uploads::user_id.eq(&user_id))
+        .filter(batch_uploads::collection_id.eq(&collection_id))
+        .get_result::<(i64, i64)>(&db.conn)
+        .optional()?;
+    Ok(totals.map(|(records, bytes)| results::GetBatch {
+        id: params.id,
+        totals: results::BatchTotals {
+            records: records as u64,
+            bytes: bytes as u64,
+        },
+    }))
+}
+
+pub fn delete(db: &PostgresDb, params: params::DeleteBatch) -> DbResult<()> {
+    let batch_id = decode_id(&params.id)?;
+    let user_id = params.user_id.legacy_id as i64;
+    let collection_id = db.get_collection_id(&params.collection)?;
+    diesel::delete(batch_uploads::table)
+        .filter(batch_uploads::batch_id.eq(&batch_id))
+        .filter(batch_uploads::user_id.eq(&user_id))
+        .filter(batch_uploads::collection_id.eq(&collection_id))
+        .execute(&db.conn)?;
+    diesel::delete(batch_upload_items::table)
+        .filter(batch_upload_items::batch_id.eq(&batch_id))
+        .filter(batch_upload_items::user_id.eq(&user_id))
+        .execute(&db.conn)?;
+    Ok(())
+}
+
+/// Commits a batch to the bsos table, deleting the batch when succesful.
+///
+/// Updates the batch's existing bsos then inserts its new ones, as an upsert
+/// can't leave the columns of the existing ones the batch didn't set as is
+pub fn commit(db: &PostgresDb, params: params::CommitBatch) -> DbResult<results::CommitBatch> {
+    let batch_id = decode_id(&params.batch.id)?;
+    let user_id = params.user_id.legacy_id as i64;
+    let collection_id = db.get_collection_id(&params.collection)?;
+    let timestamp = db.timestamp();
+    sql_query(include_str!("batch_commit_update.sql"))
+        .bind::<BigInt, _>(timestamp.as_i64())
+        .bind::<BigInt, _>(batch_id)
+        .bind::<BigInt, _>(user_id)
+        .bind::<Integer, _>(collection_id)
+        .execute(&db.conn)?;
+    sql_query(include_str!("batch_commit_insert.sql"))
+        .bind::<BigInt, _>(timestamp.as_i64())
+        .bind::<BigInt, _>(batch_id)
+        .bind::<BigInt, _>(user_id)
+        .bind::<Integer, _>(collection_id)
+        .bind::<BigInt, _>((MAXTTL as i64) * 1000) // XXX:
+        .execute(&db.conn)?;
+
+    db.update_collection(user_id as u64, collection_id)?;
+
+    delete(
+        db,
+        params::DeleteBatch {
+            user_id: params.user_id,
+            collection: params.collection,
+            id: params.batch.id,
+        },
+    )?;
+    Ok(timestamp)
+}
+
+/// Appends bsos to a batch, returning the batch's updated totals
+pub fn do_append(
+    db: &PostgresDb,
+    batch_id: i64,
+    user_id: UserIdentifier,
+    _collection_id: i32,
+    bsos: Vec<params::PostCollectionBso>,
+) -> DbResult<results::BatchTotals> {
+    for bso in &bsos {
+        check_bso_size(&bso.id, bso.payload.as_deref())?;
+    }
+
+    let user_id = user_id.legacy_id as i64;
+    let records = bsos.len() as i64;
+    let bytes: i64 = bsos
+        .iter()
+        .map(|bso| bso.payload.as_ref().map_or(0, |p| p.len() as i64))
+        .sum();
+
+    // One statement per bso: a single upsert can't update a row twice, as
+    // a list repeating a bso id would
+    for bso in bsos {
+        let payload_size = bso.payload.as_ref().map(|p| p.len() as i64);
+        sql_query(
+            "INSERT INTO batch_upload_items
+                (batch, userid, id, sortindex, payload, payload_size, ttl_offset)
+             VALUES ($1, $2, $3, $4, $5, $6, $7)
+                 ON CONFLICT (batch, userid, id) DO UPDATE SET
+                    sortindex = COALESCE(EXCLUDED.sortindex, batch_upload_items.sortindex),
+                    payload = COALESCE(EXCLUDED.payload, batch_upload_items.payload),
+                    payload_size = COALESCE(EXCLUDED.payload_size, batch_upload_items.payload_size),
+                    ttl_offset = COALESCE(EXCLUDED.ttl_offset, batch_upload_items.ttl_offset)",
+        )
+        .bind::<BigInt, _>(batch_id)
+        .bind::<BigInt, _>(user_id)
+        .bind::<Text, _>(bso.id)
+        .bind::<Nullable<Integer>, _>(bso.sortindex)
+        .bind::<Nullable<Text>, _>(bso.payload)
+        .bind::<Nullable<BigInt>, _>(payload_size)
+        .bind::<Nullable<Integer>, _>(bso.ttl.map(|ttl| ttl as i32))
+        .execute(&db.conn)?;
+    }
+
+    let (records, bytes) = diesel::update(
+        batch_uploads::table
+            .filter(batch_uploads::batch_id.eq(batch_id))
+            .filter(batch_uploads::user_id.eq(user_id)),
+    )
+    .set((
+        batch_uploads::total_records.eq(batch_uploads::total_records + records),
+        batch_uploads::total_bytes.eq(batch_uploads::total_bytes + bytes),
+    ))
+    .returning((batch_uploads::total_records, batch_uploads::total_bytes))
+    .get_result::<(i64, i64)>(&db.conn)?;
+    Ok(results::BatchTotals {
+        records: records as u64,
+        bytes: bytes as u64,
+    })
+}
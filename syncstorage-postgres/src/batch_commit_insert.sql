@@ -0,0 +1,14 @@
+INSERT INTO bso (userid, collection, id, modified, sortindex, ttl, payload, payload_size)
+SELECT
+       $3,
+       $4,
+       id,
+       $1,
+       sortindex,
+       COALESCE((ttl_offset::BIGINT * 1000) + $1, $5),
+       COALESCE(payload, ''),
+       COALESCE(payload_size, 0)
+  FROM batch_upload_items
+ WHERE batch = $2
+   AND userid = $3
+    ON CONFLICT (userid, collection, id) DO NOTHING
@@ -0,0 +1,12 @@
+UPDATE bso
+   SET modified = $1,
+       sortindex = COALESCE(batch_upload_items.sortindex, bso.sortindex),
+       ttl = COALESCE((batch_upload_items.ttl_offset::BIGINT * 1000) + $1, bso.ttl),
+       payload = COALESCE(batch_upload_items.payload, bso.payload),
+       payload_size = COALESCE(batch_upload_items.payload_size, bso.payload_size)
+  FROM batch_upload_items
+ WHERE batch_upload_items.batch = $2
+   AND batch_upload_items.userid = $3
+   AND bso.userid = $3
+   AND bso.collection = $4
+   AND bso.id = batch_upload_items.id
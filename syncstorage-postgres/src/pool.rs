@@ -0,0 +1,319 @@
+use async_trait::async_trait;
+
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use diesel::{
+    pg::PgConnection, r2d2::Pool, result::QueryResult, Connection, ExpressionMethods, QueryDsl,
+    RunQueryDsl,
+};
+#[cfg(debug_assertions)]
+use diesel_logger::LoggingConnection;
+use syncserver_common::{BlockingThreadpool, BulkPermit, CacheBackend, Metrics};
+use syncserver_db_common::{
+    manager::PostgresConnectionManager, migrations::check_schema_version, CheckoutStats,
+    GetPoolState, PoolState,
+};
+use syncstorage_db_common::{results, Db, DbPool};
+use syncstorage_settings::{Quota, Settings};
+use syncstorage_sql_db_common::{batch::validate_batch_id, pool_builder, CollectionCreator};
+
+use super::{models::PostgresDb, schema::collections, DbError, DbResult};
+
+embed_migrations!();
+
+/// The version of the latest migration in `migrations/`: the schema version
+/// this build requires the database to be at
+const SCHEMA_VERSION: &str = "20261016010000";
+
+/// How long a lazily initialized pool waits between attempts to initialize
+/// the database
+const LAZY_INIT_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Run the diesel embedded migrations
+///
+/// Like the MySQL backend's, this runs on its own separate conn rather than
+/// one of the pool's (which tests wrap in a test transaction).
+pub fn run_embedded_migrations(database_url: &str) -> DbResult<()> {
+    let conn = PgConnection::establish(database_url)?;
+    #[cfg(debug_assertions)]
+    // XXX: this doesn't show the DDL statements
+    // https://github.com/shssoichiro/diesel-logger/issues/1
+    embedded_migrations::run(&LoggingConnection::new(conn))?;
+    #[cfg(not(debug_assertions))]
+    embedded_migrations::run(&conn)?;
+    Ok(())
+}
+
+pub(super) type CollectionCache =
+    syncstorage_sql_db_common::CollectionCache<PostgresConnectionManager>;
+
+/// Applies pending migrations (when `run_migrations`) and checks the
+/// database is at the schema version this build requires
+fn init_database(database_url: &str, run_migrations: bool) -> DbResult<()> {
+    if run_migrations {
+        run_embedded_migrations(database_url)?;
+    }
+    check_schema_version(&PgConnection::establish(database_url)?, SCHEMA_VERSION)?;
+    Ok(())
+}
+
+#[derive(Clone)]
+pub struct PostgresDbPool {
+    /// Pool of db connections
+    pool: Pool<PostgresConnectionManager>,
+    /// The pool's checkouts, counted by its event handler
+    checkout_stats: CheckoutStats,
+    /// Cache of collection_ids and their names
+    coll_cache: Arc<CollectionCache>,
+
+    metrics: Metrics,
+    quota: Quota,
+    blocking_threadpool: Arc<BlockingThreadpool>,
+    /// Whether the database has been migrated and its schema version checked
+    initialized: Arc<AtomicBool>,
+}
+
+impl PostgresDbPool {
+    /// Creates a new pool of PostgreSQL db connections.
+    ///
+    /// Also initializes the PostgreSQL db, ensuring all migrations are ran,
+    /// unless `run_migrations` is disabled.
+    ///
+    /// Unlike the MySQL backend, this doesn't support the collection
+    /// timestamp cache nor payload deduplication: their settings are ignored.
+    ///
+    /// With `database_pool_lazy_init` enabled this doesn't fail when the db
+    /// is unreachable: the pool is created without connecting and the db is
+    /// initialized by a background thread, retrying until it succeeds. Until
+    /// then the pool reports itself as uninitialized and hands out no
+    /// connections.
+    pub fn new(
+        settings: &Settings,
+        metrics: &Metrics,
+        blocking_threadpool: Arc<BlockingThreadpool>,
+        cache_backend: &CacheBackend,
+    ) -> DbResult<Self> {
+        if !settings.database_pool_lazy_init {
+            if settings.run_migrations {
+                run_embedded_migrations(&settings.database_url)?;
+            }
+            return Self::new_without_migrations(
+                settings,
+                metrics,
+                blocking_threadpool,
+                cache_backend,
+            );
+        }
+
+        let pool = Self::build(settings, metrics, blocking_threadpool, cache_backend, false)?;
+        let initialized = Arc::clone(&pool.initialized);
+        let metrics = metrics.clone();
+        let database_url = settings.database_url.clone();
+        let run_migrations = settings.run_migrations;
+        thread::Builder::new()
+            .name("postgres-db-init".to_owned())
+            .spawn(move || loop {
+                match init_database(&database_url, run_migrations) {
+                    Ok(()) => {
+                        info!("Database initialized");
+                        initialized.store(true, Ordering::Relaxed);
+                        break;
+                    }
+                    Err(e) => {
+                        warn!("Database initialization failed, retrying"; "error" => e.to_string());
+                        metrics.incr("storage.pool.init.failure");
+                        thread::sleep(LAZY_INIT_RETRY_INTERVAL);
+                    }
+                }
+            })
+            .map_err(|e| DbError::internal(format!("Couldn't spawn db init thread: {}", e)))?;
+        Ok(pool)
+    }
+
+    pub fn new_without_migrations(
+        settings: &Settings,
+        metrics: &Metrics,
+        blocking_threadpool: Arc<BlockingThreadpool>,
+        cache_backend: &CacheBackend,
+    ) -> DbResult<Self> {
+        init_database(&settings.database_url, false)?;
+        Self::build(settings, metrics, blocking_threadpool, cache_backend, true)
+    }
+
+    fn build(
+        settings: &Settings,
+        metrics: &Metrics,
+        blocking_threadpool: Arc<BlockingThreadpool>,
+        cache_backend: &CacheBackend,
+        initialized: bool,
+    ) -> DbResult<Self> {
+        let new_manager = || {
+            PostgresConnectionManager::new(
+                settings.database_url.clone(),
+                metrics,
+                settings
+                    .database_statement_timeout
+                    .map(|seconds| Duration::from_secs(seconds.into())),
+                settings
+                    .database_lock_wait_timeout
+                    .map(|seconds| Duration::from_secs(seconds.into())),
+            )
+        };
+        let manager = new_manager();
+        let checkout_stats = CheckoutStats::default();
+        let builder = pool_builder(settings, &checkout_stats);
+        let creator = CollectionCreator::new(new_manager(), settings, insert_collection);
+
+        // An uninitialized pool is likely unable to connect yet: don't wait on
+        // establishing its `min_idle` connections
+        let pool = if initialized {
+            builder.build(manager)?
+        } else {
+            builder.build_unchecked(manager)
+        };
+
+        Ok(Self {
+            pool,
+            checkout_stats,
+            coll_cache: Arc::new(CollectionCache::new(
+                cache_backend,
+                settings
+                    .collection_cache_max_size
+                    .map(|max_size| max_size as usize),
+                Some(creator),
+            )),
+            metrics: metrics.clone(),
+            quota: Quota::from(settings),
+            blocking_threadpool,
+            initialized: Arc::new(AtomicBool::new(initialized)),
+        })
+    }
+
+    pub fn get_sync(&self) -> DbResult<PostgresDb> {
+        if !self.is_initialized() {
+            return Err(DbError::internal(
+                "The database is not initialized yet".to_owned(),
+            ));
+        }
+        Ok(PostgresDb::new(
+            self.pool.get()?,
+            Arc::clone(&self.coll_cache),
+            &self.metrics,
+            &self.quota,
+            self.blocking_threadpool.clone(),
+        ))
+    }
+
+    /// Checks out `min_idle` connections (at least one) at once, so each is
+    /// established and primed
+    fn warm_up_sync(&self) -> DbResult<usize> {
+        let connections = self
+            .pool
+            .min_idle()
+            .unwrap_or(1)
+            .clamp(1, self.pool.max_size());
+        let dbs = (0..connections)
+            .map(|_| self.get_sync())
+            .collect::<DbResult<Vec<_>>>()?;
+        for db in &dbs {
+            db.prime_statements()?;
+        }
+        dbs[0].cache_custom_collections()
+    }
+}
+
+#[async_trait]
+impl DbPool for PostgresDbPool {
+    type Error = DbError;
+
+    async fn get<'a>(&'a self) -> DbResult<Box<dyn Db<Error = Self::Error>>> {
+        let pool = self.clone();
+        self.blocking_threadpool
+            .spawn(move || pool.get_sync())
+            .await
+            .map(|db| Box::new(db) as Box<dyn Db<Error = Self::Error>>)
+    }
+
+    fn validate_batch_id(&self, id: String) -> DbResult<()> {
+        validate_batch_id(&id)
+    }
+
+    fn collection_cache_stats(&self) -> results::CollectionCacheStats {
+        self.coll_cache.stats()
+    }
+
+    fn is_initialized(&self) -> bool {
+        self.initialized.load(Ordering::Relaxed)
+    }
+
+    async fn warm_up(&self) -> DbResult<usize> {
+        if !self.is_initialized() {
+            return Ok(0);
+        }
+        let pool = self.clone();
+        self.blocking_threadpool
+            .spawn(move || pool.warm_up_sync())
+            .await
+    }
+
+    async fn bulk_permit(&self) -> BulkPermit {
+        self.blocking_threadpool.bulk_permit().await
+    }
+
+    fn box_clone(&self) -> Box<dyn DbPool<Error = Self::Error>> {
+        Box::new(self.clone())
+    }
+}
+
+impl fmt::Debug for PostgresDbPool {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("PostgresDbPool")
+            .field("coll_cache", &self.coll_cache)
+            .finish()
+    }
+}
+
+impl GetPoolState for PostgresDbPool {
+    fn state(&self) -> PoolState {
+        self.checkout_stats.pool_state(self.pool.state())
+    }
+}
+
+/// Inserts the collection `name` unless it exists, returning its id (see
+/// `CollectionCreator`)
+fn insert_collection(conn: &PgConnection, name: &str) -> QueryResult<i32> {
+    diesel::insert_into(collections::table)
+        .values(collections::name.eq(name))
+        .on_conflict_do_nothing()
+        .execute(conn)?;
+    collections::table
+        .select(collections::id)
+        .filter(collections::name.eq(name))
+        .first(conn)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use syncserver_db_common::migrations::latest_migration_version;
+
+    use super::*;
+
+    #[test]
+    fn schema_version_is_latest_migration() {
+        let migrations_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("migrations");
+        assert_eq!(
+            latest_migration_version(&migrations_dir).as_deref(),
+            Some(SCHEMA_VERSION)
+        );
+    }
+}
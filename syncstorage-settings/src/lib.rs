@@ -1,6 +1,6 @@
 //! Application settings objects and initialization
 
-use std::cmp::min;
+use std::{cmp::min, collections::HashMap, sync::Arc};
 
 use rand::{thread_rng, Rng};
 use serde::{Deserialize, Serialize};
@@ -20,11 +20,53 @@ pub static DEFAULT_MAX_TOTAL_RECORDS: u32 = 100 * DEFAULT_MAX_POST_RECORDS;
 // This gives us more than a bit of wiggle room.
 static DEFAULT_MAX_QUOTA_LIMIT: u32 = 2 * GIGABYTE;
 
-#[derive(Clone, Debug, Default, Copy)]
+#[derive(Clone, Debug, Default)]
 pub struct Quota {
     pub size: usize,
     pub enabled: bool,
     pub enforced: bool,
+    /// Per user limits overriding `size`, keyed by uid (see
+    /// `Settings::quota_overrides`)
+    pub overrides: Arc<HashMap<String, usize>>,
+    /// Whether MySQL maintains the per collection usage counters (serving
+    /// the info endpoints from them) whether or not quota's enabled (see
+    /// `Settings::collection_usage_counters`)
+    pub usage_counters: bool,
+}
+
+impl Quota {
+    /// The quota limit applying to a user: `None` when quota is disabled or
+    /// the user is exempt from it
+    pub fn limit_for(&self, legacy_id: u64, fxa_uid: &str) -> Option<usize> {
+        if !self.enabled {
+            return None;
+        }
+        let limit = self
+            .overrides
+            .get(fxa_uid)
+            .or_else(|| self.overrides.get(&legacy_id.to_string()))
+            .copied()
+            .unwrap_or(self.size);
+        (limit > 0).then_some(limit)
+    }
+}
+
+impl From<&Settings> for Quota {
+    fn from(settings: &Settings) -> Self {
+        Quota {
+            size: settings.limits.max_quota_limit as usize,
+            enabled: settings.enable_quota,
+            enforced: settings.enforce_quota,
+            overrides: Arc::new(
+                settings
+                    .quota_overrides
+                    .iter()
+                    .map(|(uid, limit)| (uid.clone(), *limit as usize))
+                    .collect(),
+            ),
+            usage_counters: settings.collection_usage_counters,
+        }
+    }
 }
 
 #[derive(Copy, Clone, Default, Debug)]
@@ -75,20 +117,156 @@ pub struct Settings {
     pub database_pool_connection_lifespan: Option<u32>,
     /// Max time a connection should sit idle before being dropped.
     pub database_pool_connection_max_idle: Option<u32>,
+    /// Whether MySQL connections are validated when checked out of the pool.
+    /// Connections failing validation are evicted and replaced rather than
+    /// handed to a request, so stale connections left over from a failover
+    /// don't surface as "server has gone away" errors
+    pub database_pool_test_on_checkout: bool,
+    /// Whether the server starts even when the database is unreachable,
+    /// initializing it in the background and retrying until it succeeds
+    /// (heartbeats fail meanwhile). By default startup fails fast instead.
+    /// MySQL and PostgreSQL only: Spanner sessions are always created on
+    /// demand
+    pub database_pool_lazy_init: bool,
+    /// Whether the db pool is warmed up before the server starts accepting
+    /// requests: its `database_pool_min_idle` connections (at least one) are
+    /// established with their common statements prepared (MySQL only) and
+    /// the custom collections' names cached. Skipped by a lazily initialized
+    /// pool that hasn't reached the database yet
+    pub database_pool_warm_up: bool,
+    /// Max execution time of a single statement, in seconds, after which it's
+    /// cancelled rather than left holding its pooled connection. Applied as
+    /// the MySQL session's `max_execution_time` (which only covers `SELECT`s),
+    /// as PostgreSQL's `statement_timeout` and as the deadline of every
+    /// Spanner RPC (SQLite ignores it). Unlimited when unset
+    pub database_statement_timeout: Option<u32>,
+    /// Max time a statement waits on a lock held by another transaction
+    /// (e.g. a write waiting on the collection lock of another of the user's
+    /// writes), in seconds, after which the request fails with a 503 and a
+    /// `Retry-After`. Applied as the `innodb_lock_wait_timeout` of each
+    /// pooled MySQL connection (5 seconds when unset), PostgreSQL's
+    /// `lock_timeout` (unlimited when unset) and SQLite's `busy_timeout` (10
+    /// seconds when unset); Spanner ignores it
+    pub database_lock_wait_timeout: Option<u32>,
     #[cfg(debug_assertions)]
     pub database_use_test_transactions: bool,
-    #[cfg(debug_assertions)]
+    /// Whether BSO puts and posts to Spanner are written with mutations
+    /// rather than DML. Mutations are cheaper for large
+    /// writes but count every cell against Spanner's per commit mutation
+    /// limit, while DML reads its own writes within the transaction
     pub database_spanner_use_mutations: bool,
     /// Whether leader aware router headers are sent to Spanner
     pub database_spanner_route_to_leader: bool,
+    /// Spanner RPC priority ("low", "medium" or "high") for user facing
+    /// requests. Spanner's default (high) when unset
+    pub database_spanner_interactive_priority: Option<String>,
+    /// Spanner RPC priority for batch upload appends and commits
+    pub database_spanner_batch_priority: Option<String>,
+    /// Staleness (in seconds) of Spanner reads of collections, letting them
+    /// be served by the nearest replica without waiting for it to catch up.
+    /// Reads stay strong for users who wrote within that time, so clients
+    /// still read their own writes. Strong reads when unset
+    pub database_spanner_read_staleness: Option<u32>,
+    /// How often table-wide row counts are reported as metrics, in seconds.
+    /// These queries scan whole tables: disabled when unset.
+    pub database_stats_interval: Option<u32>,
+    /// How often (in seconds) the estimated numbers of distinct users seen
+    /// per UTC day and week are reported as metrics. With a shared
+    /// `cache_url` every host merges its estimates into the cache's, so each
+    /// reports the deployment's daily/weekly active users; otherwise only
+    /// the users it served. Disabled when unset
+    pub active_users_report_interval: Option<u32>,
+    /// How often (in seconds) expired BSOs, and the collections they leave
+    /// empty, are deleted from MySQL, PostgreSQL or SQLite. Spanner's are
+    /// left to `purge_ttl` or its row deletion policies. A single instance
+    /// of a deployment purging suffices: disabled when unset
+    pub purge_interval: Option<u32>,
+    /// Max number of rows deleted per statement of a purge, bounding how
+    /// long each holds its locks
+    pub purge_batch_size: u32,
+    /// Max number of rows deleted per purge, the rest waiting for the next
+    pub purge_max_rows: u32,
+    /// Max number of BSOs queued for purging when reads don't find them (in
+    /// case they expired), deleted in the background without waiting for
+    /// the next purge. Disabled when unset
+    pub purge_on_read_queue_size: Option<u32>,
+    /// Whether pending MySQL, PostgreSQL or SQLite migrations are applied
+    /// when the server starts.
+    /// When disabled, migrations are only applied by the `migrate`
+    /// subcommand and the server refuses to start against an outdated schema
+    pub run_migrations: bool,
+    /// Max number of collection id/name mappings cached by the db pool, when
+    /// cached in process (see `cache_url`). Unbounded when unset
+    pub collection_cache_max_size: Option<u32>,
+    /// Max number of users whose collection timestamps are cached by the
+    /// MySQL db pool, so precondition checks and info/collections of actively
+    /// syncing users skip the db. Only correct when every write for a user
+    /// goes through this one process (e.g. a single self-hosted instance):
+    /// writes made by other instances aren't seen, so it's always held in
+    /// process regardless of `cache_url`. Disabled when unset
+    pub collection_timestamp_cache_max_size: Option<u32>,
+    /// Max number of tokens whose Hawk key (derived from the master secret
+    /// by HKDF) is cached until the token expires, so the requests of a
+    /// syncing client skip the derivation. Always held in process: the keys
+    /// are secrets. Disabled when 0
+    pub hawk_key_cache_max_size: u32,
+    /// Max number of nonces of recently accepted Hawk requests held in
+    /// process, so replays of a request are rejected; when full the oldest
+    /// nonces are evicted. Disabled when 0
+    pub hawk_nonce_cache_max_size: u32,
+    /// How far (in seconds) a Hawk request's timestamp may be from the
+    /// server's clock while nonces are checked: their replays are rejected
+    /// for as long
+    pub hawk_nonce_window: u32,
+    /// Whether nonces are held in the `cache_url` backend rather than in
+    /// process, so replays to other processes are caught too (atomically on
+    /// Redis only)
+    pub hawk_nonce_cache_shared: bool,
+    /// Size (in bytes) from which BSO payloads written by PUTs and POSTs are
+    /// stored once per distinct content in MySQL's `bso_payloads` table,
+    /// for deployments where many users store identical payloads. Payloads
+    /// committed by batch uploads are always stored inline. Disabled when
+    /// unset
+    pub payload_dedup_min_size: Option<u32>,
+    /// Whether MySQL keeps each collection's BSO count and size up to date
+    /// in `user_collections` on every write (as quota does), serving
+    /// info/collection_counts and info/collection_usage from them rather
+    /// than aggregating every BSO of the user. Trades a heavier write for
+    /// cheap info requests from heavy users. Expired BSOs are counted until
+    /// their collection's next write, as are BSOs of collections last
+    /// written before this was enabled (which are missing until then)
+    pub collection_usage_counters: bool,
 
     /// Server-enforced limits for request payloads.
     pub limits: ServerLimits,
+    /// Max number of BSOs returned by a collection GET, whatever its
+    /// `limit`: larger results are paginated (with an `X-Weave-Next-Offset`).
+    /// Unlimited when unset
+    pub max_get_records: Option<u32>,
+    /// Max combined size (in bytes) of the payloads returned by a full
+    /// collection GET, past which it's paginated as well. A page always
+    /// holds at least one BSO. Unlimited when unset
+    pub max_get_bytes: Option<u32>,
 
     pub statsd_label: String,
 
     pub enable_quota: bool,
     pub enforce_quota: bool,
+    /// Per collection usage (in bytes) past which writes still succeed but
+    /// carry quota warning headers. `limits.max_quota_limit` remains the
+    /// hard limit
+    pub quota_soft_limit: Option<u32>,
+    /// Per user quota limits (in bytes), keyed by uid: the FxA uid for
+    /// Spanner or the numeric user id for the SQL backends (scoped to
+    /// their tenant, see `tenants`). A limit of 0 exempts the user from
+    /// quota entirely (e.g. for QA accounts)
+    pub quota_overrides: HashMap<String, u32>,
+    /// Tenants served besides the default one, so isolated sync populations
+    /// (e.g. staging and production FxA) can share a database. Keyed by the
+    /// name their Tokenserver stamps into its tokens (its `tenant` setting),
+    /// with the id scoping their users' db keys: ids must be unique, non
+    /// zero and never reused. Tokens of unknown tenants are rejected
+    pub tenants: HashMap<String, u16>,
 
     pub spanner_emulator_host: Option<String>,
     pub enabled: bool,
@@ -99,6 +277,50 @@ pub struct Settings {
     /// Percentage of `lbheartbeat_ttl` time to "jitter" (adds additional,
     /// randomized time)
     pub lbheartbeat_ttl_jitter: u32,
+
+    /// Number of requests in flight past which the server is considered
+    /// overloaded
+    pub overload_max_in_flight_requests: Option<u32>,
+    /// Average time (in milliseconds) spent waiting on a database connection
+    /// past which the server is considered overloaded
+    pub overload_max_pool_wait_ms: Option<u32>,
+    /// Fraction (0.0 - 1.0) of the responses sent while overloaded that carry
+    /// an `X-Weave-Backoff` header. The responses themselves still succeed
+    pub overload_backoff_fraction: f64,
+    /// The `X-Weave-Backoff` value (in seconds) asking clients to hold off
+    /// syncing
+    pub overload_backoff_seconds: u32,
+    /// Percentage (0 - 100) of users, bucketed by uid, sent an
+    /// `X-Weave-Backoff` header regardless of load. Adjustable at runtime
+    /// through the `/__backoff__` admin endpoint
+    pub backoff_broadcast_percentage: u8,
+    /// BSOs written by a single user within a minute past which they're
+    /// reported as a pathological client
+    pub abuse_max_writes_per_minute: Option<u32>,
+    /// Payload bytes written by a single user within a minute past which
+    /// they're reported as a pathological client
+    pub abuse_max_bytes_per_minute: Option<u32>,
+    /// How long (in seconds) reported users are then sent an
+    /// `X-Weave-Backoff` for. Users are only reported when unset
+    pub abuse_backoff_seconds: Option<u32>,
+    /// Max writes (PUTs and POSTs, each batch upload request counting as one)
+    /// per minute a single user may make to a collection, keyed by
+    /// collection name (e.g. `tabs`). Users may burst up to the limit, past
+    /// which writes are rejected with a 503 and an `X-Weave-Backoff` until
+    /// their allowance refills. Collections without a (non zero) limit are
+    /// never throttled
+    pub collection_write_rate_limits: HashMap<String, u32>,
+    /// Bearer token required by admin endpoints, which are disabled when
+    /// unset
+    pub admin_token: Option<String>,
+    /// Latency budget (in milliseconds) past which requests log a WARN
+    /// breaking down where their time went (auth, lock, queries, etc).
+    /// Requests aren't traced when unset
+    pub slow_request_threshold_ms: Option<u32>,
+    /// Number of database statements a request may issue before it's
+    /// logged at WARN and counted (as `storage.db.query_budget_exceeded`),
+    /// flagging N+1 query patterns. Unchecked when unset
+    pub database_query_budget: Option<u32>,
 }
 
 impl Default for Settings {
@@ -110,19 +332,58 @@ impl Default for Settings {
             database_pool_connection_lifespan: None,
             database_pool_connection_max_idle: None,
             database_pool_connection_timeout: Some(30),
+            database_pool_test_on_checkout: true,
+            database_pool_lazy_init: false,
+            database_pool_warm_up: false,
+            database_statement_timeout: None,
+            database_lock_wait_timeout: None,
             #[cfg(debug_assertions)]
             database_use_test_transactions: false,
-            #[cfg(debug_assertions)]
             database_spanner_use_mutations: true,
             database_spanner_route_to_leader: false,
+            database_spanner_interactive_priority: None,
+            database_spanner_batch_priority: None,
+            database_spanner_read_staleness: None,
+            database_stats_interval: None,
+            active_users_report_interval: None,
+            purge_interval: None,
+            purge_batch_size: 1_000,
+            purge_max_rows: 100_000,
+            purge_on_read_queue_size: None,
+            run_migrations: true,
+            collection_cache_max_size: None,
+            collection_timestamp_cache_max_size: None,
+            hawk_key_cache_max_size: 10_000,
+            hawk_nonce_cache_max_size: 100_000,
+            hawk_nonce_window: 60,
+            hawk_nonce_cache_shared: false,
+            payload_dedup_min_size: None,
+            collection_usage_counters: false,
             limits: ServerLimits::default(),
+            max_get_records: None,
+            max_get_bytes: None,
             statsd_label: "syncstorage".to_string(),
             enable_quota: false,
             enforce_quota: false,
+            quota_soft_limit: None,
+            quota_overrides: HashMap::new(),
+            tenants: HashMap::new(),
             spanner_emulator_host: None,
             enabled: true,
             lbheartbeat_ttl: None,
             lbheartbeat_ttl_jitter: 25,
+            overload_max_in_flight_requests: None,
+            overload_max_pool_wait_ms: None,
+            overload_backoff_fraction: 0.1,
+            overload_backoff_seconds: 300,
+            backoff_broadcast_percentage: 0,
+            abuse_max_writes_per_minute: None,
+            abuse_max_bytes_per_minute: None,
+            abuse_backoff_seconds: None,
+            collection_write_rate_limits: HashMap::new(),
+            admin_token: None,
+            slow_request_threshold_ms: None,
+            database_query_budget: None,
         }
     }
 }
@@ -138,6 +399,7 @@ impl Settings {
             self.limits.max_quota_limit = 0;
             self.enable_quota = false;
             self.enforce_quota = false;
+            self.quota_soft_limit = None;
         }
     }
 
@@ -167,13 +429,12 @@ pub struct ServerLimits {
     /// Maximum size of an individual BSO payload, in bytes.
     pub max_record_payload_bytes: u32,
 
-    /// Maximum `Content-Length` for all incoming requests, in bytes.
+    /// Maximum body size of all incoming requests, in bytes.
     ///
-    /// Enforced externally to this repo, at the web server level.
-    /// It's important that nginx (or whatever)
-    /// really is configured to enforce exactly this limit,
-    /// otherwise client requests may fail with a 413
-    /// before even reaching the API.
+    /// Larger bodies are rejected with a 413 before being buffered. A web
+    /// server in front (nginx or whatever) should enforce the same limit,
+    /// otherwise client requests may fail with a 413 before even reaching
+    /// the API.
     pub max_request_bytes: u32,
 
     /// Maximum combined size of BSO payloads across a batch upload, in bytes.
@@ -198,3 +459,27 @@ impl Default for ServerLimits {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quota_overrides() {
+        let mut settings = Settings {
+            enable_quota: true,
+            ..Default::default()
+        };
+        settings.limits.max_quota_limit = 100;
+        settings.quota_overrides.insert("42".to_owned(), 1_000);
+        settings.quota_overrides.insert("qa-fxa-uid".to_owned(), 0);
+        let quota = Quota::from(&settings);
+
+        assert_eq!(quota.limit_for(1, "fxa-uid"), Some(100));
+        assert_eq!(quota.limit_for(42, "fxa-uid"), Some(1_000));
+        assert_eq!(quota.limit_for(1, "qa-fxa-uid"), None);
+
+        settings.enable_quota = false;
+        assert_eq!(Quota::from(&settings).limit_for(42, "fxa-uid"), None);
+    }
+}
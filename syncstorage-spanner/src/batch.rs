@@ -72,7 +72,10 @@ pub async fn validate_async(db: &SpannerDb, params: params::ValidateBatch) -> Db
 }
 
 // Append a collection to a pending batch (`create_batch` creates a new batch)
-pub async fn append_async(db: &SpannerDb, params: params::AppendToBatch) -> DbResult<()> {
+pub async fn append_async(
+    db: &SpannerDb,
+    params: params::AppendToBatch,
+) -> DbResult<results::AppendToBatch> {
     let mut metrics = db.metrics.clone();
     metrics.start_timer("storage.spanner.append_items_to_batch", None);
     let collection_id = db.get_collection_id_async(&params.collection).await?;
@@ -109,8 +112,7 @@ pub async fn append_async(db: &SpannerDb, params: params::AppendToBatch) -> DbRe
         params.bsos,
         &params.collection,
     )
-    .await?;
-    Ok(())
+    .await
 }
 
 pub async fn get_async(
@@ -126,7 +128,7 @@ pub async fn get_async(
     };
     let batch = db
         .sql(
-            "SELECT 1
+            "SELECT COALESCE(total_records, 0), COALESCE(total_bytes, 0)
                FROM batches
               WHERE fxa_uid = @fxa_uid
                 AND fxa_kid = @fxa_kid
@@ -138,9 +140,15 @@ pub async fn get_async(
         .param_types(sqlparam_types)
         .execute_async(&db.conn)?
         .one_or_none()
-        .await?
-        .map(move |_| params::Batch { id: params.id });
-    Ok(batch)
+        .await?;
+    batch
+        .map(|row| {
+            Ok(params::Batch {
+                id: params.id,
+                totals: batch_totals(&row)?,
+            })
+        })
+        .transpose()
 }
 
 pub async fn delete_async(db: &SpannerDb, params: params::DeleteBatch) -> DbResult<()> {
@@ -249,12 +257,13 @@ pub async fn do_append_async(
     batch: results::CreateBatch,
     bsos: Vec<params::PostCollectionBso>,
     collection: &str,
-) -> DbResult<()> {
+) -> DbResult<results::BatchTotals> {
     // Pass an array of struct objects as @values (for UNNEST), e.g.:
     // [("<fxa_uid>", "<fxa_kid>", 101, "ba1", "bso1", NULL, "payload1", NULL),
     //  ("<fxa_uid>", "<fxa_kid>", 101, "ba1", "bso2", NULL, "payload2", NULL)]
     // https://cloud.google.com/spanner/docs/structs#creating_struct_objects
     let mut running_size: usize = 0;
+    let records = bsos.len() as i64;
 
     // problem: Append may try to insert a duplicate record into the batch_bsos table.
     // this is because spanner doesn't do upserts easily. An upsert like operation can
@@ -401,9 +410,9 @@ pub async fn do_append_async(
         );
     }
 
-    if db.quota.enabled {
+    if let Some(limit) = db.quota.limit_for(user_id.legacy_id, &user_id.fxa_uid) {
         if let Some(size) = batch.size {
-            if size + running_size >= db.quota.size {
+            if size + running_size >= limit {
                 if db.quota.enforced {
                     return Err(db.quota_error(collection));
                 } else {
@@ -513,7 +522,56 @@ pub async fn do_append_async(
         }
     }
 
-    Ok(())
+    let (sqlparams, sqlparam_types) = params! {
+        "fxa_uid" => user_id.fxa_uid.clone(),
+        "fxa_kid" => user_id.fxa_kid.clone(),
+        "collection_id" => collection_id,
+        "batch_id" => batch.id.clone(),
+        "records" => records,
+        "bytes" => running_size as i64,
+    };
+    db.sql(
+        "UPDATE batches
+            SET total_records = COALESCE(total_records, 0) + @records,
+                total_bytes = COALESCE(total_bytes, 0) + @bytes
+          WHERE fxa_uid = @fxa_uid
+            AND fxa_kid = @fxa_kid
+            AND collection_id = @collection_id
+            AND batch_id = @batch_id",
+    )?
+    .params(sqlparams.clone())
+    .param_types(sqlparam_types.clone())
+    .execute_dml_async(&db.conn)
+    .await?;
+    let row = db
+        .sql(
+            "SELECT total_records, total_bytes
+               FROM batches
+              WHERE fxa_uid = @fxa_uid
+                AND fxa_kid = @fxa_kid
+                AND collection_id = @collection_id
+                AND batch_id = @batch_id",
+        )?
+        .params(sqlparams)
+        .param_types(sqlparam_types)
+        .execute_async(&db.conn)?
+        .one()
+        .await?;
+    batch_totals(&row)
+}
+
+/// Parses a batch's totals from the first two columns of a row
+fn batch_totals(row: &[Value]) -> DbResult<results::BatchTotals> {
+    let parse = |value: &Value| {
+        value
+            .get_string_value()
+            .parse::<u64>()
+            .map_err(|e| DbError::integrity(e.to_string()))
+    };
+    Ok(results::BatchTotals {
+        records: parse(&row[0])?,
+        bytes: parse(&row[1])?,
+    })
 }
 
 /// Ensure a parent row exists in user_collections prior to creating a child
@@ -10,7 +10,8 @@ use cadence::{
 };
 use google_cloud_rust_raw::spanner::v1::{
     spanner::{
-        BeginTransactionRequest, CommitRequest, CreateSessionRequest, ExecuteSqlRequest, Session,
+        BeginTransactionRequest, CommitRequest, CreateSessionRequest, ExecuteSqlRequest,
+        RequestOptions, RequestOptions_Priority, Session,
     },
     spanner_grpc::SpannerClient,
     transaction::{
@@ -20,11 +21,13 @@ use google_cloud_rust_raw::spanner::v1::{
 };
 use grpcio::{CallOption, ChannelBuilder, ChannelCredentials, EnvBuilder, MetadataBuilder};
 use log::{info, trace, warn};
+use syncstorage_spanner::NATIVE_TTL_TABLES_QUERY;
 use url::{Host, Url};
 
 const SPANNER_ADDRESS: &str = "spanner.googleapis.com:443";
 const RETRY_ENV_VAR: &str = "PURGE_TTL_RETRY_COUNT"; // Default value = 10
 const SLEEP_ENV_VAR: &str = "PURGE_TTL_RETRY_SLEEP_MILLIS"; // Default value = 0
+const PRIORITY_ENV_VAR: &str = "PURGE_TTL_PRIORITY"; // Default value = low
 
 use protobuf::well_known_types::Value;
 
@@ -86,10 +89,26 @@ pub enum RequestType {
     PartitionedDml,
 }
 
+/// Purges run at a lower priority (and are tagged) so they don't compete with
+/// user facing requests
+fn request_options(priority: &str) -> Result<RequestOptions, String> {
+    let priority = match priority.to_lowercase().as_str() {
+        "low" => RequestOptions_Priority::PRIORITY_LOW,
+        "medium" => RequestOptions_Priority::PRIORITY_MEDIUM,
+        "high" => RequestOptions_Priority::PRIORITY_HIGH,
+        other => return Err(format!("Invalid {}: {}", PRIORITY_ENV_VAR, other)),
+    };
+    let mut options = RequestOptions::new();
+    options.set_priority(priority);
+    options.set_request_tag("purge_ttl".to_owned());
+    Ok(options)
+}
+
 fn begin_transaction(
     client: &SpannerClient,
     session: &Session,
     request_type: RequestType,
+    options: &RequestOptions,
 ) -> Result<(ExecuteSqlRequest, Vec<u8>), Box<grpcio::Error>> {
     // Create a transaction
     let mut opt = TransactionOptions::new();
@@ -117,16 +136,22 @@ fn begin_transaction(
     let mut req = ExecuteSqlRequest::new();
     req.set_session(session.get_name().to_string());
     req.set_transaction(ts);
+    req.set_request_options(options.clone());
 
     Ok((req, id))
 }
 
-fn continue_transaction(session: &Session, transaction_id: Vec<u8>) -> ExecuteSqlRequest {
+fn continue_transaction(
+    session: &Session,
+    transaction_id: Vec<u8>,
+    options: &RequestOptions,
+) -> ExecuteSqlRequest {
     let mut ts = TransactionSelector::new();
     ts.set_id(transaction_id);
     let mut req = ExecuteSqlRequest::new();
     req.set_session(session.get_name().to_string());
     req.set_transaction(ts);
+    req.set_request_options(options.clone());
     req
 }
 
@@ -134,10 +159,12 @@ fn commit_transaction(
     client: &SpannerClient,
     session: &Session,
     txn: Vec<u8>,
+    options: &RequestOptions,
 ) -> Result<(), Box<grpcio::Error>> {
     let mut req = CommitRequest::new();
     req.set_session(session.get_name().to_owned());
     req.set_transaction_id(txn);
+    req.set_request_options(options.clone());
     client.commit(&req)?;
     Ok(())
 }
@@ -167,9 +194,10 @@ fn delete_incremental(
     column: String,
     chunk_size: u64,
     max_to_delete: u64,
+    options: &RequestOptions,
 ) -> Result<(), Box<grpcio::Error>> {
     let mut total: u64 = 0;
-    let (mut req, mut txn) = begin_transaction(client, session, RequestType::ReadWrite)?;
+    let (mut req, mut txn) = begin_transaction(client, session, RequestType::ReadWrite, options)?;
     loop {
         let select_sql = format!("SELECT fxa_uid, fxa_kid, collection_id, {} FROM {} WHERE expiry < CURRENT_TIMESTAMP() LIMIT {}", column, table, chunk_size);
         trace!(
@@ -213,12 +241,12 @@ fn delete_incremental(
         }
         delete_sql = format!("{})", delete_sql.trim_end_matches(&", ".to_string()));
         trace!("Deleting chunk with: {}", delete_sql);
-        let mut delete_req = continue_transaction(session, txn.clone());
+        let mut delete_req = continue_transaction(session, txn.clone(), options);
         delete_req.set_sql(delete_sql);
         client.execute_sql(&delete_req)?;
         info!("{}: removed {} rows", table, total);
-        commit_transaction(client, session, txn.clone())?;
-        let (newreq, newtxn) = begin_transaction(client, session, RequestType::ReadWrite)?;
+        commit_transaction(client, session, txn.clone(), options)?;
+        let (newreq, newtxn) = begin_transaction(client, session, RequestType::ReadWrite, options)?;
         req = newreq;
         txn = newtxn;
     }
@@ -230,8 +258,9 @@ fn delete_all(
     client: &SpannerClient,
     session: &Session,
     table: String,
+    options: &RequestOptions,
 ) -> Result<(), Box<grpcio::Error>> {
-    let (mut req, _txn) = begin_transaction(client, session, RequestType::PartitionedDml)?;
+    let (mut req, _txn) = begin_transaction(client, session, RequestType::PartitionedDml, options)?;
     req.set_sql(format!(
         "DELETE FROM {} WHERE expiry < CURRENT_TIMESTAMP()",
         table
@@ -245,6 +274,23 @@ fn delete_all(
     Ok(())
 }
 
+/// The tables Spanner already deletes expired rows from, per their row
+/// deletion policies
+fn native_ttl_tables(
+    client: &SpannerClient,
+    session: &Session,
+    options: &RequestOptions,
+) -> Result<Vec<String>, Box<grpcio::Error>> {
+    let (mut req, _txn) = begin_transaction(client, session, RequestType::ReadOnly, options)?;
+    req.set_sql(NATIVE_TTL_TABLES_QUERY.to_owned());
+    let result = SyncResultSet {
+        result: client.execute_sql(&req)?,
+    };
+    Ok(result
+        .map(|row| row[0].get_string_value().to_owned())
+        .collect())
+}
+
 fn retryable(err: &grpcio::Error) -> bool {
     // if it is NOT an ABORT, we should not retry this function.
     match err {
@@ -293,6 +339,9 @@ fn main() -> Result<(), Box<dyn Error>> {
         str::parse::<u64>(&env::var(SLEEP_ENV_VAR).unwrap_or_else(|_| "0".to_owned())).unwrap_or(0),
     );
 
+    let options =
+        request_options(&env::var(PRIORITY_ENV_VAR).unwrap_or_else(|_| "low".to_owned()))?;
+
     let database = db_url["spanner://".len()..].to_owned();
     info!("Retries: {}, sleep: {}ms", retries, nap_time.as_millis());
     info!("For {}", database);
@@ -320,9 +369,18 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     let statsd = statsd_from_env()?;
 
+    let native_ttl = native_ttl_tables(&client, &session, &options)?;
+    if !native_ttl.is_empty() {
+        info!(
+            "Skipping tables with row deletion policies: {:?}",
+            native_ttl
+        );
+    }
+    let purged = |table: &str| !native_ttl.iter().any(|t| t == table);
+
     {
         let _timer_total = start_timer(&statsd, "purge_ttl.total_duration");
-        {
+        if purged("batches") {
             let _timer_batches = start_timer(&statsd, "purge_ttl.batches_duration");
             let mut success = false;
             for i in 0..retries {
@@ -334,9 +392,10 @@ fn main() -> Result<(), Box<dyn Error>> {
                         "batch_id".to_owned(),
                         chunk_size,
                         max_to_delete,
+                        &options,
                     )
                 } else {
-                    delete_all(&client, &session, "batches".to_owned())
+                    delete_all(&client, &session, "batches".to_owned(), &options)
                 } {
                     Ok(_) => {
                         success = true;
@@ -360,7 +419,7 @@ fn main() -> Result<(), Box<dyn Error>> {
                 );
             }
         }
-        {
+        if purged("bsos") {
             let _timer_bso = start_timer(&statsd, "purge_ttl.bso_duration");
             let mut success = false;
             for i in 0..retries {
@@ -372,9 +431,10 @@ fn main() -> Result<(), Box<dyn Error>> {
                         "bso_id".to_owned(),
                         chunk_size,
                         max_to_delete,
+                        &options,
                     )
                 } else {
-                    delete_all(&client, &session, "bsos".to_owned())
+                    delete_all(&client, &session, "bsos".to_owned(), &options)
                 } {
                     Ok(_) => {
                         success = true;
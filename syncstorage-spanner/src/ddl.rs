@@ -0,0 +1,379 @@
+//! Management of the Spanner schema, which isn't covered by the diesel
+//! migrations of the other backends: the expected DDL, its differences from
+//! a live database's and the creation of what's missing from it (the
+//! `spanner-ddl` subcommand).
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use google_cloud_rust_raw::{
+    longrunning::{operations::GetOperationRequest, operations_grpc::OperationsClient},
+    spanner::admin::database::v1::{
+        spanner_database_admin::{GetDatabaseDdlRequest, UpdateDatabaseDdlRequest},
+        spanner_database_admin_grpc::DatabaseAdminClient,
+    },
+};
+use grpcio::{CallOption, EnvBuilder};
+use protobuf::RepeatedField;
+use syncstorage_settings::Settings;
+
+use crate::{
+    error::DbError,
+    manager::{create_channel, SpannerSessionSettings},
+    metadata::MetadataBuilder,
+    DbResult,
+};
+
+/// The tables and indexes
+pub const SCHEMA_DDL: &str = include_str!("schema.ddl");
+
+/// The optional row deletion policies, expiring BSOs and batches natively
+pub const TTL_POLICIES_DDL: &str = include_str!("ttl_policies.ddl");
+
+/// How often a schema update's progress is checked
+const UPDATE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The statements of a DDL file, in a canonical form (see `canonical`)
+pub fn parse(ddl: &str) -> Vec<String> {
+    let uncommented = ddl
+        .lines()
+        .map(|line| line.split("--").next().unwrap_or_default())
+        .collect::<Vec<_>>()
+        .join("\n");
+    uncommented
+        .split(';')
+        .filter(|statement| !statement.trim().is_empty())
+        .flat_map(canonical)
+        .collect()
+}
+
+/// The schema the server expects, optionally with its row deletion policies
+pub fn expected_schema(ttl_policies: bool) -> Vec<String> {
+    let mut statements = parse(SCHEMA_DDL);
+    if ttl_policies {
+        statements.extend(parse(TTL_POLICIES_DDL));
+    }
+    statements
+}
+
+/// Render statements as a DDL file
+pub fn to_ddl(statements: &[String]) -> String {
+    statements
+        .iter()
+        .map(|statement| format!("{};\n", statement))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// A statement's canonical form, comparable with the statements Spanner
+/// returns for its schema: its whitespace is normalized and any row deletion
+/// policy is split out of its `CREATE TABLE`, into the `ALTER TABLE` adding
+/// it
+fn canonical(statement: &str) -> Vec<String> {
+    let mut normalized = String::with_capacity(statement.len());
+    for c in statement
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .chars()
+    {
+        if matches!(c, '(' | ')' | ',') && normalized.ends_with(' ') {
+            normalized.pop();
+        }
+        if c == ')' && normalized.ends_with(',') {
+            // A trailing comma of a column list
+            normalized.pop();
+        }
+        if c == ' ' && normalized.ends_with('(') {
+            continue;
+        }
+        normalized.push(c);
+    }
+
+    const POLICY: &str = ", ROW DELETION POLICY(";
+    match (table_name(&normalized), normalized.find(POLICY)) {
+        (Some(table), Some(i)) => {
+            let policy = format!(
+                "ALTER TABLE {} ADD ROW DELETION POLICY{}",
+                table,
+                &normalized[i + POLICY.len() - 1..]
+            );
+            normalized.truncate(i);
+            vec![normalized, policy]
+        }
+        _ => vec![normalized],
+    }
+}
+
+/// The table created by a canonical `CREATE TABLE` statement
+fn table_name(statement: &str) -> Option<&str> {
+    let name = statement.strip_prefix("CREATE TABLE ")?;
+    name.split(|c: char| c == ' ' || c == '(').next()
+}
+
+/// The name of the schema object defined by a canonical statement
+fn object_name(statement: &str) -> String {
+    let words: Vec<_> = statement
+        .split(|c: char| c == ' ' || c == '(')
+        .take(6)
+        .collect();
+    match words.as_slice() {
+        ["CREATE", "TABLE", name, ..] => format!("TABLE {}", name),
+        ["CREATE", "INDEX", name, ..]
+        | ["CREATE", "UNIQUE", "INDEX", name, ..]
+        | ["CREATE", "NULL_FILTERED", "INDEX", name, ..]
+        | ["CREATE", "UNIQUE", "NULL_FILTERED", "INDEX", name, ..] => format!("INDEX {}", name),
+        ["ALTER", "TABLE", name, "ADD", "ROW", "DELETION"] => {
+            format!("ROW DELETION POLICY {}", name)
+        }
+        _ => statement.to_owned(),
+    }
+}
+
+/// The differences between the expected schema and a database's
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct SchemaDiff {
+    /// Statements creating the objects missing from the database, in the
+    /// expected schema's order
+    pub missing: Vec<String>,
+    /// The objects defined differently by the database: the expected
+    /// statements paired with the database's
+    pub changed: Vec<(String, String)>,
+    /// The database's objects that aren't expected
+    pub unexpected: Vec<String>,
+}
+
+impl SchemaDiff {
+    pub fn is_empty(&self) -> bool {
+        self.missing.is_empty() && self.changed.is_empty() && self.unexpected.is_empty()
+    }
+
+    /// Whether applying the missing statements creates the table
+    pub fn creates_table(&self, table: &str) -> bool {
+        self.missing
+            .iter()
+            .any(|statement| table_name(statement) == Some(table))
+    }
+}
+
+impl fmt::Display for SchemaDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return writeln!(f, "The database's schema is up to date");
+        }
+        if !self.missing.is_empty() {
+            writeln!(f, "Missing from the database:")?;
+            for statement in &self.missing {
+                writeln!(f, "  {};", statement)?;
+            }
+        }
+        if !self.changed.is_empty() {
+            writeln!(f, "Changed in the database:")?;
+            for (expected, live) in &self.changed {
+                writeln!(f, "  expected: {};", expected)?;
+                writeln!(f, "  found:    {};", live)?;
+            }
+        }
+        if !self.unexpected.is_empty() {
+            writeln!(f, "Not expected:")?;
+            for statement in &self.unexpected {
+                writeln!(f, "  {};", statement)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Compare the expected schema with a database's, both as canonical
+/// statements. Objects are matched by name
+pub fn diff(expected: &[String], live: &[String]) -> SchemaDiff {
+    let live_objects: HashMap<_, _> = live
+        .iter()
+        .map(|statement| (object_name(statement), statement))
+        .collect();
+    let expected_objects: HashSet<_> = expected.iter().map(|s| object_name(s)).collect();
+
+    let mut diff = SchemaDiff::default();
+    for statement in expected {
+        match live_objects.get(&object_name(statement)) {
+            None => diff.missing.push(statement.clone()),
+            Some(live) if *live != statement => {
+                diff.changed.push((statement.clone(), (*live).clone()))
+            }
+            Some(_) => (),
+        }
+    }
+    diff.unexpected = live
+        .iter()
+        .filter(|statement| !expected_objects.contains(&object_name(statement)))
+        .cloned()
+        .collect();
+    diff
+}
+
+/// A client of the Spanner Database Admin API, for the configured database.
+///
+/// Its calls block: it's only meant for the command line.
+pub struct DatabaseAdmin {
+    client: DatabaseAdminClient,
+    operations: OperationsClient,
+    database: String,
+}
+
+impl DatabaseAdmin {
+    pub fn connect(settings: &Settings) -> DbResult<Self> {
+        let settings = SpannerSessionSettings::from_settings(settings)?;
+        let env = Arc::new(EnvBuilder::new().build());
+        let chan = create_channel(env, settings.emulator_host.as_deref())?;
+        Ok(Self {
+            client: DatabaseAdminClient::new(chan.clone()),
+            operations: OperationsClient::new(chan),
+            database: settings.database,
+        })
+    }
+
+    fn call_opt(&self, param: &str, value: &str) -> DbResult<CallOption> {
+        let meta = MetadataBuilder::with_prefix(&self.database)
+            .routing_param(param, value)
+            .build()?;
+        Ok(CallOption::default().headers(meta))
+    }
+
+    /// The database's schema, as canonical statements
+    pub fn get_ddl(&self) -> DbResult<Vec<String>> {
+        let mut req = GetDatabaseDdlRequest::new();
+        req.set_database(self.database.clone());
+        let opt = self.call_opt("database", &self.database)?;
+        let mut response = self.client.get_database_ddl_opt(&req, opt)?;
+        Ok(response
+            .take_statements()
+            .iter()
+            .flat_map(|statement| canonical(statement))
+            .collect())
+    }
+
+    /// Apply the statements to the database's schema, waiting for the
+    /// update to complete
+    pub fn update_ddl(&self, statements: &[String]) -> DbResult<()> {
+        let mut req = UpdateDatabaseDdlRequest::new();
+        req.set_database(self.database.clone());
+        req.set_statements(RepeatedField::from_slice(statements));
+        let opt = self.call_opt("database", &self.database)?;
+        let mut operation = self.client.update_database_ddl_opt(&req, opt)?;
+
+        while !operation.get_done() {
+            thread::sleep(UPDATE_POLL_INTERVAL);
+            let mut req = GetOperationRequest::new();
+            req.set_name(operation.get_name().to_owned());
+            let opt = self.call_opt("name", operation.get_name())?;
+            operation = self.operations.get_operation_opt(&req, opt)?;
+        }
+        if operation.has_error() {
+            return Err(DbError::internal(format!(
+                "Schema update failed: {}",
+                operation.get_error().get_message()
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The bsos table and an index as returned by GetDatabaseDdl, with the
+    /// TTL policies applied
+    const LIVE_BSOS: &str = "CREATE TABLE bsos (
+  fxa_uid STRING(MAX) NOT NULL,
+  fxa_kid STRING(MAX) NOT NULL,
+  collection_id INT64 NOT NULL,
+  bso_id STRING(MAX) NOT NULL,
+  sortindex INT64,
+  payload STRING(MAX) NOT NULL,
+  modified TIMESTAMP NOT NULL,
+  expiry TIMESTAMP NOT NULL,
+) PRIMARY KEY(fxa_uid, fxa_kid, collection_id, bso_id),
+  INTERLEAVE IN PARENT user_collections ON DELETE CASCADE,
+  ROW DELETION POLICY (OLDER_THAN(expiry, INTERVAL 0 DAY))";
+    const LIVE_INDEX: &str = "CREATE INDEX BsoModified ON bsos(fxa_uid, fxa_kid, collection_id, modified DESC), INTERLEAVE IN user_collections";
+
+    #[test]
+    fn parses_the_schema() {
+        let schema = expected_schema(false);
+        assert_eq!(schema.len(), 10);
+        assert!(schema
+            .iter()
+            .all(|s| !s.contains("--") && !s.contains('\n')));
+        assert_eq!(
+            schema.last().map(String::as_str),
+            Some(
+                "CREATE TABLE batch_bsos(fxa_uid STRING(MAX) NOT NULL, \
+                 fxa_kid STRING(MAX) NOT NULL, collection_id INT64 NOT NULL, \
+                 batch_id STRING(MAX) NOT NULL, batch_bso_id STRING(MAX) NOT NULL, \
+                 sortindex INT64, payload STRING(MAX), ttl INT64) \
+                 PRIMARY KEY(fxa_uid, fxa_kid, collection_id, batch_id, batch_bso_id), \
+                 INTERLEAVE IN PARENT batches ON DELETE CASCADE"
+            )
+        );
+        assert_eq!(expected_schema(true).len(), 12);
+    }
+
+    #[test]
+    fn canonical_forms_match_spanners() {
+        let expected = expected_schema(true);
+        let live: Vec<_> = [LIVE_BSOS, LIVE_INDEX]
+            .iter()
+            .flat_map(|s| canonical(s))
+            .collect();
+        assert_eq!(live.len(), 3);
+        for statement in &live {
+            assert!(expected.contains(statement), "{}", statement);
+        }
+    }
+
+    #[test]
+    fn diffs_objects_by_name() {
+        assert!(diff(&expected_schema(true), &expected_schema(true)).is_empty());
+
+        // No TTL policies, one of them unexpected
+        let mut live = expected_schema(false);
+        live.retain(|s| object_name(s) != "INDEX BsoExpiry");
+        live.push(
+            canonical(
+                "ALTER TABLE bsos ADD ROW DELETION POLICY (OLDER_THAN(expiry, INTERVAL 1 DAY))",
+            )
+            .remove(0),
+        );
+        let collections = live
+            .iter()
+            .position(|s| table_name(s) == Some("collections"))
+            .unwrap();
+        live[collections] = "CREATE TABLE collections(collection_id INT64 NOT NULL) \
+                             PRIMARY KEY(collection_id)"
+            .to_owned();
+
+        let diff = diff(&expected_schema(false), &live);
+        assert_eq!(diff.missing.len(), 1);
+        assert_eq!(object_name(&diff.missing[0]), "INDEX BsoExpiry");
+        assert!(!diff.creates_table("collections"));
+        assert_eq!(diff.changed.len(), 1);
+        assert_eq!(table_name(&diff.changed[0].1), Some("collections"));
+        assert_eq!(
+            diff.unexpected,
+            vec!["ALTER TABLE bsos ADD ROW DELETION POLICY(OLDER_THAN(expiry, INTERVAL 1 DAY))"]
+        );
+    }
+
+    #[test]
+    fn creates_missing_tables() {
+        let diff = diff(&expected_schema(false), &[]);
+        assert_eq!(diff.missing, expected_schema(false));
+        assert!(diff.creates_table("collections"));
+        assert!(!diff.creates_table("collection"));
+    }
+}
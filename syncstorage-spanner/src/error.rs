@@ -52,6 +52,16 @@ impl DbError {
     pub fn too_large(msg: String) -> Self {
         DbErrorKind::TooLarge(msg).into()
     }
+
+    /// Whether an insert failed on a row (or unique index entry) that
+    /// already exists
+    pub(crate) fn is_already_exists(&self) -> bool {
+        matches!(
+            &self.kind,
+            DbErrorKind::Grpc(grpcio::Error::RpcFailure(status))
+                if status.code() == grpcio::RpcStatusCode::ALREADY_EXISTS
+        )
+    }
 }
 
 #[derive(Debug, Error)]
@@ -106,9 +116,21 @@ impl DbErrorIntrospect for DbError {
         matches!(&self.kind, DbErrorKind::Common(e) if e.is_conflict())
     }
 
+    fn is_lock_timeout(&self) -> bool {
+        matches!(&self.kind, DbErrorKind::Common(e) if e.is_lock_timeout())
+    }
+
     fn is_quota(&self) -> bool {
         matches!(&self.kind, DbErrorKind::Common(e) if e.is_quota())
     }
+
+    fn is_invalid_value(&self) -> bool {
+        matches!(&self.kind, DbErrorKind::Common(e) if e.is_invalid_value())
+    }
+
+    fn is_too_large(&self) -> bool {
+        matches!(&self.kind, DbErrorKind::Common(e) if e.is_too_large())
+    }
 }
 
 impl ReportableError for DbError {
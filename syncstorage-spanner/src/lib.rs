@@ -7,6 +7,7 @@ extern crate slog_scope;
 mod macros;
 
 mod batch;
+pub mod ddl;
 mod error;
 mod manager;
 mod metadata;
@@ -15,7 +16,7 @@ mod pool;
 mod support;
 
 pub use error::DbError;
-pub use models::SpannerDb;
+pub use models::{SpannerDb, NATIVE_TTL_TABLES_QUERY};
 pub use pool::SpannerDbPool;
 
 type DbResult<T> = Result<T, error::DbError>;
@@ -67,6 +67,7 @@ impl From<bb8::State> for PoolState {
         PoolState {
             connections: state.connections,
             idle_connections: state.idle_connections,
+            ..Default::default()
         }
     }
 }
@@ -3,4 +3,4 @@ mod deadpool;
 mod session;
 
 pub(super) use self::deadpool::{Conn, SpannerSessionManager};
-pub(super) use self::session::SpannerSession;
+pub(super) use self::session::{create_channel, SpannerSession, SpannerSessionSettings};
@@ -1,14 +1,14 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use google_cloud_rust_raw::spanner::v1::{
-    spanner::{CreateSessionRequest, GetSessionRequest, Session},
+    spanner::{CreateSessionRequest, GetSessionRequest, RequestOptions_Priority, Session},
     spanner_grpc::SpannerClient,
 };
 use grpcio::{CallOption, ChannelBuilder, ChannelCredentials, Environment};
 use syncserver_common::{BlockingThreadpool, Metrics};
 use syncstorage_settings::Settings;
 
-use crate::{error::DbError, metadata::MetadataBuilder};
+use crate::{error::DbError, metadata::MetadataBuilder, support::OperationClass};
 
 const SPANNER_ADDRESS: &str = "spanner.googleapis.com:443";
 
@@ -43,7 +43,11 @@ impl SpannerSession {
             .metadata_builder()
             .routing_param("session", self.session.get_name())
             .build()?;
-        Ok(CallOption::default().headers(meta))
+        let opt = CallOption::default().headers(meta);
+        Ok(match self.settings.statement_timeout {
+            Some(timeout) => opt.timeout(timeout),
+            None => opt,
+        })
     }
 }
 
@@ -60,11 +64,23 @@ pub struct SpannerSessionSettings {
     /// metdata
     pub route_to_leader: bool,
 
+    /// RPC priority of user facing requests
+    pub interactive_priority: RequestOptions_Priority,
+    /// RPC priority of batch upload requests
+    pub batch_priority: RequestOptions_Priority,
+
     /// Max age of a Session
     pub max_lifespan: Option<u32>,
     /// Max idle time of a Session
     pub max_idle: Option<u32>,
 
+    /// Deadline of the RPCs made through [SpannerSession::session_opt]
+    pub statement_timeout: Option<Duration>,
+
+    /// Staleness of reads of users without recent writes, strong reads when
+    /// `None`
+    pub read_staleness: Option<Duration>,
+
     /// For tests: disables transactions from committing
     pub(crate) use_test_transactions: bool,
     /// Spanner emulator hostname when set to Spanner emulator mode
@@ -81,19 +97,24 @@ impl SpannerSessionSettings {
             .to_owned();
 
         #[cfg(not(debug_assertions))]
-        let (use_test_transactions, use_mutations) = (false, true);
+        let use_test_transactions = false;
         #[cfg(debug_assertions)]
-        let (use_test_transactions, use_mutations) = (
-            settings.database_use_test_transactions,
-            settings.database_spanner_use_mutations,
-        );
+        let use_test_transactions = settings.database_use_test_transactions;
 
         Ok(Self {
             database,
-            use_mutations,
+            use_mutations: settings.database_spanner_use_mutations,
             route_to_leader: settings.database_spanner_route_to_leader,
+            interactive_priority: parse_priority(&settings.database_spanner_interactive_priority)?,
+            batch_priority: parse_priority(&settings.database_spanner_batch_priority)?,
             max_lifespan: settings.database_pool_connection_lifespan,
             max_idle: settings.database_pool_connection_max_idle,
+            statement_timeout: settings
+                .database_statement_timeout
+                .map(|seconds| Duration::from_secs(seconds.into())),
+            read_staleness: settings
+                .database_spanner_read_staleness
+                .map(|seconds| Duration::from_secs(seconds.into())),
             use_test_transactions,
             emulator_host: settings.spanner_emulator_host.clone(),
         })
@@ -104,6 +125,14 @@ impl SpannerSessionSettings {
         self.emulator_host.is_some()
     }
 
+    /// The RPC priority for the given class of operations
+    pub fn priority(&self, class: OperationClass) -> RequestOptions_Priority {
+        match class {
+            OperationClass::Interactive => self.interactive_priority,
+            OperationClass::Batch => self.batch_priority,
+        }
+    }
+
     /// Build [grpcio::Metadata] with a Resource prefix and other applicable
     /// settings
     pub fn metadata_builder(&self) -> MetadataBuilder {
@@ -111,6 +140,44 @@ impl SpannerSessionSettings {
     }
 }
 
+fn parse_priority(priority: &Option<String>) -> Result<RequestOptions_Priority, DbError> {
+    Ok(
+        match priority.as_deref().map(str::to_lowercase).as_deref() {
+            None => RequestOptions_Priority::PRIORITY_UNSPECIFIED,
+            Some("low") => RequestOptions_Priority::PRIORITY_LOW,
+            Some("medium") => RequestOptions_Priority::PRIORITY_MEDIUM,
+            Some("high") => RequestOptions_Priority::PRIORITY_HIGH,
+            Some(other) => {
+                return Err(DbError::internal(format!(
+                    "Invalid Spanner priority: {}",
+                    other
+                )))
+            }
+        },
+    )
+}
+
+/// Create a gRPC Channel to Spanner, or to its emulator when given its host
+pub(crate) fn create_channel(
+    env: Arc<Environment>,
+    emulator_host: Option<&str>,
+) -> Result<grpcio::Channel, DbError> {
+    let builder = ChannelBuilder::new(env)
+        .max_send_message_len(100 << 20)
+        .max_receive_message_len(100 << 20);
+    if let Some(spanner_emulator_address) = emulator_host {
+        Ok(builder.connect(spanner_emulator_address))
+    } else {
+        // Requires
+        // GOOGLE_APPLICATION_CREDENTIALS=/path/to/service-account.json
+
+        // XXX: issue732: Could google_default_credentials (or
+        // ChannelBuilder::secure_connect) block?!
+        let creds = ChannelCredentials::google_default_credentials()?;
+        Ok(builder.set_credentials(creds).connect(SPANNER_ADDRESS))
+    }
+}
+
 /// Create a Session (and the underlying gRPC Channel)
 pub async fn create_spanner_session(
     settings: &SpannerSessionSettings,
@@ -121,25 +188,10 @@ pub async fn create_spanner_session(
     let emulator_host = settings.emulator_host.clone();
     let chan = blocking_threadpool
         .spawn(move || -> Result<grpcio::Channel, DbError> {
-            if let Some(spanner_emulator_address) = emulator_host {
-                Ok(ChannelBuilder::new(env)
-                    .max_send_message_len(100 << 20)
-                    .max_receive_message_len(100 << 20)
-                    .connect(&spanner_emulator_address))
-            } else {
-                // Requires
-                // GOOGLE_APPLICATION_CREDENTIALS=/path/to/service-account.json
+            if emulator_host.is_none() {
                 metrics.start_timer("storage.pool.grpc_auth", None);
-
-                // XXX: issue732: Could google_default_credentials (or
-                // ChannelBuilder::secure_connect) block?!
-                let creds = ChannelCredentials::google_default_credentials()?;
-                Ok(ChannelBuilder::new(env)
-                    .max_send_message_len(100 << 20)
-                    .max_receive_message_len(100 << 20)
-                    .set_credentials(creds)
-                    .connect(SPANNER_ADDRESS))
             }
+            create_channel(env, emulator_host.as_deref())
         })
         .await?;
     let client = SpannerClient::new(chan);
@@ -10,7 +10,9 @@ use std::{
 use futures::future::TryFutureExt;
 use google_cloud_rust_raw::spanner::v1::{
     mutation::{Mutation, Mutation_Write},
-    spanner::{BeginTransactionRequest, CommitRequest, ExecuteSqlRequest, RollbackRequest},
+    spanner::{
+        BeginTransactionRequest, CommitRequest, ExecuteSqlRequest, RequestOptions, RollbackRequest,
+    },
     transaction::{
         TransactionOptions, TransactionOptions_ReadOnly, TransactionOptions_ReadWrite,
         TransactionSelector,
@@ -23,20 +25,20 @@ use protobuf::{
     Message, RepeatedField,
 };
 use syncserver_common::{Metrics, MAX_SPANNER_LOAD_SIZE};
-use syncserver_db_common::DbFuture;
+use syncserver_db_common::{timed, DbCallParams, DbFuture};
 use syncstorage_db_common::{
-    error::DbErrorIntrospect, params, results, util::SyncTimestamp, Db, Sorting, UserIdentifier,
-    DEFAULT_BSO_TTL, FIRST_CUSTOM_COLLECTION_ID,
+    collection_tag, error::DbErrorIntrospect, params, results, util::SyncTimestamp, Db, Projection,
+    Sorting, UserIdentifier, DEFAULT_BSO_TTL, FIRST_CUSTOM_COLLECTION_ID,
 };
 use syncstorage_settings::Quota;
 
 use crate::{
     batch,
     error::DbError,
-    pool::{CollectionCache, Conn},
+    pool::{CollectionCache, Conn, RecentWrites},
     support::{
         as_type, bso_from_row, bso_to_insert_row, bso_to_update_row, ExecuteSqlRequestBuilder,
-        IntoSpannerValue, StreamedResultSetAsync,
+        IntoSpannerValue, OperationClass, StreamedResultSetAsync,
     },
     DbResult,
 };
@@ -48,8 +50,18 @@ enum CollectionLock {
 }
 
 const TOMBSTONE: i32 = 0;
+
+/// Attempts at creating a collection while concurrent requests create others
+const CREATE_COLLECTION_ATTEMPTS: usize = 3;
 pub(super) const PRETOUCH_TS: &str = "0001-01-01T00:00:00.00Z";
 
+/// Lists the tables Spanner deletes expired rows from itself, per their row
+/// deletion policies (see `ttl_policies.ddl`)
+pub const NATIVE_TTL_TABLES_QUERY: &str = "SELECT table_name
+   FROM information_schema.tables
+  WHERE table_schema = ''
+    AND row_deletion_policy_expression IS NOT NULL";
+
 /// Per session Db metadata
 #[derive(Debug, Default)]
 struct SpannerDbSession {
@@ -66,8 +78,20 @@ struct SpannerDbSession {
     mutations: Option<Vec<Mutation>>,
     in_write_transaction: bool,
     execute_sql_count: u64,
+    /// Number of statements executed, in or out of transactions
+    query_count: u64,
     /// Whether update_collection has already been called
     updated_collection: bool,
+    /// Determines the priority and tag of subsequent requests. Once a
+    /// session begins batch work, the remainder of it (including its
+    /// commit) is treated as batch work
+    operation_class: OperationClass,
+    /// Users written to by this session, recorded in `recent_writes` once
+    /// committed
+    written_users: HashSet<UserIdentifier>,
+    /// Users' storage epochs, as read by their write locks (see
+    /// `Db::locked_user`)
+    locked_users: HashMap<UserIdentifier, results::LockedUser>,
 }
 
 #[derive(Clone, Debug)]
@@ -76,6 +100,8 @@ pub struct SpannerDb {
 
     /// Pool level cache of collection_ids and their names
     coll_cache: Arc<CollectionCache>,
+    /// Pool level record of users' latest writes
+    recent_writes: Arc<RecentWrites>,
 
     pub metrics: Metrics,
     pub quota: Quota,
@@ -105,6 +131,7 @@ impl SpannerDb {
     pub(super) fn new(
         conn: Conn,
         coll_cache: Arc<CollectionCache>,
+        recent_writes: Arc<RecentWrites>,
         metrics: &Metrics,
         quota: Quota,
     ) -> Self {
@@ -118,6 +145,7 @@ impl SpannerDb {
             #[allow(clippy::arc_with_non_send_sync)]
             inner: Arc::new(inner),
             coll_cache,
+            recent_writes,
             metrics: metrics.clone(),
             quota,
         }
@@ -163,33 +191,46 @@ impl SpannerDb {
                 "Can't escalate read-lock to write-lock".to_owned(),
             ));
         }
-        let result = self
-            .sql(
-                "SELECT COALESCE(MAX(collection_id), 1)
-                   FROM collections",
-            )?
-            .execute_async(&self.conn)?
-            .one()
-            .await?;
-        let max = result[0]
-            .get_string_value()
-            .parse::<i32>()
-            .map_err(|e| DbError::integrity(e.to_string()))?;
-        let id = FIRST_CUSTOM_COLLECTION_ID.max(max + 1);
-        let (sqlparams, sqlparam_types) = params! {
-            "name" => name.to_string(),
-            "collection_id" => id,
-        };
+        for _ in 0..CREATE_COLLECTION_ATTEMPTS {
+            let result = self
+                .sql(
+                    "SELECT COALESCE(MAX(collection_id), 1)
+                       FROM collections",
+                )?
+                .execute_async(&self.conn)?
+                .one()
+                .await?;
+            let max = result[0]
+                .get_string_value()
+                .parse::<i32>()
+                .map_err(|e| DbError::integrity(e.to_string()))?;
+            let id = FIRST_CUSTOM_COLLECTION_ID.max(max + 1);
+            let (sqlparams, sqlparam_types) = params! {
+                "name" => name.to_string(),
+                "collection_id" => id,
+            };
 
-        self.sql(
-            "INSERT INTO collections (collection_id, name)
-             VALUES (@collection_id, @name)",
-        )?
-        .params(sqlparams)
-        .param_types(sqlparam_types)
-        .execute_dml_async(&self.conn)
-        .await?;
-        Ok(id)
+            let inserted = self
+                .sql(
+                    "INSERT INTO collections (collection_id, name)
+                     VALUES (@collection_id, @name)",
+                )?
+                .params(sqlparams)
+                .param_types(sqlparam_types)
+                .execute_dml_async(&self.conn)
+                .await;
+            match inserted {
+                Ok(_) => return Ok(id),
+                // A concurrent request committed either the same collection
+                // (use its id) or another one taking this id (try the next)
+                Err(e) if e.is_already_exists() => match self.get_collection_id_async(name).await {
+                    Err(e) if e.is_collection_not_found() => continue,
+                    result => return result,
+                },
+                Err(e) => return Err(e),
+            }
+        }
+        Err(DbError::conflict())
     }
 
     async fn get_or_create_collection_id_async(&self, name: &str) -> DbResult<i32> {
@@ -200,8 +241,11 @@ impl SpannerDb {
     }
 
     async fn lock_for_read_async(&self, params: params::LockCollection) -> DbResult<()> {
-        // Begin a transaction
-        self.begin_async(false).await?;
+        // Begin a transaction, which may read stale data unless the user
+        // recently wrote
+        let stale_read = self.recent_writes.allows_stale_read(&params.user_id);
+        self.begin_with_options_async(self.transaction_options(false, stale_read))
+            .await?;
 
         let collection_id = self
             .get_collection_id_async(&params.collection)
@@ -253,6 +297,10 @@ impl SpannerDb {
                 "Can't escalate read-lock to write-lock".to_owned(),
             ));
         }
+        self.session
+            .borrow_mut()
+            .written_users
+            .insert(params.user_id.clone());
         let (sqlparams, mut sqlparam_types) = params! {
             "fxa_uid" => params.user_id.fxa_uid.clone(),
             "fxa_kid" => params.user_id.fxa_kid.clone(),
@@ -261,9 +309,15 @@ impl SpannerDb {
         };
         sqlparam_types.insert("pretouch_ts".to_owned(), as_type(TypeCode::TIMESTAMP));
 
+        // The user's storage epoch is read along with the lock (see
+        // `Db::locked_user`)
         let result = self
             .sql(
-                "SELECT CURRENT_TIMESTAMP(), modified
+                "SELECT CURRENT_TIMESTAMP(), modified,
+                        (SELECT epoch
+                           FROM user_epochs
+                          WHERE fxa_uid = @fxa_uid
+                            AND fxa_kid = @fxa_kid)
                    FROM user_collections
                   WHERE fxa_uid = @fxa_uid
                     AND fxa_kid = @fxa_kid
@@ -276,7 +330,7 @@ impl SpannerDb {
             .one_or_none()
             .await?;
 
-        let timestamp = if let Some(result) = result {
+        let (timestamp, storage_epoch) = if let Some(result) = result {
             let modified = sync_timestamp_from_rfc3339(result[1].get_string_value())?;
             let now = sync_timestamp_from_rfc3339(result[0].get_string_value())?;
             // Forbid the write if it would not properly incr the modified
@@ -288,16 +342,46 @@ impl SpannerDb {
                 .borrow_mut()
                 .coll_modified_cache
                 .insert((params.user_id.clone(), collection_id), modified);
-            now
+            (now, result[2].clone())
         } else {
+            let (sqlparams, sqlparam_types) = params! {
+                "fxa_uid" => params.user_id.fxa_uid.clone(),
+                "fxa_kid" => params.user_id.fxa_kid.clone(),
+            };
             let result = self
-                .sql("SELECT CURRENT_TIMESTAMP()")?
+                .sql(
+                    "SELECT CURRENT_TIMESTAMP(),
+                            (SELECT epoch
+                               FROM user_epochs
+                              WHERE fxa_uid = @fxa_uid
+                                AND fxa_kid = @fxa_kid)",
+                )?
+                .params(sqlparams)
+                .param_types(sqlparam_types)
                 .execute_async(&self.conn)?
                 .one()
                 .await?;
-            sync_timestamp_from_rfc3339(result[0].get_string_value())?
+            (
+                sync_timestamp_from_rfc3339(result[0].get_string_value())?,
+                result[1].clone(),
+            )
         };
         self.set_timestamp(timestamp);
+        let storage_epoch = if storage_epoch.has_null_value() {
+            None
+        } else {
+            Some(sync_timestamp_from_rfc3339(
+                storage_epoch.get_string_value(),
+            )?)
+        };
+        // Spanner records no keys (see `get_keys_changed_at`)
+        self.session.borrow_mut().locked_users.insert(
+            params.user_id.clone(),
+            results::LockedUser {
+                storage_epoch,
+                keys_changed_at: None,
+            },
+        );
 
         self.session
             .borrow_mut()
@@ -311,15 +395,32 @@ impl SpannerDb {
         self.session.borrow_mut().timestamp = Some(timestamp);
     }
 
-    pub(super) fn begin(&self, for_write: bool) -> DbResult<()> {
-        let spanner = &self.conn;
+    /// Options for a new transaction. Read-only transactions read data as
+    /// of `read_staleness` ago when `stale_read` and a staleness is
+    /// configured
+    fn transaction_options(&self, for_write: bool, stale_read: bool) -> TransactionOptions {
         let mut options = TransactionOptions::new();
         if for_write {
             options.set_read_write(TransactionOptions_ReadWrite::new());
             self.session.borrow_mut().in_write_transaction = true;
         } else {
-            options.set_read_only(TransactionOptions_ReadOnly::new());
+            let mut read_only = TransactionOptions_ReadOnly::new();
+            match self.conn.settings.read_staleness {
+                Some(staleness) if stale_read => {
+                    let mut exact_staleness = protobuf::well_known_types::Duration::new();
+                    exact_staleness.set_seconds(staleness.as_secs() as i64);
+                    read_only.set_exact_staleness(exact_staleness);
+                }
+                _ => read_only.set_strong(true),
+            }
+            options.set_read_only(read_only);
         }
+        options
+    }
+
+    pub(super) fn begin(&self, for_write: bool) -> DbResult<()> {
+        let spanner = &self.conn;
+        let options = self.transaction_options(for_write, false);
         let mut req = BeginTransactionRequest::new();
         req.set_session(spanner.session.get_name().to_owned());
         req.set_options(options);
@@ -334,14 +435,12 @@ impl SpannerDb {
     }
 
     pub(super) async fn begin_async(&self, for_write: bool) -> DbResult<()> {
+        self.begin_with_options_async(self.transaction_options(for_write, false))
+            .await
+    }
+
+    async fn begin_with_options_async(&self, options: TransactionOptions) -> DbResult<()> {
         let spanner = &self.conn;
-        let mut options = TransactionOptions::new();
-        if for_write {
-            options.set_read_write(TransactionOptions_ReadWrite::new());
-            self.session.borrow_mut().in_write_transaction = true;
-        } else {
-            options.set_read_only(TransactionOptions_ReadOnly::new());
-        }
         let mut req = BeginTransactionRequest::new();
         req.set_session(spanner.session.get_name().to_owned());
         req.set_options(options);
@@ -374,9 +473,23 @@ impl SpannerDb {
         Ok(self.session.borrow().transaction.clone())
     }
 
+    pub(super) fn set_operation_class(&self, class: OperationClass) {
+        self.session.borrow_mut().operation_class = class;
+    }
+
+    fn request_options(&self) -> RequestOptions {
+        let class = self.session.borrow().operation_class;
+        let mut options = RequestOptions::new();
+        options.set_priority(self.conn.settings.priority(class));
+        options.set_request_tag(class.tag().to_owned());
+        options
+    }
+
     fn sql_request(&self, sql: &str) -> DbResult<ExecuteSqlRequest> {
         let mut sqlr = ExecuteSqlRequest::new();
         sqlr.set_sql(sql.to_owned());
+        sqlr.set_request_options(self.request_options());
+        self.session.borrow_mut().query_count += 1;
         if let Some(transaction) = self.get_transaction()? {
             sqlr.set_transaction(transaction);
             let mut session = self.session.borrow_mut();
@@ -462,6 +575,7 @@ impl SpannerDb {
             let mut req = CommitRequest::new();
             req.set_session(spanner.session.get_name().to_owned());
             req.set_transaction_id(transaction.get_id().to_vec());
+            req.set_request_options(self.request_options());
             if let Some(mutations) = self.session.borrow_mut().mutations.take() {
                 req.set_mutations(RepeatedField::from_vec(mutations));
             }
@@ -489,6 +603,7 @@ impl SpannerDb {
             let mut req = CommitRequest::new();
             req.set_session(spanner.session.get_name().to_owned());
             req.set_transaction_id(transaction.get_id().to_vec());
+            req.set_request_options(self.request_options());
             if let Some(mutations) = self.session.borrow_mut().mutations.take() {
                 req.set_mutations(RepeatedField::from_vec(mutations));
             }
@@ -496,6 +611,9 @@ impl SpannerDb {
                 .client
                 .commit_async_opt(&req, spanner.session_opt()?)?
                 .await?;
+            for user_id in self.session.borrow_mut().written_users.drain() {
+                self.recent_writes.record(&user_id);
+            }
             Ok(())
         } else {
             Err(DbError::internal("No transaction to commit".to_owned()))
@@ -633,6 +751,31 @@ impl SpannerDb {
             .collect()
     }
 
+    /// Caches the names of every custom collection, returning their count
+    pub(super) async fn cache_custom_collections(&self) -> DbResult<usize> {
+        let (sqlparams, sqlparam_types) = params! { "min_id" => FIRST_CUSTOM_COLLECTION_ID };
+        let mut rs = self
+            .sql(
+                "SELECT collection_id, name
+                   FROM collections
+                  WHERE collection_id >= @min_id",
+            )?
+            .params(sqlparams)
+            .param_types(sqlparam_types)
+            .execute_async(&self.conn)?;
+        let mut count = 0;
+        while let Some(row) = rs.next_async().await {
+            let mut row = row?;
+            let id = row[0]
+                .get_string_value()
+                .parse::<i32>()
+                .map_err(|e| DbError::integrity(e.to_string()))?;
+            self.coll_cache.put(id, row[1].take_string_value()).await;
+            count += 1;
+        }
+        Ok(count)
+    }
+
     async fn load_collection_names(
         &self,
         collection_ids: impl Iterator<Item = &i32>,
@@ -788,29 +931,25 @@ impl SpannerDb {
             "fxa_uid" => user_id.fxa_uid,
             "fxa_kid" => user_id.fxa_kid
         };
+        // SUM is NULL for users without any (unexpired) BSOs
         let result = self
             .sql(
-                "SELECT SUM(BYTE_LENGTH(payload))
+                "SELECT COALESCE(SUM(BYTE_LENGTH(payload)), 0)
                    FROM bsos
                   WHERE fxa_uid = @fxa_uid
                     AND fxa_kid = @fxa_kid
-                    AND expiry > CURRENT_TIMESTAMP()
-                  GROUP BY fxa_uid",
+                    AND expiry > CURRENT_TIMESTAMP()",
             )?
             .params(sqlparams)
             .param_types(sqlparam_types)
             .execute_async(&self.conn)?
-            .one_or_none()
+            .one()
             .await?;
-        if let Some(result) = result {
-            let usage = result[0]
-                .get_string_value()
-                .parse::<i64>()
-                .map_err(|e| DbError::integrity(e.to_string()))?;
-            Ok(usage as u64)
-        } else {
-            Ok(0)
-        }
+        let usage = result[0]
+            .get_string_value()
+            .parse::<i64>()
+            .map_err(|e| DbError::integrity(e.to_string()))?;
+        Ok(usage as u64)
     }
 
     async fn get_quota_usage_async(
@@ -1009,6 +1148,10 @@ impl SpannerDb {
     }
 
     async fn delete_storage_async(&self, user_id: params::DeleteStorage) -> DbResult<()> {
+        self.session
+            .borrow_mut()
+            .written_users
+            .insert(user_id.clone());
         // Also deletes child bsos/batch rows (INTERLEAVE IN PARENT
         // user_collections ON DELETE CASCADE)
         let (sqlparams, sqlparam_types) = params! {
@@ -1027,6 +1170,73 @@ impl SpannerDb {
         Ok(())
     }
 
+    async fn get_storage_epoch_async(
+        &self,
+        user_id: params::GetStorageEpoch,
+    ) -> DbResult<results::GetStorageEpoch> {
+        let (sqlparams, sqlparam_types) = params! {
+            "fxa_uid" => user_id.fxa_uid,
+            "fxa_kid" => user_id.fxa_kid
+        };
+        let result = self
+            .sql(
+                "SELECT epoch
+                   FROM user_epochs
+                  WHERE fxa_uid = @fxa_uid
+                    AND fxa_kid = @fxa_kid",
+            )?
+            .params(sqlparams)
+            .param_types(sqlparam_types)
+            .execute_async(&self.conn)?
+            .one_or_none()
+            .await?;
+        result
+            .map(|row| sync_timestamp_from_rfc3339(row[0].get_string_value()))
+            .transpose()
+    }
+
+    async fn bump_storage_epoch_async(
+        &self,
+        user_id: params::BumpStorageEpoch,
+    ) -> DbResult<results::BumpStorageEpoch> {
+        // Like update_collection_async: no SQL upserts
+        let timestamp = self.checked_timestamp()?;
+        self.session.borrow_mut().locked_users.remove(&user_id);
+        let (sqlparams, mut sqlparam_types) = params! {
+            "fxa_uid" => user_id.fxa_uid,
+            "fxa_kid" => user_id.fxa_kid,
+            "epoch" => timestamp.as_rfc3339()?,
+        };
+        sqlparam_types.insert("epoch".to_owned(), as_type(TypeCode::TIMESTAMP));
+        let result = self
+            .sql(
+                "SELECT 1
+                   FROM user_epochs
+                  WHERE fxa_uid = @fxa_uid
+                    AND fxa_kid = @fxa_kid",
+            )?
+            .params(sqlparams.clone())
+            .param_types(sqlparam_types.clone())
+            .execute_async(&self.conn)?
+            .one_or_none()
+            .await?;
+        let sql = if result.is_some() {
+            "UPDATE user_epochs
+                SET epoch = @epoch
+              WHERE fxa_uid = @fxa_uid
+                AND fxa_kid = @fxa_kid"
+        } else {
+            "INSERT INTO user_epochs (fxa_uid, fxa_kid, epoch)
+             VALUES (@fxa_uid, @fxa_kid, @epoch)"
+        };
+        self.sql(sql)?
+            .params(sqlparams)
+            .param_types(sqlparam_types)
+            .execute_dml_async(&self.conn)
+            .await?;
+        Ok(timestamp)
+    }
+
     pub fn checked_timestamp(&self) -> DbResult<SyncTimestamp> {
         self.session
             .borrow()
@@ -1038,12 +1248,132 @@ impl SpannerDb {
         &self,
         params: params::DeleteCollection,
     ) -> DbResult<results::DeleteCollection> {
-        // Also deletes child bsos/batch rows (INTERLEAVE IN PARENT
-        // user_collections ON DELETE CASCADE)
         let collection_id = self.get_collection_id_async(&params.collection).await?;
+        if self
+            .delete_user_collection(&params.user_id, collection_id, params.collection)
+            .await?
+        {
+            self.erect_tombstone(&params.user_id).await
+        } else {
+            self.get_storage_timestamp(params.user_id).await
+        }
+    }
+
+    async fn delete_collections_async(
+        &self,
+        params: params::DeleteCollections,
+    ) -> DbResult<results::DeleteCollections> {
+        let mut collections = HashMap::new();
+        for collection in params.collections {
+            let deleted = match self.get_collection_id_async(&collection).await {
+                Ok(collection_id) => {
+                    self.delete_user_collection(&params.user_id, collection_id, collection.clone())
+                        .await?
+                }
+                Err(e) if e.is_collection_not_found() => false,
+                Err(e) => return Err(e),
+            };
+            collections.insert(collection, deleted);
+        }
+        let modified = if collections.values().any(|deleted| *deleted) {
+            self.session
+                .borrow_mut()
+                .written_users
+                .insert(params.user_id.clone());
+            self.erect_tombstone(&params.user_id).await?
+        } else {
+            self.get_storage_timestamp(params.user_id).await?
+        };
+        Ok(results::DeleteCollections {
+            modified,
+            collections,
+        })
+    }
+
+    async fn delete_collections_except_async(
+        &self,
+        params: params::DeleteCollectionsExcept,
+    ) -> DbResult<results::DeleteCollectionsExcept> {
+        // Collections that were never created have nothing to keep
+        let mut keep = vec![TOMBSTONE];
+        for collection in &params.keep {
+            match self.get_collection_id_async(collection).await {
+                Ok(collection_id) => keep.push(collection_id),
+                Err(e) if e.is_collection_not_found() => (),
+                Err(e) => return Err(e),
+            }
+        }
         let (sqlparams, mut sqlparam_types) = params! {
             "fxa_uid" => params.user_id.fxa_uid.clone(),
             "fxa_kid" => params.user_id.fxa_kid.clone(),
+            "keep" => keep,
+            "pretouch_ts" => PRETOUCH_TS.to_owned(),
+        };
+        sqlparam_types.insert("pretouch_ts".to_owned(), as_type(TypeCode::TIMESTAMP));
+
+        let mut deleted = HashMap::new();
+        let mut rs = self
+            .sql(
+                "SELECT collection_id
+                   FROM user_collections
+                  WHERE fxa_uid = @fxa_uid
+                    AND fxa_kid = @fxa_kid
+                    AND collection_id NOT IN UNNEST(@keep)
+                    AND modified > @pretouch_ts",
+            )?
+            .params(sqlparams.clone())
+            .param_types(sqlparam_types.clone())
+            .execute_async(&self.conn)?;
+        while let Some(row) = rs.next_async().await {
+            let id = row?[0]
+                .get_string_value()
+                .parse::<i32>()
+                .map_err(|e| DbError::integrity(e.to_string()))?;
+            deleted.insert(id, true);
+        }
+        if deleted.is_empty() {
+            return Ok(results::DeleteCollections {
+                modified: self.get_storage_timestamp(params.user_id).await?,
+                collections: HashMap::new(),
+            });
+        }
+
+        // Also deletes child bsos/batch rows (INTERLEAVE IN PARENT
+        // user_collections ON DELETE CASCADE)
+        self.sql(
+            "DELETE FROM user_collections
+              WHERE fxa_uid = @fxa_uid
+                AND fxa_kid = @fxa_kid
+                AND collection_id NOT IN UNNEST(@keep)
+                AND modified > @pretouch_ts",
+        )?
+        .params(sqlparams)
+        .param_types(sqlparam_types)
+        .execute_dml_async(&self.conn)
+        .await?;
+        let collections = self.map_collection_names(deleted).await?;
+        self.session
+            .borrow_mut()
+            .written_users
+            .insert(params.user_id.clone());
+        Ok(results::DeleteCollections {
+            modified: self.erect_tombstone(&params.user_id).await?,
+            collections,
+        })
+    }
+
+    /// Delete a user's collection, returning whether it existed
+    async fn delete_user_collection(
+        &self,
+        user_id: &UserIdentifier,
+        collection_id: i32,
+        collection: String,
+    ) -> DbResult<bool> {
+        // Also deletes child bsos/batch rows (INTERLEAVE IN PARENT
+        // user_collections ON DELETE CASCADE)
+        let (sqlparams, mut sqlparam_types) = params! {
+            "fxa_uid" => user_id.fxa_uid.clone(),
+            "fxa_kid" => user_id.fxa_kid.clone(),
             "collection_id" => collection_id,
             "pretouch_ts" => PRETOUCH_TS.to_owned(),
         };
@@ -1062,13 +1392,11 @@ impl SpannerDb {
             .await?;
         if affected_rows > 0 {
             let mut tags = HashMap::default();
-            tags.insert("collection".to_string(), params.collection);
+            tags.insert("collection".to_string(), collection);
             self.metrics
                 .incr_with_tags("storage.spanner.delete_collection", tags);
-            self.erect_tombstone(&params.user_id).await
-        } else {
-            self.get_storage_timestamp(params.user_id).await
         }
+        Ok(affected_rows > 0)
     }
 
     pub(super) async fn update_collection_async(
@@ -1162,7 +1490,8 @@ impl SpannerDb {
                   WHERE fxa_uid = @fxa_uid
                     AND fxa_kid = @fxa_kid
                     AND collection_id = @collection_id
-                    AND bso_id = @bso_id",
+                    AND bso_id = @bso_id
+                    AND expiry > CURRENT_TIMESTAMP()",
             )?
             .params(sqlparams)
             .param_types(sqlparam_types)
@@ -1178,6 +1507,39 @@ impl SpannerDb {
         }
     }
 
+    /// Delete the bso if it has expired. Its collection (and its quota
+    /// counters) are left as is: reads already skipped the bso
+    async fn purge_expired_bso_async(
+        &self,
+        params: params::PurgeExpiredBso,
+    ) -> DbResult<results::PurgeExpiredBso> {
+        let collection_id = match self.get_collection_id_async(&params.collection).await {
+            Ok(collection_id) => collection_id,
+            Err(e) if e.is_collection_not_found() => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        let (sqlparams, sqlparam_types) = params! {
+            "fxa_uid" => params.user_id.fxa_uid,
+            "fxa_kid" => params.user_id.fxa_kid,
+            "collection_id" => collection_id,
+            "bso_id" => params.id,
+        };
+        let affected_rows = self
+            .sql(
+                "DELETE FROM bsos
+                  WHERE fxa_uid = @fxa_uid
+                    AND fxa_kid = @fxa_kid
+                    AND collection_id = @collection_id
+                    AND bso_id = @bso_id
+                    AND expiry <= CURRENT_TIMESTAMP()",
+            )?
+            .params(sqlparams)
+            .param_types(sqlparam_types)
+            .execute_dml_async(&self.conn)
+            .await?;
+        Ok(affected_rows > 0)
+    }
+
     async fn delete_bsos_async(&self, params: params::DeleteBsos) -> DbResult<results::DeleteBsos> {
         let user_id = params.user_id.clone();
         let collection_id = self.get_collection_id_async(&params.collection).await?;
@@ -1328,6 +1690,7 @@ impl SpannerDb {
             params::Offset {
                 offset: offset + modifieds.len() as u64,
                 timestamp: None,
+                after: None,
             }
             .to_string(),
         )
@@ -1368,22 +1731,43 @@ impl SpannerDb {
     }
 
     async fn get_bsos_async(&self, params: params::GetBsos) -> DbResult<results::GetBsos> {
-        let query = "\
-            SELECT bso_id, sortindex, payload, modified, expiry
+        // Columns outside the projection aren't read
+        let (sortindex, payload) = match params.projection {
+            Projection::Full => ("sortindex", "payload"),
+            Projection::NoSortindex => ("NULL", "payload"),
+            Projection::Meta => ("NULL", "''"),
+        };
+        let query = format!(
+            "\
+            SELECT bso_id, {sortindex}, {payload}, modified, expiry
               FROM bsos
              WHERE fxa_uid = @fxa_uid
                AND fxa_kid = @fxa_kid
                AND collection_id = @collection_id
-               AND expiry > CURRENT_TIMESTAMP()";
+               AND expiry > CURRENT_TIMESTAMP()",
+            sortindex = sortindex,
+            payload = payload
+        );
         let limit = params.limit.map(i64::from).unwrap_or(-1);
-        let params::Offset { offset, timestamp } = params.offset.clone().unwrap_or_default();
+        let params::Offset {
+            offset, timestamp, ..
+        } = params.offset.clone().unwrap_or_default();
         let sort = params.sort;
+        let max_payload_bytes = params.max_payload_bytes;
 
-        let mut streaming = self.bsos_query_async(query, params).await?;
+        let mut streaming = self.bsos_query_async(&query, params).await?;
         let mut bsos = vec![];
+        let mut payload_bytes = 0;
+        let mut truncated = false;
         while let Some(row) = streaming.next_async().await {
-            let row = row?;
-            bsos.push(bso_from_row(row)?);
+            let bso = bso_from_row(row?)?;
+            // Stop reading past max_payload_bytes (see payload_page_len)
+            payload_bytes += bso.payload.len() as u64;
+            if !bsos.is_empty() && matches!(max_payload_bytes, Some(max) if payload_bytes > max) {
+                truncated = true;
+                break;
+            }
+            bsos.push(bso);
         }
 
         // NOTE: when bsos.len() == 0, server-syncstorage (the Python impl)
@@ -1393,8 +1777,10 @@ impl SpannerDb {
         // backwards compat.:
         // https://bugzilla.mozilla.org/show_bug.cgi?id=963332
 
-        let next_offset = if limit >= 0 && bsos.len() > limit as usize {
-            bsos.pop();
+        let next_offset = if truncated || (limit >= 0 && bsos.len() > limit as usize) {
+            if !truncated {
+                bsos.pop();
+            }
             let modifieds: Vec<i64> = bsos.iter().map(|r| r.modified.as_i64()).collect();
             self.encode_next_offset(sort, offset, timestamp.map(|t| t.as_i64()), modifieds)
         } else {
@@ -1409,7 +1795,9 @@ impl SpannerDb {
 
     async fn get_bso_ids_async(&self, params: params::GetBsos) -> DbResult<results::GetBsoIds> {
         let limit = params.limit.map(i64::from).unwrap_or(-1);
-        let params::Offset { offset, timestamp } = params.offset.clone().unwrap_or_default();
+        let params::Offset {
+            offset, timestamp, ..
+        } = params.offset.clone().unwrap_or_default();
         let sort = params.sort;
 
         let query = "\
@@ -1449,6 +1837,59 @@ impl SpannerDb {
         })
     }
 
+    async fn get_bsos_count_async(
+        &self,
+        params: params::GetBsosCount,
+    ) -> DbResult<results::GetBsosCount> {
+        let mut query = "\
+            SELECT COUNT(*)
+              FROM bsos
+             WHERE fxa_uid = @fxa_uid
+               AND fxa_kid = @fxa_kid
+               AND collection_id = @collection_id
+               AND expiry > CURRENT_TIMESTAMP()"
+            .to_owned();
+        let (mut sqlparams, mut sqlparam_types) = params! {
+            "fxa_uid" => params.user_id.fxa_uid,
+            "fxa_kid" => params.user_id.fxa_kid,
+            "collection_id" => self.get_collection_id_async(&params.collection).await?,
+        };
+
+        if !params.ids.is_empty() {
+            query = format!("{} AND bso_id IN UNNEST(@ids)", query);
+            sqlparam_types.insert("ids".to_owned(), params.ids.spanner_type());
+            sqlparams.insert("ids".to_owned(), params.ids.into_spanner_value());
+        }
+        if let Some(older) = params.older {
+            query = format!("{} AND modified < @older", query);
+            sqlparams.insert(
+                "older".to_string(),
+                older.as_rfc3339()?.into_spanner_value(),
+            );
+            sqlparam_types.insert("older".to_string(), as_type(TypeCode::TIMESTAMP));
+        }
+        if let Some(newer) = params.newer {
+            query = format!("{} AND modified > @newer", query);
+            sqlparams.insert(
+                "newer".to_string(),
+                newer.as_rfc3339()?.into_spanner_value(),
+            );
+            sqlparam_types.insert("newer".to_string(), as_type(TypeCode::TIMESTAMP));
+        }
+
+        let row = self
+            .sql(&query)?
+            .params(sqlparams)
+            .param_types(sqlparam_types)
+            .execute_async(&self.conn)?
+            .one()
+            .await?;
+        row[0]
+            .get_string_value()
+            .parse::<u64>()
+            .map_err(|e| DbError::integrity(e.to_string()))
+    }
+
     async fn get_bso_async(&self, params: params::GetBso) -> DbResult<Option<results::GetBso>> {
         let collection_id = self.get_collection_id_async(&params.collection).await?;
         let (sqlparams, sqlparam_types) = params! {
@@ -1663,6 +2104,46 @@ impl SpannerDb {
         Ok(true)
     }
 
+    async fn get_database_stats_async(&self) -> DbResult<results::GetDatabaseStats> {
+        let row = self
+            .sql(
+                "SELECT (SELECT COUNT(*) FROM bsos),
+                        (SELECT COUNT(*) FROM bsos WHERE expiry <= CURRENT_TIMESTAMP()),
+                        (SELECT COUNT(*) FROM user_collections),
+                        (SELECT COUNT(*) FROM batches)",
+            )?
+            .execute_async(&self.conn)?
+            .one()
+            .await?;
+        let count = |i: usize| {
+            row[i]
+                .get_string_value()
+                .parse::<i64>()
+                .map_err(|e| DbError::integrity(e.to_string()))
+        };
+        Ok(results::GetDatabaseStats {
+            bsos: count(0)?,
+            expired_bsos: count(1)?,
+            user_collections: count(2)?,
+            batches: count(3)?,
+        })
+    }
+
+    async fn get_native_ttl_async(&self) -> DbResult<results::GetNativeTtl> {
+        let mut native_ttl = results::GetNativeTtl::default();
+        let mut rs = self
+            .sql(NATIVE_TTL_TABLES_QUERY)?
+            .execute_async(&self.conn)?;
+        while let Some(row) = rs.next_async().await {
+            match row?[0].get_string_value() {
+                "bsos" => native_ttl.bsos = true,
+                "batches" => native_ttl.batches = true,
+                _ => (),
+            }
+        }
+        Ok(native_ttl)
+    }
+
     pub fn quota_error(&self, collection: &str) -> DbError {
         // return the over quota error.
         let mut tags = HashMap::default();
@@ -1678,9 +2159,10 @@ impl SpannerDb {
         collection_id: i32,
     ) -> DbResult<Option<usize>> {
         // duplicate quota trap in test func below.
-        if !self.quota.enabled {
-            return Ok(None);
-        }
+        let limit = match self.quota.limit_for(user_id.legacy_id, &user_id.fxa_uid) {
+            Some(limit) => limit,
+            None => return Ok(None),
+        };
         let usage = self
             .get_quota_usage_async(params::GetQuotaUsage {
                 user_id: user_id.clone(),
@@ -1688,7 +2170,7 @@ impl SpannerDb {
                 collection_id,
             })
             .await?;
-        if usage.total_bytes >= self.quota.size {
+        if usage.total_bytes >= limit {
             if self.quota.enforced {
                 return Err(self.quota_error(collection));
             } else {
@@ -1701,7 +2183,6 @@ impl SpannerDb {
     // NOTE: Currently this put_bso_async_without_mutations impl is only used
     // during db tests, see the with_mutations impl for the non-tests version
     async fn put_bso_without_mutations(&self, bso: params::PutBso) -> DbResult<results::PutBso> {
-        use syncstorage_db_common::util::to_rfc3339;
         let collection_id = self
             .get_or_create_collection_id_async(&bso.collection)
             .await?;
@@ -1709,15 +2190,30 @@ impl SpannerDb {
         self.check_quota(&bso.user_id, &bso.collection, collection_id)
             .await?;
 
+        // prewarm the collections table by ensuring that the row is added if not present.
+        self.update_collection_async(&bso.user_id, collection_id, &bso.collection)
+            .await?;
+        let user_id = bso.user_id.clone();
+        self.write_bso_without_mutations(bso, collection_id).await?;
+        // update the counts for the user_collections table.
+        self.update_user_collection_quotas(&user_id, collection_id)
+            .await
+    }
+
+    /// Write a BSO, leaving its collection's `user_collections` row (which
+    /// must already exist) to the caller
+    async fn write_bso_without_mutations(
+        &self,
+        bso: params::PutBso,
+        collection_id: i32,
+    ) -> DbResult<()> {
+        use syncstorage_db_common::util::to_rfc3339;
         let (mut sqlparams, mut sqlparam_types) = params! {
             "fxa_uid" => bso.user_id.fxa_uid.clone(),
             "fxa_kid" => bso.user_id.fxa_kid.clone(),
             "collection_id" => collection_id,
             "bso_id" => bso.id,
         };
-        // prewarm the collections table by ensuring that the row is added if not present.
-        self.update_collection_async(&bso.user_id, collection_id, &bso.collection)
-            .await?;
         let timestamp = self.checked_timestamp()?;
 
         let result = self
@@ -1798,7 +2294,7 @@ impl SpannerDb {
 
             if q.is_empty() {
                 // Nothing to update
-                return Ok(timestamp);
+                return Ok(());
             }
 
             format!(
@@ -1867,9 +2363,7 @@ impl SpannerDb {
             .param_types(sqlparam_types)
             .execute_dml_async(&self.conn)
             .await?;
-        // update the counts for the user_collections table.
-        self.update_user_collection_quotas(&bso.user_id, collection_id)
-            .await
+        Ok(())
     }
 
     // NOTE: Currently this post_bsos_without_mutations impl is only used
@@ -1881,6 +2375,14 @@ impl SpannerDb {
         let collection_id = self
             .get_or_create_collection_id_async(&input.collection)
             .await?;
+        if !input.for_batch {
+            self.check_quota(&input.user_id, &input.collection, collection_id)
+                .await?;
+        }
+        // Touched once for the whole post, ahead of the BSOs (their parent
+        // row)
+        self.update_collection_async(&input.user_id, collection_id, &input.collection)
+            .await?;
         let mut result = results::PostBsos {
             modified: self.checked_timestamp()?,
             success: Default::default(),
@@ -1889,14 +2391,17 @@ impl SpannerDb {
 
         for pbso in input.bsos {
             let id = pbso.id;
-            self.put_bso_without_mutations(params::PutBso {
-                user_id: input.user_id.clone(),
-                collection: input.collection.clone(),
-                id: id.clone(),
-                payload: pbso.payload,
-                sortindex: pbso.sortindex,
-                ttl: pbso.ttl,
-            })
+            self.write_bso_without_mutations(
+                params::PutBso {
+                    user_id: input.user_id.clone(),
+                    collection: input.collection.clone(),
+                    id: id.clone(),
+                    payload: pbso.payload,
+                    sortindex: pbso.sortindex,
+                    ttl: pbso.ttl,
+                },
+                collection_id,
+            )
             .await?;
             result.success.push(id);
         }
@@ -1911,27 +2416,43 @@ impl Db for SpannerDb {
 
     fn commit(&self) -> DbFuture<'_, (), Self::Error> {
         let db = self.clone();
-        Box::pin(async move { db.commit_async().map_err(Into::into).await })
+        Box::pin(timed(&self.metrics, "commit", None, async move {
+            db.commit_async().map_err(Into::into).await
+        }))
     }
 
     fn rollback(&self) -> DbFuture<'_, (), Self::Error> {
         let db = self.clone();
-        Box::pin(async move { db.rollback_async().map_err(Into::into).await })
+        Box::pin(timed(&self.metrics, "rollback", None, async move {
+            db.rollback_async().map_err(Into::into).await
+        }))
     }
 
     fn lock_for_read(&self, param: params::LockCollection) -> DbFuture<'_, (), Self::Error> {
         let db = self.clone();
-        Box::pin(async move { db.lock_for_read_async(param).map_err(Into::into).await })
+        Box::pin(timed(
+            &self.metrics,
+            "lock_for_read",
+            param.collection_tag(),
+            async move { db.lock_for_read_async(param).map_err(Into::into).await },
+        ))
     }
 
     fn lock_for_write(&self, param: params::LockCollection) -> DbFuture<'_, (), Self::Error> {
         let db = self.clone();
-        Box::pin(async move { db.lock_for_write_async(param).map_err(Into::into).await })
+        Box::pin(timed(
+            &self.metrics,
+            "lock_for_write",
+            param.collection_tag(),
+            async move { db.lock_for_write_async(param).map_err(Into::into).await },
+        ))
     }
 
     fn begin(&self, for_write: bool) -> DbFuture<'_, (), Self::Error> {
         let db = self.clone();
-        Box::pin(async move { db.begin_async(for_write).map_err(Into::into).await })
+        Box::pin(timed(&self.metrics, "begin", None, async move {
+            db.begin_async(for_write).map_err(Into::into).await
+        }))
     }
 
     fn get_collection_timestamp(
@@ -1939,11 +2460,16 @@ impl Db for SpannerDb {
         param: params::GetCollectionTimestamp,
     ) -> DbFuture<'_, results::GetCollectionTimestamp, Self::Error> {
         let db = self.clone();
-        Box::pin(async move {
-            db.get_collection_timestamp_async(param)
-                .map_err(Into::into)
-                .await
-        })
+        Box::pin(timed(
+            &self.metrics,
+            "get_collection_timestamp",
+            param.collection_tag(),
+            async move {
+                db.get_collection_timestamp_async(param)
+                    .map_err(Into::into)
+                    .await
+            },
+        ))
     }
 
     fn get_storage_timestamp(
@@ -1951,7 +2477,12 @@ impl Db for SpannerDb {
         param: params::GetStorageTimestamp,
     ) -> DbFuture<'_, results::GetStorageTimestamp, Self::Error> {
         let db = self.clone();
-        Box::pin(async move { db.get_storage_timestamp(param).map_err(Into::into).await })
+        Box::pin(timed(
+            &self.metrics,
+            "get_storage_timestamp",
+            param.collection_tag(),
+            async move { db.get_storage_timestamp(param).map_err(Into::into).await },
+        ))
     }
 
     fn delete_collection(
@@ -1959,12 +2490,76 @@ impl Db for SpannerDb {
         param: params::DeleteCollection,
     ) -> DbFuture<'_, results::DeleteCollection, Self::Error> {
         let db = self.clone();
-        Box::pin(async move { db.delete_collection_async(param).map_err(Into::into).await })
+        Box::pin(timed(
+            &self.metrics,
+            "delete_collection",
+            param.collection_tag(),
+            async move { db.delete_collection_async(param).map_err(Into::into).await },
+        ))
+    }
+
+    fn delete_collections(
+        &self,
+        param: params::DeleteCollections,
+    ) -> DbFuture<'_, results::DeleteCollections, Self::Error> {
+        let db = self.clone();
+        Box::pin(timed(
+            &self.metrics,
+            "delete_collections",
+            param.collection_tag(),
+            async move { db.delete_collections_async(param).map_err(Into::into).await },
+        ))
+    }
+
+    fn delete_collections_except(
+        &self,
+        param: params::DeleteCollectionsExcept,
+    ) -> DbFuture<'_, results::DeleteCollectionsExcept, Self::Error> {
+        let db = self.clone();
+        Box::pin(timed(
+            &self.metrics,
+            "delete_collections_except",
+            param.collection_tag(),
+            async move {
+                db.delete_collections_except_async(param)
+                    .map_err(Into::into)
+                    .await
+            },
+        ))
     }
 
     fn check(&self) -> DbFuture<'_, results::Check, Self::Error> {
         let db = self.clone();
-        Box::pin(async move { db.check_async().map_err(Into::into).await })
+        Box::pin(timed(&self.metrics, "check", None, async move {
+            db.check_async().map_err(Into::into).await
+        }))
+    }
+
+    fn get_database_stats(&self) -> DbFuture<'_, results::GetDatabaseStats, Self::Error> {
+        let db = self.clone();
+        Box::pin(timed(
+            &self.metrics,
+            "get_database_stats",
+            None,
+            async move { db.get_database_stats_async().map_err(Into::into).await },
+        ))
+    }
+
+    fn get_native_ttl(&self) -> DbFuture<'_, results::GetNativeTtl, Self::Error> {
+        let db = self.clone();
+        Box::pin(timed(&self.metrics, "get_native_ttl", None, async move {
+            db.get_native_ttl_async().map_err(Into::into).await
+        }))
+    }
+
+    /// Left to the `purge_ttl` job (or to row deletion policies): Spanner's
+    /// deletes of that size must run as partitioned DML, outside of a
+    /// session's transactions
+    fn purge_expired(
+        &self,
+        _params: params::PurgeExpired,
+    ) -> DbFuture<'_, results::PurgeExpired, Self::Error> {
+        Box::pin(futures::future::ok(results::PurgeExpired::default()))
     }
 
     fn get_collection_timestamps(
@@ -1972,11 +2567,16 @@ impl Db for SpannerDb {
         user_id: params::GetCollectionTimestamps,
     ) -> DbFuture<'_, results::GetCollectionTimestamps, Self::Error> {
         let db = self.clone();
-        Box::pin(async move {
-            db.get_collection_timestamps_async(user_id)
-                .map_err(Into::into)
-                .await
-        })
+        Box::pin(timed(
+            &self.metrics,
+            "get_collection_timestamps",
+            user_id.collection_tag(),
+            async move {
+                db.get_collection_timestamps_async(user_id)
+                    .map_err(Into::into)
+                    .await
+            },
+        ))
     }
 
     fn get_collection_counts(
@@ -1984,11 +2584,16 @@ impl Db for SpannerDb {
         user_id: params::GetCollectionCounts,
     ) -> DbFuture<'_, results::GetCollectionCounts, Self::Error> {
         let db = self.clone();
-        Box::pin(async move {
-            db.get_collection_counts_async(user_id)
-                .map_err(Into::into)
-                .await
-        })
+        Box::pin(timed(
+            &self.metrics,
+            "get_collection_counts",
+            user_id.collection_tag(),
+            async move {
+                db.get_collection_counts_async(user_id)
+                    .map_err(Into::into)
+                    .await
+            },
+        ))
     }
 
     fn get_collection_usage(
@@ -1996,11 +2601,16 @@ impl Db for SpannerDb {
         user_id: params::GetCollectionUsage,
     ) -> DbFuture<'_, results::GetCollectionUsage, Self::Error> {
         let db = self.clone();
-        Box::pin(async move {
-            db.get_collection_usage_async(user_id)
-                .map_err(Into::into)
-                .await
-        })
+        Box::pin(timed(
+            &self.metrics,
+            "get_collection_usage",
+            user_id.collection_tag(),
+            async move {
+                db.get_collection_usage_async(user_id)
+                    .map_err(Into::into)
+                    .await
+            },
+        ))
     }
 
     fn get_storage_usage(
@@ -2008,7 +2618,12 @@ impl Db for SpannerDb {
         param: params::GetStorageUsage,
     ) -> DbFuture<'_, results::GetStorageUsage, Self::Error> {
         let db = self.clone();
-        Box::pin(async move { db.get_storage_usage_async(param).map_err(Into::into).await })
+        Box::pin(timed(
+            &self.metrics,
+            "get_storage_usage",
+            param.collection_tag(),
+            async move { db.get_storage_usage_async(param).map_err(Into::into).await },
+        ))
     }
 
     fn get_quota_usage(
@@ -2016,7 +2631,12 @@ impl Db for SpannerDb {
         param: params::GetQuotaUsage,
     ) -> DbFuture<'_, results::GetQuotaUsage, Self::Error> {
         let db = self.clone();
-        Box::pin(async move { db.get_quota_usage_async(param).map_err(Into::into).await })
+        Box::pin(timed(
+            &self.metrics,
+            "get_quota_usage",
+            param.collection_tag(),
+            async move { db.get_quota_usage_async(param).map_err(Into::into).await },
+        ))
     }
 
     fn delete_storage(
@@ -2024,7 +2644,54 @@ impl Db for SpannerDb {
         param: params::DeleteStorage,
     ) -> DbFuture<'_, results::DeleteStorage, Self::Error> {
         let db = self.clone();
-        Box::pin(async move { db.delete_storage_async(param).map_err(Into::into).await })
+        Box::pin(timed(
+            &self.metrics,
+            "delete_storage",
+            param.collection_tag(),
+            async move { db.delete_storage_async(param).map_err(Into::into).await },
+        ))
+    }
+
+    fn get_storage_epoch(
+        &self,
+        param: params::GetStorageEpoch,
+    ) -> DbFuture<'_, results::GetStorageEpoch, Self::Error> {
+        let db = self.clone();
+        Box::pin(timed(
+            &self.metrics,
+            "get_storage_epoch",
+            param.collection_tag(),
+            async move { db.get_storage_epoch_async(param).map_err(Into::into).await },
+        ))
+    }
+
+    fn bump_storage_epoch(
+        &self,
+        param: params::BumpStorageEpoch,
+    ) -> DbFuture<'_, results::BumpStorageEpoch, Self::Error> {
+        let db = self.clone();
+        Box::pin(timed(
+            &self.metrics,
+            "bump_storage_epoch",
+            param.collection_tag(),
+            async move { db.bump_storage_epoch_async(param).map_err(Into::into).await },
+        ))
+    }
+
+    /// Nothing's recorded: Spanner keys storage by the token's `fxa_kid`, so
+    /// writes with keys other than the latest land in storage of their own
+    fn get_keys_changed_at(
+        &self,
+        _params: params::GetKeysChangedAt,
+    ) -> DbFuture<'_, results::GetKeysChangedAt, Self::Error> {
+        Box::pin(futures::future::ok(None))
+    }
+
+    fn record_keys_changed_at(
+        &self,
+        _params: params::RecordKeysChangedAt,
+    ) -> DbFuture<'_, results::RecordKeysChangedAt, Self::Error> {
+        Box::pin(futures::future::ok(()))
     }
 
     fn delete_bso(
@@ -2032,7 +2699,25 @@ impl Db for SpannerDb {
         param: params::DeleteBso,
     ) -> DbFuture<'_, results::DeleteBso, Self::Error> {
         let db = self.clone();
-        Box::pin(async move { db.delete_bso_async(param).map_err(Into::into).await })
+        Box::pin(timed(
+            &self.metrics,
+            "delete_bso",
+            param.collection_tag(),
+            async move { db.delete_bso_async(param).map_err(Into::into).await },
+        ))
+    }
+
+    fn purge_expired_bso(
+        &self,
+        param: params::PurgeExpiredBso,
+    ) -> DbFuture<'_, results::PurgeExpiredBso, Self::Error> {
+        let db = self.clone();
+        Box::pin(timed(
+            &self.metrics,
+            "purge_expired_bso",
+            param.collection_tag(),
+            async move { db.purge_expired_bso_async(param).map_err(Into::into).await },
+        ))
     }
 
     fn delete_bsos(
@@ -2040,12 +2725,22 @@ impl Db for SpannerDb {
         param: params::DeleteBsos,
     ) -> DbFuture<'_, results::DeleteBsos, Self::Error> {
         let db = self.clone();
-        Box::pin(async move { db.delete_bsos_async(param).map_err(Into::into).await })
+        Box::pin(timed(
+            &self.metrics,
+            "delete_bsos",
+            param.collection_tag(),
+            async move { db.delete_bsos_async(param).map_err(Into::into).await },
+        ))
     }
 
     fn get_bsos(&self, param: params::GetBsos) -> DbFuture<'_, results::GetBsos, Self::Error> {
         let db = self.clone();
-        Box::pin(async move { db.get_bsos_async(param).map_err(Into::into).await })
+        Box::pin(timed(
+            &self.metrics,
+            "get_bsos",
+            param.collection_tag(),
+            async move { db.get_bsos_async(param).map_err(Into::into).await },
+        ))
     }
 
     fn get_bso_ids(
@@ -2053,12 +2748,35 @@ impl Db for SpannerDb {
         param: params::GetBsoIds,
     ) -> DbFuture<'_, results::GetBsoIds, Self::Error> {
         let db = self.clone();
-        Box::pin(async move { db.get_bso_ids_async(param).map_err(Into::into).await })
+        Box::pin(timed(
+            &self.metrics,
+            "get_bso_ids",
+            param.collection_tag(),
+            async move { db.get_bso_ids_async(param).map_err(Into::into).await },
+        ))
+    }
+
+    fn get_bsos_count(
+        &self,
+        param: params::GetBsosCount,
+    ) -> DbFuture<'_, results::GetBsosCount, Self::Error> {
+        let db = self.clone();
+        Box::pin(timed(
+            &self.metrics,
+            "get_bsos_count",
+            param.collection_tag(),
+            async move { db.get_bsos_count_async(param).map_err(Into::into).await },
+        ))
     }
 
     fn get_bso(&self, param: params::GetBso) -> DbFuture<'_, Option<results::GetBso>, Self::Error> {
         let db = self.clone();
-        Box::pin(async move { db.get_bso_async(param).map_err(Into::into).await })
+        Box::pin(timed(
+            &self.metrics,
+            "get_bso",
+            param.collection_tag(),
+            async move { db.get_bso_async(param).map_err(Into::into).await },
+        ))
     }
 
     fn get_bso_timestamp(
@@ -2066,17 +2784,32 @@ impl Db for SpannerDb {
         param: params::GetBsoTimestamp,
     ) -> DbFuture<'_, results::GetBsoTimestamp, Self::Error> {
         let db = self.clone();
-        Box::pin(async move { db.get_bso_timestamp_async(param).map_err(Into::into).await })
+        Box::pin(timed(
+            &self.metrics,
+            "get_bso_timestamp",
+            param.collection_tag(),
+            async move { db.get_bso_timestamp_async(param).map_err(Into::into).await },
+        ))
     }
 
     fn put_bso(&self, param: params::PutBso) -> DbFuture<'_, results::PutBso, Self::Error> {
         let db = self.clone();
-        Box::pin(async move { db.put_bso_async(param).map_err(Into::into).await })
+        Box::pin(timed(
+            &self.metrics,
+            "put_bso",
+            param.collection_tag(),
+            async move { db.put_bso_async(param).map_err(Into::into).await },
+        ))
     }
 
     fn post_bsos(&self, param: params::PostBsos) -> DbFuture<'_, results::PostBsos, Self::Error> {
         let db = self.clone();
-        Box::pin(async move { db.post_bsos_async(param).map_err(Into::into).await })
+        Box::pin(timed(
+            &self.metrics,
+            "post_bsos",
+            param.collection_tag(),
+            async move { db.post_bsos_async(param).map_err(Into::into).await },
+        ))
     }
 
     fn create_batch(
@@ -2084,7 +2817,15 @@ impl Db for SpannerDb {
         param: params::CreateBatch,
     ) -> DbFuture<'_, results::CreateBatch, Self::Error> {
         let db = self.clone();
-        Box::pin(async move { batch::create_async(&db, param).map_err(Into::into).await })
+        Box::pin(timed(
+            &self.metrics,
+            "create_batch",
+            param.collection_tag(),
+            async move {
+                db.set_operation_class(OperationClass::Batch);
+                batch::create_async(&db, param).map_err(Into::into).await
+            },
+        ))
     }
 
     fn validate_batch(
@@ -2092,7 +2833,12 @@ impl Db for SpannerDb {
         param: params::ValidateBatch,
     ) -> DbFuture<'_, results::ValidateBatch, Self::Error> {
         let db = self.clone();
-        Box::pin(async move { batch::validate_async(&db, param).map_err(Into::into).await })
+        Box::pin(timed(
+            &self.metrics,
+            "validate_batch",
+            param.collection_tag(),
+            async move { batch::validate_async(&db, param).map_err(Into::into).await },
+        ))
     }
 
     fn append_to_batch(
@@ -2100,7 +2846,15 @@ impl Db for SpannerDb {
         param: params::AppendToBatch,
     ) -> DbFuture<'_, results::AppendToBatch, Self::Error> {
         let db = self.clone();
-        Box::pin(async move { batch::append_async(&db, param).map_err(Into::into).await })
+        Box::pin(timed(
+            &self.metrics,
+            "append_to_batch",
+            param.collection_tag(),
+            async move {
+                db.set_operation_class(OperationClass::Batch);
+                batch::append_async(&db, param).map_err(Into::into).await
+            },
+        ))
     }
 
     fn get_batch(
@@ -2108,7 +2862,12 @@ impl Db for SpannerDb {
         param: params::GetBatch,
     ) -> DbFuture<'_, Option<results::GetBatch>, Self::Error> {
         let db = self.clone();
-        Box::pin(async move { batch::get_async(&db, param).map_err(Into::into).await })
+        Box::pin(timed(
+            &self.metrics,
+            "get_batch",
+            param.collection_tag(),
+            async move { batch::get_async(&db, param).map_err(Into::into).await },
+        ))
     }
 
     fn commit_batch(
@@ -2116,12 +2875,25 @@ impl Db for SpannerDb {
         param: params::CommitBatch,
     ) -> DbFuture<'_, results::CommitBatch, Self::Error> {
         let db = self.clone();
-        Box::pin(async move { batch::commit_async(&db, param).map_err(Into::into).await })
+        Box::pin(timed(
+            &self.metrics,
+            "commit_batch",
+            param.collection_tag(),
+            async move {
+                db.set_operation_class(OperationClass::Batch);
+                batch::commit_async(&db, param).map_err(Into::into).await
+            },
+        ))
     }
 
     fn get_collection_id(&self, name: String) -> DbFuture<'_, i32, Self::Error> {
         let db = self.clone();
-        Box::pin(async move { db.get_collection_id_async(&name).map_err(Into::into).await })
+        Box::pin(timed(
+            &self.metrics,
+            "get_collection_id",
+            Some(collection_tag(&name)),
+            async move { db.get_collection_id_async(&name).map_err(Into::into).await },
+        ))
     }
 
     fn get_connection_info(&self) -> results::ConnectionInfo {
@@ -2142,9 +2914,22 @@ impl Db for SpannerDb {
         }
     }
 
+    fn query_count(&self) -> u64 {
+        self.session.borrow().query_count
+    }
+
+    fn locked_user(&self, user_id: &UserIdentifier) -> Option<results::LockedUser> {
+        self.session.borrow().locked_users.get(user_id).cloned()
+    }
+
     fn create_collection(&self, name: String) -> DbFuture<'_, i32, Self::Error> {
         let db = self.clone();
-        Box::pin(async move { db.create_collection_async(&name).map_err(Into::into).await })
+        Box::pin(timed(
+            &self.metrics,
+            "create_collection",
+            Some(collection_tag(&name)),
+            async move { db.create_collection_async(&name).map_err(Into::into).await },
+        ))
     }
 
     fn update_collection(
@@ -2152,11 +2937,16 @@ impl Db for SpannerDb {
         param: params::UpdateCollection,
     ) -> DbFuture<'_, SyncTimestamp, Self::Error> {
         let db = self.clone();
-        Box::pin(async move {
-            db.update_collection_async(&param.user_id, param.collection_id, &param.collection)
-                .map_err(Into::into)
-                .await
-        })
+        Box::pin(timed(
+            &self.metrics,
+            "update_collection",
+            param.collection_tag(),
+            async move {
+                db.update_collection_async(&param.user_id, param.collection_id, &param.collection)
+                    .map_err(Into::into)
+                    .await
+            },
+        ))
     }
 
     fn timestamp(&self) -> SyncTimestamp {
@@ -2173,7 +2963,12 @@ impl Db for SpannerDb {
         param: params::DeleteBatch,
     ) -> DbFuture<'_, results::DeleteBatch, Self::Error> {
         let db = self.clone();
-        Box::pin(async move { batch::delete_async(&db, param).map_err(Into::into).await })
+        Box::pin(timed(
+            &self.metrics,
+            "delete_batch",
+            param.collection_tag(),
+            async move { batch::delete_async(&db, param).map_err(Into::into).await },
+        ))
     }
 
     fn clear_coll_cache(&self) -> DbFuture<'_, (), Self::Error> {
@@ -2189,6 +2984,8 @@ impl Db for SpannerDb {
             size: limit,
             enabled,
             enforced,
+            overrides: self.quota.overrides.clone(),
+            usage_counters: self.quota.usage_counters,
         };
     }
 
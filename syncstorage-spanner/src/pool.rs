@@ -1,9 +1,19 @@
-use std::{collections::HashMap, fmt, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 
 use async_trait::async_trait;
-use syncserver_common::{BlockingThreadpool, Metrics};
+use syncserver_common::{BlockingThreadpool, CacheBackend, Metrics};
 use syncserver_db_common::{GetPoolState, PoolState};
-use syncstorage_db_common::{Db, DbPool, STD_COLLS};
+use syncstorage_db_common::{
+    results, Db, DbPool, UserIdentifier, FIRST_CUSTOM_COLLECTION_ID, STD_COLLS,
+};
 use syncstorage_settings::{Quota, Settings};
 use tokio::sync::RwLock;
 
@@ -21,6 +31,8 @@ pub struct SpannerDbPool {
     pool: deadpool::managed::Pool<SpannerSession, DbError>,
     /// In-memory cache of collection_ids and their names
     coll_cache: Arc<CollectionCache>,
+    /// Users who recently committed writes
+    recent_writes: Arc<RecentWrites>,
 
     metrics: Metrics,
     quota: Quota,
@@ -28,10 +40,15 @@ pub struct SpannerDbPool {
 
 impl SpannerDbPool {
     /// Creates a new pool of Spanner db connections.
+    ///
+    /// The collection cache stays in process regardless of `cache_url`: it's
+    /// read from async code, where the configured `Cache`'s blocking calls
+    /// would stall the executor
     pub fn new(
         settings: &Settings,
         metrics: &Metrics,
         blocking_threadpool: Arc<BlockingThreadpool>,
+        _cache_backend: &CacheBackend,
     ) -> DbResult<Self> {
         //run_embedded_migrations(settings)?;
         Self::new_without_migrations(settings, metrics, blocking_threadpool)
@@ -47,6 +64,11 @@ impl SpannerDbPool {
             .database_pool_connection_timeout
             .map(|seconds| Duration::from_secs(seconds as u64));
         let manager = SpannerSessionManager::new(settings, metrics, blocking_threadpool)?;
+        let recent_writes = RecentWrites::new(
+            settings
+                .database_spanner_read_staleness
+                .map(|seconds| Duration::from_secs(seconds.into())),
+        );
         let timeouts = deadpool::managed::Timeouts {
             wait,
             ..Default::default()
@@ -56,13 +78,14 @@ impl SpannerDbPool {
 
         Ok(Self {
             pool,
-            coll_cache: Default::default(),
+            coll_cache: Arc::new(CollectionCache::new(
+                settings
+                    .collection_cache_max_size
+                    .map(|max_size| max_size as usize),
+            )),
+            recent_writes: Arc::new(recent_writes),
             metrics: metrics.clone(),
-            quota: Quota {
-                size: settings.limits.max_quota_limit as usize,
-                enabled: settings.enable_quota,
-                enforced: settings.enforce_quota,
-            },
+            quota: Quota::from(settings),
         })
     }
 
@@ -76,8 +99,9 @@ impl SpannerDbPool {
         Ok(SpannerDb::new(
             conn,
             Arc::clone(&self.coll_cache),
+            Arc::clone(&self.recent_writes),
             &self.metrics,
-            self.quota,
+            self.quota.clone(),
         ))
     }
 }
@@ -100,6 +124,16 @@ impl DbPool for SpannerDbPool {
         super::batch::validate_batch_id(&id).map_err(Into::into)
     }
 
+    fn collection_cache_stats(&self) -> results::CollectionCacheStats {
+        self.coll_cache.stats()
+    }
+
+    /// Spanner prepares statements server side: only the collections' names
+    /// are cached
+    async fn warm_up(&self) -> DbResult<usize> {
+        self.get_async().await?.cache_custom_collections().await
+    }
+
     fn box_clone(&self) -> Box<dyn DbPool<Error = Self::Error>> {
         Box::new(self.clone())
     }
@@ -123,22 +157,62 @@ impl fmt::Debug for SpannerDbPool {
 pub(super) struct CollectionCache {
     pub by_name: RwLock<HashMap<String, i32>>,
     pub by_id: RwLock<HashMap<i32, String>>,
+    /// Max number of entries held, unbounded when `None`
+    max_size: Option<usize>,
+    entries: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    inserts: AtomicU64,
+    evictions: AtomicU64,
 }
 
 impl CollectionCache {
+    pub fn new(max_size: Option<usize>) -> Self {
+        Self {
+            max_size,
+            ..Default::default()
+        }
+    }
+
     pub async fn put(&self, id: i32, name: String) {
-        // XXX: should this emit a metric?
-        // XXX: one RwLock might be sufficient?
-        self.by_name.write().await.insert(name.clone(), id);
-        self.by_id.write().await.insert(id, name);
+        let mut by_name = self.by_name.write().await;
+        let mut by_id = self.by_id.write().await;
+        if by_name.contains_key(&name) {
+            return;
+        }
+        if self
+            .max_size
+            .map_or(false, |max_size| by_id.len() >= max_size)
+        {
+            // Standard collections are never evicted
+            let evicted = by_id
+                .keys()
+                .find(|&&id| id >= FIRST_CUSTOM_COLLECTION_ID)
+                .copied();
+            match evicted.and_then(|evicted| by_id.remove(&evicted)) {
+                Some(evicted_name) => {
+                    by_name.remove(&evicted_name);
+                    self.evictions.fetch_add(1, Ordering::Relaxed);
+                }
+                None => return,
+            }
+        }
+        by_name.insert(name.clone(), id);
+        by_id.insert(id, name);
+        self.inserts.fetch_add(1, Ordering::Relaxed);
+        self.entries.store(by_id.len() as u64, Ordering::Relaxed);
     }
 
     pub async fn get_id(&self, name: &str) -> Option<i32> {
-        self.by_name.read().await.get(name).cloned()
+        let id = self.by_name.read().await.get(name).cloned();
+        self.record_lookups(id.is_some() as u64, id.is_none() as u64);
+        id
     }
 
     pub async fn get_name(&self, id: i32) -> Option<String> {
-        self.by_id.read().await.get(&id).cloned()
+        let name = self.by_id.read().await.get(&id).cloned();
+        self.record_lookups(name.is_some() as u64, name.is_none() as u64);
+        name
     }
 
     /// Get multiple names, returning a tuple of both the mapping of
@@ -157,12 +231,30 @@ impl CollectionCache {
                 missing.push(id)
             }
         }
+        self.record_lookups(names.len() as u64, missing.len() as u64);
         (names, missing)
     }
 
     pub async fn clear(&self) {
         self.by_name.write().await.clear();
         self.by_id.write().await.clear();
+        self.entries.store(0, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> results::CollectionCacheStats {
+        results::CollectionCacheStats {
+            entries: self.entries.load(Ordering::Relaxed),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            inserts: self.inserts.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            ..Default::default()
+        }
+    }
+
+    fn record_lookups(&self, hits: u64, misses: u64) {
+        self.hits.fetch_add(hits, Ordering::Relaxed);
+        self.misses.fetch_add(misses, Ordering::Relaxed);
     }
 }
 
@@ -181,6 +273,96 @@ impl Default for CollectionCache {
                     .map(|(k, v)| (*k, (*v).to_owned()))
                     .collect(),
             ),
+            max_size: None,
+            entries: AtomicU64::new(STD_COLLS.len() as u64),
+            hits: AtomicU64::default(),
+            misses: AtomicU64::default(),
+            inserts: AtomicU64::default(),
+            evictions: AtomicU64::default(),
         }
     }
 }
+
+/// Number of tracked users past which users whose writes have become visible
+/// to stale reads are pruned
+const RECENT_WRITES_PRUNE_THRESHOLD: usize = 10_000;
+
+/// Tracks when users last committed writes, giving them read-your-writes
+/// consistency when stale reads are enabled: a user's reads are strong until
+/// their latest write is older than the read staleness.
+///
+/// This is per process, so it relies on a user's requests reaching the same
+/// instance shortly after their write.
+#[derive(Debug)]
+pub(super) struct RecentWrites {
+    /// How long after a write its user's reads must stay strong. `None` when
+    /// stale reads are disabled
+    window: Option<Duration>,
+    by_user: Mutex<HashMap<UserIdentifier, Instant>>,
+}
+
+impl RecentWrites {
+    pub fn new(read_staleness: Option<Duration>) -> Self {
+        Self {
+            // Allow some slack for the skew between this clock and Spanner's
+            window: read_staleness.map(|staleness| staleness + Duration::from_secs(1)),
+            by_user: Mutex::default(),
+        }
+    }
+
+    pub fn record(&self, user_id: &UserIdentifier) {
+        if let Some(window) = self.window {
+            let now = Instant::now();
+            let mut by_user = self.by_user.lock().unwrap_or_else(|e| e.into_inner());
+            if by_user.len() >= RECENT_WRITES_PRUNE_THRESHOLD {
+                by_user.retain(|_, written| now.duration_since(*written) < window);
+            }
+            by_user.insert(user_id.clone(), now);
+        }
+    }
+
+    /// Whether the user's reads may be stale: when stale reads are enabled
+    /// and the user hasn't written within the window
+    pub fn allows_stale_read(&self, user_id: &UserIdentifier) -> bool {
+        match self.window {
+            Some(window) => self
+                .by_user
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .get(user_id)
+                .map_or(true, |written| written.elapsed() >= window),
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recent_writes() {
+        let user_id = UserIdentifier {
+            legacy_id: 1,
+            fxa_uid: "fxa_uid".to_owned(),
+            fxa_kid: "fxa_kid".to_owned(),
+        };
+
+        let disabled = RecentWrites::new(None);
+        assert!(!disabled.allows_stale_read(&user_id));
+        disabled.record(&user_id);
+        assert!(!disabled.allows_stale_read(&user_id));
+
+        let recent_writes = RecentWrites::new(Some(Duration::from_secs(10)));
+        assert!(recent_writes.allows_stale_read(&user_id));
+        recent_writes.record(&user_id);
+        assert!(!recent_writes.allows_stale_read(&user_id));
+
+        let other_user = UserIdentifier {
+            legacy_id: 2,
+            fxa_uid: "other_fxa_uid".to_owned(),
+            ..user_id
+        };
+        assert!(recent_writes.allows_stale_read(&other_user));
+    }
+}
@@ -20,6 +20,27 @@ use syncstorage_db_common::{
 
 use crate::{error::DbError, pool::Conn, DbResult};
 
+/// Classes of operations, each given its own Spanner RPC priority and request
+/// tag so batch work doesn't compete with user facing latency
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum OperationClass {
+    /// Regular user facing reads and writes
+    #[default]
+    Interactive,
+    /// Batch upload appends and commits
+    Batch,
+}
+
+impl OperationClass {
+    /// The request tag reported in Spanner's query statistics
+    pub fn tag(self) -> &'static str {
+        match self {
+            OperationClass::Interactive => "interactive",
+            OperationClass::Batch => "batch",
+        }
+    }
+}
+
 pub trait IntoSpannerValue {
     const TYPE_CODE: TypeCode;
 
@@ -58,6 +79,14 @@ impl IntoSpannerValue for u32 {
     }
 }
 
+impl IntoSpannerValue for i64 {
+    const TYPE_CODE: TypeCode = TypeCode::INT64;
+
+    fn into_spanner_value(self) -> Value {
+        self.to_string().into_spanner_value()
+    }
+}
+
 impl<T> IntoSpannerValue for Vec<T>
 where
     T: IntoSpannerValue,
@@ -0,0 +1,48 @@
+use base64::Engine;
+
+use crate::{DbError, DbResult};
+
+/// The ttl of the bsos committed from batches, in seconds
+pub const MAXTTL: i32 = 2_100_000_000;
+
+pub fn validate_batch_id(id: &str) -> DbResult<()> {
+    decode_id(id).map(|_| ())
+}
+
+pub fn encode_id(id: i64) -> String {
+    base64::engine::general_purpose::STANDARD.encode(id.to_string())
+}
+
+pub fn decode_id(id: &str) -> DbResult<i64> {
+    let bytes = base64::engine::general_purpose::STANDARD
+        .decode(id)
+        .unwrap_or_else(|_| id.as_bytes().to_vec());
+    let decoded = std::str::from_utf8(&bytes).unwrap_or(id);
+    decoded
+        .parse::<i64>()
+        .map_err(|e| DbError::internal(format!("Invalid batch_id: {}", e)))
+}
+
+/// Delegates a backend's synchronous batch method to its `batch` module
+#[macro_export]
+macro_rules! batch_db_method {
+    ($name:ident, $batch_name:ident, $type:ident) => {
+        pub fn $name(&self, params: params::$type) -> DbResult<results::$type> {
+            batch::$batch_name(self, params)
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn batch_id_round_trip() {
+        let id = 1_700_000_000_123;
+        assert_eq!(decode_id(&encode_id(id)).unwrap(), id);
+        // Unencoded ids are accepted too
+        assert_eq!(decode_id(&id.to_string()).unwrap(), id);
+        assert!(validate_batch_id("not a batch").is_err());
+    }
+}
@@ -0,0 +1,225 @@
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use diesel::{
+    r2d2::{ManageConnection, Pool},
+    result::QueryResult,
+    Connection,
+};
+use syncserver_common::{Cache, CacheBackend};
+use syncserver_db_common::singleflight::SingleFlight;
+use syncstorage_db_common::{results, FIRST_CUSTOM_COLLECTION_ID, STD_COLLS};
+use syncstorage_settings::Settings;
+
+use crate::{check_collection_name, DbError, DbResult};
+
+/// Size of the pool collections are created on, apart from the callers'
+/// connections
+const CREATOR_POOL_SIZE: u32 = 2;
+
+/// Inserts a collection named by its second argument unless it exists,
+/// returning its id: the only part of creating one in the backend's dialect
+pub type InsertCollection<C> = fn(&C, &str) -> QueryResult<i32>;
+
+/// Creates collections on a small pool of its own, each in a transaction of
+/// its own committed at once. Its connections are never wrapped in test
+/// transactions: the collections it creates are always committed
+pub struct CollectionCreator<M: ManageConnection> {
+    pool: Pool<M>,
+    insert: InsertCollection<M::Connection>,
+}
+
+impl<M> CollectionCreator<M>
+where
+    M: ManageConnection,
+    M::Connection: Connection,
+{
+    pub fn new(manager: M, settings: &Settings, insert: InsertCollection<M::Connection>) -> Self {
+        let pool = Pool::builder()
+            .max_size(CREATOR_POOL_SIZE)
+            .test_on_check_out(settings.database_pool_test_on_checkout)
+            .connection_timeout(Duration::from_secs(
+                settings.database_pool_connection_timeout.unwrap_or(30) as u64,
+            ))
+            .min_idle(Some(0))
+            .build_unchecked(manager);
+        Self { pool, insert }
+    }
+
+    fn create(&self, name: &str) -> DbResult<i32> {
+        let conn = self.pool.get()?;
+        Ok(conn.transaction(|| (self.insert)(&conn, name))?)
+    }
+}
+
+impl<M: ManageConnection> fmt::Debug for CollectionCreator<M> {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("CollectionCreator")
+            .field("state", &self.pool.state())
+            .finish()
+    }
+}
+
+/// Cache of collection ids and their names. The standard collections are
+/// held statically, custom ones in the configured `Cache` (keyed both by
+/// name and by id)
+#[derive(Debug)]
+pub struct CollectionCache<M: ManageConnection> {
+    cache: Arc<dyn Cache>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    inserts: AtomicU64,
+    creations: AtomicU64,
+    /// Creates collections outside of the callers' transactions, on the
+    /// backends that can (see `get_or_create_id`)
+    creator: Option<CollectionCreator<M>>,
+    creating: SingleFlight,
+}
+
+impl<M> CollectionCache<M>
+where
+    M: ManageConnection,
+    M::Connection: Connection,
+{
+    /// `max_size` counts the standard collections
+    pub fn new(
+        backend: &CacheBackend,
+        max_size: Option<usize>,
+        creator: Option<CollectionCreator<M>>,
+    ) -> Self {
+        // Each custom collection takes two entries
+        let max_size = max_size.map(|max_size| max_size.saturating_sub(STD_COLLS.len()) * 2);
+        Self {
+            cache: backend.cache("collections", max_size),
+            hits: AtomicU64::default(),
+            misses: AtomicU64::default(),
+            inserts: AtomicU64::default(),
+            creations: AtomicU64::default(),
+            creator,
+            creating: SingleFlight::default(),
+        }
+    }
+
+    pub fn put(&self, id: i32, name: String) -> DbResult<()> {
+        if id < FIRST_CUSTOM_COLLECTION_ID {
+            return Ok(());
+        }
+        let name_key = format!("name:{}", name);
+        if self.cache.get(&name_key).is_some() {
+            return Ok(());
+        }
+        self.cache.set_value(&name_key, &id, None);
+        self.cache.set_value(&format!("id:{}", id), &name, None);
+        self.inserts.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn get_id(&self, name: &str) -> DbResult<Option<i32>> {
+        let id = STD_COLLS
+            .iter()
+            .find(|(_, std_name)| *std_name == name)
+            .map(|(id, _)| *id)
+            .or_else(|| self.cache.get_value(&format!("name:{}", name)));
+        self.record_lookups(id.is_some() as u64, id.is_none() as u64);
+        Ok(id)
+    }
+
+    pub fn get_name(&self, id: i32) -> DbResult<Option<String>> {
+        let name = STD_COLLS
+            .iter()
+            .find(|(std_id, _)| *std_id == id)
+            .map(|(_, name)| (*name).to_owned())
+            .or_else(|| self.cache.get_value(&format!("id:{}", id)));
+        self.record_lookups(name.is_some() as u64, name.is_none() as u64);
+        Ok(name)
+    }
+
+    /// Creates the custom collection `name` unless it's cached, one caller
+    /// per name at a time.
+    ///
+    /// It's inserted by the `creator`, committed at once, rather than within
+    /// the caller's transaction: usually a write lock's, whose reads aren't
+    /// cached. Its id is then cached right away, so the callers of a burst
+    /// of first writes to the collection, queued behind the first one, find
+    /// it there instead of inserting it again.
+    ///
+    /// Backends without a `creator` (SQLite, whose writers are serialized
+    /// anyway) create collections within the callers' transactions instead
+    pub fn get_or_create_id(&self, name: &str) -> DbResult<i32> {
+        if let Some(id) = self.get_id(name)? {
+            return Ok(id);
+        }
+        check_collection_name(name)?;
+
+        let creator = self.creator.as_ref().ok_or_else(|| {
+            DbError::internal("Collections are created within transactions".to_owned())
+        })?;
+        self.creating.run(name, || {
+            if let Some(id) = self.get_id(name)? {
+                return Ok(id);
+            }
+            self.creations.fetch_add(1, Ordering::Relaxed);
+            let id = creator.create(name)?;
+            self.put(id, name.to_owned())?;
+            Ok(id)
+        })
+    }
+
+    pub fn clear(&self) {
+        self.cache.clear();
+    }
+
+    /// Entries and evictions are only tracked by in process caches
+    pub fn stats(&self) -> results::CollectionCacheStats {
+        let cache_stats = self.cache.stats();
+        results::CollectionCacheStats {
+            entries: STD_COLLS.len() as u64 + cache_stats.entries / 2,
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            inserts: self.inserts.load(Ordering::Relaxed),
+            creations: self.creations.load(Ordering::Relaxed),
+            evictions: cache_stats.evictions / 2,
+        }
+    }
+
+    fn record_lookups(&self, hits: u64, misses: u64) {
+        self.hits.fetch_add(hits, Ordering::Relaxed);
+        self.misses.fetch_add(misses, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use syncserver_db_common::manager::MysqlConnectionManager;
+
+    use super::*;
+
+    #[test]
+    fn collection_cache_max_size() {
+        let cache = CollectionCache::<MysqlConnectionManager>::new(
+            &CacheBackend::Memory,
+            Some(STD_COLLS.len() + 1),
+            None,
+        );
+        assert_eq!(cache.get_id("clients").unwrap(), Some(1));
+        assert_eq!(cache.get_id("xxx_col1").unwrap(), None);
+
+        cache.put(101, "xxx_col1".to_owned()).unwrap();
+        cache.put(102, "xxx_col2".to_owned()).unwrap();
+        // The custom collection was evicted, the standard ones remain
+        assert_eq!(cache.get_id("xxx_col1").unwrap(), None);
+        assert_eq!(cache.get_name(102).unwrap(), Some("xxx_col2".to_owned()));
+        assert_eq!(cache.get_id("clients").unwrap(), Some(1));
+
+        let stats = cache.stats();
+        assert_eq!(stats.entries, STD_COLLS.len() as u64 + 1);
+        assert_eq!((stats.hits, stats.misses), (3, 2));
+        assert_eq!((stats.inserts, stats.evictions), (2, 1));
+    }
+}
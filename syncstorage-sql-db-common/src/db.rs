@@ -0,0 +1,266 @@
+/// Implements `Db` (and `DbRequestId`) for a diesel backend's `$db`, each
+/// call running the synchronous method of `$db` it delegates to on the
+/// blocking threadpool.
+///
+/// `$db` derefs to its connection (`conn`) and `session` (a
+/// `RefCell<DbSession>`) and has the fields `coll_cache`, `metrics`,
+/// `quota`, `blocking_threadpool` and `request_id`. Like `sync_db_method!`,
+/// this is expanded within the caller's scope of `params`, `results`,
+/// `DbFuture`, `DbError` and `sync_db_method`.
+#[macro_export]
+macro_rules! impl_sql_db {
+    ($db:ty) => {
+        impl ::syncserver_db_common::DbRequestId for $db {
+            fn request_id(&self) -> Option<&str> {
+                self.request_id.as_deref()
+            }
+        }
+
+        impl ::syncstorage_db_common::Db for $db {
+            type Error = DbError;
+
+            fn commit(&self) -> DbFuture<'_, (), Self::Error> {
+                let db = self.clone();
+                Box::pin(::syncserver_db_common::timed_request(
+                    &self.metrics,
+                    self.request_id.as_deref(),
+                    "commit",
+                    None,
+                    self.blocking_threadpool.spawn(move || db.commit_sync()),
+                ))
+            }
+
+            fn rollback(&self) -> DbFuture<'_, (), Self::Error> {
+                let db = self.clone();
+                Box::pin(::syncserver_db_common::timed_request(
+                    &self.metrics,
+                    self.request_id.as_deref(),
+                    "rollback",
+                    None,
+                    self.blocking_threadpool.spawn(move || db.rollback_sync()),
+                ))
+            }
+
+            fn begin(&self, for_write: bool) -> DbFuture<'_, (), Self::Error> {
+                let db = self.clone();
+                Box::pin(::syncserver_db_common::timed_request(
+                    &self.metrics,
+                    self.request_id.as_deref(),
+                    "begin",
+                    None,
+                    self.blocking_threadpool.spawn(move || db.begin(for_write)),
+                ))
+            }
+
+            fn check(&self) -> DbFuture<'_, results::Check, Self::Error> {
+                let db = self.clone();
+                Box::pin(self.blocking_threadpool.spawn(move || db.check_sync()))
+            }
+
+            fn get_database_stats(&self) -> DbFuture<'_, results::GetDatabaseStats, Self::Error> {
+                let db = self.clone();
+                Box::pin(
+                    self.blocking_threadpool
+                        .spawn(move || db.get_database_stats_sync()),
+                )
+            }
+
+            fn get_native_ttl(&self) -> DbFuture<'_, results::GetNativeTtl, Self::Error> {
+                // Expired rows are only ever purged by us
+                Box::pin(async { Ok(results::GetNativeTtl::default()) })
+            }
+
+            sync_db_method!(lock_for_read, lock_for_read_sync, LockCollection);
+            sync_db_method!(lock_for_write, lock_for_write_sync, LockCollection);
+            sync_db_method!(
+                get_collection_timestamps,
+                get_collection_timestamps_sync,
+                GetCollectionTimestamps
+            );
+            sync_db_method!(
+                get_collection_timestamp,
+                get_collection_timestamp_sync,
+                GetCollectionTimestamp
+            );
+            sync_db_method!(
+                get_collection_counts,
+                get_collection_counts_sync,
+                GetCollectionCounts
+            );
+            sync_db_method!(
+                get_collection_usage,
+                get_collection_usage_sync,
+                GetCollectionUsage
+            );
+            sync_db_method!(
+                get_storage_timestamp,
+                get_storage_timestamp_sync,
+                GetStorageTimestamp
+            );
+            sync_db_method!(get_storage_usage, get_storage_usage_sync, GetStorageUsage);
+            sync_db_method!(get_quota_usage, get_quota_usage_sync, GetQuotaUsage);
+            sync_db_method!(delete_storage, delete_storage_sync, DeleteStorage);
+            sync_db_method!(get_storage_epoch, get_storage_epoch_sync, GetStorageEpoch);
+            sync_db_method!(
+                bump_storage_epoch,
+                bump_storage_epoch_sync,
+                BumpStorageEpoch
+            );
+            sync_db_method!(
+                get_keys_changed_at,
+                get_keys_changed_at_sync,
+                GetKeysChangedAt
+            );
+            sync_db_method!(
+                record_keys_changed_at,
+                record_keys_changed_at_sync,
+                RecordKeysChangedAt
+            );
+            sync_db_method!(delete_collection, delete_collection_sync, DeleteCollection);
+            sync_db_method!(
+                delete_collections,
+                delete_collections_sync,
+                DeleteCollections
+            );
+            sync_db_method!(
+                delete_collections_except,
+                delete_collections_except_sync,
+                DeleteCollectionsExcept
+            );
+            sync_db_method!(delete_bsos, delete_bsos_sync, DeleteBsos);
+            sync_db_method!(get_bsos, get_bsos_sync, GetBsos);
+            sync_db_method!(get_bso_ids, get_bso_ids_sync, GetBsoIds);
+            sync_db_method!(get_bsos_count, get_bsos_count_sync, GetBsosCount);
+            sync_db_method!(post_bsos, post_bsos_sync, PostBsos);
+            sync_db_method!(delete_bso, delete_bso_sync, DeleteBso);
+            sync_db_method!(@bulk purge_expired_bso, purge_expired_bso_sync, PurgeExpiredBso);
+            sync_db_method!(get_bso, get_bso_sync, GetBso, Option<results::GetBso>);
+            sync_db_method!(
+                get_bso_timestamp,
+                get_bso_timestamp_sync,
+                GetBsoTimestamp,
+                results::GetBsoTimestamp
+            );
+            sync_db_method!(put_bso, put_bso_sync, PutBso);
+            sync_db_method!(create_batch, create_batch_sync, CreateBatch);
+            sync_db_method!(validate_batch, validate_batch_sync, ValidateBatch);
+            sync_db_method!(@bulk append_to_batch, append_to_batch_sync, AppendToBatch);
+            sync_db_method!(
+                get_batch,
+                get_batch_sync,
+                GetBatch,
+                Option<results::GetBatch>
+            );
+            sync_db_method!(@bulk commit_batch, commit_batch_sync, CommitBatch);
+            sync_db_method!(@bulk purge_expired, purge_expired_sync, PurgeExpired);
+
+            fn get_collection_id(&self, name: String) -> DbFuture<'_, i32, Self::Error> {
+                let db = self.clone();
+                Box::pin(::syncserver_db_common::timed_request(
+                    &self.metrics,
+                    self.request_id.as_deref(),
+                    "get_collection_id",
+                    Some(::syncstorage_db_common::collection_tag(&name)),
+                    self.blocking_threadpool
+                        .spawn(move || db.get_collection_id(&name)),
+                ))
+            }
+
+            fn get_connection_info(&self) -> results::ConnectionInfo {
+                results::ConnectionInfo::default()
+            }
+
+            fn query_count(&self) -> u64 {
+                self.conn.query_count()
+            }
+
+            fn written_quota_usage(
+                &self,
+                user_id: &::syncstorage_db_common::UserIdentifier,
+                collection: &str,
+            ) -> Option<results::GetQuotaUsage> {
+                let collection_id = self.coll_cache.get_id(collection).ok()??;
+                self.session
+                    .borrow()
+                    .written_usage
+                    .get(&(user_id.legacy_id, collection_id))
+                    .cloned()
+            }
+
+            fn locked_user(
+                &self,
+                user_id: &::syncstorage_db_common::UserIdentifier,
+            ) -> Option<results::LockedUser> {
+                self.session
+                    .borrow()
+                    .locked_users
+                    .get(&user_id.legacy_id)
+                    .cloned()
+            }
+
+            fn create_collection(&self, name: String) -> DbFuture<'_, i32, Self::Error> {
+                let db = self.clone();
+                Box::pin(::syncserver_db_common::timed_request(
+                    &self.metrics,
+                    self.request_id.as_deref(),
+                    "create_collection",
+                    Some(::syncstorage_db_common::collection_tag(&name)),
+                    self.blocking_threadpool
+                        .spawn(move || db.get_or_create_collection_id(&name)),
+                ))
+            }
+
+            fn update_collection(
+                &self,
+                param: params::UpdateCollection,
+            ) -> DbFuture<'_, ::syncstorage_db_common::util::SyncTimestamp, Self::Error> {
+                let db = self.clone();
+                Box::pin(::syncserver_db_common::timed_request(
+                    &self.metrics,
+                    self.request_id.as_deref(),
+                    "update_collection",
+                    ::syncserver_db_common::DbCallParams::collection_tag(&param),
+                    self.blocking_threadpool.spawn(move || {
+                        db.update_collection(param.user_id.legacy_id, param.collection_id)
+                    }),
+                ))
+            }
+
+            fn timestamp(&self) -> ::syncstorage_db_common::util::SyncTimestamp {
+                self.timestamp()
+            }
+
+            fn set_timestamp(&self, timestamp: ::syncstorage_db_common::util::SyncTimestamp) {
+                self.session.borrow_mut().timestamp = timestamp;
+            }
+
+            sync_db_method!(delete_batch, delete_batch_sync, DeleteBatch);
+
+            fn clear_coll_cache(&self) -> DbFuture<'_, (), Self::Error> {
+                let db = self.clone();
+                Box::pin(self.blocking_threadpool.spawn(move || {
+                    db.coll_cache.clear();
+                    Ok(())
+                }))
+            }
+
+            fn set_quota(&mut self, enabled: bool, limit: usize, enforced: bool) {
+                self.quota = ::syncstorage_settings::Quota {
+                    size: limit,
+                    enabled,
+                    enforced,
+                    overrides: self.quota.overrides.clone(),
+                    usage_counters: self.quota.usage_counters,
+                }
+            }
+
+            fn set_request_id(&mut self, request_id: String) {
+                self.request_id = Some(request_id);
+            }
+
+            fn box_clone(&self) -> Box<dyn ::syncstorage_db_common::Db<Error = Self::Error>> {
+                Box::new(self.clone())
+            }
+        }
+    };
+}
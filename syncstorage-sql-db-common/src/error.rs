@@ -7,9 +7,22 @@ use syncserver_db_common::error::MysqlError;
 use syncstorage_db_common::error::{DbErrorIntrospect, SyncstorageDbError};
 use thiserror::Error;
 
-/// An error type that represents any MySQL-related errors that may occur while processing a
-/// syncstorage request. These errors may be application-specific or lower-level errors that arise
-/// from the database backend.
+/// The messages of the errors raised by statements that gave up waiting on
+/// another transaction's locks, which diesel only exposes through them
+const LOCK_WAIT_TIMEOUT_MESSAGES: &[&str] = &[
+    // MySQL's `ER_LOCK_WAIT_TIMEOUT` (1205)
+    "Lock wait timeout exceeded",
+    // PostgreSQL's `lock_not_available` (55P03), raised by `lock_timeout`
+    "canceling statement due to lock timeout",
+    // The message of `SQLITE_BUSY`: the database's write lock is still held
+    // by another connection once its `busy_timeout` expires
+    "database is locked",
+];
+
+/// An error type that represents any errors that may occur while processing a
+/// syncstorage request with one of the diesel backends. These errors may be
+/// application-specific or lower-level errors that arise from the database
+/// backend.
 #[derive(Debug)]
 pub struct DbError {
     kind: DbErrorKind,
@@ -34,6 +47,10 @@ impl DbError {
         DbErrorKind::Common(SyncstorageDbError::conflict()).into()
     }
 
+    pub fn lock_timeout() -> Self {
+        DbErrorKind::Common(SyncstorageDbError::lock_timeout()).into()
+    }
+
     pub fn internal(msg: String) -> Self {
         DbErrorKind::Common(SyncstorageDbError::internal(msg)).into()
     }
@@ -41,6 +58,14 @@ impl DbError {
     pub fn quota() -> Self {
         DbErrorKind::Common(SyncstorageDbError::quota()).into()
     }
+
+    pub fn invalid_value(msg: String) -> Self {
+        DbErrorKind::Common(SyncstorageDbError::invalid_value(msg)).into()
+    }
+
+    pub fn too_large(msg: String) -> Self {
+        DbErrorKind::Common(SyncstorageDbError::too_large(msg)).into()
+    }
 }
 
 #[derive(Debug, Error)]
@@ -49,7 +74,7 @@ enum DbErrorKind {
     Common(SyncstorageDbError),
 
     #[error("{}", _0)]
-    Mysql(MysqlError),
+    Diesel(MysqlError),
 }
 
 impl From<DbErrorKind> for DbError {
@@ -86,9 +111,21 @@ impl DbErrorIntrospect for DbError {
         matches!(&self.kind, DbErrorKind::Common(e) if e.is_conflict())
     }
 
+    fn is_lock_timeout(&self) -> bool {
+        matches!(&self.kind, DbErrorKind::Common(e) if e.is_lock_timeout())
+    }
+
     fn is_quota(&self) -> bool {
         matches!(&self.kind, DbErrorKind::Common(e) if e.is_quota())
     }
+
+    fn is_invalid_value(&self) -> bool {
+        matches!(&self.kind, DbErrorKind::Common(e) if e.is_invalid_value())
+    }
+
+    fn is_too_large(&self) -> bool {
+        matches!(&self.kind, DbErrorKind::Common(e) if e.is_too_large())
+    }
 }
 
 impl ReportableError for DbError {
@@ -118,27 +155,37 @@ impl InternalError for DbError {
 impl_fmt_display!(DbError, DbErrorKind);
 
 from_error!(SyncstorageDbError, DbError, DbErrorKind::Common);
+from_error!(MysqlError, DbError, DbErrorKind::Diesel);
 from_error!(
     diesel::result::Error,
     DbError,
-    |error: diesel::result::Error| DbError::from(DbErrorKind::Mysql(MysqlError::from(error)))
+    |error: diesel::result::Error| match &error {
+        diesel::result::Error::DatabaseError(_, info)
+            if LOCK_WAIT_TIMEOUT_MESSAGES
+                .iter()
+                .any(|message| info.message().starts_with(message)) =>
+        {
+            DbError::lock_timeout()
+        }
+        _ => DbError::from(DbErrorKind::Diesel(MysqlError::from(error))),
+    }
 );
 from_error!(
     diesel::result::ConnectionError,
     DbError,
-    |error: diesel::result::ConnectionError| DbError::from(DbErrorKind::Mysql(MysqlError::from(
+    |error: diesel::result::ConnectionError| DbError::from(DbErrorKind::Diesel(MysqlError::from(
         error
     )))
 );
 from_error!(
     diesel::r2d2::PoolError,
     DbError,
-    |error: diesel::r2d2::PoolError| DbError::from(DbErrorKind::Mysql(MysqlError::from(error)))
+    |error: diesel::r2d2::PoolError| DbError::from(DbErrorKind::Diesel(MysqlError::from(error)))
 );
 from_error!(
     diesel_migrations::RunMigrationsError,
     DbError,
-    |error: diesel_migrations::RunMigrationsError| DbError::from(DbErrorKind::Mysql(
+    |error: diesel_migrations::RunMigrationsError| DbError::from(DbErrorKind::Diesel(
         MysqlError::from(error)
     ))
 );
@@ -0,0 +1,231 @@
+//! The parts of the diesel backends (MySQL, PostgreSQL and SQLite) that don't
+//! depend on their SQL dialect: error mapping, the collection cache, session
+//! and lock bookkeeping, quota and pagination helpers, batch ids and the
+//! `Db` impl delegating to their synchronous methods (see `impl_sql_db!`)
+#[macro_use]
+extern crate slog_scope;
+
+pub mod batch;
+mod collection_cache;
+mod db;
+mod error;
+mod session;
+
+use std::{collections::HashMap, time::Duration};
+
+use diesel::{
+    r2d2::{Builder, ManageConnection},
+    Connection,
+};
+use syncserver_common::Metrics;
+#[cfg(debug_assertions)]
+use syncserver_db_common::test::TestTransactionCustomizer;
+use syncserver_db_common::CheckoutStats;
+use syncstorage_db_common::{params, results, Sorting, UserIdentifier};
+use syncstorage_settings::{Quota, Settings, DEFAULT_MAX_TOTAL_RECORDS};
+
+pub use collection_cache::{CollectionCache, CollectionCreator, InsertCollection};
+pub use error::DbError;
+pub use session::{CollectionLock, DbSession};
+
+pub type DbResult<T> = Result<T, DbError>;
+
+/// Sizes of the columns values are written to (see the backends'
+/// migrations). Values exceeding them are rejected before they're bound,
+/// rather than failing in the database as 500s
+pub const MAX_BSO_ID_LENGTH: usize = 64;
+pub const MAX_COLLECTION_NAME_LENGTH: usize = 32;
+
+/// Columns of a query of `user_collections` reading its user's storage epoch
+/// and keys, so a write lock reads them along with the collection's timestamp
+/// (see `DbSession::lock_user`)
+pub const USER_EPOCH_COLUMN: &str = "(SELECT epoch
+     FROM user_epochs
+    WHERE user_epochs.userid = user_collections.userid)";
+pub const USER_KEYS_CHANGED_AT_COLUMN: &str = "(SELECT keys_changed_at
+     FROM user_keys
+    WHERE user_keys.userid = user_collections.userid)";
+
+/// Reject a collection name its column can't hold with a 400
+pub fn check_collection_name(name: &str) -> DbResult<()> {
+    if name.chars().count() > MAX_COLLECTION_NAME_LENGTH {
+        return Err(DbError::invalid_value(format!(
+            "Collection name longer than {} characters",
+            MAX_COLLECTION_NAME_LENGTH
+        )));
+    }
+    Ok(())
+}
+
+/// Reject a BSO id its column can't hold with a 400
+pub fn check_bso_id(id: &str) -> DbResult<()> {
+    if id.chars().count() > MAX_BSO_ID_LENGTH {
+        return Err(DbError::invalid_value(format!(
+            "BSO id longer than {} characters",
+            MAX_BSO_ID_LENGTH
+        )));
+    }
+    Ok(())
+}
+
+/// Check the collection's `usage` (only read when the user has a quota)
+/// against the user's quota, failing the write once it's reached when the
+/// quota's enforced
+pub fn check_quota(
+    quota: &Quota,
+    metrics: &Metrics,
+    user_id: &UserIdentifier,
+    collection: &str,
+    usage: impl FnOnce() -> DbResult<results::GetQuotaUsage>,
+) -> DbResult<()> {
+    let Some(limit) = quota.limit_for(user_id.legacy_id, &user_id.fxa_uid) else {
+        return Ok(());
+    };
+    let usage = usage()?;
+    if usage.total_bytes >= limit {
+        let mut tags = HashMap::default();
+        tags.insert("collection".to_owned(), collection.to_owned());
+        metrics.incr_with_tags("storage.quota.at_limit", tags);
+        if quota.enforced {
+            return Err(DbError::quota());
+        } else {
+            warn!("Quota at limit for user's collection ({} bytes)", usage.total_bytes; "collection"=>collection.to_owned());
+        }
+    }
+    Ok(())
+}
+
+/// Key the values of `by_id` by the names of their collections, as loaded
+/// into `names`
+pub fn map_collection_names<T>(
+    by_id: HashMap<i32, T>,
+    mut names: HashMap<i32, String>,
+) -> DbResult<HashMap<String, T>> {
+    by_id
+        .into_iter()
+        .map(|(id, value)| {
+            names.remove(&id).map(|name| (name, value)).ok_or_else(|| {
+                DbError::internal("load_collection_names unknown collection id".to_owned())
+            })
+        })
+        .collect()
+}
+
+/// A page of up to `limit` BSOs (`DEFAULT_MAX_TOTAL_RECORDS` unless
+/// requested otherwise)
+#[derive(Clone, Copy, Debug)]
+pub struct Page {
+    limit: i64,
+}
+
+impl Page {
+    pub fn new(limit: Option<u32>) -> Self {
+        Self {
+            limit: limit.map_or(DEFAULT_MAX_TOTAL_RECORDS, |limit| limit) as i64,
+        }
+    }
+
+    /// Whether an explicit "limit=0" was requested
+    pub fn is_empty(&self) -> bool {
+        self.limit == 0
+    }
+
+    /// The number of rows to fetch: an extra one, to detect if there are
+    /// more rows that match the query conditions
+    pub fn fetch_limit(&self) -> i64 {
+        if self.limit > 0 {
+            self.limit + 1
+        } else {
+            0
+        }
+    }
+
+    /// Drop the extra row fetched past the page, returning the offset of the
+    /// next page when there was one. `position` locates a row in results
+    /// sorted by `sort`, which later pages seek past
+    pub fn next_offset<T>(
+        &self,
+        rows: &mut Vec<T>,
+        offset: &params::Offset,
+        sort: Sorting,
+        position: impl Fn(&T) -> params::BsoPosition,
+    ) -> Option<String> {
+        if rows.len() as i64 <= self.limit {
+            return None;
+        }
+        rows.pop();
+        let last = rows.last().map(position);
+        Some(offset.next(rows.len(), sort, last).to_string())
+    }
+}
+
+/// A builder of a backend's pool of `max_size` connections, counting its
+/// checkouts in `checkout_stats`. Debug builds wrap its connections in test
+/// transactions when `database_use_test_transactions` is set
+pub fn pool_builder<M>(settings: &Settings, checkout_stats: &CheckoutStats) -> Builder<M>
+where
+    M: ManageConnection<Error = diesel::r2d2::Error>,
+    M::Connection: Connection,
+{
+    let builder = Builder::new()
+        .max_size(settings.database_pool_max_size)
+        .event_handler(Box::new(checkout_stats.clone()))
+        .test_on_check_out(settings.database_pool_test_on_checkout)
+        .connection_timeout(Duration::from_secs(
+            settings.database_pool_connection_timeout.unwrap_or(30) as u64,
+        ))
+        .min_idle(settings.database_pool_min_idle);
+
+    #[cfg(debug_assertions)]
+    let builder = if settings.database_use_test_transactions {
+        builder.connection_customizer(Box::new(TestTransactionCustomizer))
+    } else {
+        builder
+    };
+    builder
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rows(ids: &[&str]) -> Vec<params::BsoPosition> {
+        ids.iter()
+            .map(|id| params::BsoPosition {
+                modified: Default::default(),
+                id: (*id).to_owned(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn page_next_offset() {
+        let page = Page::new(Some(2));
+        assert_eq!(page.fetch_limit(), 3);
+        let offset = params::Offset::default();
+
+        let mut last_page = rows(&["a", "b"]);
+        assert_eq!(
+            page.next_offset(&mut last_page, &offset, Sorting::Index, Clone::clone),
+            None
+        );
+        assert_eq!(last_page.len(), 2);
+
+        let mut more = rows(&["a", "b", "c"]);
+        assert_eq!(
+            page.next_offset(&mut more, &offset, Sorting::Index, Clone::clone),
+            Some("2".to_owned())
+        );
+        assert_eq!(more, rows(&["a", "b"]));
+
+        let empty = Page::new(Some(0));
+        assert!(empty.is_empty());
+        assert_eq!(empty.fetch_limit(), 0);
+    }
+
+    #[test]
+    fn collection_name_length() {
+        assert!(check_collection_name(&"x".repeat(MAX_COLLECTION_NAME_LENGTH)).is_ok());
+        assert!(check_collection_name(&"x".repeat(MAX_COLLECTION_NAME_LENGTH + 1)).is_err());
+    }
+}
@@ -0,0 +1,166 @@
+use std::collections::{HashMap, HashSet};
+
+use syncstorage_db_common::{results, util::SyncTimestamp};
+
+use crate::{DbError, DbResult};
+
+#[derive(Debug)]
+pub enum CollectionLock {
+    Read,
+    Write,
+}
+
+/// Per session Db metadata
+#[derive(Debug, Default)]
+pub struct DbSession {
+    /// The "current time" on the server used for this session's operations
+    pub timestamp: SyncTimestamp,
+    /// Cache of collection modified timestamps per (user_id, collection_id)
+    pub coll_modified_cache: HashMap<(u64, i32), SyncTimestamp>,
+    /// Currently locked collections
+    pub coll_locks: HashMap<(u64, i32), CollectionLock>,
+    /// Whether a transaction was started (begin() called)
+    pub in_transaction: bool,
+    pub in_write_transaction: bool,
+    /// Collection usage per (user_id, collection_id), as computed by this
+    /// session's writes (see `Db::written_quota_usage`)
+    pub written_usage: HashMap<(u64, i32), results::GetQuotaUsage>,
+    /// Collection timestamps written per user_id, applied to the pool's
+    /// timestamp cache once committed (by the backends that have one)
+    pub written_timestamps: HashMap<u64, HashMap<i32, SyncTimestamp>>,
+    /// Users whose collections were deleted, dropped from the pool's
+    /// timestamp cache once committed (by the backends that have one)
+    pub invalidated_users: HashSet<u64>,
+    /// Users' storage epochs and keys, as read by their write locks (see
+    /// `Db::locked_user`)
+    pub locked_users: HashMap<u64, results::LockedUser>,
+}
+
+impl DbSession {
+    pub fn begin(&mut self, for_write: bool) {
+        self.in_transaction = true;
+        if for_write {
+            self.in_write_transaction = true;
+        }
+    }
+
+    /// Whether a read or write lock is held on the collection, which is then
+    /// safe to use as-is
+    pub fn is_locked(&self, user_id: u64, collection_id: i32) -> bool {
+        self.coll_locks.contains_key(&(user_id, collection_id))
+    }
+
+    /// Fails when a read lock is held on the collection: it can't be
+    /// escalated to a write lock
+    pub fn check_write_lockable(&self, user_id: u64, collection_id: i32) -> DbResult<()> {
+        if let Some(CollectionLock::Read) = self.coll_locks.get(&(user_id, collection_id)) {
+            return Err(DbError::internal(
+                "Can't escalate read-lock to write-lock".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Records the lock taken on the collection, along with its timestamp as
+    /// read under the lock (unless it's yet to be written).
+    ///
+    /// A write lock is refused with a conflict when the collection was
+    /// modified at or past this session's timestamp, as the write would not
+    /// properly increment it
+    pub fn lock(
+        &mut self,
+        user_id: u64,
+        collection_id: i32,
+        lock: CollectionLock,
+        modified: Option<SyncTimestamp>,
+    ) -> DbResult<()> {
+        if let Some(modified) = modified {
+            if matches!(lock, CollectionLock::Write) && modified >= self.timestamp {
+                return Err(DbError::conflict());
+            }
+            self.coll_modified_cache
+                .insert((user_id, collection_id), modified);
+        }
+        // XXX: who's responsible for unlocking (removing the entry)
+        self.coll_locks.insert((user_id, collection_id), lock);
+        Ok(())
+    }
+
+    /// Records the user's storage epoch and keys, as read along with a write
+    /// lock on one of their collections (see `USER_EPOCH_COLUMN`)
+    pub fn lock_user(
+        &mut self,
+        user_id: u64,
+        storage_epoch: Option<i64>,
+        keys_changed_at: Option<i64>,
+    ) -> DbResult<()> {
+        let storage_epoch = storage_epoch.map(SyncTimestamp::from_i64).transpose()?;
+        self.locked_users.insert(
+            user_id,
+            results::LockedUser {
+                storage_epoch,
+                keys_changed_at,
+            },
+        );
+        Ok(())
+    }
+
+    /// Forgets the user's storage epoch and keys read with their write lock,
+    /// as they're about to change
+    pub fn forget_locked_user(&mut self, user_id: u64) {
+        self.locked_users.remove(&user_id);
+    }
+
+    /// The collection's timestamp, as read under this session's lock
+    pub fn locked_modified(&self, user_id: u64, collection_id: i32) -> Option<SyncTimestamp> {
+        self.coll_modified_cache
+            .get(&(user_id, collection_id))
+            .copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use syncstorage_db_common::error::DbErrorIntrospect;
+
+    use super::*;
+
+    #[test]
+    fn write_lock_conflicts() {
+        let mut session = DbSession {
+            timestamp: SyncTimestamp::from_i64(2000).unwrap(),
+            ..Default::default()
+        };
+        let modified = SyncTimestamp::from_i64(1000).unwrap();
+        session
+            .lock(1, 101, CollectionLock::Write, Some(modified))
+            .unwrap();
+        assert!(session.is_locked(1, 101));
+        assert_eq!(session.locked_modified(1, 101), Some(modified));
+
+        let err = session
+            .lock(1, 102, CollectionLock::Write, Some(session.timestamp))
+            .unwrap_err();
+        assert!(err.is_conflict());
+        assert!(!session.is_locked(1, 102));
+
+        session.lock(2, 101, CollectionLock::Read, None).unwrap();
+        assert!(session.check_write_lockable(2, 101).is_err());
+        assert!(session.check_write_lockable(1, 101).is_ok());
+    }
+
+    #[test]
+    fn locked_user() {
+        let mut session = DbSession::default();
+        session.lock_user(1, Some(1000), None).unwrap();
+        assert_eq!(
+            session.locked_users.get(&1),
+            Some(&results::LockedUser {
+                storage_epoch: Some(SyncTimestamp::from_i64(1000).unwrap()),
+                keys_changed_at: None,
+            })
+        );
+        session.forget_locked_user(1);
+        assert!(session.locked_users.is_empty());
+    }
+}
@@ -0,0 +1,6 @@
+DROP TABLE user_epochs;
+DROP TABLE batch_upload_items;
+DROP TABLE batch_uploads;
+DROP TABLE user_collections;
+DROP TABLE collections;
+DROP TABLE bso;
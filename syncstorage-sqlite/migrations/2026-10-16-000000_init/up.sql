@@ -0,0 +1,79 @@
+-- The PostgreSQL schema, in SQLite's types (which don't enforce the VARCHAR
+-- lengths: see `check_bso_size`). Column names are the legacy ones MySQL
+-- uses
+CREATE TABLE bso (
+    userid BIGINT NOT NULL,
+    collection INTEGER NOT NULL,
+    id VARCHAR(64) NOT NULL,
+    sortindex INTEGER,
+    payload TEXT NOT NULL,
+    payload_size BIGINT NOT NULL DEFAULT 0,
+    -- last modified time in milliseconds since epoch
+    modified BIGINT NOT NULL,
+    -- expiration in milliseconds since epoch
+    ttl BIGINT NOT NULL DEFAULT 3153600000000,
+    PRIMARY KEY (userid, collection, id)
+) WITHOUT ROWID;
+CREATE INDEX bso_ttl_idx ON bso (ttl);
+CREATE INDEX bso_usr_col_mod_idx ON bso (userid, collection, modified, id);
+CREATE INDEX bso_usr_col_sortindex_idx ON bso (userid, collection, sortindex, id);
+
+-- An alias of the rowid: new collections are numbered past the largest id
+CREATE TABLE collections (
+    id INTEGER PRIMARY KEY,
+    name VARCHAR(32) UNIQUE NOT NULL
+);
+INSERT INTO collections (id, name) VALUES
+    (  1, 'clients'),
+    (  2, 'crypto'),
+    (  3, 'forms'),
+    (  4, 'history'),
+    (  5, 'keys'),
+    (  6, 'meta'),
+    (  7, 'bookmarks'),
+    (  8, 'prefs'),
+    (  9, 'tabs'),
+    ( 10, 'passwords'),
+    ( 11, 'addons'),
+    ( 12, 'addresses'),
+    ( 13, 'creditcards'),
+    -- Reserve space for additions to the standard collections
+    (100, '');
+
+CREATE TABLE user_collections (
+    userid BIGINT NOT NULL,
+    collection INTEGER NOT NULL,
+    -- last modified time in milliseconds since epoch
+    last_modified BIGINT NOT NULL,
+    total_bytes BIGINT NOT NULL DEFAULT 0,
+    count INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (userid, collection)
+) WITHOUT ROWID;
+
+CREATE TABLE batch_uploads (
+    batch BIGINT NOT NULL,
+    userid BIGINT NOT NULL,
+    collection INTEGER NOT NULL,
+    total_records BIGINT NOT NULL DEFAULT 0,
+    total_bytes BIGINT NOT NULL DEFAULT 0,
+    PRIMARY KEY (batch, userid)
+) WITHOUT ROWID;
+
+CREATE TABLE batch_upload_items (
+    batch BIGINT NOT NULL,
+    userid BIGINT NOT NULL,
+    id VARCHAR(64) NOT NULL,
+    sortindex INTEGER,
+    payload TEXT,
+    payload_size BIGINT,
+    ttl_offset INTEGER,
+    PRIMARY KEY (batch, userid, id)
+) WITHOUT ROWID;
+
+-- When each user's storage was last reset (e.g. migrated), in milliseconds
+-- since epoch. Writes from tokens issued before it are rejected
+CREATE TABLE user_epochs (
+    userid BIGINT NOT NULL,
+    epoch BIGINT NOT NULL,
+    PRIMARY KEY (userid)
+) WITHOUT ROWID;
@@ -0,0 +1,19 @@
+#[macro_use]
+extern crate diesel;
+#[macro_use]
+extern crate diesel_migrations;
+#[macro_use]
+extern crate slog_scope;
+
+mod batch;
+mod models;
+mod pool;
+mod schema;
+#[cfg(test)]
+mod test;
+
+pub use models::SqliteDb;
+pub use pool::{run_embedded_migrations, SqliteDbPool};
+pub use syncstorage_sql_db_common::DbError;
+
+pub(crate) use syncstorage_sql_db_common::DbResult;
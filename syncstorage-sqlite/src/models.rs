@@ -0,0 +1,1518 @@
+use std::{
+    self,
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    fmt,
+    ops::Deref,
+    sync::Arc,
+};
+
+use diesel::{
+    connection::TransactionManager,
+    delete,
+    dsl::{count_star, max, Gt, LtEq},
+    expression::sql_literal::sql,
+    r2d2::PooledConnection,
+    sql_query,
+    sql_types::{BigInt, Bool, Integer, Nullable, Text},
+    sqlite::Sqlite,
+    BoolExpressionMethods, BoxableExpression, Connection, ExpressionMethods, GroupByDsl,
+    OptionalExtension, QueryDsl, RunQueryDsl,
+};
+#[cfg(debug_assertions)]
+use diesel_logger::LoggingConnection;
+use syncserver_common::{BlockingThreadpool, Metrics};
+use syncserver_db_common::{
+    counting::CountingConnection, lock_wait_timer, manager::SqliteConnectionManager,
+    sync_db_method, DbFuture,
+};
+use syncstorage_db_common::{
+    error::DbErrorIntrospect, params, payload_page_len, results, util::SyncTimestamp, Projection,
+    Sorting, UserIdentifier, DEFAULT_BSO_TTL, FIRST_CUSTOM_COLLECTION_ID, STD_COLLS,
+};
+use syncstorage_settings::Quota;
+use syncstorage_sql_db_common::{
+    batch_db_method, check_bso_id, check_collection_name, check_quota, impl_sql_db,
+    map_collection_names, CollectionLock, DbSession, Page, USER_EPOCH_COLUMN,
+    USER_KEYS_CHANGED_AT_COLUMN,
+};
+
+use super::{
+    batch,
+    pool::CollectionCache,
+    schema::{batch_uploads, bso, collections, user_collections, user_epochs, user_keys},
+    DbError, DbResult,
+};
+
+type Conn = PooledConnection<SqliteConnectionManager>;
+
+const TOMBSTONE: i32 = 0;
+/// The `last_modified` of the user_collections rows of standard collections
+/// created ahead of their first write (see `create_std_collections`), which
+/// don't exist as far as readers are concerned
+const UNWRITTEN: i64 = 0;
+/// SQL Variable remapping
+/// These names are the legacy values mapped to the new names.
+const COLLECTION_ID: &str = "collection";
+const USER_ID: &str = "userid";
+const MODIFIED: &str = "modified";
+const EXPIRY: &str = "ttl";
+const LAST_MODIFIED: &str = "last_modified";
+const COUNT: &str = "count";
+const TOTAL_BYTES: &str = "total_bytes";
+
+/// The length of a bso's payload in bytes (`LENGTH` of text counts
+/// characters)
+const BSO_PAYLOAD_LENGTH: &str = "LENGTH(CAST(payload AS BLOB))";
+/// Max number of BSOs written by a single multi-row insert
+const POST_BSOS_CHUNK_SIZE: usize = 100;
+
+#[derive(Clone, Debug)]
+pub struct SqliteDb {
+    /// Synchronous Diesel calls are executed on the blocking threadpool to
+    /// satisfy the Db trait's asynchronous interface (see `MysqlDb`, which
+    /// this mirrors).
+    pub(super) inner: Arc<SqliteDbInner>,
+
+    /// Pool level cache of collection_ids and their names
+    coll_cache: Arc<CollectionCache>,
+
+    pub metrics: Metrics,
+    pub quota: Quota,
+    blocking_threadpool: Arc<BlockingThreadpool>,
+    /// The id of the request this is pinned to, logged with its calls
+    request_id: Option<String>,
+}
+
+/// Despite the db conn structs being !Sync (see Arc<SqliteDbInner> above)
+/// we don't spawn multiple SqliteDb calls at a time in the thread pool.
+/// Calls are queued to the thread pool via Futures, naturally serialized.
+unsafe impl Send for SqliteDb {}
+
+pub struct SqliteDbInner {
+    #[cfg(not(debug_assertions))]
+    pub(super) conn: CountingConnection<Conn>,
+    #[cfg(debug_assertions)]
+    pub(super) conn: CountingConnection<LoggingConnection<Conn>>, // display SQL when RUST_LOG="diesel_logger=trace"
+
+    session: RefCell<DbSession>,
+}
+
+impl fmt::Debug for SqliteDbInner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SqliteDbInner {{ session: {:?} }}", self.session)
+    }
+}
+
+impl Deref for SqliteDb {
+    type Target = SqliteDbInner;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl SqliteDb {
+    pub(super) fn new(
+        conn: Conn,
+        coll_cache: Arc<CollectionCache>,
+        metrics: &Metrics,
+        quota: &Quota,
+        blocking_threadpool: Arc<BlockingThreadpool>,
+    ) -> Self {
+        let inner = SqliteDbInner {
+            #[cfg(not(debug_assertions))]
+            conn: CountingConnection::new(conn),
+            #[cfg(debug_assertions)]
+            conn: CountingConnection::new(LoggingConnection::new(conn)),
+            session: RefCell::new(Default::default()),
+        };
+        // https://github.com/mozilla-services/syncstorage-rs/issues/1480
+        #[allow(clippy::arc_with_non_send_sync)]
+        SqliteDb {
+            inner: Arc::new(inner),
+            coll_cache,
+            metrics: metrics.clone(),
+            quota: quota.clone(),
+            blocking_threadpool,
+            request_id: None,
+        }
+    }
+
+    /// APIs for collection-level locking
+    ///
+    /// SQLite has no row locks to take on the user_collections table: a read
+    /// lock is merely a (snapshot isolated) transaction, while a write lock
+    /// takes the database's write lock for the connection (see `begin`). So
+    /// all collections of all users are locked for as long as any one of
+    /// them is written to: a trade-off that suits the single user
+    /// deployments this backend is meant for.
+    fn lock_for_read_sync(&self, params: params::LockCollection) -> DbResult<()> {
+        let user_id = params.user_id.legacy_id as i64;
+        let collection_id = self.get_collection_id(&params.collection).or_else(|e| {
+            if e.is_collection_not_found() {
+                // If the collection doesn't exist, we still want to start a
+                // transaction so it will continue to not exist.
+                Ok(0)
+            } else {
+                Err(e)
+            }
+        })?;
+        if self
+            .session
+            .borrow()
+            .is_locked(user_id as u64, collection_id)
+        {
+            return Ok(());
+        }
+
+        // Lock the db
+        self.begin(false)?;
+        let modified = user_collections::table
+            .select(user_collections::modified)
+            .filter(user_collections::user_id.eq(user_id))
+            .filter(user_collections::collection_id.eq(collection_id))
+            .first(&self.conn)
+            .optional()?
+            .filter(|modified| *modified != UNWRITTEN)
+            .map(SyncTimestamp::from_i64)
+            .transpose()?;
+        self.session.borrow_mut().lock(
+            user_id as u64,
+            collection_id,
+            CollectionLock::Read,
+            modified,
+        )
+    }
+
+    pub(super) fn lock_for_write_sync(&self, params: params::LockCollection) -> DbResult<()> {
+        let user_id = params.user_id.legacy_id as i64;
+        let collection_id = self.get_or_create_collection_id(&params.collection)?;
+        self.session
+            .borrow()
+            .check_write_lockable(user_id as u64, collection_id)?;
+
+        // Lock the db: `BEGIN IMMEDIATE` is what waits on the write lock
+        {
+            let _timer = lock_wait_timer(&self.metrics, "write");
+            self.begin(true)?;
+        }
+        let locked = user_collections::table
+            .select((
+                user_collections::modified,
+                sql::<Nullable<BigInt>>(USER_EPOCH_COLUMN),
+                sql::<Nullable<BigInt>>(USER_KEYS_CHANGED_AT_COLUMN),
+            ))
+            .filter(user_collections::user_id.eq(user_id))
+            .filter(user_collections::collection_id.eq(collection_id))
+            .first::<(i64, Option<i64>, Option<i64>)>(&self.conn)
+            .optional()?;
+        // The user's storage epoch and keys are read along with the lock,
+        // unless it's the collection's first write (see `Db::locked_user`)
+        let modified = match locked {
+            Some((modified, storage_epoch, keys_changed_at)) => {
+                self.session.borrow_mut().lock_user(
+                    user_id as u64,
+                    storage_epoch,
+                    keys_changed_at,
+                )?;
+                Some(modified)
+            }
+            None => None,
+        };
+        if modified.is_none() && collection_id < FIRST_CUSTOM_COLLECTION_ID {
+            // Likely the user's first write
+            self.create_std_collections(user_id)?;
+        }
+        let modified = modified
+            .filter(|modified| *modified != UNWRITTEN)
+            .map(SyncTimestamp::from_i64)
+            .transpose()?;
+        self.session.borrow_mut().lock(
+            user_id as u64,
+            collection_id,
+            CollectionLock::Write,
+            modified,
+        )
+    }
+
+    /// Begins a transaction. Write transactions are begun `IMMEDIATE`,
+    /// taking the database's write lock up front (waiting on any other
+    /// connection holding it) rather than upon their first write, when
+    /// failing to upgrade to it would abort the transaction with
+    /// `SQLITE_BUSY`
+    pub(super) fn begin(&self, for_write: bool) -> DbResult<()> {
+        let transaction_manager = self.conn.transaction_manager();
+        // Nested in a test transaction, which can only take a savepoint
+        if for_write && transaction_manager.get_transaction_depth() == 0 {
+            transaction_manager.begin_transaction_sql(&self.conn, "BEGIN IMMEDIATE")?;
+        } else {
+            transaction_manager.begin_transaction(&self.conn)?;
+        }
+        self.session.borrow_mut().begin(for_write);
+        Ok(())
+    }
+
+    fn commit_sync(&self) -> DbResult<()> {
+        if self.session.borrow().in_transaction {
+            self.conn
+                .transaction_manager()
+                .commit_transaction(&self.conn)?;
+        }
+        Ok(())
+    }
+
+    pub(super) fn rollback_sync(&self) -> DbResult<()> {
+        if self.session.borrow().in_transaction {
+            self.conn
+                .transaction_manager()
+                .rollback_transaction(&self.conn)?;
+        }
+        Ok(())
+    }
+
+    fn erect_tombstone(&self, user_id: i64) -> DbResult<()> {
+        sql_query(format!(
+            r#"INSERT INTO user_collections ({user_id}, {collection_id}, {modified})
+               VALUES (?, ?, ?)
+                   ON CONFLICT ({user_id}, {collection_id}) DO UPDATE SET
+                      {modified} = EXCLUDED.{modified}"#,
+            user_id = USER_ID,
+            collection_id = COLLECTION_ID,
+            modified = LAST_MODIFIED
+        ))
+        .bind::<BigInt, _>(user_id)
+        .bind::<Integer, _>(TOMBSTONE)
+        .bind::<BigInt, _>(self.timestamp().as_i64())
+        .execute(&self.conn)?;
+        Ok(())
+    }
+
+    /// Create the user_collections rows of every standard collection the user
+    /// lacks in a single statement, rather than one per collection as each
+    /// is first written. They're left `UNWRITTEN` until then
+    fn create_std_collections(&self, user_id: i64) -> DbResult<()> {
+        let mut query = sql_query(format!(
+            "INSERT INTO user_collections ({user_id}, {collection_id}, {modified})
+             VALUES {rows}
+                 ON CONFLICT DO NOTHING",
+            user_id = USER_ID,
+            collection_id = COLLECTION_ID,
+            modified = LAST_MODIFIED,
+            rows = values_placeholders(STD_COLLS.len(), 3),
+        ))
+        .into_boxed::<Sqlite>();
+        for (collection_id, _) in STD_COLLS {
+            query = query
+                .bind::<BigInt, _>(user_id)
+                .bind::<Integer, _>(*collection_id)
+                .bind::<BigInt, _>(UNWRITTEN);
+        }
+        query.execute(&self.conn)?;
+        Ok(())
+    }
+
+    pub(super) fn delete_storage_sync(&self, user_id: UserIdentifier) -> DbResult<()> {
+        let user_id = user_id.legacy_id as i64;
+        // Delete user data.
+        delete(bso::table)
+            .filter(bso::user_id.eq(user_id))
+            .execute(&self.conn)?;
+        // Delete user collections.
+        delete(user_collections::table)
+            .filter(user_collections::user_id.eq(user_id))
+            .execute(&self.conn)?;
+        Ok(())
+    }
+
+    fn get_storage_epoch_sync(
+        &self,
+        user_id: UserIdentifier,
+    ) -> DbResult<results::GetStorageEpoch> {
+        user_epochs::table
+            .select(user_epochs::epoch)
+            .filter(user_epochs::user_id.eq(user_id.legacy_id as i64))
+            .first::<i64>(&self.conn)
+            .optional()?
+            .map(SyncTimestamp::from_i64)
+            .transpose()
+            .map_err(Into::into)
+    }
+
+    fn bump_storage_epoch_sync(
+        &self,
+        user_id: UserIdentifier,
+    ) -> DbResult<results::BumpStorageEpoch> {
+        self.session
+            .borrow_mut()
+            .forget_locked_user(user_id.legacy_id);
+        let timestamp = self.timestamp();
+        sql_query(
+            r#"INSERT INTO user_epochs (userid, epoch)
+               VALUES (?, ?)
+                   ON CONFLICT (userid) DO UPDATE SET
+                      epoch = EXCLUDED.epoch"#,
+        )
+        .bind::<BigInt, _>(user_id.legacy_id as i64)
+        .bind::<BigInt, _>(timestamp.as_i64())
+        .execute(&self.conn)?;
+        Ok(timestamp)
+    }
+
+    fn get_keys_changed_at_sync(
+        &self,
+        user_id: UserIdentifier,
+    ) -> DbResult<results::GetKeysChangedAt> {
+        Ok(user_keys::table
+            .select(user_keys::keys_changed_at)
+            .filter(user_keys::user_id.eq(user_id.legacy_id as i64))
+            .first::<i64>(&self.conn)
+            .optional()?)
+    }
+
+    fn record_keys_changed_at_sync(
+        &self,
+        params: params::RecordKeysChangedAt,
+    ) -> DbResult<results::RecordKeysChangedAt> {
+        self.session
+            .borrow_mut()
+            .forget_locked_user(params.user_id.legacy_id);
+        sql_query(
+            r#"INSERT INTO user_keys (userid, keys_changed_at)
+               VALUES (?, ?)
+                   ON CONFLICT (userid) DO UPDATE SET
+                      keys_changed_at = MAX(keys_changed_at, EXCLUDED.keys_changed_at)"#,
+        )
+        .bind::<BigInt, _>(params.user_id.legacy_id as i64)
+        .bind::<BigInt, _>(params.keys_changed_at)
+        .execute(&self.conn)?;
+        Ok(())
+    }
+
+    // Deleting the collection should result in:
+    //  - collection does not appear in /info/collections
+    //  - X-Last-Modified timestamp at the storage level changing
+    fn delete_collection_sync(&self, params: params::DeleteCollection) -> DbResult<SyncTimestamp> {
+        let user_id = params.user_id.legacy_id as i64;
+        let collection_id = self.get_collection_id(&params.collection)?;
+        if !self.delete_collection_rows(user_id, collection_id)? {
+            return Err(DbError::collection_not_found());
+        } else {
+            self.erect_tombstone(user_id)?;
+        }
+        self.get_storage_timestamp_sync(params.user_id)
+    }
+
+    fn delete_collections_sync(
+        &self,
+        params: params::DeleteCollections,
+    ) -> DbResult<results::DeleteCollections> {
+        let user_id = params.user_id.legacy_id as i64;
+        let mut collections = HashMap::new();
+        for collection in params.collections {
+            let deleted = match self.get_collection_id(&collection) {
+                Ok(collection_id) => self.delete_collection_rows(user_id, collection_id)?,
+                Err(e) if e.is_collection_not_found() => false,
+                Err(e) => return Err(e),
+            };
+            collections.insert(collection, deleted);
+        }
+        if collections.values().any(|deleted| *deleted) {
+            self.erect_tombstone(user_id)?;
+        }
+        Ok(results::DeleteCollections {
+            modified: self.get_storage_timestamp_sync(params.user_id)?,
+            collections,
+        })
+    }
+
+    pub(super) fn delete_collections_except_sync(
+        &self,
+        params: params::DeleteCollectionsExcept,
+    ) -> DbResult<results::DeleteCollectionsExcept> {
+        let user_id = params.user_id.legacy_id as i64;
+        // Collections that were never created have nothing to keep
+        let mut keep = vec![TOMBSTONE];
+        for collection in &params.keep {
+            match self.get_collection_id(collection) {
+                Ok(collection_id) => keep.push(collection_id),
+                Err(e) if e.is_collection_not_found() => (),
+                Err(e) => return Err(e),
+            }
+        }
+
+        let deleted = user_collections::table
+            .select(user_collections::collection_id)
+            .filter(user_collections::user_id.eq(user_id))
+            .filter(user_collections::collection_id.ne_all(keep.clone()))
+            .filter(user_collections::modified.ne(UNWRITTEN))
+            .load::<i32>(&self.conn)?;
+        delete(bso::table)
+            .filter(bso::user_id.eq(user_id))
+            .filter(bso::collection_id.ne_all(keep.clone()))
+            .execute(&self.conn)?;
+        delete(user_collections::table)
+            .filter(user_collections::user_id.eq(user_id))
+            .filter(user_collections::collection_id.ne_all(keep))
+            .filter(user_collections::modified.ne(UNWRITTEN))
+            .execute(&self.conn)?;
+
+        let collections =
+            self.map_collection_names(deleted.into_iter().map(|id| (id, true)).collect())?;
+        if !collections.is_empty() {
+            self.erect_tombstone(user_id)?;
+        }
+        Ok(results::DeleteCollections {
+            modified: self.get_storage_timestamp_sync(params.user_id)?,
+            collections,
+        })
+    }
+
+    /// Delete a collection's bsos and its user_collections row, returning
+    /// whether there was anything to delete
+    fn delete_collection_rows(&self, user_id: i64, collection_id: i32) -> DbResult<bool> {
+        let mut count = delete(bso::table)
+            .filter(bso::user_id.eq(user_id))
+            .filter(bso::collection_id.eq(&collection_id))
+            .execute(&self.conn)?;
+        count += delete(user_collections::table)
+            .filter(user_collections::user_id.eq(user_id))
+            .filter(user_collections::collection_id.eq(&collection_id))
+            .filter(user_collections::modified.ne(UNWRITTEN))
+            .execute(&self.conn)?;
+        Ok(count > 0)
+    }
+
+    pub(super) fn get_or_create_collection_id(&self, name: &str) -> DbResult<i32> {
+        if let Some(id) = self.coll_cache.get_id(name)? {
+            return Ok(id);
+        }
+        check_collection_name(name)?;
+
+        // Unlike the MySQL and PostgreSQL backends there's no need to create
+        // collections outside of the caller's transaction: SQLite serializes
+        // writers, so the first writes to a new collection don't race
+        let id = self.conn.transaction(|| {
+            diesel::insert_or_ignore_into(collections::table)
+                .values(collections::name.eq(name))
+                .execute(&self.conn)?;
+
+            collections::table
+                .select(collections::id)
+                .filter(collections::name.eq(name))
+                .first(&self.conn)
+        })?;
+
+        if !self.session.borrow().in_write_transaction {
+            self.coll_cache.put(id, name.to_owned())?;
+        }
+
+        Ok(id)
+    }
+
+    pub(super) fn get_collection_id(&self, name: &str) -> DbResult<i32> {
+        if let Some(id) = self.coll_cache.get_id(name)? {
+            return Ok(id);
+        }
+
+        let id = sql_query(
+            "SELECT id
+               FROM collections
+              WHERE name = ?",
+        )
+        .bind::<Text, _>(name)
+        .get_result::<IdResult>(&self.conn)
+        .optional()?
+        .ok_or_else(DbError::collection_not_found)?
+        .id;
+        if !self.session.borrow().in_write_transaction {
+            self.coll_cache.put(id, name.to_owned())?;
+        }
+        Ok(id)
+    }
+
+    pub(super) fn put_bso_sync(&self, bso: params::PutBso) -> DbResult<results::PutBso> {
+        let collection_id = self.get_or_create_collection_id(&bso.collection)?;
+        self.check_quota(&bso.user_id, &bso.collection, collection_id)?;
+        self.conn.transaction(|| {
+            self.write_bso(&bso, collection_id)?;
+            self.update_collection(bso.user_id.legacy_id, collection_id)
+        })
+    }
+
+    fn check_quota(
+        &self,
+        user_id: &UserIdentifier,
+        collection: &str,
+        collection_id: i32,
+    ) -> DbResult<()> {
+        check_quota(&self.quota, &self.metrics, user_id, collection, || {
+            self.get_quota_usage_sync(params::GetQuotaUsage {
+                user_id: user_id.clone(),
+                collection: collection.to_owned(),
+                collection_id,
+            })
+        })
+    }
+
+    /// Write a BSO, leaving its collection's `user_collections` row to the
+    /// caller
+    fn write_bso(&self, bso: &params::PutBso, collection_id: i32) -> DbResult<()> {
+        check_bso_size(&bso.id)?;
+        let user_id = bso.user_id.legacy_id as i64;
+        let timestamp = self.timestamp().as_i64();
+        let payload = bso.payload.as_deref().unwrap_or_default();
+        let ttl = bso.ttl.unwrap_or(DEFAULT_BSO_TTL);
+        let mut q = format!(
+            r#"
+        INSERT INTO bso ({user_id}, {collection_id}, id, sortindex, payload, payload_size, {modified}, {expiry})
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+            ON CONFLICT ({user_id}, {collection_id}, id) DO UPDATE SET
+               {user_id} = EXCLUDED.{user_id}"#,
+            user_id = USER_ID,
+            modified = MODIFIED,
+            collection_id = COLLECTION_ID,
+            expiry = EXPIRY
+        );
+        if bso.sortindex.is_some() {
+            q.push_str(", sortindex = EXCLUDED.sortindex");
+        }
+        if bso.payload.is_some() {
+            q.push_str(", payload = EXCLUDED.payload, payload_size = EXCLUDED.payload_size");
+        }
+        if bso.ttl.is_some() {
+            q.push_str(&format!(", {expiry} = EXCLUDED.{expiry}", expiry = EXPIRY));
+        }
+        if bso.payload.is_some() || bso.sortindex.is_some() {
+            q.push_str(&format!(
+                ", {modified} = EXCLUDED.{modified}",
+                modified = MODIFIED
+            ));
+        }
+        sql_query(q)
+            .bind::<BigInt, _>(user_id)
+            .bind::<Integer, _>(&collection_id)
+            .bind::<Text, _>(&bso.id)
+            .bind::<Nullable<Integer>, _>(bso.sortindex)
+            .bind::<Text, _>(payload)
+            .bind::<BigInt, _>(payload.len() as i64)
+            .bind::<BigInt, _>(timestamp)
+            .bind::<BigInt, _>(timestamp + (i64::from(ttl) * 1000)) // remember: this is in millis
+            .execute(&self.conn)?;
+        Ok(())
+    }
+
+    fn get_bsos_sync(&self, params: params::GetBsos) -> DbResult<results::GetBsos> {
+        let user_id = params.user_id.legacy_id as i64;
+        let collection_id = self.get_collection_id(&params.collection)?;
+        let now = self.timestamp().as_i64();
+        // Columns outside the projection aren't read (particularly payloads)
+        let (payload, sortindex) = match params.projection {
+            Projection::Full => ("bso.payload", "bso.sortindex"),
+            Projection::NoSortindex => ("bso.payload", "NULL"),
+            Projection::Meta => ("''", "NULL"),
+        };
+        let mut query = bso::table
+            .select((
+                bso::id,
+                bso::modified,
+                sql::<Text>(payload),
+                sql::<Nullable<Integer>>(sortindex),
+                bso::expiry,
+            ))
+            .filter(bso::user_id.eq(user_id))
+            .filter(bso::collection_id.eq(collection_id))
+            .filter(unexpired(now))
+            .into_boxed();
+
+        if let Some(older) = params.older {
+            query = query.filter(bso::modified.lt(older.as_i64()));
+        }
+        if let Some(newer) = params.newer {
+            query = query.filter(bso::modified.gt(newer.as_i64()));
+        }
+
+        if !params.ids.is_empty() {
+            query = query.filter(bso::id.eq_any(params.ids));
+        }
+
+        // it's possible for two BSOs to be inserted with the same `modified` date,
+        // since there's no guarantee of order when doing a get, pagination can return
+        // an error. We "fudge" a bit here by taking the id order as a secondary, since
+        // that is guaranteed to be unique by the client.
+        //
+        // Each order is served by an index on (userid, collection, <sort
+        // column>, id)
+        query = match params.sort {
+            Sorting::Index => query.order((bso::sortindex.desc(), bso::id.desc())),
+            Sorting::Newest => query.order((bso::modified.desc(), bso::id.desc())),
+            Sorting::Oldest => query.order((bso::modified.asc(), bso::id.asc())),
+            _ => query,
+        };
+
+        let page = Page::new(params.limit);
+        query = query.limit(page.fetch_limit());
+
+        let offset = params.offset.unwrap_or_default();
+        // Seeking past the previous page's last BSO spares reading every
+        // BSO before it, as skipping over them does
+        if let Some(after) = offset.after(params.sort) {
+            query = query.filter(past(after, params.sort));
+        } else if offset.offset > 0 {
+            query = query.offset(offset.offset as i64);
+        }
+        let mut bsos = query.load::<results::GetBso>(&self.conn)?;
+
+        let mut next_offset = page
+            .next_offset(&mut bsos, &offset, params.sort, Into::into)
+            .or_else(|| {
+                // if an explicit "limit=0" is sent, return the offset of "0"
+                // Otherwise, this would break at least the db::tests::db::get_bsos_limit_offset
+                // unit test.
+                page.is_empty().then(|| 0.to_string())
+            });
+        if let Some(len) = params
+            .max_payload_bytes
+            .and_then(|max_bytes| payload_page_len(&bsos, max_bytes))
+        {
+            bsos.truncate(len);
+            let last = bsos.last().map(Into::into);
+            next_offset = Some(offset.next(len, params.sort, last).to_string());
+        }
+
+        Ok(results::GetBsos {
+            items: bsos,
+            offset: next_offset,
+        })
+    }
+
+    fn get_bso_ids_sync(&self, params: params::GetBsos) -> DbResult<results::GetBsoIds> {
+        let user_id = params.user_id.legacy_id as i64;
+        let collection_id = self.get_collection_id(&params.collection)?;
+        let mut query = bso::table
+            .select((bso::id, bso::modified))
+            .filter(bso::user_id.eq(user_id))
+            .filter(bso::collection_id.eq(collection_id))
+            .filter(unexpired(self.timestamp().as_i64()))
+            .into_boxed();
+
+        if let Some(older) = params.older {
+            query = query.filter(bso::modified.lt(older.as_i64()));
+        }
+        if let Some(newer) = params.newer {
+            query = query.filter(bso::modified.gt(newer.as_i64()));
+        }
+
+        if !params.ids.is_empty() {
+            query = query.filter(bso::id.eq_any(params.ids));
+        }
+
+        query = match params.sort {
+            Sorting::Index => query.order((bso::sortindex.desc(), bso::id.desc())),
+            Sorting::Newest => query.order((bso::modified.desc(), bso::id.desc())),
+            Sorting::Oldest => query.order((bso::modified.asc(), bso::id.asc())),
+            _ => query,
+        };
+
+        let page = Page::new(params.limit);
+        query = query.limit(page.fetch_limit());
+        let offset = params.offset.unwrap_or_default();
+        if let Some(after) = offset.after(params.sort) {
+            query = query.filter(past(after, params.sort));
+        } else if offset.offset > 0 {
+            query = query.offset(offset.offset as i64);
+        }
+        let mut rows = query.load::<(String, SyncTimestamp)>(&self.conn)?;
+
+        let next_offset = page.next_offset(&mut rows, &offset, params.sort, |(id, modified)| {
+            params::BsoPosition {
+                modified: *modified,
+                id: id.clone(),
+            }
+        });
+
+        Ok(results::GetBsoIds {
+            items: rows.into_iter().map(|(id, _)| id).collect(),
+            offset: next_offset,
+        })
+    }
+
+    fn get_bsos_count_sync(&self, params: params::GetBsosCount) -> DbResult<results::GetBsosCount> {
+        let user_id = params.user_id.legacy_id as i64;
+        let collection_id = self.get_collection_id(&params.collection)?;
+        let mut query = bso::table
+            .select(count_star())
+            .filter(bso::user_id.eq(user_id))
+            .filter(bso::collection_id.eq(collection_id))
+            .filter(unexpired(self.timestamp().as_i64()))
+            .into_boxed();
+
+        if let Some(older) = params.older {
+            query = query.filter(bso::modified.lt(older.as_i64()));
+        }
+        if let Some(newer) = params.newer {
+            query = query.filter(bso::modified.gt(newer.as_i64()));
+        }
+        if !params.ids.is_empty() {
+            query = query.filter(bso::id.eq_any(params.ids));
+        }
+        Ok(query.get_result::<i64>(&self.conn)? as u64)
+    }
+
+    pub(super) fn get_bso_sync(&self, params: params::GetBso) -> DbResult<Option<results::GetBso>> {
+        let user_id = params.user_id.legacy_id as i64;
+        let collection_id = self.get_collection_id(&params.collection)?;
+        Ok(bso::table
+            .select((
+                bso::id,
+                bso::modified,
+                bso::payload,
+                bso::sortindex,
+                bso::expiry,
+            ))
+            .filter(bso::user_id.eq(user_id))
+            .filter(bso::collection_id.eq(&collection_id))
+            .filter(bso::id.eq(&params.id))
+            .filter(unexpired(self.timestamp().as_i64()))
+            .get_result::<results::GetBso>(&self.conn)
+            .optional()?)
+    }
+
+    fn delete_bso_sync(&self, params: params::DeleteBso) -> DbResult<results::DeleteBso> {
+        let user_id = params.user_id.legacy_id;
+        let collection_id = self.get_collection_id(&params.collection)?;
+        let affected_rows = delete(bso::table)
+            .filter(bso::user_id.eq(user_id as i64))
+            .filter(bso::collection_id.eq(&collection_id))
+            .filter(bso::id.eq(params.id))
+            .filter(unexpired(self.timestamp().as_i64()))
+            .execute(&self.conn)?;
+        if affected_rows == 0 {
+            return Err(DbError::bso_not_found());
+        }
+        self.update_collection(user_id, collection_id)
+    }
+
+    /// Delete the bso if it has expired. Its collection's left as is: reads
+    /// already skipped the bso
+    fn purge_expired_bso_sync(
+        &self,
+        params: params::PurgeExpiredBso,
+    ) -> DbResult<results::PurgeExpiredBso> {
+        let collection_id = match self.get_collection_id(&params.collection) {
+            Ok(collection_id) => collection_id,
+            Err(e) if e.is_collection_not_found() => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        let affected_rows = delete(bso::table)
+            .filter(bso::user_id.eq(params.user_id.legacy_id as i64))
+            .filter(bso::collection_id.eq(&collection_id))
+            .filter(bso::id.eq(params.id))
+            .filter(expired(SyncTimestamp::default().as_i64()))
+            .execute(&self.conn)?;
+        Ok(affected_rows > 0)
+    }
+
+    fn delete_bsos_sync(&self, params: params::DeleteBsos) -> DbResult<results::DeleteBsos> {
+        let user_id = params.user_id.legacy_id as i64;
+        let collection_id = self.get_collection_id(&params.collection)?;
+        delete(bso::table)
+            .filter(bso::user_id.eq(user_id))
+            .filter(bso::collection_id.eq(&collection_id))
+            .filter(bso::id.eq_any(params.ids))
+            .execute(&self.conn)?;
+        self.update_collection(user_id as u64, collection_id)
+    }
+
+    pub(super) fn post_bsos_sync(&self, input: params::PostBsos) -> DbResult<results::PostBsos> {
+        let collection_id = self.get_or_create_collection_id(&input.collection)?;
+        let mut result = results::PostBsos {
+            modified: self.timestamp(),
+            success: Default::default(),
+            failed: input.failed,
+        };
+        // BSOs the columns can't hold fail individually, like those failing
+        // to write
+        let mut bsos = Vec::with_capacity(input.bsos.len());
+        for pbso in input.bsos {
+            match check_bso_size(&pbso.id) {
+                Ok(()) => bsos.push(pbso),
+                Err(e) => {
+                    result.failed.insert(pbso.id, e.to_string());
+                }
+            }
+        }
+        let ids: Vec<String> = bsos.iter().map(|pbso| pbso.id.clone()).collect();
+        let mut succeeded = HashSet::new();
+
+        // Quota's checked once for the whole post, against the usage
+        // recorded before it (the collection's only touched once, after all
+        // its BSOs are written)
+        self.check_quota(&input.user_id, &input.collection, collection_id)?;
+
+        // BSOs without a payload only update some of their columns: those
+        // are written one at a time, as are posts repeating BSO ids (an
+        // upsert can't update the same row twice)
+        let bulk_writable = ids.iter().collect::<HashSet<_>>().len() == ids.len();
+        let (bulk, single): (Vec<_>, Vec<_>) = bsos
+            .into_iter()
+            .partition(|pbso| bulk_writable && pbso.payload.is_some());
+
+        // Rows of a multi-row insert must update the same columns
+        let mut shapes: HashMap<(bool, bool), Vec<params::PostCollectionBso>> = HashMap::new();
+        for pbso in bulk {
+            shapes
+                .entry((pbso.sortindex.is_some(), pbso.ttl.is_some()))
+                .or_default()
+                .push(pbso);
+        }
+        for bsos in shapes.into_values() {
+            let mut bsos = bsos.into_iter().peekable();
+            while bsos.peek().is_some() {
+                let chunk: Vec<_> = bsos.by_ref().take(POST_BSOS_CHUNK_SIZE).collect();
+                // A failing statement is rolled back on its own
+                match self.insert_bsos_chunk(input.user_id.legacy_id, collection_id, &chunk) {
+                    Ok(()) => succeeded.extend(chunk.into_iter().map(|pbso| pbso.id)),
+                    Err(e) => {
+                        // Isolate the failing BSO(s) rather than failing the
+                        // whole chunk
+                        warn!("Bulk BSO insert failed, retrying one at a time: {}", e);
+                        self.metrics.incr("storage.post_bsos.chunk_fallback");
+                        self.put_bsos_singly(
+                            &input.user_id,
+                            &input.collection,
+                            collection_id,
+                            chunk,
+                            &mut succeeded,
+                            &mut result.failed,
+                        );
+                    }
+                }
+            }
+        }
+        self.put_bsos_singly(
+            &input.user_id,
+            &input.collection,
+            collection_id,
+            single,
+            &mut succeeded,
+            &mut result.failed,
+        );
+
+        result.success = ids
+            .into_iter()
+            .filter(|id| succeeded.contains(id))
+            .collect();
+        self.update_collection(input.user_id.legacy_id, collection_id)?;
+        Ok(result)
+    }
+
+    /// Write BSOs one at a time, reporting their individual failures
+    fn put_bsos_singly(
+        &self,
+        user_id: &UserIdentifier,
+        collection: &str,
+        collection_id: i32,
+        bsos: Vec<params::PostCollectionBso>,
+        succeeded: &mut HashSet<String>,
+        failed: &mut HashMap<String, String>,
+    ) {
+        for pbso in bsos {
+            let id = pbso.id;
+            let put_result = self.write_bso(
+                &params::PutBso {
+                    user_id: user_id.clone(),
+                    collection: collection.to_owned(),
+                    id: id.clone(),
+                    payload: pbso.payload,
+                    sortindex: pbso.sortindex,
+                    ttl: pbso.ttl,
+                },
+                collection_id,
+            );
+            match put_result {
+                Ok(_) => {
+                    succeeded.insert(id);
+                }
+                Err(e) => {
+                    failed.insert(id, e.to_string());
+                }
+            }
+        }
+    }
+
+    /// Write BSOs (all with payloads and the same optional fields set) with
+    /// a single multi-row insert
+    fn insert_bsos_chunk(
+        &self,
+        user_id: u64,
+        collection_id: i32,
+        bsos: &[params::PostCollectionBso],
+    ) -> DbResult<()> {
+        let Some(first) = bsos.first() else {
+            return Ok(());
+        };
+        let timestamp = self.timestamp().as_i64();
+        let mut q = format!(
+            "INSERT INTO bso ({user_id}, {collection_id}, id, sortindex, payload, payload_size, {modified}, {expiry}) VALUES {values}
+                ON CONFLICT ({user_id}, {collection_id}, id) DO UPDATE SET
+                   payload = EXCLUDED.payload,
+                   payload_size = EXCLUDED.payload_size,
+                   {modified} = EXCLUDED.{modified}",
+            user_id = USER_ID,
+            collection_id = COLLECTION_ID,
+            modified = MODIFIED,
+            expiry = EXPIRY,
+            values = values_placeholders(bsos.len(), 8),
+        );
+        if first.sortindex.is_some() {
+            q.push_str(", sortindex = EXCLUDED.sortindex");
+        }
+        if first.ttl.is_some() {
+            q.push_str(&format!(", {expiry} = EXCLUDED.{expiry}", expiry = EXPIRY));
+        }
+        let mut query = sql_query(q).into_boxed::<Sqlite>();
+        for bso in bsos {
+            let payload = bso.payload.as_deref().unwrap_or_default();
+            let ttl = bso.ttl.unwrap_or(DEFAULT_BSO_TTL);
+            query = query
+                .bind::<BigInt, _>(user_id as i64)
+                .bind::<Integer, _>(collection_id)
+                .bind::<Text, _>(bso.id.clone())
+                .bind::<Nullable<Integer>, _>(bso.sortindex)
+                .bind::<Text, _>(payload.to_owned())
+                .bind::<BigInt, _>(payload.len() as i64)
+                .bind::<BigInt, _>(timestamp)
+                .bind::<BigInt, _>(timestamp + (i64::from(ttl) * 1000));
+        }
+        query.execute(&self.conn)?;
+        Ok(())
+    }
+
+    fn get_storage_timestamp_sync(&self, user_id: UserIdentifier) -> DbResult<SyncTimestamp> {
+        let user_id = user_id.legacy_id as i64;
+        let modified = user_collections::table
+            .select(max(user_collections::modified))
+            .filter(user_collections::user_id.eq(user_id))
+            .first::<Option<i64>>(&self.conn)?
+            .unwrap_or_default();
+        SyncTimestamp::from_i64(modified).map_err(Into::into)
+    }
+
+    fn get_collection_timestamp_sync(
+        &self,
+        params: params::GetCollectionTimestamp,
+    ) -> DbResult<SyncTimestamp> {
+        let user_id = params.user_id.legacy_id;
+        let collection_id = self.get_collection_id(&params.collection)?;
+        if let Some(modified) = self
+            .session
+            .borrow()
+            .locked_modified(user_id, collection_id)
+        {
+            return Ok(modified);
+        }
+        user_collections::table
+            .select(user_collections::modified)
+            .filter(user_collections::user_id.eq(user_id as i64))
+            .filter(user_collections::collection_id.eq(collection_id))
+            .filter(user_collections::modified.ne(UNWRITTEN))
+            .first(&self.conn)
+            .optional()?
+            .ok_or_else(DbError::collection_not_found)
+    }
+
+    fn get_bso_timestamp_sync(&self, params: params::GetBsoTimestamp) -> DbResult<SyncTimestamp> {
+        let user_id = params.user_id.legacy_id as i64;
+        let collection_id = self.get_collection_id(&params.collection)?;
+        let modified = bso::table
+            .select(bso::modified)
+            .filter(bso::user_id.eq(user_id))
+            .filter(bso::collection_id.eq(&collection_id))
+            .filter(bso::id.eq(&params.id))
+            .first::<i64>(&self.conn)
+            .optional()?
+            .unwrap_or_default();
+        SyncTimestamp::from_i64(modified).map_err(Into::into)
+    }
+
+    pub(super) fn get_collection_timestamps_sync(
+        &self,
+        user_id: UserIdentifier,
+    ) -> DbResult<results::GetCollectionTimestamps> {
+        let modifieds = sql_query(format!(
+            "SELECT {collection_id}, {modified}
+               FROM user_collections
+              WHERE {user_id} = ?
+               AND {collection_id} != ?
+               AND {modified} != ?",
+            collection_id = COLLECTION_ID,
+            user_id = USER_ID,
+            modified = LAST_MODIFIED
+        ))
+        .bind::<BigInt, _>(user_id.legacy_id as i64)
+        .bind::<Integer, _>(TOMBSTONE)
+        .bind::<BigInt, _>(UNWRITTEN)
+        .load::<UserCollectionsResult>(&self.conn)?
+        .into_iter()
+        .map(|cr| {
+            SyncTimestamp::from_i64(cr.last_modified)
+                .map(|ts| (cr.collection, ts))
+                .map_err(Into::into)
+        })
+        .collect::<DbResult<HashMap<_, _>>>()?;
+        self.map_collection_names(modifieds)
+    }
+
+    fn check_sync(&self) -> DbResult<results::Check> {
+        // does the database answer at all? (executing a SELECT reports the
+        // rows changed by the previous statement rather than any it returns)
+        let result = diesel::select(sql::<Integer>("1")).get_result::<i32>(&self.conn)?;
+        Ok(result == 1)
+    }
+
+    fn get_database_stats_sync(&self) -> DbResult<results::GetDatabaseStats> {
+        let now = SyncTimestamp::default().as_i64();
+        let bsos = bso::table.count().get_result::<i64>(&self.conn)?;
+        let expired_bsos = bso::table
+            .filter(expired(now))
+            .count()
+            .get_result::<i64>(&self.conn)?;
+        let user_collections = user_collections::table
+            .filter(user_collections::modified.ne(UNWRITTEN))
+            .count()
+            .get_result::<i64>(&self.conn)?;
+        let batches = batch_uploads::table.count().get_result::<i64>(&self.conn)?;
+        Ok(results::GetDatabaseStats {
+            bsos,
+            expired_bsos,
+            user_collections,
+            batches,
+        })
+    }
+
+    /// Purge expired bsos, then the collections they leave empty
+    fn purge_expired_sync(&self, params: params::PurgeExpired) -> DbResult<results::PurgeExpired> {
+        let bsos = self.delete_expired_bsos(params.limit)?;
+        let user_collections = self.delete_empty_collections(params.limit)?;
+        Ok(results::PurgeExpired {
+            bsos: bsos as u64,
+            user_collections: user_collections as u64,
+            ..Default::default()
+        })
+    }
+
+    /// Delete up to `limit` expired bsos, returning the number deleted
+    fn delete_expired_bsos(&self, limit: u32) -> DbResult<usize> {
+        // DELETE has no LIMIT (unless SQLite was built with it)
+        Ok(sql_query(format!(
+            "DELETE FROM bso
+              WHERE ({user_id}, {collection_id}, id) IN (
+                    SELECT {user_id}, {collection_id}, id
+                      FROM bso
+                     WHERE {expiry} <= ?
+                     LIMIT ?)",
+            user_id = USER_ID,
+            collection_id = COLLECTION_ID,
+            expiry = EXPIRY,
+        ))
+        .bind::<BigInt, _>(SyncTimestamp::default().as_i64())
+        .bind::<BigInt, _>(i64::from(limit))
+        .execute(&self.conn)?)
+    }
+
+    /// Delete up to `limit` collections without any bsos (e.g. once theirs
+    /// expired and were purged), returning the number deleted.
+    ///
+    /// Like deleting a collection, this raises the user's tombstone so their
+    /// storage timestamp doesn't go back, though only up to the collection's
+    /// timestamp: nothing they can read changed.
+    fn delete_empty_collections(&self, limit: u32) -> DbResult<usize> {
+        let no_bsos = format!(
+            "NOT EXISTS (SELECT 1 FROM bso
+                          WHERE bso.{user_id} = user_collections.{user_id}
+                            AND bso.{collection_id} = user_collections.{collection_id})",
+            user_id = USER_ID,
+            collection_id = COLLECTION_ID,
+        );
+        let empty = sql_query(format!(
+            "SELECT {user_id}, {collection_id}, {modified}
+               FROM user_collections
+              WHERE {collection_id} != ?
+                AND {modified} != ?
+                AND {no_bsos}
+              LIMIT ?",
+            user_id = USER_ID,
+            collection_id = COLLECTION_ID,
+            modified = LAST_MODIFIED,
+            no_bsos = no_bsos,
+        ))
+        .bind::<Integer, _>(TOMBSTONE)
+        .bind::<BigInt, _>(UNWRITTEN)
+        .bind::<BigInt, _>(i64::from(limit))
+        .load::<EmptyCollectionResult>(&self.conn)?;
+
+        let mut deleted = 0;
+        for collection in empty {
+            self.raise_tombstone(collection.userid, collection.last_modified)?;
+            // Spared when written to since
+            deleted += sql_query(format!(
+                "DELETE FROM user_collections
+                  WHERE {user_id} = ?
+                    AND {collection_id} = ?
+                    AND {modified} = ?
+                    AND {no_bsos}",
+                user_id = USER_ID,
+                collection_id = COLLECTION_ID,
+                modified = LAST_MODIFIED,
+                no_bsos = no_bsos,
+            ))
+            .bind::<BigInt, _>(collection.userid)
+            .bind::<Integer, _>(collection.collection)
+            .bind::<BigInt, _>(collection.last_modified)
+            .execute(&self.conn)?;
+        }
+        Ok(deleted)
+    }
+
+    /// Raise the user's tombstone to `modified`, unless it's already past it
+    fn raise_tombstone(&self, user_id: i64, modified: i64) -> DbResult<()> {
+        sql_query(format!(
+            r#"INSERT INTO user_collections ({user_id}, {collection_id}, {modified})
+               VALUES (?, ?, ?)
+                   ON CONFLICT ({user_id}, {collection_id}) DO UPDATE SET
+                      {modified} = MAX({modified}, EXCLUDED.{modified})"#,
+            user_id = USER_ID,
+            collection_id = COLLECTION_ID,
+            modified = LAST_MODIFIED
+        ))
+        .bind::<BigInt, _>(user_id)
+        .bind::<Integer, _>(TOMBSTONE)
+        .bind::<BigInt, _>(modified)
+        .execute(&self.conn)?;
+        Ok(())
+    }
+
+    fn map_collection_names<T>(&self, by_id: HashMap<i32, T>) -> DbResult<HashMap<String, T>> {
+        let names = self.load_collection_names(by_id.keys())?;
+        map_collection_names(by_id, names)
+    }
+
+    /// Runs the common lookups (for a user without any data) so diesel
+    /// prepares and caches their statements on this connection
+    pub(super) fn prime_statements(&self) -> DbResult<()> {
+        let user_id = UserIdentifier::default();
+        self.get_storage_timestamp_sync(user_id.clone())?;
+        let collection = STD_COLLS[0].1.to_owned();
+        self.get_bso_timestamp_sync(params::GetBsoTimestamp {
+            user_id: user_id.clone(),
+            collection: collection.clone(),
+            id: String::new(),
+        })?;
+        self.get_bso_sync(params::GetBso {
+            user_id,
+            collection,
+            id: String::new(),
+        })?;
+        Ok(())
+    }
+
+    /// Caches the names of every custom collection, returning their count
+    pub(super) fn cache_custom_collections(&self) -> DbResult<usize> {
+        let collections = collections::table
+            .select((collections::id, collections::name))
+            .filter(collections::id.ge(FIRST_CUSTOM_COLLECTION_ID))
+            .load::<(i32, String)>(&self.conn)?;
+        let count = collections.len();
+        for (id, name) in collections {
+            self.coll_cache.put(id, name)?;
+        }
+        Ok(count)
+    }
+
+    fn load_collection_names<'a>(
+        &self,
+        collection_ids: impl Iterator<Item = &'a i32>,
+    ) -> DbResult<HashMap<i32, String>> {
+        let mut names = HashMap::new();
+        let mut uncached = Vec::new();
+        for &id in collection_ids {
+            if let Some(name) = self.coll_cache.get_name(id)? {
+                names.insert(id, name);
+            } else {
+                uncached.push(id);
+            }
+        }
+
+        if !uncached.is_empty() {
+            let result = collections::table
+                .select((collections::id, collections::name))
+                .filter(collections::id.eq_any(uncached))
+                .load::<(i32, String)>(&self.conn)?;
+
+            for (id, name) in result {
+                names.insert(id, name.clone());
+                if !self.session.borrow().in_write_transaction {
+                    self.coll_cache.put(id, name)?;
+                }
+            }
+        }
+
+        Ok(names)
+    }
+
+    pub(super) fn update_collection(
+        &self,
+        user_id: u64,
+        collection_id: i32,
+    ) -> DbResult<SyncTimestamp> {
+        let quota = if self.quota.enabled || self.quota.usage_counters {
+            let quota = self.calc_quota_usage_sync(user_id, collection_id)?;
+            self.session
+                .borrow_mut()
+                .written_usage
+                .insert((user_id, collection_id), quota.clone());
+            quota
+        } else {
+            results::GetQuotaUsage {
+                count: 0,
+                total_bytes: 0,
+            }
+        };
+        let upsert = format!(
+            r#"
+                INSERT INTO user_collections ({user_id}, {collection_id}, {modified}, {total_bytes}, {count})
+                VALUES (?, ?, ?, ?, ?)
+                    ON CONFLICT ({user_id}, {collection_id}) DO UPDATE SET
+                       {modified} = EXCLUDED.{modified},
+                       {total_bytes} = EXCLUDED.{total_bytes},
+                       {count} = EXCLUDED.{count}
+        "#,
+            user_id = USER_ID,
+            collection_id = COLLECTION_ID,
+            modified = LAST_MODIFIED,
+            count = COUNT,
+            total_bytes = TOTAL_BYTES,
+        );
+        sql_query(upsert)
+            .bind::<BigInt, _>(user_id as i64)
+            .bind::<Integer, _>(&collection_id)
+            .bind::<BigInt, _>(&self.timestamp().as_i64())
+            .bind::<BigInt, _>(quota.total_bytes as i64)
+            .bind::<Integer, _>(&quota.count)
+            .execute(&self.conn)?;
+        Ok(self.timestamp())
+    }
+
+    // Perform a lighter weight "read only" storage size check
+    fn get_storage_usage_sync(
+        &self,
+        user_id: UserIdentifier,
+    ) -> DbResult<results::GetStorageUsage> {
+        let uid = user_id.legacy_id as i64;
+        // SUM is NULL for users without any (unexpired) BSOs
+        let total_bytes = bso::table
+            .select(sql::<BigInt>(&format!(
+                "COALESCE(SUM({}), 0)",
+                BSO_PAYLOAD_LENGTH
+            )))
+            .filter(bso::user_id.eq(uid))
+            .filter(unexpired(self.timestamp().as_i64()))
+            .get_result::<i64>(&self.conn)?;
+        Ok(total_bytes as u64)
+    }
+
+    // Perform a lighter weight "read only" quota storage check
+    fn get_quota_usage_sync(
+        &self,
+        params: params::GetQuotaUsage,
+    ) -> DbResult<results::GetQuotaUsage> {
+        let uid = params.user_id.legacy_id as i64;
+        let (total_bytes, count): (i64, i32) = user_collections::table
+            .select((
+                sql::<BigInt>("COALESCE(SUM(total_bytes), 0)"),
+                sql::<Integer>("COALESCE(SUM(count), 0)"),
+            ))
+            .filter(user_collections::user_id.eq(uid))
+            .filter(user_collections::collection_id.eq(params.collection_id))
+            .get_result(&self.conn)
+            .optional()?
+            .unwrap_or_default();
+        Ok(results::GetQuotaUsage {
+            total_bytes: total_bytes as usize,
+            count,
+        })
+    }
+
+    // perform a heavier weight quota calculation
+    fn calc_quota_usage_sync(
+        &self,
+        user_id: u64,
+        collection_id: i32,
+    ) -> DbResult<results::GetQuotaUsage> {
+        let (total_bytes, count): (i64, i32) = bso::table
+            .select((
+                sql::<BigInt>(&format!("COALESCE(SUM({}), 0)", BSO_PAYLOAD_LENGTH)),
+                sql::<Integer>("COUNT(*)"),
+            ))
+            .filter(bso::user_id.eq(user_id as i64))
+            .filter(unexpired(self.timestamp().as_i64()))
+            .filter(bso::collection_id.eq(collection_id))
+            .get_result(&self.conn)
+            .optional()?
+            .unwrap_or_default();
+        Ok(results::GetQuotaUsage {
+            total_bytes: total_bytes as usize,
+            count,
+        })
+    }
+
+    pub(super) fn get_collection_usage_sync(
+        &self,
+        user_id: UserIdentifier,
+    ) -> DbResult<results::GetCollectionUsage> {
+        if self.quota.usage_counters {
+            return self.get_collection_counters_sync(&user_id, TOTAL_BYTES);
+        }
+        let counts = bso::table
+            .select((
+                bso::collection_id,
+                sql::<BigInt>(&format!("COALESCE(SUM({}), 0)", BSO_PAYLOAD_LENGTH)),
+            ))
+            .filter(bso::user_id.eq(user_id.legacy_id as i64))
+            .filter(unexpired(self.timestamp().as_i64()))
+            .group_by(bso::collection_id)
+            .load(&self.conn)?
+            .into_iter()
+            .collect();
+        self.map_collection_names(counts)
+    }
+
+    pub(super) fn get_collection_counts_sync(
+        &self,
+        user_id: UserIdentifier,
+    ) -> DbResult<results::GetCollectionCounts> {
+        if self.quota.usage_counters {
+            return self.get_collection_counters_sync(&user_id, COUNT);
+        }
+        let counts = bso::table
+            .select((
+                bso::collection_id,
+                sql::<BigInt>(&format!(
+                    "COUNT({collection_id})",
+                    collection_id = COLLECTION_ID
+                )),
+            ))
+            .filter(bso::user_id.eq(user_id.legacy_id as i64))
+            .filter(unexpired(self.timestamp().as_i64()))
+            .group_by(bso::collection_id)
+            .load(&self.conn)?
+            .into_iter()
+            .collect();
+        self.map_collection_names(counts)
+    }
+
+    /// A usage counter (`count` or `total_bytes`) of each of the user's
+    /// non-empty collections, as last maintained by `update_collection`
+    fn get_collection_counters_sync(
+        &self,
+        user_id: &UserIdentifier,
+        counter: &str,
+    ) -> DbResult<HashMap<String, i64>> {
+        let counters = user_collections::table
+            .select((user_collections::collection_id, sql::<BigInt>(counter)))
+            .filter(user_collections::user_id.eq(user_id.legacy_id as i64))
+            .filter(user_collections::count.gt(0))
+            .load(&self.conn)?
+            .into_iter()
+            .collect();
+        self.map_collection_names(counters)
+    }
+
+    batch_db_method!(create_batch_sync, create, CreateBatch);
+    batch_db_method!(validate_batch_sync, validate, ValidateBatch);
+    batch_db_method!(append_to_batch_sync, append, AppendToBatch);
+    batch_db_method!(commit_batch_sync, commit, CommitBatch);
+    batch_db_method!(delete_batch_sync, delete, DeleteBatch);
+
+    fn get_batch_sync(&self, params: params::GetBatch) -> DbResult<Option<results::GetBatch>> {
+        batch::get(self, params)
+    }
+
+    pub(super) fn timestamp(&self) -> SyncTimestamp {
+        self.session.borrow().timestamp
+    }
+}
+
+impl_sql_db!(SqliteDb);
+
+/// Filters the BSOs that haven't expired as of `now` (see
+/// `syncstorage_db_common::is_expired`)
+fn unexpired(now: i64) -> Gt<bso::expiry, i64> {
+    bso::expiry.gt(now)
+}
+
+/// Filters the BSOs past `after` in results sorted by `sort` (by `modified`,
+/// then `id`)
+fn past(
+    after: &params::BsoPosition,
+    sort: Sorting,
+) -> Box<dyn BoxableExpression<bso::table, Sqlite, SqlType = Bool>> {
+    let (modified, id) = (after.modified.as_i64(), after.id.clone());
+    if sort == Sorting::Oldest {
+        Box::new(
+            bso::modified
+                .gt(modified)
+                .or(bso::modified.eq(modified).and(bso::id.gt(id))),
+        )
+    } else {
+        Box::new(
+            bso::modified
+                .lt(modified)
+                .or(bso::modified.eq(modified).and(bso::id.lt(id))),
+        )
+    }
+}
+
+/// Filters the BSOs that have expired as of `now`
+fn expired(now: i64) -> LtEq<bso::expiry, i64> {
+    bso::expiry.le(now)
+}
+
+/// Reject a BSO whose id is longer than its column is declared to hold with
+/// a 400, as the other backends do
+pub(super) fn check_bso_size(id: &str) -> DbResult<()> {
+    check_bso_id(id)
+}
+
+/// The placeholders of a multi-row insert's `rows` of `columns` values each:
+/// `(?, ?), (?, ?)` for 2 of 2
+fn values_placeholders(rows: usize, columns: usize) -> String {
+    let row = format!("({})", vec!["?"; columns].join(", "));
+    vec![row; rows].join(", ")
+}
+
+#[derive(Debug, QueryableByName)]
+struct IdResult {
+    #[sql_type = "Integer"]
+    id: i32,
+}
+
+#[derive(Debug, QueryableByName)]
+struct EmptyCollectionResult {
+    #[sql_type = "BigInt"]
+    userid: i64, // USER_ID
+    #[sql_type = "Integer"]
+    collection: i32, // COLLECTION_ID
+    #[sql_type = "BigInt"]
+    last_modified: i64, // LAST_MODIFIED
+}
+
+#[derive(Debug, QueryableByName)]
+struct UserCollectionsResult {
+    // Can't substitute column names here.
+    #[sql_type = "Integer"]
+    collection: i32, // COLLECTION_ID
+    #[sql_type = "BigInt"]
+    last_modified: i64, // LAST_MODIFIED
+}
@@ -0,0 +1,235 @@
+use async_trait::async_trait;
+
+use std::{fmt, sync::Arc, time::Duration};
+
+use diesel::{r2d2::Pool, sqlite::SqliteConnection, Connection};
+#[cfg(debug_assertions)]
+use diesel_logger::LoggingConnection;
+use syncserver_common::{BlockingThreadpool, BulkPermit, CacheBackend, Metrics};
+use syncserver_db_common::{
+    manager::SqliteConnectionManager, migrations::check_schema_version, CheckoutStats,
+    GetPoolState, PoolState,
+};
+use syncstorage_db_common::{results, Db, DbPool};
+use syncstorage_settings::{Quota, Settings};
+use syncstorage_sql_db_common::{batch::validate_batch_id, pool_builder};
+
+use super::{models::SqliteDb, DbError, DbResult};
+
+embed_migrations!();
+
+/// The version of the latest migration in `migrations/`: the schema version
+/// this build requires the database to be at
+const SCHEMA_VERSION: &str = "20261016010000";
+
+/// How long a connection waits on another's write lock (see
+/// `SqliteDb::lock_for_write_sync`) before giving up, unless
+/// `database_lock_wait_timeout` says otherwise
+const BUSY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The filename of the database of a `sqlite://<filename>` url, e.g.
+/// `/var/lib/syncstorage.db` for `sqlite:///var/lib/syncstorage.db`.
+///
+/// In memory databases are refused: each of the pool's connections would
+/// get its own
+fn database_path(database_url: &str) -> DbResult<&str> {
+    match database_url.strip_prefix("sqlite://") {
+        Some(path) if path.is_empty() || path.starts_with(":memory:") => Err(DbError::internal(
+            "SQLite database_url must name a database file".to_owned(),
+        )),
+        Some(path) => Ok(path),
+        None => Err(DbError::internal(format!(
+            "Invalid SQLite database_url: {}",
+            database_url
+        ))),
+    }
+}
+
+/// Run the diesel embedded migrations
+///
+/// Like the MySQL backend's, this runs on its own separate conn rather than
+/// one of the pool's (which tests wrap in a test transaction).
+pub fn run_embedded_migrations(database_url: &str) -> DbResult<()> {
+    let conn = SqliteConnection::establish(database_path(database_url)?)?;
+    #[cfg(debug_assertions)]
+    // XXX: this doesn't show the DDL statements
+    // https://github.com/shssoichiro/diesel-logger/issues/1
+    embedded_migrations::run(&LoggingConnection::new(conn))?;
+    #[cfg(not(debug_assertions))]
+    embedded_migrations::run(&conn)?;
+    Ok(())
+}
+
+/// Collections are created within the callers' transactions, which SQLite
+/// serializes anyway (see `SqliteDb::get_or_create_collection_id`)
+pub(super) type CollectionCache =
+    syncstorage_sql_db_common::CollectionCache<SqliteConnectionManager>;
+
+#[derive(Clone)]
+pub struct SqliteDbPool {
+    /// Pool of db connections
+    pool: Pool<SqliteConnectionManager>,
+    /// The pool's checkouts, counted by its event handler
+    checkout_stats: CheckoutStats,
+    /// Cache of collection_ids and their names
+    coll_cache: Arc<CollectionCache>,
+
+    metrics: Metrics,
+    quota: Quota,
+    blocking_threadpool: Arc<BlockingThreadpool>,
+}
+
+impl SqliteDbPool {
+    /// Creates a new pool of SQLite db connections.
+    ///
+    /// Also initializes the SQLite db (creating its file when missing),
+    /// ensuring all migrations are ran, unless `run_migrations` is disabled.
+    ///
+    /// The database being a local file, `database_pool_lazy_init` is
+    /// ignored, as are `database_statement_timeout`, the collection
+    /// timestamp cache and payload deduplication.
+    pub fn new(
+        settings: &Settings,
+        metrics: &Metrics,
+        blocking_threadpool: Arc<BlockingThreadpool>,
+        cache_backend: &CacheBackend,
+    ) -> DbResult<Self> {
+        if settings.run_migrations {
+            run_embedded_migrations(&settings.database_url)?;
+        }
+        let database_path = database_path(&settings.database_url)?;
+        check_schema_version(&SqliteConnection::establish(database_path)?, SCHEMA_VERSION)?;
+
+        let busy_timeout = settings
+            .database_lock_wait_timeout
+            .map_or(BUSY_TIMEOUT, |seconds| Duration::from_secs(seconds.into()));
+        let manager = SqliteConnectionManager::new(database_path, metrics, busy_timeout);
+        let checkout_stats = CheckoutStats::default();
+        let builder = pool_builder(settings, &checkout_stats);
+
+        Ok(Self {
+            pool: builder.build(manager)?,
+            checkout_stats,
+            coll_cache: Arc::new(CollectionCache::new(
+                cache_backend,
+                settings
+                    .collection_cache_max_size
+                    .map(|max_size| max_size as usize),
+                None,
+            )),
+            metrics: metrics.clone(),
+            quota: Quota::from(settings),
+            blocking_threadpool,
+        })
+    }
+
+    pub fn get_sync(&self) -> DbResult<SqliteDb> {
+        Ok(SqliteDb::new(
+            self.pool.get()?,
+            Arc::clone(&self.coll_cache),
+            &self.metrics,
+            &self.quota,
+            self.blocking_threadpool.clone(),
+        ))
+    }
+
+    /// Checks out `min_idle` connections (at least one) at once, so each is
+    /// established and primed
+    fn warm_up_sync(&self) -> DbResult<usize> {
+        let connections = self
+            .pool
+            .min_idle()
+            .unwrap_or(1)
+            .clamp(1, self.pool.max_size());
+        let dbs = (0..connections)
+            .map(|_| self.get_sync())
+            .collect::<DbResult<Vec<_>>>()?;
+        for db in &dbs {
+            db.prime_statements()?;
+        }
+        dbs[0].cache_custom_collections()
+    }
+}
+
+#[async_trait]
+impl DbPool for SqliteDbPool {
+    type Error = DbError;
+
+    async fn get<'a>(&'a self) -> DbResult<Box<dyn Db<Error = Self::Error>>> {
+        let pool = self.clone();
+        self.blocking_threadpool
+            .spawn(move || pool.get_sync())
+            .await
+            .map(|db| Box::new(db) as Box<dyn Db<Error = Self::Error>>)
+    }
+
+    fn validate_batch_id(&self, id: String) -> DbResult<()> {
+        validate_batch_id(&id)
+    }
+
+    fn collection_cache_stats(&self) -> results::CollectionCacheStats {
+        self.coll_cache.stats()
+    }
+
+    async fn warm_up(&self) -> DbResult<usize> {
+        let pool = self.clone();
+        self.blocking_threadpool
+            .spawn(move || pool.warm_up_sync())
+            .await
+    }
+
+    async fn bulk_permit(&self) -> BulkPermit {
+        self.blocking_threadpool.bulk_permit().await
+    }
+
+    fn box_clone(&self) -> Box<dyn DbPool<Error = Self::Error>> {
+        Box::new(self.clone())
+    }
+}
+
+impl fmt::Debug for SqliteDbPool {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("SqliteDbPool")
+            .field("coll_cache", &self.coll_cache)
+            .finish()
+    }
+}
+
+impl GetPoolState for SqliteDbPool {
+    fn state(&self) -> PoolState {
+        self.checkout_stats.pool_state(self.pool.state())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use syncserver_db_common::migrations::latest_migration_version;
+
+    use super::*;
+
+    #[test]
+    fn schema_version_is_latest_migration() {
+        let migrations_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("migrations");
+        assert_eq!(
+            latest_migration_version(&migrations_dir).as_deref(),
+            Some(SCHEMA_VERSION)
+        );
+    }
+
+    #[test]
+    fn database_paths() {
+        assert_eq!(
+            database_path("sqlite:///var/lib/syncstorage.db").unwrap(),
+            "/var/lib/syncstorage.db"
+        );
+        assert_eq!(
+            database_path("sqlite://syncstorage.db").unwrap(),
+            "syncstorage.db"
+        );
+        assert!(database_path("sqlite://:memory:").is_err());
+        assert!(database_path("sqlite://").is_err());
+        assert!(database_path("mysql://root@127.0.0.1/syncstorage").is_err());
+    }
+}
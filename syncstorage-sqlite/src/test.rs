@@ -0,0 +1,229 @@
+use std::{collections::HashMap, sync::Arc};
+
+use diesel::{sql_query, sql_types::Text, ExpressionMethods, QueryDsl, RunQueryDsl};
+use http::StatusCode;
+use syncserver_common::{BlockingThreadpool, CacheBackend, Metrics};
+use syncserver_settings::Settings as SyncserverSettings;
+use syncstorage_db_common::{params, UserIdentifier, STD_COLLS};
+use syncstorage_settings::Settings as SyncstorageSettings;
+use syncstorage_sql_db_common::{MAX_BSO_ID_LENGTH, MAX_COLLECTION_NAME_LENGTH};
+use url::Url;
+
+use crate::{
+    models::{check_bso_size, SqliteDb},
+    pool::SqliteDbPool,
+    schema::{collections, user_collections},
+    DbResult,
+};
+
+pub fn db(settings: &SyncstorageSettings) -> DbResult<SqliteDb> {
+    let _ = env_logger::try_init();
+    // inherit SYNC_SYNCSTORAGE__DATABASE_URL from the env
+
+    let pool = SqliteDbPool::new(
+        settings,
+        &Metrics::noop(),
+        Arc::new(BlockingThreadpool::default()),
+        &CacheBackend::Memory,
+    )?;
+    pool.get_sync()
+}
+
+/// Whether the test database is a SQLite one (the tests below are skipped
+/// otherwise)
+fn is_sqlite(settings: &SyncstorageSettings) -> bool {
+    Url::parse(&settings.database_url).unwrap().scheme() == "sqlite"
+}
+
+#[test]
+fn static_collection_id() -> DbResult<()> {
+    let settings = SyncserverSettings::test_settings().syncstorage;
+    if !is_sqlite(&settings) {
+        return Ok(());
+    }
+    let db = db(&settings)?;
+
+    for (id, name) in STD_COLLS {
+        let stored = collections::table
+            .select(collections::name)
+            .filter(collections::id.eq(id))
+            .first::<String>(&db.inner.conn)?;
+        assert_eq!(&stored, name);
+        assert_eq!(db.get_collection_id(name)?, *id);
+    }
+
+    // Custom collections are numbered past the reserved ids
+    let cid = db.get_or_create_collection_id("col1")?;
+    assert!(cid > 100);
+    assert_eq!(db.get_or_create_collection_id("col1")?, cid);
+    Ok(())
+}
+
+#[derive(Debug, QueryableByName)]
+struct JournalMode {
+    #[sql_type = "Text"]
+    journal_mode: String,
+}
+
+#[test]
+fn write_ahead_logging() -> DbResult<()> {
+    let settings = SyncserverSettings::test_settings().syncstorage;
+    if !is_sqlite(&settings) {
+        return Ok(());
+    }
+    let db = db(&settings)?;
+
+    let result = sql_query("PRAGMA journal_mode").get_result::<JournalMode>(&db.inner.conn)?;
+    assert_eq!(result.journal_mode, "wal");
+    Ok(())
+}
+
+#[test]
+fn post_bsos_isolates_failing_bsos() -> DbResult<()> {
+    let settings = SyncserverSettings::test_settings().syncstorage;
+    if !is_sqlite(&settings) {
+        return Ok(());
+    }
+    let db = db(&settings)?;
+
+    let user_id = UserIdentifier {
+        legacy_id: 1,
+        ..Default::default()
+    };
+    let postbso = |id: &str, payload: &str| params::PostCollectionBso {
+        id: id.to_owned(),
+        payload: Some(payload.to_owned()),
+        sortindex: None,
+        ttl: None,
+    };
+    let result = db.post_bsos_sync(params::PostBsos {
+        user_id: user_id.clone(),
+        collection: "bookmarks".to_owned(),
+        bsos: vec![
+            postbso("b0", "payload b0"),
+            postbso(&"x".repeat(MAX_BSO_ID_LENGTH + 1), "payload b1"),
+            postbso("b2", "payload b2"),
+        ],
+        for_batch: false,
+        failed: HashMap::new(),
+    })?;
+    assert_eq!(result.success, vec!["b0", "b2"]);
+    assert_eq!(result.failed.len(), 1);
+
+    let bso = db.get_bso_sync(params::GetBso {
+        user_id,
+        collection: "bookmarks".to_owned(),
+        id: "b2".to_owned(),
+    })?;
+    assert_eq!(bso.unwrap().payload, "payload b2");
+    Ok(())
+}
+
+#[test]
+fn oversized_values_rejected() -> DbResult<()> {
+    let settings = SyncserverSettings::test_settings().syncstorage;
+    if !is_sqlite(&settings) {
+        return Ok(());
+    }
+    let db = db(&settings)?;
+
+    let user_id = UserIdentifier {
+        legacy_id: 1,
+        ..Default::default()
+    };
+    let put_bso = |collection: &str, id: &str| {
+        db.put_bso_sync(params::PutBso {
+            user_id: user_id.clone(),
+            collection: collection.to_owned(),
+            id: id.to_owned(),
+            payload: Some("payload".to_owned()),
+            sortindex: None,
+            ttl: None,
+        })
+    };
+
+    put_bso("bookmarks", &"x".repeat(MAX_BSO_ID_LENGTH))?;
+    let err = put_bso("bookmarks", &"x".repeat(MAX_BSO_ID_LENGTH + 1)).unwrap_err();
+    assert_eq!(err.status, StatusCode::BAD_REQUEST);
+
+    put_bso(&"c".repeat(MAX_COLLECTION_NAME_LENGTH), "b0")?;
+    let err = put_bso(&"c".repeat(MAX_COLLECTION_NAME_LENGTH + 1), "b0").unwrap_err();
+    assert_eq!(err.status, StatusCode::BAD_REQUEST);
+    Ok(())
+}
+
+#[test]
+fn bso_size_limits() {
+    assert!(check_bso_size(&"x".repeat(MAX_BSO_ID_LENGTH)).is_ok());
+    let err = check_bso_size(&"x".repeat(MAX_BSO_ID_LENGTH + 1)).unwrap_err();
+    assert_eq!(err.status, StatusCode::BAD_REQUEST);
+}
+
+#[test]
+fn collection_usage_counters() -> DbResult<()> {
+    let mut settings = SyncserverSettings::test_settings().syncstorage;
+    if !is_sqlite(&settings) {
+        return Ok(());
+    }
+    settings.collection_usage_counters = true;
+    let db = db(&settings)?;
+
+    let user_id = UserIdentifier {
+        legacy_id: 1_243,
+        ..Default::default()
+    };
+    db.delete_storage_sync(user_id.clone())?;
+    let postbso = |id: &str, ttl| params::PostCollectionBso {
+        id: id.to_owned(),
+        // 8 bytes, 7 characters
+        payload: Some("payloäd".to_owned()),
+        sortindex: None,
+        ttl,
+    };
+    db.post_bsos_sync(params::PostBsos {
+        user_id: user_id.clone(),
+        collection: "history".to_owned(),
+        bsos: vec![
+            postbso("h0", None),
+            postbso("h1", None),
+            postbso("h2", Some(0)),
+        ],
+        for_batch: false,
+        failed: HashMap::new(),
+    })?;
+
+    // Maintained by the write, excluding what had already expired
+    let counts = db.get_collection_counts_sync(user_id.clone())?;
+    assert_eq!(counts, HashMap::from([("history".to_owned(), 2)]));
+    let usage = db.get_collection_usage_sync(user_id)?;
+    assert_eq!(usage, HashMap::from([("history".to_owned(), 16)]));
+    Ok(())
+}
+
+#[test]
+fn std_collections_created_on_first_write() -> DbResult<()> {
+    let settings = SyncserverSettings::test_settings().syncstorage;
+    if !is_sqlite(&settings) {
+        return Ok(());
+    }
+    let db = db(&settings)?;
+
+    let user_id = UserIdentifier {
+        legacy_id: 1_245,
+        ..Default::default()
+    };
+    db.delete_storage_sync(user_id.clone())?;
+    db.lock_for_write_sync(params::LockCollection {
+        user_id: user_id.clone(),
+        collection: "bookmarks".to_owned(),
+    })?;
+    let rows = user_collections::table
+        .filter(user_collections::user_id.eq(1_245))
+        .count()
+        .get_result::<i64>(&db.inner.conn)?;
+    assert_eq!(rows as usize, STD_COLLS.len());
+    // Unwritten until they're written to
+    assert!(db.get_collection_timestamps_sync(user_id)?.is_empty());
+    db.rollback_sync()?;
+    Ok(())
+}
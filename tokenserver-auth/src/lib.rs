@@ -1,3 +1,6 @@
+#[macro_use]
+extern crate slog_scope;
+
 pub mod browserid;
 pub mod oauth;
 
@@ -41,8 +44,12 @@ pub struct MakeTokenPlaintext {
     pub hashed_device_id: String,
     pub hashed_fxa_uid: String,
     pub expires: u64,
+    /// When the token was minted, in seconds since epoch
+    pub issued_at: u64,
     pub uid: i64,
     pub tokenserver_origin: TokenserverOrigin,
+    /// The tenant of the user's storage, when not the default one
+    pub tenant: Option<String>,
 }
 
 impl IntoPy<PyObject> for MakeTokenPlaintext {
@@ -60,7 +67,11 @@ impl IntoPy<PyObject> for MakeTokenPlaintext {
         // These need to be set separately since they aren't strings, and
         // Rust doesn't support heterogeneous arrays
         dict.set_item("expires", self.expires).unwrap();
+        dict.set_item("issued_at", self.issued_at).unwrap();
         dict.set_item("uid", self.uid).unwrap();
+        if let Some(tenant) = self.tenant {
+            dict.set_item("tenant", tenant).unwrap();
+        }
 
         dict.into()
     }
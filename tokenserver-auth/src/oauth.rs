@@ -1,18 +1,29 @@
 use async_trait::async_trait;
+use base64::{engine, Engine};
 use pyo3::{
     prelude::{Py, PyAny, PyErr, PyModule, Python},
     types::{IntoPyDict, PyString},
 };
+use reqwest::Client as ReqwestClient;
 use serde::{Deserialize, Serialize};
-use serde_json;
-use syncserver_common::BlockingThreadpool;
+use serde_json::{self, json, Value};
+use sha2::{Digest, Sha256};
+use syncserver_common::{BlockingThreadpool, Cache, CacheBackend};
 use tokenserver_common::TokenserverError;
 use tokenserver_settings::{Jwk, Settings};
 use tokio::time;
 
 use super::VerifyToken;
 
-use std::{sync::Arc, time::Duration};
+use std::{
+    collections::HashSet,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// The minimum time between two JWKS fetches triggered by tokens that failed to verify, so a
+/// flood of invalid tokens can't turn into a flood of requests to FxA.
+const MIN_JWKS_REFETCH_INTERVAL: Duration = Duration::from_secs(60);
 
 /// The information extracted from a valid OAuth token.
 #[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
@@ -22,6 +33,100 @@ pub struct VerifyOutput {
     pub generation: Option<i64>,
 }
 
+#[derive(Deserialize)]
+struct JwksResponse {
+    keys: Vec<Value>,
+}
+
+/// The key sets fetched from FxA's `/v1/jwks` endpoint. The previous set is retained until the
+/// next refresh so that tokens signed with a key rotated out since are still accepted.
+#[derive(Default)]
+struct FetchedJwks {
+    current: Vec<Value>,
+    previous: Vec<Value>,
+    last_fetch_attempt: Option<Instant>,
+}
+
+/// A cached verification result. Only invalid credentials are cached as failures
+#[derive(Deserialize, Serialize)]
+enum CachedVerification {
+    Valid(VerifyOutput),
+    Invalid {
+        description: String,
+        context: String,
+    },
+}
+
+/// Recent verification results keyed by the SHA-256 hash of the token, so bursts of requests
+/// carrying the same token are only verified once. Rejected tokens are cached briefly as well,
+/// but failures to reach FxA never are.
+struct VerificationCache {
+    cache: Arc<dyn Cache>,
+    ttl: Duration,
+    failure_ttl: Duration,
+}
+
+impl VerificationCache {
+    fn key(token: &str) -> String {
+        format!("{:x}", Sha256::digest(token.as_bytes()))
+    }
+
+    fn get(&self, key: &str) -> Option<Result<VerifyOutput, TokenserverError>> {
+        self.cache
+            .get_value::<CachedVerification>(key)
+            .map(|cached| match cached {
+                CachedVerification::Valid(output) => Ok(output),
+                CachedVerification::Invalid {
+                    description,
+                    context,
+                } => Err(TokenserverError {
+                    context,
+                    ..TokenserverError::invalid_credentials(description)
+                }),
+            })
+    }
+
+    fn insert(&self, key: &str, token: &str, result: &Result<VerifyOutput, TokenserverError>) {
+        let (cached, ttl) = match result {
+            Ok(output) => (
+                CachedVerification::Valid(output.clone()),
+                match token_expires_in(token) {
+                    Some(expires_in) => self.ttl.min(expires_in),
+                    None => self.ttl,
+                },
+            ),
+            Err(e) if e.status == "invalid-credentials" => (
+                CachedVerification::Invalid {
+                    description: e.description.clone(),
+                    context: e.context.clone(),
+                },
+                self.failure_ttl,
+            ),
+            Err(_) => return,
+        };
+        if ttl.is_zero() {
+            return;
+        }
+        self.cache.set_value(key, &cached, Some(ttl));
+    }
+}
+
+/// The time left before a JWT access token expires, according to its `exp` claim. `None` for
+/// opaque tokens or JWTs without an expiry.
+fn token_expires_in(token: &str) -> Option<Duration> {
+    let payload = token.split('.').nth(1)?;
+    let claims: Value = serde_json::from_slice(
+        &engine::general_purpose::URL_SAFE_NO_PAD
+            .decode(payload)
+            .ok()?,
+    )
+    .ok()?;
+    let exp = claims.get("exp")?.as_u64()?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+
+    Some(Duration::from_secs(exp.saturating_sub(now)))
+}
+
 /// The verifier used to verify OAuth tokens.
 #[derive(Clone)]
 pub struct Verifier {
@@ -30,6 +135,12 @@ pub struct Verifier {
     inner: Py<PyAny>,
     timeout: u64,
     blocking_threadpool: Arc<BlockingThreadpool>,
+    configured_jwks: Vec<Value>,
+    fetched_jwks: Arc<RwLock<FetchedJwks>>,
+    jwks_refresh_interval: Option<Duration>,
+    jwks_url: String,
+    request_client: ReqwestClient,
+    verification_cache: Option<Arc<VerificationCache>>,
 }
 
 impl Verifier {
@@ -38,6 +149,7 @@ impl Verifier {
     pub fn new(
         settings: &Settings,
         blocking_threadpool: Arc<BlockingThreadpool>,
+        cache_backend: &CacheBackend,
     ) -> Result<Self, TokenserverError> {
         let inner: Py<PyAny> = Python::with_gil::<_, Result<Py<PyAny>, PyErr>>(|py| {
             let code = include_str!("verify.py");
@@ -85,21 +197,186 @@ impl Verifier {
         })
         .map_err(super::pyerr_to_tokenserver_error)?;
 
+        let configured_jwks = [
+            &settings.fxa_oauth_primary_jwk,
+            &settings.fxa_oauth_secondary_jwk,
+        ]
+        .into_iter()
+        .flatten()
+        .map(jwk_to_json)
+        .collect();
+        let jwks_url = {
+            let server_url = settings.fxa_oauth_server_url.trim_end_matches('/');
+            if server_url.ends_with("/v1") {
+                format!("{}/jwks", server_url)
+            } else {
+                format!("{}/v1/jwks", server_url)
+            }
+        };
+        let request_client = ReqwestClient::builder()
+            .timeout(Duration::from_secs(settings.fxa_oauth_request_timeout))
+            .use_rustls_tls()
+            .build()
+            .map_err(|e| TokenserverError {
+                context: format!("Failed to build the JWKS reqwest client: {}", e),
+                ..TokenserverError::internal_error()
+            })?;
+
         Ok(Self {
             inner,
             timeout: settings.fxa_oauth_request_timeout,
             blocking_threadpool,
+            configured_jwks,
+            fetched_jwks: Arc::new(RwLock::new(FetchedJwks::default())),
+            jwks_refresh_interval: settings
+                .fxa_oauth_jwks_refresh_interval
+                .map(Duration::from_secs),
+            jwks_url,
+            request_client,
+            verification_cache: settings.fxa_oauth_verification_cache_ttl.map(|ttl| {
+                Arc::new(VerificationCache {
+                    cache: cache_backend.cache(
+                        "oauth",
+                        Some(settings.fxa_oauth_verification_cache_max_size),
+                    ),
+                    ttl: Duration::from_secs(ttl),
+                    failure_ttl: Duration::from_secs(
+                        settings.fxa_oauth_verification_failure_cache_ttl,
+                    ),
+                })
+            }),
         })
     }
-}
 
-#[async_trait]
-impl VerifyToken for Verifier {
-    type Output = VerifyOutput;
+    /// Spawns a task periodically refreshing the JWKS from FxA. Does nothing if JWKS fetching is
+    /// disabled.
+    pub fn spawn_jwks_refresh_task(&self) {
+        let interval = match self.jwks_refresh_interval {
+            Some(interval) => interval,
+            None => return,
+        };
+        let verifier = self.clone();
 
-    /// Verifies an OAuth token. Returns `VerifyOutput` for valid tokens and a `TokenserverError`
-    /// for invalid tokens.
-    async fn verify(&self, token: String) -> Result<VerifyOutput, TokenserverError> {
+        tokio::spawn(async move {
+            let mut ticker = time::interval(interval);
+            loop {
+                ticker.tick().await;
+                if let Err(e) = verifier.refresh_jwks().await {
+                    warn!("⚠️ Failed to refresh the FxA JWKS: {}", e.context);
+                }
+            }
+        });
+    }
+
+    /// Whether a failed verification should trigger a JWKS fetch, in case FxA rotated its keys
+    /// since the last refresh.
+    fn should_refetch_jwks(&self) -> bool {
+        self.jwks_refresh_interval.is_some()
+            && self
+                .fetched_jwks
+                .read()
+                .unwrap()
+                .last_fetch_attempt
+                .map_or(true, |at| at.elapsed() >= MIN_JWKS_REFETCH_INTERVAL)
+    }
+
+    /// Fetches the JWKS from FxA, installing it in the Python client when it changed. Returns
+    /// whether the key set changed.
+    async fn refresh_jwks(&self) -> Result<bool, TokenserverError> {
+        self.fetched_jwks.write().unwrap().last_fetch_attempt = Some(Instant::now());
+
+        let JwksResponse { keys } = self
+            .request_client
+            .get(&self.jwks_url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| TokenserverError {
+                context: format!("Failed to fetch the FxA JWKS: {}", e),
+                ..TokenserverError::resource_unavailable()
+            })?
+            .json()
+            .await
+            .map_err(|e| TokenserverError {
+                context: format!("Invalid FxA JWKS: {}", e),
+                ..TokenserverError::resource_unavailable()
+            })?;
+
+        let jwks = {
+            let mut fetched = self.fetched_jwks.write().unwrap();
+            if fetched.current == keys {
+                return Ok(false);
+            }
+            fetched.previous = std::mem::replace(&mut fetched.current, keys);
+
+            // Deduplicate by key ID, preferring the configured keys over the fetched ones
+            let mut seen = HashSet::new();
+            self.configured_jwks
+                .iter()
+                .chain(&fetched.current)
+                .chain(&fetched.previous)
+                .filter(|jwk| {
+                    seen.insert(jwk.get("kid").and_then(Value::as_str).map(str::to_owned))
+                })
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        let jwks_json = serde_json::to_string(&jwks).map_err(|e| TokenserverError {
+            context: format!("Failed to serialize the FxA JWKS: {}", e),
+            ..TokenserverError::internal_error()
+        })?;
+
+        let verifier = self.clone();
+        self.blocking_threadpool
+            .spawn(move || {
+                Python::with_gil(|py| {
+                    verifier
+                        .inner
+                        .as_ref(py)
+                        .getattr("set_jwks")?
+                        .call((jwks_json,), None)
+                        .map(|_| ())
+                })
+                .map_err(super::pyerr_to_tokenserver_error)
+            })
+            .await?;
+
+        Ok(true)
+    }
+
+    /// Verifies a token, fetching the JWKS again and retrying if FxA might have rotated its keys.
+    async fn verify_uncached(&self, token: String) -> Result<VerifyOutput, TokenserverError> {
+        match self.verify_once(token.clone()).await {
+            // The token may have been signed with a key FxA rotated in since the JWKS was last
+            // refreshed, so fetch it again and retry if it changed
+            Err(e) if e.http_status.is_client_error() && self.should_refetch_jwks() => {
+                match self.refresh_jwks().await {
+                    Ok(true) => self.verify_once(token).await,
+                    _ => Err(e),
+                }
+            }
+            result => result,
+        }
+    }
+
+    /// Calls the verification cache, on the blocking threadpool when it's remote. `None` if
+    /// the threadpool failed to run the call
+    async fn with_cache<T, F>(&self, cache: &Arc<VerificationCache>, f: F) -> Option<T>
+    where
+        F: FnOnce(&VerificationCache) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        if !cache.cache.is_remote() {
+            return Some(f(cache));
+        }
+        let cache = Arc::clone(cache);
+        self.blocking_threadpool
+            .spawn(move || Ok::<_, TokenserverError>(f(&cache)))
+            .await
+            .ok()
+    }
+
+    async fn verify_once(&self, token: String) -> Result<VerifyOutput, TokenserverError> {
         // We don't want to move `self` into the body of the closure here because we'd need to
         // clone it. Cloning it is only necessary if we need to verify the token remotely via FxA,
         // since that would require passing `self` to a separate thread. Passing &Self to a closure
@@ -166,3 +443,269 @@ impl VerifyToken for Verifier {
             })?
     }
 }
+
+#[async_trait]
+impl VerifyToken for Verifier {
+    type Output = VerifyOutput;
+
+    /// Verifies an OAuth token. Returns `VerifyOutput` for valid tokens and a `TokenserverError`
+    /// for invalid tokens.
+    async fn verify(&self, token: String) -> Result<VerifyOutput, TokenserverError> {
+        let cache = match &self.verification_cache {
+            Some(cache) => cache,
+            None => return self.verify_uncached(token).await,
+        };
+        let key = VerificationCache::key(&token);
+        let cached = {
+            let key = key.clone();
+            self.with_cache(cache, move |cache| cache.get(&key)).await
+        };
+        if let Some(Some(result)) = cached {
+            return result;
+        }
+
+        let result = self.verify_uncached(token.clone()).await;
+        let cached_result = result.clone();
+        self.with_cache(cache, move |cache| {
+            cache.insert(&key, &token, &cached_result)
+        })
+        .await;
+
+        result
+    }
+}
+
+fn jwk_to_json(jwk: &Jwk) -> Value {
+    json!({
+        "kty": jwk.kty,
+        "alg": jwk.alg,
+        "kid": jwk.kid,
+        "use": jwk.use_of_key,
+        "n": jwk.n,
+        "e": jwk.e,
+        "fxa-createdAt": jwk.fxa_created_at,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use mockito::{self, Mock};
+
+    /// The modulus of a throwaway RSA key, so that the test keys parse
+    const RSA_MODULUS: &str = "jKMTSIJ4DX__ov2wDpN3Mi1E8gahWhnPyzkiJ576YwlK-sIZ6Xw4VWUK5SOWsKetPEWiRBmparZl3_VXy_KGrH5mMV3OxTekjCjNLQSDGUbHNW30dTxKuq4UCpdvgd7h9AKz6-Oj6PPdy5vX3FJWOPaZSKyXlxTUjzZe7esP88Fon8_dRLrhTwwpHttZEHqDxneyKCQ6gA9dmGEuXjFFFJE3-Wi_dqMe11rhVwssD7ShxFk8R6NfW0O13M0Y0gkYgmlqOZHZN3h2ML4oik7LNF8WoUkFyDc-o11hjPlxv8anZi4iBM4_qcHt_mmych3Hf1ocD35EL21iaIwcT4eh3Q";
+
+    fn cache() -> VerificationCache {
+        VerificationCache {
+            cache: CacheBackend::Memory.cache("oauth", Some(2)),
+            ttl: Duration::from_secs(300),
+            failure_ttl: Duration::from_secs(5),
+        }
+    }
+
+    fn jwk(kid: &str) -> Jwk {
+        Jwk {
+            kty: "RSA".to_owned(),
+            alg: "RS256".to_owned(),
+            kid: kid.to_owned(),
+            fxa_created_at: 1_600_000_000,
+            use_of_key: "sig".to_owned(),
+            n: RSA_MODULUS.to_owned(),
+            e: "AQAB".to_owned(),
+        }
+    }
+
+    /// A verifier fetching its JWKS from `/{path}/v1/jwks` on the mock server, so that tests
+    /// running concurrently don't share mocks
+    fn verifier(path: &str, jwks_refresh_interval: Option<u64>) -> Verifier {
+        Verifier::new(
+            &Settings {
+                fxa_oauth_server_url: format!("{}/{}", mockito::server_url(), path),
+                fxa_oauth_primary_jwk: Some(jwk("configured")),
+                fxa_oauth_jwks_refresh_interval: jwks_refresh_interval,
+                ..Default::default()
+            },
+            Arc::new(BlockingThreadpool::default()),
+            &CacheBackend::Memory,
+        )
+        .unwrap()
+    }
+
+    fn mock_jwks(path: &str, kids: &[&str]) -> Mock {
+        let keys: Vec<_> = kids.iter().map(|kid| jwk_to_json(&jwk(kid))).collect();
+        mockito::mock("GET", format!("/{}/v1/jwks", path).as_str())
+            .with_header("content-type", "application/json")
+            .with_body(json!({ "keys": keys }).to_string())
+    }
+
+    /// The key IDs of the current and previous fetched key sets
+    fn fetched_kids(verifier: &Verifier) -> (Vec<String>, Vec<String>) {
+        let kids = |jwks: &[Value]| {
+            jwks.iter()
+                .filter_map(|jwk| jwk.get("kid").and_then(Value::as_str).map(str::to_owned))
+                .collect()
+        };
+        let fetched = verifier.fetched_jwks.read().unwrap();
+        (kids(&fetched.current), kids(&fetched.previous))
+    }
+
+    fn jwt_with_kid(kid: &str) -> String {
+        let header = engine::general_purpose::URL_SAFE_NO_PAD
+            .encode(json!({ "alg": "RS256", "kid": kid, "typ": "at+jwt" }).to_string());
+        let claims =
+            engine::general_purpose::URL_SAFE_NO_PAD.encode(json!({ "sub": "test" }).to_string());
+        format!("{}.{}.signature", header, claims)
+    }
+
+    fn jwt_expiring_at(exp: u64) -> String {
+        let claims = engine::general_purpose::URL_SAFE_NO_PAD
+            .encode(json!({ "sub": "test", "exp": exp }).to_string());
+        format!("header.{}.signature", claims)
+    }
+
+    #[test]
+    fn test_verification_cache() {
+        let cache = cache();
+        let output = VerifyOutput {
+            fxa_uid: "test".to_owned(),
+            generation: Some(1234),
+        };
+
+        let valid_key = VerificationCache::key("valid");
+        cache.insert(&valid_key, "valid", &Ok(output.clone()));
+        assert_eq!(cache.get(&valid_key), Some(Ok(output)));
+
+        let key = VerificationCache::key("invalid");
+        let error = TokenserverError {
+            context: "Invalid OAuth token".to_owned(),
+            ..TokenserverError::invalid_credentials("Unauthorized".to_owned())
+        };
+        cache.insert(&key, "invalid", &Err(error.clone()));
+        assert_eq!(cache.get(&key), Some(Err(error)));
+
+        // The cache is full: the oldest verification is evicted
+        let key = VerificationCache::key("other");
+        cache.insert(&key, "other", &Ok(VerifyOutput::default()));
+        assert_eq!(cache.get(&key), Some(Ok(VerifyOutput::default())));
+        assert_eq!(cache.get(&valid_key), None);
+    }
+
+    #[test]
+    fn test_verification_cache_skips_unavailable_and_expired() {
+        let cache = cache();
+
+        let key = VerificationCache::key("unavailable");
+        cache.insert(
+            &key,
+            "unavailable",
+            &Err(TokenserverError::resource_unavailable()),
+        );
+        assert_eq!(cache.get(&key), None);
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let expired = jwt_expiring_at(now - 10);
+        let key = VerificationCache::key(&expired);
+        cache.insert(&key, &expired, &Ok(VerifyOutput::default()));
+        assert_eq!(cache.get(&key), None);
+
+        assert_eq!(
+            token_expires_in(&jwt_expiring_at(now + 60)).map(|d| d.as_secs() <= 60),
+            Some(true)
+        );
+        assert_eq!(token_expires_in("opaque-token"), None);
+    }
+
+    #[tokio::test]
+    async fn test_refresh_jwks() {
+        let verifier = verifier("refresh", Some(3600));
+
+        {
+            let mock = mock_jwks("refresh", &["k1"]).expect(2).create();
+            assert_eq!(verifier.refresh_jwks().await, Ok(true));
+            // The key set didn't change: it isn't installed again
+            assert_eq!(verifier.refresh_jwks().await, Ok(false));
+            mock.assert();
+            assert_eq!(fetched_kids(&verifier), (vec!["k1".to_owned()], vec![]));
+        }
+
+        // FxA rotated its keys: the previous set is retained
+        {
+            let mock = mock_jwks("refresh", &["k2"]).create();
+            assert_eq!(verifier.refresh_jwks().await, Ok(true));
+            mock.assert();
+            assert_eq!(
+                fetched_kids(&verifier),
+                (vec!["k2".to_owned()], vec!["k1".to_owned()])
+            );
+        }
+
+        // Failed fetches leave the key sets as they were
+        {
+            let mock = mockito::mock("GET", "/refresh/v1/jwks")
+                .with_status(500)
+                .create();
+            let error = verifier.refresh_jwks().await.unwrap_err();
+            mock.assert();
+            assert_eq!(
+                error.http_status,
+                TokenserverError::resource_unavailable().http_status
+            );
+            assert_eq!(
+                fetched_kids(&verifier),
+                (vec!["k2".to_owned()], vec!["k1".to_owned()])
+            );
+        }
+
+        {
+            let mock = mockito::mock("GET", "/refresh/v1/jwks")
+                .with_header("content-type", "application/json")
+                .with_body("{}")
+                .create();
+            let error = verifier.refresh_jwks().await.unwrap_err();
+            mock.assert();
+            assert_eq!(
+                error.http_status,
+                TokenserverError::resource_unavailable().http_status
+            );
+            assert!(error.context.starts_with("Invalid FxA JWKS"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_verify_refetches_jwks() {
+        let verifier = verifier("refetch", Some(3600));
+        let mock = mock_jwks("refetch", &["rotated"]).expect(1).create();
+
+        // Signed with a key unknown to the verifier: the JWKS is fetched again in case FxA
+        // rotated it in
+        let error = verifier.verify(jwt_with_kid("rotated")).await.unwrap_err();
+        assert!(error.http_status.is_client_error());
+        mock.assert();
+        assert_eq!(
+            fetched_kids(&verifier),
+            (vec!["rotated".to_owned()], vec![])
+        );
+
+        // Further failures within the minimum refetch interval don't hit FxA again
+        for token in [jwt_with_kid("unknown"), "invalid".to_owned()] {
+            let error = verifier.verify(token).await.unwrap_err();
+            assert!(error.http_status.is_client_error());
+        }
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_verify_without_jwks_refresh() {
+        let verifier = verifier("no-refresh", None);
+        let mock = mock_jwks("no-refresh", &["rotated"]).expect(0).create();
+
+        let error = verifier.verify(jwt_with_kid("rotated")).await.unwrap_err();
+        assert!(error.http_status.is_client_error());
+        mock.assert();
+        assert_eq!(fetched_kids(&verifier), (vec![], vec![]));
+    }
+}
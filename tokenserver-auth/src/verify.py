@@ -7,11 +7,18 @@ DEFAULT_OAUTH_SCOPE = 'https://identity.mozilla.com/apps/oldsync'
 
 class FxaOAuthClient:
     def __init__(self, server_url=None, jwks=None):
+        self._server_url = server_url
         self._client = Client(server_url=server_url, jwks=jwks)
 
+    def set_jwks(self, jwks):
+        # Swap in a new client rather than mutating the current one, so any
+        # verification already in progress keeps using a consistent key set
+        self._client = Client(server_url=self._server_url, jwks=json.loads(jwks))
+
     def verify_token(self, token):
+        client = self._client
         try:
-            token_data = self._client.verify_token(token, DEFAULT_OAUTH_SCOPE)
+            token_data = client.verify_token(token, DEFAULT_OAUTH_SCOPE)
 
             # Serialize the data to make it easier to parse in Rust
             return json.dumps(token_data)
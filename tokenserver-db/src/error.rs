@@ -78,6 +78,7 @@ impl InternalError for DbError {
 
 impl_fmt_display!(DbError, DbErrorKind);
 
+from_error!(MysqlError, DbError, DbErrorKind::Mysql);
 from_error!(
     diesel::result::Error,
     DbError,
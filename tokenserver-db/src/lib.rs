@@ -10,4 +10,4 @@ mod pool;
 pub mod results;
 
 pub use models::{Db, TokenserverDb};
-pub use pool::{DbPool, TokenserverPool};
+pub use pool::{run_embedded_migrations, DbPool, TokenserverPool};
@@ -1,6 +1,5 @@
 use diesel::{
-    mysql::MysqlConnection,
-    r2d2::{ConnectionManager, PooledConnection},
+    r2d2::PooledConnection,
     sql_types::{Bigint, Float, Integer, Nullable, Text},
     OptionalExtension, RunQueryDsl,
 };
@@ -8,7 +7,9 @@ use diesel::{
 use diesel_logger::LoggingConnection;
 use http::StatusCode;
 use syncserver_common::{BlockingThreadpool, Metrics};
-use syncserver_db_common::{sync_db_method, DbFuture};
+use syncserver_db_common::{
+    manager::MysqlConnectionManager, sync_db_method, DbFuture, DbRequestId,
+};
 
 use std::{
     sync::Arc,
@@ -24,7 +25,7 @@ use super::{
 /// "retired" from the db.
 const MAX_GENERATION: i64 = i64::MAX;
 
-type Conn = PooledConnection<ConnectionManager<MysqlConnection>>;
+type Conn = PooledConnection<MysqlConnectionManager>;
 
 #[derive(Clone)]
 pub struct TokenserverDb {
@@ -368,6 +369,7 @@ impl TokenserverDb {
             // allocate a new one.
             let allocate_user_result =
                 self.allocate_user_sync(params.clone() as params::AllocateUser)?;
+            self.metrics.incr_with_tag("user.created", "reason", "new");
 
             Ok(results::GetOrCreateUser {
                 uid: allocate_user_result.uid,
@@ -432,6 +434,8 @@ impl TokenserverDb {
                             capacity_release_rate: params.capacity_release_rate,
                         })?
                     };
+                    self.metrics
+                        .incr_with_tag("user.created", "reason", "reassigned");
 
                     Ok(results::GetOrCreateUser {
                         uid: allocate_user_result.uid,
@@ -484,6 +488,7 @@ impl TokenserverDb {
             service_id: params.service_id,
             node: node.node.clone(),
         })?;
+        metrics.incr_with_tag("node.assignment", "node", &node.node);
 
         let created_at = {
             let start = SystemTime::now();
@@ -666,6 +671,8 @@ impl TokenserverDb {
     }
 }
 
+impl DbRequestId for TokenserverDb {}
+
 impl Db for TokenserverDb {
     sync_db_method!(replace_user, replace_user_sync, ReplaceUser);
     sync_db_method!(replace_users, replace_users_sync, ReplaceUsers);
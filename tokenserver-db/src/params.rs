@@ -1,5 +1,7 @@
 //! Parameter types for database methods.
 
+use syncserver_db_common::DbCallParams;
+
 #[derive(Clone, Default)]
 pub struct PostNode {
     pub service_id: i32,
@@ -123,3 +125,35 @@ pub struct UnassignNode {
 pub struct RemoveNode {
     pub node_id: i64,
 }
+
+// None of the tokenserver's calls operate on a collection
+macro_rules! call_params {
+    ($($name:ident),+) => ($(
+        impl DbCallParams for $name {}
+    )+)
+}
+
+call_params!(
+    PostNode,
+    GetNode,
+    PostService,
+    GetUsers,
+    GetOrCreateUser,
+    PostUser,
+    PutUser,
+    ReplaceUsers,
+    ReplaceUser,
+    GetNodeId,
+    GetBestNode,
+    AddUserToNode,
+    GetServiceId
+);
+
+#[cfg(test)]
+call_params!(
+    SetUserCreatedAt,
+    SetUserReplacedAt,
+    GetUser,
+    UnassignNode,
+    RemoveNode
+);
@@ -1,16 +1,15 @@
 use std::{sync::Arc, time::Duration};
 
 use async_trait::async_trait;
-use diesel::{
-    mysql::MysqlConnection,
-    r2d2::{ConnectionManager, Pool},
-    Connection,
-};
+use diesel::{mysql::MysqlConnection, r2d2::Pool, Connection};
 use diesel_logger::LoggingConnection;
 use syncserver_common::{BlockingThreadpool, Metrics};
 #[cfg(debug_assertions)]
 use syncserver_db_common::test::TestTransactionCustomizer;
-use syncserver_db_common::{GetPoolState, PoolState};
+use syncserver_db_common::{
+    manager::MysqlConnectionManager, migrations::check_schema_version, CheckoutStats, GetPoolState,
+    PoolState,
+};
 use tokenserver_settings::Settings;
 
 use super::{
@@ -20,11 +19,15 @@ use super::{
 
 embed_migrations!();
 
+/// The version of the latest migration in `migrations/`: the schema version
+/// this build requires the database to be at
+const SCHEMA_VERSION: &str = "20211222160451";
+
 /// Run the diesel embedded migrations
 ///
 /// Mysql DDL statements implicitly commit which could disrupt MysqlPool's
 /// begin_test_transaction during tests. So this runs on its own separate conn.
-fn run_embedded_migrations(database_url: &str) -> DbResult<()> {
+pub fn run_embedded_migrations(database_url: &str) -> DbResult<()> {
     let conn = MysqlConnection::establish(database_url)?;
 
     embedded_migrations::run(&LoggingConnection::new(conn))?;
@@ -35,7 +38,9 @@ fn run_embedded_migrations(database_url: &str) -> DbResult<()> {
 #[derive(Clone)]
 pub struct TokenserverPool {
     /// Pool of db connections
-    inner: Pool<ConnectionManager<MysqlConnection>>,
+    inner: Pool<MysqlConnectionManager>,
+    /// The pool's checkouts, counted by its event handler
+    checkout_stats: CheckoutStats,
     metrics: Metrics,
     // This field is public so the service ID can be set after the pool is created
     pub service_id: Option<i32>,
@@ -53,10 +58,17 @@ impl TokenserverPool {
         if settings.run_migrations {
             run_embedded_migrations(&settings.database_url)?;
         }
-
-        let manager = ConnectionManager::<MysqlConnection>::new(settings.database_url.clone());
+        check_schema_version(
+            &MysqlConnection::establish(&settings.database_url)?,
+            SCHEMA_VERSION,
+        )?;
+
+        let manager =
+            MysqlConnectionManager::new(settings.database_url.clone(), metrics, None, None);
+        let checkout_stats = CheckoutStats::default();
         let builder = Pool::builder()
             .max_size(settings.database_pool_max_size)
+            .event_handler(Box::new(checkout_stats.clone()))
             .connection_timeout(Duration::from_secs(
                 settings.database_pool_connection_timeout.unwrap_or(30) as u64,
             ))
@@ -71,6 +83,7 @@ impl TokenserverPool {
 
         Ok(Self {
             inner: builder.build(manager)?,
+            checkout_stats,
             metrics: metrics.clone(),
             spanner_node_id: settings.spanner_node_id,
             service_id: None,
@@ -143,7 +156,7 @@ pub trait DbPool: Sync + Send + GetPoolState {
 
 impl GetPoolState for TokenserverPool {
     fn state(&self) -> PoolState {
-        self.inner.state().into()
+        self.checkout_stats.pool_state(self.inner.state())
     }
 }
 
@@ -158,3 +171,21 @@ impl Clone for Box<dyn DbPool> {
         self.box_clone()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use syncserver_db_common::migrations::latest_migration_version;
+
+    use super::*;
+
+    #[test]
+    fn schema_version_is_latest_migration() {
+        let migrations_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("migrations");
+        assert_eq!(
+            latest_migration_version(&migrations_dir).as_deref(),
+            Some(SCHEMA_VERSION)
+        );
+    }
+}
@@ -35,6 +35,19 @@ pub struct Settings {
     /// A secondary JWK to be used to verify OAuth tokens. This is intended to be used to enable
     /// seamless key rotations on FxA.
     pub fxa_oauth_secondary_jwk: Option<Jwk>,
+    /// How often, in seconds, FxA's JWKS is fetched from the `/v1/jwks` endpoint of the OAuth
+    /// server. Fetched keys are used alongside the configured JWKs, and the previously fetched
+    /// set is kept until the next refresh so that tokens signed with a key FxA has just rotated
+    /// out still verify locally. JWKS fetching is disabled when unset.
+    pub fxa_oauth_jwks_refresh_interval: Option<u64>,
+    /// How long, in seconds, successful OAuth verifications are cached for, keyed by a hash of
+    /// the token. Never longer than the token's own expiry. Caching is disabled when unset.
+    pub fxa_oauth_verification_cache_ttl: Option<u64>,
+    /// How long, in seconds, tokens FxA rejected are cached for when the verification cache is
+    /// enabled.
+    pub fxa_oauth_verification_failure_cache_ttl: u64,
+    /// The max number of OAuth verifications cached.
+    pub fxa_oauth_verification_cache_max_size: usize,
     /// The issuer expected in the BrowserID verification response.
     pub fxa_browserid_issuer: String,
     /// The audience to be sent to the FxA BrowserID verification server.
@@ -53,6 +66,12 @@ pub struct Settings {
     /// The type of the storage nodes used by this instance of Tokenserver.
     #[serde(default = "NodeType::spanner")]
     pub node_type: NodeType,
+    /// Path to a JSON file mapping storage nodes (as recorded in the `nodes` table) to the URLs
+    /// advertised for them, e.g. `{"https://sync-1.example.com": "https://sync.example.com"}`.
+    /// Lets nodes move to new URLs without downtime: the file is reread every
+    /// `node_registry_refresh_interval` seconds. Nodes it doesn't list are advertised as recorded.
+    pub node_registry_path: Option<String>,
+    pub node_registry_refresh_interval: u64,
     /// The label to be used when reporting Metrics.
     pub statsd_label: String,
     /// Whether or not to run the Tokenserver migrations upon startup.
@@ -67,6 +86,17 @@ pub struct Settings {
     pub additional_blocking_threads_for_fxa_requests: Option<u32>,
     /// The amount of time in seconds before a token provided by Tokenserver expires.
     pub token_duration: u64,
+    /// The tenant stamped into the tokens issued, isolating this Tokenserver's users' storage
+    /// from other tenants' on storage nodes shared with them (which must list it in their
+    /// `tenants`). Tokens carry no tenant when unset, the storage nodes' default one.
+    pub tenant: Option<String>,
+    /// Whether the storage requests of tokens this Tokenserver minted are checked against the
+    /// user's records when the server also serves storage, rejecting tokens of a record since
+    /// replaced or of keys older than the current record's.
+    pub check_storage_tokens: bool,
+    /// How long (in seconds) users' records are cached for storage token checks: a change to a
+    /// user's records may go unnoticed that long. Not cached when 0.
+    pub storage_token_check_cache_ttl: u32,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -95,6 +125,10 @@ impl Default for Settings {
             fxa_oauth_request_timeout: 10,
             fxa_oauth_primary_jwk: None,
             fxa_oauth_secondary_jwk: None,
+            fxa_oauth_jwks_refresh_interval: None,
+            fxa_oauth_verification_cache_ttl: None,
+            fxa_oauth_verification_failure_cache_ttl: 5,
+            fxa_oauth_verification_cache_max_size: 10_000,
             fxa_browserid_audience: "https://token.stage.mozaws.net".to_owned(),
             fxa_browserid_issuer: "api-accounts.stage.mozaws.net".to_owned(),
             fxa_browserid_server_url: "https://verifier.stage.mozaws.net/v2".to_owned(),
@@ -102,11 +136,16 @@ impl Default for Settings {
             fxa_browserid_connect_timeout: 5,
             node_capacity_release_rate: None,
             node_type: NodeType::Spanner,
+            node_registry_path: None,
+            node_registry_refresh_interval: 60,
             statsd_label: "syncstorage.tokenserver".to_owned(),
             run_migrations: cfg!(test),
             spanner_node_id: None,
             additional_blocking_threads_for_fxa_requests: Some(1),
             token_duration: 3600,
+            tenant: None,
+            check_storage_tokens: false,
+            storage_token_check_cache_ttl: 60,
         }
     }
 }
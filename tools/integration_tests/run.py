@@ -65,6 +65,19 @@ if __name__ == "__main__":
     finally:
         terminate_process(the_server_subprocess)
 
+    # Spanner writes with either mutations (the default) or DML: run the
+    # storage tests against the DML write path as well
+    database_url = os.environ.get("SYNC_SYNCSTORAGE__DATABASE_URL", "")
+    if database_url.startswith("spanner://"):
+        os.environ["SYNC_SYNCSTORAGE__DATABASE_SPANNER_USE_MUTATIONS"] = \
+            "false"
+        the_server_subprocess = start_server()
+        try:
+            res |= run_live_functional_tests(TestStorage, sys.argv)
+        finally:
+            terminate_process(the_server_subprocess)
+        del os.environ["SYNC_SYNCSTORAGE__DATABASE_SPANNER_USE_MUTATIONS"]
+
     os.environ["SYNC_TOKENSERVER__FXA_BROWSERID_SERVER_URL"] = \
         "https://verifier.stage.mozaws.net/v2"
     os.environ["SYNC_TOKENSERVER__FXA_OAUTH_SERVER_URL"] = \